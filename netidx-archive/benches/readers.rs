@@ -0,0 +1,146 @@
+//! Rough timing of many playback sessions reading one archive at the
+//! same time, with and without the block cache, both replaying a
+//! finished archive and tailing one that is being written. Run with
+//! `cargo bench -p netidx-archive`.
+use netidx::{
+    path::Path,
+    subscriber::{Event, Value},
+};
+use netidx_archive::{
+    cache::BlockCache, ArchiveReader, ArchiveWriter, BatchItem, Cursor,
+    MonotonicTimestamper, BATCH_POOL,
+};
+use std::{
+    fs,
+    path::Path as FilePath,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::Instant,
+};
+
+const PATHS: usize = 100;
+const BATCHES: usize = 20_000;
+const SESSIONS: usize = 200;
+
+fn remove(file: &FilePath) {
+    for f in [file.to_path_buf(), file.with_extension("activity")] {
+        if f.is_file() {
+            fs::remove_file(f).unwrap();
+        }
+    }
+}
+
+fn write_batches(t: &mut ArchiveWriter, ts: &mut MonotonicTimestamper, n: usize) {
+    for i in 0..n {
+        let mut batch = BATCH_POOL.take();
+        for p in 0..PATHS {
+            let id = t.id_for_path(&Path::from(format!("/bench/{}", p))).unwrap();
+            let v = Value::from(format!("value {} of path {}", i, p));
+            batch.push(BatchItem(id, Event::Update(v)));
+        }
+        t.add_batch(false, ts.timestamp(), &batch).unwrap();
+    }
+    t.flush().unwrap();
+}
+
+// every session reads the whole archive, 3 batches at a time like
+// the recorder does. Returns the number of batches read.
+fn sessions(reader: &ArchiveReader, done: Arc<AtomicBool>) -> usize {
+    let threads = (0..SESSIONS)
+        .map(|_| {
+            let reader = reader.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut cursor = Cursor::new();
+                let mut n = 0;
+                loop {
+                    let finished = done.load(Ordering::Acquire);
+                    if finished {
+                        // read_deltas won't wait for a rescan another
+                        // session is doing, so make sure we see the end
+                        reader.check_remap_rescan().unwrap();
+                    }
+                    let batches = reader.read_deltas(&mut cursor, 3).unwrap();
+                    if batches.is_empty() {
+                        if finished {
+                            break n;
+                        }
+                        thread::yield_now();
+                    }
+                    n += batches.len();
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    threads.into_iter().map(|t| t.join().unwrap()).sum()
+}
+
+fn report(name: &str, reader: &ArchiveReader, start: Instant, read: usize) {
+    let elapsed = start.elapsed();
+    let (hits, misses) = reader.block_cache().stats();
+    println!(
+        "{}: {} sessions read {} batches in {:?}, {:.0} batches/s, {} hits {} misses",
+        name,
+        SESSIONS,
+        read,
+        elapsed,
+        read as f64 / elapsed.as_secs_f64(),
+        hits,
+        misses
+    );
+}
+
+fn replay(file: &FilePath, name: &str, cache: BlockCache) {
+    let reader = ArchiveReader::open(file).unwrap().with_block_cache(cache);
+    let start = Instant::now();
+    let read = sessions(&reader, Arc::new(AtomicBool::new(true)));
+    assert_eq!(read, BATCHES * SESSIONS);
+    report(name, &reader, start, read);
+}
+
+fn tail(file: &FilePath, name: &str, cache: BlockCache) {
+    remove(file);
+    let mut t = ArchiveWriter::open(file).unwrap();
+    let paths =
+        (0..PATHS).map(|p| Path::from(format!("/bench/{}", p))).collect::<Vec<_>>();
+    t.add_paths(paths.iter()).unwrap();
+    let reader = t.reader().unwrap().with_block_cache(cache);
+    let done = Arc::new(AtomicBool::new(false));
+    let start = Instant::now();
+    let sessions = {
+        let reader = reader.clone();
+        let done = done.clone();
+        thread::spawn(move || sessions(&reader, done))
+    };
+    let mut ts = MonotonicTimestamper::new();
+    for _ in 0..BATCHES / 100 {
+        write_batches(&mut t, &mut ts, 100);
+    }
+    done.store(true, Ordering::Release);
+    let read = sessions.join().unwrap();
+    assert_eq!(read, BATCHES * SESSIONS);
+    report(name, &reader, start, read);
+    drop(reader);
+    drop(t);
+    remove(file);
+}
+
+fn main() {
+    let file = FilePath::new("bench-data-readers");
+    remove(file);
+    {
+        let mut t = ArchiveWriter::open(file).unwrap();
+        let paths =
+            (0..PATHS).map(|p| Path::from(format!("/bench/{}", p))).collect::<Vec<_>>();
+        t.add_paths(paths.iter()).unwrap();
+        write_batches(&mut t, &mut MonotonicTimestamper::new(), BATCHES);
+    }
+    replay(file, "replay uncached", BlockCache::new(0));
+    replay(file, "replay cached", BlockCache::new(256 * 1024 * 1024));
+    remove(file);
+    tail(file, "tail uncached", BlockCache::new(0));
+    tail(file, "tail cached", BlockCache::new(64 * 1024 * 1024));
+}
@@ -0,0 +1,143 @@
+//! An auxiliary index of how many times each path was updated in
+//! each time bucket, so tools can find out what was changing during
+//! a period without reading all the deltas. The index is maintained
+//! by `ArchiveWriter` and kept next to the archive in a file with
+//! the same name plus `.activity`. It is an append only sequence of
+//! `(bucket, [(id, count)])` records, a bucket may appear more than
+//! once, in which case the counts are summed.
+//!
+//! The index is best effort, it is written when the archive is
+//! flushed, but not synced, and archives written before it existed
+//! don't have one.
+use crate::Id;
+use anyhow::Result;
+use bytes::BytesMut;
+use chrono::prelude::*;
+use fxhash::FxHashMap;
+use log::warn;
+use netidx::pack::Pack;
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::{self, File, OpenOptions},
+    io::Write,
+    ops::RangeBounds,
+    path::{Path as FilePath, PathBuf},
+};
+
+/// The resolution of the activity index in seconds
+pub const BUCKET_SECS: i64 = 60;
+
+pub(crate) fn index_path(archive: &FilePath) -> PathBuf {
+    let mut path = archive.as_os_str().to_owned();
+    path.push(".activity");
+    PathBuf::from(path)
+}
+
+// floor ts to a multiple of width seconds
+pub(crate) fn bucket(ts: DateTime<Utc>, width: i64) -> DateTime<Utc> {
+    let secs = ts.timestamp().div_euclid(width) * width;
+    Utc.timestamp_opt(secs, 0).unwrap()
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct Activity(BTreeMap<DateTime<Utc>, FxHashMap<Id, u32>>);
+
+impl Activity {
+    /// load the index at `path`, stopping at the first record that
+    /// can't be decoded, e.g. because it was partly written.
+    pub(crate) fn load(path: &FilePath) -> Result<Self> {
+        let mut t = Activity::default();
+        if !path.is_file() {
+            return Ok(t);
+        }
+        let data = fs::read(path)?;
+        let mut buf = &data[..];
+        while !buf.is_empty() {
+            match <(DateTime<Utc>, Vec<(Id, u32)>) as Pack>::decode(&mut buf) {
+                Ok((ts, counts)) => t.add(ts, counts.into_iter()),
+                Err(e) => {
+                    warn!("truncated activity index {}: {}", path.display(), e);
+                    break;
+                }
+            }
+        }
+        Ok(t)
+    }
+
+    fn add(&mut self, ts: DateTime<Utc>, counts: impl Iterator<Item = (Id, u32)>) {
+        let b = self.0.entry(bucket(ts, BUCKET_SECS)).or_default();
+        for (id, n) in counts {
+            *b.entry(id).or_insert(0) += n;
+        }
+    }
+
+    pub(crate) fn record(&mut self, ts: DateTime<Utc>, ids: impl Iterator<Item = Id>) {
+        self.add(ts, ids.map(|id| (id, 1)))
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+
+    /// sum the counts of the ids `f` accepts in `range` into buckets
+    /// `width` seconds wide
+    pub(crate) fn query<K, R, F>(
+        &self,
+        range: R,
+        width: i64,
+        mut f: F,
+    ) -> BTreeMap<DateTime<Utc>, HashMap<K, u64>>
+    where
+        K: std::hash::Hash + Eq,
+        R: RangeBounds<DateTime<Utc>>,
+        F: FnMut(Id) -> Option<K>,
+    {
+        let mut res: BTreeMap<DateTime<Utc>, HashMap<K, u64>> = BTreeMap::new();
+        for (ts, counts) in self.0.range(range) {
+            for (id, n) in counts {
+                if let Some(k) = f(*id) {
+                    let b = res.entry(bucket(*ts, width)).or_default();
+                    *b.entry(k).or_insert(0) += *n as u64;
+                }
+            }
+        }
+        res
+    }
+}
+
+/// The writer side of the index, counts accumulate in memory until
+/// `flush` appends them to the file.
+#[derive(Debug)]
+pub(crate) struct ActivityLog {
+    file: File,
+    pending: Activity,
+}
+
+impl ActivityLog {
+    /// open the index for `archive`, if `truncate` is true then
+    /// discard anything already in it.
+    pub(crate) fn open(archive: &FilePath, truncate: bool) -> Result<Self> {
+        let path = index_path(archive);
+        if truncate && path.is_file() {
+            fs::remove_file(&path)?;
+        }
+        let file = OpenOptions::new().append(true).create(true).open(&path)?;
+        Ok(ActivityLog { file, pending: Activity::default() })
+    }
+
+    pub(crate) fn record(&mut self, ts: DateTime<Utc>, ids: impl Iterator<Item = Id>) {
+        self.pending.record(ts, ids)
+    }
+
+    pub(crate) fn flush(&mut self) -> Result<()> {
+        if self.pending.is_empty() {
+            return Ok(());
+        }
+        let mut buf = BytesMut::new();
+        for (ts, counts) in std::mem::take(&mut self.pending.0) {
+            let rec = (ts, counts.into_iter().collect::<Vec<_>>());
+            rec.encode(&mut buf)?;
+        }
+        Ok(self.file.write_all(&buf)?)
+    }
+}
@@ -0,0 +1,139 @@
+//! Pivot the updates to a set of paths into a wide table sampled on
+//! a regular time grid. Each row holds, for every column, the last
+//! value the path had at or before the row's timestamp (last
+//! observation carried forward), or `None` if it had no value yet,
+//! or was unsubscribed. This is the shape most analysis tools want,
+//! and it saves them from having to replay the archive themselves.
+use crate::ArchiveRange;
+use anyhow::{Error, Result};
+use chrono::{prelude::*, DurationRound};
+use netidx::{
+    path::Path,
+    subscriber::{Event, Value},
+};
+use std::{collections::HashMap, ops::Bound};
+
+/// An iterator over the rows of an aligned table, see
+/// `ArchiveReader::aligned`. Rows are `(timestamp, values)`, and the
+/// values are in the same order as `columns`.
+pub struct Aligned {
+    columns: Vec<Path>,
+    index: HashMap<Path, usize>,
+    range: ArchiveRange,
+    peeked: Option<(DateTime<Utc>, Path, Event)>,
+    row: Vec<Option<Value>>,
+    step: chrono::Duration,
+    next: Option<DateTime<Utc>>,
+    end: Bound<DateTime<Utc>>,
+    last: Option<DateTime<Utc>>,
+    done: bool,
+}
+
+impl Aligned {
+    pub(crate) fn new(
+        mut columns: Vec<Path>,
+        range: ArchiveRange,
+        start: Bound<DateTime<Utc>>,
+        end: Bound<DateTime<Utc>>,
+        step: chrono::Duration,
+    ) -> Result<Self> {
+        if step <= chrono::Duration::zero() {
+            bail!("the alignment step must be positive")
+        }
+        columns.sort();
+        columns.dedup();
+        let index = columns.iter().enumerate().map(|(i, p)| (p.clone(), i)).collect();
+        let next = match start {
+            Bound::Unbounded => None,
+            Bound::Included(ts) => Some(ts),
+            Bound::Excluded(ts) => ts.checked_add_signed(step),
+        };
+        Ok(Aligned {
+            row: vec![None; columns.len()],
+            columns,
+            index,
+            range,
+            peeked: None,
+            step,
+            next,
+            end,
+            last: None,
+            done: false,
+        })
+    }
+
+    /// The paths in the table, in column order
+    pub fn columns(&self) -> &[Path] {
+        &self.columns
+    }
+
+    /// If reading the archive failed then this will return the
+    /// error that ended the iteration.
+    pub fn error(&self) -> Option<&Error> {
+        self.range.error()
+    }
+
+    fn peek(&mut self) -> Option<&(DateTime<Utc>, Path, Event)> {
+        if self.peeked.is_none() {
+            self.peeked = self.range.next();
+        }
+        self.peeked.as_ref()
+    }
+}
+
+impl Iterator for Aligned {
+    type Item = (DateTime<Utc>, Vec<Option<Value>>);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.done {
+            return None;
+        }
+        let at = match self.next {
+            Some(at) => at,
+            None => {
+                // unbounded start, begin on the grid line at or
+                // before the first event
+                let ts = self.peek()?.0;
+                let at = ts.duration_trunc(self.step).unwrap_or(ts);
+                self.next = Some(at);
+                at
+            }
+        };
+        let past_end = match self.end {
+            Bound::Included(end) => at > end,
+            Bound::Excluded(end) => at >= end,
+            Bound::Unbounded => false,
+        };
+        if past_end {
+            self.done = true;
+            return None;
+        }
+        while self.peek().map(|(ts, _, _)| *ts <= at).unwrap_or(false) {
+            let (ts, path, ev) = self.peeked.take().unwrap();
+            self.last = Some(ts);
+            if let Some(i) = self.index.get(&path) {
+                self.row[*i] = match ev {
+                    Event::Update(v) => Some(v),
+                    Event::Unsubscribed => None,
+                };
+            }
+        }
+        // with an unbounded end the table stops at the first grid
+        // line at or after the last event
+        if matches!(self.end, Bound::Unbounded) && self.peek().is_none() {
+            let prev = at.checked_sub_signed(self.step);
+            match self.last {
+                Some(last) if prev.map(|prev| prev < last).unwrap_or(true) => (),
+                _ => {
+                    self.done = true;
+                    return None;
+                }
+            }
+        }
+        match at.checked_add_signed(self.step) {
+            Some(next) => self.next = Some(next),
+            None => self.done = true,
+        }
+        Some((at, self.row.clone()))
+    }
+}
@@ -0,0 +1,164 @@
+//! A cache of decoded batches shared by archive readers.
+//!
+//! Many playback sessions reading the same archive tend to read the
+//! same batches at around the same time, for example every session
+//! tailing a live archive reads each new batch as it arrives. Rather
+//! than each session decoding the batch from the memory map again,
+//! the first reader to decode it puts it in the cache, and the rest
+//! clone it from there. Values are reference counted, so cloning a
+//! decoded batch is much cheaper than decoding it.
+//!
+//! The cache is split into shards, each with it's own lock, and
+//! entries are evicted with the clock (second chance) algorithm
+//! once a shard is full.
+use crate::BatchItem;
+use anyhow::Result;
+use fxhash::FxHashMap;
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+const SHARDS: usize = 16;
+
+/// The capacity of the cache shared by every reader that isn't given
+/// one explicitly, see `ArchiveReader::with_block_cache`.
+pub const DEFAULT_CAPACITY: usize = 64 * 1024 * 1024;
+
+lazy_static! {
+    pub(crate) static ref DEFAULT: BlockCache = BlockCache::new(DEFAULT_CAPACITY);
+}
+
+// the archive the batch is from, and it's position in the file
+pub(crate) type Key = (u64, usize);
+
+#[derive(Debug)]
+struct Entry {
+    batch: Arc<Vec<BatchItem>>,
+    weight: usize,
+    referenced: bool,
+}
+
+#[derive(Debug, Default)]
+struct Shard {
+    entries: FxHashMap<Key, Entry>,
+    clock: VecDeque<Key>,
+    used: usize,
+}
+
+impl Shard {
+    fn get(&mut self, key: &Key) -> Option<Arc<Vec<BatchItem>>> {
+        self.entries.get_mut(key).map(|e| {
+            e.referenced = true;
+            e.batch.clone()
+        })
+    }
+
+    fn insert(
+        &mut self,
+        capacity: usize,
+        key: Key,
+        batch: Arc<Vec<BatchItem>>,
+        weight: usize,
+    ) {
+        // the batch will never fit, or another reader decoded it at
+        // the same time and got here first
+        if weight > capacity || self.entries.contains_key(&key) {
+            return;
+        }
+        self.used += weight;
+        self.entries.insert(key, Entry { batch, weight, referenced: false });
+        self.clock.push_back(key);
+        while self.used > capacity {
+            let key = match self.clock.pop_front() {
+                None => break,
+                Some(key) => key,
+            };
+            match self.entries.get_mut(&key) {
+                None => (),
+                Some(e) if e.referenced => {
+                    e.referenced = false;
+                    self.clock.push_back(key);
+                }
+                Some(_) => {
+                    if let Some(e) = self.entries.remove(&key) {
+                        self.used -= e.weight;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[derive(Debug)]
+struct CacheInner {
+    shards: Vec<Mutex<Shard>>,
+    shard_capacity: usize,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+/// A cache of decoded batches that can be shared by any number of
+/// readers of any number of archives. `BlockCache` is internally
+/// wrapped in an Arc, so cloning it is virtually free.
+#[derive(Debug, Clone)]
+pub struct BlockCache(Arc<CacheInner>);
+
+impl BlockCache {
+    /// Create a cache holding at most `capacity` bytes of batches, as
+    /// measured by their size in the archive. Decoded batches take
+    /// somewhat more memory than that. A capacity of 0 disables
+    /// caching.
+    pub fn new(capacity: usize) -> Self {
+        BlockCache(Arc::new(CacheInner {
+            shards: (0..SHARDS).map(|_| Mutex::new(Shard::default())).collect(),
+            shard_capacity: capacity / SHARDS,
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }))
+    }
+
+    pub fn capacity(&self) -> usize {
+        self.0.shard_capacity * SHARDS
+    }
+
+    /// The number of bytes of batches currently in the cache
+    pub fn used(&self) -> usize {
+        self.0.shards.iter().map(|s| s.lock().used).sum()
+    }
+
+    /// The number of reads that were served from the cache, and the
+    /// number that had to decode the batch.
+    pub fn stats(&self) -> (u64, u64) {
+        (self.0.hits.load(Ordering::Relaxed), self.0.misses.load(Ordering::Relaxed))
+    }
+
+    /// Get the batch at `key`, calling `decode` to decode it, and
+    /// it's size, if it isn't in the cache. The shard lock is not
+    /// held while decoding.
+    pub(crate) fn get_or_decode<F>(
+        &self,
+        key: Key,
+        decode: F,
+    ) -> Result<Arc<Vec<BatchItem>>>
+    where
+        F: FnOnce() -> Result<(usize, Vec<BatchItem>)>,
+    {
+        let shard = &self.0.shards[fxhash::hash64(&key) as usize % SHARDS];
+        if let Some(batch) = shard.lock().get(&key) {
+            self.0.hits.fetch_add(1, Ordering::Relaxed);
+            return Ok(batch);
+        }
+        self.0.misses.fetch_add(1, Ordering::Relaxed);
+        let (weight, batch) = decode()?;
+        let batch = Arc::new(batch);
+        if self.0.shard_capacity > 0 {
+            shard.lock().insert(self.0.shard_capacity, key, batch.clone(), weight);
+        }
+        Ok(batch)
+    }
+}
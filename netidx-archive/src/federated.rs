@@ -0,0 +1,520 @@
+//! Read a set of archives as if they were one, e.g. a directory of
+//! per-day files written by a recorder that rotates it's archive, or
+//! the archives of several recorders that each record a different
+//! site. Batches from different archives are merged in timestamp
+//! order, batches with the same timestamp are concatenated, and the
+//! ids of each archive are mapped into one id space keyed by path,
+//! so a path has the same id no matter which archive it came from.
+//!
+//! Images are built from every archive that has data before the
+//! requested position, so the state of a path is carried forward
+//! from earlier archives even if a later archive doesn't mention it.
+//!
+//! The archives can also be the segments of a `Tiered` set, in which
+//! case segments that were offloaded are fetched as reading reaches
+//! them.
+use crate::{
+    tiered::Tiered, ArchiveReader, BatchItem, Cursor, Id, Seek, BATCH_POOL,
+    CURSOR_BATCH_POOL, EPSILON, IDX_POOL, IMG_POOL,
+};
+use anyhow::Result;
+use chrono::prelude::*;
+use fxhash::{FxBuildHasher, FxHashMap};
+use indexmap::IndexMap;
+use log::warn;
+use netidx::{path::Path, pool::Pooled, subscriber::Event};
+use parking_lot::{Mutex, RwLock};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fs,
+    ops::Bound,
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+};
+
+struct Member {
+    file: PathBuf,
+    reader: ArchiveReader,
+    // the archive's ids to federated ids
+    ids: Mutex<FxHashMap<Id, Id>>,
+}
+
+impl Member {
+    fn first(&self) -> Option<DateTime<Utc>> {
+        self.reader.snapshot().deltamap.first()
+    }
+
+    fn last(&self) -> Option<DateTime<Utc>> {
+        self.reader.snapshot().deltamap.last()
+    }
+}
+
+#[derive(Default)]
+struct Ids {
+    path_by_id: IndexMap<Id, Path, FxBuildHasher>,
+    id_by_path: HashMap<Path, Id>,
+}
+
+struct FederatedInner {
+    dir: Option<PathBuf>,
+    tiered: Option<Tiered>,
+    // sorted by the time range of the archive
+    members: RwLock<Vec<Arc<Member>>>,
+    ids: Mutex<Ids>,
+}
+
+/// A read only view of a set of archives, with the same interface as
+/// `ArchiveReader` for reading. `FederatedReader` is internally
+/// wrapped in an Arc, so cloning it is virtually free.
+#[derive(Clone)]
+pub struct FederatedReader(Arc<FederatedInner>);
+
+impl FederatedReader {
+    fn new(dir: Option<PathBuf>, tiered: Option<Tiered>) -> Self {
+        FederatedReader(Arc::new(FederatedInner {
+            dir,
+            tiered,
+            members: RwLock::new(Vec::new()),
+            ids: Mutex::new(Ids::default()),
+        }))
+    }
+
+    /// Open the specified archives
+    pub fn open<P: AsRef<FilePath>>(files: impl IntoIterator<Item = P>) -> Result<Self> {
+        let t = Self::new(None, None);
+        for file in files {
+            t.add(file.as_ref(), ArchiveReader::open(file.as_ref())?);
+        }
+        Ok(t)
+    }
+
+    /// Open every archive in `dir`. Files that can't be opened as
+    /// archives, including archives that are currently being written
+    /// by a recorder, are skipped with a warning. Call `rescan` to
+    /// pick up archives added to `dir` later.
+    pub fn open_dir(dir: impl AsRef<FilePath>) -> Result<Self> {
+        let t = Self::new(Some(dir.as_ref().to_path_buf()), None);
+        t.rescan()?;
+        Ok(t)
+    }
+
+    /// Open the segments of `tiered`. Local segments are opened
+    /// right away, offloaded segments are fetched when seeking or
+    /// reading reaches them. Use `add_reader` to add the segment
+    /// that is currently being written, if any.
+    pub fn open_tiered(tiered: Tiered) -> Result<Self> {
+        let t = Self::new(None, Some(tiered));
+        t.rescan()?;
+        Ok(t)
+    }
+
+    /// Add an archive that is already open to the set, e.g. the
+    /// reader of an archive that is being written. Does nothing if
+    /// `file` is already in the set.
+    pub fn add_reader(&self, file: impl AsRef<FilePath>, reader: ArchiveReader) {
+        self.add(file.as_ref(), reader)
+    }
+
+    /// Open any archives that were added to the directory since it
+    /// was opened, or last rescanned. If the reader was opened with
+    /// `open_tiered` open any new local segments instead. Does
+    /// nothing if the reader was opened with `open`.
+    pub fn rescan(&self) -> Result<()> {
+        if let Some(tiered) = &self.0.tiered {
+            for seg in tiered.segments() {
+                if !seg.remote {
+                    self.load(tiered, &seg.name)?
+                }
+            }
+            return Ok(());
+        }
+        let dir = match &self.0.dir {
+            None => return Ok(()),
+            Some(dir) => dir,
+        };
+        let mut files = vec![];
+        for ent in fs::read_dir(dir)? {
+            let ent = ent?;
+            if ent.file_type()?.is_file() {
+                files.push(ent.path());
+            }
+        }
+        files.sort();
+        for file in files {
+            let activity = file.extension().map(|e| e == "activity").unwrap_or(false);
+            if !activity && !self.known(&file) {
+                match ArchiveReader::open(&file) {
+                    Ok(reader) => self.add(&file, reader),
+                    Err(e) => warn!("skipping archive {}: {}", file.display(), e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn known(&self, file: &FilePath) -> bool {
+        self.0.members.read().iter().any(|m| m.file == file)
+    }
+
+    fn add(&self, file: &FilePath, reader: ArchiveReader) {
+        if self.known(file) {
+            return;
+        }
+        let member = Arc::new(Member {
+            file: file.to_path_buf(),
+            reader,
+            ids: Mutex::new(HashMap::default()),
+        });
+        for (id, _) in member.reader.get_index().drain(..) {
+            self.map_id(&member, id);
+        }
+        let mut members = self.0.members.write();
+        if members.iter().any(|m| m.file == member.file) {
+            return;
+        }
+        members.push(member);
+        members.sort_by_key(|m| m.reader.time_range());
+    }
+
+    // open the segment `name`, fetching it if it was offloaded
+    fn load(&self, tiered: &Tiered, name: &str) -> Result<()> {
+        let file = tiered.dir().join(name);
+        if !self.known(&file) {
+            self.add(&file, tiered.reader(name)?)
+        }
+        Ok(())
+    }
+
+    // make sure the segments needed to read from `ts` are open, the
+    // one containing `ts`, and the next one towards the end of the
+    // set if `forward`, otherwise towards the beginning. None means
+    // from the beginning, or end, of the set.
+    fn fetch(&self, ts: Option<DateTime<Utc>>, forward: bool) -> Result<()> {
+        let tiered = match &self.0.tiered {
+            None => return Ok(()),
+            Some(tiered) => tiered,
+        };
+        let segs = tiered.segments();
+        let mut names = vec![];
+        match ts {
+            None if forward => names.extend(segs.first()),
+            None => names.extend(segs.last()),
+            Some(ts) => {
+                names.extend(segs.iter().filter(|s| s.start <= ts && ts <= s.end));
+                if forward {
+                    names.extend(segs.iter().find(|s| s.start > ts))
+                } else {
+                    names.extend(segs.iter().rev().find(|s| s.end < ts))
+                }
+            }
+        }
+        for seg in names {
+            self.load(tiered, &seg.name)?
+        }
+        Ok(())
+    }
+
+    // the start of the first segment after `ts` that isn't open
+    fn unopened_after(&self, ts: Option<DateTime<Utc>>) -> Option<DateTime<Utc>> {
+        let tiered = self.0.tiered.as_ref()?;
+        tiered
+            .segments()
+            .into_iter()
+            .filter(|s| ts.map(|ts| s.start > ts).unwrap_or(true))
+            .find(|s| !self.known(&tiered.dir().join(&s.name)))
+            .map(|s| s.start)
+    }
+
+    fn fetch_for_seek(&self, ts: Option<DateTime<Utc>>, forward: bool) {
+        if let Err(e) = self.fetch(ts, forward) {
+            warn!("failed to fetch segment: {}", e)
+        }
+    }
+
+    // the position reading from `cursor` starts at
+    fn pos(cursor: &Cursor) -> Option<DateTime<Utc>> {
+        match (cursor.current, cursor.start) {
+            (Some(ts), _) => Some(ts),
+            (None, Bound::Included(ts) | Bound::Excluded(ts)) => Some(ts),
+            (None, Bound::Unbounded) => None,
+        }
+    }
+
+    // the time range of every segment, including offloaded ones
+    fn segment_ranges(&self) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+        match &self.0.tiered {
+            None => vec![],
+            Some(tiered) => tiered.segments().iter().map(|s| (s.start, s.end)).collect(),
+        }
+    }
+
+    // map an archive id to a federated id
+    fn map_id(&self, m: &Member, id: Id) -> Option<Id> {
+        let mut map = m.ids.lock();
+        if let Some(id) = map.get(&id) {
+            return Some(*id);
+        }
+        let path = m.reader.path_for_id(&id)?;
+        let mut ids = self.0.ids.lock();
+        let fid = match ids.id_by_path.get(&path) {
+            Some(fid) => *fid,
+            None => {
+                let fid = Id(ids.path_by_id.len() as u64);
+                ids.path_by_id.insert(fid, path.clone());
+                ids.id_by_path.insert(path, fid);
+                fid
+            }
+        };
+        map.insert(id, fid);
+        Some(fid)
+    }
+
+    fn map_batch(
+        &self,
+        m: &Member,
+        mut batch: Pooled<Vec<BatchItem>>,
+    ) -> Pooled<Vec<BatchItem>> {
+        let mut res = BATCH_POOL.take();
+        for BatchItem(id, ev) in batch.drain(..) {
+            match self.map_id(m, id) {
+                Some(id) => res.push(BatchItem(id, ev)),
+                None => warn!("{}: unknown id {:?}", m.file.display(), id),
+            }
+        }
+        res
+    }
+
+    fn members(&self) -> Vec<Arc<Member>> {
+        self.0.members.read().clone()
+    }
+
+    /// The files in the set, in the order of their time ranges
+    pub fn files(&self) -> Vec<PathBuf> {
+        self.0.members.read().iter().map(|m| m.file.clone()).collect()
+    }
+
+    /// The timestamps of the first and last batches in any archive in
+    /// the set, or None if they are all empty.
+    pub fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let mut ranges = self
+            .members()
+            .iter()
+            .filter_map(|m| m.reader.time_range())
+            .collect::<Vec<_>>();
+        ranges.extend(self.segment_ranges());
+        let first = ranges.iter().map(|r| r.0).min()?;
+        let last = ranges.iter().map(|r| r.1).max()?;
+        Some((first, last))
+    }
+
+    pub fn delta_batches(&self) -> usize {
+        self.members().iter().map(|m| m.reader.delta_batches()).sum()
+    }
+
+    pub fn id_for_path(&self, path: &Path) -> Option<Id> {
+        self.0.ids.lock().id_by_path.get(path).copied()
+    }
+
+    pub fn path_for_id(&self, id: &Id) -> Option<Path> {
+        self.0.ids.lock().path_by_id.get(id).cloned()
+    }
+
+    /// See `ArchiveReader::check_remap_rescan`
+    pub fn check_remap_rescan(&self) -> Result<()> {
+        for m in self.members() {
+            m.reader.check_remap_rescan()?
+        }
+        Ok(())
+    }
+
+    fn first(&self) -> Option<DateTime<Utc>> {
+        let segs = self.segment_ranges().into_iter().map(|(start, _)| start);
+        self.members().iter().filter_map(|m| m.first()).chain(segs).min()
+    }
+
+    fn last(&self) -> Option<DateTime<Utc>> {
+        let segs = self.segment_ranges().into_iter().map(|(_, end)| end);
+        self.members().iter().filter_map(|m| m.last()).chain(segs).max()
+    }
+
+    /// See `ArchiveReader::seek`. Batches with the same timestamp in
+    /// different archives count as one batch.
+    pub fn seek(&self, cursor: &mut Cursor, seek: Seek) {
+        if let Seek::BatchRelative(steps) = seek {
+            let ts = match cursor.current {
+                Some(ts) => Some(ts),
+                None if steps >= 0 => Self::pos(cursor),
+                None => match cursor.end {
+                    Bound::Included(ts) | Bound::Excluded(ts) => Some(ts),
+                    Bound::Unbounded => None,
+                },
+            };
+            self.fetch_for_seek(ts, steps >= 0)
+        }
+        self.seek_loaded(cursor, seek);
+        if let Some(ts) = cursor.current {
+            self.fetch_for_seek(Some(ts), true)
+        }
+    }
+
+    fn seek_loaded(&self, cursor: &mut Cursor, seek: Seek) {
+        match seek {
+            Seek::Beginning => match self.first() {
+                None => cursor.current = None,
+                Some(ts) => cursor.set_current(ts),
+            },
+            Seek::End => match self.last() {
+                None => cursor.current = None,
+                Some(ts) => cursor.set_current(ts),
+            },
+            Seek::Absolute(ts) => cursor.set_current(ts),
+            Seek::TimeRelative(offset) => match cursor.current() {
+                Some(ts) => cursor.set_current(ts + offset),
+                None => {
+                    if offset >= chrono::Duration::microseconds(0) {
+                        match cursor.start() {
+                            Bound::Included(ts) => cursor.set_current(ts + offset),
+                            Bound::Excluded(ts) => {
+                                cursor.set_current(ts + *EPSILON + offset)
+                            }
+                            Bound::Unbounded => {
+                                if let Some(ts) = self.first() {
+                                    cursor.set_current(ts + offset)
+                                }
+                            }
+                        }
+                    } else {
+                        match cursor.end() {
+                            Bound::Included(ts) => cursor.set_current(ts + offset),
+                            Bound::Excluded(ts) => {
+                                cursor.set_current(ts - *EPSILON + offset)
+                            }
+                            Bound::Unbounded => {
+                                if let Some(ts) = self.last() {
+                                    cursor.set_current(ts + offset)
+                                }
+                            }
+                        }
+                    }
+                }
+            },
+            Seek::BatchRelative(steps) => {
+                let n = steps.abs() as usize;
+                let mut set = BTreeSet::new();
+                for m in self.members() {
+                    let snap = m.reader.snapshot();
+                    if steps >= 0 {
+                        let init =
+                            cursor.current.map(Bound::Excluded).unwrap_or(cursor.start);
+                        set.extend(
+                            snap.deltamap
+                                .range((init, cursor.end))
+                                .map(|(ts, _)| ts)
+                                .take(n),
+                        );
+                    } else {
+                        let init =
+                            cursor.current.map(Bound::Excluded).unwrap_or(cursor.end);
+                        set.extend(
+                            snap.deltamap
+                                .range((cursor.start, init))
+                                .rev()
+                                .map(|(ts, _)| ts)
+                                .take(n),
+                        );
+                    }
+                }
+                let ts = if steps >= 0 {
+                    set.iter().take(n).last()
+                } else {
+                    set.iter().rev().take(n).last()
+                };
+                if let Some(ts) = ts {
+                    cursor.current = Some(*ts);
+                }
+            }
+        }
+    }
+
+    /// Return all the id/path pairs in the set, see
+    /// `ArchiveReader::get_index`.
+    pub fn get_index(&self) -> Pooled<Vec<(Id, Path)>> {
+        let mut idx = IDX_POOL.take();
+        let ids = self.0.ids.lock();
+        idx.extend(ids.path_by_id.iter().map(|(id, path)| (*id, path.clone())));
+        idx
+    }
+
+    /// Build the image at the cursor, see `ArchiveReader::build_image`.
+    /// Where more than one archive has a value for a path, the value
+    /// from the archive that was written most recently before the
+    /// cursor wins.
+    pub fn build_image(&self, cursor: &Cursor) -> Result<Pooled<HashMap<Id, Event>>> {
+        let upto = match (cursor.current, cursor.start) {
+            (Some(ts), _) | (None, Bound::Included(ts)) => Bound::Excluded(ts),
+            (None, Bound::Excluded(ts)) => Bound::Included(ts),
+            (None, Bound::Unbounded) => return Ok(IMG_POOL.take()),
+        };
+        if let Bound::Included(ts) | Bound::Excluded(ts) = upto {
+            self.fetch(Some(ts), false)?
+        }
+        let mut members = self
+            .members()
+            .into_iter()
+            .filter_map(|m| {
+                let snap = m.reader.snapshot();
+                let range = (Bound::Unbounded, upto);
+                let delta = snap.deltamap.range(range).next_back().map(|(ts, _)| ts);
+                let image = snap.imagemap.range(range).next_back().map(|(ts, _)| ts);
+                let last = delta.max(image)?;
+                drop(snap);
+                Some((last, m))
+            })
+            .collect::<Vec<_>>();
+        members.sort_by_key(|(last, _)| *last);
+        let mut image = IMG_POOL.take();
+        for (_, m) in members {
+            for (id, ev) in m.reader.build_image(cursor)?.drain() {
+                if let Some(id) = self.map_id(&m, id) {
+                    image.insert(id, ev);
+                }
+            }
+        }
+        Ok(image)
+    }
+
+    /// Read at most `n` delta batches from the cursor and advance it,
+    /// see `ArchiveReader::read_deltas`.
+    pub fn read_deltas(
+        &self,
+        cursor: &mut Cursor,
+        n: usize,
+    ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
+        let pos = Self::pos(cursor);
+        self.fetch(pos, true)?;
+        // stop before any segment that isn't open yet, it will be
+        // fetched by the next read
+        let stop = self.unopened_after(pos);
+        let mut merged: BTreeMap<DateTime<Utc>, Pooled<Vec<BatchItem>>> = BTreeMap::new();
+        for m in self.members() {
+            let mut c = *cursor;
+            for (ts, batch) in m.reader.read_deltas(&mut c, n)?.drain(..) {
+                let mut batch = self.map_batch(&m, batch);
+                match merged.get_mut(&ts) {
+                    Some(b) => b.extend(batch.drain(..)),
+                    None => {
+                        merged.insert(ts, batch);
+                    }
+                }
+            }
+        }
+        let mut res = CURSOR_BATCH_POOL.take();
+        let before_stop =
+            |ts: &DateTime<Utc>| stop.map(|stop| *ts < stop).unwrap_or(true);
+        res.extend(merged.into_iter().take_while(|(ts, _)| before_stop(ts)).take(n));
+        if let Some((ts, _)) = res.back() {
+            cursor.current = Some(*ts);
+        }
+        Ok(res)
+    }
+}
@@ -0,0 +1,203 @@
+//! The in memory index of an archive's image and delta records by
+//! timestamp, and the snapshots readers use to read it concurrently.
+//!
+//! Readers never lock the index. Instead the current state of the
+//! archive, the index and the memory map, is published as an
+//! immutable `Snapshot`. When the archive grows, the new records are
+//! indexed into a copy of the current snapshot which then replaces
+//! it, readers that already hold the old snapshot keep using it
+//! undisturbed.
+use chrono::prelude::*;
+use crossbeam::epoch::{self, Atomic, Owned};
+use mapr::Mmap;
+use std::{
+    cmp::max,
+    fmt,
+    ops::{Bound, RangeBounds},
+    sync::{atomic::Ordering, Arc},
+};
+
+// the number of entries in each chunk of a TimeIndex
+const CHUNK: usize = 4096;
+
+/// A sorted map from timestamps to record positions in the
+/// archive. Entries are stored in fixed size chunks which are shared
+/// between clones, so cloning the index only copies the list of
+/// chunks, and extending a clone only copies the last chunk, if it is
+/// partially full.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct TimeIndex {
+    // every chunk except the last is full
+    chunks: Vec<Arc<Vec<(DateTime<Utc>, usize)>>>,
+    len: usize,
+}
+
+impl TimeIndex {
+    pub(crate) fn len(&self) -> usize {
+        self.len
+    }
+
+    fn get(&self, i: usize) -> (DateTime<Utc>, usize) {
+        self.chunks[i / CHUNK][i % CHUNK]
+    }
+
+    // the index of the first entry for which f is false, f must be
+    // true for a prefix of the index and false after it
+    fn partition_point(&self, f: impl Fn(&DateTime<Utc>) -> bool) -> usize {
+        let c = self.chunks.partition_point(|c| f(&c[c.len() - 1].0));
+        if c == self.chunks.len() {
+            self.len
+        } else {
+            c * CHUNK + self.chunks[c].partition_point(|(ts, _)| f(ts))
+        }
+    }
+
+    pub(crate) fn first(&self) -> Option<DateTime<Utc>> {
+        self.chunks.first().map(|c| c[0].0)
+    }
+
+    pub(crate) fn last(&self) -> Option<DateTime<Utc>> {
+        self.chunks.last().map(|c| c[c.len() - 1].0)
+    }
+
+    fn push(&mut self, ts: DateTime<Utc>, pos: usize) {
+        match self.chunks.last_mut() {
+            Some(c) if c.len() < CHUNK => Arc::make_mut(c).push((ts, pos)),
+            None | Some(_) => {
+                let mut c = Vec::with_capacity(CHUNK);
+                c.push((ts, pos));
+                self.chunks.push(Arc::new(c))
+            }
+        }
+        self.len += 1;
+    }
+
+    /// Insert the record at `pos` with timestamp `ts`, replacing the
+    /// position of any existing record with the same timestamp.
+    pub(crate) fn insert(&mut self, ts: DateTime<Utc>, pos: usize) {
+        match self.last() {
+            None => self.push(ts, pos),
+            Some(last) if last < ts => self.push(ts, pos),
+            Some(_) => {
+                // timestamps are monotonic in archives written by
+                // ArchiveWriter, so this is very rare
+                let i = self.partition_point(|t| *t < ts);
+                if i < self.len && self.get(i).0 == ts {
+                    Arc::make_mut(&mut self.chunks[i / CHUNK])[i % CHUNK].1 = pos;
+                } else {
+                    let mut all = self.range(..).collect::<Vec<_>>();
+                    all.insert(i, (ts, pos));
+                    *self = TimeIndex::default();
+                    for (ts, pos) in all {
+                        self.push(ts, pos)
+                    }
+                }
+            }
+        }
+    }
+
+    /// The entries with timestamps in `range`, in order. Unlike
+    /// `BTreeMap::range` an inverted range is empty instead of
+    /// panicking.
+    pub(crate) fn range<R: RangeBounds<DateTime<Utc>>>(&self, range: R) -> Range<'_> {
+        let lo = match range.start_bound() {
+            Bound::Unbounded => 0,
+            Bound::Included(ts) => self.partition_point(|t| t < ts),
+            Bound::Excluded(ts) => self.partition_point(|t| t <= ts),
+        };
+        let hi = match range.end_bound() {
+            Bound::Unbounded => self.len,
+            Bound::Included(ts) => self.partition_point(|t| t <= ts),
+            Bound::Excluded(ts) => self.partition_point(|t| t < ts),
+        };
+        Range { index: self, lo, hi: max(lo, hi) }
+    }
+}
+
+/// An iterator over part of a `TimeIndex`, see `TimeIndex::range`
+pub(crate) struct Range<'a> {
+    index: &'a TimeIndex,
+    lo: usize,
+    hi: usize,
+}
+
+impl Iterator for Range<'_> {
+    type Item = (DateTime<Utc>, usize);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.lo < self.hi {
+            self.lo += 1;
+            Some(self.index.get(self.lo - 1))
+        } else {
+            None
+        }
+    }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        (self.hi - self.lo, Some(self.hi - self.lo))
+    }
+}
+
+impl DoubleEndedIterator for Range<'_> {
+    fn next_back(&mut self) -> Option<Self::Item> {
+        if self.lo < self.hi {
+            self.hi -= 1;
+            Some(self.index.get(self.hi))
+        } else {
+            None
+        }
+    }
+}
+
+impl ExactSizeIterator for Range<'_> {}
+
+/// An immutable view of an archive up to `end`. Every record
+/// indexed is within `mmap`.
+#[derive(Debug)]
+pub(crate) struct Snapshot {
+    pub(crate) mmap: Arc<Mmap>,
+    pub(crate) imagemap: TimeIndex,
+    pub(crate) deltamap: TimeIndex,
+    pub(crate) end: usize,
+}
+
+/// Holds the current snapshot of an archive. Loading it never blocks,
+/// even while it is being replaced.
+pub(crate) struct SnapshotCell(Atomic<Arc<Snapshot>>);
+
+impl fmt::Debug for SnapshotCell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "SnapshotCell({:?})", self.load())
+    }
+}
+
+impl Drop for SnapshotCell {
+    fn drop(&mut self) {
+        // we have &mut self, so nobody else can be reading the cell
+        unsafe {
+            let guard = epoch::unprotected();
+            drop(self.0.load(Ordering::Relaxed, guard).into_owned())
+        }
+    }
+}
+
+impl SnapshotCell {
+    pub(crate) fn new(snap: Snapshot) -> Self {
+        SnapshotCell(Atomic::new(Arc::new(snap)))
+    }
+
+    pub(crate) fn load(&self) -> Arc<Snapshot> {
+        let guard = epoch::pin();
+        let snap = self.0.load(Ordering::Acquire, &guard);
+        // the cell is never null, and a replaced snapshot isn't freed
+        // until every thread that was pinned when it was replaced,
+        // including this one, has unpinned.
+        unsafe { snap.deref() }.clone()
+    }
+
+    pub(crate) fn store(&self, snap: Arc<Snapshot>) {
+        let guard = epoch::pin();
+        let old = self.0.swap(Owned::new(snap), Ordering::AcqRel, &guard);
+        unsafe { guard.defer_destroy(old) }
+    }
+}
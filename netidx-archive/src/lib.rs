@@ -5,11 +5,14 @@ extern crate lazy_static;
 #[macro_use]
 extern crate anyhow;
 
+use activity::{Activity, ActivityLog};
 use anyhow::{Context, Error, Result};
 use bytes::{Buf, BufMut};
+use cache::BlockCache;
 use chrono::prelude::*;
 use fs3::{allocation_granularity, FileExt};
 use fxhash::FxBuildHasher;
+use index::{Snapshot, SnapshotCell, TimeIndex};
 use indexmap::IndexMap;
 use log::warn;
 use mapr::{Mmap, MmapMut};
@@ -18,30 +21,66 @@ use netidx::{
     pack::{decode_varint, encode_varint, varint_len, Pack, PackError},
     path::Path,
     pool::{Pool, Pooled},
+    protocol::glob::GlobSet,
     subscriber::{Event, FromValue, Value},
 };
 use packed_struct::PackedStruct;
-use parking_lot::{
-    lock_api::{RwLockUpgradableReadGuard, RwLockWriteGuard},
-    RwLock,
-};
+use parking_lot::{Mutex, RwLock};
 use std::{
     self,
     cmp::max,
     collections::{BTreeMap, HashMap, VecDeque},
     error, fmt,
     fs::{File, OpenOptions},
-    iter::IntoIterator,
+    iter::{self, IntoIterator},
     mem,
     ops::{Bound, Drop, RangeBounds},
     path::Path as FilePath,
     str::FromStr,
     sync::{
-        atomic::{AtomicUsize, Ordering},
+        atomic::{AtomicU64, AtomicUsize, Ordering},
         Arc,
     },
 };
 
+pub mod activity;
+pub mod align;
+pub mod cache;
+pub mod federated;
+mod index;
+pub mod tiered;
+
+#[cfg(all(target_os = "linux", feature = "io_uring"))]
+mod uring;
+
+#[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+mod uring {
+    use anyhow::Result;
+    use std::fs::File;
+
+    #[derive(Debug)]
+    pub(crate) enum Syncer {}
+
+    impl Syncer {
+        pub(crate) fn new() -> Result<Syncer> {
+            bail!("io_uring support requires linux and the io_uring feature")
+        }
+
+        pub(crate) fn sync(
+            &mut self,
+            _: &File,
+            _: usize,
+            _: usize,
+        ) -> Result<Option<usize>> {
+            match *self {}
+        }
+
+        pub(crate) fn wait(&mut self) -> Result<Option<usize>> {
+            match *self {}
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct FileHeader {
     version: u32,
@@ -290,13 +329,14 @@ lazy_static! {
     pub static ref BATCH_POOL: Pool<Vec<BatchItem>> = Pool::new(100, 100000);
     static ref CURSOR_BATCH_POOL: Pool<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>> =
         Pool::new(100, 100000);
-    static ref POS_POOL: Pool<Vec<(DateTime<Utc>, usize)>> = Pool::new(10, 100000);
     static ref IDX_POOL: Pool<Vec<(Id, Path)>> = Pool::new(10, 20_000_000);
     static ref IMG_POOL: Pool<HashMap<Id, Event>> = Pool::new(10, 20_000_000);
     static ref EPSILON: chrono::Duration = chrono::Duration::microseconds(1);
-    static ref TO_READ_POOL: Pool<Vec<usize>> = Pool::new(10, 20_000_000);
 }
 
+// the number of delta batches an ArchiveRange will read at a time
+const RANGE_CHUNK: usize = 100;
+
 #[derive(Debug, Clone, Copy)]
 pub enum Timestamp {
     NewBasis(DateTime<Utc>),
@@ -336,11 +376,19 @@ pub struct MonotonicTimestamper {
     prev: DateTime<Utc>,
     basis: Option<DateTime<Utc>>,
     offset: u32,
+    reset: bool,
 }
 
 impl MonotonicTimestamper {
     pub fn new() -> Self {
-        MonotonicTimestamper { prev: Utc::now(), basis: None, offset: 0 }
+        MonotonicTimestamper { prev: Utc::now(), basis: None, offset: 0, reset: false }
+    }
+
+    /// Make the next timestamp a `NewBasis`, e.g. because it will be
+    /// the first one written to a new archive. Timestamps remain
+    /// monotonic.
+    pub fn reset(&mut self) {
+        self.reset = true;
     }
 
     fn update_basis(&mut self, new_basis: DateTime<Utc>) -> DateTime<Utc> {
@@ -371,6 +419,12 @@ impl MonotonicTimestamper {
         let now = Utc::now();
         let ts = match self.basis {
             None => Timestamp::NewBasis(self.update_basis(now)),
+            Some(basis) if self.reset => {
+                self.reset = false;
+                let last = basis + Duration::microseconds(self.offset as i64);
+                let next = now.max(last + Duration::microseconds(1));
+                Timestamp::NewBasis(self.update_basis(next))
+            }
             Some(basis) => match (now - self.prev).num_microseconds() {
                 Some(off) if off <= 0 => {
                     if self.offset < MAX_TIMESTAMP {
@@ -514,8 +568,8 @@ impl error::Error for RecordTooLarge {}
 fn scan_records(
     path_by_id: &mut IndexMap<Id, Path, FxBuildHasher>,
     id_by_path: &mut HashMap<Path, Id>,
-    mut imagemap: Option<&mut BTreeMap<DateTime<Utc>, usize>>,
-    mut deltamap: Option<&mut BTreeMap<DateTime<Utc>, usize>>,
+    mut imagemap: Option<&mut TimeIndex>,
+    mut deltamap: Option<&mut TimeIndex>,
     time_basis: &mut DateTime<Utc>,
     max_id: &mut u64,
     end: usize,
@@ -583,8 +637,8 @@ fn scan_records(
 fn scan_file(
     path_by_id: &mut IndexMap<Id, Path, FxBuildHasher>,
     id_by_path: &mut HashMap<Path, Id>,
-    imagemap: Option<&mut BTreeMap<DateTime<Utc>, usize>>,
-    deltamap: Option<&mut BTreeMap<DateTime<Utc>, usize>>,
+    imagemap: Option<&mut TimeIndex>,
+    deltamap: Option<&mut TimeIndex>,
     time_basis: &mut DateTime<Utc>,
     max_id: &mut u64,
     buf: &mut impl Buf,
@@ -614,6 +668,32 @@ fn scan_file(
     )
 }
 
+/// How an [ArchiveWriter](ArchiveWriter) gets flushed data to disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Backend {
+    /// Flush synchronously syncs the memory map to disk. Works
+    /// everywhere, and is the default.
+    #[default]
+    Portable,
+    /// Flush starts an fdatasync on an io_uring and returns
+    /// immediately. The committed marker is advanced by a later flush
+    /// once the sync has completed. Only available on Linux with the
+    /// `io_uring` feature enabled.
+    IoUring,
+}
+
+impl FromStr for Backend {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "portable" => Ok(Backend::Portable),
+            "io_uring" | "io-uring" => Ok(Backend::IoUring),
+            s => bail!("invalid archive backend {}, expected portable or io_uring", s),
+        }
+    }
+}
+
 /// This reads and writes the netidx archive format (as written by the
 /// "record" command in the tools). The archive format is intended to
 /// be a compact format for storing recordings of netidx data for long
@@ -694,10 +774,18 @@ pub struct ArchiveWriter {
     next_id: u64,
     block_size: usize,
     mmap: MmapMut,
+    uring: Option<uring::Syncer>,
+    activity: Arc<RwLock<Activity>>,
+    activity_log: ActivityLog,
 }
 
 impl Drop for ArchiveWriter {
     fn drop(&mut self) {
+        if let Some(mut uring) = self.uring.take() {
+            if let Ok(Some(end)) = uring.wait() {
+                self.commit(end)
+            }
+        }
         let _ = self.flush();
         let _ = self.mmap.flush(); // for the committed header
     }
@@ -707,15 +795,30 @@ impl ArchiveWriter {
     /// Open the specified archive for read/write access, if the file
     /// does not exist then a new archive will be created.
     pub fn open(path: impl AsRef<FilePath>) -> Result<Self> {
+        Self::open_with_backend(path, Backend::Portable)
+    }
+
+    /// Open the specified archive for read/write access using the
+    /// specified backend to flush. This will fail if the backend
+    /// isn't supported on this platform.
+    pub fn open_with_backend(
+        path: impl AsRef<FilePath>,
+        backend: Backend,
+    ) -> Result<Self> {
         if mem::size_of::<usize>() < mem::size_of::<u64>() {
             warn!("archive file size is limited to 4 GiB on this platform")
         }
+        let uring = match backend {
+            Backend::Portable => None,
+            Backend::IoUring => Some(uring::Syncer::new()?),
+        };
         let mut time_basis = DateTime::<Utc>::MIN_UTC;
         if FilePath::is_file(path.as_ref()) {
             let file = OpenOptions::new().read(true).write(true).open(path.as_ref())?;
             file.try_lock_exclusive()?;
-            let block_size = allocation_granularity(path)? as usize;
+            let block_size = allocation_granularity(path.as_ref())? as usize;
             let mmap = unsafe { MmapMut::map_mut(&file)? };
+            let activity = Activity::load(&activity::index_path(path.as_ref()))?;
             let mut t = ArchiveWriter {
                 path_by_id: IndexMap::with_hasher(FxBuildHasher::default()),
                 id_by_path: HashMap::new(),
@@ -725,6 +828,9 @@ impl ArchiveWriter {
                 next_id: 0,
                 block_size,
                 mmap,
+                uring,
+                activity: Arc::new(RwLock::new(activity)),
+                activity_log: ActivityLog::open(path.as_ref(), false)?,
             };
             let end = scan_file(
                 &mut t.path_by_id,
@@ -764,6 +870,9 @@ impl ArchiveWriter {
                 next_id: 0,
                 block_size,
                 mmap,
+                uring,
+                activity: Arc::new(RwLock::new(Activity::default())),
+                activity_log: ActivityLog::open(path.as_ref(), true)?,
             })
         }
     }
@@ -789,16 +898,33 @@ impl ArchiveWriter {
         Ok(len)
     }
 
+    fn commit(&mut self, end: usize) {
+        let mut buf = &mut self.mmap[COMMITTED_OFFSET..];
+        buf.put_u64(end as u64);
+        self.committed = end;
+    }
+
     /// flush uncommitted changes to disk, mark all flushed records as
     /// committed, and update the end of archive marker. Does nothing
     /// if everything is already committed.
+    ///
+    /// With the `IoUring` backend this only starts the flush, records
+    /// are marked committed by a later call once it has completed.
+    ///
+    /// This also writes out the activity index, see `activity`.
     pub fn flush(&mut self) -> Result<()> {
+        self.activity_log.flush()?;
         let end = self.end.load(Ordering::Relaxed);
-        if self.committed < end {
-            self.mmap.flush()?;
-            let mut buf = &mut self.mmap[COMMITTED_OFFSET..];
-            buf.put_u64(end as u64);
-            self.committed = end;
+        let synced = match &mut self.uring {
+            Some(uring) => uring.sync(&self.file, self.committed, end)?,
+            None if self.committed < end => {
+                self.mmap.flush()?;
+                Some(end)
+            }
+            None => None,
+        };
+        if let Some(end) = synced {
+            self.commit(end)
         }
         Ok(())
     }
@@ -894,6 +1020,11 @@ impl ArchiveWriter {
             <RecordHeader as Pack>::encode(&rh, &mut buf)?;
             <Pooled<Vec<BatchItem>> as Pack>::encode(&batch, &mut buf)?;
             self.end.fetch_add(len, Ordering::AcqRel);
+            if !image {
+                let ts = timestamp.datetime();
+                self.activity.write().record(ts, batch.iter().map(|b| b.0));
+                self.activity_log.record(ts, batch.iter().map(|b| b.0));
+            }
         }
         Ok(())
     }
@@ -922,50 +1053,83 @@ impl ArchiveWriter {
     /// read-only duplicate of the memory map.
     ///
     /// If you need lots of readers it's best to create just one using
-    /// this method, and then clone it, that way the same memory map
-    /// can be shared by all the readers.
+    /// this method, and then clone it, that way the same memory map,
+    /// index, and cached batches can be shared by all the readers.
     pub fn reader(&self) -> Result<ArchiveReader> {
-        Ok(ArchiveReader {
-            index: Arc::new(RwLock::new(ArchiveIndex::new())),
-            file: self.file.clone(),
-            end: self.end.clone(),
-            mmap: Arc::new(RwLock::new(unsafe { Mmap::map(&self.file)? })),
-        })
+        let snap = Snapshot {
+            mmap: Arc::new(unsafe { Mmap::map(&*self.file)? }),
+            imagemap: TimeIndex::default(),
+            deltamap: TimeIndex::default(),
+            end: <FileHeader as Pack>::const_encoded_len().unwrap(),
+        };
+        Ok(ArchiveReader::new(
+            snap,
+            PathIndex::default(),
+            DateTime::<Utc>::MIN_UTC,
+            self.file.clone(),
+            self.end.clone(),
+            self.activity.clone(),
+        ))
     }
 }
 
-#[derive(Debug)]
-struct ArchiveIndex {
+#[derive(Debug, Default)]
+struct PathIndex {
     path_by_id: IndexMap<Id, Path, FxBuildHasher>,
     id_by_path: HashMap<Path, Id>,
-    imagemap: BTreeMap<DateTime<Utc>, usize>,
-    deltamap: BTreeMap<DateTime<Utc>, usize>,
-    time_basis: DateTime<Utc>,
-    end: usize,
 }
 
-impl ArchiveIndex {
-    fn new() -> Self {
-        ArchiveIndex {
-            path_by_id: IndexMap::with_hasher(FxBuildHasher::default()),
-            id_by_path: HashMap::new(),
-            imagemap: BTreeMap::new(),
-            deltamap: BTreeMap::new(),
-            time_basis: DateTime::<Utc>::MIN_UTC,
-            end: <FileHeader as Pack>::const_encoded_len().unwrap(),
-        }
-    }
-}
+// every reader gets a distinct id to key it's batches in the cache
+static NEXT_READER: AtomicU64 = AtomicU64::new(0);
 
+/// Reads an archive, see [ArchiveWriter](ArchiveWriter) for a
+/// description of the format.
+///
+/// Cloning an `ArchiveReader` is cheap, and all the clones share the
+/// same memory map, index, and block cache. Any number of clones can read from any
+/// number of cursors concurrently without blocking each other. Each
+/// read works from an immutable snapshot of the index, and when the
+/// archive grows a new snapshot is built and swapped in without
+/// disturbing reads that are in progress.
+///
+/// Decoded batches are kept in a [BlockCache](cache::BlockCache), so
+/// that many sessions reading the same part of the archive only
+/// decode each batch once.
 #[derive(Debug, Clone)]
 pub struct ArchiveReader {
-    index: Arc<RwLock<ArchiveIndex>>,
+    snapshot: Arc<SnapshotCell>,
+    paths: Arc<RwLock<PathIndex>>,
+    // held while indexing new records, it is the time basis of the
+    // last record indexed
+    scan: Arc<Mutex<DateTime<Utc>>>,
     file: Arc<File>,
     end: Arc<AtomicUsize>,
-    mmap: Arc<RwLock<Mmap>>,
+    activity: Arc<RwLock<Activity>>,
+    id: u64,
+    cache: BlockCache,
 }
 
 impl ArchiveReader {
+    fn new(
+        snap: Snapshot,
+        paths: PathIndex,
+        time_basis: DateTime<Utc>,
+        file: Arc<File>,
+        end: Arc<AtomicUsize>,
+        activity: Arc<RwLock<Activity>>,
+    ) -> Self {
+        ArchiveReader {
+            snapshot: Arc::new(SnapshotCell::new(snap)),
+            paths: Arc::new(RwLock::new(paths)),
+            scan: Arc::new(Mutex::new(time_basis)),
+            file,
+            end,
+            activity,
+            id: NEXT_READER.fetch_add(1, Ordering::Relaxed),
+            cache: cache::DEFAULT.clone(),
+        }
+    }
+
     /// Open the specified archive read only. Note, it is possible to
     /// read and write to an archive simultaneously, however to do so
     /// you must open an [ArchiveWriter](ArchiveWriter) and then use
@@ -975,79 +1139,142 @@ impl ArchiveReader {
         let file = OpenOptions::new().read(true).open(path.as_ref())?;
         file.try_lock_shared()?;
         let mmap = unsafe { Mmap::map(&file)? };
-        let mut index = ArchiveIndex::new();
+        let mut paths = PathIndex::default();
+        let mut imagemap = TimeIndex::default();
+        let mut deltamap = TimeIndex::default();
+        let mut time_basis = DateTime::<Utc>::MIN_UTC;
         let mut max_id = 0;
         let end = scan_file(
-            &mut index.path_by_id,
-            &mut index.id_by_path,
-            Some(&mut index.imagemap),
-            Some(&mut index.deltamap),
-            &mut index.time_basis,
+            &mut paths.path_by_id,
+            &mut paths.id_by_path,
+            Some(&mut imagemap),
+            Some(&mut deltamap),
+            &mut time_basis,
             &mut max_id,
             &mut &*mmap,
         )?;
-        index.end = end;
-        Ok(ArchiveReader {
-            index: Arc::new(RwLock::new(index)),
-            file: Arc::new(file),
-            end: Arc::new(AtomicUsize::new(end)),
-            mmap: Arc::new(RwLock::new(mmap)),
-        })
+        let activity = Activity::load(&activity::index_path(path.as_ref()))?;
+        let snap = Snapshot { mmap: Arc::new(mmap), imagemap, deltamap, end };
+        Ok(ArchiveReader::new(
+            snap,
+            paths,
+            time_basis,
+            Arc::new(file),
+            Arc::new(AtomicUsize::new(end)),
+            Arc::new(RwLock::new(activity)),
+        ))
+    }
+
+    /// Use `cache` to cache decoded batches instead of the default
+    /// cache shared by every reader in the process. The returned
+    /// reader, and it's clones, use `cache`, the original is
+    /// unaffected.
+    pub fn with_block_cache(mut self, cache: BlockCache) -> Self {
+        self.cache = cache;
+        self
+    }
+
+    /// The cache used by this reader
+    pub fn block_cache(&self) -> &BlockCache {
+        &self.cache
+    }
+
+    // The current snapshot, without checking for new records
+    pub(crate) fn snapshot(&self) -> Arc<Snapshot> {
+        self.snapshot.load()
     }
 
     pub fn capacity(&self) -> usize {
-        self.mmap.read().len()
+        self.snapshot().mmap.len()
     }
 
     pub fn delta_batches(&self) -> usize {
-        self.index.read().deltamap.len()
+        self.snapshot().deltamap.len()
     }
 
     pub fn image_batches(&self) -> usize {
-        self.index.read().imagemap.len()
+        self.snapshot().imagemap.len()
+    }
+
+    /// The timestamps of the first and last batches in the archive,
+    /// image or delta, or None if the archive is empty.
+    pub fn time_range(&self) -> Option<(DateTime<Utc>, DateTime<Utc>)> {
+        let snap = self.snapshot();
+        let first = [snap.imagemap.first(), snap.deltamap.first()];
+        let last = [snap.imagemap.last(), snap.deltamap.last()];
+        let first = first.into_iter().flatten().min()?;
+        let last = last.into_iter().flatten().max()?;
+        Some((first, last))
     }
 
     pub fn id_for_path(&self, path: &Path) -> Option<Id> {
-        self.index.read().id_by_path.get(path).copied()
+        self.paths.read().id_by_path.get(path).copied()
     }
 
     pub fn path_for_id(&self, id: &Id) -> Option<Path> {
-        self.index.read().path_by_id.get(id).cloned()
+        self.paths.read().path_by_id.get(id).cloned()
     }
 
     /// Check if the memory map needs to be remapped due to growth,
     /// and check if additional records exist that need to be
     /// indexed. This method is only relevant if this `ArchiveReader`
     /// was created from an `ArchiveWriter`, this method is called
-    /// automatically by `read_deltas` and `build_image`.
+    /// automatically by `read_deltas` and `build_image`, except that
+    /// they won't wait if another reader is already indexing, and
+    /// will read the snapshot from before the new records instead.
     pub fn check_remap_rescan(&self) -> Result<()> {
+        self.refresh(true).map(|_| ())
+    }
+
+    // Index any records that were written since the current snapshot
+    // was taken, and return the up to date snapshot. If `wait` is
+    // false, and another thread is already doing this, then just
+    // return the current snapshot.
+    fn refresh(&self, wait: bool) -> Result<Arc<Snapshot>> {
         let end = self.end.load(Ordering::Acquire);
-        let mmap = self.mmap.upgradable_read();
-        let mmap = if end > mmap.len() {
-            let mut mmap = RwLockUpgradableReadGuard::upgrade(mmap);
-            drop(mem::replace(&mut *mmap, unsafe { Mmap::map(&*self.file)? }));
-            RwLockWriteGuard::downgrade_to_upgradable(mmap)
+        let snap = self.snapshot();
+        if snap.end >= end {
+            return Ok(snap);
+        }
+        let mut time_basis = if wait {
+            self.scan.lock()
+        } else {
+            match self.scan.try_lock() {
+                Some(g) => g,
+                None => return Ok(snap),
+            }
+        };
+        // someone else may have indexed them while we were waiting
+        let snap = self.snapshot();
+        if snap.end >= end {
+            return Ok(snap);
+        }
+        let mmap = if end > snap.mmap.len() {
+            Arc::new(unsafe { Mmap::map(&*self.file)? })
         } else {
-            mmap
+            snap.mmap.clone()
         };
-        let index = self.index.upgradable_read();
-        if index.end < end {
-            let mut index = RwLockUpgradableReadGuard::upgrade(index);
-            let mut max_id = 0;
-            let r = &mut *index;
-            r.end = scan_records(
-                &mut r.path_by_id,
-                &mut r.id_by_path,
-                Some(&mut r.imagemap),
-                Some(&mut r.deltamap),
-                &mut r.time_basis,
+        let mut imagemap = snap.imagemap.clone();
+        let mut deltamap = snap.deltamap.clone();
+        let mut max_id = 0;
+        let new_end = {
+            let mut paths = self.paths.write();
+            let paths = &mut *paths;
+            scan_records(
+                &mut paths.path_by_id,
+                &mut paths.id_by_path,
+                Some(&mut imagemap),
+                Some(&mut deltamap),
+                &mut *time_basis,
                 &mut max_id,
                 end,
-                r.end,
-                &mut &mmap[r.end..end],
-            )?;
-        }
-        Ok(())
+                snap.end,
+                &mut &mmap[snap.end..end],
+            )?
+        };
+        let snap = Arc::new(Snapshot { mmap, imagemap, deltamap, end: new_end });
+        self.snapshot.store(snap.clone());
+        Ok(snap)
     }
 
     /// Move the cursor according to the `Seek` instruction. If the
@@ -1056,21 +1283,22 @@ impl ArchiveReader {
     /// end. If the seek instruction would move the cursor out of
     /// bounds, then it will move to the closest in bounds position.
     pub fn seek(&self, cursor: &mut Cursor, seek: Seek) {
+        let snap = self.snapshot();
         match seek {
-            Seek::Beginning => match self.index.read().deltamap.keys().next() {
+            Seek::Beginning => match snap.deltamap.first() {
                 None => {
                     cursor.current = None;
                 }
                 Some(ts) => {
-                    cursor.set_current(*ts);
+                    cursor.set_current(ts);
                 }
             },
-            Seek::End => match self.index.read().deltamap.keys().next_back() {
+            Seek::End => match snap.deltamap.last() {
                 None => {
                     cursor.current = None;
                 }
                 Some(ts) => {
-                    cursor.set_current(*ts);
+                    cursor.set_current(ts);
                 }
             },
             Seek::Absolute(ts) => {
@@ -1085,12 +1313,10 @@ impl ArchiveReader {
                             Bound::Excluded(ts) => {
                                 cursor.set_current(ts + *EPSILON + offset)
                             }
-                            Bound::Unbounded => {
-                                match self.index.read().deltamap.keys().next() {
-                                    None => (),
-                                    Some(ts) => cursor.set_current(*ts + offset),
-                                }
-                            }
+                            Bound::Unbounded => match snap.deltamap.first() {
+                                None => (),
+                                Some(ts) => cursor.set_current(ts + offset),
+                            },
                         };
                     } else {
                         match cursor.end() {
@@ -1098,38 +1324,35 @@ impl ArchiveReader {
                             Bound::Excluded(ts) => {
                                 cursor.set_current(ts - *EPSILON + offset)
                             }
-                            Bound::Unbounded => {
-                                match self.index.read().deltamap.keys().next_back() {
-                                    None => (),
-                                    Some(ts) => cursor.set_current(*ts + offset),
-                                }
-                            }
+                            Bound::Unbounded => match snap.deltamap.last() {
+                                None => (),
+                                Some(ts) => cursor.set_current(ts + offset),
+                            },
                         }
                     }
                 }
             },
             Seek::BatchRelative(steps) => {
-                let index = self.index.read();
                 if steps >= 0 {
                     let init =
                         cursor.current.map(Bound::Excluded).unwrap_or(cursor.start);
-                    let mut iter = index.deltamap.range((init, cursor.end));
+                    let mut iter = snap.deltamap.range((init, cursor.end));
                     for _ in 0..steps as usize {
                         match iter.next() {
                             None => break,
                             Some((ts, _)) => {
-                                cursor.current = Some(*ts);
+                                cursor.current = Some(ts);
                             }
                         }
                     }
                 } else {
                     let init = cursor.current.map(Bound::Excluded).unwrap_or(cursor.end);
-                    let mut iter = index.deltamap.range((cursor.start, init));
+                    let mut iter = snap.deltamap.range((cursor.start, init));
                     for _ in 0..steps.abs() as usize {
                         match iter.next_back() {
                             None => break,
                             Some((ts, _)) => {
-                                cursor.current = Some(*ts);
+                                cursor.current = Some(ts);
                             }
                         }
                     }
@@ -1146,36 +1369,39 @@ impl ArchiveReader {
         // we must ensure we don't hold the lock for too long in the
         // case where the index is huge.
         'main: loop {
-            let inner = self.index.read();
+            let inner = self.paths.read();
             for _ in 0..1000 {
-                let (id, path) = inner.path_by_id.get_index(i).unwrap();
-                idx.push((*id, path.clone()));
-                i += 1;
                 if i >= inner.path_by_id.len() {
                     break 'main;
                 }
+                let (id, path) = inner.path_by_id.get_index(i).unwrap();
+                idx.push((*id, path.clone()));
+                i += 1;
             }
         }
         idx
     }
 
-    fn get_batch_at(
-        mmap: &Mmap,
-        pos: usize,
-        end: usize,
-    ) -> Result<Pooled<Vec<BatchItem>>> {
-        if pos >= end {
+    // decode the batch at `pos` and return it along with it's
+    // encoded length
+    fn decode_batch_at(snap: &Snapshot, pos: usize) -> Result<(usize, Vec<BatchItem>)> {
+        if pos >= snap.end {
             bail!("record out of bounds")
         } else {
-            let mut buf = &mmap[pos..];
+            let mut buf = &snap.mmap[pos..];
             let rh = <RecordHeader as Pack>::decode(&mut buf)?;
-            if pos + rh.record_length as usize > end {
+            if pos + rh.record_length as usize > snap.end {
                 bail!("get_batch: error truncated record at {}", pos);
             }
-            Ok(<Pooled<Vec<BatchItem>> as Pack>::decode(&mut buf)?)
+            let batch = <Vec<BatchItem> as Pack>::decode(&mut buf)?;
+            Ok((rh.record_length as usize, batch))
         }
     }
 
+    fn get_batch_at(&self, snap: &Snapshot, pos: usize) -> Result<Arc<Vec<BatchItem>>> {
+        self.cache.get_or_decode((self.id, pos), || Self::decode_batch_at(snap, pos))
+    }
+
     /// Builds an image corresponding to the state at the cursor, or
     /// if the cursor has no current position then at the beginning of
     /// the cursor. If the cursor has no position and then beginning
@@ -1185,7 +1411,7 @@ impl ArchiveReader {
     /// the deltas between the closest image that is older, and the
     /// cursor start need to be read.
     pub fn build_image(&self, cursor: &Cursor) -> Result<Pooled<HashMap<Id, Event>>> {
-        self.check_remap_rescan()?;
+        let snap = self.refresh(false)?;
         let pos = match cursor.current {
             None => cursor.start,
             Some(pos) => Bound::Included(pos),
@@ -1193,33 +1419,25 @@ impl ArchiveReader {
         match pos {
             Bound::Unbounded => Ok(Pooled::orphan(HashMap::new())),
             _ => {
-                let (mut to_read, end) = {
-                    // we need to invert the excluded/included to get
-                    // the correct initial state.
-                    let pos = match pos {
-                        Bound::Excluded(t) => Bound::Included(t),
-                        Bound::Included(t) => Bound::Excluded(t),
-                        Bound::Unbounded => unreachable!(),
-                    };
-                    let index = self.index.read();
-                    let mut to_read = TO_READ_POOL.take();
-                    let mut iter = index.imagemap.range((Bound::Unbounded, pos));
-                    let s = match iter.next_back() {
-                        None => Bound::Unbounded,
-                        Some((ts, pos)) => {
-                            to_read.push(*pos);
-                            Bound::Included(*ts)
-                        }
-                    };
-                    to_read.extend(index.deltamap.range((s, pos)).map(|v| v.1));
-                    (to_read, index.end)
+                // we need to invert the excluded/included to get
+                // the correct initial state.
+                let pos = match pos {
+                    Bound::Excluded(t) => Bound::Included(t),
+                    Bound::Included(t) => Bound::Excluded(t),
+                    Bound::Unbounded => unreachable!(),
                 };
                 let mut image = IMG_POOL.take();
-                let mmap = self.mmap.read();
-                for pos in to_read.drain(..) {
-                    let mut batch =
-                        ArchiveReader::get_batch_at(&*mmap, pos as usize, end)?;
-                    image.extend(batch.drain(..).map(|b| (b.0, b.1)));
+                let s = match snap.imagemap.range((Bound::Unbounded, pos)).next_back() {
+                    None => Bound::Unbounded,
+                    Some((ts, at)) => {
+                        let batch = self.get_batch_at(&snap, at)?;
+                        image.extend(batch.iter().map(|b| (b.0, b.1.clone())));
+                        Bound::Included(ts)
+                    }
+                };
+                for (_, at) in snap.deltamap.range((s, pos)) {
+                    let batch = self.get_batch_at(&snap, at)?;
+                    image.extend(batch.iter().map(|b| (b.0, b.1.clone())));
                 }
                 Ok(image)
             }
@@ -1235,34 +1453,305 @@ impl ArchiveReader {
         cursor: &mut Cursor,
         n: usize,
     ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
-        self.check_remap_rescan()?;
-        let mut idxs = POS_POOL.take();
+        let snap = self.refresh(false)?;
         let mut res = CURSOR_BATCH_POOL.take();
         let start = match cursor.current {
             None => cursor.start,
             Some(dt) => Bound::Excluded(dt),
         };
-        let end = {
-            let index = self.index.read();
-            idxs.extend(
-                index
-                    .deltamap
-                    .range((start, cursor.end))
-                    .map(|(ts, pos)| (*ts, *pos))
-                    .take(n),
-            );
-            index.end
-        };
         let mut current = cursor.current;
-        let mmap = self.mmap.read();
-        for (ts, pos) in idxs.drain(..) {
-            let batch = ArchiveReader::get_batch_at(&*mmap, pos as usize, end)?;
+        for (ts, pos) in snap.deltamap.range((start, cursor.end)).take(n) {
+            let mut batch = BATCH_POOL.take();
+            batch.extend(self.get_batch_at(&snap, pos)?.iter().cloned());
             current = Some(ts);
             res.push_back((ts, batch));
         }
         cursor.current = current;
         Ok(res)
     }
+
+    /// Query the archive for all the events on paths matching
+    /// `filter` in the time `range`. The state of every matching path
+    /// at the start of the range is reconstructed and returned first,
+    /// stamped with the range start, followed by every delta in the
+    /// range in order. If the start of the range is unbounded there
+    /// is no initial state, and the deltas start at the beginning of
+    /// the archive.
+    ///
+    /// This is intended for analytics jobs that want to scan an
+    /// archive without driving a cursor themselves. Deltas are read
+    /// lazily as the iterator is consumed, so it is fine to query a
+    /// very large range.
+    pub fn range<R: RangeBounds<DateTime<Utc>>>(
+        &self,
+        filter: &GlobSet,
+        range: R,
+    ) -> Result<ArchiveRange> {
+        let mut cursor = Cursor::new();
+        cursor.set_start(range.start_bound().cloned());
+        cursor.set_end(range.end_bound().cloned());
+        let mut t = ArchiveRange {
+            reader: self.clone(),
+            filter: filter.clone(),
+            cursor,
+            paths: HashMap::new(),
+            pending: VecDeque::new(),
+            error: None,
+            done: false,
+        };
+        let start = match cursor.start() {
+            Bound::Unbounded => None,
+            Bound::Included(ts) | Bound::Excluded(ts) => Some(ts),
+        };
+        if let Some(start) = start {
+            let mut image = self.build_image(&cursor)?;
+            let mut image = image.drain().collect::<Vec<_>>();
+            image.sort_unstable_by_key(|(id, _)| *id);
+            for (id, ev) in image {
+                if let Some(path) = t.path(id) {
+                    t.pending.push_back((start, path, ev));
+                }
+            }
+        }
+        Ok(t)
+    }
+
+    /// Pivot the updates to paths matching `filter` in `range` into
+    /// a table with one column per path, sampled every `step`, see
+    /// `align`. If the range has a start then the first row is at the
+    /// start, otherwise it is at the multiple of `step` since the
+    /// unix epoch at or before the first update. Likewise the last
+    /// row is at or before the end of the range, or if it is
+    /// unbounded, at the first grid line at or after the last update.
+    pub fn aligned<R: RangeBounds<DateTime<Utc>>>(
+        &self,
+        filter: &GlobSet,
+        range: R,
+        step: chrono::Duration,
+    ) -> Result<align::Aligned> {
+        let columns = self
+            .get_index()
+            .iter()
+            .filter(|(_, path)| filter.is_match(path))
+            .map(|(_, path)| path.clone())
+            .collect::<Vec<_>>();
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+        let range = self.range(filter, (start, end))?;
+        align::Aligned::new(columns, range, start, end, step)
+    }
+
+    /// Count the updates to each path matching `filter` in `range`,
+    /// in buckets `bucket` wide, using the activity index (see
+    /// `activity`) instead of reading the deltas. `bucket` is rounded
+    /// up to a multiple of `activity::BUCKET_SECS`, buckets are
+    /// aligned to multiples of their width since the unix epoch, and
+    /// are keyed by their start. Buckets with no matching updates are
+    /// omitted.
+    pub fn activity<R: RangeBounds<DateTime<Utc>>>(
+        &self,
+        filter: &GlobSet,
+        range: R,
+        bucket: chrono::Duration,
+    ) -> BTreeMap<DateTime<Utc>, HashMap<Path, u64>> {
+        use activity::BUCKET_SECS;
+        let width = max(1, (bucket.num_seconds() + BUCKET_SECS - 1) / BUCKET_SECS);
+        // the index bucket containing the start is in the range
+        let start = match range.start_bound() {
+            Bound::Unbounded => Bound::Unbounded,
+            Bound::Included(ts) | Bound::Excluded(ts) => {
+                Bound::Included(activity::bucket(*ts, BUCKET_SECS))
+            }
+        };
+        let paths = self.paths.read();
+        let mut matched: HashMap<Id, Option<Path>> = HashMap::new();
+        self.activity.read().query(
+            (start, range.end_bound().cloned()),
+            width * BUCKET_SECS,
+            |id| {
+                matched
+                    .entry(id)
+                    .or_insert_with(|| {
+                        paths.path_by_id.get(&id).filter(|p| filter.is_match(p)).cloned()
+                    })
+                    .clone()
+            },
+        )
+    }
+
+    /// Write the events on paths matching `filter` in the time
+    /// `range` to a new standalone archive at `dest`. If the start of
+    /// the range is bounded then the state of every matching path at
+    /// the start is written first as an image batch, followed by the
+    /// matching part of every delta batch in the range, with it's
+    /// original timestamp. `dest` must not already exist. Returns the
+    /// number of delta batches written.
+    pub fn export<R: RangeBounds<DateTime<Utc>>>(
+        &self,
+        filter: &GlobSet,
+        range: R,
+        dest: impl AsRef<FilePath>,
+    ) -> Result<usize> {
+        if dest.as_ref().exists() {
+            bail!("export destination {} already exists", dest.as_ref().display())
+        }
+        let mut writer = ArchiveWriter::open(dest)?;
+        let mut ids: HashMap<Id, Option<Id>> = HashMap::new();
+        let mut map = |writer: &mut ArchiveWriter, id: Id| -> Result<Option<Id>> {
+            match ids.get(&id) {
+                Some(new_id) => Ok(*new_id),
+                None => {
+                    let new_id = match self.path_for_id(&id) {
+                        Some(path) if filter.is_match(&path) => {
+                            writer.add_paths(iter::once(&path))?;
+                            writer.id_for_path(&path)
+                        }
+                        Some(_) | None => None,
+                    };
+                    ids.insert(id, new_id);
+                    Ok(new_id)
+                }
+            }
+        };
+        let mut basis: Option<DateTime<Utc>> = None;
+        let mut timestamp = |ts: DateTime<Utc>| match basis {
+            Some(b) if b <= ts => match (ts - b).num_microseconds() {
+                Some(off) if off <= MAX_TIMESTAMP as i64 => {
+                    Timestamp::Offset(b, off as u32)
+                }
+                None | Some(_) => {
+                    basis = Some(ts);
+                    Timestamp::NewBasis(ts)
+                }
+            },
+            Some(_) | None => {
+                basis = Some(ts);
+                Timestamp::NewBasis(ts)
+            }
+        };
+        let mut cursor = Cursor::new();
+        cursor.set_start(range.start_bound().cloned());
+        cursor.set_end(range.end_bound().cloned());
+        // the image is the state just before an included start, or
+        // at an excluded start, stamp it accordingly so that seeking
+        // to the start of the exported archive finds it.
+        let image_ts = match cursor.start() {
+            Bound::Unbounded => None,
+            Bound::Excluded(ts) => Some(ts),
+            Bound::Included(ts) => Some(ts - chrono::Duration::microseconds(1)),
+        };
+        if let Some(image_ts) = image_ts {
+            let mut image = self.build_image(&cursor)?;
+            let mut batch = BATCH_POOL.take();
+            for (id, ev) in image.drain() {
+                if let Some(id) = map(&mut writer, id)? {
+                    batch.push(BatchItem(id, ev));
+                }
+            }
+            batch.sort_unstable_by_key(|b| b.0);
+            writer.add_batch(true, timestamp(image_ts), &batch)?;
+        }
+        let mut n = 0;
+        loop {
+            let mut batches = self.read_deltas(&mut cursor, RANGE_CHUNK)?;
+            if batches.is_empty() {
+                break;
+            }
+            for (ts, mut batch) in batches.drain(..) {
+                let mut exported = BATCH_POOL.take();
+                for BatchItem(id, ev) in batch.drain(..) {
+                    if let Some(id) = map(&mut writer, id)? {
+                        exported.push(BatchItem(id, ev));
+                    }
+                }
+                if !exported.is_empty() {
+                    writer.add_batch(false, timestamp(ts), &exported)?;
+                    n += 1;
+                }
+            }
+        }
+        writer.flush()?;
+        Ok(n)
+    }
+}
+
+/// An iterator over the events in a time range of an archive. See
+/// [ArchiveReader::range](ArchiveReader::range).
+///
+/// If reading the archive fails part way through then iteration will
+/// end early, and the error can be retrieved with
+/// [ArchiveRange::error](ArchiveRange::error).
+pub struct ArchiveRange {
+    reader: ArchiveReader,
+    filter: GlobSet,
+    cursor: Cursor,
+    paths: HashMap<Id, Option<Path>>,
+    pending: VecDeque<(DateTime<Utc>, Path, Event)>,
+    error: Option<Error>,
+    done: bool,
+}
+
+impl ArchiveRange {
+    fn path(&mut self, id: Id) -> Option<Path> {
+        let reader = &self.reader;
+        let filter = &self.filter;
+        self.paths
+            .entry(id)
+            .or_insert_with(|| {
+                reader.path_for_id(&id).and_then(|path| {
+                    if filter.is_match(&path) {
+                        Some(path)
+                    } else {
+                        None
+                    }
+                })
+            })
+            .clone()
+    }
+
+    fn fill(&mut self) {
+        while self.pending.is_empty() && !self.done {
+            match self.reader.read_deltas(&mut self.cursor, RANGE_CHUNK) {
+                Err(e) => {
+                    self.error = Some(e);
+                    self.done = true;
+                }
+                Ok(mut batches) => {
+                    if batches.is_empty() {
+                        self.done = true;
+                    }
+                    for (ts, mut batch) in batches.drain(..) {
+                        for BatchItem(id, ev) in batch.drain(..) {
+                            if let Some(path) = self.path(id) {
+                                self.pending.push_back((ts, path, ev));
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// The cursor position of the range, this is the timestamp of
+    /// the last delta batch that was read.
+    pub fn current(&self) -> Option<DateTime<Utc>> {
+        self.cursor.current()
+    }
+
+    /// If reading the archive failed then this will return the
+    /// error that ended the iteration.
+    pub fn error(&self) -> Option<&Error> {
+        self.error.as_ref()
+    }
+}
+
+impl Iterator for ArchiveRange {
+    type Item = (DateTime<Utc>, Path, Event);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.fill();
+        self.pending.pop_front()
+    }
 }
 
 #[cfg(test)]
@@ -1271,6 +1760,15 @@ mod test {
     use netidx::subscriber::Value;
     use std::fs;
 
+    // remove an archive and its activity index
+    fn remove(file: &FilePath) {
+        for f in [file.to_path_buf(), activity::index_path(file)] {
+            if f.is_file() {
+                fs::remove_file(f).unwrap();
+            }
+        }
+    }
+
     fn check_contents(t: &ArchiveReader, paths: &[Path], batches: usize) {
         t.check_remap_rescan().unwrap();
         assert_eq!(t.delta_batches(), batches);
@@ -1293,9 +1791,7 @@ mod test {
         let file = FilePath::new("test-data");
         let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
         let mut timestamper = MonotonicTimestamper::new();
-        if FilePath::is_file(&file) {
-            fs::remove_file(file).unwrap();
-        }
+        remove(file);
         let mut batch = BATCH_POOL.take();
         let initial_size = {
             // check that we can open, and write an archive
@@ -1352,8 +1848,572 @@ mod test {
             check_contents(&reader, &paths, n);
             assert!(ArchiveWriter::open(&file).is_err());
         }
-        if FilePath::is_file(&file) {
-            fs::remove_file(file).unwrap();
+        remove(file);
+    }
+
+    #[cfg(all(target_os = "linux", feature = "io_uring"))]
+    #[test]
+    fn io_uring_test() {
+        let file = FilePath::new("test-data-uring");
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+        let mut timestamper = MonotonicTimestamper::new();
+        remove(file);
+        {
+            let mut t =
+                ArchiveWriter::open_with_backend(&file, Backend::IoUring).unwrap();
+            t.add_paths(&paths).unwrap();
+            let mut batch = BATCH_POOL.take();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(42)))
+            }));
+            for _ in 0..10 {
+                t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+                t.flush().unwrap();
+            }
+            check_contents(&t.reader().unwrap(), &paths, 10);
+        }
+        {
+            // dropping the writer waits for the sync in flight, and
+            // commits everything
+            let t = ArchiveReader::open(&file).unwrap();
+            check_contents(&t, &paths, 10);
+        }
+        remove(file);
+    }
+
+    #[cfg(not(all(target_os = "linux", feature = "io_uring")))]
+    #[test]
+    fn io_uring_unsupported() {
+        let file = FilePath::new("test-data-uring-unsupported");
+        assert!(ArchiveWriter::open_with_backend(&file, Backend::IoUring).is_err());
+        assert!(!FilePath::is_file(&file));
+    }
+
+    #[test]
+    fn range_test() {
+        use netidx::{chars::Chars, protocol::glob::Glob};
+        use std::iter;
+        let file = FilePath::new("test-data-range");
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+        let mut timestamper = MonotonicTimestamper::new();
+        remove(file);
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        let mut stamps = vec![];
+        for i in 0..10u64 {
+            let mut batch = BATCH_POOL.take();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(i)))
+            }));
+            let ts = timestamper.timestamp();
+            stamps.push(ts.datetime());
+            t.add_batch(false, ts, &batch).unwrap();
+        }
+        t.flush().unwrap();
+        let r = t.reader().unwrap();
+        let glob = Glob::new(Chars::from("/foo/bar")).unwrap();
+        let filter = GlobSet::new(true, iter::once(glob)).unwrap();
+        // the whole archive, only /foo/bar
+        let all = r.range(&filter, ..).unwrap().collect::<Vec<_>>();
+        assert_eq!(all.len(), 10);
+        for (i, (ts, path, ev)) in all.iter().enumerate() {
+            assert_eq!(*ts, stamps[i]);
+            assert_eq!(path, &paths[0]);
+            assert_eq!(ev, &Event::Update(Value::U64(i as u64)));
+        }
+        // a range in the middle starts with the reconstructed image
+        let mid = r.range(&filter, stamps[5]..stamps[8]).unwrap().collect::<Vec<_>>();
+        assert_eq!(mid.len(), 4);
+        assert_eq!(mid[0], (stamps[5], paths[0].clone(), Event::Update(Value::U64(4))));
+        for (i, (ts, _, ev)) in mid[1..].iter().enumerate() {
+            assert_eq!(*ts, stamps[5 + i]);
+            assert_eq!(ev, &Event::Update(Value::U64(5 + i as u64)));
+        }
+        drop(r);
+        drop(t);
+        remove(file);
+    }
+
+    #[test]
+    fn activity_test() {
+        use netidx::{chars::Chars, protocol::glob::Glob};
+        use std::iter;
+        let file = FilePath::new("test-data-activity");
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz"), Path::from("/qux")];
+        remove(file);
+        let base = Utc.timestamp_opt(1_000_000 * activity::BUCKET_SECS, 0).unwrap();
+        let at = |secs| Timestamp::NewBasis(base + chrono::Duration::seconds(secs));
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        // /foo/bar changes every 10 seconds for 3 minutes, /foo/baz
+        // only in the second minute, /qux every time.
+        for i in 0..18 {
+            let mut batch = BATCH_POOL.take();
+            for (j, p) in paths.iter().enumerate() {
+                if j != 1 || (6..12).contains(&i) {
+                    let id = t.id_for_path(p).unwrap();
+                    batch.push(BatchItem(id, Event::Update(Value::U64(i as u64))));
+                }
+            }
+            t.add_batch(false, at(i * 10), &batch).unwrap();
+        }
+        t.flush().unwrap();
+        let glob = Glob::new(Chars::from("/foo/*")).unwrap();
+        let filter = GlobSet::new(true, iter::once(glob)).unwrap();
+        let minute = chrono::Duration::seconds(60);
+        let check = |r: &ArchiveReader| {
+            let a = r.activity(&filter, .., minute);
+            assert_eq!(a.len(), 3);
+            let counts = a.values().collect::<Vec<_>>();
+            assert_eq!(counts[0].get(&paths[0]), Some(&6));
+            assert_eq!(counts[0].get(&paths[1]), None);
+            assert_eq!(counts[1].get(&paths[1]), Some(&6));
+            assert!(counts.iter().all(|c| !c.contains_key(&paths[2])));
+            assert_eq!(a.keys().next(), Some(&base));
+            // wider buckets, and a range starting mid bucket
+            let a = r.activity(&filter, at(70).datetime().., minute * 2);
+            assert_eq!(a.len(), 2);
+            let counts = a.values().collect::<Vec<_>>();
+            assert_eq!(counts[0].get(&paths[0]), Some(&6));
+            assert_eq!(counts[0].get(&paths[1]), Some(&6));
+            assert_eq!(counts[1].get(&paths[0]), Some(&6));
+        };
+        check(&t.reader().unwrap());
+        drop(t);
+        check(&ArchiveReader::open(&file).unwrap());
+        remove(file);
+    }
+
+    #[test]
+    fn aligned_test() {
+        use netidx::{chars::Chars, protocol::glob::Glob};
+        use std::iter;
+        let file = FilePath::new("test-data-aligned");
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz"), Path::from("/qux")];
+        remove(file);
+        let base = Utc.timestamp_opt(1_000_000 * 60, 0).unwrap();
+        let at = |secs| Timestamp::NewBasis(base + chrono::Duration::seconds(secs));
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        let events = [
+            (3, 0, Event::Update(Value::U64(0))),
+            (13, 0, Event::Update(Value::U64(1))),
+            (15, 1, Event::Update(Value::U64(100))),
+            (15, 2, Event::Update(Value::U64(42))),
+            (23, 0, Event::Update(Value::U64(2))),
+            (33, 1, Event::Unsubscribed),
+        ];
+        for (secs, i, ev) in events {
+            let mut batch = BATCH_POOL.take();
+            batch.push(BatchItem(t.id_for_path(&paths[i]).unwrap(), ev));
+            t.add_batch(false, at(secs), &batch).unwrap();
+        }
+        t.flush().unwrap();
+        let glob = Glob::new(Chars::from("/foo/*")).unwrap();
+        let filter = GlobSet::new(true, iter::once(glob)).unwrap();
+        let r = t.reader().unwrap();
+        let secs = chrono::Duration::seconds;
+        let u = |v| Some(Value::U64(v));
+        // the whole archive, starting on the grid line before the
+        // first update and ending on the one after the last
+        let a = r.aligned(&filter, .., secs(10)).unwrap();
+        assert_eq!(a.columns(), &paths[0..2]);
+        let rows = a.collect::<Vec<_>>();
+        let expected = [
+            (0, vec![None, None]),
+            (10, vec![u(0), None]),
+            (20, vec![u(1), u(100)]),
+            (30, vec![u(2), u(100)]),
+            (40, vec![u(2), None]),
+        ];
+        assert_eq!(rows.len(), expected.len());
+        for ((ts, row), (secs, expected)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(*ts, at(*secs).datetime());
+            assert_eq!(row, expected);
+        }
+        // a range in the middle starts with the state at the start
+        let range = at(10).datetime()..at(25).datetime();
+        let rows = r.aligned(&filter, range, secs(5)).unwrap().collect::<Vec<_>>();
+        let expected =
+            [(10, vec![u(0), None]), (15, vec![u(1), u(100)]), (20, vec![u(1), u(100)])];
+        assert_eq!(rows.len(), expected.len());
+        for ((ts, row), (secs, expected)) in rows.iter().zip(expected.iter()) {
+            assert_eq!(*ts, at(*secs).datetime());
+            assert_eq!(row, expected);
+        }
+        assert!(r.aligned(&filter, .., secs(0)).is_err());
+        drop(r);
+        drop(t);
+        remove(file);
+    }
+
+    #[test]
+    fn export_test() {
+        use netidx::{chars::Chars, protocol::glob::Glob};
+        use std::iter;
+        let file = FilePath::new("test-data-export-src");
+        let dest = FilePath::new("test-data-export-dst");
+        let paths = [Path::from("/foo/bar"), Path::from("/foo/baz")];
+        let mut timestamper = MonotonicTimestamper::new();
+        for f in [file, dest] {
+            remove(f);
+        }
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(&paths).unwrap();
+        let mut stamps = vec![];
+        for i in 0..10u64 {
+            let mut batch = BATCH_POOL.take();
+            batch.extend(paths.iter().map(|p| {
+                BatchItem(t.id_for_path(p).unwrap(), Event::Update(Value::U64(i)))
+            }));
+            let ts = timestamper.timestamp();
+            stamps.push(ts.datetime());
+            t.add_batch(false, ts, &batch).unwrap();
+        }
+        t.flush().unwrap();
+        let r = t.reader().unwrap();
+        let glob = Glob::new(Chars::from("/foo/bar")).unwrap();
+        let filter = GlobSet::new(true, iter::once(glob)).unwrap();
+        assert_eq!(r.export(&filter, stamps[5]..stamps[8], &dest).unwrap(), 3);
+        assert!(r.export(&filter, .., &dest).is_err());
+        let e = ArchiveReader::open(&dest).unwrap();
+        assert_eq!(e.delta_batches(), 3);
+        assert_eq!(e.image_batches(), 1);
+        assert!(e.id_for_path(&paths[1]).is_none());
+        let glob = Glob::new(Chars::from("/**")).unwrap();
+        let all = GlobSet::new(true, iter::once(glob)).unwrap();
+        let exported = e.range(&all, stamps[5]..).unwrap().collect::<Vec<_>>();
+        assert_eq!(
+            exported[0],
+            (stamps[5], paths[0].clone(), Event::Update(Value::U64(4)))
+        );
+        assert_eq!(exported.len(), 4);
+        for (i, (ts, path, ev)) in exported[1..].iter().enumerate() {
+            assert_eq!(*ts, stamps[5 + i]);
+            assert_eq!(path, &paths[0]);
+            assert_eq!(ev, &Event::Update(Value::U64(5 + i as u64)));
+        }
+        drop(e);
+        drop(r);
+        drop(t);
+        for f in [file, dest] {
+            remove(f);
+        }
+    }
+
+    #[test]
+    fn federated_test() {
+        use federated::FederatedReader;
+        let files = [
+            FilePath::new("test-data-federated-a"),
+            FilePath::new("test-data-federated-b"),
+        ];
+        let shared = Path::from("/shared");
+        let paths = [Path::from("/a"), Path::from("/b")];
+        let base = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        let at = |secs| base + chrono::Duration::seconds(secs);
+        for f in files {
+            remove(f);
+        }
+        // a writes at even seconds, b at odd seconds, and both at 8
+        for (i, f) in files.iter().enumerate() {
+            let mut t = ArchiveWriter::open(f).unwrap();
+            t.add_paths([&shared, &paths[i]]).unwrap();
+            for secs in (i as i64..8).step_by(2).chain(iter::once(8)) {
+                let mut batch = BATCH_POOL.take();
+                for p in [&shared, &paths[i]] {
+                    let id = t.id_for_path(p).unwrap();
+                    batch.push(BatchItem(id, Event::Update(Value::I64(secs))));
+                }
+                t.add_batch(false, Timestamp::NewBasis(at(secs)), &batch).unwrap();
+            }
+        }
+        let r = FederatedReader::open(files).unwrap();
+        assert_eq!(r.get_index().len(), 3);
+        assert_eq!(r.delta_batches(), 10);
+        assert_eq!(r.time_range(), Some((at(0), at(8))));
+        // merged in order, in chunks, with the batches at 8 concatenated
+        let mut cursor = Cursor::new();
+        let mut all = vec![];
+        loop {
+            let mut batches = r.read_deltas(&mut cursor, 4).unwrap();
+            if batches.is_empty() {
+                break;
+            }
+            all.extend(batches.drain(..));
+        }
+        assert_eq!(all.len(), 9);
+        for (secs, (ts, batch)) in all.iter().enumerate() {
+            assert_eq!(*ts, at(secs as i64));
+            assert_eq!(batch.len(), if secs == 8 { 4 } else { 2 });
+            let p = &paths[secs % 2];
+            for BatchItem(id, ev) in batch.iter().take(2) {
+                let path = r.path_for_id(id).unwrap();
+                assert!(path == shared || &path == p);
+                assert_eq!(ev, &Event::Update(Value::I64(secs as i64)));
+            }
+        }
+        // batches at the same time in both archives count as one
+        let mut cursor = Cursor::new();
+        r.seek(&mut cursor, Seek::Beginning);
+        assert_eq!(cursor.current(), Some(at(0)));
+        r.seek(&mut cursor, Seek::BatchRelative(5));
+        assert_eq!(cursor.current(), Some(at(5)));
+        r.seek(&mut cursor, Seek::BatchRelative(-2));
+        assert_eq!(cursor.current(), Some(at(3)));
+        r.seek(&mut cursor, Seek::BatchRelative(100));
+        assert_eq!(cursor.current(), Some(at(8)));
+        // the most recent value of each path, from either archive
+        r.seek(&mut cursor, Seek::Absolute(at(5)));
+        let image = r.build_image(&cursor).unwrap();
+        let get = |p: &Path| image.get(&r.id_for_path(p).unwrap()).cloned();
+        assert_eq!(get(&shared), Some(Event::Update(Value::I64(4))));
+        assert_eq!(get(&paths[0]), Some(Event::Update(Value::I64(4))));
+        assert_eq!(get(&paths[1]), Some(Event::Update(Value::I64(3))));
+        drop(image);
+        drop(r);
+        for f in files {
+            remove(f);
+        }
+    }
+
+    #[test]
+    fn time_index_test() {
+        let base = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        let at = |secs: usize| base + chrono::Duration::seconds(secs as i64);
+        let mut t = TimeIndex::default();
+        // enough to span several chunks
+        for i in 0..10_000 {
+            t.insert(at(i * 2), i);
+        }
+        let snap = t.clone();
+        t.insert(at(20_000), 10_000);
+        assert_eq!(snap.len(), 10_000);
+        assert_eq!(t.len(), 10_001);
+        assert_eq!(snap.last(), Some(at(19_998)));
+        let r = t.range(at(8191)..=at(8194)).collect::<Vec<_>>();
+        assert_eq!(r, vec![(at(8192), 4096), (at(8194), 4097)]);
+        let r = t.range((Bound::Excluded(at(8192)), Bound::Unbounded));
+        assert_eq!(r.len(), 10_001 - 4097);
+        assert_eq!(t.range(..at(4)).next_back(), Some((at(2), 1)));
+        assert_eq!(t.range(at(10)..at(2)).count(), 0);
+        // out of order inserts land in place, and a duplicate
+        // timestamp replaces the position
+        t.insert(at(8193), 42);
+        t.insert(at(8194), 43);
+        let r = t.range(at(8192)..).take(3).collect::<Vec<_>>();
+        assert_eq!(r, vec![(at(8192), 4096), (at(8193), 42), (at(8194), 43)]);
+        assert_eq!(t.len(), 10_002);
+        assert_eq!(snap.range(at(8193)..).next(), Some((at(8194), 4097)));
+    }
+
+    #[test]
+    fn concurrent_readers_test() {
+        use std::thread;
+        let file = FilePath::new("test-data-concurrent");
+        let path = Path::from("/foo/bar");
+        let mut timestamper = MonotonicTimestamper::new();
+        remove(file);
+        let mut t = ArchiveWriter::open(&file).unwrap();
+        t.add_paths(iter::once(&path)).unwrap();
+        let cache = BlockCache::new(1024 * 1024);
+        let r = t.reader().unwrap().with_block_cache(cache.clone());
+        let readers = (0..8)
+            .map(|_| {
+                let r = r.clone();
+                thread::spawn(move || {
+                    let mut cursor = Cursor::new();
+                    let mut next = 0;
+                    while next < 1000 {
+                        for (_, mut b) in r.read_deltas(&mut cursor, 3).unwrap().drain(..)
+                        {
+                            let BatchItem(_, ev) = b.pop().unwrap();
+                            assert_eq!(ev, Event::Update(Value::U64(next)));
+                            next += 1;
+                        }
+                        thread::yield_now();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let id = t.id_for_path(&path).unwrap();
+        for i in 0..1000u64 {
+            let mut batch = BATCH_POOL.take();
+            batch.push(BatchItem(id, Event::Update(Value::U64(i))));
+            t.add_batch(false, timestamper.timestamp(), &batch).unwrap();
+            if i % 10 == 0 {
+                t.flush().unwrap();
+            }
+        }
+        t.flush().unwrap();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+        let (hits, misses) = cache.stats();
+        assert!(misses >= 1000);
+        assert_eq!(hits + misses, 8000);
+        // the cache is bounded
+        assert!(cache.used() <= cache.capacity());
+        drop(r);
+        drop(t);
+        remove(file);
+    }
+
+    #[test]
+    fn tiered_test() {
+        use tiered::{DirStore, Tiered};
+        let dir = FilePath::new("test-data-tiered");
+        let remote = FilePath::new("test-data-tiered-remote");
+        for d in [dir, remote] {
+            if FilePath::is_dir(d) {
+                fs::remove_dir_all(d).unwrap();
+            }
+        }
+        let store = Arc::new(DirStore::new(remote).unwrap());
+        let tiered = Tiered::open(dir, store.clone()).unwrap();
+        let path = Path::from("/foo/bar");
+        let mut stamps = vec![];
+        for seg in ["seg0", "seg1"] {
+            // each segment is a standalone archive, so needs it's own time basis
+            let mut timestamper = MonotonicTimestamper::new();
+            let mut t = ArchiveWriter::open(dir.join(seg)).unwrap();
+            t.add_paths(iter::once(&path)).unwrap();
+            for i in 0..5u64 {
+                let mut batch = BATCH_POOL.take();
+                let id = t.id_for_path(&path).unwrap();
+                batch.push(BatchItem(id, Event::Update(Value::U64(i))));
+                let ts = timestamper.timestamp();
+                stamps.push(ts.datetime());
+                t.add_batch(false, ts, &batch).unwrap();
+            }
+            drop(t);
+            tiered.add_segment(seg).unwrap().unwrap();
+        }
+        tiered.offload("seg0").unwrap();
+        assert!(!dir.join("seg0").is_file());
+        assert!(remote.join("seg0").is_file());
+        // the manifest survives reopening
+        let tiered = Tiered::open(dir, store).unwrap();
+        let segs = tiered.segments();
+        assert_eq!(segs.len(), 2);
+        assert_eq!(
+            (segs[0].start, segs[0].end, segs[0].remote),
+            (stamps[0], stamps[4], true)
+        );
+        assert_eq!(
+            (segs[1].start, segs[1].end, segs[1].remote),
+            (stamps[5], stamps[9], false)
+        );
+        // seeking into the remote segment fetches it
+        let r = tiered.reader_at(stamps[2]).unwrap().unwrap();
+        assert!(dir.join("seg0").is_file());
+        assert_eq!(r.delta_batches(), 5);
+        drop(r);
+        tiered.evict("seg0").unwrap();
+        assert!(!dir.join("seg0").is_file());
+        assert!(tiered
+            .reader_at(stamps[9] + chrono::Duration::seconds(1))
+            .unwrap()
+            .is_none());
+        tiered.remove("seg0").unwrap();
+        assert!(!remote.join("seg0").is_file());
+        assert_eq!(tiered.segments().len(), 1);
+        for d in [dir, remote] {
+            fs::remove_dir_all(d).unwrap();
+        }
+    }
+
+    #[test]
+    fn tiered_playback_test() {
+        use federated::FederatedReader;
+        use tiered::{DirStore, Tiered};
+        let dir = FilePath::new("test-data-tiered-playback");
+        let remote = FilePath::new("test-data-tiered-playback-remote");
+        for d in [dir, remote] {
+            if FilePath::is_dir(d) {
+                fs::remove_dir_all(d).unwrap();
+            }
+        }
+        let store = Arc::new(DirStore::new(remote).unwrap());
+        let tiered = Tiered::open(dir, store).unwrap();
+        let path = Path::from("/foo/bar");
+        let base = Utc.timestamp_opt(1_000_000, 0).unwrap();
+        let at = |secs| base + chrono::Duration::seconds(secs);
+        // record 3 segments of 4 batches, closing the first two
+        let mut live = None;
+        for seg in 0..3 {
+            let (name, mut t) = tiered.new_segment(Backend::Portable).unwrap();
+            t.add_paths(iter::once(&path)).unwrap();
+            let id = t.id_for_path(&path).unwrap();
+            for secs in seg * 4..seg * 4 + 4 {
+                let mut batch = BATCH_POOL.take();
+                batch.push(BatchItem(id, Event::Update(Value::I64(secs))));
+                t.add_batch(false, Timestamp::NewBasis(at(secs)), &batch).unwrap();
+            }
+            if seg < 2 {
+                drop(t);
+                assert!(tiered.close_segment(&name).unwrap().unwrap().remote);
+            } else {
+                live = Some((name, t));
+            }
+        }
+        let segs = tiered.segments();
+        assert_eq!(segs.len(), 2);
+        for seg in &segs {
+            assert!(seg.remote);
+            assert!(!dir.join(&seg.name).is_file());
+            assert!(remote.join(&seg.name).is_file());
+        }
+        let (name, t) = live.unwrap();
+        let r = FederatedReader::open_tiered(tiered.clone()).unwrap();
+        r.add_reader(dir.join(&name), t.reader().unwrap());
+        // offloaded segments are known, but not fetched
+        assert_eq!(r.time_range(), Some((at(0), at(11))));
+        assert_eq!(r.files(), vec![dir.join(&name)]);
+        // play back everything, fetching segments as they are reached
+        let mut cursor = Cursor::new();
+        let mut all = vec![];
+        loop {
+            let mut batches = r.read_deltas(&mut cursor, 10).unwrap();
+            if batches.is_empty() {
+                break;
+            }
+            all.extend(batches.drain(..));
+        }
+        assert_eq!(all.len(), 12);
+        for (secs, (ts, batch)) in all.iter().enumerate() {
+            assert_eq!(*ts, at(secs as i64));
+            assert_eq!(batch.len(), 1);
+            assert_eq!(r.path_for_id(&batch[0].0), Some(path.clone()));
+            assert_eq!(batch[0].1, Event::Update(Value::I64(secs as i64)));
+        }
+        assert_eq!(r.files().len(), 3);
+        // seeking into an evicted segment fetches it again
+        for seg in &segs {
+            tiered.evict(&seg.name).unwrap();
+        }
+        let r = FederatedReader::open_tiered(tiered.clone()).unwrap();
+        r.add_reader(dir.join(&name), t.reader().unwrap());
+        let mut cursor = Cursor::new();
+        r.seek(&mut cursor, Seek::Absolute(at(5)));
+        assert!(dir.join(&segs[1].name).is_file());
+        let img = r.build_image(&cursor).unwrap();
+        let id = r.id_for_path(&path).unwrap();
+        assert_eq!(img.get(&id), Some(&Event::Update(Value::I64(4))));
+        let batches = r.read_deltas(&mut cursor, 1).unwrap();
+        assert_eq!(batches[0].0, at(6));
+        // a segment left open by a recorder that exited is closed when
+        // the segment directory is recovered
+        drop(r);
+        drop(t);
+        let store = Arc::new(DirStore::new(remote).unwrap());
+        let tiered = Tiered::open(dir, store).unwrap();
+        tiered.recover().unwrap();
+        assert_eq!(tiered.segments().len(), 3);
+        assert!(tiered.segments().iter().all(|s| s.remote));
+        assert!(remote.join(&name).is_file());
+        for d in [dir, remote] {
+            fs::remove_dir_all(d).unwrap();
         }
     }
 }
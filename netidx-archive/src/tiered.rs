@@ -0,0 +1,337 @@
+//! Tiered storage for archives. Closed archive files (segments) can
+//! be offloaded to an object store, and are fetched back into a
+//! local cache on demand when a reader needs them. A manifest of the
+//! segments and the time range each one covers is kept in the local
+//! segment directory, so finding the segment for a timestamp never
+//! touches the object store.
+use crate::{activity, ArchiveReader, ArchiveWriter, Backend};
+use anyhow::Result;
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::prelude::*;
+use netidx::pack::{Pack, PackError};
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path as FilePath, PathBuf},
+    sync::Arc,
+};
+
+static MANIFEST: &str = "manifest";
+static MANIFEST_TMP: &str = "manifest.tmp";
+
+/// An object store that segments can be offloaded to. Implement
+/// this for S3, GCS, Azure, etc. Methods are called from blocking
+/// contexts, so implementations may block.
+pub trait ObjectStore: Send + Sync + 'static {
+    /// upload the file at `src` as `key`, replacing any existing object.
+    fn put(&self, key: &str, src: &FilePath) -> Result<()>;
+
+    /// download `key` to the file at `dest`.
+    fn get(&self, key: &str, dest: &FilePath) -> Result<()>;
+
+    /// delete `key`.
+    fn delete(&self, key: &str) -> Result<()>;
+}
+
+/// An object store backed by a directory, e.g. a mounted bucket or
+/// a network file system.
+#[derive(Debug, Clone)]
+pub struct DirStore(PathBuf);
+
+impl DirStore {
+    pub fn new(root: impl AsRef<FilePath>) -> Result<Self> {
+        fs::create_dir_all(root.as_ref())?;
+        Ok(DirStore(root.as_ref().to_path_buf()))
+    }
+}
+
+impl ObjectStore for DirStore {
+    fn put(&self, key: &str, src: &FilePath) -> Result<()> {
+        let tmp = self.0.join(format!("{}.tmp", key));
+        fs::copy(src, &tmp)?;
+        Ok(fs::rename(tmp, self.0.join(key))?)
+    }
+
+    fn get(&self, key: &str, dest: &FilePath) -> Result<()> {
+        fs::copy(self.0.join(key), dest)?;
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> Result<()> {
+        Ok(fs::remove_file(self.0.join(key))?)
+    }
+}
+
+/// A closed archive file and the time range it covers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Segment {
+    pub name: String,
+    pub start: DateTime<Utc>,
+    pub end: DateTime<Utc>,
+    /// true if the segment has been offloaded to the object store
+    pub remote: bool,
+}
+
+impl Pack for Segment {
+    fn encoded_len(&self) -> usize {
+        Pack::encoded_len(&self.name)
+            + Pack::encoded_len(&self.start)
+            + Pack::encoded_len(&self.end)
+            + Pack::encoded_len(&self.remote)
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        Pack::encode(&self.name, buf)?;
+        Pack::encode(&self.start, buf)?;
+        Pack::encode(&self.end, buf)?;
+        Pack::encode(&self.remote, buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        let name = Pack::decode(buf)?;
+        let start = Pack::decode(buf)?;
+        let end = Pack::decode(buf)?;
+        let remote = Pack::decode(buf)?;
+        Ok(Segment { name, start, end, remote })
+    }
+}
+
+struct TieredInner {
+    segments: Vec<Segment>,
+    readers: HashMap<String, ArchiveReader>,
+}
+
+/// A set of archive segments, some local and some offloaded to an
+/// object store.
+///
+/// Segments live in `dir` while they are local, and the manifest is
+/// kept there as well. Offloaded segments that are fetched back are
+/// cached in `dir` until they are evicted.
+#[derive(Clone)]
+pub struct Tiered {
+    dir: PathBuf,
+    store: Arc<dyn ObjectStore>,
+    inner: Arc<Mutex<TieredInner>>,
+}
+
+impl Tiered {
+    /// Open the segment directory `dir`, loading the manifest if one
+    /// exists.
+    pub fn open(dir: impl AsRef<FilePath>, store: Arc<dyn ObjectStore>) -> Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        let path = dir.join(MANIFEST);
+        let segments = if path.is_file() {
+            let data = fs::read(&path)?;
+            <Vec<Segment> as Pack>::decode(&mut &*data)?
+        } else {
+            vec![]
+        };
+        let inner = TieredInner { segments, readers: HashMap::new() };
+        Ok(Tiered { dir, store, inner: Arc::new(Mutex::new(inner)) })
+    }
+
+    /// The segment directory
+    pub fn dir(&self) -> &FilePath {
+        &self.dir
+    }
+
+    fn write_manifest(&self, segments: &Vec<Segment>) -> Result<()> {
+        let mut buf = BytesMut::with_capacity(Pack::encoded_len(segments));
+        Pack::encode(segments, &mut buf)?;
+        let tmp = self.dir.join(MANIFEST_TMP);
+        fs::write(&tmp, &*buf)?;
+        Ok(fs::rename(tmp, self.dir.join(MANIFEST))?)
+    }
+
+    /// The segments in the manifest, ordered by start time.
+    pub fn segments(&self) -> Vec<Segment> {
+        self.inner.lock().segments.clone()
+    }
+
+    /// The segment containing `ts`, if any.
+    pub fn segment_for(&self, ts: DateTime<Utc>) -> Option<Segment> {
+        let inner = self.inner.lock();
+        inner.segments.iter().find(|s| s.start <= ts && ts <= s.end).cloned()
+    }
+
+    /// Add the closed archive file `name` in the segment directory to
+    /// the manifest. The archive must not be written to after it is
+    /// added. Empty archives are not added.
+    pub fn add_segment(&self, name: &str) -> Result<Option<Segment>> {
+        let reader = ArchiveReader::open(self.dir.join(name))?;
+        let (start, end) = match reader.time_range() {
+            None => return Ok(None),
+            Some(r) => r,
+        };
+        let mut inner = self.inner.lock();
+        if inner.segments.iter().any(|s| s.name == name) {
+            bail!("segment {} is already in the manifest", name)
+        }
+        let seg = Segment { name: name.into(), start, end, remote: false };
+        let mut segments = inner.segments.clone();
+        segments.push(seg.clone());
+        segments.sort_by_key(|s| s.start);
+        self.write_manifest(&segments)?;
+        inner.segments = segments;
+        inner.readers.insert(seg.name.clone(), reader);
+        Ok(Some(seg))
+    }
+
+    /// Create a new segment in the segment directory, named for the
+    /// time it was created, and open it for writing. When you are
+    /// done writing it, drop the writer and call `close_segment`.
+    pub fn new_segment(&self, backend: Backend) -> Result<(String, ArchiveWriter)> {
+        let base = Utc::now().format("%Y%m%dT%H%M%S%6fZ").to_string();
+        let mut name = base.clone();
+        let mut i = 0;
+        while self.dir.join(&name).exists() {
+            i += 1;
+            name = format!("{}-{}", base, i);
+        }
+        let writer = ArchiveWriter::open_with_backend(self.dir.join(&name), backend)?;
+        Ok((name, writer))
+    }
+
+    /// Add the closed segment `name` to the manifest and offload it.
+    /// Empty segments are deleted instead.
+    pub fn close_segment(&self, name: &str) -> Result<Option<Segment>> {
+        match self.add_segment(name)? {
+            None => {
+                let path = self.dir.join(name);
+                for f in [activity::index_path(&path), path] {
+                    if f.is_file() {
+                        fs::remove_file(f)?;
+                    }
+                }
+                Ok(None)
+            }
+            Some(mut seg) => {
+                self.offload(name)?;
+                seg.remote = true;
+                Ok(Some(seg))
+            }
+        }
+    }
+
+    /// Close every archive in the segment directory that isn't in the
+    /// manifest, e.g. the segment a recorder was writing when it
+    /// last exited. Don't call this while a segment is being written.
+    pub fn recover(&self) -> Result<()> {
+        let mut names = vec![];
+        for ent in fs::read_dir(&self.dir)? {
+            let ent = ent?;
+            if !ent.file_type()?.is_file() {
+                continue;
+            }
+            let name = match ent.file_name().into_string() {
+                Ok(name) => name,
+                Err(_) => continue,
+            };
+            let skip = name == MANIFEST
+                || name == MANIFEST_TMP
+                || [".activity", ".tmp", ".fetch"].iter().any(|e| name.ends_with(e))
+                || self.inner.lock().segments.iter().any(|s| s.name == name);
+            if !skip {
+                names.push(name);
+            }
+        }
+        names.sort();
+        for name in names {
+            self.close_segment(&name)?;
+        }
+        Ok(())
+    }
+
+    /// Upload the segment `name` to the object store, mark it remote,
+    /// and remove the local copy.
+    pub fn offload(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let i = match inner.segments.iter().position(|s| s.name == name) {
+            None => bail!("no such segment {}", name),
+            Some(i) => i,
+        };
+        if !inner.segments[i].remote {
+            let path = self.dir.join(name);
+            self.store.put(name, &path)?;
+            let mut segments = inner.segments.clone();
+            segments[i].remote = true;
+            self.write_manifest(&segments)?;
+            inner.segments = segments;
+            inner.readers.remove(name);
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+
+    /// Return a reader for the segment `name`, fetching it from the
+    /// object store if it is remote and not already cached.
+    pub fn reader(&self, name: &str) -> Result<ArchiveReader> {
+        let mut inner = self.inner.lock();
+        if let Some(r) = inner.readers.get(name) {
+            return Ok(r.clone());
+        }
+        let seg = match inner.segments.iter().find(|s| s.name == name) {
+            None => bail!("no such segment {}", name),
+            Some(s) => s,
+        };
+        let path = self.dir.join(name);
+        if seg.remote && !path.is_file() {
+            let tmp = self.dir.join(format!("{}.fetch", name));
+            self.store.get(name, &tmp)?;
+            fs::rename(&tmp, &path)?;
+        }
+        let reader = ArchiveReader::open(path)?;
+        inner.readers.insert(name.into(), reader.clone());
+        Ok(reader)
+    }
+
+    /// Return a reader for the segment containing `ts`, fetching it if
+    /// necessary. See `reader`.
+    pub fn reader_at(&self, ts: DateTime<Utc>) -> Result<Option<ArchiveReader>> {
+        match self.segment_for(ts) {
+            None => Ok(None),
+            Some(seg) => Ok(Some(self.reader(&seg.name)?)),
+        }
+    }
+
+    /// Drop the cached copy of the remote segment `name`. Readers that
+    /// are still open will keep working, the file will be fetched
+    /// again the next time it is needed. Does nothing for local
+    /// segments.
+    pub fn evict(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock();
+        if inner.segments.iter().any(|s| s.name == name && s.remote) {
+            inner.readers.remove(name);
+            let path = self.dir.join(name);
+            if path.is_file() {
+                fs::remove_file(path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Remove the segment `name` from the manifest, and delete it
+    /// locally and from the object store.
+    pub fn remove(&self, name: &str) -> Result<()> {
+        let mut inner = self.inner.lock();
+        let seg = match inner.segments.iter().find(|s| s.name == name) {
+            None => bail!("no such segment {}", name),
+            Some(s) => s.clone(),
+        };
+        let segments =
+            inner.segments.iter().filter(|s| s.name != name).cloned().collect();
+        self.write_manifest(&segments)?;
+        inner.segments = segments;
+        inner.readers.remove(name);
+        if seg.remote {
+            self.store.delete(name)?;
+        }
+        let path = self.dir.join(name);
+        if path.is_file() {
+            fs::remove_file(path)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+//! Just enough of io_uring to sync the archive file in the
+//! background. There is never more than one operation in flight, so
+//! the ring is tiny and completions can be matched up trivially.
+use anyhow::{bail, Result};
+use rustix::{
+    fd::{AsRawFd, OwnedFd},
+    io_uring::{
+        io_uring_cqe, io_uring_enter, io_uring_params, io_uring_setup, io_uring_sqe,
+        io_uring_user_data, op_flags_union, IoringEnterFlags, IoringFsyncFlags, IoringOp,
+        IORING_OFF_CQ_RING, IORING_OFF_SQES, IORING_OFF_SQ_RING,
+    },
+    mm::{mmap, munmap, MapFlags, ProtFlags},
+};
+use std::{
+    ffi::c_void,
+    fs::File,
+    io, mem, ptr,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+const ENTRIES: u32 = 4;
+
+struct Map {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Drop for Map {
+    fn drop(&mut self) {
+        let _ = unsafe { munmap(self.ptr, self.len) };
+    }
+}
+
+impl Map {
+    fn new(fd: &OwnedFd, len: usize, offset: u64) -> Result<Map> {
+        let prot = ProtFlags::READ | ProtFlags::WRITE;
+        let flags = MapFlags::SHARED | MapFlags::POPULATE;
+        let ptr = unsafe { mmap(ptr::null_mut(), len, prot, flags, fd, offset)? };
+        Ok(Map { ptr, len })
+    }
+
+    unsafe fn at<T>(&self, off: u32) -> *mut T {
+        (self.ptr as *mut u8).add(off as usize) as *mut T
+    }
+}
+
+pub(crate) struct Ring {
+    // the maps must be dropped before the fd is closed
+    sq: Map,
+    cq: Map,
+    sqes: Map,
+    params: io_uring_params,
+    fd: OwnedFd,
+}
+
+// the maps are only touched through &mut self
+unsafe impl Send for Ring {}
+unsafe impl Sync for Ring {}
+
+impl std::fmt::Debug for Ring {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Ring({})", self.fd.as_raw_fd())
+    }
+}
+
+impl Ring {
+    pub(crate) fn new() -> Result<Ring> {
+        let mut params = io_uring_params::default();
+        let fd = unsafe { io_uring_setup(ENTRIES, &mut params)? };
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * 4;
+        let cq_len = params.cq_off.cqes as usize
+            + params.cq_entries as usize * mem::size_of::<io_uring_cqe>();
+        let sqes_len = params.sq_entries as usize * mem::size_of::<io_uring_sqe>();
+        let sq = Map::new(&fd, sq_len, IORING_OFF_SQ_RING)?;
+        let cq = Map::new(&fd, cq_len, IORING_OFF_CQ_RING)?;
+        let sqes = Map::new(&fd, sqes_len, IORING_OFF_SQES)?;
+        Ok(Ring { sq, cq, sqes, params, fd })
+    }
+
+    /// Start an fdatasync of `file`. `user_data` is returned with
+    /// the completion.
+    pub(crate) fn fdatasync(&mut self, file: &File, user_data: u64) -> Result<()> {
+        let off = &self.params.sq_off;
+        unsafe {
+            let tail = &*self.sq.at::<AtomicU32>(off.tail);
+            let mask = *self.sq.at::<u32>(off.ring_mask);
+            let t = tail.load(Ordering::Relaxed);
+            let idx = t & mask;
+            let sqe = io_uring_sqe {
+                opcode: IoringOp::Fsync,
+                fd: file.as_raw_fd(),
+                op_flags: op_flags_union { fsync_flags: IoringFsyncFlags::DATASYNC },
+                user_data: io_uring_user_data::from_u64(user_data),
+                ..io_uring_sqe::default()
+            };
+            ptr::write(self.sqes.at::<io_uring_sqe>(0).add(idx as usize), sqe);
+            *self.sq.at::<u32>(off.array).add(idx as usize) = idx;
+            tail.store(t.wrapping_add(1), Ordering::Release);
+            io_uring_enter(&self.fd, 1, 0, IoringEnterFlags::empty())?;
+        }
+        Ok(())
+    }
+
+    /// Return the next completion if there is one, waiting for it
+    /// if `wait` is true.
+    pub(crate) fn complete(&mut self, wait: bool) -> Result<Option<(u64, i32)>> {
+        let off = &self.params.cq_off;
+        loop {
+            unsafe {
+                let head = &*self.cq.at::<AtomicU32>(off.head);
+                let tail = &*self.cq.at::<AtomicU32>(off.tail);
+                let mask = *self.cq.at::<u32>(off.ring_mask);
+                let h = head.load(Ordering::Relaxed);
+                if h != tail.load(Ordering::Acquire) {
+                    let cqe =
+                        &*self.cq.at::<io_uring_cqe>(off.cqes).add((h & mask) as usize);
+                    let res = (cqe.user_data.u64_(), cqe.res);
+                    head.store(h.wrapping_add(1), Ordering::Release);
+                    break Ok(Some(res));
+                }
+                if !wait {
+                    break Ok(None);
+                }
+                match io_uring_enter(&self.fd, 0, 1, IoringEnterFlags::GETEVENTS) {
+                    Ok(_) => (),
+                    Err(e) if e == rustix::io::Errno::INTR => (),
+                    Err(e) => break Err(io::Error::from(e).into()),
+                }
+            }
+        }
+    }
+}
+
+/// Syncs the archive file in the background, one fdatasync at a
+/// time.
+#[derive(Debug)]
+pub(crate) struct Syncer {
+    ring: Ring,
+    pending: Option<usize>,
+    submitted: usize,
+}
+
+impl Syncer {
+    pub(crate) fn new() -> Result<Syncer> {
+        Ok(Syncer { ring: Ring::new()?, pending: None, submitted: 0 })
+    }
+
+    fn completed(&mut self, wait: bool) -> Result<Option<usize>> {
+        match self.pending {
+            None => Ok(None),
+            Some(end) => match self.ring.complete(wait)? {
+                None => Ok(None),
+                Some((_, res)) => {
+                    self.pending = None;
+                    if res < 0 {
+                        bail!(io::Error::from_raw_os_error(-res))
+                    }
+                    Ok(Some(end))
+                }
+            },
+        }
+    }
+
+    /// Start syncing everything up to `end`, unless a sync is
+    /// already in flight or `end` is already synced. Returns the end
+    /// of the previous sync if it has completed.
+    pub(crate) fn sync(
+        &mut self,
+        file: &File,
+        committed: usize,
+        end: usize,
+    ) -> Result<Option<usize>> {
+        let done = self.completed(false)?;
+        if self.pending.is_none() && end > committed.max(self.submitted) {
+            self.ring.fdatasync(file, end as u64)?;
+            self.pending = Some(end);
+            self.submitted = end;
+        }
+        Ok(done)
+    }
+
+    /// Wait for the sync in flight, if any, and return it's end.
+    pub(crate) fn wait(&mut self) -> Result<Option<usize>> {
+        self.completed(true)
+    }
+}
@@ -159,7 +159,7 @@ impl CtxInner {
         };
         self.to_gui.send(m)?;
         if !self.raw_view.load(Ordering::Relaxed) {
-            let s = self.subscriber.subscribe(base_path.append(".view"));
+            let s = self.subscriber.subscribe(view::view_path(&base_path));
             let (tx, rx) = mpsc::channel(2);
             s.updates(UpdatesFlags::BEGIN_WITH_LAST, tx);
             self.view_path = Some(base_path.clone());
@@ -239,6 +239,7 @@ impl CtxInner {
                             Ok(v) => {
                                 let _ = fin.send(match v {
                                     Value::Error(s) => Err(anyhow!(String::from(&*s))),
+                                    Value::ErrorInfo(e) => Err(anyhow!(e.to_string())),
                                     _ => Ok(()),
                                 });
                             }
@@ -281,22 +282,20 @@ impl CtxInner {
             Some(mut batch) => {
                 for (_, view) in batch.drain(..) {
                     match view {
-                        Event::Update(Value::String(s)) => {
-                            match serde_json::from_str::<view::Widget>(&*s) {
-                                Err(e) => warn!("error parsing view definition {}", e),
-                                Ok(spec) => {
-                                    if let Some(path) = &self.view_path {
-                                        let m = ToGui::View {
-                                            loc: Some(ViewLoc::Netidx(path.clone())),
-                                            spec,
-                                            generated: false,
-                                        };
-                                        self.to_gui.send(m)?;
-                                        info!("updated gui view")
-                                    }
+                        Event::Update(v) => match view::ViewDef::from_value(v) {
+                            Err(e) => warn!("error parsing view definition {}", e),
+                            Ok(def) => {
+                                if let Some(path) = &self.view_path {
+                                    let m = ToGui::View {
+                                        loc: Some(ViewLoc::Netidx(path.clone())),
+                                        spec: def.root,
+                                        generated: false,
+                                    };
+                                    self.to_gui.send(m)?;
+                                    info!("updated gui view")
                                 }
                             }
-                        }
+                        },
                         v => warn!("unexpected type of view definition {:?}", v),
                     }
                 }
@@ -319,7 +318,7 @@ impl CtxInner {
         for (id, ev) in batch.drain(..) {
             match ev {
                 Event::Update(v) => self.changed.push((id, v)),
-                Event::Unsubscribed => {
+                Event::Unsubscribed(_) => {
                     self.changed.push((id, Value::Error(Chars::from("#LOST"))))
                 }
             }
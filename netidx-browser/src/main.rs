@@ -30,7 +30,7 @@ use netidx::{
     config::Config,
     path::Path,
     pool::{Pool, Pooled},
-    protocol::value::FromValue,
+    protocol::value::{DisplayTz, FromValue, ValueFormat},
     resolver_client,
     subscriber::{DesiredAuth, Dval, Event, SubId, UpdatesFlags, Value},
 };
@@ -39,6 +39,7 @@ use netidx_bscript::{
     vm::{self, ExecCtx, Node, RpcCallId, TimerId},
 };
 use netidx_protocols::view;
+use once_cell::sync::OnceCell;
 use radix_trie::Trie;
 use std::{
     cell::{Cell, RefCell},
@@ -55,11 +56,15 @@ use std::{
 };
 use util::{ask_modal, err_modal};
 
+// the timezone datetimes are displayed in, set once from the command line
+static DISPLAY_TZ: OnceCell<DisplayTz> = OnceCell::new();
+
 struct WVal<'a>(&'a Value);
 
 impl<'a> fmt::Display for WVal<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt_naked(f)
+        let tz = DISPLAY_TZ.get().copied().unwrap_or_default();
+        self.0.fmt_with(f, &ValueFormat::new().naked(true).tz(tz))
     }
 }
 
@@ -1231,6 +1236,14 @@ fn add_local_options(application: &gtk::Application) {
         "load the specified view file on load",
         Some("file"),
     );
+    application.add_main_option(
+        "tz",
+        glib::Char::from(b't'),
+        glib::OptionFlags::empty(),
+        glib::OptionArg::String,
+        "display datetimes in the specified timezone (utc)",
+        Some("[utc, local, or an offset like +05:30]"),
+    );
 }
 
 fn parse_auth(opts: &glib::VariantDict) -> DesiredAuth {
@@ -1270,6 +1283,11 @@ fn main() {
             Some(path) => Config::load(path.get::<String>().unwrap()).unwrap(),
         };
         let auth = parse_auth(opts);
+        if let Some(tz) = opts.lookup_value("tz", Some(&glib::VariantTy::STRING)) {
+            let tz =
+                tz.get::<String>().unwrap().parse::<DisplayTz>().expect("invalid tz");
+            let _ = DISPLAY_TZ.set(tz);
+        }
         let default_loc = match opts.lookup_value("path", Some(&glib::VariantTy::STRING))
         {
             Some(path) => ViewLoc::Netidx(Path::from(path.get::<String>().unwrap())),
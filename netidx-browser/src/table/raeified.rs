@@ -969,7 +969,7 @@ impl RaeifiedTable {
             // we should already be subscribed, so we're just looking up the dval by path.
             let dv = self.shared.ctx.borrow_mut().user.backend.subscriber.subscribe(path);
             let val = Rc::new(RefCell::new(match dv.last() {
-                Event::Unsubscribed => Some(Value::Null),
+                Event::Unsubscribed(_) => Some(Value::Null),
                 Event::Update(v) => Some(v),
             }));
             let d = gtk::Dialog::with_buttons(
@@ -378,6 +378,11 @@ mod tests {
             Just(String::from("sum")),
             Just(String::from("product")),
             Just(String::from("divide")),
+            Just(String::from("bitand")),
+            Just(String::from("bitor")),
+            Just(String::from("bitxor")),
+            Just(String::from("shl")),
+            Just(String::from("shr")),
             Just(String::from("mean")),
             Just(String::from("min")),
             Just(String::from("max")),
@@ -279,7 +279,7 @@ pub struct SumEv;
 impl CachedCurEval for SumEv {
     fn eval(from: &CachedVals) -> Option<Value> {
         from.flat_iter().fold(None, |res, v| match res {
-            res @ Some(Value::Error(_)) => res,
+            res @ Some(Value::Error(_) | Value::ErrorInfo(_)) => res,
             res => add_vals(res, v.clone()),
         })
     }
@@ -304,7 +304,7 @@ fn prod_vals(lhs: Option<Value>, rhs: Option<Value>) -> Option<Value> {
 impl CachedCurEval for ProductEv {
     fn eval(from: &CachedVals) -> Option<Value> {
         from.flat_iter().fold(None, |res, v| match res {
-            res @ Some(Value::Error(_)) => res,
+            res @ Some(Value::Error(_) | Value::ErrorInfo(_)) => res,
             res => prod_vals(res, v.clone()),
         })
     }
@@ -329,7 +329,7 @@ fn div_vals(lhs: Option<Value>, rhs: Option<Value>) -> Option<Value> {
 impl CachedCurEval for DivideEv {
     fn eval(from: &CachedVals) -> Option<Value> {
         from.flat_iter().fold(None, |res, v| match res {
-            res @ Some(Value::Error(_)) => res,
+            res @ Some(Value::Error(_) | Value::ErrorInfo(_)) => res,
             res => div_vals(res, v.clone()),
         })
     }
@@ -341,6 +341,86 @@ impl CachedCurEval for DivideEv {
 
 pub type Divide = CachedCur<DivideEv>;
 
+// fold a bitwise operator over the args, integers are required
+fn bit_vals(from: &CachedVals, op: fn(Value, Value) -> Value) -> Option<Value> {
+    from.flat_iter().fold(None, |res, v| match (res, v) {
+        (res @ Some(Value::Error(_) | Value::ErrorInfo(_)), _) => res,
+        (None, None) | (Some(_), None) => None,
+        (None, r @ Some(_)) => r,
+        (Some(l), Some(r)) => Some(op(l, r)),
+    })
+}
+
+pub struct BitAndEv;
+
+impl CachedCurEval for BitAndEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        bit_vals(from, |l, r| l & r)
+    }
+
+    fn name() -> &'static str {
+        "bitand"
+    }
+}
+
+pub type BitAnd = CachedCur<BitAndEv>;
+
+pub struct BitOrEv;
+
+impl CachedCurEval for BitOrEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        bit_vals(from, |l, r| l | r)
+    }
+
+    fn name() -> &'static str {
+        "bitor"
+    }
+}
+
+pub type BitOr = CachedCur<BitOrEv>;
+
+pub struct BitXorEv;
+
+impl CachedCurEval for BitXorEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        bit_vals(from, |l, r| l ^ r)
+    }
+
+    fn name() -> &'static str {
+        "bitxor"
+    }
+}
+
+pub type BitXor = CachedCur<BitXorEv>;
+
+pub struct ShlEv;
+
+impl CachedCurEval for ShlEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        bit_vals(from, |l, r| l << r)
+    }
+
+    fn name() -> &'static str {
+        "shl"
+    }
+}
+
+pub type Shl = CachedCur<ShlEv>;
+
+pub struct ShrEv;
+
+impl CachedCurEval for ShrEv {
+    fn eval(from: &CachedVals) -> Option<Value> {
+        bit_vals(from, |l, r| l >> r)
+    }
+
+    fn name() -> &'static str {
+        "shr"
+    }
+}
+
+pub type Shr = CachedCur<ShrEv>;
+
 pub struct MinEv;
 
 impl CachedCurEval for MinEv {
@@ -464,7 +544,7 @@ impl CachedCurEval for IsErrEv {
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [v] => v.as_ref().map(|v| match v {
-                Value::Error(_) => Value::True,
+                Value::Error(_) | Value::ErrorInfo(_) => Value::True,
                 _ => Value::False,
             }),
             _ => Some(Value::Error(Chars::from("is_error expected 1 argument"))),
@@ -880,7 +960,7 @@ impl CachedCurEval for FilterErrEv {
     fn eval(from: &CachedVals) -> Option<Value> {
         match &*from.0 {
             [s] => match s {
-                None | Some(Value::Error(_)) => None,
+                None | Some(Value::Error(_) | Value::ErrorInfo(_)) => None,
                 Some(_) => s.clone(),
             },
             _ => {
@@ -972,7 +1052,9 @@ impl CachedCurEval for IsaEv {
             (Typ::Bytes, Some(Value::Bytes(_))) => Some(Value::True),
             (Typ::Bytes, Some(_)) => Some(Value::False),
             (Typ::Result, Some(Value::Ok)) => Some(Value::True),
-            (Typ::Result, Some(Value::Error(_))) => Some(Value::True),
+            (Typ::Result, Some(Value::Error(_) | Value::ErrorInfo(_))) => {
+                Some(Value::True)
+            }
             (Typ::Result, Some(_)) => Some(Value::False),
             (Typ::Array, Some(Value::Array(_))) => Some(Value::True),
             (Typ::Array, Some(_)) => Some(Value::False),
@@ -1695,7 +1777,7 @@ impl<C: Ctx, E: Clone> Apply<C, E> for Load {
             Load::err()
         } else {
             self.cur.as_ref().and_then(|dv| match dv.last() {
-                subscriber::Event::Unsubscribed => {
+                subscriber::Event::Unsubscribed(_) => {
                     Some(Value::Error(Chars::from("#LOST")))
                 }
                 subscriber::Event::Update(v) => Some(v),
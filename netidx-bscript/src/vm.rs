@@ -250,6 +250,9 @@ impl<C: Ctx, E: Clone> ExecCtx<C, E> {
         stdfn::Any::register(&mut t);
         stdfn::Array::register(&mut t);
         stdfn::Basename::register(&mut t);
+        stdfn::BitAnd::register(&mut t);
+        stdfn::BitOr::register(&mut t);
+        stdfn::BitXor::register(&mut t);
         stdfn::Cast::register(&mut t);
         stdfn::Cmp::register(&mut t);
         stdfn::Contains::register(&mut t);
@@ -278,6 +281,8 @@ impl<C: Ctx, E: Clone> ExecCtx<C, E> {
         stdfn::RpcCall::register(&mut t);
         stdfn::Sample::register(&mut t);
         stdfn::Set::register(&mut t);
+        stdfn::Shl::register(&mut t);
+        stdfn::Shr::register(&mut t);
         stdfn::StartsWith::register(&mut t);
         stdfn::Store::register(&mut t);
         stdfn::StringConcat::register(&mut t);
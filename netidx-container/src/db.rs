@@ -2,6 +2,7 @@ use super::Params;
 use anyhow::{anyhow, bail, Result};
 use arcstr::ArcStr;
 use bytes::{Buf, BufMut};
+use chrono::prelude::*;
 use futures::{
     channel::{
         mpsc::{unbounded, UnboundedReceiver, UnboundedSender},
@@ -48,7 +49,7 @@ impl Sendable {
             Sendable::Packed(res) => {
                 let mut res = res.lock();
                 match &*res {
-                    Value::Error(_) => (),
+                    Value::Error(_) | Value::ErrorInfo(_) => (),
                     _ => {
                         *res = v;
                     }
@@ -74,6 +75,7 @@ lazy_static! {
     static ref TXNS: Pool<Txns> = Pool::new(16, 65534);
     static ref STXNS: Pool<Txns> = Pool::new(65534, 32);
     static ref BYPATH: Pool<HashMap<Path, Pooled<Txns>>> = Pool::new(16, 65534);
+    static ref EXPIRY: Pool<Vec<(Path, Option<Expiry>)>> = Pool::new(256, 8124);
 }
 
 pub(super) enum UpdateKind {
@@ -90,6 +92,7 @@ pub(super) struct Update {
     pub(super) unlocked: Pooled<Vec<Path>>,
     pub(super) added_roots: Pooled<Vec<Path>>,
     pub(super) removed_roots: Pooled<Vec<Path>>,
+    pub(super) expiry: Pooled<Vec<(Path, Option<Expiry>)>>,
 }
 
 impl Update {
@@ -102,6 +105,7 @@ impl Update {
             unlocked: PATHS.take(),
             added_roots: PATHS.take(),
             removed_roots: PATHS.take(),
+            expiry: EXPIRY.take(),
         }
     }
 
@@ -113,6 +117,7 @@ impl Update {
         self.unlocked.extend(other.unlocked.drain(..));
         self.added_roots.extend(other.added_roots.drain(..));
         self.removed_roots.extend(other.removed_roots.drain(..));
+        self.expiry.extend(other.expiry.drain(..));
     }
 
     fn merge(mut self, other: Update) -> Update {
@@ -188,6 +193,29 @@ impl Pack for Datum {
     }
 }
 
+/// When a key expires, and what happens to it then
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Expiry {
+    pub at: DateTime<Utc>,
+    /// set the key to null instead of deleting it
+    pub null: bool,
+}
+
+impl Pack for Expiry {
+    fn encoded_len(&self) -> usize {
+        Pack::encoded_len(&(self.at, self.null))
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<(), PackError> {
+        Pack::encode(&(self.at, self.null), buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self, PackError> {
+        let (at, null) = <(DateTime<Utc>, bool)>::decode(buf)?;
+        Ok(Expiry { at, null })
+    }
+}
+
 fn lookup_value<P: AsRef<[u8]>>(tree: &sled::Tree, path: P) -> Result<Option<Datum>> {
     match tree.get(path.as_ref())? {
         None => Ok(None),
@@ -255,6 +283,9 @@ enum TxnOp {
     AddRoot(Path),
     DelRoot(Path),
     RemoveSubtree(Path),
+    SetExpiry(Path, Option<Expiry>),
+    Expire(Path, DateTime<Utc>),
+    CompareAndSet(Vec<(Path, Value, Value)>),
     Flush(oneshot::Sender<()>),
 }
 
@@ -281,6 +312,9 @@ impl TxnOp {
             RemoveSubtree(p) => p.clone(),
             AddRoot(p) => p.clone(),
             DelRoot(p) => p.clone(),
+            SetExpiry(p, _) => p.clone(),
+            Expire(p, _) => p.clone(),
+            CompareAndSet(_) => Path::root(),
             Flush(_) => Path::root(),
         }
     }
@@ -391,6 +425,40 @@ impl Txn {
     pub fn remove_subtree(&mut self, path: Path, reply: Reply) {
         self.0.push((TxnOp::RemoveSubtree(path), reply))
     }
+
+    /// Set or clear the expiration of an existing key. When it
+    /// expires the key will be deleted, or set to null if
+    /// `expiry.null` is true. Expirations are stored in the database,
+    /// and survive restarts.
+    pub fn set_expiry(&mut self, path: Path, expiry: Option<Expiry>, reply: Reply) {
+        self.0.push((TxnOp::SetExpiry(path, expiry), reply))
+    }
+
+    /// Expire `path` `ttl` from now, see `set_expiry`.
+    pub fn set_ttl(&mut self, path: Path, ttl: Duration, null: bool, reply: Reply) {
+        let at = chrono::Duration::from_std(ttl)
+            .ok()
+            .and_then(|ttl| Utc::now().checked_add_signed(ttl))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC);
+        self.set_expiry(path, Some(Expiry { at, null }), reply)
+    }
+
+    pub(super) fn expire(&mut self, path: Path, at: DateTime<Utc>) {
+        self.0.push((TxnOp::Expire(path, at), None))
+    }
+
+    /// Atomically set each path to it's new value if every path
+    /// currently has it's expected value, a missing path has the
+    /// value null. Either all the new values are written, or none of
+    /// them are. The reply is a struct,
+    ///
+    /// `[["committed", bool], ["conflicts", [[path, current], ...]]]`
+    ///
+    /// where conflicts lists the paths that didn't have their
+    /// expected value, and what they had instead.
+    pub fn compare_and_set(&mut self, ops: Vec<(Path, Value, Value)>, reply: Reply) {
+        self.0.push((TxnOp::CompareAndSet(ops), reply))
+    }
 }
 
 fn remove(data: &sled::Tree, pending: &mut Update, path: Path) -> Result<()> {
@@ -1147,6 +1215,131 @@ fn del_root(
     Ok(())
 }
 
+fn set_expiry(
+    data: &sled::Tree,
+    ttl: &sled::Tree,
+    pending: &mut Update,
+    path: Path,
+    expiry: Option<Expiry>,
+) -> Result<()> {
+    let key = path.as_bytes();
+    match expiry {
+        None => {
+            if ttl.remove(key)?.is_some() {
+                pending.expiry.push((path, None));
+            }
+        }
+        Some(expiry) => {
+            match lookup_value(data, key)? {
+                Some(Datum::Data(_)) | Some(Datum::Formula(_, _)) => (),
+                None | Some(Datum::Deleted) => bail!("no such key {}", path),
+            }
+            let mut val = BUF.take();
+            expiry.encode(&mut *val)?;
+            ttl.insert(key, &**val)?;
+            pending.expiry.push((path, Some(expiry)));
+        }
+    }
+    Ok(())
+}
+
+// expire path if it's expiration is still `at`, it may have been
+// changed or cleared since the expiration was scheduled
+fn expire(
+    data: &sled::Tree,
+    ttl: &sled::Tree,
+    pending: &mut Update,
+    path: Path,
+    at: DateTime<Utc>,
+) -> Result<()> {
+    let key = path.as_bytes();
+    let expiry = match ttl.get(key)? {
+        None => return Ok(()),
+        Some(v) => Expiry::decode(&mut &*v)?,
+    };
+    if expiry.at != at || expiry.at > Utc::now() {
+        return Ok(());
+    }
+    ttl.remove(key)?;
+    pending.expiry.push((path.clone(), None));
+    if expiry.null {
+        set_data(data, pending, true, path, Value::Null)
+    } else {
+        remove(data, pending, path)
+    }
+}
+
+// deleted keys don't keep their expiration, otherwise a key created
+// later at the same path would inherit it
+fn clear_deleted_expiry(ttl: &sled::Tree, pending: &mut Update) -> Result<()> {
+    if ttl.is_empty() {
+        return Ok(());
+    }
+    let deleted = pending
+        .data
+        .iter()
+        .chain(pending.formula.iter())
+        .filter(|(_, k)| matches!(k, UpdateKind::Deleted))
+        .map(|(p, _)| p);
+    let mut cleared = PATHS.take();
+    for path in deleted {
+        if ttl.remove(path.as_bytes())?.is_some() {
+            cleared.push(path.clone());
+        }
+    }
+    pending.expiry.extend(cleared.drain(..).map(|p| (p, None)));
+    Ok(())
+}
+
+fn compare_and_set(
+    data: &sled::Tree,
+    pending: &mut Update,
+    ops: Vec<(Path, Value, Value)>,
+) -> Result<Value> {
+    let mut seen = HashSet::new();
+    let mut exists = Vec::with_capacity(ops.len());
+    let mut conflicts = Vec::new();
+    for (path, expected, _) in &ops {
+        if !seen.insert(path) {
+            bail!("{} appears more than once", path)
+        }
+        let current = match lookup_value(data, path.as_bytes())? {
+            None | Some(Datum::Deleted) => None,
+            Some(Datum::Data(v)) => Some(v),
+            Some(Datum::Formula(_, _)) => bail!("{} is a formula", path),
+        };
+        exists.push(current.is_some());
+        let current = current.unwrap_or(Value::Null);
+        if &current != expected {
+            conflicts.push(Value::Array(vec![Value::from(path.clone()), current].into()));
+        }
+    }
+    let committed = conflicts.is_empty();
+    if committed {
+        let mut batch = sled::Batch::default();
+        let mut val = BUF.take();
+        for (path, _, new) in &ops {
+            val.clear();
+            Datum::Data(new.clone()).encode(&mut *val)?;
+            batch.insert(path.as_bytes(), &**val);
+        }
+        data.apply_batch(batch)?;
+        for ((path, _, new), exists) in ops.into_iter().zip(exists) {
+            let up =
+                if exists { UpdateKind::Updated(new) } else { UpdateKind::Inserted(new) };
+            pending.data.push((path, up));
+        }
+    }
+    let field = |k: &'static str, v: Value| Value::Array(vec![Value::from(k), v].into());
+    Ok(Value::Array(
+        vec![
+            field("committed", Value::from(committed)),
+            field("conflicts", Value::Array(conflicts.into())),
+        ]
+        .into(),
+    ))
+}
+
 fn send_reply(reply: Reply, r: Result<()>) {
     match (r, reply) {
         (Ok(()), Some(reply)) => {
@@ -1164,6 +1357,7 @@ fn commit_complex(
     data: &sled::Tree,
     locked: &sled::Tree,
     roots: &sled::Tree,
+    ttl: &sled::Tree,
     mut txn: Txn,
 ) -> Update {
     let mut pending = Update::new();
@@ -1224,9 +1418,25 @@ fn commit_complex(
             TxnOp::SetUnlocked(path) => set_unlocked(&locked, &mut pending, path),
             TxnOp::AddRoot(path) => add_root(&roots, &mut pending, path),
             TxnOp::DelRoot(path) => del_root(&data, &roots, &locked, &mut pending, path),
+            TxnOp::SetExpiry(path, expiry) => {
+                set_expiry(&data, &ttl, &mut pending, path, expiry)
+            }
+            TxnOp::Expire(path, at) => expire(&data, &ttl, &mut pending, path, at),
+            TxnOp::CompareAndSet(ops) => {
+                match compare_and_set(&data, &mut pending, ops) {
+                    Err(e) => Err(e),
+                    Ok(v) => {
+                        if let Some(reply) = reply {
+                            reply.send(v)
+                        }
+                        continue;
+                    }
+                }
+            }
             TxnOp::Flush(finished) => {
                 let _: Result<_, _> = data.flush();
                 let _: Result<_, _> = locked.flush();
+                let _: Result<_, _> = ttl.flush();
                 let _: Result<_, _> = finished.send(());
                 Ok(())
             }
@@ -1276,6 +1486,9 @@ fn commit_simple(data: &sled::Tree, locked: &sled::Tree, mut txn: Txn) -> Update
                         | TxnOp::RemoveSubtree { .. }
                         | TxnOp::AddRoot(_)
                         | TxnOp::DelRoot(_)
+                        | TxnOp::SetExpiry(_, _)
+                        | TxnOp::Expire(_, _)
+                        | TxnOp::CompareAndSet(_)
                         | TxnOp::Flush(_) => unreachable!(),
                     };
                     send_reply(reply, r)
@@ -1387,6 +1600,7 @@ async fn commit_txns_task(
     data: sled::Tree,
     locked: sled::Tree,
     roots: sled::Tree,
+    ttl: sled::Tree,
     incoming: UnboundedReceiver<Txn>,
     outgoing: UnboundedSender<Update>,
 ) {
@@ -1420,13 +1634,16 @@ async fn commit_txns_task(
                         | TxnOp::AddTableColumns { .. }
                         | TxnOp::AddTableRows { .. }
                         | TxnOp::AddRoot(_)
+                        | TxnOp::SetExpiry(_, _)
+                        | TxnOp::CompareAndSet(_)
                         | TxnOp::Flush(_) => (false, delete),
                         TxnOp::RemoveSubtree { .. }
                         | TxnOp::DelTableColumns { .. }
                         | TxnOp::DelTableRows { .. }
                         | TxnOp::DelSheetColumns { .. }
                         | TxnOp::DelSheetRows { .. }
-                        | TxnOp::DelRoot(_) => (false, true),
+                        | TxnOp::DelRoot(_)
+                        | TxnOp::Expire(_, _) => (false, true),
                         TxnOp::Remove(_) => (simple, true),
                         TxnOp::SetData(_, _, _)
                         | TxnOp::SetFormula(_, _)
@@ -1435,11 +1652,19 @@ async fn commit_txns_task(
                         | TxnOp::SetUnlocked(_) => (simple, delete),
                     });
                 delete_required |= delete;
-                let pending = if simple {
+                let mut pending = if simple {
                     task::block_in_place(|| commit_simple(&data, &locked, txn))
                 } else {
-                    task::block_in_place(|| commit_complex(&data, &locked, &roots, txn))
+                    task::block_in_place(|| {
+                        commit_complex(&data, &locked, &roots, &ttl, txn)
+                    })
                 };
+                if delete {
+                    // CR estokes: log this
+                    let _: Result<_> = task::block_in_place(|| {
+                        clear_deleted_expiry(&ttl, &mut pending)
+                    });
+                }
                 if let Some(stats) = &stats {
                     stats.set_busy(false);
                 }
@@ -1464,6 +1689,7 @@ pub struct Db {
     data: sled::Tree,
     locked: sled::Tree,
     roots: sled::Tree,
+    ttl: sled::Tree,
     submit_txn: UnboundedSender<Txn>,
     stats: Option<Arc<Stats>>,
 }
@@ -1495,6 +1721,7 @@ impl Db {
         let data = db.open_tree("data")?;
         let locked = db.open_tree("locked")?;
         let roots = db.open_tree("roots")?;
+        let ttl = db.open_tree("ttl")?;
         let (tx_incoming, rx_incoming) = unbounded();
         let (tx_outgoing, rx_outgoing) = unbounded();
         task::spawn(commit_txns_task(
@@ -1502,14 +1729,16 @@ impl Db {
             data.clone(),
             locked.clone(),
             roots.clone(),
+            ttl.clone(),
             rx_incoming,
             tx_outgoing,
         ));
-        Ok((Db { db, data, locked, roots, submit_txn: tx_incoming, stats }, rx_outgoing))
+        let db = Db { db, data, locked, roots, ttl, submit_txn: tx_incoming, stats };
+        Ok((db, rx_outgoing))
     }
 
     pub fn open_tree(&self, name: &str) -> Result<sled::Tree> {
-        if name == "data" || name == "locked" || name == "roots" {
+        if name == "data" || name == "locked" || name == "roots" || name == "ttl" {
             bail!("tree name reserved")
         }
         Ok(self.db.open_tree(name)?)
@@ -1710,10 +1939,28 @@ impl Db {
         iter_paths(&self.roots)
     }
 
+    /// Return the expiration of `path`, if it has one
+    pub fn expiry<P: AsRef<[u8]>>(&self, path: P) -> Result<Option<Expiry>> {
+        match self.ttl.get(path.as_ref())? {
+            None => Ok(None),
+            Some(v) => Ok(Some(Expiry::decode(&mut &*v)?)),
+        }
+    }
+
+    /// Iterate over all the keys that have an expiration
+    pub fn expiring(&self) -> impl Iterator<Item = Result<(Path, Expiry)>> + 'static {
+        self.ttl.iter().map(|r| {
+            let (k, v) = r?;
+            let path = Path::from(ArcStr::from(str::from_utf8(&k)?));
+            Ok((path, Expiry::decode(&mut &*v)?))
+        })
+    }
+
     pub fn clear(&self) -> Result<()> {
         self.db.clear()?;
         self.data.clear()?;
         self.locked.clear()?;
+        self.ttl.clear()?;
         Ok(self.roots.clear()?)
     }
 }
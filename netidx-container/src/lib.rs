@@ -9,7 +9,8 @@ mod stats;
 
 use anyhow::{anyhow, bail, Result};
 use arcstr::ArcStr;
-pub use db::{Datum, DatumKind, Db, Reply, Sendable, Txn};
+use chrono::{DateTime, Utc};
+pub use db::{Datum, DatumKind, Db, Expiry, Reply, Sendable, Txn};
 use futures::{
     self,
     channel::{mpsc, oneshot},
@@ -45,7 +46,7 @@ use stats::Stats;
 use std::{
     collections::{
         hash_map::Entry,
-        BTreeMap,
+        BTreeMap, BTreeSet,
         Bound::{self, *},
         HashMap, HashSet,
     },
@@ -780,6 +781,24 @@ struct OptTimer {
     timer: Option<Pin<Box<time::Sleep>>>,
 }
 
+impl OptTimer {
+    fn set(&mut self, deadline: Option<time::Instant>) {
+        match deadline {
+            None => {
+                self.timer = None;
+            }
+            Some(deadline) => match &mut self.timer {
+                None => {
+                    self.timer = Some(Box::pin(time::sleep_until(deadline)));
+                }
+                Some(timer) => {
+                    timer.as_mut().reset(deadline);
+                }
+            },
+        }
+    }
+}
+
 impl Future for OptTimer {
     type Output = ();
 
@@ -824,6 +843,9 @@ struct ContainerInner {
     >,
     timer: OptTimer,
     timers: BTreeMap<time::Instant, TimerId>,
+    expire_timer: OptTimer,
+    expiring: BTreeSet<(DateTime<Utc>, Path)>,
+    expiry: FxHashMap<Path, DateTime<Utc>>,
 }
 
 impl ContainerInner {
@@ -877,6 +899,9 @@ impl ContainerInner {
             compiled: HashMap::with_hasher(FxBuildHasher::default()),
             timer: OptTimer { timer: None },
             timers: BTreeMap::new(),
+            expire_timer: OptTimer { timer: None },
+            expiring: BTreeSet::new(),
+            expiry: HashMap::with_hasher(FxBuildHasher::default()),
         })
     }
 
@@ -1038,6 +1063,11 @@ impl ContainerInner {
                 DatumKind::Deleted | DatumKind::Invalid => (),
             }
         }
+        for res in self.ctx.user.db.expiring() {
+            let (path, expiry) = res?;
+            self.schedule_expiry(path, Some(expiry));
+        }
+        self.reschedule_expire_timer();
         Ok(batch.commit(self.params.timeout.map(Duration::from_secs)).await)
     }
 
@@ -1360,19 +1390,7 @@ impl ContainerInner {
     }
 
     fn reschedule_timer(&mut self) {
-        match self.timers.iter().next() {
-            None => {
-                self.timer.timer = None;
-            }
-            Some((deadline, _)) => match &mut self.timer.timer {
-                None => {
-                    self.timer.timer = Some(Box::pin(time::sleep_until(*deadline)));
-                }
-                Some(timer) => {
-                    timer.as_mut().reset(*deadline);
-                }
-            },
-        }
+        self.timer.set(self.timers.keys().next().copied())
     }
 
     fn set_timer(&mut self, id: TimerId, duration: Duration) {
@@ -1395,6 +1413,40 @@ impl ContainerInner {
         self.reschedule_timer()
     }
 
+    fn schedule_expiry(&mut self, path: Path, expiry: Option<Expiry>) {
+        if let Some(at) = self.expiry.remove(&path) {
+            self.expiring.remove(&(at, path.clone()));
+        }
+        if let Some(expiry) = expiry {
+            self.expiring.insert((expiry.at, path.clone()));
+            self.expiry.insert(path, expiry.at);
+        }
+    }
+
+    fn reschedule_expire_timer(&mut self) {
+        // expirations are wall clock times, so wake up at least once
+        // a day in case the clock moved
+        static MAX_SLEEP: Duration = Duration::from_secs(86400);
+        let deadline = self.expiring.iter().next().map(|(at, _)| {
+            let d = (*at - Utc::now()).to_std().unwrap_or(Duration::ZERO);
+            time::Instant::now() + d.min(MAX_SLEEP)
+        });
+        self.expire_timer.set(deadline)
+    }
+
+    fn process_expired(&mut self, txn: &mut Txn) {
+        let now = Utc::now();
+        while let Some((at, _)) = self.expiring.iter().next() {
+            if *at > now {
+                break;
+            }
+            let (at, path) = self.expiring.pop_first().unwrap();
+            self.expiry.remove(&path);
+            txn.expire(path, at);
+        }
+        self.reschedule_expire_timer()
+    }
+
     fn process_bscript_event(&mut self, batch: &mut UpdateBatch, event: LcEvent) {
         match event {
             LcEvent::Refs => self.update_refs(batch),
@@ -1462,6 +1514,33 @@ impl ContainerInner {
         txn.set_data(true, path, value, reply);
     }
 
+    fn set_ttl(
+        &mut self,
+        txn: &mut Txn,
+        path: Path,
+        ttl: Option<Duration>,
+        null: bool,
+        reply: Reply,
+    ) {
+        let path = or_reply!(reply, self.check_path(path));
+        match ttl {
+            None => txn.set_expiry(path, None, reply),
+            Some(ttl) => txn.set_ttl(path, ttl, null, reply),
+        }
+    }
+
+    fn compare_and_set(
+        &mut self,
+        txn: &mut Txn,
+        ops: Vec<(Path, Value, Value)>,
+        reply: Reply,
+    ) {
+        for (path, _, _) in &ops {
+            or_reply!(reply, self.check_path(path.clone()));
+        }
+        txn.compare_and_set(ops, reply);
+    }
+
     fn set_formula(
         &mut self,
         txn: &mut Txn,
@@ -1533,6 +1612,12 @@ impl ContainerInner {
             RpcRequestKind::SetFormula { path, formula, on_write } => {
                 self.set_formula(txn, path, formula, on_write, Some(reply))
             }
+            RpcRequestKind::SetTtl { path, ttl, null } => {
+                self.set_ttl(txn, path, ttl, null, Some(reply))
+            }
+            RpcRequestKind::CompareAndSet(ops) => {
+                self.compare_and_set(txn, ops, Some(reply))
+            }
             RpcRequestKind::CreateSheet {
                 path,
                 rows,
@@ -1654,6 +1739,12 @@ impl ContainerInner {
         let mut locked = false;
         let mut roots = false;
         let mut rels = RELS.take();
+        if !update.expiry.is_empty() {
+            for (path, expiry) in update.expiry.drain(..) {
+                self.schedule_expiry(path, expiry);
+            }
+            self.reschedule_expire_timer();
+        }
         fn add_rel(rels: &mut Pooled<FxHashSet<Path>>, rel: &Path) {
             if let Some(table) = Path::dirname(rel).and_then(Path::dirname) {
                 if !rels.contains(table) {
@@ -1842,6 +1933,9 @@ impl ContainerInner {
                 () = &mut self.timer => {
                     self.process_timer_tick(&mut batch);
                 },
+                () = &mut self.expire_timer => {
+                    self.process_expired(&mut txn);
+                },
                 complete => break,
             }
             if txn.dirty() {
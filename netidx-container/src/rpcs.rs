@@ -5,6 +5,7 @@ use netidx::{
     chars::Chars, path::Path, publisher::Publisher, subscriber::Value, utils::Batched,
 };
 use netidx_protocols::rpc::server::{ArgSpec, Proc, RpcCall, RpcReply};
+use std::time::Duration;
 
 pub(super) enum RpcRequestKind {
     Delete(Path),
@@ -20,6 +21,12 @@ pub(super) enum RpcRequestKind {
         formula: Option<Chars>,
         on_write: Option<Chars>,
     },
+    SetTtl {
+        path: Path,
+        ttl: Option<Duration>,
+        null: bool,
+    },
+    CompareAndSet(Vec<(Path, Value, Value)>),
     CreateSheet {
         path: Path,
         rows: usize,
@@ -59,6 +66,8 @@ pub(super) struct RpcApi {
     _unlock_subtree_rpc: Proc,
     _set_data_rpc: Proc,
     _set_formula_rpc: Proc,
+    _set_ttl_rpc: Proc,
+    _txn_rpc: Proc,
     _create_sheet_rpc: Proc,
     _add_sheet_rows: Proc,
     _add_sheet_cols: Proc,
@@ -86,6 +95,8 @@ impl RpcApi {
             start_unlock_subtree_rpc(&publisher, &base_path, tx.clone())?;
         let _set_data_rpc = start_set_data_rpc(&publisher, &base_path, tx.clone())?;
         let _set_formula_rpc = start_set_formula_rpc(&publisher, &base_path, tx.clone())?;
+        let _set_ttl_rpc = start_set_ttl_rpc(&publisher, &base_path, tx.clone())?;
+        let _txn_rpc = start_txn_rpc(&publisher, &base_path, tx.clone())?;
         let _create_sheet_rpc =
             start_create_sheet_rpc(&publisher, &base_path, tx.clone())?;
         let _add_sheet_rows =
@@ -115,6 +126,8 @@ impl RpcApi {
             _unlock_subtree_rpc,
             _set_data_rpc,
             _set_formula_rpc,
+            _set_ttl_rpc,
+            _txn_rpc,
             _create_sheet_rpc,
             _add_sheet_rows,
             _add_sheet_cols,
@@ -330,6 +343,66 @@ pub(super) fn start_set_formula_rpc(
     )
 }
 
+pub(super) fn start_set_ttl_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(
+        mut c: RpcCall,
+        mut path: Vec<Path>,
+        ttl: Option<Duration>,
+        null: bool,
+    ) -> Option<RpcRequest> {
+        if path.len() == 0 {
+            rpc_err!(c.reply, "expected at least 1 path")
+        } else if path.len() == 1 {
+            let path = path.pop().unwrap();
+            let kind = RpcRequestKind::SetTtl { path, ttl, null };
+            Some(RpcRequest { reply: c.reply, kind })
+        } else {
+            let reqs = path
+                .into_iter()
+                .map(|path| RpcRequestKind::SetTtl { path, ttl, null })
+                .collect();
+            Some(RpcRequest { reply: c.reply, kind: RpcRequestKind::Packed(reqs) })
+        }
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("set-ttl"),
+        "expire the specified paths after ttl, deleting them or setting them to null",
+        map,
+        Some(tx),
+        path: Vec<Path> = Vec::<Path>::new(); "the paths to expire",
+        ttl: Option<Duration> = None::<Duration>; "time to live, null to never expire",
+        null: bool = false; "set the value to null instead of deleting it"
+    )
+}
+
+pub(super) fn start_txn_rpc(
+    publisher: &Publisher,
+    base_path: &Path,
+    tx: mpsc::Sender<RpcRequest>,
+) -> Result<Proc> {
+    fn map(mut c: RpcCall, ops: Vec<(Path, Value, Value)>) -> Option<RpcRequest> {
+        if ops.len() == 0 {
+            rpc_err!(c.reply, "expected at least 1 operation")
+        } else {
+            let kind = RpcRequestKind::CompareAndSet(ops);
+            Some(RpcRequest { reply: c.reply, kind })
+        }
+    }
+    define_rpc!(
+        publisher,
+        base_path.append("txn"),
+        "atomically compare and set paths, all are set or none are",
+        map,
+        Some(tx),
+        ops: Vec<(Path, Value, Value)> = Value::Null; "[path, expected, new] triples"
+    )
+}
+
 pub(super) fn start_create_sheet_rpc(
     publisher: &Publisher,
     base_path: &Path,
@@ -1,5 +1,9 @@
 use crate::pack::{Pack, PackError};
 use bytes::{Bytes, Buf, BufMut};
+use serde::{
+    de::{self, SeqAccess, Visitor},
+    Deserialize, Deserializer, Serialize, Serializer,
+};
 use std::{
     cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
     convert::AsRef,
@@ -12,7 +16,13 @@ use std::{
 
 /// This is a thin wrapper around a Bytes that guarantees that it's contents are
 /// well formed unicode.
-#[derive(Clone, Serialize, Deserialize)]
+///
+/// With serde `Chars` is a string. Deserializing from an owned
+/// `String` or byte buffer takes it without copying, borrowed input
+/// is copied once. For compatibility with older versions, which
+/// serialized `Chars` as an array of bytes, a sequence of utf8 bytes
+/// is also accepted.
+#[derive(Clone)]
 pub struct Chars(Bytes);
 
 impl Chars {
@@ -91,6 +101,64 @@ impl Pack for Chars {
     }
 }
 
+impl Serialize for Chars {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self)
+    }
+}
+
+struct CharsVisitor;
+
+impl<'de> Visitor<'de> for CharsVisitor {
+    type Value = Chars;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "a string")
+    }
+
+    fn visit_str<E: de::Error>(self, v: &str) -> Result<Chars, E> {
+        Ok(Chars(Bytes::copy_from_slice(v.as_bytes())))
+    }
+
+    fn visit_string<E: de::Error>(self, v: String) -> Result<Chars, E> {
+        Ok(Chars::from(v))
+    }
+
+    fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Chars, E> {
+        match str::from_utf8(v) {
+            Ok(s) => self.visit_str(s),
+            Err(_) => Err(E::invalid_value(de::Unexpected::Bytes(v), &self)),
+        }
+    }
+
+    fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Chars, E> {
+        match String::from_utf8(v) {
+            Ok(s) => Ok(Chars::from(s)),
+            Err(e) => {
+                Err(E::invalid_value(de::Unexpected::Bytes(e.as_bytes()), &self))
+            }
+        }
+    }
+
+    fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Chars, A::Error> {
+        let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+        while let Some(b) = seq.next_element::<u8>()? {
+            v.push(b)
+        }
+        self.visit_byte_buf(v)
+    }
+}
+
+impl<'de> Deserialize<'de> for Chars {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Chars, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(CharsVisitor)
+        } else {
+            deserializer.deserialize_str(CharsVisitor)
+        }
+    }
+}
+
 impl From<&'static str> for Chars {
     fn from(src: &'static str) -> Chars {
         Chars(Bytes::from(src.as_bytes()))
@@ -7,7 +7,7 @@ use rust_decimal::Decimal;
 use std::{
     any::{Any, TypeId},
     boxed::Box,
-    cell::RefCell,
+    cell::{Cell, RefCell},
     cmp::Eq,
     collections::HashMap,
     default::Default,
@@ -27,6 +27,7 @@ pub enum PackError {
     InvalidFormat,
     BufferShort,
     Application(u64),
+    LimitExceeded(Limit),
 }
 
 impl fmt::Display for PackError {
@@ -37,6 +38,101 @@ impl fmt::Display for PackError {
 
 impl error::Error for PackError {}
 
+/// A decode limit that was exceeded, see `DecodeLimits`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Limit {
+    ArrayLen,
+    Depth,
+    StringLen,
+    BatchLen,
+}
+
+/// Limits enforced while decoding data from an untrusted peer.
+/// Limits only apply to decoding done inside `DecodeLimits::scope`,
+/// and exceeding one fails the decode with
+/// `PackError::LimitExceeded`.
+///
+/// The default limits nesting to 512 levels, which is enough to
+/// keep recursive decoding from overflowing the stack, and leaves
+/// everything else unlimited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DecodeLimits {
+    /// the maximum number of elements in an array
+    pub max_array_len: usize,
+    /// the maximum nesting depth of arrays
+    pub max_depth: usize,
+    /// the maximum length in bytes of a string or byte array
+    pub max_string_len: usize,
+    /// the maximum number of messages decoded from one batch
+    pub max_batch_len: usize,
+}
+
+impl Default for DecodeLimits {
+    fn default() -> Self {
+        DecodeLimits {
+            max_array_len: usize::MAX,
+            max_depth: 512,
+            max_string_len: usize::MAX,
+            max_batch_len: usize::MAX,
+        }
+    }
+}
+
+thread_local! {
+    static LIMITS: Cell<DecodeLimits> = Cell::new(DecodeLimits::default());
+    static DEPTH: Cell<usize> = const { Cell::new(0) };
+}
+
+impl DecodeLimits {
+    /// Run `f` with these limits in effect on this thread.
+    pub fn scope<R>(self, f: impl FnOnce() -> R) -> R {
+        struct Restore(DecodeLimits);
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                LIMITS.with(|l| l.set(self.0))
+            }
+        }
+        let _restore = Restore(LIMITS.with(|l| l.replace(self)));
+        f()
+    }
+
+    /// The limits currently in effect on this thread.
+    pub fn current() -> DecodeLimits {
+        LIMITS.with(|l| l.get())
+    }
+
+    /// Fail if `len` is greater than `max`.
+    pub fn check(len: usize, max: usize, limit: Limit) -> Result<(), PackError> {
+        if len > max {
+            Err(PackError::LimitExceeded(limit))
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Held while decoding a nested value, fails if the nesting depth
+/// would exceed the current `DecodeLimits::max_depth`.
+pub struct DepthGuard(());
+
+impl DepthGuard {
+    pub fn enter() -> Result<DepthGuard, PackError> {
+        let max = DecodeLimits::current().max_depth;
+        DEPTH.with(|d| {
+            let depth = d.get() + 1;
+            DecodeLimits::check(depth, max, Limit::Depth)?;
+            d.set(depth);
+            Ok(DepthGuard(()))
+        })
+    }
+}
+
+impl Drop for DepthGuard {
+    fn drop(&mut self) {
+        DEPTH.with(|d| d.set(d.get() - 1))
+    }
+}
+
 pub trait Pack {
     fn const_encoded_len() -> Option<usize> {
         None
@@ -157,6 +253,8 @@ impl Pack for Bytes {
         if len as usize > buf.remaining() {
             Err(PackError::TooBig)
         } else {
+            let max = DecodeLimits::current().max_string_len;
+            DecodeLimits::check(len as usize, max, Limit::StringLen)?;
             Ok(buf.copy_to_bytes(len as usize))
         }
     }
@@ -1,6 +1,6 @@
 /// This is shamelessly based on the dynamic-pool crate, with
 /// modifications
-use crossbeam::queue::ArrayQueue;
+use crossbeam::queue::SegQueue;
 use std::{
     cmp::{Eq, Ord, Ordering, PartialEq, PartialOrd},
     collections::{HashMap, HashSet, VecDeque},
@@ -9,7 +9,10 @@ use std::{
     hash::{BuildHasher, Hash, Hasher},
     mem,
     ops::{Deref, DerefMut},
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering as MemOrd},
+        Arc, Mutex, Weak,
+    },
 };
 
 pub trait Poolable {
@@ -96,10 +99,240 @@ impl Poolable for String {
     }
 }
 
+/// The number of takes between adjustments of an adaptive pool's
+/// capacity
+const WINDOW: u64 = 1024;
+
+/// Overrides for the limits of a pool, see `configure`. Limits that
+/// are `None` are left as they are.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PoolConfig {
+    /// an adaptive pool never shrinks below this many objects
+    pub min_capacity: Option<usize>,
+    /// the pool never retains more than this many objects
+    pub max_capacity: Option<usize>,
+    /// objects larger than this are deallocated instead of returned
+    pub max_elt_capacity: Option<usize>,
+    /// whether the capacity adapts to the demand on the pool
+    pub adaptive: Option<bool>,
+}
+
+/// A snapshot of the state and lifetime counters of a pool
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PoolStats {
+    /// the number of objects the pool will currently retain
+    pub capacity: usize,
+    pub min_capacity: usize,
+    pub max_capacity: usize,
+    pub max_elt_capacity: usize,
+    pub adaptive: bool,
+    /// the number of objects in the pool right now
+    pub pooled: usize,
+    /// the most objects the pool has held at once
+    pub high_water: usize,
+    /// objects taken from the pool
+    pub takes: u64,
+    /// takes that were satisfied by a pooled object
+    pub hits: u64,
+    /// objects returned to the pool
+    pub returned: u64,
+    /// objects that were deallocated because the pool was full, or
+    /// they were larger than `max_elt_capacity`
+    pub discarded: u64,
+    pub grown: u64,
+    pub shrunk: u64,
+}
+
+impl PoolStats {
+    /// The fraction of takes satisfied by a pooled object
+    pub fn hit_rate(&self) -> f64 {
+        if self.takes == 0 {
+            1.
+        } else {
+            self.hits as f64 / self.takes as f64
+        }
+    }
+}
+
 #[derive(Debug)]
 struct PoolInner<T: Poolable + Send + Sync + 'static> {
-    pool: ArrayQueue<T>,
-    max_elt_capacity: usize,
+    pool: SegQueue<T>,
+    // an upper bound on the length of pool
+    len: AtomicUsize,
+    capacity: AtomicUsize,
+    min_capacity: AtomicUsize,
+    max_capacity: AtomicUsize,
+    max_elt_capacity: AtomicUsize,
+    adaptive: AtomicBool,
+    high_water: AtomicUsize,
+    takes: AtomicU64,
+    hits: AtomicU64,
+    returned: AtomicU64,
+    discarded: AtomicU64,
+    grown: AtomicU64,
+    shrunk: AtomicU64,
+    // the state of the current adaptation window
+    window_hits: AtomicU64,
+    window_full: AtomicU64,
+    window_low: AtomicUsize,
+}
+
+impl<T: Poolable + Send + Sync + 'static> PoolInner<T> {
+    fn pop(&self) -> Option<T> {
+        let object = self.pool.pop()?;
+        let len = self.len.fetch_sub(1, MemOrd::Relaxed) - 1;
+        self.window_low.fetch_min(len, MemOrd::Relaxed);
+        Some(object)
+    }
+
+    fn push(&self, mut object: T) {
+        if object.capacity() > self.max_elt_capacity.load(MemOrd::Relaxed) {
+            self.discarded.fetch_add(1, MemOrd::Relaxed);
+            return;
+        }
+        let len = self.len.fetch_add(1, MemOrd::Relaxed);
+        if len >= self.capacity.load(MemOrd::Relaxed) {
+            self.len.fetch_sub(1, MemOrd::Relaxed);
+            self.discarded.fetch_add(1, MemOrd::Relaxed);
+            self.window_full.fetch_add(1, MemOrd::Relaxed);
+        } else {
+            object.reset();
+            self.pool.push(object);
+            self.returned.fetch_add(1, MemOrd::Relaxed);
+            self.high_water.fetch_max(len + 1, MemOrd::Relaxed);
+        }
+    }
+
+    // drop pooled objects until there are at most capacity
+    fn trim(&self) {
+        while self.len.load(MemOrd::Relaxed) > self.capacity.load(MemOrd::Relaxed) {
+            if self.pop().is_none() {
+                break;
+            }
+        }
+    }
+
+    // Called every WINDOW takes. If objects were thrown away because
+    // the pool was full, and too many takes had to allocate, then
+    // the pool is too small, so double it. Otherwise, if the pool
+    // never emptied below some number of objects during the window,
+    // then those objects weren't needed, so shrink by half of them.
+    fn adapt(&self) {
+        let hits = self.window_hits.swap(0, MemOrd::Relaxed);
+        let full = self.window_full.swap(0, MemOrd::Relaxed);
+        let low = self.window_low.swap(self.len.load(MemOrd::Relaxed), MemOrd::Relaxed);
+        let capacity = self.capacity.load(MemOrd::Relaxed);
+        let min = self.min_capacity.load(MemOrd::Relaxed);
+        let max = self.max_capacity.load(MemOrd::Relaxed);
+        if full > 0 && hits < WINDOW * 9 / 10 {
+            let new = (capacity.max(1) * 2).min(max);
+            if new > capacity {
+                self.capacity.store(new, MemOrd::Relaxed);
+                self.grown.fetch_add(1, MemOrd::Relaxed);
+            }
+        } else if low > 1 {
+            let new = capacity.saturating_sub(low / 2).max(min);
+            if new < capacity {
+                self.capacity.store(new, MemOrd::Relaxed);
+                self.shrunk.fetch_add(1, MemOrd::Relaxed);
+                self.trim();
+            }
+        }
+    }
+}
+
+trait Instrumented: Send + Sync {
+    fn stats(&self) -> PoolStats;
+    fn configure(&self, cfg: &PoolConfig);
+}
+
+impl<T: Poolable + Send + Sync + 'static> Instrumented for PoolInner<T> {
+    fn stats(&self) -> PoolStats {
+        PoolStats {
+            capacity: self.capacity.load(MemOrd::Relaxed),
+            min_capacity: self.min_capacity.load(MemOrd::Relaxed),
+            max_capacity: self.max_capacity.load(MemOrd::Relaxed),
+            max_elt_capacity: self.max_elt_capacity.load(MemOrd::Relaxed),
+            adaptive: self.adaptive.load(MemOrd::Relaxed),
+            pooled: self.pool.len(),
+            high_water: self.high_water.load(MemOrd::Relaxed),
+            takes: self.takes.load(MemOrd::Relaxed),
+            hits: self.hits.load(MemOrd::Relaxed),
+            returned: self.returned.load(MemOrd::Relaxed),
+            discarded: self.discarded.load(MemOrd::Relaxed),
+            grown: self.grown.load(MemOrd::Relaxed),
+            shrunk: self.shrunk.load(MemOrd::Relaxed),
+        }
+    }
+
+    fn configure(&self, cfg: &PoolConfig) {
+        if let Some(n) = cfg.max_elt_capacity {
+            self.max_elt_capacity.store(n, MemOrd::Relaxed);
+        }
+        if let Some(b) = cfg.adaptive {
+            self.adaptive.store(b, MemOrd::Relaxed);
+        }
+        if let Some(n) = cfg.max_capacity {
+            self.max_capacity.store(n, MemOrd::Relaxed);
+        }
+        if let Some(n) = cfg.min_capacity {
+            self.min_capacity.store(n, MemOrd::Relaxed);
+        }
+        let max = self.max_capacity.load(MemOrd::Relaxed);
+        let min = self.min_capacity.load(MemOrd::Relaxed).min(max);
+        self.min_capacity.store(min, MemOrd::Relaxed);
+        let capacity = self.capacity.load(MemOrd::Relaxed);
+        let capacity = if self.adaptive.load(MemOrd::Relaxed) {
+            capacity.max(min).min(max)
+        } else {
+            max
+        };
+        self.capacity.store(capacity, MemOrd::Relaxed);
+        self.trim();
+    }
+}
+
+struct Registry {
+    pools: Vec<(&'static str, Weak<dyn Instrumented>)>,
+    // applied in order to pools registered later
+    configs: Vec<(String, PoolConfig)>,
+}
+
+static REGISTRY: Mutex<Registry> =
+    Mutex::new(Registry { pools: Vec::new(), configs: Vec::new() });
+
+/// Apply `cfg` to every named pool whose name starts with `prefix`,
+/// including pools that are created later. E.G. `"subscriber::"`
+/// selects all the subscriber's pools, and `""` selects all named
+/// pools. Pools are usually process wide statics, so this affects
+/// every user of them in the process.
+pub fn configure(prefix: &str, cfg: PoolConfig) {
+    let mut reg = REGISTRY.lock().unwrap();
+    reg.pools.retain(|(name, pool)| match pool.upgrade() {
+        None => false,
+        Some(pool) => {
+            if name.starts_with(prefix) {
+                pool.configure(&cfg)
+            }
+            true
+        }
+    });
+    reg.configs.push((String::from(prefix), cfg));
+}
+
+/// Return the stats of every live named pool, sorted by name
+pub fn stats() -> Vec<(&'static str, PoolStats)> {
+    let mut reg = REGISTRY.lock().unwrap();
+    let mut res = vec![];
+    reg.pools.retain(|(name, pool)| match pool.upgrade() {
+        None => false,
+        Some(pool) => {
+            res.push((*name, pool.stats()));
+            true
+        }
+    });
+    res.sort_by_key(|(name, _)| *name);
+    res
 }
 
 /// a lock-free, thread-safe, dynamically-sized object pool.
@@ -111,28 +344,93 @@ struct PoolInner<T: Poolable + Send + Sync + 'static> {
 /// re-use).
 ///
 /// if, during an attempted return, a pool already has
-/// `maximum_capacity` objects in the pool, the pool will throw away
+/// `capacity` objects in the pool, the pool will throw away
 /// that object.
+///
+/// By default the capacity adapts to the demand on the pool, growing
+/// up to 16 times the initial capacity when objects are being thrown
+/// away and then allocated again, and shrinking when pooled objects
+/// go unused, see `PoolConfig`.
 #[derive(Clone, Debug)]
 pub struct Pool<T: Poolable + Send + Sync + 'static>(Arc<PoolInner<T>>);
 
 impl<T: Poolable + Sync + Send + 'static> Pool<T> {
-    /// creates a new `Pool<T>`. this pool will retain up to
-    /// `max_capacity` objects of size less than or equal to
+    /// creates a new `Pool<T>`. this pool will initially retain up
+    /// to `max_capacity` objects of size less than or equal to
     /// max_elt_capacity. Objects larger than max_elt_capacity will be
     /// deallocated immediatly.
     pub fn new(max_capacity: usize, max_elt_capacity: usize) -> Pool<T> {
         Pool(Arc::new(PoolInner {
-            pool: ArrayQueue::new(max_capacity),
-            max_elt_capacity,
+            pool: SegQueue::new(),
+            len: AtomicUsize::new(0),
+            capacity: AtomicUsize::new(max_capacity),
+            min_capacity: AtomicUsize::new(max_capacity.min(1)),
+            max_capacity: AtomicUsize::new(max_capacity.saturating_mul(16)),
+            max_elt_capacity: AtomicUsize::new(max_elt_capacity),
+            adaptive: AtomicBool::new(true),
+            high_water: AtomicUsize::new(0),
+            takes: AtomicU64::new(0),
+            hits: AtomicU64::new(0),
+            returned: AtomicU64::new(0),
+            discarded: AtomicU64::new(0),
+            grown: AtomicU64::new(0),
+            shrunk: AtomicU64::new(0),
+            window_hits: AtomicU64::new(0),
+            window_full: AtomicU64::new(0),
+            window_low: AtomicUsize::new(0),
         }))
     }
 
+    /// creates a new `Pool<T>` like `new`, and registers it by name,
+    /// so that it's stats are included in `stats`, and it can be
+    /// tuned with `configure`.
+    pub fn named(
+        name: &'static str,
+        max_capacity: usize,
+        max_elt_capacity: usize,
+    ) -> Pool<T> {
+        let t = Self::new(max_capacity, max_elt_capacity);
+        let mut reg = REGISTRY.lock().unwrap();
+        for (prefix, cfg) in &reg.configs {
+            if name.starts_with(prefix.as_str()) {
+                t.0.configure(cfg)
+            }
+        }
+        let inner: Arc<dyn Instrumented> = t.0.clone();
+        reg.pools.push((name, Arc::downgrade(&inner)));
+        t
+    }
+
     /// takes an item from the pool, creating one if none are available.
     pub fn take(&self) -> Pooled<T> {
-        let object = self.0.pool.pop().unwrap_or_else(Poolable::empty);
+        let inner = &self.0;
+        let n = inner.takes.fetch_add(1, MemOrd::Relaxed) + 1;
+        let object = match inner.pop() {
+            Some(object) => {
+                inner.hits.fetch_add(1, MemOrd::Relaxed);
+                inner.window_hits.fetch_add(1, MemOrd::Relaxed);
+                object
+            }
+            None => {
+                inner.window_low.store(0, MemOrd::Relaxed);
+                Poolable::empty()
+            }
+        };
+        if n.is_multiple_of(WINDOW) && inner.adaptive.load(MemOrd::Relaxed) {
+            inner.adapt()
+        }
         Pooled { pool: Arc::downgrade(&self.0), object }
     }
+
+    /// Apply `cfg` to this pool only
+    pub fn configure(&self, cfg: PoolConfig) {
+        self.0.configure(&cfg)
+    }
+
+    /// Return a snapshot of the pool's stats
+    pub fn stats(&self) -> PoolStats {
+        self.0.stats()
+    }
 }
 
 /// an object, checked out from a pool.
@@ -203,11 +501,7 @@ impl<T: Poolable + Sync + Send + 'static> DerefMut for Pooled<T> {
 impl<T: Poolable + Sync + Send + 'static> Drop for Pooled<T> {
     fn drop(&mut self) {
         if let Some(inner) = self.pool.upgrade() {
-            if self.object.capacity() <= inner.max_elt_capacity {
-                let mut object = mem::replace(&mut self.object, Poolable::empty());
-                object.reset();
-                inner.pool.push(object).ok();
-            }
+            inner.push(mem::replace(&mut self.object, Poolable::empty()));
         }
     }
 }
@@ -0,0 +1,36 @@
+//! Rough timing of `Value` decoding for deep structured values. Run
+//! with `cargo bench -p netidx-netproto`.
+use netidx_core::{pack::Pack, utils::pack};
+use netidx_netproto::value::Value;
+use std::time::Instant;
+
+fn nested(depth: usize, width: usize) -> Value {
+    if depth == 0 {
+        Value::from("leaf")
+    } else {
+        let elts = (0..width).map(|i| match i % 3 {
+            0 => Value::U64(i as u64),
+            1 => Value::F64(i as f64),
+            _ => nested(depth - 1, width),
+        });
+        Value::Array(elts.collect())
+    }
+}
+
+fn bench(name: &str, v: &Value, iters: usize) {
+    let bytes = pack(v).expect("encode").freeze();
+    let start = Instant::now();
+    for _ in 0..iters {
+        let mut buf = bytes.clone();
+        let d = <Value as Pack>::decode(&mut buf).expect("decode");
+        assert!(matches!(d, Value::Array(_)));
+    }
+    let per = start.elapsed() / iters as u32;
+    println!("{}: {} bytes, {:?} per decode", name, bytes.len(), per);
+}
+
+fn main() {
+    bench("flat 1000", &nested(1, 1000), 10_000);
+    bench("depth 4 width 8", &nested(4, 8), 100_000);
+    bench("depth 8 width 3", &nested(8, 3), 100_000);
+}
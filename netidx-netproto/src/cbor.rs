@@ -0,0 +1,512 @@
+//! An alternate, self describing encoding of values as canonical
+//! CBOR (RFC 8949), for exchanging values with systems that already
+//! speak CBOR. `Pack` remains the wire format, this is for bridges.
+//!
+//! Values map onto CBOR's own types where one exists. Integers,
+//! floats, text, byte strings, booleans, null, and arrays are
+//! encoded directly, `DateTime` and `Duration` use the extended time
+//! and duration tags of RFC 9581 (1001 and 1002), and `Decimal` uses
+//! the decimal fraction tag (4). The integer types CBOR doesn't
+//! distinguish, and the variants it has no type for, are wrapped in
+//! tags from a private range starting at `TAG_BASE`, so a value
+//! decodes to exactly the variant it was encoded from, while a CBOR
+//! decoder that ignores unknown tags still sees a plain number or
+//! string. `I64` is the untagged integer.
+//!
+//! The encoding is deterministic, lengths are definite and in their
+//! shortest form, and map keys are sorted. Floats keep their width,
+//! `F32` is a single and `F64` a double.
+//!
+//! Decoding accepts more than the encoder produces, so that data from
+//! other encoders can be read. Untagged integers become `I64`, or
+//! `U64` if they are too big, half precision floats become `F64`,
+//! undefined becomes `Null`, timestamps in tags 0 and 1 become
+//! `DateTime`, maps become arrays of `[key, value]` pairs, the same
+//! representation as structs, and unknown tags are ignored.
+//! Indefinite lengths are not supported.
+use crate::value::{ErrorInfo, Value};
+use anyhow::Result;
+use bytes::Bytes;
+use chrono::prelude::*;
+use netidx_core::chars::Chars;
+use rust_decimal::Decimal;
+use std::{cmp::Ordering, str, sync::Arc, time::Duration};
+
+/// The first tag of the private range used for variants CBOR can't
+/// express on it's own. Each tag wraps the natural encoding of the
+/// variant.
+pub const TAG_BASE: u64 = 0x6e78_0000;
+/// `U32`, wraps an unsigned integer
+pub const TAG_U32: u64 = TAG_BASE;
+/// `V32`, wraps an unsigned integer
+pub const TAG_V32: u64 = TAG_BASE + 1;
+/// `I32`, wraps an integer
+pub const TAG_I32: u64 = TAG_BASE + 2;
+/// `Z32`, wraps an integer
+pub const TAG_Z32: u64 = TAG_BASE + 3;
+/// `U64`, wraps an unsigned integer
+pub const TAG_U64: u64 = TAG_BASE + 4;
+/// `V64`, wraps an unsigned integer
+pub const TAG_V64: u64 = TAG_BASE + 5;
+/// `Z64`, wraps an integer
+pub const TAG_Z64: u64 = TAG_BASE + 6;
+/// `Ok`, wraps null
+pub const TAG_OK: u64 = TAG_BASE + 7;
+/// `Error`, wraps the message
+pub const TAG_ERROR: u64 = TAG_BASE + 8;
+/// `ErrorInfo`, wraps `[code, message]` or `[code, message, payload]`
+pub const TAG_ERROR_INFO: u64 = TAG_BASE + 9;
+
+const TAG_RFC3339: u64 = 0;
+const TAG_EPOCH: u64 = 1;
+const TAG_POS_BIGNUM: u64 = 2;
+const TAG_NEG_BIGNUM: u64 = 3;
+const TAG_DECIMAL: u64 = 4;
+const TAG_TIME: u64 = 1001;
+const TAG_DURATION: u64 = 1002;
+
+// keys of the time and duration maps
+const KEY_SECS: i64 = 1;
+const KEY_MILLIS: i64 = -3;
+const KEY_MICROS: i64 = -6;
+const KEY_NANOS: i64 = -9;
+
+const MAX_DEPTH: usize = 128;
+
+const UINT: u8 = 0;
+const NINT: u8 = 1;
+const BYTES: u8 = 2;
+const TEXT: u8 = 3;
+const ARRAY: u8 = 4;
+const MAP: u8 = 5;
+const TAG: u8 = 6;
+const SIMPLE: u8 = 7;
+
+const FALSE: u8 = 0xf4;
+const TRUE: u8 = 0xf5;
+const NULL: u8 = 0xf6;
+const F32: u8 = 0xfa;
+const F64: u8 = 0xfb;
+
+fn head(buf: &mut Vec<u8>, major: u8, n: u64) {
+    let major = major << 5;
+    if n < 24 {
+        buf.push(major | n as u8)
+    } else if n <= u8::MAX as u64 {
+        buf.push(major | 24);
+        buf.push(n as u8)
+    } else if n <= u16::MAX as u64 {
+        buf.push(major | 25);
+        buf.extend_from_slice(&(n as u16).to_be_bytes())
+    } else if n <= u32::MAX as u64 {
+        buf.push(major | 26);
+        buf.extend_from_slice(&(n as u32).to_be_bytes())
+    } else {
+        buf.push(major | 27);
+        buf.extend_from_slice(&n.to_be_bytes())
+    }
+}
+
+fn int(buf: &mut Vec<u8>, i: i64) {
+    if i >= 0 {
+        head(buf, UINT, i as u64)
+    } else {
+        head(buf, NINT, !i as u64)
+    }
+}
+
+fn int128(buf: &mut Vec<u8>, i: i128) {
+    let (major, tag, n) =
+        if i >= 0 { (UINT, TAG_POS_BIGNUM, i) } else { (NINT, TAG_NEG_BIGNUM, !i) };
+    match u64::try_from(n) {
+        Ok(n) => head(buf, major, n),
+        Err(_) => {
+            let b = n.to_be_bytes();
+            let zeros = b.iter().take_while(|b| **b == 0).count();
+            head(buf, TAG, tag);
+            head(buf, BYTES, (b.len() - zeros) as u64);
+            buf.extend_from_slice(&b[zeros..])
+        }
+    }
+}
+
+fn text(buf: &mut Vec<u8>, s: &str) {
+    head(buf, TEXT, s.len() as u64);
+    buf.extend_from_slice(s.as_bytes())
+}
+
+// a time or duration map, seconds and then nanoseconds if there are any
+fn time(buf: &mut Vec<u8>, tag: u64, secs: i64, nanos: u32) {
+    head(buf, TAG, tag);
+    head(buf, MAP, if nanos == 0 { 1 } else { 2 });
+    int(buf, KEY_SECS);
+    int(buf, secs);
+    if nanos > 0 {
+        int(buf, KEY_NANOS);
+        head(buf, UINT, nanos as u64)
+    }
+}
+
+fn encode(buf: &mut Vec<u8>, v: &Value) {
+    match v {
+        Value::U32(n) => {
+            head(buf, TAG, TAG_U32);
+            head(buf, UINT, *n as u64)
+        }
+        Value::V32(n) => {
+            head(buf, TAG, TAG_V32);
+            head(buf, UINT, *n as u64)
+        }
+        Value::I32(n) => {
+            head(buf, TAG, TAG_I32);
+            int(buf, *n as i64)
+        }
+        Value::Z32(n) => {
+            head(buf, TAG, TAG_Z32);
+            int(buf, *n as i64)
+        }
+        Value::U64(n) => {
+            head(buf, TAG, TAG_U64);
+            head(buf, UINT, *n)
+        }
+        Value::V64(n) => {
+            head(buf, TAG, TAG_V64);
+            head(buf, UINT, *n)
+        }
+        Value::I64(n) => int(buf, *n),
+        Value::Z64(n) => {
+            head(buf, TAG, TAG_Z64);
+            int(buf, *n)
+        }
+        Value::F32(n) => {
+            buf.push(F32);
+            buf.extend_from_slice(&n.to_bits().to_be_bytes())
+        }
+        Value::F64(n) => {
+            buf.push(F64);
+            buf.extend_from_slice(&n.to_bits().to_be_bytes())
+        }
+        Value::DateTime(d) => {
+            time(buf, TAG_TIME, d.timestamp(), d.timestamp_subsec_nanos())
+        }
+        Value::Duration(d) => {
+            // durations are unsigned, so the seconds are encoded as u64
+            head(buf, TAG, TAG_DURATION);
+            head(buf, MAP, if d.subsec_nanos() == 0 { 1 } else { 2 });
+            int(buf, KEY_SECS);
+            head(buf, UINT, d.as_secs());
+            if d.subsec_nanos() > 0 {
+                int(buf, KEY_NANOS);
+                head(buf, UINT, d.subsec_nanos() as u64)
+            }
+        }
+        Value::String(s) => text(buf, s),
+        Value::Bytes(b) => {
+            head(buf, BYTES, b.len() as u64);
+            buf.extend_from_slice(b)
+        }
+        Value::True => buf.push(TRUE),
+        Value::False => buf.push(FALSE),
+        Value::Null => buf.push(NULL),
+        Value::Ok => {
+            head(buf, TAG, TAG_OK);
+            buf.push(NULL)
+        }
+        Value::Error(e) => {
+            head(buf, TAG, TAG_ERROR);
+            text(buf, e)
+        }
+        Value::Array(a) => {
+            head(buf, ARRAY, a.len() as u64);
+            for v in a.iter() {
+                encode(buf, v)
+            }
+        }
+        Value::Decimal(d) => {
+            head(buf, TAG, TAG_DECIMAL);
+            head(buf, ARRAY, 2);
+            int(buf, -(d.scale() as i64));
+            int128(buf, d.mantissa())
+        }
+        Value::ErrorInfo(e) => {
+            head(buf, TAG, TAG_ERROR_INFO);
+            head(buf, ARRAY, if e.payload.is_some() { 3 } else { 2 });
+            head(buf, UINT, e.code as u64);
+            text(buf, &e.message);
+            if let Some(v) = &e.payload {
+                encode(buf, v)
+            }
+        }
+    }
+}
+
+/// Encode `fields` as a CBOR map from text keys to values, with the
+/// keys in canonical order. This is for building self describing
+/// records around values, e.g. the messages of a bridge. Such a map
+/// decodes to an array of `[key, value]` pairs.
+pub fn encode_map<'a>(fields: impl IntoIterator<Item = (&'a str, &'a Value)>) -> Vec<u8> {
+    let mut fields = fields.into_iter().collect::<Vec<_>>();
+    // canonical order is shorter keys first, then bytewise
+    fields.sort_by(|(k0, _), (k1, _)| match k0.len().cmp(&k1.len()) {
+        Ordering::Equal => k0.as_bytes().cmp(k1.as_bytes()),
+        o => o,
+    });
+    let mut buf = Vec::new();
+    head(&mut buf, MAP, fields.len() as u64);
+    for (k, v) in fields {
+        text(&mut buf, k);
+        encode(&mut buf, v)
+    }
+    buf
+}
+
+fn take<'a>(buf: &mut &'a [u8], n: u64) -> Result<&'a [u8]> {
+    match usize::try_from(n) {
+        Ok(n) if n <= buf.len() => {
+            let (a, b) = buf.split_at(n);
+            *buf = b;
+            Ok(a)
+        }
+        _ => bail!("truncated cbor"),
+    }
+}
+
+// the major type, the additional info, and the argument
+fn read_head(buf: &mut &[u8]) -> Result<(u8, u8, u64)> {
+    let b = take(buf, 1)?[0];
+    let (major, info) = (b >> 5, b & 0x1f);
+    let n = match info {
+        0..=23 => info as u64,
+        24 => take(buf, 1)?[0] as u64,
+        25 => u16::from_be_bytes(take(buf, 2)?.try_into()?) as u64,
+        26 => u32::from_be_bytes(take(buf, 4)?.try_into()?) as u64,
+        27 => u64::from_be_bytes(take(buf, 8)?.try_into()?),
+        31 => bail!("indefinite lengths are not supported"),
+        _ => bail!("invalid cbor additional info {}", info),
+    };
+    Ok((major, info, n))
+}
+
+fn read_int(buf: &mut &[u8]) -> Result<i64> {
+    match read_head(buf)? {
+        (UINT, _, n) => Ok(i64::try_from(n)?),
+        (NINT, _, n) => Ok(!i64::try_from(n)?),
+        (major, _, _) => bail!("expected an integer, got major type {}", major),
+    }
+}
+
+fn read_uint(buf: &mut &[u8]) -> Result<u64> {
+    match read_head(buf)? {
+        (UINT, _, n) => Ok(n),
+        (major, _, _) => bail!("expected an unsigned integer, got major type {}", major),
+    }
+}
+
+fn read_int128(buf: &mut &[u8]) -> Result<i128> {
+    match read_head(buf)? {
+        (UINT, _, n) => Ok(n as i128),
+        (NINT, _, n) => Ok(!(n as i128)),
+        (TAG, _, tag @ (TAG_POS_BIGNUM | TAG_NEG_BIGNUM)) => {
+            let len = match read_head(buf)? {
+                (BYTES, _, len) => len,
+                (major, _, _) => bail!("expected a bignum, got major type {}", major),
+            };
+            let b = take(buf, len)?;
+            if b.len() > 15 {
+                bail!("bignum too large")
+            }
+            let n = b.iter().fold(0i128, |n, b| (n << 8) | *b as i128);
+            Ok(if tag == TAG_POS_BIGNUM { n } else { !n })
+        }
+        (major, _, _) => bail!("expected an integer, got major type {}", major),
+    }
+}
+
+fn read_text(buf: &mut &[u8]) -> Result<Chars> {
+    match read_head(buf)? {
+        (TEXT, _, len) => Ok(Chars::from(String::from(str::from_utf8(take(buf, len)?)?))),
+        (major, _, _) => bail!("expected text, got major type {}", major),
+    }
+}
+
+fn read_len(buf: &mut &[u8], expected: u8) -> Result<u64> {
+    match read_head(buf)? {
+        (major, _, len) if major == expected => Ok(len),
+        (major, _, _) => bail!("expected major type {}, got {}", expected, major),
+    }
+}
+
+// read an RFC 9581 time or duration map, return seconds and nanoseconds
+fn read_time(buf: &mut &[u8]) -> Result<(i128, u32)> {
+    let mut secs = None;
+    let mut nanos = 0u64;
+    for _ in 0..read_len(buf, MAP)? {
+        match read_int(buf)? {
+            KEY_SECS => secs = Some(read_int128(buf)?),
+            KEY_MILLIS => nanos = read_uint(buf)?.saturating_mul(1_000_000),
+            KEY_MICROS => nanos = read_uint(buf)?.saturating_mul(1_000),
+            KEY_NANOS => nanos = read_uint(buf)?,
+            k => bail!("unsupported time key {}", k),
+        }
+    }
+    match secs {
+        Some(secs) => Ok((secs, u32::try_from(nanos)?)),
+        None => bail!("time is missing seconds"),
+    }
+}
+
+fn datetime(secs: i64, nanos: u32) -> Result<Value> {
+    match Utc.timestamp_opt(secs, nanos).single() {
+        Some(d) => Ok(Value::DateTime(d)),
+        None => bail!("timestamp out of range"),
+    }
+}
+
+fn decode_tagged(buf: &mut &[u8], tag: u64, depth: usize) -> Result<Value> {
+    match tag {
+        TAG_U32 => Ok(Value::U32(u32::try_from(read_uint(buf)?)?)),
+        TAG_V32 => Ok(Value::V32(u32::try_from(read_uint(buf)?)?)),
+        TAG_I32 => Ok(Value::I32(i32::try_from(read_int(buf)?)?)),
+        TAG_Z32 => Ok(Value::Z32(i32::try_from(read_int(buf)?)?)),
+        TAG_U64 => Ok(Value::U64(read_uint(buf)?)),
+        TAG_V64 => Ok(Value::V64(read_uint(buf)?)),
+        TAG_Z64 => Ok(Value::Z64(read_int(buf)?)),
+        TAG_OK => match take(buf, 1)?[0] {
+            NULL => Ok(Value::Ok),
+            _ => bail!("expected null"),
+        },
+        TAG_ERROR => Ok(Value::Error(read_text(buf)?)),
+        TAG_ERROR_INFO => {
+            let len = read_len(buf, ARRAY)?;
+            if len != 2 && len != 3 {
+                bail!("expected 2 or 3 elements, got {}", len)
+            }
+            let code = u32::try_from(read_uint(buf)?)?;
+            let message = read_text(buf)?;
+            let payload = if len == 3 { Some(decode(buf, depth + 1)?) } else { None };
+            Ok(Value::ErrorInfo(Arc::new(ErrorInfo { code, message, payload })))
+        }
+        TAG_DECIMAL => {
+            if read_len(buf, ARRAY)? != 2 {
+                bail!("expected [exponent, mantissa]")
+            }
+            let exp = read_int(buf)?;
+            let mantissa = read_int128(buf)?;
+            let (mantissa, scale) = if exp <= 0 {
+                (mantissa, u32::try_from(exp.unsigned_abs())?)
+            } else {
+                let m = u32::try_from(exp)
+                    .ok()
+                    .and_then(|exp| 10i128.checked_pow(exp))
+                    .and_then(|p| mantissa.checked_mul(p));
+                match m {
+                    Some(m) => (m, 0),
+                    None => bail!("decimal out of range"),
+                }
+            };
+            Ok(Value::Decimal(Decimal::try_from_i128_with_scale(mantissa, scale)?))
+        }
+        TAG_TIME => {
+            let (secs, nanos) = read_time(buf)?;
+            datetime(i64::try_from(secs)?, nanos)
+        }
+        TAG_DURATION => {
+            let (secs, nanos) = read_time(buf)?;
+            let d = Duration::from_secs(u64::try_from(secs)?)
+                .checked_add(Duration::from_nanos(nanos as u64));
+            match d {
+                Some(d) => Ok(Value::Duration(d)),
+                None => bail!("duration out of range"),
+            }
+        }
+        TAG_RFC3339 => {
+            let s = read_text(buf)?;
+            Ok(Value::DateTime(DateTime::parse_from_rfc3339(&s)?.with_timezone(&Utc)))
+        }
+        TAG_EPOCH => match decode(buf, depth + 1)? {
+            Value::I64(secs) => datetime(secs, 0),
+            Value::F64(f) if f.is_finite() => {
+                let secs = f.floor();
+                datetime(secs as i64, ((f - secs) * 1e9) as u32)
+            }
+            v => bail!("invalid epoch time {}", v),
+        },
+        // the meaning of unknown tags is lost, but not the content
+        _ => decode(buf, depth + 1),
+    }
+}
+
+fn f16_to_f64(h: u16) -> f64 {
+    let sign = if h & 0x8000 != 0 { -1. } else { 1. };
+    let exp = (h >> 10) & 0x1f;
+    let mant = (h & 0x3ff) as f64;
+    sign * match exp {
+        0 => mant * 2f64.powi(-24),
+        31 if mant == 0. => f64::INFINITY,
+        31 => f64::NAN,
+        e => (1. + mant / 1024.) * 2f64.powi(e as i32 - 15),
+    }
+}
+
+fn decode(buf: &mut &[u8], depth: usize) -> Result<Value> {
+    if depth > MAX_DEPTH {
+        bail!("cbor nested too deeply")
+    }
+    match read_head(buf)? {
+        (UINT, _, n) => Ok(match i64::try_from(n) {
+            Ok(n) => Value::I64(n),
+            Err(_) => Value::U64(n),
+        }),
+        (NINT, _, n) => Ok(Value::I64(!i64::try_from(n)?)),
+        (BYTES, _, len) => Ok(Value::Bytes(Bytes::copy_from_slice(take(buf, len)?))),
+        (TEXT, _, len) => {
+            Ok(Value::String(Chars::from(String::from(str::from_utf8(take(buf, len)?)?))))
+        }
+        (ARRAY, _, len) => {
+            let mut elts = Vec::with_capacity(len.min(buf.len() as u64) as usize);
+            for _ in 0..len {
+                elts.push(decode(buf, depth + 1)?)
+            }
+            Ok(Value::Array(Arc::from(elts)))
+        }
+        (MAP, _, len) => {
+            let mut pairs = Vec::with_capacity(len.min(buf.len() as u64) as usize);
+            for _ in 0..len {
+                let k = decode(buf, depth + 1)?;
+                let v = decode(buf, depth + 1)?;
+                pairs.push(Value::Array(Arc::from(vec![k, v])))
+            }
+            Ok(Value::Array(Arc::from(pairs)))
+        }
+        (TAG, _, tag) => decode_tagged(buf, tag, depth),
+        (SIMPLE, info, n) => match info {
+            20 => Ok(Value::False),
+            21 => Ok(Value::True),
+            22 | 23 => Ok(Value::Null),
+            25 => Ok(Value::F64(f16_to_f64(n as u16))),
+            26 => Ok(Value::F32(f32::from_bits(n as u32))),
+            27 => Ok(Value::F64(f64::from_bits(n))),
+            _ => bail!("unsupported simple value {}", n),
+        },
+        (major, _, _) => bail!("invalid major type {}", major),
+    }
+}
+
+impl Value {
+    /// Encode the value as canonical CBOR, see the `cbor` module.
+    pub fn to_cbor(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode(&mut buf, self);
+        buf
+    }
+
+    /// Decode a value from a single CBOR data item, see the `cbor`
+    /// module. Trailing bytes are an error.
+    pub fn from_cbor(mut buf: &[u8]) -> Result<Value> {
+        let v = decode(&mut buf, 0)?;
+        if !buf.is_empty() {
+            bail!("{} trailing bytes after cbor value", buf.len())
+        }
+        Ok(v)
+    }
+}
@@ -0,0 +1,97 @@
+//! Compact updates of string values. When a string differs from the
+//! previous value of the same id in only a few characters, as e.g.
+//! market data often does, a publisher can send a `StrDelta` to
+//! subscribers that asked for deltas instead of the whole string.
+//! The delta keeps a prefix and a suffix of the previous value and
+//! replaces everything between them.
+use crate::value::Value;
+use anyhow::Result;
+use netidx_core::{
+    chars::Chars,
+    pack::{varint_len, Pack},
+};
+use netidx_derive::Pack;
+
+// FNV-1a, used to check that a delta is applied to the value it was
+// made from. It must be the same on every platform.
+fn checksum(s: &str) -> u32 {
+    s.as_bytes()
+        .iter()
+        .fold(0x811c9dc5u32, |h, b| (h ^ *b as u32).wrapping_mul(0x01000193))
+}
+
+/// The change from one string to another
+#[derive(Debug, Clone, PartialEq, Eq, Pack)]
+pub struct StrDelta {
+    /// the checksum of the previous value
+    pub base: u32,
+    /// the number of bytes kept from the start of the previous value
+    pub prefix: u32,
+    /// the number of bytes kept from the end of the previous value
+    pub suffix: u32,
+    /// the bytes that replace everything else
+    pub middle: Chars,
+}
+
+impl StrDelta {
+    /// Compute the delta from `old` to `new`. Returns `None` if the
+    /// delta wouldn't be smaller than `new` itself, in which case the
+    /// whole value should be sent.
+    pub fn diff(old: &str, new: &str) -> Option<StrDelta> {
+        let (ob, nb) = (old.as_bytes(), new.as_bytes());
+        let max = ob.len().min(nb.len());
+        let mut prefix = ob.iter().zip(nb).take_while(|(o, n)| o == n).count();
+        while !new.is_char_boundary(prefix) {
+            prefix -= 1;
+        }
+        let mut suffix = ob
+            .iter()
+            .rev()
+            .zip(nb.iter().rev())
+            .take(max - prefix)
+            .take_while(|(o, n)| o == n)
+            .count();
+        while !new.is_char_boundary(nb.len() - suffix) {
+            suffix -= 1;
+        }
+        let delta = StrDelta {
+            base: checksum(old),
+            prefix: u32::try_from(prefix).ok()?,
+            suffix: u32::try_from(suffix).ok()?,
+            middle: Chars::from(String::from(&new[prefix..nb.len() - suffix])),
+        };
+        // a string value is a tag byte followed by the string
+        let full = 1 + varint_len(nb.len() as u64) + nb.len();
+        if delta.encoded_len() < full {
+            Some(delta)
+        } else {
+            None
+        }
+    }
+
+    /// Apply the delta to `old`. Fails if `old` isn't the value the
+    /// delta was made from.
+    pub fn apply(&self, old: &str) -> Result<Chars> {
+        let (prefix, suffix) = (self.prefix as usize, self.suffix as usize);
+        if checksum(old) != self.base
+            || prefix + suffix > old.len()
+            || !old.is_char_boundary(prefix)
+            || !old.is_char_boundary(old.len() - suffix)
+        {
+            bail!("delta does not apply to the previous value")
+        }
+        let mut s = String::with_capacity(prefix + self.middle.len() + suffix);
+        s.push_str(&old[..prefix]);
+        s.push_str(&self.middle);
+        s.push_str(&old[old.len() - suffix..]);
+        Ok(Chars::from(s))
+    }
+
+    /// Apply the delta to a value, which must be a string
+    pub fn apply_value(&self, old: &Value) -> Result<Value> {
+        match old {
+            Value::String(s) => Ok(Value::String(self.apply(s)?)),
+            v => bail!("can't apply a string delta to {}", v),
+        }
+    }
+}
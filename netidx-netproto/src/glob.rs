@@ -111,6 +111,11 @@ impl Glob {
         Ok(Glob { raw, base, scope, glob })
     }
 
+    /// the glob pattern as it was originally written
+    pub fn raw(&self) -> &Chars {
+        &self.raw
+    }
+
     pub fn base(&self) -> &str {
         &self.base
     }
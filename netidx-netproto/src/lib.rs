@@ -4,11 +4,16 @@
 #[macro_use] extern crate netidx_core;
 #[macro_use] extern crate serde_derive;
 
+pub mod cbor;
+pub mod delta;
 pub mod glob;
+pub mod patch;
 pub mod publisher;
 pub mod value_parser;
 pub mod value;
+pub mod value_serde;
 pub mod resolver;
+pub mod schema;
 
 #[cfg(test)]
 mod test;
@@ -0,0 +1,172 @@
+//! Partial updates of array values. Rather than republishing a large
+//! array when a few elements change, a publisher can send a `Patch`
+//! describing the change, which subscribers that asked for patches
+//! apply to the last value they received. Structs, which are arrays
+//! of `[name, value]` pairs, are patched the same way, the path to a
+//! field's value is `[field index, 1]`.
+use crate::value::Value;
+use anyhow::Result;
+use netidx_derive::Pack;
+use smallvec::SmallVec;
+use std::{mem, sync::Arc};
+
+/// The location of an element in a value. Each index selects an
+/// element of a nested array, the empty path is the value itself.
+pub type PatchPath = SmallVec<[u32; 4]>;
+
+/// One change to a value
+#[derive(Debug, Clone, PartialEq, Pack)]
+pub enum Edit {
+    /// Replace the element at the path
+    Set(PatchPath, Value),
+    /// Append elements to the array at the path
+    Extend(PatchPath, Vec<Value>),
+    /// Shorten the array at the path to the specified length
+    Truncate(PatchPath, u32),
+}
+
+impl Edit {
+    pub fn path(&self) -> &[u32] {
+        match self {
+            Edit::Set(p, _) | Edit::Extend(p, _) | Edit::Truncate(p, _) => &p[..],
+        }
+    }
+}
+
+// a value with the arrays along the edited paths unpacked, so a
+// patch with many edits copies each array at most once
+enum Node {
+    Val(Value),
+    Arr(Vec<Node>),
+}
+
+impl Node {
+    fn elts(&mut self) -> Result<&mut Vec<Node>> {
+        let unpacked = match self {
+            Node::Val(Value::Array(a)) => {
+                Some(a.iter().cloned().map(Node::Val).collect())
+            }
+            Node::Val(_) | Node::Arr(_) => None,
+        };
+        if let Some(elts) = unpacked {
+            *self = Node::Arr(elts);
+        }
+        match self {
+            Node::Arr(elts) => Ok(elts),
+            Node::Val(v) => bail!("expected an array, got {}", v),
+        }
+    }
+
+    fn child(&mut self, i: u32) -> Result<&mut Node> {
+        let elts = self.elts()?;
+        let len = elts.len();
+        elts.get_mut(i as usize)
+            .ok_or_else(|| anyhow!("index {} out of bounds, length {}", i, len))
+    }
+
+    fn into_value(self) -> Value {
+        match self {
+            Node::Val(v) => v,
+            Node::Arr(elts) => Value::Array(Arc::from(
+                elts.into_iter().map(Node::into_value).collect::<Vec<_>>(),
+            )),
+        }
+    }
+}
+
+/// A list of edits, applied in order
+#[derive(Debug, Clone, PartialEq, Default, Pack)]
+pub struct Patch {
+    pub edits: Vec<Edit>,
+}
+
+impl Patch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.edits.is_empty()
+    }
+
+    /// Replace the element at `path` with `v`
+    pub fn set<T: Into<Value>>(&mut self, path: &[u32], v: T) -> &mut Self {
+        self.edits.push(Edit::Set(PatchPath::from_slice(path), v.into()));
+        self
+    }
+
+    /// Append `elts` to the array at `path`
+    pub fn extend(
+        &mut self,
+        path: &[u32],
+        elts: impl IntoIterator<Item = Value>,
+    ) -> &mut Self {
+        let elts = elts.into_iter().collect();
+        self.edits.push(Edit::Extend(PatchPath::from_slice(path), elts));
+        self
+    }
+
+    /// Shorten the array at `path` to `len` elements
+    pub fn truncate(&mut self, path: &[u32], len: u32) -> &mut Self {
+        self.edits.push(Edit::Truncate(PatchPath::from_slice(path), len));
+        self
+    }
+
+    /// Return the result of applying the patch to `v`. Fails if a
+    /// path doesn't lead to an element of `v`, or an `Extend` or
+    /// `Truncate` isn't applied to an array, in which case the
+    /// patch was made for a different value.
+    pub fn apply(&self, v: &Value) -> Result<Value> {
+        let mut root = Node::Val(v.clone());
+        for edit in &self.edits {
+            let mut node = &mut root;
+            for i in edit.path() {
+                node = node.child(*i)?;
+            }
+            match edit {
+                Edit::Set(_, v) => *node = Node::Val(v.clone()),
+                Edit::Extend(_, vs) => {
+                    node.elts()?.extend(vs.iter().cloned().map(Node::Val))
+                }
+                Edit::Truncate(_, len) => node.elts()?.truncate(*len as usize),
+            }
+        }
+        Ok(root.into_value())
+    }
+
+    /// Compute a patch that transforms `old` into `new`. Arrays are
+    /// compared element by element, and nested arrays recursively,
+    /// anything else that differs is replaced.
+    pub fn diff(old: &Value, new: &Value) -> Patch {
+        fn go(path: &mut PatchPath, old: &Value, new: &Value, patch: &mut Patch) {
+            match (old, new) {
+                (Value::Array(o), Value::Array(n)) => {
+                    if Arc::ptr_eq(o, n) {
+                        return;
+                    }
+                    for (i, (o, n)) in o.iter().zip(n.iter()).enumerate() {
+                        path.push(i as u32);
+                        go(path, o, n, patch);
+                        path.pop();
+                    }
+                    if n.len() > o.len() {
+                        let elts = n[o.len()..].iter().cloned().collect();
+                        patch.edits.push(Edit::Extend(path.clone(), elts))
+                    } else if n.len() < o.len() {
+                        patch.edits.push(Edit::Truncate(path.clone(), n.len() as u32))
+                    }
+                }
+                (o, n) => {
+                    // numbers of different types can be equal, so check
+                    // the variant before comparing the values
+                    if mem::discriminant(o) != mem::discriminant(n) || o != n {
+                        patch.edits.push(Edit::Set(path.clone(), n.clone()))
+                    }
+                }
+            }
+        }
+        let mut patch = Patch::new();
+        go(&mut PatchPath::new(), old, new, &mut patch);
+        patch
+    }
+}
@@ -1,24 +1,47 @@
-use crate::{resolver::UserInfo, value::Value};
+use crate::{delta::StrDelta, patch::Patch, resolver::UserInfo, value::Value};
+use arcstr::ArcStr;
 use bytes::Bytes;
-use netidx_core::path::Path;
+use netidx_core::{chars::Chars, path::Path};
 use netidx_derive::Pack;
-use std::net::SocketAddr;
+use std::{mem, net::SocketAddr};
 
 atomic_id!(Id);
 
+/// A shared memory segment offered by a subscriber to a publisher on
+/// the same host. If the publisher accepts it echoes the offer back
+/// in it's reply hello, and the connection will then use the segment
+/// instead of the tcp stream. Otherwise it replies with `None` and
+/// the connection stays on tcp.
+#[derive(Debug, Clone, PartialEq, Eq, Pack)]
+pub struct ShmOffer {
+    /// the name of the segment, as passed to shm_open
+    pub name: ArcStr,
+    /// the size in bytes of each of the two rings in the segment
+    pub ring_size: u64,
+}
+
+/// An idempotency key attached to a write, see `To::Write`.
+/// `writer` identifies the writer, and should stay the same across
+/// reconnects, `seq` identifies the write among the writer's writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pack)]
+pub struct WriteKey {
+    pub writer: u64,
+    pub seq: u64,
+}
+
 #[derive(Debug, Clone, PartialEq, Eq, Pack)]
 pub enum Hello {
     /// No authentication will be provided. The publisher may drop
     /// the connection at this point, if it chooses to allow this
     /// then it will return Anonymous.
-    Anonymous,
+    Anonymous(#[pack(default)] Option<ShmOffer>),
     /// Authenticate using kerberos 5, following the hello, the
     /// subscriber and publisher will exchange tokens to complete the
     /// authentication.
     Krb5(#[pack(default)] Option<UserInfo>),
     /// Authenticate using a local unix socket, only valid for
     /// publishers on the same machine as the subscriber.
-    Local(#[pack(default)] Option<UserInfo>),
+    Local(#[pack(default)] Option<UserInfo>, #[pack(default)] Option<ShmOffer>),
     /// In order to prevent denial of service, spoofing, etc,
     /// authenticated publishers must prove that they are actually
     /// listening on the socket they claim to be listening on. To
@@ -45,20 +68,90 @@ pub enum To {
     /// the result will be NoSuchValue. The optional security
     /// token is a proof from the resolver server that this
     /// subscription is permitted. In the case of an anonymous
-    /// connection this proof will be empty.
+    /// connection this proof will be empty. If `patches` is true
+    /// then the subscriber can apply `From::Patch`, and if `deltas` is
+    /// true it can apply `From::Delta`. Publishers treat both as
+    /// properties of the connection, so they should be the same in
+    /// every subscribe sent on it.
+    ///
+    /// If `glob` is given this is a subscribe many, which subscribes
+    /// to every value published under `path` that matches `glob`,
+    /// relative to `path`, and the token must be the
+    /// `PublisherRef::subtree_token` the resolver gave for `path`. The
+    /// result is one or more `From::SubscribedMany`. Publishers that
+    /// are older than subscribe many ignore `glob`, and reply as if it
+    /// was a normal subscribe to `path`.
+    ///
+    /// If `error_info` is true then the subscriber can decode
+    /// `Value::ErrorInfo`. Publishers send subscribers that can't a
+    /// `Value::Error` with the same message instead. Like `patches` it
+    /// is a property of the connection.
     Subscribe {
         path: Path,
         resolver: SocketAddr,
         timestamp: u64,
         permissions: u32,
         token: Bytes,
+        #[pack(default)]
+        patches: bool,
+        #[pack(default)]
+        deltas: bool,
+        #[pack(default)]
+        glob: Option<Chars>,
+        #[pack(default)]
+        error_info: bool,
     },
     /// Unsubscribe from the specified value, this will always result
     /// in an Unsubscribed message even if you weren't ever subscribed
     /// to the value, or it doesn't exist.
     Unsubscribe(Id),
-    /// Send a write to the specified value.
-    Write(Id, bool, Value),
+    /// Send a write to the specified value. If the bool is true the
+    /// publisher will reply with a `WriteResult`. If a key is given,
+    /// and the publisher has recently received a write with the
+    /// same key from the same user, it will not execute the write
+    /// again. Publishers that are older than keys ignore them.
+    Write(Id, bool, Value, #[pack(default)] Option<WriteKey>),
+}
+
+/// Why a publisher unsubscribed a subscriber from a value
+#[derive(
+    Debug,
+    Clone,
+    Copy,
+    Default,
+    PartialEq,
+    Eq,
+    PartialOrd,
+    Ord,
+    Hash,
+    Pack,
+    Serialize,
+    Deserialize,
+)]
+pub enum UnsubscribeReason {
+    /// The subscriber asked to be unsubscribed, or the publisher
+    /// didn't give a reason, e.g. because it is older than reason
+    /// codes.
+    #[default]
+    Unspecified,
+    /// The value is no longer published
+    Unpublished,
+    /// The subscriber is no longer permitted to subscribe
+    Denied,
+    /// The publisher shed the subscription because it is overloaded
+    Overloaded,
+    /// The value is now published somewhere else, e.g. because the
+    /// publisher moved to a new address. Resubscribing will find it.
+    Replaced,
+}
+
+/// What a subscriber may do with a value, as far as the publisher
+/// knew when it was subscribed. `write` is true only if the
+/// subscriber has write permission and the value accepts writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Pack)]
+pub struct ValPermissions {
+    pub read: bool,
+    pub write: bool,
 }
 
 #[derive(Debug, Clone, PartialEq, Pack)]
@@ -71,18 +164,64 @@ pub enum From {
     /// You have been unsubscriped from Path. This can be the result
     /// of an Unsubscribe message, or it may be sent unsolicited, in
     /// the case the value is no longer published, or the publisher is
-    /// in the process of shutting down.
-    Unsubscribed(Id),
+    /// in the process of shutting down, in which case the reason says
+    /// why.
+    Unsubscribed(Id, #[pack(default)] UnsubscribeReason),
     /// You are now subscribed to Path with subscription id `Id`, and
     /// The next message contains the first value for Id. All further
     /// communications about this subscription will only refer to the
-    /// Id.
-    Subscribed(Path, Id, Value),
+    /// Id. The subscriber's permissions for the value are included,
+    /// except by publishers that are older than permission hints.
+    Subscribed(Path, Id, Value, #[pack(default)] Option<ValPermissions>),
     /// A value update to Id
     Update(Id, Value),
     /// Indicates that the publisher is idle, but still
     /// functioning correctly.
     Heartbeat,
-    /// Indicates the result of a write request
-    WriteResult(Id, Value),
+    /// Indicates the result of a write request. The bool is true if
+    /// the write was a duplicate of a recent write with the same
+    /// key, and was not executed. In that case the value is the
+    /// result of the original write, if it is known, otherwise `Ok`.
+    WriteResult(Id, Value, #[pack(default)] bool),
+    /// An update to Id, expressed as a change to it's previous
+    /// value. Only sent to subscribers that asked for patches.
+    Patch(Id, Patch),
+    /// A string update to Id, expressed as a change to it's previous
+    /// value. Only sent to subscribers that asked for deltas.
+    Delta(Id, StrDelta),
+    /// The result of a subscribe many of Path, each element is like
+    /// a `Subscribed`. A large result is split into multiple messages,
+    /// the bool is true in the last one. Only sent to subscribers that
+    /// asked for a subscribe many.
+    SubscribedMany(Path, Vec<(Path, Id, Value)>, bool),
+}
+
+impl From {
+    /// return the message as a subscriber that did not ask for
+    /// `error_info` can decode it, see `Value::without_error_info`.
+    /// Patches are left alone, they are only sent to subscribers that
+    /// are newer than `ErrorInfo`.
+    pub fn without_error_info(self) -> From {
+        match self {
+            From::Subscribed(path, id, v, perms) => {
+                From::Subscribed(path, id, v.without_error_info(), perms)
+            }
+            From::Update(id, v) => From::Update(id, v.without_error_info()),
+            From::WriteResult(id, v, dup) => {
+                From::WriteResult(id, v.without_error_info(), dup)
+            }
+            From::SubscribedMany(path, mut subs, last) => {
+                for (_, _, v) in subs.iter_mut() {
+                    *v = mem::replace(v, Value::Null).without_error_info();
+                }
+                From::SubscribedMany(path, subs, last)
+            }
+            m @ (From::NoSuchValue(_)
+            | From::Denied(_)
+            | From::Unsubscribed(_, _)
+            | From::Heartbeat
+            | From::Patch(_, _)
+            | From::Delta(_, _)) => m,
+        }
+    }
 }
@@ -53,13 +53,28 @@ pub enum AuthWrite {
 pub struct ClientHelloWrite {
     pub write_addr: SocketAddr,
     pub auth: AuthWrite,
+    /// Another address the publisher listens on, of the other
+    /// address family than `write_addr`
+    #[pack(default)]
+    pub alt_addr: Option<SocketAddr>,
+}
+
+/// Optional features of the read protocol supported by the
+/// client. This is sent along with the read hello, servers that don't
+/// understand it will ignore it.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Pack)]
+pub struct ReadCaps {
+    /// The client will cache publisher records for the life of the
+    /// connection, so the server need only send each publisher once
+    /// per `FromRead::PublisherEpoch`.
+    pub publisher_cache: bool,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub enum ClientHello {
     /// Instruct the resolver server that this connection will not
     /// publish paths.
-    ReadOnly(AuthRead),
+    ReadOnly(AuthRead, #[pack(default)] ReadCaps),
     /// Instruct the resolver server that this connection will
     /// only publish paths. All published paths will use the
     /// specified address `write_addr`, and the publisher must
@@ -173,12 +188,21 @@ pub struct Publisher {
     pub target_auth: TargetAuth,
     #[pack(default)]
     pub user_info: Option<UserInfo>,
+    /// Another address the publisher listens on, of the other
+    /// address family than `addr`. Subscribers may use either.
+    #[pack(default)]
+    pub alt_addr: Option<SocketAddr>,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
 pub struct PublisherRef {
     pub id: PublisherId,
     pub token: Bytes,
+    /// Like `token`, but it also authorizes a subscribe many of the
+    /// resolved path. It is empty unless the permissions granted at
+    /// the path aren't reduced by a deny anywhere beneath it.
+    #[pack(default)]
+    pub subtree_token: Bytes,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Pack)]
@@ -243,6 +267,16 @@ pub enum FromRead {
     Error(Chars),
     ListMatching(ListMatching),
     GetChangeNr(GetChangeNr),
+    /// Only sent to clients that support the publisher cache. Any
+    /// cached publisher records from a different epoch are no longer
+    /// valid and must be discarded.
+    PublisherEpoch(u64),
+    /// The server is shutting down. Requests it hasn't answered
+    /// never will be, the client should reconnect to another member
+    /// of the cluster and send them there. Older clients fail to
+    /// decode this and treat it as a connection error, which has
+    /// the same effect.
+    Shutdown,
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
@@ -263,6 +297,13 @@ pub enum ToWrite {
     PublishDefaultWithFlags(Path, u32),
     /// Unpublish a default publisher
     UnpublishDefault(Path),
+    /// Delegate the subtree at the referral's path to the servers in
+    /// the referral, replacing any existing delegation of that
+    /// path. Requires the delegate permission at the path.
+    Delegate(Referral),
+    /// Remove the delegation of the subtree at path. Requires the
+    /// delegate permission at the path.
+    Undelegate(Path),
 }
 
 #[derive(Clone, Debug, PartialEq, Eq, Hash, Pack)]
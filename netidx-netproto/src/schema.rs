@@ -0,0 +1,158 @@
+//! Type descriptors that publishers may attach to values, so
+//! namespaces can describe themselves. The schema of a value at
+//! `path` is published at `path/.schema` (see `schema_path`) as an
+//! array of `[key, value]` pairs, the same representation the serde
+//! bridge uses for structs,
+//!
+//! - `typ`: the name of the value's `Typ`, e.g. `"f64"`
+//! - `doc`: a human readable description
+//! - `unit`: the unit of the value, e.g. `"m/s"`
+//! - `min`, `max`: the range of the value
+//! - `fields`: for records, an array of `[name, schema]` pairs
+//!
+//! All keys are optional, unknown keys are ignored so that new ones
+//! may be added later.
+use crate::value::{FromValue, Typ, Value};
+use anyhow::Result;
+use netidx_core::{chars::Chars, path::Path};
+use std::{convert, fmt};
+
+/// The last path component of the path a schema is published at
+pub const SCHEMA: &str = ".schema";
+
+/// The path the schema of `path` is published at
+pub fn schema_path(path: &Path) -> Path {
+    path.append(SCHEMA)
+}
+
+/// A description of a published value
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Schema {
+    /// The type of the value, `None` if it may vary
+    pub typ: Option<Typ>,
+    pub doc: Option<Chars>,
+    pub unit: Option<Chars>,
+    pub min: Option<Value>,
+    pub max: Option<Value>,
+    /// The fields of a record, in order
+    pub fields: Vec<(Chars, Schema)>,
+}
+
+impl Schema {
+    pub fn new(typ: Typ) -> Self {
+        Schema { typ: Some(typ), ..Schema::default() }
+    }
+
+    pub fn doc<T: Into<Chars>>(mut self, doc: T) -> Self {
+        self.doc = Some(doc.into());
+        self
+    }
+
+    pub fn unit<T: Into<Chars>>(mut self, unit: T) -> Self {
+        self.unit = Some(unit.into());
+        self
+    }
+
+    pub fn range<T: Into<Value>>(mut self, min: T, max: T) -> Self {
+        self.min = Some(min.into());
+        self.max = Some(max.into());
+        self
+    }
+
+    /// Add a field to a record schema
+    pub fn field<T: Into<Chars>>(mut self, name: T, schema: Schema) -> Self {
+        self.fields.push((name.into(), schema));
+        self
+    }
+}
+
+fn pair(k: &'static str, v: Value) -> Value {
+    Value::Array(vec![Value::from(k), v].into())
+}
+
+impl convert::From<&Schema> for Value {
+    fn from(s: &Schema) -> Value {
+        let mut pairs = vec![];
+        if let Some(doc) = &s.doc {
+            pairs.push(pair("doc", Value::String(doc.clone())));
+        }
+        if !s.fields.is_empty() {
+            let fields = s
+                .fields
+                .iter()
+                .map(|(n, f)| Value::Array(vec![Value::String(n.clone()), f.into()].into()))
+                .collect::<Vec<_>>();
+            pairs.push(pair("fields", Value::Array(fields.into())));
+        }
+        if let Some(max) = &s.max {
+            pairs.push(pair("max", max.clone()));
+        }
+        if let Some(min) = &s.min {
+            pairs.push(pair("min", min.clone()));
+        }
+        if let Some(typ) = &s.typ {
+            pairs.push(pair("typ", Value::from(typ.name())));
+        }
+        if let Some(unit) = &s.unit {
+            pairs.push(pair("unit", Value::String(unit.clone())));
+        }
+        Value::Array(pairs.into())
+    }
+}
+
+impl convert::From<Schema> for Value {
+    fn from(s: Schema) -> Value {
+        Value::from(&s)
+    }
+}
+
+impl FromValue for Schema {
+    fn from_value(v: Value) -> Result<Self> {
+        let mut s = Schema::default();
+        for (k, v) in v.cast_to::<Vec<(Chars, Value)>>()? {
+            match &*k {
+                "typ" => s.typ = Some(v.cast_to::<Chars>()?.parse::<Typ>()?),
+                "doc" => s.doc = Some(v.cast_to::<Chars>()?),
+                "unit" => s.unit = Some(v.cast_to::<Chars>()?),
+                "min" => s.min = Some(v),
+                "max" => s.max = Some(v),
+                "fields" => s.fields = v.cast_to::<Vec<(Chars, Schema)>>()?,
+                _ => (),
+            }
+        }
+        Ok(s)
+    }
+}
+
+impl fmt::Display for Schema {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.typ {
+            Some(typ) => write!(f, "{}", typ)?,
+            None if self.fields.is_empty() => write!(f, "any")?,
+            None => (),
+        }
+        if !self.fields.is_empty() {
+            write!(f, "{{")?;
+            for (i, (name, field)) in self.fields.iter().enumerate() {
+                if i > 0 {
+                    write!(f, ", ")?;
+                }
+                write!(f, "{}: {}", name, field)?;
+            }
+            write!(f, "}}")?;
+        }
+        if let Some(unit) = &self.unit {
+            write!(f, " [{}]", unit)?;
+        }
+        match (&self.min, &self.max) {
+            (None, None) => (),
+            (Some(min), None) => write!(f, " {}..", min)?,
+            (None, Some(max)) => write!(f, " ..{}", max)?,
+            (Some(min), Some(max)) => write!(f, " {}..{}", min, max)?,
+        }
+        if let Some(doc) = &self.doc {
+            write!(f, " \"{}\"", doc)?;
+        }
+        Ok(())
+    }
+}
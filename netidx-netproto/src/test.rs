@@ -65,8 +65,8 @@ mod resolver {
         resolver::{
             Auth, AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite,
             FromRead, FromWrite, GetChangeNr, HashMethod, ListMatching, Publisher,
-            PublisherId, PublisherRef, ReadyForOwnershipCheck, Referral, Resolved,
-            Secret, ServerHelloWrite, Table, TargetAuth, ToRead, ToWrite,
+            PublisherId, PublisherRef, ReadCaps, ReadyForOwnershipCheck, Referral,
+            Resolved, Secret, ServerHelloWrite, Table, TargetAuth, ToRead, ToWrite,
         },
     };
     use netidx_core::pack::PackError;
@@ -134,13 +134,20 @@ mod resolver {
     }
 
     fn client_hello_write() -> impl Strategy<Value = ClientHelloWrite> {
-        (any::<SocketAddr>(), auth_write())
-            .prop_map(|(write_addr, auth)| ClientHelloWrite { write_addr, auth })
+        (any::<SocketAddr>(), auth_write(), option(any::<SocketAddr>())).prop_map(
+            |(write_addr, auth, alt_addr)| ClientHelloWrite {
+                write_addr,
+                auth,
+                alt_addr,
+            },
+        )
     }
 
     fn client_hello() -> impl Strategy<Value = ClientHello> {
         prop_oneof![
-            auth_read().prop_map(ClientHello::ReadOnly),
+            (auth_read(), any::<bool>()).prop_map(|(auth, publisher_cache)| {
+                ClientHello::ReadOnly(auth, ReadCaps { publisher_cache })
+            }),
             client_hello_write().prop_map(ClientHello::WriteOnly)
         ]
     }
@@ -193,20 +200,26 @@ mod resolver {
         let hash_method = hash_method();
         let target_auth = target_auth();
         let user_info = option(user_info());
-        (resolver, id, addr, hash_method, target_auth, user_info).prop_map(
-            |(resolver, id, addr, hash_method, target_auth, user_info)| Publisher {
-                resolver,
-                id,
-                addr,
-                hash_method,
-                target_auth,
-                user_info,
+        let alt_addr = option(any::<SocketAddr>());
+        (resolver, id, addr, hash_method, target_auth, user_info, alt_addr).prop_map(
+            |(resolver, id, addr, hash_method, target_auth, user_info, alt_addr)| {
+                Publisher {
+                    resolver,
+                    id,
+                    addr,
+                    hash_method,
+                    target_auth,
+                    user_info,
+                    alt_addr,
+                }
             },
         )
     }
 
     fn publisher_ref() -> impl Strategy<Value = PublisherRef> {
-        (publisher_id(), bytes()).prop_map(|(id, token)| PublisherRef { id, token })
+        (publisher_id(), bytes(), bytes()).prop_map(|(id, token, subtree_token)| {
+            PublisherRef { id, token, subtree_token }
+        })
     }
 
     fn resolved() -> impl Strategy<Value = Resolved> {
@@ -294,7 +307,9 @@ mod resolver {
             table().prop_map(FromRead::Table),
             referral().prop_map(FromRead::Referral),
             Just(FromRead::Denied),
-            chars().prop_map(FromRead::Error)
+            chars().prop_map(FromRead::Error),
+            any::<u64>().prop_map(FromRead::PublisherEpoch),
+            Just(FromRead::Shutdown)
         ]
     }
 
@@ -317,7 +332,9 @@ mod resolver {
                 .prop_map(|(path, flags)| ToWrite::PublishWithFlags(path, flags)),
             (path(), any::<u32>())
                 .prop_map(|(path, flags)| ToWrite::PublishDefaultWithFlags(path, flags)),
-            path().prop_map(ToWrite::UnpublishDefault)
+            path().prop_map(ToWrite::UnpublishDefault),
+            referral().prop_map(ToWrite::Delegate),
+            path().prop_map(ToWrite::Undelegate)
         ]
     }
 
@@ -382,14 +399,67 @@ mod resolver {
             check(a)
         }
     }
+
+    #[test]
+    fn test_hello_write_compat() {
+        // ClientHelloWrite before alternate addresses were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        struct OldHelloWrite {
+            write_addr: SocketAddr,
+            auth: AuthWrite,
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let write_addr = "127.0.0.1:5000".parse::<SocketAddr>().unwrap();
+        let alt_addr = "[::1]:5000".parse::<SocketAddr>().unwrap();
+        let old = OldHelloWrite { write_addr, auth: AuthWrite::Anonymous };
+        let new = |alt_addr| ClientHelloWrite {
+            write_addr,
+            auth: AuthWrite::Anonymous,
+            alt_addr,
+        };
+        let h: ClientHelloWrite = recode(&old);
+        assert_eq!(h, new(None));
+        let h: OldHelloWrite = recode(&new(Some(alt_addr)));
+        assert_eq!(h, old);
+    }
+
+    #[test]
+    fn test_publisher_ref_compat() {
+        // PublisherRef before subtree tokens were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        struct OldPublisherRef {
+            id: PublisherId,
+            token: Bytes,
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let id = PublisherId::mk(42);
+        let token = Bytes::from_static(b"token");
+        let old = OldPublisherRef { id, token: token.clone() };
+        let r: PublisherRef = recode(&old);
+        let subtree_token = Bytes::new();
+        assert_eq!(r, PublisherRef { id, token: token.clone(), subtree_token });
+        let subtree_token = Bytes::from_static(b"subtree");
+        let r: OldPublisherRef = recode(&PublisherRef { id, token, subtree_token });
+        assert_eq!(r, old);
+    }
 }
 
 mod publisher {
     use super::*;
     use crate::{
-        publisher::{From, Hello, Id, To},
-        value::Value,
+        cbor,
+        delta::StrDelta,
+        patch::{Edit, Patch, PatchPath},
+        publisher::{
+            From, Hello, Id, ShmOffer, To, UnsubscribeReason, ValPermissions, WriteKey,
+        },
+        value::{DisplayTz, ErrorInfo, Radix, Typ, Value, ValueFormat},
     };
+    use bytes::BufMut;
     use chrono::prelude::*;
     use netidx_core::pack::PackError;
     use proptest::collection;
@@ -404,11 +474,17 @@ mod publisher {
         let _: Result<Value> = Pack::decode(&mut &*b);
     }
 
+    fn shm_offer() -> impl Strategy<Value = ShmOffer> {
+        (arcstr(), any::<u64>())
+            .prop_map(|(name, ring_size)| ShmOffer { name, ring_size })
+    }
+
     fn hello() -> impl Strategy<Value = Hello> {
         prop_oneof![
-            Just(Hello::Anonymous),
+            option(shm_offer()).prop_map(Hello::Anonymous),
             option(user_info()).prop_map(Hello::Krb5),
-            option(user_info()).prop_map(Hello::Local),
+            (option(user_info()), option(shm_offer()))
+                .prop_map(|(u, s)| Hello::Local(u, s)),
             option(user_info()).prop_map(Hello::Tls),
             any::<SocketAddr>().prop_map(Hello::ResolverAuthenticate)
         ]
@@ -416,21 +492,45 @@ mod publisher {
 
     fn to() -> impl Strategy<Value = To> {
         prop_oneof![
-            (path(), any::<SocketAddr>(), any::<u64>(), any::<u32>(), bytes()).prop_map(
-                |(path, resolver, timestamp, permissions, token)| To::Subscribe {
-                    path,
-                    resolver,
-                    timestamp,
-                    permissions,
-                    token
-                }
-            ),
+            (
+                path(),
+                any::<SocketAddr>(),
+                any::<u64>(),
+                any::<u32>(),
+                bytes(),
+                any::<bool>(),
+                any::<bool>(),
+                option(chars()),
+                any::<bool>()
+            )
+                .prop_map(
+                    |(
+                        path,
+                        resolver,
+                        timestamp,
+                        permissions,
+                        token,
+                        patches,
+                        deltas,
+                        glob,
+                        error_info,
+                    )| {
+                        To::Subscribe {
+                            path,
+                            resolver,
+                            timestamp,
+                            permissions,
+                            token,
+                            patches,
+                            deltas,
+                            glob,
+                            error_info,
+                        }
+                    }
+                ),
             any::<u64>().prop_map(|i| To::Unsubscribe(Id::mk(i))),
-            (any::<u64>(), value(), any::<bool>()).prop_map(|(i, v, r)| To::Write(
-                Id::mk(i),
-                r,
-                v
-            ))
+            (any::<u64>(), value(), any::<bool>(), option(write_key()))
+                .prop_map(|(i, v, r, k)| To::Write(Id::mk(i), r, v, k))
         ]
     }
 
@@ -470,24 +570,79 @@ mod publisher {
             chars().prop_map(Value::Error),
         ];
         leaf.prop_recursive(10, 1000, 100, |inner| {
-            prop_oneof![collection::vec(inner.clone(), 0..100)
-                .prop_map(|e| Value::Array(Arc::from(e)))]
+            prop_oneof![
+                collection::vec(inner.clone(), 0..100)
+                    .prop_map(|e| Value::Array(Arc::from(e))),
+                (any::<u32>(), chars(), option(inner.clone())).prop_map(
+                    |(code, message, payload)| {
+                        Value::ErrorInfo(Arc::new(ErrorInfo { code, message, payload }))
+                    }
+                )
+            ]
         })
     }
 
+    fn patch() -> impl Strategy<Value = Patch> {
+        let path = collection::vec(any::<u32>(), 0..4).prop_map(PatchPath::from_vec);
+        let edit = prop_oneof![
+            (path.clone(), value()).prop_map(|(p, v)| Edit::Set(p, v)),
+            (path.clone(), collection::vec(value(), 0..4))
+                .prop_map(|(p, v)| Edit::Extend(p, v)),
+            (path, any::<u32>()).prop_map(|(p, n)| Edit::Truncate(p, n)),
+        ];
+        collection::vec(edit, 0..4).prop_map(|edits| Patch { edits })
+    }
+
+    fn str_delta() -> impl Strategy<Value = StrDelta> {
+        (any::<u32>(), any::<u32>(), any::<u32>(), chars()).prop_map(
+            |(base, prefix, suffix, middle)| StrDelta { base, prefix, suffix, middle },
+        )
+    }
+
+    fn unsubscribe_reason() -> impl Strategy<Value = UnsubscribeReason> {
+        prop_oneof![
+            Just(UnsubscribeReason::Unspecified),
+            Just(UnsubscribeReason::Unpublished),
+            Just(UnsubscribeReason::Denied),
+            Just(UnsubscribeReason::Overloaded),
+            Just(UnsubscribeReason::Replaced),
+        ]
+    }
+
+    fn val_permissions() -> impl Strategy<Value = ValPermissions> {
+        (any::<bool>(), any::<bool>())
+            .prop_map(|(read, write)| ValPermissions { read, write })
+    }
+
+    fn write_key() -> impl Strategy<Value = WriteKey> {
+        (any::<u64>(), any::<u64>()).prop_map(|(writer, seq)| WriteKey { writer, seq })
+    }
+
     fn from() -> impl Strategy<Value = From> {
         prop_oneof![
             path().prop_map(From::NoSuchValue),
             path().prop_map(From::Denied),
-            any::<u64>().prop_map(|i| From::Unsubscribed(Id::mk(i))),
-            (path(), any::<u64>(), value()).prop_map(|(p, i, v)| From::Subscribed(
-                p,
-                Id::mk(i),
-                v
-            )),
+            (any::<u64>(), unsubscribe_reason())
+                .prop_map(|(i, r)| From::Unsubscribed(Id::mk(i), r)),
+            (path(), any::<u64>(), value(), option(val_permissions())).prop_map(
+                |(p, i, v, perms)| From::Subscribed(p, Id::mk(i), v, perms)
+            ),
             (any::<u64>(), value()).prop_map(|(i, v)| From::Update(Id::mk(i), v)),
             Just(From::Heartbeat),
-            (any::<u64>(), value()).prop_map(|(i, v)| From::WriteResult(Id::mk(i), v))
+            (any::<u64>(), value(), any::<bool>())
+                .prop_map(|(i, v, d)| From::WriteResult(Id::mk(i), v, d)),
+            (any::<u64>(), patch()).prop_map(|(i, p)| From::Patch(Id::mk(i), p)),
+            (any::<u64>(), str_delta()).prop_map(|(i, d)| From::Delta(Id::mk(i), d)),
+            (
+                path(),
+                collection::vec((path(), any::<u64>(), value()), 0..4),
+                any::<bool>()
+            )
+                .prop_map(|(p, subs, done)| From::SubscribedMany(
+                    p,
+                    subs.into_iter().map(|(p, i, v)| (p, Id::mk(i), v)).collect(),
+                    done
+                ))
         ]
     }
 
@@ -504,6 +659,15 @@ mod publisher {
                 e0.len() == e1.len()
                     && e0.iter().zip(e1.iter()).all(|(v0, v1)| vequiv(v0, v1))
             }
+            (Value::ErrorInfo(e0), Value::ErrorInfo(e1)) => {
+                e0.code == e1.code
+                    && e0.message == e1.message
+                    && match (&e0.payload, &e1.payload) {
+                        (Some(v0), Some(v1)) => vequiv(v0, v1),
+                        (None, None) => true,
+                        (_, _) => false,
+                    }
+            }
             (v0, v1) => v0 == v1,
         }
     }
@@ -514,6 +678,40 @@ mod publisher {
         assert!(vequiv(&v, &v_))
     }
 
+    fn default_format(v: Value) {
+        assert_eq!(format!("{}", v), v.format_with(&ValueFormat::new()));
+        assert_eq!(v.to_string_naked(), v.format_with(&ValueFormat::new().naked(true)));
+    }
+
+    // cbor decodes to exactly the same variant, so the same pack
+    // encoding, except a negative zero decimal loses it's sign
+    fn cbor_equiv(v0: &Value, v1: &Value) -> bool {
+        match (v0, v1) {
+            (Value::Decimal(d0), Value::Decimal(d1)) => {
+                d0 == d1 && d0.scale() == d1.scale()
+            }
+            (Value::Array(e0), Value::Array(e1)) => {
+                e0.len() == e1.len()
+                    && e0.iter().zip(e1.iter()).all(|(v0, v1)| cbor_equiv(v0, v1))
+            }
+            (Value::ErrorInfo(e0), Value::ErrorInfo(e1)) => {
+                e0.code == e1.code
+                    && e0.message == e1.message
+                    && match (&e0.payload, &e1.payload) {
+                        (Some(v0), Some(v1)) => cbor_equiv(v0, v1),
+                        (None, None) => true,
+                        (_, _) => false,
+                    }
+            }
+            (v0, v1) => pack(v0).unwrap() == pack(v1).unwrap(),
+        }
+    }
+
+    fn cbor_round_trip(v: Value) {
+        let v_ = Value::from_cbor(&v.to_cbor()).unwrap();
+        assert!(cbor_equiv(&v, &v_), "{} != {}", v, v_)
+    }
+
     proptest! {
         #[test]
         fn test_fuzz(b in bytes()) {
@@ -535,9 +733,662 @@ mod publisher {
             check(a)
         }
 
+        #[test]
+        fn test_patch_diff(v0 in value(), v1 in value()) {
+            let v = Patch::diff(&v0, &v1).apply(&v0).unwrap();
+            assert!(vequiv(&v, &v1))
+        }
+
+        #[test]
+        fn test_str_delta_diff(s0 in chars(), s1 in chars()) {
+            if let Some(d) = StrDelta::diff(&s0, &s1) {
+                assert_eq!(&*d.apply(&s0).unwrap(), &*s1)
+            }
+        }
+
         #[test]
         fn test_value_roundtrip(v in value()) {
             round_trip(v)
         }
+
+        #[test]
+        fn test_value_default_format(v in value()) {
+            default_format(v)
+        }
+
+        #[test]
+        fn test_cbor_roundtrip(v in value()) {
+            cbor_round_trip(v)
+        }
+
+        #[test]
+        fn test_cbor_fuzz(b in bytes()) {
+            let _ = Value::from_cbor(&b);
+        }
+    }
+
+    #[test]
+    fn test_hello_compat() {
+        // Hello before shared memory offers were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldHello {
+            Anonymous,
+            Krb5(#[pack(default)] Option<UserInfo>),
+            Local(#[pack(default)] Option<UserInfo>),
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let offer = ShmOffer { name: "/netidx-0".into(), ring_size: 1 << 20 };
+        let h: Hello = recode(&OldHello::Anonymous);
+        assert_eq!(h, Hello::Anonymous(None));
+        let h: Hello = recode(&OldHello::Local(None));
+        assert_eq!(h, Hello::Local(None, None));
+        let h: OldHello = recode(&Hello::Anonymous(Some(offer.clone())));
+        assert_eq!(h, OldHello::Anonymous);
+        let h: OldHello = recode(&Hello::Local(None, Some(offer)));
+        assert_eq!(h, OldHello::Local(None));
+    }
+
+    #[test]
+    fn test_unsubscribed_compat() {
+        // From before unsubscribe reasons were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldFrom {
+            NoSuchValue(Path),
+            Denied(Path),
+            Unsubscribed(Id),
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let id = Id::mk(42);
+        let m: From = recode(&OldFrom::Unsubscribed(id));
+        assert_eq!(m, From::Unsubscribed(id, UnsubscribeReason::Unspecified));
+        let m: OldFrom = recode(&From::Unsubscribed(id, UnsubscribeReason::Replaced));
+        assert_eq!(m, OldFrom::Unsubscribed(id));
+    }
+
+    #[test]
+    fn test_subscribe_compat() {
+        // To before patches were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldTo {
+            Subscribe {
+                path: Path,
+                resolver: SocketAddr,
+                timestamp: u64,
+                permissions: u32,
+                token: Bytes,
+            },
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let path = Path::from("/foo");
+        let resolver = "127.0.0.1:4564".parse::<SocketAddr>().unwrap();
+        let token = Bytes::from_static(b"token");
+        let old = OldTo::Subscribe {
+            path: path.clone(),
+            resolver,
+            timestamp: 42,
+            permissions: 1,
+            token: token.clone(),
+        };
+        let new = |patches, error_info| To::Subscribe {
+            path: path.clone(),
+            resolver,
+            timestamp: 42,
+            permissions: 1,
+            token: token.clone(),
+            patches,
+            deltas: false,
+            glob: None,
+            error_info,
+        };
+        // old subscribers can't decode ErrorInfo
+        let m: To = recode(&old);
+        assert_eq!(m, new(false, false));
+        let m: OldTo = recode(&new(true, true));
+        assert_eq!(m, old);
+    }
+
+    #[test]
+    fn test_write_key_compat() {
+        // To and From before write keys were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldTo {
+            Subscribe { path: Path },
+            Unsubscribe(Id),
+            Write(Id, bool, Value),
+        }
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldFrom {
+            NoSuchValue(Path),
+            Denied(Path),
+            Unsubscribed(Id),
+            Subscribed(Path, Id, Value),
+            Update(Id, Value),
+            Heartbeat,
+            WriteResult(Id, Value),
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let id = Id::mk(42);
+        let key = WriteKey { writer: 7, seq: 1 };
+        let v = Value::from(42u64);
+        let m: To = recode(&OldTo::Write(id, true, v.clone()));
+        assert_eq!(m, To::Write(id, true, v.clone(), None));
+        let m: OldTo = recode(&To::Write(id, true, v.clone(), Some(key)));
+        assert_eq!(m, OldTo::Write(id, true, v.clone()));
+        let m: From = recode(&OldFrom::WriteResult(id, v.clone()));
+        assert_eq!(m, From::WriteResult(id, v.clone(), false));
+        let m: OldFrom = recode(&From::WriteResult(id, v.clone(), true));
+        assert_eq!(m, OldFrom::WriteResult(id, v));
+    }
+
+    #[test]
+    fn test_error_info_compat() {
+        // subscribers older than ErrorInfo are sent a plain Error
+        let id = Id::mk(42);
+        let e = Value::coded_err(ErrorInfo::PERMISSION_DENIED, "denied");
+        let m = From::WriteResult(id, e.clone(), false).without_error_info();
+        assert_eq!(m, From::WriteResult(id, Value::Error(Chars::from("denied")), false));
+        let v = Value::from(vec![Value::from(1u64), Value::from(vec![e.clone()])]);
+        let m = From::Update(id, v).without_error_info();
+        let v = Value::from(vec![
+            Value::from(1u64),
+            Value::from(vec![Value::Error(Chars::from("denied"))]),
+        ]);
+        assert_eq!(m, From::Update(id, v));
+        let path = Path::from("/foo");
+        let m = From::SubscribedMany(path.clone(), vec![(path.clone(), id, e)], true);
+        let m = m.without_error_info();
+        let sub = (path.clone(), id, Value::Error(Chars::from("denied")));
+        assert_eq!(m, From::SubscribedMany(path, vec![sub], true));
+        let v = Value::from(vec![Value::from(1u64)]);
+        assert_eq!(From::Update(id, v.clone()).without_error_info(), From::Update(id, v));
+    }
+
+    #[test]
+    fn test_permissions_compat() {
+        // From before permission hints were added
+        #[derive(Debug, Clone, PartialEq, netidx_derive::Pack)]
+        enum OldFrom {
+            NoSuchValue(Path),
+            Denied(Path),
+            Unsubscribed(Id),
+            Subscribed(Path, Id, Value),
+        }
+        fn recode<T: Pack, U: Pack>(t: &T) -> U {
+            U::decode(&mut pack(t).unwrap()).unwrap()
+        }
+        let path = Path::from("/foo");
+        let id = Id::mk(42);
+        let v = Value::from(42u64);
+        let perms = ValPermissions { read: true, write: false };
+        let m: From = recode(&OldFrom::Subscribed(path.clone(), id, v.clone()));
+        assert_eq!(m, From::Subscribed(path.clone(), id, v.clone(), None));
+        let m: OldFrom =
+            recode(&From::Subscribed(path.clone(), id, v.clone(), Some(perms)));
+        assert_eq!(m, OldFrom::Subscribed(path, id, v));
+    }
+
+    #[test]
+    fn test_patch() {
+        let v = Value::Array(Arc::from(vec![
+            Value::from(1u64),
+            Value::Array(Arc::from(vec![Value::from("a"), Value::from("b")])),
+        ]));
+        let mut p = Patch::new();
+        p.set(&[0], 2u64).extend(&[1], [Value::from("c")]).truncate(&[], 2);
+        let v = p.apply(&v).unwrap();
+        assert_eq!(
+            v,
+            Value::Array(Arc::from(vec![
+                Value::from(2u64),
+                Value::Array(Arc::from(vec![
+                    Value::from("a"),
+                    Value::from("b"),
+                    Value::from("c")
+                ])),
+            ]))
+        );
+        let mut p = Patch::new();
+        p.set(&[1, 5], Value::Null);
+        assert!(p.apply(&v).is_err());
+        let mut p = Patch::new();
+        p.truncate(&[0], 0);
+        assert!(p.apply(&v).is_err());
+    }
+
+    #[test]
+    fn test_str_delta() {
+        let v0 = "AAPL bid=187.21 ask=187.23 size=300 venue=XNAS";
+        let v1 = "AAPL bid=187.22 ask=187.23 size=300 venue=XNAS";
+        let d = StrDelta::diff(v0, v1).unwrap();
+        assert_eq!(&*d.middle, "2");
+        assert_eq!(&*d.apply(v0).unwrap(), v1);
+        // a delta only applies to the value it was made from
+        assert!(d.apply(v1).is_err());
+        assert!(d.apply_value(&Value::from(42u64)).is_err());
+        // multi byte characters are never split
+        let (v0, v1) = ("the price is 10€ as of today", "the price is 10£ as of today");
+        let d = StrDelta::diff(v0, v1).unwrap();
+        assert_eq!(&*d.middle, "£");
+        assert_eq!(&*d.apply(v0).unwrap(), v1);
+        // nothing in common, send the whole value
+        assert!(StrDelta::diff("abc", "xyz").is_none());
+    }
+
+    #[test]
+    fn test_value_format() {
+        let fmt = |v: Value, f: ValueFormat| v.format_with(&f);
+        let p2 = ValueFormat::new().precision(2);
+        assert_eq!("f64:1.23", fmt(Value::F64(1.23456), p2));
+        assert_eq!("f64:3.", fmt(Value::F64(3.), ValueFormat::new().precision(0)));
+        assert_eq!(
+            "duration:1.50s",
+            fmt(Value::Duration(Duration::from_millis(1500)), p2)
+        );
+        assert_eq!("decimal:1.25", fmt(Value::Decimal("1.2500".parse().unwrap()), p2));
+        for v in [
+            Value::F64(1.23456),
+            Value::F32(-0.5),
+            Value::Duration(Duration::from_secs(7)),
+        ] {
+            let s = fmt(v.clone(), p2);
+            assert_eq!(Typ::get(&v), Typ::get(&s.parse::<Value>().unwrap()));
+        }
+        let hex = ValueFormat::new().radix(Radix::Hex);
+        assert_eq!(
+            "[i32:-0x10, f64:1.5]",
+            fmt(Value::from(vec![Value::I32(-16), Value::F64(1.5)]), hex)
+        );
+        let th = ValueFormat::new().thousands(',');
+        assert_eq!("1,234,567", fmt(Value::U64(1234567), th));
+        assert_eq!("-123", fmt(Value::I32(-123), th));
+        assert_eq!("-1,234.5", fmt(Value::F64(-1234.5), th));
+        assert_eq!("u32:0xffff", fmt(Value::U32(0xffff), th.radix(Radix::Hex)));
+        let si = ValueFormat::new().si(true);
+        assert_eq!("1.5k", fmt(Value::U32(1500), si));
+        assert_eq!("12", fmt(Value::I64(12), si));
+        assert_eq!("-2.346M", fmt(Value::F64(-2345678.), si));
+        assert_eq!("250µ", fmt(Value::F32(0.00025), si));
+        assert_eq!("1.20k", fmt(Value::F64(1200.), si.precision(2)));
+        let hu = ValueFormat::new().humanize(true);
+        let d = |s: u64, ms: u64| {
+            Value::Duration(Duration::from_secs(s) + Duration::from_millis(ms))
+        };
+        assert_eq!("0s", fmt(d(0, 0), hu));
+        assert_eq!("250ms", fmt(d(0, 250), hu));
+        assert_eq!("1m 5.5s", fmt(d(65, 500), hu));
+        assert_eq!("1d 0h 1m", fmt(d(86460, 0), hu));
+        assert_eq!("\"x\"", fmt(Value::from("x"), ValueFormat::new()));
+        assert_eq!("x", fmt(Value::from("x"), ValueFormat::new().naked(true)));
+        assert_eq!("1", fmt(Value::F64(1.), ValueFormat::new().naked(true)));
+    }
+
+    #[test]
+    fn test_naive_date_time() {
+        let d = NaiveDate::from_ymd_opt(2023, 3, 14).unwrap();
+        let v = Value::from(d);
+        assert_eq!(
+            v,
+            Value::DateTime(Utc.with_ymd_and_hms(2023, 3, 14, 0, 0, 0).unwrap())
+        );
+        assert_eq!(v.clone().cast_to::<NaiveDate>().unwrap(), d);
+        assert_eq!(v.get_as::<NaiveDate>(), Some(d));
+        assert_eq!(Value::from("2023-03-14").cast_to::<NaiveDate>().unwrap(), d);
+        let t = NaiveTime::from_hms_milli_opt(13, 45, 7, 250).unwrap();
+        let v = Value::from(t);
+        assert_eq!(v, Value::Duration(Duration::from_millis(49507250)));
+        assert_eq!(v.clone().cast_to::<NaiveTime>().unwrap(), t);
+        assert_eq!(v.get_as::<NaiveTime>(), Some(t));
+        assert_eq!(Value::from("13:45:07.250").cast_to::<NaiveTime>().unwrap(), t);
+        let dt = Value::DateTime(Utc.with_ymd_and_hms(2023, 3, 14, 13, 45, 7).unwrap());
+        assert_eq!(dt.cast_to::<NaiveTime>().unwrap(), t.with_nanosecond(0).unwrap());
+        assert!(Value::Duration(Duration::from_secs(86400))
+            .cast_to::<NaiveTime>()
+            .is_err());
+    }
+
+    #[test]
+    fn test_display_tz() {
+        let fmt = |v: Value, f: ValueFormat| v.format_with(&f);
+        let v = Value::DateTime(Utc.with_ymd_and_hms(2023, 3, 14, 22, 0, 0).unwrap());
+        assert_eq!("utc".parse::<DisplayTz>().unwrap(), DisplayTz::Utc);
+        assert_eq!("Local".parse::<DisplayTz>().unwrap(), DisplayTz::Local);
+        assert!("mars".parse::<DisplayTz>().is_err());
+        let tz = "+05:30".parse::<DisplayTz>().unwrap();
+        assert_eq!(tz, DisplayTz::Fixed(FixedOffset::east_opt(19800).unwrap()));
+        assert_eq!(fmt(v.clone(), ValueFormat::new()), v.to_string());
+        assert_eq!(
+            "2023-03-15 03:30:00 +05:30",
+            fmt(v.clone(), ValueFormat::new().naked(true).tz(tz))
+        );
+        // the value itself is unchanged
+        let s = fmt(v.clone(), ValueFormat::new().tz(tz));
+        assert_eq!(s.parse::<Value>().unwrap(), v);
+    }
+
+    #[test]
+    fn test_value_serde() {
+        use std::collections::HashMap;
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        enum Shape {
+            Empty,
+            Circle(f64),
+            Rect(u32, u32),
+            Poly { points: Vec<(i64, i64)> },
+        }
+        #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+        struct Model {
+            name: String,
+            id: u64,
+            tags: Vec<String>,
+            parent: Option<u32>,
+            shapes: Vec<Shape>,
+            attrs: HashMap<String, i32>,
+            timeout: Duration,
+            data: Vec<u8>,
+        }
+        let m = Model {
+            name: "foo".into(),
+            id: 42,
+            tags: vec!["a".into(), "b".into()],
+            parent: None,
+            shapes: vec![
+                Shape::Empty,
+                Shape::Circle(1.5),
+                Shape::Rect(2, 3),
+                Shape::Poly { points: vec![(0, 0), (-1, 1)] },
+            ],
+            attrs: HashMap::from_iter([("x".into(), -1), ("y".into(), 2)]),
+            timeout: Duration::from_millis(1500),
+            data: vec![1, 2, 3],
+        };
+        let v = Value::from_serde(&m).unwrap();
+        assert_eq!(v.clone().cast_to_serde::<Model>().unwrap(), m);
+        // structs are arrays of pairs, the same as maps
+        let fields = v.clone().cast_to::<HashMap<String, Value>>().unwrap();
+        assert_eq!(fields["name"], Value::from("foo"));
+        let shapes = fields["shapes"].clone().cast_to_serde::<Vec<Shape>>().unwrap();
+        assert_eq!(shapes, m.shapes);
+        // values built by hand, with native durations and bytes
+        let v: Value = "[[\"name\", \"bar\"], [\"id\", 7], [\"tags\", []], \
+                        [\"parent\", 3], [\"shapes\", [\"Empty\", [\"Circle\", 2]]], \
+                        [\"attrs\", []], [\"timeout\", duration:2.s], \
+                        [\"data\", bytes:AQI=]]"
+            .parse()
+            .unwrap();
+        let m = v.cast_to_serde::<Model>().unwrap();
+        assert_eq!(m.name, "bar");
+        assert_eq!(m.parent, Some(3));
+        assert_eq!(m.shapes, vec![Shape::Empty, Shape::Circle(2.)]);
+        assert_eq!(m.timeout, Duration::from_secs(2));
+        assert_eq!(m.data, vec![1, 2]);
+        assert!(Value::from("Hexagon").cast_to_serde::<Shape>().is_err());
+        assert!(Value::Error("boom".into()).cast_to_serde::<Model>().is_err());
+        assert!(Value::U64(1 << 40).cast_to_serde::<u32>().is_err());
+    }
+
+    #[test]
+    fn test_value_json() {
+        let v = Value::from(vec![
+            Value::Bytes(Bytes::from_static(&[1, 2])),
+            Value::String(Chars::from("foo")),
+            Value::Error(Chars::from("boom")),
+            Value::U64(42),
+            Value::Null,
+        ]);
+        let s = serde_json::to_string(&v).unwrap();
+        assert_eq!(
+            s,
+            r#"{"Array":[{"Bytes":"AQI="},{"String":"foo"},{"Error":"boom"},{"U64":42},"Null"]}"#
+        );
+        assert_eq!(serde_json::from_str::<Value>(&s).unwrap(), v);
+        // the bytes are the same base64 the text format uses
+        let elts = v.clone().cast_to::<Vec<Value>>().unwrap();
+        assert_eq!("bytes:AQI=".parse::<Value>().unwrap(), elts[0]);
+        let e = Value::ErrorInfo(Arc::new(ErrorInfo::new(2, "denied")));
+        let s = serde_json::to_string(&e).unwrap();
+        assert_eq!(s, r#"{"ErrorInfo":{"code":2,"message":"denied","payload":null}}"#);
+        assert_eq!(serde_json::from_str::<Value>(&s).unwrap(), e);
+        // the encoding used before strings and bytes matched the text format
+        let old = r#"{"Array":[{"Bytes":[1,2]},{"String":[102,111,111]},{"Error":[98,111,111,109]},{"U64":42},"Null"]}"#;
+        assert_eq!(serde_json::from_str::<Value>(old).unwrap(), v);
+        assert!(serde_json::from_str::<Value>(r#"{"Bytes":"not base64!"}"#).is_err());
+        assert!(serde_json::from_str::<Value>(r#"{"String":[255]}"#).is_err());
+        // the value_serde bridge sees strings, not bytes
+        assert_eq!(Value::from_serde(&Chars::from("foo")).unwrap(), Value::from("foo"));
+    }
+
+    #[test]
+    fn test_chars_serde_compat() {
+        let c = Chars::from("hi");
+        let s = serde_json::to_string(&c).unwrap();
+        assert_eq!(s, r#""hi""#);
+        assert_eq!(serde_json::from_str::<Chars>(&s).unwrap(), c);
+        // before 0.17 chars were serialized as bytes
+        assert_eq!(serde_json::from_str::<Chars>("[104,105]").unwrap(), c);
+        assert!(serde_json::from_str::<Chars>("[255]").is_err());
+        let old = Value::from_serde(&Bytes::from_static(b"hi")).unwrap();
+        assert_eq!(old.cast_to_serde::<Chars>().unwrap(), c);
+        let v = Value::from_serde(&c).unwrap();
+        assert_eq!(v.cast_to_serde::<Chars>().unwrap(), c);
+    }
+
+    #[test]
+    fn test_value_bitwise() {
+        assert_eq!(Value::U32(0b1100) & Value::U32(0b1010), Value::U32(0b1000));
+        assert_eq!(Value::U32(0b1100) | Value::U64(0b1010), Value::U64(0b1110));
+        assert_eq!(Value::V64(0b1100) ^ Value::V32(0b1010), Value::U64(0b0110));
+        assert_eq!(Value::I32(-1) & Value::U32(0xff), Value::I64(0xff));
+        assert_eq!(Value::True & Value::False, Value::False);
+        assert_eq!(Value::from("0xf0") | Value::U32(1), Value::U32(0xf1));
+        assert_eq!(Value::U32(1) << Value::I64(31), Value::U32(1 << 31));
+        assert_eq!(Value::U64(1) << Value::U32(40), Value::U64(1 << 40));
+        assert_eq!(Value::I32(-16) >> Value::U32(2), Value::I32(-4));
+        assert!(matches!(Value::U32(1) << Value::U32(32), Value::Error(_)));
+        assert!(matches!(Value::U32(1) >> Value::I32(-1), Value::Error(_)));
+        assert!(matches!(Value::F64(1.) & Value::U32(1), Value::Error(_)));
+        assert!(matches!(Value::U32(1) | Value::Null, Value::Error(_)));
+        assert!(matches!(Value::F32(1.) << Value::U32(1), Value::Error(_)));
+    }
+
+    #[test]
+    fn test_value_get_int() {
+        assert_eq!(Value::U64(300).get_as::<u8>(), None);
+        assert_eq!(Value::U64(300).get_as_lossy::<u8>(), Some(44));
+        assert_eq!(Value::U64(200).get_as::<u8>(), Some(200));
+        assert_eq!(Value::I32(-1).get_as::<u32>(), None);
+        assert_eq!(Value::I32(-1).get_as_lossy::<u32>(), Some(u32::MAX));
+        assert_eq!(Value::I64(-40000).get_as::<i16>(), None);
+        assert_eq!(Value::I64(-300).get_as::<i16>(), Some(-300));
+        assert_eq!(Value::U64(u64::MAX).get_as::<i64>(), None);
+        assert_eq!(Value::Z64(-5).get_as::<i64>(), Some(-5));
+        assert_eq!(Value::V32(7).get_as::<u64>(), Some(7));
+        assert_eq!(Value::from("7").get_as::<u64>(), None);
+        assert_eq!(Value::from(vec![1u64, 256]).get_as::<Vec<u8>>(), None);
+        assert_eq!(Value::from(vec![1u64, 255]).get_as::<Vec<u8>>(), Some(vec![1, 255]));
+        let e = Value::U32(300).cast_to::<u8>().unwrap_err();
+        assert_eq!(e.to_string(), "300 is out of range for u8");
+        assert_eq!(Value::U32(100).cast_to::<i8>().unwrap(), 100);
+    }
+
+    #[test]
+    fn test_write_outcome() {
+        use crate::value::WriteOutcome;
+        let outcomes = [
+            WriteOutcome::Ok,
+            WriteOutcome::Error { code: ErrorInfo::FAILED, message: Chars::from("no") },
+            WriteOutcome::Pending { token: 42 },
+        ];
+        for o in outcomes {
+            assert_eq!(WriteOutcome::from(Value::from(o.clone())), o);
+        }
+        assert_eq!(Value::from(WriteOutcome::Ok), Value::Ok);
+        assert_eq!(
+            WriteOutcome::from(Value::Error(Chars::from("bad"))),
+            WriteOutcome::Error { code: ErrorInfo::OTHER, message: Chars::from("bad") }
+        );
+        assert_eq!(WriteOutcome::from(Value::U64(7)), WriteOutcome::Ok);
+        assert_eq!(WriteOutcome::from(Value::Null), WriteOutcome::Ok);
+        assert_eq!(WriteOutcome::from(Value::from(("Pending", "x"))), WriteOutcome::Ok);
+    }
+
+    #[test]
+    fn test_value_addr_conversions() {
+        use std::net::{IpAddr, Ipv6Addr};
+        let ip: IpAddr = "10.0.0.1".parse().unwrap();
+        assert_eq!(Value::from(ip), Value::from("10.0.0.1"));
+        assert_eq!(Value::from("10.0.0.1").cast_to::<IpAddr>().unwrap(), ip);
+        let ip6: Ipv6Addr = "::1".parse().unwrap();
+        assert_eq!(Value::from(ip6).cast_to::<Ipv6Addr>().unwrap(), ip6);
+        let sa: SocketAddr = "[::1]:4564".parse().unwrap();
+        assert_eq!(Value::from(sa), Value::from("[::1]:4564"));
+        assert_eq!(Value::from("[::1]:4564").get_as::<SocketAddr>(), Some(sa));
+        assert!(Value::from("not an address").cast_to::<IpAddr>().is_err());
+        assert!(Value::U32(42).get_as::<SocketAddr>().is_none());
+        #[cfg(feature = "uuid")]
+        {
+            let id = uuid::Uuid::from_u128(0x67e5504410b1426f9247bb680e5fe0c8);
+            let canonical = "67e55044-10b1-426f-9247-bb680e5fe0c8";
+            assert_eq!(Value::from(id), Value::from(canonical));
+            assert_eq!(Value::from(canonical).cast_to::<uuid::Uuid>().unwrap(), id);
+            let simple = Value::from("67e5504410b1426f9247bb680e5fe0c8");
+            assert_eq!(simple.cast_to::<uuid::Uuid>().unwrap(), id);
+            let bytes = Value::Bytes(Bytes::copy_from_slice(id.as_bytes()));
+            assert_eq!(bytes.cast_to::<uuid::Uuid>().unwrap(), id);
+        }
+        #[cfg(feature = "url")]
+        {
+            let u: url::Url = "https://example.com/a b".parse().unwrap();
+            assert_eq!(Value::from(u.clone()), Value::from("https://example.com/a%20b"));
+            assert_eq!(Value::from(u.clone()).cast_to::<url::Url>().unwrap(), u);
+            assert!(Value::from("no scheme").cast_to::<url::Url>().is_err());
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        use crate::{
+            schema::{schema_path, Schema},
+            value::Typ,
+        };
+        let x = Schema::new(Typ::F64).unit("m").range(-1., 1.);
+        let s = Schema::default()
+            .doc("a point")
+            .field("x", x.clone())
+            .field("y", Schema::new(Typ::F64).unit("m"));
+        let v = Value::from(&s);
+        assert_eq!(v.cast_to::<Schema>().unwrap(), s);
+        let range = format!("{}..{}", Value::F64(-1.), Value::F64(1.));
+        assert_eq!(x.to_string(), format!("f64 [m] {}", range));
+        assert_eq!(s.to_string(), format!("{{x: {}, y: f64 [m]}} \"a point\"", x));
+        let v: Value = "[[\"typ\", \"u32\"], [\"new\", 42]]".parse().unwrap();
+        assert_eq!(v.cast_to::<Schema>().unwrap(), Schema::new(Typ::U32));
+        assert!(Value::from("f64").cast_to::<Schema>().is_err());
+        assert_eq!(&*schema_path(&Path::from("/foo/bar")), "/foo/bar/.schema");
+    }
+
+    #[test]
+    fn test_truncated_array() {
+        let strings = (0..10).map(|i| Value::from(format!("{}", i)));
+        let v = Value::Array(strings.collect());
+        let b = pack(&v).unwrap().freeze();
+        for i in 0..b.len() {
+            assert!(<Value as Pack>::decode(&mut b.slice(0..i)).is_err())
+        }
+        let mut buf = BytesMut::new();
+        buf.put_u8(19);
+        netidx_core::pack::encode_varint(1 << 40, &mut buf);
+        buf.put_u8(16);
+        let r = <Value as Pack>::decode(&mut buf.freeze());
+        assert!(matches!(r, Err(PackError::TooBig)));
+    }
+
+    #[test]
+    fn test_decode_limits() {
+        use netidx_core::pack::{DecodeLimits, Limit};
+        let decode = |limits: DecodeLimits, v: &Value| {
+            let b = pack(v).unwrap().freeze();
+            limits.scope(|| <Value as Pack>::decode(&mut &*b))
+        };
+        let arr = Value::Array((0..10).map(Value::U32).collect());
+        let s = Value::from("hello world");
+        let mut deep = Value::Null;
+        for _ in 0..10 {
+            deep = Value::Array(vec![deep].into());
+        }
+        let d = DecodeLimits::default();
+        assert_eq!(decode(d, &arr).unwrap(), arr);
+        assert_eq!(decode(d, &s).unwrap(), s);
+        assert_eq!(decode(d, &deep).unwrap(), deep);
+        let l = DecodeLimits { max_array_len: 9, ..d };
+        assert!(matches!(
+            decode(l, &arr),
+            Err(PackError::LimitExceeded(Limit::ArrayLen))
+        ));
+        let l = DecodeLimits { max_string_len: 10, ..d };
+        assert!(matches!(decode(l, &s), Err(PackError::LimitExceeded(Limit::StringLen))));
+        let l = DecodeLimits { max_depth: 9, ..d };
+        assert!(matches!(decode(l, &deep), Err(PackError::LimitExceeded(Limit::Depth))));
+        let l = DecodeLimits { max_depth: 10, ..d };
+        assert_eq!(decode(l, &deep).unwrap(), deep);
+        // the limits are restored when the scope ends
+        assert_eq!(DecodeLimits::current(), d);
+        // a stack blowing nest fails with the default limits
+        let mut buf = BytesMut::new();
+        for _ in 0..100_000 {
+            buf.put_u8(19);
+            buf.put_u8(1);
+        }
+        buf.put_u8(16);
+        let r = <Value as Pack>::decode(&mut buf.freeze());
+        assert!(matches!(r, Err(PackError::LimitExceeded(Limit::Depth))));
+    }
+
+    #[test]
+    fn test_cbor_interop() {
+        let dec = |b: &[u8]| Value::from_cbor(b).unwrap();
+        // examples from RFC 8949 appendix A
+        assert_eq!(Value::I64(100).to_cbor(), vec![0x18, 0x64]);
+        assert_eq!(Value::I64(-1000).to_cbor(), vec![0x39, 0x03, 0xe7]);
+        assert_eq!(Value::from("IETF").to_cbor(), b"\x64IETF".to_vec());
+        assert_eq!(
+            dec(&[0x1b, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff]),
+            Value::U64(u64::MAX)
+        );
+        assert_eq!(dec(&[0xf9, 0x3c, 0x00]), Value::F64(1.));
+        assert_eq!(dec(&[0xf9, 0xc4, 0x00]), Value::F64(-4.));
+        assert_eq!(dec(&[0xf7]), Value::Null);
+        let d = Decimal::new(27315, 2);
+        let b = vec![0xc4, 0x82, 0x21, 0x19, 0x6a, 0xb3];
+        assert_eq!(Value::Decimal(d).to_cbor(), b);
+        assert_eq!(dec(&b), Value::Decimal(d));
+        let ts = Utc.timestamp_opt(1363896240, 500_000_000).unwrap();
+        assert_eq!(dec(b"\xc0\x74\x32\x30\x31\x33\x2d\x30\x33\x2d\x32\x31\x54\x32\x30\x3a\x30\x34\x3a\x30\x30\x5a"), Value::DateTime(Utc.timestamp_opt(1363896240, 0).unwrap()));
+        assert_eq!(
+            dec(&[0xc1, 0xfb, 0x41, 0xd4, 0x52, 0xd9, 0xec, 0x20, 0x00, 0x00]),
+            Value::DateTime(ts)
+        );
+        // maps become arrays of pairs, and unknown tags are ignored
+        let v = dec(&[0xa1, 0x61, 0x61, 0xd8, 0x20, 0x01]);
+        assert_eq!(
+            v,
+            Value::Array(Arc::from(vec![Value::Array(Arc::from(vec![
+                Value::from("a"),
+                Value::I64(1)
+            ]))]))
+        );
+        // records are encoded with canonical key order
+        let (one, two) = (Value::I64(1), Value::I64(2));
+        let b = cbor::encode_map([("bb", &two), ("a", &one)]);
+        assert_eq!(b, vec![0xa2, 0x61, 0x61, 0x01, 0x62, 0x62, 0x62, 0x02]);
+        assert!(Value::from_cbor(&[0x9f, 0xff]).is_err());
+        assert!(Value::from_cbor(&[0x01, 0x01]).is_err());
     }
 }
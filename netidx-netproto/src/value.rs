@@ -5,11 +5,12 @@ use chrono::{naive::NaiveDateTime, prelude::*};
 use indexmap::{IndexMap, IndexSet};
 use netidx_core::{
     chars::Chars,
-    pack::{self, Pack, PackError},
+    pack::{self, DecodeLimits, DepthGuard, Limit, Pack, PackError},
     path::Path,
     utils,
 };
 use rust_decimal::Decimal;
+use serde::{de::DeserializeOwned, Serialize};
 use smallvec::SmallVec;
 use std::{
     cmp::{Ordering, PartialEq, PartialOrd},
@@ -17,8 +18,9 @@ use std::{
     convert, fmt,
     hash::{BuildHasher, Hash},
     iter, mem,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
     num::Wrapping,
-    ops::{Add, Div, Mul, Not, Sub},
+    ops::{Add, BitAnd, BitOr, BitXor, Div, Mul, Not, Shl, Shr, Sub},
     panic::{catch_unwind, AssertUnwindSafe},
     result,
     str::FromStr,
@@ -26,7 +28,7 @@ use std::{
     time::Duration,
 };
 
-use crate::value_parser;
+use crate::{value_parser, value_serde};
 
 type Result<T> = result::Result<T, PackError>;
 
@@ -160,7 +162,7 @@ impl Typ {
             Value::Bytes(_) => Typ::Bytes,
             Value::True | Value::False => Typ::Bool,
             Value::Null => Typ::Null,
-            Value::Ok | Value::Error(_) => Typ::Result,
+            Value::Ok | Value::Error(_) | Value::ErrorInfo(_) => Typ::Result,
             Value::Array(_) => Typ::Array,
         }
     }
@@ -318,6 +320,155 @@ impl fmt::Display for Typ {
     }
 }
 
+/// A structured error carrying a machine readable code, a human
+/// readable message, and an optional payload with any extra detail.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Hash, Serialize, Deserialize)]
+pub struct ErrorInfo {
+    pub code: u32,
+    pub message: Chars,
+    pub payload: Option<Value>,
+}
+
+impl ErrorInfo {
+    /// no specific code applies, the message says what went wrong
+    pub const OTHER: u32 = 0;
+    /// the value being written is not subscribed
+    pub const NOT_SUBSCRIBED: u32 = 1;
+    /// the operation is not permitted
+    pub const PERMISSION_DENIED: u32 = 2;
+    /// the value does not accept writes
+    pub const WRITES_NOT_ACCEPTED: u32 = 3;
+    /// an argument could not be converted to the required type
+    pub const INVALID_ARGUMENT: u32 = 4;
+    /// an argument was given that was not expected
+    pub const UNKNOWN_ARGUMENT: u32 = 5;
+    /// the operation was accepted, but failed without a specific reply
+    pub const FAILED: u32 = 6;
+
+    pub fn new<M: Into<Chars>>(code: u32, message: M) -> Self {
+        Self { code, message: message.into(), payload: None }
+    }
+
+    pub fn with_payload<M: Into<Chars>>(code: u32, message: M, payload: Value) -> Self {
+        Self { code, message: message.into(), payload: Some(payload) }
+    }
+}
+
+// not len wrapped, because it is decoded recursively with Value
+impl Pack for ErrorInfo {
+    fn encoded_len(&self) -> usize {
+        pack::varint_len(self.code as u64)
+            + <Chars as Pack>::encoded_len(&self.message)
+            + <Option<Value> as Pack>::encoded_len(&self.payload)
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> Result<()> {
+        pack::encode_varint(self.code as u64, buf);
+        <Chars as Pack>::encode(&self.message, buf)?;
+        <Option<Value> as Pack>::encode(&self.payload, buf)
+    }
+
+    fn decode(buf: &mut impl Buf) -> Result<Self> {
+        let code = pack::decode_varint(buf)? as u32;
+        let message = <Chars as Pack>::decode(buf)?;
+        let payload = <Option<Value> as Pack>::decode(buf)?;
+        Ok(Self { code, message, payload })
+    }
+}
+
+impl fmt::Display for ErrorInfo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}", self.code, self.message)
+    }
+}
+
+/// The standard reply to a write made with `write_with_recipt`, so
+/// publishers and subscribers can agree on what a receipt means. On
+/// the wire `Ok` is `Value::Ok`, `Error` is `Value::ErrorInfo`
+/// without a payload, and `Pending` is the pair `["Pending", token]`.
+///
+/// Converting from a value never fails. `Value::Error` is an error
+/// with code `ErrorInfo::OTHER`, and any value that isn't an error or
+/// a pending pair is taken as success, since many publishers reply
+/// with data, or `Null`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum WriteOutcome {
+    /// The write was done
+    Ok,
+    /// The write failed, `code` is one of the `ErrorInfo` codes, or
+    /// one defined by the publisher.
+    Error { code: u32, message: Chars },
+    /// The write was accepted, but will be completed later. `token`
+    /// identifies it in whatever way the publisher provides to find
+    /// out how it went.
+    Pending { token: u64 },
+}
+
+impl WriteOutcome {
+    pub fn is_ok(&self) -> bool {
+        matches!(self, WriteOutcome::Ok)
+    }
+
+    pub fn is_error(&self) -> bool {
+        matches!(self, WriteOutcome::Error { .. })
+    }
+
+    pub fn is_pending(&self) -> bool {
+        matches!(self, WriteOutcome::Pending { .. })
+    }
+}
+
+impl convert::From<WriteOutcome> for Value {
+    fn from(o: WriteOutcome) -> Value {
+        match o {
+            WriteOutcome::Ok => Value::Ok,
+            WriteOutcome::Error { code, message } => Value::coded_err(code, message),
+            WriteOutcome::Pending { token } => Value::from(("Pending", token)),
+        }
+    }
+}
+
+impl convert::From<Value> for WriteOutcome {
+    fn from(v: Value) -> WriteOutcome {
+        match v {
+            Value::Error(message) => {
+                WriteOutcome::Error { code: ErrorInfo::OTHER, message }
+            }
+            Value::ErrorInfo(e) => {
+                WriteOutcome::Error { code: e.code, message: e.message.clone() }
+            }
+            Value::Array(a) => match &a[..] {
+                [Value::String(tag), Value::U64(token) | Value::V64(token)]
+                    if &**tag == "Pending" =>
+                {
+                    WriteOutcome::Pending { token: *token }
+                }
+                _ => WriteOutcome::Ok,
+            },
+            _ => WriteOutcome::Ok,
+        }
+    }
+}
+
+impl FromValue for WriteOutcome {
+    fn from_value(v: Value) -> Res<Self> {
+        Ok(WriteOutcome::from(v))
+    }
+
+    fn get(v: Value) -> Option<Self> {
+        Some(WriteOutcome::from(v))
+    }
+}
+
+/// A netidx value.
+///
+/// The serde representation is stable, and is the externally tagged
+/// form serde derives for an enum, e.g. `{"U64": 42}`, or `"Null"`
+/// in json. Strings and errors are plain strings and bytes are
+/// standard base64 in human readable formats, the same as the text
+/// format (see `value_serde::base64_bytes`). `DateTime` is rfc3339
+/// and `Duration` is `{"secs": .., "nanos": ..}`. For more compact
+/// formats bytes and strings are serialized natively.
 // This enum is limited to 0x3F cases, because the high 2 bits of the
 // tag are reserved for zero cost wrapper types.
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -349,7 +500,7 @@ pub enum Value {
     /// unicode string, zero copy decode
     String(Chars),
     /// byte array, zero copy decode
-    Bytes(Bytes),
+    Bytes(#[serde(with = "value_serde::base64_bytes")] Bytes),
     /// boolean true
     True,
     /// boolean false
@@ -364,6 +515,8 @@ pub enum Value {
     Array(Arc<[Value]>),
     /// fixed point decimal type
     Decimal(Decimal),
+    /// An explicit error with a code and an optional payload
+    ErrorInfo(Arc<ErrorInfo>),
 }
 
 impl Hash for Value {
@@ -452,6 +605,10 @@ impl Hash for Value {
                 20u8.hash(state);
                 d.hash(state);
             }
+            Value::ErrorInfo(e) => {
+                21u8.hash(state);
+                e.hash(state)
+            }
         }
     }
 }
@@ -485,7 +642,11 @@ impl PartialEq for Value {
             (Value::Null, Value::Null) => true,
             (Value::Ok, Value::Ok) => true,
             (Value::Error(l), Value::Error(r)) => l == r,
-            (Value::Ok | Value::Error(_), Value::Ok | Value::Error(_)) => false,
+            (Value::ErrorInfo(l), Value::ErrorInfo(r)) => l == r,
+            (
+                Value::Ok | Value::Error(_) | Value::ErrorInfo(_),
+                Value::Ok | Value::Error(_) | Value::ErrorInfo(_),
+            ) => false,
             (Value::Array(l), Value::Array(r)) => l == r,
             (Value::Array(_), _) | (_, Value::Array(_)) => false,
             (l, r) if l.number() || r.number() => {
@@ -549,6 +710,9 @@ impl PartialOrd for Value {
             (Value::Error(l), Value::Error(r)) => l.partial_cmp(r),
             (Value::Error(_), _) => Some(Ordering::Less),
             (_, Value::Error(_)) => Some(Ordering::Greater),
+            (Value::ErrorInfo(l), Value::ErrorInfo(r)) => l.partial_cmp(r),
+            (Value::ErrorInfo(_), _) => Some(Ordering::Less),
+            (_, Value::ErrorInfo(_)) => Some(Ordering::Greater),
             (Value::Array(l), Value::Array(r)) => l.partial_cmp(r),
             (Value::Array(_), _) => Some(Ordering::Less),
             (_, Value::Array(_)) => Some(Ordering::Greater),
@@ -580,6 +744,24 @@ impl fmt::Display for Value {
     }
 }
 
+impl fmt::LowerHex for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_ext_radix(f, &value_parser::VAL_ESC, true, Radix::Hex)
+    }
+}
+
+impl fmt::Octal for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_ext_radix(f, &value_parser::VAL_ESC, true, Radix::Oct)
+    }
+}
+
+impl fmt::Binary for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.fmt_ext_radix(f, &value_parser::VAL_ESC, true, Radix::Bin)
+    }
+}
+
 impl FromStr for Value {
     type Err = anyhow::Error;
 
@@ -742,7 +924,11 @@ macro_rules! apply_op {
             (Value::Ok, _)
             | (_, Value::Ok)
             | (Value::Error(_), _)
-            | (_, Value::Error(_)) => Value::Error(Chars::from("can't add result types")),
+            | (_, Value::Error(_))
+            | (Value::ErrorInfo(_), _)
+            | (_, Value::ErrorInfo(_)) => {
+                Value::Error(Chars::from("can't add result types"))
+            }
             (Value::True, n) => Value::U32(1) $op n,
             (n, Value::True) => n $op Value::U32(1),
             (Value::False, n) => Value::U32(0) $op n,
@@ -900,6 +1086,9 @@ impl Not for Value {
             Value::Error(v) => {
                 Value::Error(Chars::from(format!("can't apply not to Error({})", v)))
             }
+            Value::ErrorInfo(v) => {
+                Value::Error(Chars::from(format!("can't apply not to ErrorInfo({})", v)))
+            }
             Value::Array(elts) => {
                 Value::Array(elts.iter().cloned().map(|v| !v).collect())
             }
@@ -907,6 +1096,200 @@ impl Not for Value {
     }
 }
 
+macro_rules! apply_bit_op {
+    ($self:expr, $rhs:expr, $op:tt, $name:expr) => {
+        match ($self, $rhs) {
+            (Value::U32(l) | Value::V32(l), Value::U32(r) | Value::V32(r)) => {
+                Value::U32(l $op r)
+            }
+            (Value::I32(l) | Value::Z32(l), Value::I32(r) | Value::Z32(r)) => {
+                Value::I32(l $op r)
+            }
+            (Value::U64(l) | Value::V64(l), Value::U64(r) | Value::V64(r)) => {
+                Value::U64(l $op r)
+            }
+            (Value::I64(l) | Value::Z64(l), Value::I64(r) | Value::Z64(r)) => {
+                Value::I64(l $op r)
+            }
+            (Value::U32(l) | Value::V32(l), Value::U64(r) | Value::V64(r)) => {
+                Value::U64(l as u64 $op r)
+            }
+            (Value::U64(l) | Value::V64(l), Value::U32(r) | Value::V32(r)) => {
+                Value::U64(l $op r as u64)
+            }
+            (Value::I32(l) | Value::Z32(l), Value::I64(r) | Value::Z64(r)) => {
+                Value::I64(l as i64 $op r)
+            }
+            (Value::I64(l) | Value::Z64(l), Value::I32(r) | Value::Z32(r)) => {
+                Value::I64(l $op r as i64)
+            }
+            (Value::U32(l) | Value::V32(l), Value::I32(r) | Value::Z32(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::U32(l) | Value::V32(l), Value::I64(r) | Value::Z64(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::U64(l) | Value::V64(l), Value::I32(r) | Value::Z32(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::U64(l) | Value::V64(l), Value::I64(r) | Value::Z64(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::I32(l) | Value::Z32(l), Value::U32(r) | Value::V32(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::I64(l) | Value::Z64(l), Value::U32(r) | Value::V32(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::I32(l) | Value::Z32(l), Value::U64(r) | Value::V64(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::I64(l) | Value::Z64(l), Value::U64(r) | Value::V64(r)) => {
+                Value::I64(l as i64 $op r as i64)
+            }
+            (Value::True, Value::True) => Value::from(true $op true),
+            (Value::True, Value::False) => Value::from(true $op false),
+            (Value::False, Value::True) => Value::from(false $op true),
+            (Value::False, Value::False) => Value::from(false $op false),
+            (Value::String(s), n) => match s.parse::<Value>() {
+                Err(e) => Value::Error(Chars::from(format!("{}", e))),
+                Ok(s) => s $op n,
+            },
+            (n, Value::String(s)) => match s.parse::<Value>() {
+                Err(e) => Value::Error(Chars::from(format!("{}", e))),
+                Ok(s) => n $op s,
+            },
+            (Value::True, n) => Value::U32(1) $op n,
+            (n, Value::True) => n $op Value::U32(1),
+            (Value::False, n) => Value::U32(0) $op n,
+            (n, Value::False) => n $op Value::U32(0),
+            (l, r) => Value::Error(Chars::from(format!(
+                "can't apply {} to {} and {}, integers are required",
+                $name, l, r
+            ))),
+        }
+    };
+}
+
+impl BitAnd for Value {
+    type Output = Value;
+
+    fn bitand(self, rhs: Self) -> Self {
+        apply_bit_op!(self, rhs, &, "bitand")
+    }
+}
+
+impl BitOr for Value {
+    type Output = Value;
+
+    fn bitor(self, rhs: Self) -> Self {
+        apply_bit_op!(self, rhs, |, "bitor")
+    }
+}
+
+impl BitXor for Value {
+    type Output = Value;
+
+    fn bitxor(self, rhs: Self) -> Self {
+        apply_bit_op!(self, rhs, ^, "bitxor")
+    }
+}
+
+// The shift amount may be any non negative integer, shifting by the
+// width of the left hand side or more is an error.
+macro_rules! shift_fn {
+    ($fname:ident, $op:ident, $name:expr) => {
+        fn $fname(l: Value, r: Value) -> Value {
+            match (l, r) {
+                (Value::String(s), n) => match s.parse::<Value>() {
+                    Err(e) => Value::Error(Chars::from(format!("{}", e))),
+                    Ok(s) => $fname(s, n),
+                },
+                (n, Value::String(s)) => match s.parse::<Value>() {
+                    Err(e) => Value::Error(Chars::from(format!("{}", e))),
+                    Ok(s) => $fname(n, s),
+                },
+                (Value::True, n) => $fname(Value::U32(1), n),
+                (Value::False, n) => $fname(Value::U32(0), n),
+                (l, r) => {
+                    let by = match &r {
+                        Value::U32(r) | Value::V32(r) => Some(*r),
+                        Value::U64(r) | Value::V64(r) => u32::try_from(*r).ok(),
+                        Value::I32(r) | Value::Z32(r) => u32::try_from(*r).ok(),
+                        Value::I64(r) | Value::Z64(r) => u32::try_from(*r).ok(),
+                        _ => None,
+                    };
+                    let res = by.and_then(|by| match &l {
+                        Value::U32(l) => l.$op(by).map(Value::U32),
+                        Value::V32(l) => l.$op(by).map(Value::V32),
+                        Value::I32(l) => l.$op(by).map(Value::I32),
+                        Value::Z32(l) => l.$op(by).map(Value::Z32),
+                        Value::U64(l) => l.$op(by).map(Value::U64),
+                        Value::V64(l) => l.$op(by).map(Value::V64),
+                        Value::I64(l) => l.$op(by).map(Value::I64),
+                        Value::Z64(l) => l.$op(by).map(Value::Z64),
+                        _ => None,
+                    });
+                    match res {
+                        Some(v) => v,
+                        None => Value::Error(Chars::from(format!(
+                            "can't apply {} to {} and {}, an integer and a shift \
+                             smaller than it's width are required",
+                            $name, l, r
+                        ))),
+                    }
+                }
+            }
+        }
+    };
+}
+
+shift_fn!(shift_left, checked_shl, "shl");
+shift_fn!(shift_right, checked_shr, "shr");
+
+impl Shl for Value {
+    type Output = Value;
+
+    fn shl(self, rhs: Self) -> Self {
+        shift_left(self, rhs)
+    }
+}
+
+impl Shr for Value {
+    type Output = Value;
+
+    fn shr(self, rhs: Self) -> Self {
+        shift_right(self, rhs)
+    }
+}
+
+// Decode the elements of an array directly into it's final
+// allocation, avoiding the intermediate Vec and the copy out of it.
+fn decode_array(len: usize, buf: &mut impl Buf) -> Result<Arc<[Value]>> {
+    // every element is at least one byte
+    if len > buf.remaining() {
+        return Err(PackError::TooBig);
+    }
+    let mut elts = Arc::<[Value]>::new_uninit_slice(len);
+    let slots = Arc::get_mut(&mut elts).unwrap();
+    for i in 0..len {
+        match <Value as Pack>::decode(buf) {
+            Ok(v) => {
+                slots[i].write(v);
+            }
+            Err(e) => {
+                for slot in &mut slots[..i] {
+                    // safe because slots 0..i were written above
+                    unsafe { slot.assume_init_drop() }
+                }
+                return Err(e);
+            }
+        }
+    }
+    // safe because every slot was written above
+    Ok(unsafe { elts.assume_init() })
+}
+
 impl Pack for Value {
     fn encoded_len(&self) -> usize {
         1 + match self {
@@ -932,6 +1315,7 @@ impl Pack for Value {
                     + elts.iter().fold(0, |sum, v| sum + Pack::encoded_len(v))
             }
             Value::Decimal(d) => <Decimal as Pack>::encoded_len(d),
+            Value::ErrorInfo(e) => <ErrorInfo as Pack>::encoded_len(e),
         }
     }
 
@@ -1015,6 +1399,10 @@ impl Pack for Value {
                 buf.put_u8(20);
                 <Decimal as Pack>::encode(d, buf)
             }
+            Value::ErrorInfo(e) => {
+                buf.put_u8(21);
+                <ErrorInfo as Pack>::encode(e, buf)
+            }
         }
     }
 
@@ -1040,14 +1428,17 @@ impl Pack for Value {
             17 => Ok(Value::Ok),
             18 => Ok(Value::Error(<Chars as Pack>::decode(buf)?)),
             19 => {
+                let _depth = DepthGuard::enter()?;
                 let len = pack::decode_varint(buf)? as usize;
-                let mut elts = Vec::with_capacity(len);
-                while elts.len() < len {
-                    elts.push(<Value as Pack>::decode(buf)?);
-                }
-                Ok(Value::Array(Arc::from(elts)))
+                let max = DecodeLimits::current().max_array_len;
+                DecodeLimits::check(len, max, Limit::ArrayLen)?;
+                Ok(Value::Array(decode_array(len, buf)?))
             }
             20 => Ok(Value::Decimal(<Decimal as Pack>::decode(buf)?)),
+            21 => {
+                let _depth = DepthGuard::enter()?;
+                Ok(Value::ErrorInfo(Arc::new(<ErrorInfo as Pack>::decode(buf)?)))
+            }
             _ => Err(PackError::UnknownTag),
         }
     }
@@ -1060,13 +1451,238 @@ pub trait FromValue {
         Self: Sized;
 
     /// extract the type of self from v if the type of v is equivelent
-    /// to the type of self, otherwise return None.
+    /// to the type of self, otherwise return None. Integers are
+    /// converted between integer types only if they are in range,
+    /// e.g. `Value::U64(300).get_as::<u8>()` is `None`.
     fn get(v: Value) -> Option<Self>
     where
         Self: Sized,
     {
         FromValue::from_value(v).ok()
     }
+
+    /// like `get`, except integers that are out of range for an
+    /// integer type are truncated as by `as`, e.g.
+    /// `Value::U64(300).get_as_lossy::<u8>()` is `Some(44)`. This is
+    /// how `get` behaved before 0.17.
+    fn get_lossy(v: Value) -> Option<Self>
+    where
+        Self: Sized,
+    {
+        FromValue::get(v)
+    }
+}
+
+/// The radix integers are printed in, see `Value::fmt_ext_radix`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Radix {
+    #[default]
+    Dec,
+    Hex,
+    Oct,
+    Bin,
+}
+
+/// The timezone datetimes are displayed in, see
+/// `ValueFormat::tz`. This only affects display, datetimes are always
+/// UTC on the wire.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DisplayTz {
+    #[default]
+    Utc,
+    /// the local timezone of the machine doing the formatting
+    Local,
+    Fixed(FixedOffset),
+}
+
+impl DisplayTz {
+    fn format(&self, d: &DateTime<Utc>) -> String {
+        match self {
+            DisplayTz::Utc => d.to_string(),
+            DisplayTz::Local => d.with_timezone(&Local).to_string(),
+            DisplayTz::Fixed(off) => d.with_timezone(off).to_string(),
+        }
+    }
+}
+
+impl FromStr for DisplayTz {
+    type Err = anyhow::Error;
+
+    /// `utc`, `local`, or a fixed offset such as `+05:30`
+    fn from_str(s: &str) -> Res<Self> {
+        match s.trim() {
+            s if s.eq_ignore_ascii_case("utc") => Ok(DisplayTz::Utc),
+            s if s.eq_ignore_ascii_case("local") => Ok(DisplayTz::Local),
+            s => match FixedOffset::from_str(s) {
+                Ok(off) => Ok(DisplayTz::Fixed(off)),
+                Err(_) => bail!("invalid timezone {}, expected utc, local, or +HH:MM", s),
+            },
+        }
+    }
+}
+
+/// Display options for values, see `Value::format_with`.
+///
+/// The default formats values exactly as `Display` does. Radix and
+/// precision keep the result parseable (precision may of course lose
+/// digits), while thousands separators, SI prefixes and humanized
+/// durations are meant for people, values formatted with them are
+/// printed without a type prefix and won't parse back.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ValueFormat {
+    /// print type prefixes, e.g. `u32:42`
+    pub types: bool,
+    /// print top level strings and datetimes without quotes, and
+    /// numbers without type prefixes, like `Value::fmt_naked`
+    pub naked: bool,
+    /// the radix integers are printed in
+    pub radix: Radix,
+    /// the number of digits after the decimal point of floats,
+    /// decimals and durations
+    pub precision: Option<usize>,
+    /// separate groups of thousands in decimal numbers with this
+    /// character, e.g. `1,234,567`
+    pub thousands: Option<char>,
+    /// scale numbers by SI prefixes, e.g. `1.5k`, `12.3µ`
+    pub si: bool,
+    /// print durations as e.g. `1d 2h 3m 4.5s`
+    pub humanize: bool,
+    /// the timezone datetimes are printed in
+    pub tz: DisplayTz,
+}
+
+impl Default for ValueFormat {
+    fn default() -> Self {
+        ValueFormat {
+            types: true,
+            naked: false,
+            radix: Radix::Dec,
+            precision: None,
+            thousands: None,
+            si: false,
+            humanize: false,
+            tz: DisplayTz::Utc,
+        }
+    }
+}
+
+impl ValueFormat {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn types(mut self, types: bool) -> Self {
+        self.types = types;
+        self
+    }
+
+    pub fn naked(mut self, naked: bool) -> Self {
+        self.naked = naked;
+        self
+    }
+
+    pub fn radix(mut self, radix: Radix) -> Self {
+        self.radix = radix;
+        self
+    }
+
+    pub fn precision(mut self, precision: usize) -> Self {
+        self.precision = Some(precision);
+        self
+    }
+
+    pub fn thousands(mut self, sep: char) -> Self {
+        self.thousands = Some(sep);
+        self
+    }
+
+    pub fn si(mut self, si: bool) -> Self {
+        self.si = si;
+        self
+    }
+
+    pub fn humanize(mut self, humanize: bool) -> Self {
+        self.humanize = humanize;
+        self
+    }
+
+    pub fn tz(mut self, tz: DisplayTz) -> Self {
+        self.tz = tz;
+        self
+    }
+
+    // format a float that is already scaled, without any prefix
+    fn float<T: fmt::Display>(&self, v: T, si: bool) -> String {
+        let s = match self.precision {
+            Some(p) => format!("{:.*}", p, v),
+            // SI prefixes are for reading, so don't print 17 digits
+            None if si => {
+                let s = format!("{:.3}", v);
+                String::from(s.trim_end_matches('0').trim_end_matches('.'))
+            }
+            None => format!("{}", v),
+        };
+        self.group(s)
+    }
+
+    // insert the thousands separator into the integer part of s
+    fn group(&self, s: String) -> String {
+        let sep = match self.thousands {
+            None => return s,
+            Some(sep) => sep,
+        };
+        let (sign, rest) = match s.strip_prefix('-') {
+            Some(rest) => ("-", rest),
+            None => ("", &*s),
+        };
+        let (int, frac) = rest.split_at(rest.find('.').unwrap_or(rest.len()));
+        if !int.bytes().all(|b| b.is_ascii_digit()) {
+            return s; // NaN, inf
+        }
+        let mut res = String::from(sign);
+        for (i, c) in int.chars().enumerate() {
+            if i > 0 && (int.len() - i) % 3 == 0 {
+                res.push(sep)
+            }
+            res.push(c)
+        }
+        res.push_str(frac);
+        res
+    }
+
+    fn with_si(&self, v: f64) -> String {
+        const PREFIXES: [&str; 13] =
+            ["a", "f", "p", "n", "µ", "m", "", "k", "M", "G", "T", "P", "E"];
+        if v == 0. || !v.is_finite() {
+            return self.float(v, true);
+        }
+        let e = ((v.abs().log10() / 3.).floor() as i32).clamp(-6, 6);
+        let m = v / 1000f64.powi(e);
+        format!("{}{}", self.float(m, true), PREFIXES[(e + 6) as usize])
+    }
+
+    fn human_duration(&self, d: Duration) -> String {
+        if d.is_zero() {
+            return String::from("0s");
+        }
+        if d < Duration::from_secs(1) {
+            return format!("{}s", self.with_si(d.as_secs_f64()));
+        }
+        let secs = d.as_secs();
+        let mut res = String::new();
+        for (n, unit) in
+            [(secs / 86400, "d"), (secs / 3600 % 24, "h"), (secs / 60 % 60, "m")]
+        {
+            if n > 0 || !res.is_empty() {
+                res.push_str(&format!("{}{} ", n, unit));
+            }
+        }
+        let s = (secs % 60) as f64 + d.subsec_nanos() as f64 / 1e9;
+        if s > 0. || res.is_empty() {
+            res.push_str(&format!("{}s", self.float(s, true)));
+        }
+        String::from(res.trim_end())
+    }
 }
 
 impl Value {
@@ -1104,7 +1720,7 @@ impl Value {
             Value::False => write!(f, "false"),
             Value::Null => write!(f, "null"),
             Value::Ok => write!(f, "ok"),
-            v @ Value::Error(_) => write!(f, "{}", v),
+            v @ (Value::Error(_) | Value::ErrorInfo(_)) => write!(f, "{}", v),
             v @ Value::Array(_) => write!(f, "{}", v),
         }
     }
@@ -1119,63 +1735,61 @@ impl Value {
         esc: &[char],
         types: bool,
     ) -> fmt::Result {
-        match self {
-            Value::U32(v) => {
-                if types {
-                    write!(f, "u32:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::V32(v) => {
-                if types {
-                    write!(f, "v32:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::I32(v) => {
-                if types {
-                    write!(f, "i32:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::Z32(v) => {
-                if types {
-                    write!(f, "z32:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::U64(v) => {
-                if types {
-                    write!(f, "u64:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::V64(v) => {
-                if types {
-                    write!(f, "v64:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::I64(v) => {
-                if types {
-                    write!(f, "i64:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
-            }
-            Value::Z64(v) => {
-                if types {
-                    write!(f, "z64:{}", v)
-                } else {
-                    write!(f, "{}", v)
-                }
+        self.fmt_ext_radix(f, esc, types, Radix::Dec)
+    }
+
+    /// Format the value as the parser would read it, printing
+    /// integers in `radix`.
+    pub fn to_string_radix(&self, radix: Radix) -> String {
+        struct WVal<'a>(&'a Value, Radix);
+        impl<'a> fmt::Display for WVal<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt_ext_radix(f, &value_parser::VAL_ESC, true, self.1)
             }
+        }
+        format!("{}", WVal(self, radix))
+    }
+
+    /// Same as `fmt_ext`, but print integers in `radix`. Negative
+    /// integers are printed as a sign followed by the magnitude,
+    /// e.g. -0x10, so that they parse back to the same value.
+    pub fn fmt_ext_radix(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        esc: &[char],
+        types: bool,
+        radix: Radix,
+    ) -> fmt::Result {
+        fn int(
+            f: &mut fmt::Formatter<'_>,
+            typ: Option<&str>,
+            neg: bool,
+            mag: u64,
+            radix: Radix,
+        ) -> fmt::Result {
+            if let Some(typ) = typ {
+                write!(f, "{}:", typ)?
+            }
+            if neg {
+                write!(f, "-")?
+            }
+            match radix {
+                Radix::Dec => write!(f, "{}", mag),
+                Radix::Hex => write!(f, "0x{:x}", mag),
+                Radix::Oct => write!(f, "0o{:o}", mag),
+                Radix::Bin => write!(f, "0b{:b}", mag),
+            }
+        }
+        let typ = |t| if types { Some(t) } else { None };
+        match self {
+            Value::U32(v) => int(f, typ("u32"), false, *v as u64, radix),
+            Value::V32(v) => int(f, typ("v32"), false, *v as u64, radix),
+            Value::I32(v) => int(f, typ("i32"), *v < 0, v.unsigned_abs() as u64, radix),
+            Value::Z32(v) => int(f, typ("z32"), *v < 0, v.unsigned_abs() as u64, radix),
+            Value::U64(v) => int(f, typ("u64"), false, *v, radix),
+            Value::V64(v) => int(f, typ("v64"), false, *v, radix),
+            Value::I64(v) => int(f, typ("i64"), *v < 0, v.unsigned_abs(), radix),
+            Value::Z64(v) => int(f, typ("z64"), *v < 0, v.unsigned_abs(), radix),
             Value::F32(v) => {
                 let pfx = if types { "f32:" } else { "" };
                 if v.fract() == 0. {
@@ -1229,15 +1843,144 @@ impl Value {
             Value::Error(v) => {
                 write!(f, r#"error:"{}""#, utils::escape(&*v, '\\', esc))
             }
+            Value::ErrorInfo(e) => {
+                let m = utils::escape(&*e.message, '\\', esc);
+                write!(f, r#"error:[{}, "{}""#, e.code, m)?;
+                if let Some(v) = &e.payload {
+                    write!(f, ", ")?;
+                    v.fmt_ext_radix(f, esc, types, radix)?
+                }
+                write!(f, "]")
+            }
             Value::Array(elts) => {
                 write!(f, "[")?;
                 for (i, v) in elts.iter().enumerate() {
                     if i < elts.len() - 1 {
-                        v.fmt_ext(f, esc, types)?;
+                        v.fmt_ext_radix(f, esc, types, radix)?;
                         write!(f, ", ")?
                     } else {
-                        v.fmt_ext(f, esc, types)?
+                        v.fmt_ext_radix(f, esc, types, radix)?
+                    }
+                }
+                write!(f, "]")
+            }
+        }
+    }
+
+    /// Format the value according to `fmt`, see `ValueFormat`
+    pub fn format_with(&self, fmt: &ValueFormat) -> String {
+        struct WVal<'a>(&'a Value, &'a ValueFormat);
+        impl<'a> fmt::Display for WVal<'a> {
+            fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                self.0.fmt_with(f, self.1)
+            }
+        }
+        format!("{}", WVal(self, fmt))
+    }
+
+    pub fn fmt_with(&self, f: &mut fmt::Formatter<'_>, fmt: &ValueFormat) -> fmt::Result {
+        self.fmt_with_ext(f, fmt, fmt.naked)
+    }
+
+    fn fmt_with_ext(
+        &self,
+        f: &mut fmt::Formatter<'_>,
+        fmt: &ValueFormat,
+        naked: bool,
+    ) -> fmt::Result {
+        let esc = &value_parser::VAL_ESC;
+        let human = fmt.si || fmt.thousands.is_some();
+        let pfx = |t: &'static str| if fmt.types && !naked && !human { t } else { "" };
+        let int = |f: &mut fmt::Formatter<'_>, typ, neg: bool, mag: u64| {
+            // thousands separators only apply to decimal integers
+            let human = fmt.si || (fmt.thousands.is_some() && fmt.radix == Radix::Dec);
+            let pfx = if fmt.types && !naked && !human { typ } else { "" };
+            let s = if fmt.si {
+                let v = mag as f64;
+                fmt.with_si(if neg { -v } else { v })
+            } else {
+                let sign = if neg { "-" } else { "" };
+                match fmt.radix {
+                    Radix::Dec => fmt.group(format!("{}{}", sign, mag)),
+                    Radix::Hex => format!("{}0x{:x}", sign, mag),
+                    Radix::Oct => format!("{}0o{:o}", sign, mag),
+                    Radix::Bin => format!("{}0b{:b}", sign, mag),
+                }
+            };
+            write!(f, "{}{}", pfx, s)
+        };
+        let float = |f: &mut fmt::Formatter<'_>, typ, v: f64, s: String| {
+            if fmt.si {
+                return write!(f, "{}", fmt.with_si(v));
+            }
+            // keep whole floats floats when they are parsed
+            if naked || human || !v.is_finite() || s.contains('.') {
+                write!(f, "{}{}", pfx(typ), s)
+            } else {
+                write!(f, "{}{}.", pfx(typ), s)
+            }
+        };
+        match self {
+            Value::U32(v) => int(f, "u32:", false, *v as u64),
+            Value::V32(v) => int(f, "v32:", false, *v as u64),
+            Value::I32(v) => int(f, "i32:", *v < 0, v.unsigned_abs() as u64),
+            Value::Z32(v) => int(f, "z32:", *v < 0, v.unsigned_abs() as u64),
+            Value::U64(v) => int(f, "u64:", false, *v),
+            Value::V64(v) => int(f, "v64:", false, *v),
+            Value::I64(v) => int(f, "i64:", *v < 0, v.unsigned_abs()),
+            Value::Z64(v) => int(f, "z64:", *v < 0, v.unsigned_abs()),
+            Value::F32(v) => float(f, "f32:", *v as f64, fmt.float(*v, false)),
+            Value::F64(v) => float(f, "f64:", *v, fmt.float(*v, false)),
+            Value::Decimal(v) => {
+                if fmt.si {
+                    write!(f, "{}", fmt.with_si(f64::try_from(*v).unwrap_or(f64::NAN)))
+                } else {
+                    let s = match fmt.precision {
+                        Some(p) => format!("{:.*}", p, v),
+                        None => format!("{}", v),
+                    };
+                    write!(f, "{}{}", pfx("decimal:"), fmt.group(s))
+                }
+            }
+            Value::DateTime(v) => {
+                let v = fmt.tz.format(v);
+                if naked {
+                    write!(f, "{}", v)
+                } else {
+                    write!(f, r#"{}"{}""#, pfx("datetime:"), v)
+                }
+            }
+            Value::Duration(d) if fmt.humanize => write!(f, "{}", fmt.human_duration(*d)),
+            Value::Duration(d) => {
+                let s = fmt.float(d.as_secs_f64(), false);
+                let dot = if s.contains('.') { "" } else { "." };
+                write!(f, "{}{}{}s", pfx("duration:"), s, dot)
+            }
+            Value::String(s) if naked => write!(f, "{}", s),
+            Value::String(s) => write!(f, r#""{}""#, utils::escape(&*s, '\\', esc)),
+            Value::Bytes(b) => {
+                let pfx = if fmt.types && !naked { "bytes:" } else { "" };
+                write!(f, "{}{}", pfx, BASE64.encode(&*b))
+            }
+            Value::True | Value::False | Value::Null | Value::Ok | Value::Error(_) => {
+                self.fmt_ext(f, esc, fmt.types)
+            }
+            Value::ErrorInfo(e) => {
+                let m = utils::escape(&*e.message, '\\', esc);
+                write!(f, r#"error:[{}, "{}""#, e.code, m)?;
+                if let Some(v) = &e.payload {
+                    write!(f, ", ")?;
+                    v.fmt_with_ext(f, fmt, false)?
+                }
+                write!(f, "]")
+            }
+            Value::Array(elts) => {
+                write!(f, "[")?;
+                for (i, v) in elts.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?
                     }
+                    v.fmt_with_ext(f, fmt, false)?
                 }
                 write!(f, "]")
             }
@@ -1426,7 +2169,7 @@ impl Value {
             Value::Bytes(_) if typ == Typ::Bytes => Some(self),
             Value::Bytes(_) => None,
             Value::Ok => Value::True.cast(typ),
-            Value::Error(_) => Value::False.cast(typ),
+            Value::Error(_) | Value::ErrorInfo(_) => Value::False.cast(typ),
             Value::Null if typ == Typ::Null => Some(self),
             Value::Null => None,
         }
@@ -1437,14 +2180,81 @@ impl Value {
         <T as FromValue>::from_value(self)
     }
 
+    /// cast value directly to any type implementing
+    /// `serde::Deserialize`. see `value_serde` for how structured
+    /// types are represented.
+    pub fn cast_to_serde<T: DeserializeOwned>(self) -> Res<T> {
+        Ok(T::deserialize(self)?)
+    }
+
+    /// build a value from any type implementing `serde::Serialize`,
+    /// see `value_serde`.
+    pub fn from_serde<T: Serialize + ?Sized>(t: &T) -> Res<Value> {
+        Ok(t.serialize(value_serde::Serializer)?)
+    }
+
     pub fn get_as<T: FromValue + Sized>(self) -> Option<T> {
         <T as FromValue>::get(self)
     }
 
+    pub fn get_as_lossy<T: FromValue + Sized>(self) -> Option<T> {
+        <T as FromValue>::get_lossy(self)
+    }
+
     pub fn err<T: std::error::Error>(e: T) -> Value {
         Value::Error(Chars::from(e.to_string()))
     }
 
+    /// construct a structured error with the specified code and message
+    pub fn coded_err<M: Into<Chars>>(code: u32, message: M) -> Value {
+        Value::ErrorInfo(Arc::new(ErrorInfo::new(code, message)))
+    }
+
+    /// return self with every `ErrorInfo`, including those inside
+    /// arrays, replaced by a plain `Error` with the same message. This
+    /// is how values are sent to peers that are older than `ErrorInfo`.
+    pub fn without_error_info(self) -> Value {
+        fn has_error_info(v: &Value) -> bool {
+            match v {
+                Value::ErrorInfo(_) => true,
+                Value::Array(a) => a.iter().any(has_error_info),
+                _ => false,
+            }
+        }
+        match self {
+            Value::ErrorInfo(e) => Value::Error(e.message.clone()),
+            Value::Array(a) if a.iter().any(has_error_info) => {
+                Value::Array(a.iter().map(|v| v.clone().without_error_info()).collect())
+            }
+            v => v,
+        }
+    }
+
+    /// return true if the value is either kind of error
+    pub fn is_error(&self) -> bool {
+        matches!(self, Value::Error(_) | Value::ErrorInfo(_))
+    }
+
+    /// return the message of either kind of error, None if the value
+    /// is not an error.
+    pub fn error_message(&self) -> Option<&Chars> {
+        match self {
+            Value::Error(m) => Some(m),
+            Value::ErrorInfo(e) => Some(&e.message),
+            _ => None,
+        }
+    }
+
+    /// return the code of a structured error. Plain errors have code
+    /// `ErrorInfo::OTHER`, and non error values return None.
+    pub fn error_code(&self) -> Option<u32> {
+        match self {
+            Value::Error(_) => Some(ErrorInfo::OTHER),
+            Value::ErrorInfo(e) => Some(e.code),
+            _ => None,
+        }
+    }
+
     /// return true if the value is some kind of number, otherwise
     /// false.
     pub fn number(&self) -> bool {
@@ -1469,6 +2279,7 @@ impl Value {
             | Value::Null
             | Value::Ok
             | Value::Error(_)
+            | Value::ErrorInfo(_)
             | Value::Array(_) => false,
         }
     }
@@ -1526,24 +2337,45 @@ impl<T: Into<Value> + Copy> convert::From<&T> for Value {
     }
 }
 
+// the integer in `v` as a `T` if it is in range for `T`
+fn get_int<T>(v: Value) -> Option<T>
+where
+    T: TryFrom<u32> + TryFrom<i32> + TryFrom<u64> + TryFrom<i64>,
+{
+    match v {
+        Value::U32(v) | Value::V32(v) => T::try_from(v).ok(),
+        Value::U64(v) | Value::V64(v) => T::try_from(v).ok(),
+        Value::I32(v) | Value::Z32(v) => T::try_from(v).ok(),
+        Value::I64(v) | Value::Z64(v) => T::try_from(v).ok(),
+        _ => None,
+    }
+}
+
+// the integer in `v` converted to `$t` with `as`
+macro_rules! get_int_lossy {
+    ($t:ty, $v:expr) => {
+        match $v {
+            Value::U32(v) | Value::V32(v) => Some(v as $t),
+            Value::U64(v) | Value::V64(v) => Some(v as $t),
+            Value::I32(v) | Value::Z32(v) => Some(v as $t),
+            Value::I64(v) | Value::Z64(v) => Some(v as $t),
+            _ => None,
+        }
+    };
+}
+
 impl FromValue for u8 {
     fn from_value(v: Value) -> Res<Self> {
         let v = v.cast_to::<u32>()?;
-        if v <= u8::MAX as u32 {
-            Ok(v as u8)
-        } else {
-            bail!("can't cast")
-        }
+        u8::try_from(v).map_err(|_| anyhow!("{} is out of range for u8", v))
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as u8),
-            Value::U64(v) | Value::V64(v) => Some(v as u8),
-            Value::I32(v) | Value::Z32(v) => Some(v as u8),
-            Value::I64(v) | Value::Z64(v) => Some(v as u8),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(u8, v)
     }
 }
 
@@ -1556,21 +2388,15 @@ impl convert::From<u8> for Value {
 impl FromValue for i8 {
     fn from_value(v: Value) -> Res<Self> {
         let v = v.cast_to::<i32>()?;
-        if v <= i8::MAX as i32 && v >= i8::MIN as i32 {
-            Ok(v as i8)
-        } else {
-            bail!("can't cast")
-        }
+        i8::try_from(v).map_err(|_| anyhow!("{} is out of range for i8", v))
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as i8),
-            Value::U64(v) | Value::V64(v) => Some(v as i8),
-            Value::I32(v) | Value::Z32(v) => Some(v as i8),
-            Value::I64(v) | Value::Z64(v) => Some(v as i8),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(i8, v)
     }
 }
 
@@ -1583,21 +2409,15 @@ impl convert::From<i8> for Value {
 impl FromValue for u16 {
     fn from_value(v: Value) -> Res<Self> {
         let v = v.cast_to::<u32>()?;
-        if v <= u16::MAX as u32 {
-            Ok(v as u16)
-        } else {
-            bail!("can't cast")
-        }
+        u16::try_from(v).map_err(|_| anyhow!("{} is out of range for u16", v))
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as u16),
-            Value::U64(v) | Value::V64(v) => Some(v as u16),
-            Value::I32(v) | Value::Z32(v) => Some(v as u16),
-            Value::I64(v) | Value::Z64(v) => Some(v as u16),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(u16, v)
     }
 }
 
@@ -1610,21 +2430,15 @@ impl convert::From<u16> for Value {
 impl FromValue for i16 {
     fn from_value(v: Value) -> Res<Self> {
         let v = v.cast_to::<i32>()?;
-        if v <= i16::MAX as i32 && v >= i16::MIN as i32 {
-            Ok(v as i16)
-        } else {
-            bail!("can't cast")
-        }
+        i16::try_from(v).map_err(|_| anyhow!("{} is out of range for i16", v))
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as i16),
-            Value::U64(v) | Value::V64(v) => Some(v as i16),
-            Value::I32(v) | Value::Z32(v) => Some(v as i16),
-            Value::I64(v) | Value::Z64(v) => Some(v as i16),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(i16, v)
     }
 }
 
@@ -1643,13 +2457,11 @@ impl FromValue for u32 {
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as u32),
-            Value::U64(v) | Value::V64(v) => Some(v as u32),
-            Value::I32(v) | Value::Z32(v) => Some(v as u32),
-            Value::I64(v) | Value::Z64(v) => Some(v as u32),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(u32, v)
     }
 }
 
@@ -1668,13 +2480,11 @@ impl FromValue for i32 {
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as i32),
-            Value::U64(v) | Value::V64(v) => Some(v as i32),
-            Value::I32(v) | Value::Z32(v) => Some(v as i32),
-            Value::I64(v) | Value::Z64(v) => Some(v as i32),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(i32, v)
     }
 }
 
@@ -1693,13 +2503,11 @@ impl FromValue for u64 {
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as u64),
-            Value::U64(v) | Value::V64(v) => Some(v as u64),
-            Value::I32(v) | Value::Z32(v) => Some(v as u64),
-            Value::I64(v) | Value::Z64(v) => Some(v as u64),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(u64, v)
     }
 }
 
@@ -1724,13 +2532,11 @@ impl FromValue for usize {
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as usize),
-            Value::U64(v) | Value::V64(v) => Some(v as usize),
-            Value::I32(v) | Value::Z32(v) => Some(v as usize),
-            Value::I64(v) | Value::Z64(v) => Some(v as usize),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(usize, v)
     }
 }
 
@@ -1743,13 +2549,11 @@ impl FromValue for i64 {
     }
 
     fn get(v: Value) -> Option<Self> {
-        match v {
-            Value::U32(v) | Value::V32(v) => Some(v as i64),
-            Value::U64(v) | Value::V64(v) => Some(v as i64),
-            Value::I32(v) | Value::Z32(v) => Some(v as i64),
-            Value::I64(v) | Value::Z64(v) => Some(v as i64),
-            _ => None,
-        }
+        get_int(v)
+    }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        get_int_lossy!(i64, v)
     }
 }
 
@@ -1890,6 +2694,62 @@ impl convert::From<Path> for Value {
     }
 }
 
+macro_rules! from_str_value {
+    ($t:ty) => {
+        impl FromValue for $t {
+            fn from_value(v: Value) -> Res<Self> {
+                Ok(v.cast_to::<Chars>()?.parse::<$t>()?)
+            }
+
+            fn get(v: Value) -> Option<Self> {
+                match v {
+                    Value::String(c) => c.parse::<$t>().ok(),
+                    _ => None,
+                }
+            }
+        }
+
+        impl convert::From<$t> for Value {
+            fn from(v: $t) -> Value {
+                Value::String(Chars::from(v.to_string()))
+            }
+        }
+    };
+}
+
+from_str_value!(IpAddr);
+from_str_value!(Ipv4Addr);
+from_str_value!(Ipv6Addr);
+from_str_value!(SocketAddr);
+
+#[cfg(feature = "url")]
+from_str_value!(url::Url);
+
+#[cfg(feature = "uuid")]
+impl FromValue for uuid::Uuid {
+    fn from_value(v: Value) -> Res<Self> {
+        match v {
+            Value::Bytes(b) => Ok(uuid::Uuid::from_slice(&b)?),
+            v => Ok(v.cast_to::<Chars>()?.parse::<uuid::Uuid>()?),
+        }
+    }
+
+    fn get(v: Value) -> Option<Self> {
+        match v {
+            Value::String(c) => c.parse::<uuid::Uuid>().ok(),
+            Value::Bytes(b) => uuid::Uuid::from_slice(&b).ok(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl convert::From<uuid::Uuid> for Value {
+    fn from(v: uuid::Uuid) -> Value {
+        Value::String(Chars::from(v.hyphenated().to_string()))
+    }
+}
+
 impl FromValue for String {
     fn from_value(v: Value) -> Res<Self> {
         v.cast_to::<Chars>().map(|c| c.into())
@@ -1937,6 +2797,62 @@ impl convert::From<DateTime<Utc>> for Value {
     }
 }
 
+impl FromValue for NaiveDate {
+    fn from_value(v: Value) -> Res<Self> {
+        match v {
+            Value::String(s) => Ok(s.parse::<NaiveDate>()?),
+            v => Ok(<DateTime<Utc>>::from_value(v)?.date_naive()),
+        }
+    }
+
+    fn get(v: Value) -> Option<Self> {
+        match v {
+            Value::DateTime(d) => Some(d.date_naive()),
+            _ => None,
+        }
+    }
+}
+
+/// A date is a datetime at midnight UTC
+impl convert::From<NaiveDate> for Value {
+    fn from(v: NaiveDate) -> Value {
+        Value::DateTime(Utc.from_utc_datetime(&v.and_time(NaiveTime::MIN)))
+    }
+}
+
+impl FromValue for NaiveTime {
+    fn from_value(v: Value) -> Res<Self> {
+        match v {
+            Value::String(s) => Ok(s.parse::<NaiveTime>()?),
+            Value::DateTime(d) => Ok(d.time()),
+            v => {
+                let d = Duration::from_value(v)?;
+                let secs = u32::try_from(d.as_secs())?;
+                NaiveTime::from_num_seconds_from_midnight_opt(secs, d.subsec_nanos())
+                    .ok_or_else(|| anyhow!("duration is longer than a day"))
+            }
+        }
+    }
+
+    fn get(v: Value) -> Option<Self> {
+        match v {
+            Value::Duration(d) => {
+                let secs = u32::try_from(d.as_secs()).ok()?;
+                NaiveTime::from_num_seconds_from_midnight_opt(secs, d.subsec_nanos())
+            }
+            _ => None,
+        }
+    }
+}
+
+/// A time of day is the duration since midnight
+impl convert::From<NaiveTime> for Value {
+    fn from(v: NaiveTime) -> Value {
+        let secs = v.num_seconds_from_midnight() as u64;
+        Value::Duration(Duration::new(secs, v.nanosecond()))
+    }
+}
+
 impl FromValue for Duration {
     fn from_value(v: Value) -> Res<Self> {
         v.cast(Typ::Duration).ok_or_else(|| anyhow!("can't cast")).and_then(|v| match v {
@@ -2060,6 +2976,16 @@ impl<T: FromValue> FromValue for Vec<T> {
             _ => None,
         }
     }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        match v {
+            Value::Array(elts) => elts
+                .iter()
+                .map(|v| FromValue::get_lossy(v.clone()))
+                .collect::<Option<Vec<_>>>(),
+            _ => None,
+        }
+    }
 }
 
 impl<T: convert::Into<Value>> convert::From<Vec<T>> for Value {
@@ -2352,6 +3278,13 @@ impl<T: FromValue> FromValue for Option<T> {
             v => v.get_as::<T>().map(|v| Some(v)),
         }
     }
+
+    fn get_lossy(v: Value) -> Option<Self> {
+        match v {
+            Value::Null => Some(None),
+            v => v.get_as_lossy::<T>().map(|v| Some(v)),
+        }
+    }
 }
 
 impl<T: convert::Into<Value>> convert::From<Option<T>> for Value {
@@ -2360,7 +3293,7 @@ impl<T: convert::Into<Value>> convert::From<Option<T>> for Value {
     }
 }
 
-use enumflags2::{BitFlag, BitFlags, _internal::RawBitFlags};
+use enumflags2::{_internal::RawBitFlags, BitFlag, BitFlags};
 impl<T> FromValue for BitFlags<T>
 where
     T: BitFlag,
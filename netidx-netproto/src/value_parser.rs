@@ -1,21 +1,25 @@
-use crate::value::Value;
+use crate::value::{ErrorInfo, Value};
 use base64::{Engine, engine::general_purpose::STANDARD as BASE64};
 use bytes::Bytes;
 use combine::{
-    attempt, between, choice, from_str, many1, none_of, not_followed_by, one_of,
-    optional,
+    attempt, between, choice,
+    error::StreamError,
+    from_str, none_of, not_followed_by, one_of, optional,
     parser::{
-        char::{digit, spaces, string},
+        char::{spaces, string},
         combinator::recognize,
         range::{take_while, take_while1},
         repeat::escaped,
     },
-    sep_by,
-    stream::{position, Range},
+    satisfy, sep_by,
+    stream::{position, Range, StreamErrorFor},
     token, EasyParser, ParseError, Parser, RangeStream,
 };
 use netidx_core::{chars::Chars, utils};
-use std::{borrow::Cow, result::Result, str::FromStr, sync::Arc, time::Duration};
+use std::{
+    borrow::Cow, num::ParseIntError, result::Result, str::FromStr, sync::Arc,
+    time::Duration,
+};
 
 pub static VAL_ESC: [char; 2] = ['\\', '"'];
 
@@ -45,13 +49,58 @@ where
     spaces().with(between(token('"'), token('"'), escaped_string(esc)))
 }
 
-fn uint<I>() -> impl Parser<I, Output = String>
+trait FromRadix: Sized {
+    fn from_radix(s: &str, radix: u32) -> Result<Self, ParseIntError>;
+}
+
+macro_rules! from_radix {
+    ($($t:ty),*) => {
+        $(impl FromRadix for $t {
+            fn from_radix(s: &str, radix: u32) -> Result<Self, ParseIntError> {
+                <$t>::from_str_radix(s, radix)
+            }
+        })*
+    }
+}
+
+from_radix!(u32, i32, u64, i64);
+
+fn digits<I>(radix: u32) -> impl Parser<I, Output = String>
+where
+    I: RangeStream<Token = char>,
+    I::Error: ParseError<I::Token, I::Range, I::Position>,
+    I::Range: Range,
+{
+    recognize((
+        satisfy(move |c: char| c.is_digit(radix)),
+        take_while(move |c: char| c.is_digit(radix) || c == '_'),
+    ))
+    .map(|s: String| if s.contains('_') { s.replace('_', "") } else { s })
+}
+
+/// An integer literal in decimal, hex (0x), octal (0o), or binary
+/// (0b), optionally negative, with optional `_` separators between
+/// digits.
+fn integer<I, T>() -> impl Parser<I, Output = T>
 where
     I: RangeStream<Token = char>,
     I::Error: ParseError<I::Token, I::Range, I::Position>,
     I::Range: Range,
+    T: FromRadix,
 {
-    many1(digit())
+    (
+        optional(token('-')),
+        choice((
+            attempt(string("0x").with(digits(16)).map(|d| (16, d))),
+            attempt(string("0o").with(digits(8)).map(|d| (8, d))),
+            attempt(string("0b").with(digits(2)).map(|d| (2, d))),
+            digits(10).map(|d| (10, d)),
+        )),
+    )
+        .and_then(|(neg, (radix, d)): (Option<char>, (u32, String))| {
+            let d = if neg.is_some() { format!("-{}", d) } else { d };
+            T::from_radix(&d, radix).map_err(StreamErrorFor::<I>::other)
+        })
 }
 
 fn int<I>() -> impl Parser<I, Output = String>
@@ -154,19 +203,19 @@ where
         ),
         attempt(quoted(esc)).map(|s| Value::String(Chars::from(s))),
         attempt(from_str(flt()).map(|v| Value::F64(v))),
-        attempt(from_str(int()).map(|v| Value::I64(v))),
+        attempt(integer().map(|v| Value::I64(v))),
         attempt(string("true").skip(close_expr()).map(|_| Value::True)),
         attempt(string("false").skip(close_expr()).map(|_| Value::False)),
         attempt(string("null").skip(close_expr()).map(|_| Value::Null)),
         attempt(constant("decimal").with(from_str(dcml())).map(|v| Value::Decimal(v))),
-        attempt(constant("u32").with(from_str(uint())).map(|v| Value::U32(v))),
-        attempt(constant("v32").with(from_str(uint())).map(|v| Value::V32(v))),
-        attempt(constant("i32").with(from_str(int())).map(|v| Value::I32(v))),
-        attempt(constant("z32").with(from_str(int())).map(|v| Value::Z32(v))),
-        attempt(constant("u64").with(from_str(uint())).map(|v| Value::U64(v))),
-        attempt(constant("v64").with(from_str(uint())).map(|v| Value::V64(v))),
-        attempt(constant("i64").with(from_str(int())).map(|v| Value::I64(v))),
-        attempt(constant("z64").with(from_str(int())).map(|v| Value::Z64(v))),
+        attempt(constant("u32").with(integer()).map(|v| Value::U32(v))),
+        attempt(constant("v32").with(integer()).map(|v| Value::V32(v))),
+        attempt(constant("i32").with(integer()).map(|v| Value::I32(v))),
+        attempt(constant("z32").with(integer()).map(|v| Value::Z32(v))),
+        attempt(constant("u64").with(integer()).map(|v| Value::U64(v))),
+        attempt(constant("v64").with(integer()).map(|v| Value::V64(v))),
+        attempt(constant("i64").with(integer()).map(|v| Value::I64(v))),
+        attempt(constant("z64").with(integer()).map(|v| Value::Z64(v))),
         attempt(constant("f32").with(from_str(flt())).map(|v| Value::F32(v))),
         attempt(constant("f64").with(from_str(flt())).map(|v| Value::F64(v))),
         attempt(
@@ -178,6 +227,22 @@ where
         attempt(
             constant("error").with(quoted(esc)).map(|s| Value::Error(Chars::from(s))),
         ),
+        attempt(
+            constant("error")
+                .with(between(
+                    token('['),
+                    spaces().with(token(']')),
+                    (
+                        spaces().with(integer()),
+                        spaces().with(token(',')).with(quoted(esc)),
+                        optional(spaces().with(token(',')).with(value(esc))),
+                    ),
+                ))
+                .map(|(code, message, payload): (u32, String, Option<Value>)| {
+                    let message = Chars::from(message);
+                    Value::ErrorInfo(Arc::new(ErrorInfo { code, message, payload }))
+                }),
+        ),
         attempt(
             constant("datetime").with(from_str(quoted(esc))).map(|d| Value::DateTime(d)),
         ),
@@ -218,9 +283,275 @@ pub fn parse_value(s: &str) -> anyhow::Result<Value> {
         .map_err(|e| anyhow::anyhow!(format!("{}", e)))
 }
 
+/// The default limit on the size of a single value buffered by a
+/// `ValueStreamParser`
+pub const DEFAULT_MAX_BUFFER: usize = 16 * 1024 * 1024;
+
+enum Step {
+    Done,
+    Incomplete,
+    Error(anyhow::Error),
+}
+
+// Tracks where in the buffer a value could have ended, so that
+// incomplete input isn't re-parsed every time a few more bytes
+// arrive. A value can only end at the top level, outside of any
+// array or string, on whitespace or punctuation.
+#[derive(Debug, Default)]
+struct Scan {
+    // how much of the buffer has been scanned
+    pos: usize,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+    // a value may have ended since the last parse
+    ready: bool,
+    // the buffer length at the last parse that came up incomplete
+    attempted: usize,
+}
+
+impl Scan {
+    fn scan(&mut self, buf: &[u8], delimiter: u8) {
+        for c in &buf[self.pos..] {
+            if self.in_string {
+                if self.escaped {
+                    self.escaped = false
+                } else if *c == b'\\' {
+                    self.escaped = true
+                } else if *c == b'"' {
+                    self.in_string = false;
+                    self.ready |= self.depth == 0;
+                }
+            } else {
+                match c {
+                    b'"' => {
+                        self.in_string = true;
+                        self.ready |= self.depth == 0;
+                    }
+                    b'[' => {
+                        self.ready |= self.depth == 0;
+                        self.depth += 1;
+                    }
+                    b']' => {
+                        self.depth = self.depth.saturating_sub(1);
+                        self.ready |= self.depth == 0;
+                    }
+                    b',' | b';' | b')' | b'}' => self.ready |= self.depth == 0,
+                    c if c.is_ascii_whitespace() || *c == delimiter => {
+                        self.ready |= self.depth == 0
+                    }
+                    _ => (),
+                }
+            }
+        }
+        self.pos = buf.len();
+        // don't wait forever on input we misjudged, re-parsing only
+        // when the buffer doubles keeps the total work linear
+        self.ready |= buf.len() >= self.attempted * 2;
+    }
+}
+
+/// Incrementally parse a stream of concatenated values, e.g. read
+/// from stdin or a socket. Values may be separated by any amount of
+/// whitespace, and may be split arbitrarily across calls to `feed`.
+///
+/// Because a value may be a prefix of a longer one (`1` of `10`), a
+/// value at the very end of the buffered input is not returned until
+/// more input arrives or `finish` is called.
+///
+/// When a value fails to parse the error is recorded (see
+/// `take_errors`) and the input up to and including the next
+/// delimiter is discarded, so one bad value doesn't poison the rest
+/// of the stream.
+#[derive(Debug)]
+pub struct ValueStreamParser {
+    buf: Vec<u8>,
+    max_buffer: usize,
+    delimiter: u8,
+    skipping: bool,
+    scan: Scan,
+    errors: Vec<anyhow::Error>,
+    #[cfg(test)]
+    parses: usize,
+}
+
+impl Default for ValueStreamParser {
+    fn default() -> Self {
+        ValueStreamParser {
+            buf: Vec::new(),
+            max_buffer: DEFAULT_MAX_BUFFER,
+            delimiter: b'\n',
+            skipping: false,
+            scan: Scan::default(),
+            errors: Vec::new(),
+            #[cfg(test)]
+            parses: 0,
+        }
+    }
+}
+
+impl ValueStreamParser {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the maximum number of bytes a single incomplete value may
+    /// occupy. A value that grows past this is discarded as an error.
+    pub fn max_buffer(mut self, max: usize) -> Self {
+        self.max_buffer = max;
+        self
+    }
+
+    /// Set the delimiter to skip to when recovering from an error,
+    /// by default newline.
+    pub fn delimiter(mut self, delimiter: u8) -> Self {
+        self.delimiter = delimiter;
+        self
+    }
+
+    /// The number of bytes buffered waiting for the rest of a value
+    pub fn pending(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Return the errors encountered since the last call
+    pub fn take_errors(&mut self) -> Vec<anyhow::Error> {
+        std::mem::take(&mut self.errors)
+    }
+
+    /// Add `data` to the stream, and return every value that is now
+    /// complete.
+    pub fn feed(&mut self, data: &[u8]) -> Vec<Value> {
+        self.buf.extend_from_slice(data);
+        self.drain(false)
+    }
+
+    /// Signal the end of the stream and return the remaining values.
+    /// Anything left that isn't a complete value is an error. The
+    /// parser may be reused afterwards.
+    pub fn finish(&mut self) -> Vec<Value> {
+        let vals = self.drain(true);
+        self.buf.clear();
+        self.skipping = false;
+        self.scan = Scan::default();
+        vals
+    }
+
+    // remove n bytes from the front of the buffer
+    fn consume(&mut self, n: usize) {
+        if n > 0 {
+            self.buf.drain(..n);
+            self.scan = Scan::default();
+        }
+    }
+
+    fn skip_to_delimiter(&mut self) {
+        match self.buf.iter().position(|c| *c == self.delimiter) {
+            Some(i) => {
+                self.consume(i + 1);
+                self.skipping = false;
+            }
+            None => {
+                self.consume(self.buf.len());
+                self.skipping = true;
+            }
+        }
+    }
+
+    // parse as many values from the buffer as possible, returning
+    // the number of bytes they occupied and why parsing stopped
+    fn parse(&self, eof: bool, vals: &mut Vec<Value>) -> (usize, Step) {
+        let (s, invalid) = match std::str::from_utf8(&self.buf) {
+            Ok(s) => (s, false),
+            Err(e) => {
+                // a multi byte char may be split across reads
+                let s = std::str::from_utf8(&self.buf[..e.valid_up_to()]).unwrap();
+                (s, e.error_len().is_some())
+            }
+        };
+        let complete = eof || invalid;
+        let mut pos = 0;
+        loop {
+            pos += s[pos..].bytes().take_while(|c| c.is_ascii_whitespace()).count();
+            let rest = &s[pos..];
+            if rest.is_empty() {
+                if invalid {
+                    let e = anyhow::anyhow!("invalid utf8 in value stream");
+                    break (pos, Step::Error(e));
+                }
+                break (pos, Step::Done);
+            }
+            match value(&VAL_ESC).easy_parse(position::Stream::new(rest)) {
+                Ok((v, r)) if complete || !r.input.is_empty() => {
+                    vals.push(v);
+                    pos += rest.len() - r.input.len();
+                }
+                Ok(_) => break (pos, Step::Incomplete),
+                Err(e) if complete => {
+                    break (pos, Step::Error(anyhow::anyhow!(format!("{}", e))))
+                }
+                Err(e)
+                    if e.is_unexpected_end_of_input()
+                        || !rest.as_bytes().contains(&self.delimiter) =>
+                {
+                    break (pos, Step::Incomplete)
+                }
+                Err(e) => break (pos, Step::Error(anyhow::anyhow!(format!("{}", e)))),
+            }
+        }
+    }
+
+    fn drain(&mut self, eof: bool) -> Vec<Value> {
+        let mut vals = Vec::new();
+        loop {
+            if self.skipping {
+                self.skip_to_delimiter();
+                if self.skipping {
+                    break;
+                }
+            }
+            self.scan.scan(&self.buf, self.delimiter);
+            let ready = self.scan.ready || self.buf.len() > self.max_buffer;
+            if self.buf.is_empty() || !(eof || ready) {
+                break;
+            }
+            self.scan.ready = false;
+            #[cfg(test)]
+            {
+                self.parses += 1;
+            }
+            let (consumed, step) = self.parse(eof, &mut vals);
+            self.consume(consumed);
+            match step {
+                Step::Done => break,
+                Step::Error(e) => {
+                    self.errors.push(e);
+                    self.skip_to_delimiter();
+                }
+                Step::Incomplete if self.buf.len() > self.max_buffer => {
+                    self.errors.push(anyhow::anyhow!(
+                        "value exceeds the maximum buffer size {}",
+                        self.max_buffer
+                    ));
+                    self.skip_to_delimiter();
+                }
+                Step::Incomplete => {
+                    // resume scanning where we left off
+                    self.scan.scan(&self.buf, self.delimiter);
+                    self.scan.ready = false;
+                    self.scan.attempted = self.buf.len();
+                    break;
+                }
+            }
+        }
+        vals
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::value::Radix;
 
     #[test]
     fn parse() {
@@ -268,5 +599,97 @@ mod tests {
             Value::Error(Chars::from("error")),
             parse_value(r#"error:"error""#).unwrap()
         );
+        assert_eq!(
+            Value::coded_err(ErrorInfo::PERMISSION_DENIED, "denied"),
+            parse_value(r#"error:[2, "denied"]"#).unwrap()
+        );
+        let e = ErrorInfo::with_payload(
+            42,
+            "bad",
+            Value::Array(Arc::from(vec![
+                Value::I64(1),
+                Value::String(Chars::from("two")),
+            ])),
+        );
+        let v = Value::ErrorInfo(Arc::new(e));
+        assert_eq!(v, parse_value(r#"error:[42, "bad", [1, "two"]]"#).unwrap());
+        assert_eq!(v, parse_value(&format!("{}", v)).unwrap());
+    }
+
+    #[test]
+    fn parse_radix() {
+        assert_eq!(Value::U32(0xFF), parse_value("u32:0xFF").unwrap());
+        assert_eq!(Value::U32(0xFFFF), parse_value("u32:0xFF_FF").unwrap());
+        assert_eq!(Value::U32(0o755), parse_value("u32:0o755").unwrap());
+        assert_eq!(Value::V64(0b1010), parse_value("v64:0b1010").unwrap());
+        assert_eq!(Value::I64(1_000_000), parse_value("1_000_000").unwrap());
+        assert_eq!(Value::I64(-0x10), parse_value("i64:-0x10").unwrap());
+        assert_eq!(Value::I64(-0x10), parse_value("-0x10").unwrap());
+        assert_eq!(Value::I32(i32::MIN), parse_value("i32:-0x8000_0000").unwrap());
+        assert_eq!(
+            Value::coded_err(0x10, "e"),
+            parse_value(r#"error:[0x10, "e"]"#).unwrap()
+        );
+        assert!(parse_value("u32:_1").is_err());
+        assert!(parse_value("u32:0x1_0000_0000").is_err());
+        let vs = [
+            Value::U32(0xdead),
+            Value::I32(i32::MIN),
+            Value::Z64(-42),
+            Value::U64(u64::MAX),
+            Value::Array(Arc::from(vec![Value::I64(-7), Value::from("x")])),
+        ];
+        for v in vs {
+            for r in [Radix::Dec, Radix::Hex, Radix::Oct, Radix::Bin] {
+                assert_eq!(v, parse_value(&v.to_string_radix(r)).unwrap());
+            }
+        }
+        assert_eq!("u32:0xff", format!("{:x}", Value::U32(255)));
+        assert_eq!("i64:-0o10", format!("{:o}", Value::I64(-8)));
+        assert_eq!("u32:0b101", format!("{:b}", Value::U32(5)));
+    }
+
+    #[test]
+    fn stream() {
+        let mut p = ValueStreamParser::new();
+        assert!(p.feed(b"u32:4").is_empty());
+        assert_eq!(p.feed(b"2 [1, \"a"), vec![Value::U32(42)]);
+        let a = Value::Array(Arc::from(vec![Value::I64(1), Value::from("ab")]));
+        assert_eq!(p.feed(b"b\"]\n\"caf\xc3"), vec![a]);
+        assert_eq!(p.feed(b"\xa9\" 1 2"), vec![Value::from("caf\u{e9}"), Value::I64(1)]);
+        assert_eq!(p.pending(), 1);
+        assert_eq!(p.finish(), vec![Value::I64(2)]);
+        assert!(p.take_errors().is_empty());
+        assert_eq!(p.feed(b"1 bogus 2\n3\n"), vec![Value::I64(1), Value::I64(3)]);
+        assert_eq!(p.take_errors().len(), 1);
+        let mut p = ValueStreamParser::new().max_buffer(8);
+        assert!(p.feed(b"\"aaaaaaaaaaaa").is_empty());
+        assert_eq!(p.take_errors().len(), 1);
+        assert_eq!(p.feed(b"aaa\"\n5\n"), vec![Value::I64(5)]);
+        assert!(p.feed(b"[1, 2").is_empty());
+        assert!(p.finish().is_empty());
+        assert_eq!(p.take_errors().len(), 1);
+    }
+
+    #[test]
+    fn stream_large_value() {
+        let n = 100_000;
+        let mut data = String::from("[");
+        for i in 0..n {
+            data.push_str(&format!("{}, \"s{}\", ", i, i));
+        }
+        data.push_str("0]\n");
+        let mut p = ValueStreamParser::new();
+        let mut vals = vec![];
+        for chunk in data.as_bytes().chunks(64) {
+            vals.extend(p.feed(chunk));
+        }
+        assert_eq!(vals.len(), 1);
+        match &vals[0] {
+            Value::Array(a) => assert_eq!(a.len(), n * 2 + 1),
+            v => panic!("expected an array, got {}", v),
+        }
+        // incomplete input is not re-parsed on every feed
+        assert!(p.parses < 32, "{} parses", p.parses);
     }
 }
@@ -0,0 +1,665 @@
+//! A serde bridge for `Value`. A `Value` is a serde `Deserializer`,
+//! so any type implementing `Deserialize` can be built from one, and
+//! `Serializer` turns any type implementing `Serialize` into a
+//! `Value`. See `Value::cast_to_serde` and `Value::from_serde`.
+//!
+//! Structured types are represented the same way the `FromValue`
+//! impls represent them,
+//!
+//! - sequences, tuples, and tuple structs are arrays
+//! - maps and structs are arrays of `[key, value]` pairs
+//! - unit variants are the name of the variant, all other variants
+//!   are a pair of the name of the variant and it's payload
+//! - `None`, `()`, and unit structs are `null`, `Some(v)` is `v`
+use crate::value::Value;
+use bytes::Bytes;
+use netidx_core::chars::Chars;
+use serde::{
+    de::{self, DeserializeSeed, Visitor},
+    forward_to_deserialize_any,
+    ser::{self, Serialize},
+};
+use std::{fmt, result, sync::Arc};
+
+#[derive(Debug, Clone)]
+pub struct Error(String);
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl ser::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+impl de::Error for Error {
+    fn custom<T: fmt::Display>(msg: T) -> Self {
+        Error(msg.to_string())
+    }
+}
+
+type Result<T> = result::Result<T, Error>;
+
+/// Serialize any type implementing `Serialize` into a `Value`
+#[derive(Debug, Clone, Copy)]
+pub struct Serializer;
+
+impl ser::Serializer for Serializer {
+    type Ok = Value;
+    type Error = Error;
+    type SerializeSeq = SerializeArray;
+    type SerializeTuple = SerializeArray;
+    type SerializeTupleStruct = SerializeArray;
+    type SerializeTupleVariant = SerializeVariant<SerializeArray>;
+    type SerializeMap = SerializeMap;
+    type SerializeStruct = SerializeMap;
+    type SerializeStructVariant = SerializeVariant<SerializeMap>;
+
+    fn serialize_bool(self, v: bool) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_i8(self, v: i8) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_i16(self, v: i16) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_i32(self, v: i32) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_i64(self, v: i64) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_u8(self, v: u8) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_u16(self, v: u16) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_u32(self, v: u32) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_u64(self, v: u64) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_f32(self, v: f32) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_f64(self, v: f64) -> Result<Value> {
+        Ok(v.into())
+    }
+
+    fn serialize_char(self, v: char) -> Result<Value> {
+        Ok(Value::String(Chars::from(v.to_string())))
+    }
+
+    fn serialize_str(self, v: &str) -> Result<Value> {
+        Ok(Value::String(Chars::from(String::from(v))))
+    }
+
+    fn serialize_bytes(self, v: &[u8]) -> Result<Value> {
+        Ok(Value::Bytes(Bytes::copy_from_slice(v)))
+    }
+
+    fn serialize_none(self) -> Result<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_some<T: ?Sized + Serialize>(self, value: &T) -> Result<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_unit(self) -> Result<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_struct(self, _name: &'static str) -> Result<Value> {
+        Ok(Value::Null)
+    }
+
+    fn serialize_unit_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+    ) -> Result<Value> {
+        Ok(Value::from(variant))
+    }
+
+    fn serialize_newtype_struct<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        value: &T,
+    ) -> Result<Value> {
+        value.serialize(self)
+    }
+
+    fn serialize_newtype_variant<T: ?Sized + Serialize>(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        value: &T,
+    ) -> Result<Value> {
+        Ok(Value::from((variant, value.serialize(self)?)))
+    }
+
+    fn serialize_seq(self, len: Option<usize>) -> Result<SerializeArray> {
+        Ok(SerializeArray(Vec::with_capacity(len.unwrap_or(0))))
+    }
+
+    fn serialize_tuple(self, len: usize) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_struct(
+        self,
+        _name: &'static str,
+        len: usize,
+    ) -> Result<SerializeArray> {
+        self.serialize_seq(Some(len))
+    }
+
+    fn serialize_tuple_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeArray>> {
+        let inner = self.serialize_seq(Some(len))?;
+        Ok(SerializeVariant { variant, inner })
+    }
+
+    fn serialize_map(self, len: Option<usize>) -> Result<SerializeMap> {
+        let elts = Vec::with_capacity(len.unwrap_or(0));
+        Ok(SerializeMap { elts, key: None })
+    }
+
+    fn serialize_struct(self, _name: &'static str, len: usize) -> Result<SerializeMap> {
+        self.serialize_map(Some(len))
+    }
+
+    fn serialize_struct_variant(
+        self,
+        _name: &'static str,
+        _index: u32,
+        variant: &'static str,
+        len: usize,
+    ) -> Result<SerializeVariant<SerializeMap>> {
+        let inner = self.serialize_map(Some(len))?;
+        Ok(SerializeVariant { variant, inner })
+    }
+}
+
+pub struct SerializeArray(Vec<Value>);
+
+impl ser::SerializeSeq for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        self.0.push(value.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::Array(Arc::from(self.0)))
+    }
+}
+
+impl ser::SerializeTuple for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_element<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+impl ser::SerializeTupleStruct for SerializeArray {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(self, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        ser::SerializeSeq::end(self)
+    }
+}
+
+pub struct SerializeMap {
+    elts: Vec<Value>,
+    key: Option<Value>,
+}
+
+impl ser::SerializeMap for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_key<T: ?Sized + Serialize>(&mut self, key: &T) -> Result<()> {
+        self.key = Some(key.serialize(Serializer)?);
+        Ok(())
+    }
+
+    fn serialize_value<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        match self.key.take() {
+            None => {
+                Err(ser::Error::custom("serialize_value called before serialize_key"))
+            }
+            Some(key) => {
+                self.elts.push(Value::from((key, value.serialize(Serializer)?)));
+                Ok(())
+            }
+        }
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::Array(Arc::from(self.elts)))
+    }
+}
+
+impl ser::SerializeStruct for SerializeMap {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        self.elts.push(Value::from((key, value.serialize(Serializer)?)));
+        Ok(())
+    }
+
+    fn end(self) -> Result<Value> {
+        ser::SerializeMap::end(self)
+    }
+}
+
+pub struct SerializeVariant<T> {
+    variant: &'static str,
+    inner: T,
+}
+
+impl ser::SerializeTupleVariant for SerializeVariant<SerializeArray> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(&mut self, value: &T) -> Result<()> {
+        ser::SerializeSeq::serialize_element(&mut self.inner, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::from((self.variant, ser::SerializeSeq::end(self.inner)?)))
+    }
+}
+
+impl ser::SerializeStructVariant for SerializeVariant<SerializeMap> {
+    type Ok = Value;
+    type Error = Error;
+
+    fn serialize_field<T: ?Sized + Serialize>(
+        &mut self,
+        key: &'static str,
+        value: &T,
+    ) -> Result<()> {
+        ser::SerializeStruct::serialize_field(&mut self.inner, key, value)
+    }
+
+    fn end(self) -> Result<Value> {
+        Ok(Value::from((self.variant, ser::SerializeMap::end(self.inner)?)))
+    }
+}
+
+fn visit_array<'de, V: Visitor<'de>>(elts: Arc<[Value]>, visitor: V) -> Result<V::Value> {
+    let len = elts.len();
+    let mut seq = SeqAccess { elts, pos: 0 };
+    let v = visitor.visit_seq(&mut seq)?;
+    if seq.pos < len {
+        Err(de::Error::invalid_length(len, &"fewer elements in the array"))
+    } else {
+        Ok(v)
+    }
+}
+
+fn visit_pairs<'de, V: Visitor<'de>>(elts: Arc<[Value]>, visitor: V) -> Result<V::Value> {
+    let len = elts.len();
+    let mut map = MapAccess { elts, pos: 0, value: None };
+    let v = visitor.visit_map(&mut map)?;
+    if map.pos < len {
+        Err(de::Error::invalid_length(len, &"fewer elements in the map"))
+    } else {
+        Ok(v)
+    }
+}
+
+impl<'de> de::Deserializer<'de> for Value {
+    type Error = Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::U32(v) | Value::V32(v) => visitor.visit_u32(v),
+            Value::I32(v) | Value::Z32(v) => visitor.visit_i32(v),
+            Value::U64(v) | Value::V64(v) => visitor.visit_u64(v),
+            Value::I64(v) | Value::Z64(v) => visitor.visit_i64(v),
+            Value::F32(v) => visitor.visit_f32(v),
+            Value::F64(v) => visitor.visit_f64(v),
+            Value::DateTime(v) => visitor.visit_string(v.to_rfc3339()),
+            Value::Duration(v) => visitor.visit_f64(v.as_secs_f64()),
+            Value::Decimal(v) => visitor.visit_string(v.to_string()),
+            Value::String(v) => visitor.visit_str(&v),
+            Value::Bytes(v) => visitor.visit_bytes(&v),
+            Value::True => visitor.visit_bool(true),
+            Value::False => visitor.visit_bool(false),
+            Value::Null | Value::Ok => visitor.visit_unit(),
+            Value::Error(e) => Err(de::Error::custom(e)),
+            Value::ErrorInfo(e) => Err(de::Error::custom(e)),
+            Value::Array(elts) => visit_array(elts, visitor),
+        }
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::Null => visitor.visit_none(),
+            v => visitor.visit_some(v),
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    // so that Vec<u8> can be built from bytes
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::Bytes(b) => {
+                visitor.visit_seq(de::value::SeqDeserializer::new(b.iter().copied()))
+            }
+            v => v.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_map<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value> {
+        match self {
+            Value::Array(elts) => visit_pairs(elts, visitor),
+            v => v.deserialize_any(visitor),
+        }
+    }
+
+    fn deserialize_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self {
+            Value::Duration(d) => {
+                let fields = [("secs", d.as_secs()), ("nanos", d.subsec_nanos() as u64)];
+                visitor.visit_map(de::value::MapDeserializer::new(fields.into_iter()))
+            }
+            v => v.deserialize_map(visitor),
+        }
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self {
+            Value::String(_) => visitor.visit_enum(EnumAccess(self, None)),
+            Value::Array(elts) if elts.len() == 2 => {
+                visitor.visit_enum(EnumAccess(elts[0].clone(), Some(elts[1].clone())))
+            }
+            v => Err(de::Error::invalid_type(unexpected(&v), &"an enum")),
+        }
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf unit unit_struct tuple tuple_struct identifier ignored_any
+    }
+}
+
+fn unexpected(v: &Value) -> de::Unexpected<'_> {
+    match v {
+        Value::True => de::Unexpected::Bool(true),
+        Value::False => de::Unexpected::Bool(false),
+        Value::String(s) => de::Unexpected::Str(s),
+        Value::Bytes(b) => de::Unexpected::Bytes(b),
+        Value::Null | Value::Ok => de::Unexpected::Unit,
+        Value::Array(_) => de::Unexpected::Seq,
+        _ => de::Unexpected::Other("a value"),
+    }
+}
+
+struct SeqAccess {
+    elts: Arc<[Value]>,
+    pos: usize,
+}
+
+impl<'de> de::SeqAccess<'de> for SeqAccess {
+    type Error = Error;
+
+    fn next_element_seed<T: DeserializeSeed<'de>>(
+        &mut self,
+        seed: T,
+    ) -> Result<Option<T::Value>> {
+        if self.pos < self.elts.len() {
+            let v = self.elts[self.pos].clone();
+            self.pos += 1;
+            seed.deserialize(v).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elts.len() - self.pos)
+    }
+}
+
+struct MapAccess {
+    elts: Arc<[Value]>,
+    pos: usize,
+    value: Option<Value>,
+}
+
+impl<'de> de::MapAccess<'de> for MapAccess {
+    type Error = Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>> {
+        if self.pos >= self.elts.len() {
+            return Ok(None);
+        }
+        match &self.elts[self.pos] {
+            Value::Array(pair) if pair.len() == 2 => {
+                self.pos += 1;
+                self.value = Some(pair[1].clone());
+                seed.deserialize(pair[0].clone()).map(Some)
+            }
+            v => Err(de::Error::invalid_type(unexpected(v), &"a [key, value] pair")),
+        }
+    }
+
+    fn next_value_seed<V: DeserializeSeed<'de>>(&mut self, seed: V) -> Result<V::Value> {
+        match self.value.take() {
+            Some(v) => seed.deserialize(v),
+            None => Err(de::Error::custom("next_value called before next_key")),
+        }
+    }
+
+    fn size_hint(&self) -> Option<usize> {
+        Some(self.elts.len() - self.pos)
+    }
+}
+
+struct EnumAccess(Value, Option<Value>);
+
+impl<'de> de::EnumAccess<'de> for EnumAccess {
+    type Error = Error;
+    type Variant = VariantAccess;
+
+    fn variant_seed<V: DeserializeSeed<'de>>(
+        self,
+        seed: V,
+    ) -> Result<(V::Value, VariantAccess)> {
+        let EnumAccess(variant, payload) = self;
+        Ok((seed.deserialize(variant)?, VariantAccess(payload)))
+    }
+}
+
+struct VariantAccess(Option<Value>);
+
+impl<'de> de::VariantAccess<'de> for VariantAccess {
+    type Error = Error;
+
+    fn unit_variant(self) -> Result<()> {
+        match self.0 {
+            None | Some(Value::Null) => Ok(()),
+            Some(v) => Err(de::Error::invalid_type(unexpected(&v), &"a unit variant")),
+        }
+    }
+
+    fn newtype_variant_seed<T: DeserializeSeed<'de>>(self, seed: T) -> Result<T::Value> {
+        match self.0 {
+            Some(v) => seed.deserialize(v),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"a newtype variant",
+            )),
+        }
+    }
+
+    fn tuple_variant<V: Visitor<'de>>(self, _len: usize, visitor: V) -> Result<V::Value> {
+        match self.0 {
+            Some(Value::Array(elts)) => visit_array(elts, visitor),
+            Some(v) => Err(de::Error::invalid_type(unexpected(&v), &"a tuple variant")),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"a tuple variant",
+            )),
+        }
+    }
+
+    fn struct_variant<V: Visitor<'de>>(
+        self,
+        _fields: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value> {
+        match self.0 {
+            Some(Value::Array(elts)) => visit_pairs(elts, visitor),
+            Some(v) => Err(de::Error::invalid_type(unexpected(&v), &"a struct variant")),
+            None => Err(de::Error::invalid_type(
+                de::Unexpected::UnitVariant,
+                &"a struct variant",
+            )),
+        }
+    }
+}
+
+/// serde `with` module for `Bytes` fields that matches the text
+/// format. In human readable formats (e.g. json) the bytes are a
+/// standard base64 string, the same as the body of a `bytes:`
+/// literal, otherwise they are serialized natively. A base64 string,
+/// a byte buffer, or an array of bytes is accepted when
+/// deserializing, so data written before the text encoding was
+/// adopted can still be read. Owned buffers are taken without
+/// copying.
+///
+/// ```ignore
+/// #[derive(Serialize, Deserialize)]
+/// struct Blob {
+///     #[serde(with = "netidx_netproto::value_serde::base64_bytes")]
+///     data: Bytes,
+/// }
+/// ```
+pub mod base64_bytes {
+    use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+    use bytes::Bytes;
+    use serde::{
+        de::{self, SeqAccess, Visitor},
+        Deserializer, Serializer,
+    };
+    use std::fmt;
+
+    pub fn serialize<S: Serializer>(b: &Bytes, serializer: S) -> Result<S::Ok, S::Error> {
+        if serializer.is_human_readable() {
+            serializer.serialize_str(&BASE64.encode(&**b))
+        } else {
+            serializer.serialize_bytes(b)
+        }
+    }
+
+    struct BytesVisitor;
+
+    impl<'de> Visitor<'de> for BytesVisitor {
+        type Value = Bytes;
+
+        fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "base64 encoded bytes")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Bytes, E> {
+            match BASE64.decode(v) {
+                Ok(b) => Ok(Bytes::from(b)),
+                Err(_) => Err(E::invalid_value(de::Unexpected::Str(v), &self)),
+            }
+        }
+
+        fn visit_bytes<E: de::Error>(self, v: &[u8]) -> Result<Bytes, E> {
+            Ok(Bytes::copy_from_slice(v))
+        }
+
+        fn visit_byte_buf<E: de::Error>(self, v: Vec<u8>) -> Result<Bytes, E> {
+            Ok(Bytes::from(v))
+        }
+
+        fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<Bytes, A::Error> {
+            let mut v = Vec::with_capacity(seq.size_hint().unwrap_or(0));
+            while let Some(b) = seq.next_element::<u8>()? {
+                v.push(b)
+            }
+            Ok(Bytes::from(v))
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Bytes, D::Error> {
+        if deserializer.is_human_readable() {
+            deserializer.deserialize_any(BytesVisitor)
+        } else {
+            deserializer.deserialize_byte_buf(BytesVisitor)
+        }
+    }
+}
@@ -36,7 +36,7 @@ impl Receiver {
                 for (_, ev) in batch.drain(..) {
                     match ev {
                         Event::Update(v) => self.queued.push_back(v),
-                        Event::Unsubscribed => dead.store(true, Ordering::Relaxed),
+                        Event::Unsubscribed(_) => dead.store(true, Ordering::Relaxed),
                     }
                 }
             }
@@ -133,7 +133,7 @@ impl Connection {
         let acceptor = subscriber.subscribe(path.clone());
         time::timeout(to, acceptor.wait_subscribed()).await??;
         match acceptor.last() {
-            Event::Unsubscribed => bail!("connect failed"),
+            Event::Unsubscribed(_) => bail!("connect failed"),
             Event::Update(Value::String(s)) if &*s == "connection" => {
                 Self::connect_singleton(subscriber, path).await
             }
@@ -12,6 +12,7 @@ use tokio::{runtime::Runtime, sync::oneshot, task, time};
 
 pub(crate) struct Ctx {
     pub(crate) _server: Server,
+    pub(crate) cfg: ClientConfig,
     pub(crate) publisher: Publisher,
     pub(crate) subscriber: Subscriber,
     pub(crate) base: Path,
@@ -34,9 +35,9 @@ impl Ctx {
         )
         .await
         .unwrap();
-        let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+        let subscriber = Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
         let base = Path::from("/channel");
-        Self { _server, publisher, subscriber, base }
+        Self { _server, cfg, publisher, subscriber, base }
     }
 }
 
@@ -98,7 +98,11 @@ impl<T: Serialize + DeserializeOwned + 'static> Cluster<T> {
 
     fn subscribed_others(&self) -> usize {
         self.others.len()
-            - self.others.values().filter(|d| d.last() == Event::Unsubscribed).count()
+            - self
+                .others
+                .values()
+                .filter(|d| matches!(d.last(), Event::Unsubscribed(_)))
+                .count()
     }
 
     pub fn others(&self) -> usize {
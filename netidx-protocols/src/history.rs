@@ -0,0 +1,435 @@
+//! A bounded history of recent updates kept by the publisher.
+//!
+//! Values published through a `History` remember their last
+//! `max_len` updates, along with the time each one was made, and the
+//! history is served on demand by an rpc at `base/.history`. This
+//! gives lightweight deployments access to recent history without
+//! running a recorder. The rpc takes,
+//!
+//! * `path`: the path of the value
+//! * `last`: return at most this many of the most recent updates, or
+//! all of them if null
+//! * `since`: only return updates made at or after this time, or
+//! every update if null
+//!
+//! and replies with an array of `[timestamp, value]` pairs, oldest
+//! first.
+//!
+//! The history can be kept in memory, or in netidx archive files in a
+//! temporary directory, which is better for big values or long
+//! histories. Archive files can't be truncated, so on disk the
+//! history is kept in two segments, each with room for `max_len`
+//! updates of every value. When the current segment is full the older
+//! one is deleted and a new one is started. A value that updates much
+//! less often than the others may keep fewer than `max_len` updates,
+//! and no more than `max_len` are ever returned.
+use crate::{
+    define_rpc,
+    rpc::server::{ArgSpec, Proc, RpcCall},
+    rpc_err,
+};
+use anyhow::Result;
+use arcstr::ArcStr;
+use chrono::prelude::*;
+use fxhash::{FxHashMap, FxHashSet};
+use log::warn;
+use netidx::{
+    path::Path,
+    publisher::{ErrorInfo, Publisher, UpdateBatch, Val, Value},
+    subscriber::Event,
+};
+use netidx_archive::{
+    ArchiveReader, ArchiveWriter, BatchItem, Cursor, MonotonicTimestamper, BATCH_POOL,
+};
+use parking_lot::Mutex;
+use std::{
+    collections::VecDeque,
+    fs,
+    ops::Bound,
+    path::PathBuf,
+    process,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+// the number of batches read from an archive at a time
+const READ_CHUNK: usize = 1024;
+
+/// Where the history is kept
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Storage {
+    /// keep the history in memory
+    Memory,
+    /// keep the history in archive files in a new directory under
+    /// the specified directory, or under the system temporary
+    /// directory if `None`. The directory is removed when the
+    /// `History` is dropped.
+    TempFile(Option<PathBuf>),
+}
+
+struct Segment {
+    dir: PathBuf,
+    writer: ArchiveWriter,
+    reader: ArchiveReader,
+    timestamper: MonotonicTimestamper,
+    len: usize,
+}
+
+impl Segment {
+    fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        let writer = ArchiveWriter::open(dir.join("history"))?;
+        let reader = writer.reader()?;
+        Ok(Segment {
+            dir,
+            writer,
+            reader,
+            timestamper: MonotonicTimestamper::new(),
+            len: 0,
+        })
+    }
+
+    fn add(&mut self, path: &Path, v: Value) -> Result<()> {
+        let id = match self.writer.id_for_path(path) {
+            Some(id) => id,
+            None => {
+                self.writer.add_paths([path])?;
+                self.writer
+                    .id_for_path(path)
+                    .ok_or_else(|| anyhow!("missing id for {}", path))?
+            }
+        };
+        let ts = self.timestamper.timestamp();
+        let mut batch = BATCH_POOL.take();
+        batch.push(BatchItem(id, Event::Update(v)));
+        self.writer.add_batch(false, ts, &batch)?;
+        self.len += 1;
+        Ok(())
+    }
+
+    fn read(
+        &self,
+        path: &Path,
+        since: Option<DateTime<Utc>>,
+        res: &mut Vec<(DateTime<Utc>, Value)>,
+    ) -> Result<()> {
+        let id = match self.writer.id_for_path(path) {
+            None => return Ok(()),
+            Some(id) => id,
+        };
+        let mut cursor = Cursor::new();
+        if let Some(since) = since {
+            cursor.set_start(Bound::Included(since));
+        }
+        loop {
+            let mut batches = self.reader.read_deltas(&mut cursor, READ_CHUNK)?;
+            if batches.is_empty() {
+                break Ok(());
+            }
+            for (ts, batch) in batches.drain(..) {
+                for BatchItem(bid, ev) in batch.iter() {
+                    match ev {
+                        Event::Update(v) if *bid == id => res.push((ts, v.clone())),
+                        Event::Update(_) | Event::Unsubscribed(_) => (),
+                    }
+                }
+            }
+        }
+    }
+
+    fn remove(self) {
+        let dir = self.dir.clone();
+        // close the archive before removing it's files
+        drop(self);
+        if let Err(e) = fs::remove_dir_all(&dir) {
+            warn!("failed to remove history segment {:?} {}", dir, e)
+        }
+    }
+}
+
+struct Segments {
+    dir: PathBuf,
+    next: u64,
+    current: Option<Segment>,
+    previous: Option<Segment>,
+}
+
+impl Drop for Segments {
+    fn drop(&mut self) {
+        for s in [self.current.take(), self.previous.take()].into_iter().flatten() {
+            s.remove()
+        }
+        let _ = fs::remove_dir_all(&self.dir);
+    }
+}
+
+impl Segments {
+    fn new(dir: Option<PathBuf>) -> Result<Self> {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let name = format!(
+            "netidx-history-{}-{}",
+            process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        );
+        let dir = dir.unwrap_or_else(std::env::temp_dir).join(name);
+        let current = Some(Segment::new(dir.join("0"))?);
+        Ok(Segments { dir, next: 1, current, previous: None })
+    }
+
+    fn add(&mut self, max: usize, path: &Path, v: Value) -> Result<()> {
+        let full = self.current.as_ref().map(|s| s.len >= max).unwrap_or(true);
+        if full {
+            let segment = Segment::new(self.dir.join(self.next.to_string()))?;
+            self.next += 1;
+            if let Some(s) = self.previous.take() {
+                s.remove()
+            }
+            self.previous = self.current.replace(segment);
+        }
+        match &mut self.current {
+            None => bail!("no current segment"),
+            Some(s) => s.add(path, v),
+        }
+    }
+
+    fn read(
+        &self,
+        path: &Path,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<(DateTime<Utc>, Value)>> {
+        let mut res = Vec::new();
+        for s in [&self.previous, &self.current].into_iter().flatten() {
+            s.read(path, since, &mut res)?
+        }
+        Ok(res)
+    }
+}
+
+enum Store {
+    Memory(FxHashMap<Path, VecDeque<(DateTime<Utc>, Value)>>),
+    File(Segments),
+}
+
+struct Inner {
+    max_len: usize,
+    live: FxHashSet<Path>,
+    store: Store,
+}
+
+impl Inner {
+    fn add(&mut self, path: &Path, v: Value) -> Result<()> {
+        match &mut self.store {
+            Store::Memory(by_path) => {
+                let h = by_path.entry(path.clone()).or_insert_with(VecDeque::new);
+                while h.len() >= self.max_len {
+                    h.pop_front();
+                }
+                h.push_back((Utc::now(), v));
+                Ok(())
+            }
+            Store::File(segments) => {
+                // a segment holds max_len updates of every live value
+                let max = self.max_len.saturating_mul(self.live.len().max(1));
+                segments.add(max, path, v)
+            }
+        }
+    }
+
+    fn read(
+        &self,
+        path: &Path,
+        last: Option<u64>,
+        since: Option<DateTime<Utc>>,
+    ) -> Result<Vec<Value>> {
+        if !self.live.contains(path) {
+            bail!("no history for {}", path)
+        }
+        let mut res = match &self.store {
+            Store::File(segments) => segments.read(path, since)?,
+            Store::Memory(by_path) => by_path
+                .get(path)
+                .into_iter()
+                .flatten()
+                .filter(|(ts, _)| since.map(|since| *ts >= since).unwrap_or(true))
+                .cloned()
+                .collect(),
+        };
+        let n = last.map(|n| n as usize).unwrap_or(usize::MAX).min(self.max_len);
+        if res.len() > n {
+            res.drain(..res.len() - n);
+        }
+        Ok(res.into_iter().map(|(ts, v)| Value::from((Value::DateTime(ts), v))).collect())
+    }
+}
+
+/// Publish values that keep a history of their recent updates. The
+/// `.history` rpc is published until the `History` is dropped.
+#[derive(Clone)]
+pub struct History {
+    publisher: Publisher,
+    inner: Arc<Mutex<Inner>>,
+    _rpc: Arc<Proc>,
+}
+
+impl History {
+    /// Keep the last `max_len` updates of each value published with
+    /// this history in `storage`, and publish the `.history` rpc
+    /// under `base`.
+    pub async fn new(
+        publisher: &Publisher,
+        base: Path,
+        max_len: usize,
+        storage: Storage,
+    ) -> Result<History> {
+        if max_len == 0 {
+            bail!("max_len must be at least 1")
+        }
+        let store = match storage {
+            Storage::Memory => Store::Memory(FxHashMap::default()),
+            Storage::TempFile(dir) => Store::File(Segments::new(dir)?),
+        };
+        let inner =
+            Arc::new(Mutex::new(Inner { max_len, live: FxHashSet::default(), store }));
+        let rpc = {
+            let inner = inner.clone();
+            let map = move |mut c: RpcCall,
+                            path: Path,
+                            last: Option<u64>,
+                            since: Option<DateTime<Utc>>|
+                  -> Option<()> {
+                match inner.lock().read(&path, last, since) {
+                    Ok(entries) => c.reply.send(Value::from(entries)),
+                    Err(e) => rpc_err!(c.reply, ErrorInfo::FAILED, e.to_string()),
+                }
+                None
+            };
+            define_rpc!(
+                publisher,
+                base.append(".history"),
+                "get the recent history of a value",
+                map,
+                None,
+                path: Path; "the path of the value",
+                last: Option<u64>; "return at most this many of the most recent updates",
+                since: Option<DateTime<Utc>>; "only return updates made at or after this time"
+            )?
+        };
+        publisher.flushed().await;
+        Ok(History { publisher: publisher.clone(), inner, _rpc: Arc::new(rpc) })
+    }
+
+    /// Publish `init` at `path`, and start keeping it's history. The
+    /// initial value is the first entry in the history.
+    pub fn publish(&self, path: Path, init: Value) -> Result<HistVal> {
+        let val = self.publisher.publish(path.clone(), init.clone())?;
+        let mut inner = self.inner.lock();
+        inner.live.insert(path.clone());
+        if let Err(e) = inner.add(&path, init) {
+            warn!("failed to record history of {} {}", path, e)
+        }
+        Ok(HistVal { val, path, inner: self.inner.clone() })
+    }
+}
+
+/// A published value with a history. When it is dropped the value is
+/// unpublished and it's history is discarded.
+pub struct HistVal {
+    val: Val,
+    path: Path,
+    inner: Arc<Mutex<Inner>>,
+}
+
+impl Drop for HistVal {
+    fn drop(&mut self) {
+        let mut inner = self.inner.lock();
+        inner.live.remove(&self.path);
+        if let Store::Memory(by_path) = &mut inner.store {
+            by_path.remove(&self.path);
+        }
+    }
+}
+
+impl HistVal {
+    /// Queue an update of the value in `batch`, and add it to the
+    /// history.
+    pub fn update<T: Into<Value>>(&self, batch: &mut UpdateBatch, v: T) {
+        let v = v.into();
+        if let Err(e) = self.inner.lock().add(&self.path, v.clone()) {
+            warn!("failed to record history of {} {}", self.path, e)
+        }
+        self.val.update(batch, v)
+    }
+
+    /// The underlying published value
+    pub fn val(&self) -> &Val {
+        &self.val
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{channel::test::Ctx, rpc::client};
+    use std::time::Duration;
+    use tokio::{runtime::Runtime, time};
+
+    async fn check(storage: Storage) {
+        let ctx = Ctx::new().await;
+        let base = Path::from("/history");
+        let history =
+            History::new(&ctx.publisher, base.clone(), 10, storage).await.unwrap();
+        let a = history.publish(base.append("a"), Value::U64(0)).unwrap();
+        let b = history.publish(base.append("b"), Value::U64(100)).unwrap();
+        let mut since = None;
+        for i in 1..25u64 {
+            let mut batch = ctx.publisher.start_batch();
+            a.update(&mut batch, i);
+            b.update(&mut batch, i + 100);
+            batch.commit(None).await;
+            if i == 19 {
+                since = Some(Utc::now());
+            }
+            time::sleep(Duration::from_millis(1)).await;
+        }
+        let proc =
+            client::Proc::new(&ctx.subscriber, base.append(".history")).await.unwrap();
+        let values = |r: Value| -> Vec<u64> {
+            r.cast_to::<Vec<(DateTime<Utc>, u64)>>()
+                .unwrap()
+                .into_iter()
+                .map(|(_, v)| v)
+                .collect()
+        };
+        let r = proc.call([("path", Value::from("/history/a"))]).await.unwrap();
+        assert_eq!(values(r), (15..25).collect::<Vec<_>>());
+        let args = [("path", Value::from("/history/b")), ("last", Value::U64(3))];
+        let r = proc.call(args).await.unwrap();
+        assert_eq!(values(r), vec![121, 122, 123]);
+        let args = [
+            ("path", Value::from("/history/a")),
+            ("since", Value::DateTime(since.unwrap())),
+        ];
+        let r = proc.call(args).await.unwrap();
+        assert_eq!(values(r), (20..25).collect::<Vec<_>>());
+        drop(a);
+        let r = proc.call([("path", Value::from("/history/a"))]).await.unwrap();
+        assert!(matches!(r, Value::ErrorInfo(_)));
+        drop(b);
+    }
+
+    #[test]
+    fn memory() {
+        Runtime::new().unwrap().block_on(check(Storage::Memory))
+    }
+
+    #[test]
+    fn temp_file() {
+        Runtime::new().unwrap().block_on(check(Storage::TempFile(None)))
+    }
+}
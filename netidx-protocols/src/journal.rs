@@ -0,0 +1,385 @@
+//! A durable append only event log built on top of netidx and
+//! netidx-archive.
+//!
+//! A `Journal` stores every entry appended to it in an archive file,
+//! and assigns it a sequence number. Sequence numbers start at 1 and
+//! increase by 1 for each entry, they continue where they left off
+//! when the journal is reopened. The journal publishes two values
+//! under it's base path,
+//!
+//! * `base/head`: the most recent entry as `[seq, value]`, or null if
+//! the journal is empty.
+//! * `base/read`: an rpc taking `start` and `count` that replies with
+//! an array of up to `count` entries as `[seq, value]`, beginning
+//! with entry `start`. The reply is empty if `start` is past the
+//! head.
+//!
+//! A `JournalReader` can start from any sequence number. It reads the
+//! history with `base/read` until it has caught up with the head, and
+//! then tails `base/head`, going back to the history whenever it
+//! misses an entry. Entries are delivered in order with no gaps or
+//! duplicates, so a journal is suitable for audit logs, or commands
+//! that must be replayable.
+use crate::{
+    define_rpc,
+    rpc::{
+        client,
+        server::{ArgSpec, Proc, RpcCall},
+    },
+    rpc_err,
+    topic::Message,
+};
+use anyhow::Result;
+use arcstr::ArcStr;
+use chrono::prelude::*;
+use futures::{channel::mpsc, prelude::*};
+use log::warn;
+use netidx::{
+    path::Path,
+    publisher::{ErrorInfo, Publisher, Val, Value},
+    subscriber::{Dval, Event, Subscriber, UpdatesFlags},
+};
+use netidx_archive::{
+    ArchiveReader, ArchiveWriter, BatchItem, Cursor, Id, MonotonicTimestamper, BATCH_POOL,
+};
+use parking_lot::Mutex;
+use std::{cmp::min, ops::Bound, path::Path as FilePath, sync::Arc, time::Duration};
+use tokio::{task, time};
+
+/// The most entries one call to `base/read` will return
+pub const MAX_READ: u64 = 10_000;
+
+// the timestamp of every STRIDEth entry is kept in memory, so a read
+// never has to skip more than this many entries to find it's start
+const STRIDE: u64 = 1024;
+
+// the number of batches read from the archive at a time
+const READ_CHUNK: usize = 1024;
+
+// Timestamps of entries 1, STRIDE + 1, 2 * STRIDE + 1, ...
+type Index = Arc<Mutex<Vec<DateTime<Utc>>>>;
+
+// read at most `count` entries beginning with entry `start`
+fn read(
+    reader: &ArchiveReader,
+    index: &Index,
+    id: Id,
+    start: u64,
+    count: u64,
+) -> Result<Vec<Value>> {
+    let start = start.max(1);
+    let count = min(count, MAX_READ) as usize;
+    let mut res = Vec::new();
+    let mut cursor = Cursor::new();
+    match index.lock().get(((start - 1) / STRIDE) as usize) {
+        None => return Ok(res),
+        Some(ts) => cursor.set_start(Bound::Included(*ts)),
+    }
+    while res.len() < count {
+        let mut batches = reader.read_deltas(&mut cursor, READ_CHUNK)?;
+        if batches.is_empty() {
+            break;
+        }
+        for (_, batch) in batches.drain(..) {
+            for BatchItem(bid, ev) in batch.iter() {
+                match ev {
+                    Event::Update(v) if *bid == id && res.len() < count => {
+                        match Message::decode(v) {
+                            Some(m) if m.seq >= start => res.push(v.clone()),
+                            Some(_) | None => (),
+                        }
+                    }
+                    Event::Update(_) | Event::Unsubscribed(_) => (),
+                }
+            }
+        }
+    }
+    Ok(res)
+}
+
+/// The writer of a journal. The journal is published until it is
+/// dropped.
+pub struct Journal {
+    publisher: Publisher,
+    archive: ArchiveWriter,
+    timestamper: MonotonicTimestamper,
+    index: Index,
+    id: Id,
+    seq: u64,
+    head: Val,
+    _read: Proc,
+}
+
+impl Journal {
+    /// Open the journal stored in `file`, creating it if it doesn't
+    /// exist, and publish it at `base`.
+    pub async fn new(
+        publisher: &Publisher,
+        base: Path,
+        file: impl AsRef<FilePath>,
+    ) -> Result<Journal> {
+        let head_path = base.append("head");
+        let mut archive = ArchiveWriter::open(file)?;
+        archive.add_paths([&head_path])?;
+        archive.flush()?;
+        let id = archive
+            .id_for_path(&head_path)
+            .ok_or_else(|| anyhow!("missing id for {}", head_path))?;
+        let reader = archive.reader()?;
+        let index: Index = Arc::new(Mutex::new(Vec::new()));
+        // rebuild the index, and find the head
+        let mut last: Option<Value> = None;
+        let mut seq = 0;
+        let mut cursor = Cursor::new();
+        loop {
+            let mut batches = reader.read_deltas(&mut cursor, READ_CHUNK)?;
+            if batches.is_empty() {
+                break;
+            }
+            for (ts, batch) in batches.drain(..) {
+                for BatchItem(bid, ev) in batch.iter() {
+                    match ev {
+                        Event::Update(v) if *bid == id => match Message::decode(v) {
+                            Some(m) if m.seq == seq + 1 => {
+                                if (m.seq - 1) % STRIDE == 0 {
+                                    index.lock().push(ts);
+                                }
+                                seq = m.seq;
+                                last = Some(v.clone());
+                            }
+                            Some(_) | None => {
+                                warn!("skipping invalid journal entry {}", v)
+                            }
+                        },
+                        Event::Update(_) | Event::Unsubscribed(_) => (),
+                    }
+                }
+            }
+        }
+        let head = publisher.publish(head_path, last.unwrap_or(Value::Null))?;
+        let _read = {
+            let index = index.clone();
+            let map = move |mut c: RpcCall, start: u64, count: u64| -> Option<()> {
+                match read(&reader, &index, id, start, count) {
+                    Ok(entries) => c.reply.send(Value::from(entries)),
+                    Err(e) => {
+                        c.reply.send(Value::coded_err(ErrorInfo::FAILED, e.to_string()))
+                    }
+                }
+                None
+            };
+            define_rpc!(
+                publisher,
+                base.append("read"),
+                "read entries from the journal",
+                map,
+                None,
+                start: u64 = 1u64; "the sequence number of the first entry to read",
+                count: u64 = 1000u64; "the most entries to read"
+            )?
+        };
+        publisher.flushed().await;
+        Ok(Journal {
+            publisher: publisher.clone(),
+            archive,
+            timestamper: MonotonicTimestamper::new(),
+            index,
+            id,
+            seq,
+            head,
+            _read,
+        })
+    }
+
+    /// The sequence number of the most recent entry, 0 if the
+    /// journal is empty.
+    pub fn seq(&self) -> u64 {
+        self.seq
+    }
+
+    /// Append `value` to the journal and return it's sequence
+    /// number. The entry is flushed to disk before it is published,
+    /// so an entry a reader has seen will survive a restart of the
+    /// journal.
+    pub async fn append(&mut self, value: Value) -> Result<u64> {
+        let seq = self.seq + 1;
+        let m = Message { seq, value }.encode();
+        let ts = self.timestamper.timestamp();
+        let mut batch = BATCH_POOL.take();
+        batch.push(BatchItem(self.id, Event::Update(m.clone())));
+        self.archive.add_batch(false, ts, &batch)?;
+        self.archive.flush()?;
+        self.seq = seq;
+        if (seq - 1) % STRIDE == 0 {
+            self.index.lock().push(ts.datetime());
+        }
+        let mut batch = self.publisher.start_batch();
+        self.head.update(&mut batch, m);
+        batch.commit(None).await;
+        Ok(seq)
+    }
+}
+
+/// A reader of a journal
+#[derive(Debug, Clone)]
+pub struct JournalReader {
+    head: Dval,
+    read: client::Proc,
+}
+
+impl JournalReader {
+    /// Connect to the journal published at `base`
+    pub async fn new(subscriber: &Subscriber, base: &Path) -> Result<JournalReader> {
+        let head = subscriber.subscribe(base.append("head"));
+        let read = client::Proc::new(subscriber, base.append("read")).await?;
+        Ok(JournalReader { head, read })
+    }
+
+    // read the history from `next` until the reply is empty, or it
+    // includes entry `until`, sending each entry to `tx`. Returns
+    // false if `tx` is closed.
+    async fn catch_up(
+        &self,
+        next: &mut u64,
+        until: Option<u64>,
+        tx: &mut mpsc::Sender<Message>,
+    ) -> bool {
+        while until.map(|u| *next <= u).unwrap_or(true) {
+            if tx.is_closed() {
+                return false;
+            }
+            let args = [("start", Value::U64(*next)), ("count", Value::U64(MAX_READ))];
+            let entries = match self.read.call(args).await {
+                Ok(Value::Array(a)) => a,
+                Ok(v) => {
+                    warn!("unexpected reply from journal {}", v);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+                Err(e) => {
+                    warn!("failed to read the journal {}", e);
+                    time::sleep(Duration::from_secs(1)).await;
+                    continue;
+                }
+            };
+            let prev = *next;
+            for m in entries.iter().filter_map(Message::decode) {
+                if m.seq == *next {
+                    *next += 1;
+                    if tx.send(m).await.is_err() {
+                        return false;
+                    }
+                }
+            }
+            if *next == prev {
+                // caught up, or the journal is missing the entry
+                break;
+            }
+        }
+        true
+    }
+
+    /// Receive the entries of the journal in order, beginning with
+    /// entry `start`. Entries before the current head are read from
+    /// the history, after that new entries are delivered as they
+    /// are appended.
+    pub fn entries(&self, start: u64) -> mpsc::Receiver<Message> {
+        let (tx_up, mut rx_up) = mpsc::channel(3);
+        self.head.updates(UpdatesFlags::BEGIN_WITH_LAST, tx_up);
+        let (mut tx, rx) = mpsc::channel(100);
+        let t = self.clone();
+        task::spawn(async move {
+            let mut next = start.max(1);
+            if !t.catch_up(&mut next, None, &mut tx).await {
+                return;
+            }
+            while let Some(mut batch) = rx_up.next().await {
+                for (_, ev) in batch.drain(..) {
+                    let m = match ev {
+                        Event::Unsubscribed(_) => continue,
+                        Event::Update(v) => match Message::decode(&v) {
+                            Some(m) => m,
+                            None => continue,
+                        },
+                    };
+                    if m.seq == next {
+                        next += 1;
+                        if tx.send(m).await.is_err() {
+                            return;
+                        }
+                    } else if m.seq > next {
+                        // we missed some entries
+                        if !t.catch_up(&mut next, Some(m.seq), &mut tx).await {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use std::fs;
+    use tokio::runtime::Runtime;
+
+    fn remove(file: &str) {
+        for f in [file.to_string(), format!("{}.activity", file)] {
+            let _ = fs::remove_file(f);
+        }
+    }
+
+    async fn take(rx: &mut mpsc::Receiver<Message>, n: u64) -> Vec<Message> {
+        let to = Duration::from_secs(10);
+        let mut res = vec![];
+        for _ in 0..n {
+            res.push(time::timeout(to, rx.next()).await.unwrap().unwrap());
+        }
+        res
+    }
+
+    #[test]
+    fn replay_and_tail() {
+        Runtime::new().unwrap().block_on(async move {
+            let file = "journal-test-data";
+            remove(file);
+            let ctx = Ctx::new().await;
+            let base = Path::from("/journal");
+            let n = STRIDE * 2 + 10;
+            {
+                let mut journal =
+                    Journal::new(&ctx.publisher, base.clone(), file).await.unwrap();
+                for i in 1..=n {
+                    assert_eq!(journal.append(Value::U64(i * 10)).await.unwrap(), i);
+                }
+            }
+            // the read rpc, and with it the archive, is closed
+            // asynchronously
+            time::sleep(Duration::from_millis(500)).await;
+            // reopening continues the sequence
+            let mut journal =
+                Journal::new(&ctx.publisher, base.clone(), file).await.unwrap();
+            assert_eq!(journal.seq(), n);
+            let reader = JournalReader::new(&ctx.subscriber, &base).await.unwrap();
+            let start = STRIDE + 5;
+            let mut entries = reader.entries(start);
+            let msgs = take(&mut entries, n - start + 1).await;
+            for (m, seq) in msgs.iter().zip(start..) {
+                assert_eq!(m, &Message { seq, value: Value::U64(seq * 10) });
+            }
+            for i in n + 1..n + 5 {
+                assert_eq!(journal.append(Value::U64(i * 10)).await.unwrap(), i);
+            }
+            let msgs = take(&mut entries, 4).await;
+            for (m, seq) in msgs.iter().zip(n + 1..) {
+                assert_eq!(m, &Message { seq, value: Value::U64(seq * 10) });
+            }
+            drop(journal);
+            remove(file);
+        })
+    }
+}
@@ -12,3 +12,9 @@ pub mod rpc;
 pub mod view;
 pub mod channel;
 pub mod pack_channel;
+pub mod topic;
+pub mod lock;
+pub mod metrics;
+pub mod journal;
+pub mod history;
+pub mod service;
@@ -0,0 +1,334 @@
+//! Lease based mutual exclusion built on netidx.
+//!
+//! The holder of a lock publishes the lock's path, with a unique id
+//! as the value. A contender first checks whether anyone is already
+//! publishing the path, and if so writes to it asking for a
+//! receipt. If the holder answers the lock is held, otherwise the
+//! contender publishes the path itself, waits for other contenders
+//! to show up in the resolver, and then checks who won. If several
+//! contenders published at the same time the one with the lowest
+//! address wins and the others withdraw.
+//!
+//! Once acquired the lease must be renewed by checking with the
+//! resolver at least once per `ttl`. This is done automatically. If
+//! the holder can't renew its lease, or it finds another holder with
+//! a better claim, the lock is lost and it stops publishing the
+//! path. Contenders consider a holder that doesn't answer within
+//! `ttl` to be dead.
+//!
+//! Mutual exclusion is only as strong as the resolver's view of who
+//! is publishing the path. A holder that is partitioned from its
+//! contenders, but not from the resolver, will keep the lock. A
+//! holder that is partitioned from the resolver will lose the lock
+//! within `ttl`.
+use crate::cluster::uuid_string;
+use anyhow::Result;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select_biased,
+};
+use log::warn;
+use netidx::{
+    path::Path,
+    pool::Pooled,
+    publisher::{PublishFlags, Publisher, Val, Value, WriteRequest},
+    subscriber::Subscriber,
+};
+use std::{iter, time::Duration};
+use tokio::{
+    sync::watch,
+    task::{self, JoinHandle},
+    time::{self, Instant},
+};
+use uuid::Uuid;
+
+type Writes = mpsc::Receiver<Pooled<Vec<WriteRequest>>>;
+
+// true if someone else is holding, or trying to take, the lock
+async fn contended(subscriber: &Subscriber, path: &Path, ttl: Duration) -> Result<bool> {
+    let (_, resolved) = subscriber.resolver().resolve(iter::once(path.clone())).await?;
+    if resolved[0].publishers.is_empty() {
+        return Ok(false);
+    }
+    let probe = async {
+        let v = subscriber.subscribe_nondurable_one(path.clone(), Some(ttl)).await?;
+        Ok::<_, anyhow::Error>(v.write_with_recipt(Value::Null).await?)
+    };
+    match time::timeout(ttl, probe).await {
+        Ok(Ok(_)) => Ok(true),
+        Ok(Err(_)) | Err(_) => Ok(false),
+    }
+}
+
+// true if the resolver agrees that we hold the lock. If several
+// publishers claim it the one with the lowest address wins.
+async fn winning(
+    subscriber: &Subscriber,
+    publisher: &Publisher,
+    path: &Path,
+) -> Result<bool> {
+    let (publishers, resolved) =
+        subscriber.resolver().resolve(iter::once(path.clone())).await?;
+    let winner = resolved[0]
+        .publishers
+        .iter()
+        .filter_map(|r| publishers.get(&r.id))
+        .map(|p| p.addr)
+        .min();
+    Ok(winner == Some(publisher.addr()))
+}
+
+fn reply(mut reqs: Pooled<Vec<WriteRequest>>, v: &Value) {
+    for req in reqs.drain(..) {
+        if let Some(reply) = req.send_result {
+            reply.send(v.clone())
+        }
+    }
+}
+
+// answer probes from other contenders until `deadline`
+async fn serve(writes: &mut Writes, v: &Value, deadline: Instant) {
+    let mut timeout = Box::pin(time::sleep_until(deadline).fuse());
+    loop {
+        select_biased! {
+            () = timeout => break,
+            reqs = writes.select_next_some() => reply(reqs, v),
+        }
+    }
+}
+
+struct Holder {
+    subscriber: Subscriber,
+    publisher: Publisher,
+    path: Path,
+    ttl: Duration,
+    id: Value,
+    val: Val,
+    writes: Writes,
+    held: watch::Sender<bool>,
+}
+
+impl Holder {
+    async fn run(mut self, stop: oneshot::Receiver<()>) {
+        let mut stop = stop.fuse();
+        let mut renew = time::interval(self.ttl / 3);
+        let mut confirmed = Instant::now();
+        loop {
+            select_biased! {
+                _ = stop => break,
+                reqs = self.writes.select_next_some() => reply(reqs, &self.id),
+                _ = renew.tick().fuse() => {
+                    match winning(&self.subscriber, &self.publisher, &self.path).await {
+                        Ok(true) => confirmed = Instant::now(),
+                        Ok(false) => {
+                            warn!("lock {} taken by another holder", self.path);
+                            break
+                        }
+                        Err(e) if confirmed.elapsed() >= self.ttl => {
+                            warn!("lock {} lease expired, renew failed {}", self.path, e);
+                            break
+                        }
+                        Err(e) => warn!("failed to renew lock {} {}", self.path, e),
+                    }
+                }
+            }
+        }
+        let _ = self.held.send(false);
+        drop(self.val);
+        self.publisher.flushed().await
+    }
+}
+
+/// A held lock. The lock is released when it is dropped, or when
+/// `release` is called.
+pub struct Lock {
+    path: Path,
+    id: Value,
+    held: watch::Receiver<bool>,
+    stop: oneshot::Sender<()>,
+    task: JoinHandle<()>,
+}
+
+impl Lock {
+    /// Acquire the lock at `path`, waiting as long as necessary for
+    /// the current holder to release it. `ttl` is the lease time, the
+    /// lease is renewed automatically while the lock is held. If the
+    /// lease is lost `lost` will complete.
+    ///
+    /// `publisher` must not already be publishing `path`, and
+    /// contenders for the same lock must use different publishers.
+    pub async fn acquire(
+        subscriber: &Subscriber,
+        publisher: &Publisher,
+        path: Path,
+        ttl: Duration,
+    ) -> Result<Lock> {
+        loop {
+            match Self::try_acquire(subscriber, publisher, path.clone(), ttl).await? {
+                Some(lock) => break Ok(lock),
+                None => time::sleep(ttl / 2).await,
+            }
+        }
+    }
+
+    /// Make one attempt to acquire the lock at `path`, return `None`
+    /// if it is held by someone else. See `acquire`.
+    pub async fn try_acquire(
+        subscriber: &Subscriber,
+        publisher: &Publisher,
+        path: Path,
+        ttl: Duration,
+    ) -> Result<Option<Lock>> {
+        if contended(subscriber, &path, ttl).await? {
+            return Ok(None);
+        }
+        let flags = PublishFlags::USE_EXISTING;
+        let val = publisher.publish_with_flags(flags, path.clone(), Value::Null)?;
+        let (tx, mut writes) = mpsc::channel(3);
+        publisher.writes(val.id(), tx);
+        publisher.flushed().await;
+        // give contenders that published at about the same time a
+        // chance to show up in the resolver. While we are claiming we
+        // tell other contenders the lock is taken.
+        serve(&mut writes, &Value::Null, Instant::now() + ttl / 4).await;
+        if !winning(subscriber, publisher, &path).await? {
+            drop(val);
+            publisher.flushed().await;
+            return Ok(None);
+        }
+        let id = Value::from(uuid_string(Uuid::new_v4()));
+        let mut batch = publisher.start_batch();
+        val.update(&mut batch, id.clone());
+        batch.commit(None).await;
+        let (held_tx, held) = watch::channel(true);
+        let (stop, stop_rx) = oneshot::channel();
+        let holder = Holder {
+            subscriber: subscriber.clone(),
+            publisher: publisher.clone(),
+            path: path.clone(),
+            ttl,
+            id: id.clone(),
+            val,
+            writes,
+            held: held_tx,
+        };
+        let task = task::spawn(holder.run(stop_rx));
+        Ok(Some(Lock { path, id, held, stop, task }))
+    }
+
+    /// The path of the lock
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// The unique id of this holder of the lock, it is published as
+    /// the value of the lock's path.
+    pub fn id(&self) -> &Value {
+        &self.id
+    }
+
+    /// Return true if the lock is still held
+    pub fn is_held(&self) -> bool {
+        *self.held.borrow()
+    }
+
+    /// Wait until the lease on the lock is lost. Once lost the lock
+    /// won't be held again, you must acquire a new lock.
+    pub async fn lost(&self) {
+        let mut held = self.held.clone();
+        let _ = held.wait_for(|held| !*held).await;
+    }
+
+    /// Release the lock, and wait for the release to be flushed to
+    /// the resolver.
+    pub async fn release(self) {
+        drop(self.stop);
+        let _ = self.task.await;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use netidx::resolver_client::DesiredAuth;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn mutual_exclusion() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let publisher = Publisher::new(
+                ctx.cfg.clone(),
+                DesiredAuth::Anonymous,
+                "127.0.0.1/32".parse().unwrap(),
+                768,
+            )
+            .await
+            .unwrap();
+            let path = Path::from("/lock");
+            let ttl = Duration::from_millis(500);
+            let a = Lock::acquire(&ctx.subscriber, &ctx.publisher, path.clone(), ttl)
+                .await
+                .unwrap();
+            assert!(a.is_held());
+            let b = Lock::try_acquire(&ctx.subscriber, &publisher, path.clone(), ttl)
+                .await
+                .unwrap();
+            assert!(b.is_none());
+            let waiting = {
+                let subscriber = ctx.subscriber.clone();
+                let publisher = publisher.clone();
+                let path = path.clone();
+                task::spawn(async move {
+                    Lock::acquire(&subscriber, &publisher, path, ttl).await
+                })
+            };
+            // the lease is renewed, so the waiter must not get it
+            time::sleep(ttl * 3).await;
+            assert!(a.is_held());
+            assert!(!waiting.is_finished());
+            a.release().await;
+            let to = Duration::from_secs(10);
+            let b = time::timeout(to, waiting).await.unwrap().unwrap().unwrap();
+            assert!(b.is_held());
+            let v = ctx.subscriber.subscribe_nondurable_one(path, None).await.unwrap();
+            assert_eq!(&v.last(), &netidx::subscriber::Event::Update(b.id().clone()));
+        })
+    }
+
+    #[test]
+    fn race() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let path = Path::from("/lock");
+            let ttl = Duration::from_millis(500);
+            let mut contenders = vec![];
+            for _ in 0..3 {
+                let publisher = Publisher::new(
+                    ctx.cfg.clone(),
+                    DesiredAuth::Anonymous,
+                    "127.0.0.1/32".parse().unwrap(),
+                    768,
+                )
+                .await
+                .unwrap();
+                let subscriber = ctx.subscriber.clone();
+                let path = path.clone();
+                contenders.push(task::spawn(async move {
+                    let r = Lock::try_acquire(&subscriber, &publisher, path, ttl).await;
+                    (publisher, r.unwrap())
+                }));
+            }
+            let mut held = 0;
+            for c in contenders {
+                if let (_, Some(lock)) = c.await.unwrap() {
+                    assert!(lock.is_held());
+                    held += 1;
+                }
+            }
+            assert_eq!(held, 1);
+        })
+    }
+}
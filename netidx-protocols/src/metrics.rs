@@ -0,0 +1,490 @@
+//! Helpers for moving metrics between netidx and Prometheus.
+//!
+//! A netidx path under `base` maps to a metric name by joining the
+//! parts below `base` with `_`, and replacing any character that
+//! isn't allowed in a metric name with `_`. For example under
+//! `/sys`, `/sys/host0/cpu-load` becomes `host0_cpu_load`.
+//!
+//! Only values that have a numeric interpretation become samples.
+//! Integers, floats, and decimals are used as is, booleans are 0 or
+//! 1, timestamps are seconds since the epoch, and durations are
+//! seconds. The metric type is taken from the value, unsigned
+//! integers whose name ends in `_total` are counters, everything
+//! else is a gauge.
+//!
+//! Going the other way, a sample named `name{a="x",b="y"}` is
+//! published at `base/name/a=x/b=y`.
+use anyhow::Result;
+use fxhash::FxHashMap;
+use log::{debug, warn};
+use netidx::{
+    path::Path,
+    publisher::{Publisher, UpdateBatch, Val, Value},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task::{self, JoinHandle},
+    time,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MetricType {
+    Counter,
+    Gauge,
+}
+
+impl MetricType {
+    fn name(&self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Sample {
+    pub name: String,
+    pub typ: MetricType,
+    pub value: f64,
+}
+
+fn sanitize(s: &str, buf: &mut String) {
+    for c in s.chars() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == ':' {
+            buf.push(c)
+        } else {
+            buf.push('_')
+        }
+    }
+}
+
+/// Return the metric name of `path` relative to `base`, or None if
+/// `path` isn't under `base`.
+pub fn metric_name(base: &str, path: &str) -> Option<String> {
+    if !Path::is_parent(base, path) || base == path {
+        return None;
+    }
+    let mut name = String::new();
+    for part in Path::parts(&path[base.len()..]) {
+        if !name.is_empty() {
+            name.push('_');
+        }
+        sanitize(part, &mut name);
+    }
+    if name.starts_with(|c: char| c.is_ascii_digit()) {
+        name.insert(0, '_');
+    }
+    Some(name)
+}
+
+/// The numeric interpretation of `v`, if it has one.
+pub fn value_to_f64(v: &Value) -> Option<f64> {
+    match v {
+        Value::True => Some(1.),
+        Value::False => Some(0.),
+        Value::DateTime(ts) => Some(ts.timestamp_micros() as f64 / 1e6),
+        Value::Duration(d) => Some(d.as_secs_f64()),
+        Value::U32(_)
+        | Value::V32(_)
+        | Value::I32(_)
+        | Value::Z32(_)
+        | Value::U64(_)
+        | Value::V64(_)
+        | Value::I64(_)
+        | Value::Z64(_)
+        | Value::F32(_)
+        | Value::F64(_)
+        | Value::Decimal(_) => v.clone().cast_to::<f64>().ok(),
+        Value::String(_)
+        | Value::Bytes(_)
+        | Value::Null
+        | Value::Ok
+        | Value::Error(_)
+        | Value::Array(_)
+        | Value::ErrorInfo(_) => None,
+    }
+}
+
+/// Map `path` and its current value to a sample, see the module
+/// docs for the rules.
+pub fn sample(base: &str, path: &str, v: &Value) -> Option<Sample> {
+    let name = metric_name(base, path)?;
+    let value = value_to_f64(v)?;
+    let typ = match v {
+        Value::U32(_) | Value::V32(_) | Value::U64(_) | Value::V64(_)
+            if name.ends_with("_total") =>
+        {
+            MetricType::Counter
+        }
+        _ => MetricType::Gauge,
+    };
+    Some(Sample { name, typ, value })
+}
+
+fn fmt_f64(buf: &mut String, v: f64) {
+    if v.is_nan() {
+        buf.push_str("NaN")
+    } else if v == f64::INFINITY {
+        buf.push_str("+Inf")
+    } else if v == f64::NEG_INFINITY {
+        buf.push_str("-Inf")
+    } else {
+        let _ = write!(buf, "{}", v);
+    }
+}
+
+/// Render `samples` in the Prometheus text exposition format.
+pub fn render<'a>(samples: impl IntoIterator<Item = &'a Sample>) -> String {
+    let mut sorted = samples.into_iter().collect::<Vec<_>>();
+    sorted.sort_by(|s0, s1| s0.name.cmp(&s1.name));
+    let mut buf = String::new();
+    for s in sorted {
+        let _ = writeln!(buf, "# TYPE {} {}", s.name, s.typ.name());
+        buf.push_str(&s.name);
+        buf.push(' ');
+        fmt_f64(&mut buf, s.value);
+        buf.push('\n');
+    }
+    buf
+}
+
+/// Parse the Prometheus text exposition format, returning the path
+/// under `base` and value of each sample. Comments, type
+/// information, and timestamps are ignored.
+pub fn parse(base: &Path, text: &str) -> Result<Vec<(Path, f64)>> {
+    let mut res = Vec::new();
+    for line in text.lines() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let (name, labels, rest) = match line.find(|c: char| c == '{' || c == ' ') {
+            None => bail!("invalid sample {}", line),
+            Some(i) if &line[i..i + 1] == " " => (&line[..i], vec![], &line[i..]),
+            Some(i) => {
+                let (labels, rest) = parse_labels(&line[i + 1..])?;
+                (&line[..i], labels, rest)
+            }
+        };
+        let value = match rest.split_whitespace().next() {
+            None => bail!("missing value {}", line),
+            Some("+Inf") => f64::INFINITY,
+            Some("-Inf") => f64::NEG_INFINITY,
+            Some(v) => v.parse::<f64>()?,
+        };
+        let mut path = base.append(name);
+        for (k, v) in labels {
+            path = path.append(&format!("{}={}", k, v.replace('/', "_")));
+        }
+        res.push((path, value))
+    }
+    Ok(res)
+}
+
+// parse the labels after the opening {, return them and the rest of
+// the line after the closing }
+fn parse_labels(mut s: &str) -> Result<(Vec<(String, String)>, &str)> {
+    let mut labels = Vec::new();
+    loop {
+        s = s.trim_start_matches(|c: char| c == ',' || c.is_whitespace());
+        if let Some(rest) = s.strip_prefix('}') {
+            break Ok((labels, rest));
+        }
+        let i = match s.find('=') {
+            None => bail!("invalid label"),
+            Some(i) => i,
+        };
+        let key = s[..i].trim().to_string();
+        s = s[i + 1..].trim_start();
+        if !s.starts_with('"') {
+            bail!("label values must be quoted")
+        }
+        let mut val = String::new();
+        let mut chars = s[1..].char_indices();
+        let end = loop {
+            match chars.next() {
+                None => bail!("unterminated label value"),
+                Some((i, '"')) => break i + 2,
+                Some((_, '\\')) => match chars.next() {
+                    Some((_, 'n')) => val.push('\n'),
+                    Some((_, c)) => val.push(c),
+                    None => bail!("unterminated label value"),
+                },
+                Some((_, c)) => val.push(c),
+            }
+        };
+        labels.push((key, val));
+        s = &s[end..];
+    }
+}
+
+/// Publishes samples in Prometheus text format under a base path,
+/// e.g. from scraping an exporter, so they can be used from netidx.
+pub struct Importer {
+    publisher: Publisher,
+    base: Path,
+    published: FxHashMap<Path, Val>,
+}
+
+impl Importer {
+    pub fn new(publisher: &Publisher, base: Path) -> Self {
+        Importer { publisher: publisher.clone(), base, published: FxHashMap::default() }
+    }
+
+    /// Parse `text` and publish or update every sample in it. Paths
+    /// that are not in `text` are unpublished.
+    pub async fn update(&mut self, text: &str) -> Result<()> {
+        let samples = parse(&self.base, text)?;
+        let mut batch = self.publisher.start_batch();
+        let mut present = FxHashMap::default();
+        for (path, v) in samples {
+            match self.published.remove(&path) {
+                Some(val) => {
+                    val.update_changed(&mut batch, v);
+                    present.insert(path, val);
+                }
+                None => {
+                    let val = self.publisher.publish(path.clone(), v)?;
+                    present.insert(path, val);
+                }
+            }
+        }
+        self.published = present;
+        Ok(batch.commit(None).await)
+    }
+}
+
+/// Publishes metrics about the current process under a base path.
+///
+/// - `start_time`, the process start time
+/// - `uptime_seconds`
+/// - `cpu_seconds_total`, user plus system cpu time (linux only)
+/// - `threads` (linux only)
+/// - `virtual_memory_bytes` (linux only)
+/// - `resident_memory_bytes` (linux only)
+/// - `open_fds` (linux only)
+pub struct ProcessMetrics {
+    started: SystemTime,
+    vals: BTreeMap<&'static str, Val>,
+}
+
+impl ProcessMetrics {
+    pub fn new(publisher: &Publisher, base: &Path) -> Result<Self> {
+        let started = SystemTime::now();
+        let mut vals = BTreeMap::new();
+        let start_time = Value::DateTime(started.into());
+        vals.insert(
+            "start_time",
+            publisher.publish(base.append("start_time"), start_time)?,
+        );
+        for name in ProcessMetrics::NAMES {
+            vals.insert(*name, publisher.publish(base.append(name), Value::Null)?);
+        }
+        Ok(ProcessMetrics { started, vals })
+    }
+
+    #[cfg(target_os = "linux")]
+    const NAMES: &'static [&'static str] = &[
+        "uptime_seconds",
+        "cpu_seconds_total",
+        "threads",
+        "virtual_memory_bytes",
+        "resident_memory_bytes",
+        "open_fds",
+    ];
+
+    #[cfg(not(target_os = "linux"))]
+    const NAMES: &'static [&'static str] = &["uptime_seconds"];
+
+    #[cfg(target_os = "linux")]
+    fn read(&self) -> Vec<(&'static str, Value)> {
+        use std::fs;
+        let mut res = Vec::new();
+        // the fields after the command, which may contain spaces
+        let stat = fs::read_to_string("/proc/self/stat").unwrap_or_default();
+        let fields = match stat.rfind(')') {
+            None => vec![],
+            Some(i) => stat[i + 1..].split_whitespace().collect::<Vec<_>>(),
+        };
+        let field = |i: usize| fields.get(i).and_then(|f| f.parse::<u64>().ok());
+        // utime, stime are in clock ticks, which are 100 per second
+        // on every linux platform that matters.
+        if let (Some(utime), Some(stime)) = (field(11), field(12)) {
+            res.push(("cpu_seconds_total", Value::F64((utime + stime) as f64 / 100.)));
+        }
+        if let Some(n) = field(17) {
+            res.push(("threads", Value::U64(n)));
+        }
+        if let Some(n) = field(20) {
+            res.push(("virtual_memory_bytes", Value::U64(n)));
+        }
+        if let Some(n) = field(21) {
+            res.push(("resident_memory_bytes", Value::U64(n * 4096)));
+        }
+        if let Ok(d) = fs::read_dir("/proc/self/fd") {
+            res.push(("open_fds", Value::U64(d.count() as u64)));
+        }
+        res
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn read(&self) -> Vec<(&'static str, Value)> {
+        vec![]
+    }
+
+    /// Read the current values and queue updates in `batch`.
+    pub fn update(&self, batch: &mut UpdateBatch) {
+        let uptime = self.started.elapsed().unwrap_or(Duration::ZERO).as_secs_f64();
+        let uptime = ("uptime_seconds", Value::F64(uptime));
+        for (name, v) in self.read().into_iter().chain(std::iter::once(uptime)) {
+            if let Some(val) = self.vals.get(name) {
+                val.update_changed(batch, v)
+            }
+        }
+    }
+
+    /// Publish process metrics under `base` and update them every
+    /// `interval` until the returned task is aborted.
+    pub fn start(
+        publisher: &Publisher,
+        base: &Path,
+        interval: Duration,
+    ) -> Result<JoinHandle<()>> {
+        let t = ProcessMetrics::new(publisher, base)?;
+        let publisher = publisher.clone();
+        Ok(task::spawn(async move {
+            let mut interval = time::interval(interval);
+            loop {
+                interval.tick().await;
+                let mut batch = publisher.start_batch();
+                t.update(&mut batch);
+                batch.commit(None).await
+            }
+        }))
+    }
+}
+
+async fn handle_http<F: Fn() -> String>(render: &F, mut con: TcpStream) -> Result<()> {
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = con.read(&mut buf).await?;
+        if n == 0 {
+            bail!("connection closed")
+        }
+        req.extend_from_slice(&buf[..n]);
+        if req.len() > 8192 {
+            bail!("request too large")
+        }
+    }
+    let line = req.split(|c| *c == b'\r').next().unwrap_or(&[]);
+    let mut parts = line.split(|c| *c == b' ');
+    let (status, ctyp, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", render())
+        }
+        (Some(_), Some(_)) => {
+            ("404 Not Found", "text/plain", String::from("not found\n"))
+        }
+        (_, _) => ("400 Bad Request", "text/plain", String::from("bad request\n")),
+    };
+    let hdr = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        ctyp,
+        body.len()
+    );
+    con.write_all(hdr.as_bytes()).await?;
+    con.write_all(body.as_bytes()).await?;
+    Ok(con.shutdown().await?)
+}
+
+/// Serve the result of `render` at `/metrics` on `addr` over http
+/// until the returned task is aborted. Returns the bound address.
+pub async fn serve<F>(addr: SocketAddr, render: F) -> Result<(SocketAddr, JoinHandle<()>)>
+where
+    F: Fn() -> String + Send + Sync + 'static,
+{
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    let render = Arc::new(render);
+    let jh = task::spawn(async move {
+        loop {
+            match listener.accept().await {
+                Err(e) => warn!("metrics accept failed: {}", e),
+                Ok((con, _)) => {
+                    let render = render.clone();
+                    task::spawn(async move {
+                        let timeout = Duration::from_secs(10);
+                        let r = time::timeout(timeout, handle_http(&*render, con)).await;
+                        debug!("metrics request finished {:?}", r)
+                    });
+                }
+            }
+        }
+    });
+    Ok((local_addr, jh))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use netidx::chars::Chars;
+
+    #[test]
+    fn names_and_samples() {
+        assert_eq!(metric_name("/sys", "/sys/host0/cpu-load").unwrap(), "host0_cpu_load");
+        assert_eq!(metric_name("/sys", "/sys/0/x").unwrap(), "_0_x");
+        assert!(metric_name("/sys", "/other/x").is_none());
+        assert!(metric_name("/sys", "/sys").is_none());
+        let s = sample("/", "/app/requests_total", &Value::U64(42)).unwrap();
+        assert_eq!(
+            s,
+            Sample {
+                name: "app_requests_total".into(),
+                typ: MetricType::Counter,
+                value: 42.
+            }
+        );
+        let s = sample("/", "/app/load", &Value::F64(0.5)).unwrap();
+        assert_eq!(s.typ, MetricType::Gauge);
+        let s = sample("/", "/app/up", &Value::True).unwrap();
+        assert_eq!(s.value, 1.);
+        assert!(sample("/", "/app/name", &Value::String(Chars::from("foo"))).is_none());
+    }
+
+    #[test]
+    fn render_parse_roundtrip() {
+        let samples = vec![
+            Sample { name: "b_total".into(), typ: MetricType::Counter, value: 3. },
+            Sample { name: "a".into(), typ: MetricType::Gauge, value: 0.25 },
+        ];
+        let text = render(&samples);
+        assert_eq!(text, "# TYPE a gauge\na 0.25\n# TYPE b_total counter\nb_total 3\n");
+        let base = Path::from("/prom");
+        let parsed = parse(&base, &text).unwrap();
+        assert_eq!(
+            parsed,
+            vec![(Path::from("/prom/a"), 0.25), (Path::from("/prom/b_total"), 3.)]
+        );
+        let text = "# HELP x an x\nx{job=\"a/b\",le=\"0.5\"} 7 1700000000000\ny +Inf\n";
+        let parsed = parse(&base, text).unwrap();
+        assert_eq!(
+            parsed,
+            vec![
+                (Path::from("/prom/x/job=a_b/le=0.5"), 7.),
+                (Path::from("/prom/y"), f64::INFINITY)
+            ]
+        );
+        assert!(parse(&base, "x{job=a} 1").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use anyhow::Result;
 use arcstr::ArcStr;
+use bytes::Bytes;
 use futures::{
     channel::{mpsc, oneshot},
     future,
@@ -12,9 +13,13 @@ use netidx::{
     chars::Chars,
     path::Path,
     pool::{Pool, Pooled},
-    protocol::glob::{Glob, GlobSet},
+    protocol::{
+        glob::{Glob, GlobSet},
+        schema::schema_path,
+    },
     publisher::{
-        ClId, Id, PublishFlags, Publisher, SendResult, Val, Value, WriteRequest,
+        ClId, ErrorInfo, Id, PublishFlags, Publisher, Schema, SendResult, Typ, Val,
+        Value, WriteRequest,
     },
     subscriber::{Dval, Subscriber, SubscriberId},
 };
@@ -37,18 +42,39 @@ pub mod server {
 
     atomic_id!(ProcId);
 
-    /// for use in map functions, will reply to the client with an error and return None
+    /// for use in map functions, will reply to the client with an
+    /// error and return None. If a code is given the reply will be a
+    /// structured error (see `ErrorInfo`).
     #[macro_export]
     macro_rules! rpc_err {
         ($reply:expr, $msg:expr) => {{
             $reply.send(Value::Error(Chars::from($msg)));
             return None;
         }};
+        ($reply:expr, $code:expr, $msg:expr) => {{
+            $reply.send(Value::coded_err($code, $msg));
+            return None;
+        }};
+    }
+
+    /// The default value of an rpc argument, `Null` if none is given
+    #[doc(hidden)]
+    #[macro_export]
+    macro_rules! rpc_arg_default {
+        () => {
+            netidx::publisher::Value::Null
+        };
+        ($default:expr) => {
+            netidx::publisher::Value::from($default)
+        };
     }
 
     /// defines a new rpc.
     /// `define_rpc!(publisher, path, doc, mapfn, tx, arg: typ = default; doc, ...)`
-    /// see `Proc` for an example
+    /// see `Proc` for an example. The schema of each argument is
+    /// derived from it's type, see `ArgType`. If the default is
+    /// omitted it is `Null`, and unless the type accepts `Null` the
+    /// argument is required.
     #[macro_export]
     macro_rules! define_rpc {
         (
@@ -57,28 +83,184 @@ pub mod server {
             $topdoc:expr,
             $map:expr,
             $tx:expr,
-            $($arg:ident: $typ:ty = $default:expr; $doc:expr),*
+            $($arg:ident: $typ:ty $(= $default:expr)?; $doc:expr),*
         ) => {{
             let map = move |mut c: RpcCall| {
                 $(
-                    let d = Value::from($default);
+                    let d = $crate::rpc_arg_default!($($default)?);
                     let $arg = match c.args.remove(stringify!($arg)).unwrap_or(d).cast_to::<$typ>() {
                         Ok(t) => t,
-                        Err(_) => rpc_err!(c.reply, format!("arg: {} invalid type conversion", stringify!($arg)))
+                        Err(_) => rpc_err!(c.reply, netidx::publisher::ErrorInfo::INVALID_ARGUMENT, format!("arg: {} invalid type conversion", stringify!($arg)))
                     };
                 )*
                 if c.args.len() != 0 {
-                    rpc_err!(c.reply, format!("unknown argument specified: {:?}", c.args.keys().collect::<Vec<_>>()))
+                    rpc_err!(c.reply, netidx::publisher::ErrorInfo::UNKNOWN_ARGUMENT, format!("unknown argument specified: {:?}", c.args.keys().collect::<Vec<_>>()))
                 }
                 $map(c, $($arg),*)
             };
             let args = [
-                $(ArgSpec {name: ArcStr::from(stringify!($arg)), default_value: Value::from($default), doc: Value::from($doc)}),*
+                $(ArgSpec {
+                    name: ArcStr::from(stringify!($arg)),
+                    default_value: $crate::rpc_arg_default!($($default)?),
+                    doc: Value::from($doc),
+                    schema: <$typ as $crate::rpc::server::ArgType>::schema(),
+                }),*
             ];
             Proc::new($publisher, $path, Value::from($topdoc), args, map, $tx)
         }}
     }
 
+    /// Define a function that publishes an rpc from a Rust function
+    /// signature. The doc comments of the function and of it's
+    /// arguments become the docs of the procedure and it's
+    /// arguments, and the schema of each argument is derived from
+    /// it's type, see `ArgType`. Arguments may have a default, if
+    /// they don't it is `Null`. The first argument is the
+    /// `RpcCall`, all the arguments are mutable in the body of the
+    /// function. The generated function takes the publisher, the
+    /// path to publish the procedure at, and an optional handler
+    /// channel, as `Proc::new` does, and it needs the same imports
+    /// as `define_rpc!`.
+    ///
+    /// ```no_run
+    /// #[macro_use] extern crate netidx_protocols;
+    /// use netidx::{path::Path, subscriber::Value};
+    /// use netidx_protocols::rpc::server::{Proc, ArgSpec, RpcCall};
+    /// use arcstr::ArcStr;
+    /// # use anyhow::Result;
+    ///
+    /// rpc_fn! {
+    ///     /// echos it's argument
+    ///     fn echo(
+    ///         c: RpcCall,
+    ///         /// the argument to echo
+    ///         arg: String,
+    ///         /// the number of times to echo it
+    ///         times: u64 = 1
+    ///     ) -> Option<()> {
+    ///         c.reply.send(arg.repeat(times as usize));
+    ///         None
+    ///     }
+    /// }
+    ///
+    /// # async fn z() -> Result<()> {
+    /// #   let publisher = unimplemented!();
+    ///     let echo = echo(&publisher, Path::from("/examples/api/echo"), None)?;
+    /// #   drop(echo);
+    /// #   Ok(())
+    /// # }
+    /// ```
+    #[macro_export]
+    macro_rules! rpc_fn {
+        (
+            $(#[doc = $doc:literal])*
+            $vis:vis fn $name:ident(
+                $call:ident: RpcCall
+                $(, $(#[doc = $adoc:literal])* $arg:ident: $typ:ty $(= $default:expr)?)*
+                $(,)?
+            ) -> Option<$t:ty> $body:block
+        ) => {
+            $(#[doc = $doc])*
+            $vis fn $name(
+                publisher: &netidx::publisher::Publisher,
+                path: netidx::path::Path,
+                handler: Option<futures::channel::mpsc::Sender<$t>>,
+            ) -> anyhow::Result<$crate::rpc::server::Proc> {
+                #[allow(unused_mut)]
+                fn f(mut $call: RpcCall $(, mut $arg: $typ)*) -> Option<$t> $body
+                $crate::define_rpc!(
+                    publisher,
+                    path,
+                    $crate::rpc::server::doc_string(&[$($doc),*]),
+                    f,
+                    handler,
+                    $(
+                        $arg: $typ $(= $default)?;
+                        $crate::rpc::server::doc_string(&[$($adoc),*])
+                    ),*
+                )
+            }
+        };
+    }
+
+    /// join the lines of a doc comment
+    #[doc(hidden)]
+    pub fn doc_string(lines: &[&str]) -> Value {
+        Value::from(lines.iter().map(|l| l.trim()).collect::<Vec<_>>().join(" "))
+    }
+
+    /// The type of an rpc argument. `schema` describes the values of
+    /// the type, it is published along with the argument, and the
+    /// procedure casts arguments to the `typ` of their schema, and
+    /// rejects those that can't be cast or are out of range, before
+    /// the call is mapped. Types that accept many kinds of value,
+    /// e.g. `Value` or `Option<T>`, have no schema.
+    pub trait ArgType {
+        fn schema() -> Option<Schema>;
+    }
+
+    macro_rules! arg_type {
+        ($($t:ty => $typ:expr),*) => {
+            $(
+                impl ArgType for $t {
+                    fn schema() -> Option<Schema> {
+                        Some(Schema::new($typ))
+                    }
+                }
+            )*
+        };
+    }
+
+    arg_type!(
+        u32 => Typ::U32,
+        i32 => Typ::I32,
+        u64 => Typ::U64,
+        i64 => Typ::I64,
+        usize => Typ::U64,
+        f32 => Typ::F32,
+        f64 => Typ::F64,
+        bool => Typ::Bool,
+        String => Typ::String,
+        Chars => Typ::String,
+        ArcStr => Typ::String,
+        Path => Typ::String,
+        Bytes => Typ::Bytes,
+        Duration => Typ::Duration
+    );
+
+    impl ArgType for Value {
+        fn schema() -> Option<Schema> {
+            None
+        }
+    }
+
+    impl<T> ArgType for Option<T> {
+        fn schema() -> Option<Schema> {
+            None
+        }
+    }
+
+    impl<T> ArgType for Vec<T> {
+        fn schema() -> Option<Schema> {
+            Some(Schema::new(Typ::Array))
+        }
+    }
+
+    // cast `v` to the type of `schema` and check that it is in range
+    fn validate(schema: &Schema, v: Value) -> Option<Value> {
+        let v = match schema.typ {
+            None => v,
+            Some(typ) => v.cast(typ)?,
+        };
+        let below = schema.min.as_ref().map(|min| v < *min).unwrap_or(false);
+        let above = schema.max.as_ref().map(|max| v > *max).unwrap_or(false);
+        if below || above {
+            None
+        } else {
+            Some(v)
+        }
+    }
+
     lazy_static! {
         static ref ARGS: Pool<HashMap<ArcStr, Value>> = Pool::new(10000, 50);
     }
@@ -88,7 +270,8 @@ pub mod server {
     impl Drop for RpcReply {
         fn drop(&mut self) {
             if let Some(reply) = self.0.take() {
-                let _ = reply.send(Value::Error(Chars::from("rpc call failed")));
+                let _ =
+                    reply.send(Value::coded_err(ErrorInfo::FAILED, "rpc call failed"));
             }
         }
     }
@@ -106,6 +289,9 @@ pub mod server {
         pub name: ArcStr,
         pub doc: Value,
         pub default_value: Value,
+        /// If specified arguments are validated against the schema,
+        /// see `ArgType`, and it is published at `arg/val/.schema`
+        pub schema: Option<Schema>,
     }
 
     pub struct RpcCall {
@@ -119,6 +305,7 @@ pub mod server {
         name: ArcStr,
         _value: Val,
         _doc: Val,
+        _schema: Option<Val>,
     }
 
     struct PendingCall {
@@ -131,6 +318,7 @@ pub mod server {
         call: Arc<Val>,
         _doc: Val,
         args: HashMap<Id, Arg, FxBuildHasher>,
+        schemas: HashMap<ArcStr, Schema, FxBuildHasher>,
         pending: HashMap<ClId, PendingCall, FxBuildHasher>,
         handler: Option<mpsc::Sender<T>>,
         map: M,
@@ -161,13 +349,30 @@ pub mod server {
                     _ = stop => break,
                     mut batch = self.events.select_next_some() => for req in batch.drain(..) {
                         if req.id == self.call.id() {
-                            let args = self.pending.remove(&req.client).map(|pc| pc.args)
+                            let mut args = self.pending.remove(&req.client)
+                                .map(|pc| pc.args)
                                 .unwrap_or_else(|| ARGS.take());
+                            let mut reply = RpcReply(req.send_result);
+                            let invalid = args.iter_mut().find_map(|(name, v)| {
+                                let schema = self.schemas.get(name)?;
+                                match validate(schema, v.clone()) {
+                                    Some(valid) => {
+                                        *v = valid;
+                                        None
+                                    }
+                                    None => Some((name.clone(), schema.clone())),
+                                }
+                            });
+                            if let Some((name, schema)) = invalid {
+                                let m = format!("arg: {} expected {}", name, schema);
+                                reply.send(Value::coded_err(ErrorInfo::INVALID_ARGUMENT, m));
+                                continue
+                            }
                             let call = RpcCall {
                                 client: req.client,
                                 id: self.id,
                                 args,
-                                reply: RpcReply(req.send_result),
+                                reply,
                             };
                             let t = match catch_unwind(AssertUnwindSafe(|| (self.map)(call))) {
                                 Ok(t) => t,
@@ -297,9 +502,10 @@ pub mod server {
                 doc,
             )?;
             publisher.writes(call.id(), tx_ev.clone());
+            let mut schemas = HashMap::with_hasher(FxBuildHasher::default());
             let args = args
                 .into_iter()
-                .map(|ArgSpec { name: arg, doc, default_value }| {
+                .map(|ArgSpec { name: arg, doc, default_value, schema }| {
                     let base = name.append(&*arg);
                     let _value = publisher
                         .publish_with_flags(
@@ -316,7 +522,19 @@ pub mod server {
                         base.append("doc"),
                         doc,
                     )?;
-                    Ok((_value.id(), Arg { name: arg, _value, _doc }))
+                    let _schema = match schema {
+                        None => None,
+                        Some(schema) => {
+                            let v = publisher.publish_with_flags(
+                                PublishFlags::USE_EXISTING,
+                                schema_path(&base.append("val")),
+                                Value::from(&schema),
+                            )?;
+                            schemas.insert(arg.clone(), schema);
+                            Some(v)
+                        }
+                    };
+                    Ok((_value.id(), Arg { name: arg, _value, _doc, _schema }))
                 })
                 .collect::<Result<HashMap<Id, Arg, FxBuildHasher>>>()?;
             let inner = ProcInner {
@@ -324,6 +542,7 @@ pub mod server {
                 call,
                 _doc,
                 args,
+                schemas,
                 pending: HashMap::with_hasher(FxBuildHasher::default()),
                 map,
                 handler,
@@ -498,6 +717,7 @@ pub mod client {
 #[cfg(test)]
 mod test {
     use crate::{channel::test::Ctx, rpc::server::ArgSpec};
+    use netidx::subscriber::Event;
 
     use super::server::*;
     use super::*;
@@ -531,13 +751,49 @@ mod test {
             assert_eq!(res, Value::U32(42));
             let args: Vec<(Arc<str>, Value)> = vec![];
             let res = proc.call(args.into_iter()).await.unwrap();
-            assert!(match res {
-                Value::Error(_) => true,
-                _ => false,
-            });
+            assert_eq!(res.error_code(), Some(ErrorInfo::FAILED));
             let args = vec![("arg2", Value::from("hello rpc"))];
             assert!(proc.call(args.into_iter()).await.is_err());
             Ok::<(), anyhow::Error>(())
         }).unwrap()
     }
+
+    rpc_fn! {
+        /// repeats it's argument
+        fn repeat(
+            c: RpcCall,
+            /// the string to repeat
+            s: String,
+            /// how many times
+            n: u64 = 1u64
+        ) -> Option<()> {
+            c.reply.send(s.repeat(n as usize));
+            None
+        }
+    }
+
+    #[test]
+    fn typed_args() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let proc_name = Path::from("/rpc/repeat");
+            let _server_proc = repeat(&ctx.publisher, proc_name.clone(), None).unwrap();
+            ctx.publisher.flushed().await;
+            let arg = proc_name.append("n").append("val");
+            let schema = ctx.subscriber.schema(&arg).await.unwrap();
+            assert_eq!(schema, Some(Schema::new(Typ::U64)));
+            let val = ctx.subscriber.subscribe_nondurable_one(arg, None).await.unwrap();
+            assert_eq!(val.last(), Event::Update(Value::U64(1)));
+            let proc = client::Proc::new(&ctx.subscriber, proc_name).await.unwrap();
+            let res = call_rpc!(proc, s: "ab", n: 2u64).await.unwrap();
+            assert_eq!(res, Value::from("abab"));
+            let res = call_rpc!(proc, s: "ab").await.unwrap();
+            assert_eq!(res, Value::from("ab"));
+            let res = call_rpc!(proc, s: "ab", n: "two").await.unwrap();
+            assert_eq!(res.error_code(), Some(ErrorInfo::INVALID_ARGUMENT));
+            let res = call_rpc!(proc, s: "ab", n: Value::Null).await.unwrap();
+            assert_eq!(res.error_code(), Some(ErrorInfo::INVALID_ARGUMENT));
+            Ok::<(), anyhow::Error>(())
+        }).unwrap()
+    }
 }
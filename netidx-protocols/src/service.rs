@@ -0,0 +1,402 @@
+//! A common convention for registering and discovering services.
+//!
+//! A service registered at `base` publishes two values,
+//!
+//! * `base/info`: a description of the service, see `ServiceInfo`,
+//! encoded as an array of `[key, value]` pairs, `name`, `version`,
+//! `heartbeat`, and `metadata`, which is itself an array of `[key,
+//! value]` pairs.
+//! * `base/heartbeat`: the time, updated every `heartbeat`.
+//!
+//! A service is considered alive while it's heartbeat keeps
+//! updating. If `MISSED_HEARTBEATS` heartbeats in a row don't arrive,
+//! or the heartbeat is unpublished, the service is considered
+//! down. `discover` tracks the services matching a glob and reports
+//! when they come up, go down, and disappear.
+use anyhow::Result;
+use arcstr::ArcStr;
+use chrono::prelude::*;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select_biased,
+};
+use log::warn;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    pool::Pooled,
+    protocol::glob::{Glob, GlobSet},
+    publisher::{Publisher, Value},
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags},
+};
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    iter,
+    time::Duration,
+};
+use tokio::{
+    task,
+    time::{self, Instant},
+};
+
+/// The default time between heartbeats
+pub const DEFAULT_HEARTBEAT: Duration = Duration::from_secs(1);
+
+/// A service is down after this many heartbeats in a row are missed
+pub const MISSED_HEARTBEATS: u32 = 3;
+
+/// How often `discover` asks the resolver for new services
+pub const DISCOVERY_POLL: Duration = Duration::from_secs(1);
+
+/// A description of a service
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServiceInfo {
+    /// What kind of service this is
+    pub name: String,
+    pub version: String,
+    /// How often the service updates it's heartbeat
+    pub heartbeat: Duration,
+    /// Anything else clients need to know, e.g. where the service's
+    /// rpcs are published
+    pub metadata: BTreeMap<String, Value>,
+}
+
+impl ServiceInfo {
+    pub fn new<N: Into<String>, V: Into<String>>(name: N, version: V) -> Self {
+        ServiceInfo {
+            name: name.into(),
+            version: version.into(),
+            heartbeat: DEFAULT_HEARTBEAT,
+            metadata: BTreeMap::new(),
+        }
+    }
+
+    pub(crate) fn encode(&self) -> Value {
+        let metadata = self
+            .metadata
+            .iter()
+            .map(|(k, v)| Value::from((k.clone(), v.clone())))
+            .collect::<Vec<_>>();
+        Value::from(vec![
+            Value::from(("name", self.name.clone())),
+            Value::from(("version", self.version.clone())),
+            Value::from(("heartbeat", Value::Duration(self.heartbeat))),
+            Value::from(("metadata", Value::from(metadata))),
+        ])
+    }
+
+    pub(crate) fn decode(v: &Value) -> Option<ServiceInfo> {
+        fn pairs(v: &Value) -> Option<impl Iterator<Item = (&str, &Value)>> {
+            match v {
+                Value::Array(a) => Some(a.iter().filter_map(|p| match p {
+                    Value::Array(p) if p.len() == 2 => match &p[0] {
+                        Value::String(k) => Some((&**k, &p[1])),
+                        _ => None,
+                    },
+                    _ => None,
+                })),
+                _ => None,
+            }
+        }
+        let mut info = ServiceInfo::new("", "");
+        for (k, v) in pairs(v)? {
+            match (k, v) {
+                ("name", Value::String(s)) => info.name = String::from(&**s),
+                ("version", Value::String(s)) => info.version = String::from(&**s),
+                ("heartbeat", Value::Duration(d)) => info.heartbeat = *d,
+                ("metadata", v) => {
+                    for (k, v) in pairs(v)? {
+                        info.metadata.insert(String::from(k), v.clone());
+                    }
+                }
+                (_, _) => (),
+            }
+        }
+        Some(info)
+    }
+}
+
+/// A registered service. The service is published, and it's
+/// heartbeat updated, until this is dropped.
+pub struct Service {
+    _stop: oneshot::Sender<()>,
+}
+
+impl Service {
+    /// Register a service at `base` described by `info`. It's wise
+    /// to ensure nothing else is publishing under `base`.
+    pub async fn register(
+        publisher: &Publisher,
+        base: Path,
+        info: ServiceInfo,
+    ) -> Result<Service> {
+        if info.heartbeat.is_zero() {
+            bail!("the heartbeat interval must be greater than 0")
+        }
+        let info_val = publisher.publish(base.append("info"), info.encode())?;
+        let beat = publisher.publish(base.append("heartbeat"), Utc::now())?;
+        publisher.flushed().await;
+        let (_stop, stop) = oneshot::channel();
+        let publisher = publisher.clone();
+        task::spawn(async move {
+            let _info_val = info_val;
+            let mut stop = stop.fuse();
+            let mut interval = time::interval(info.heartbeat);
+            loop {
+                select_biased! {
+                    _ = stop => break,
+                    _ = interval.tick().fuse() => {
+                        let mut batch = publisher.start_batch();
+                        beat.update(&mut batch, Utc::now());
+                        batch.commit(None).await
+                    }
+                }
+            }
+        });
+        Ok(Service { _stop })
+    }
+}
+
+/// A change in the services matching a `discover` glob
+#[derive(Debug, Clone, PartialEq)]
+pub enum ServiceEvent {
+    /// The service at the path is alive, either it was just found,
+    /// it came back after being down, or it's info changed.
+    Up(Path, ServiceInfo),
+    /// The service at the path stopped updating it's heartbeat
+    Down(Path),
+    /// The service at the path is no longer registered
+    Gone(Path),
+}
+
+struct Watched {
+    _info: Dval,
+    _heartbeat: Dval,
+    ids: [SubId; 2],
+    current: Option<ServiceInfo>,
+    last_beat: Option<Instant>,
+    up: bool,
+    announced: bool,
+}
+
+impl Watched {
+    fn alive(&self, now: Instant) -> bool {
+        match (&self.current, self.last_beat) {
+            (Some(info), Some(last)) => {
+                now.saturating_duration_since(last) <= info.heartbeat * MISSED_HEARTBEATS
+            }
+            (_, _) => false,
+        }
+    }
+
+    fn refresh(&mut self, path: &Path, now: Instant, events: &mut Vec<ServiceEvent>) {
+        match (self.up, self.alive(now)) {
+            (false, true) => {
+                self.up = true;
+                self.announced = true;
+                let info = self.current.clone().unwrap();
+                events.push(ServiceEvent::Up(path.clone(), info))
+            }
+            (true, false) => {
+                self.up = false;
+                events.push(ServiceEvent::Down(path.clone()))
+            }
+            (true, true) | (false, false) => (),
+        }
+    }
+}
+
+struct Discovery {
+    subscriber: Subscriber,
+    filter: GlobSet,
+    services: HashMap<Path, Watched>,
+    by_id: HashMap<SubId, (Path, bool)>,
+    tx_updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+}
+
+impl Discovery {
+    async fn poll(&mut self, events: &mut Vec<ServiceEvent>) -> Result<()> {
+        let mut found = HashSet::new();
+        let listed = self.subscriber.resolver().list_matching(&self.filter).await?;
+        for paths in listed.iter() {
+            for path in paths.iter() {
+                if let Some(base) = Path::dirname(path) {
+                    found.insert(Path::from(ArcStr::from(base)));
+                }
+            }
+        }
+        let gone = self
+            .services
+            .keys()
+            .filter(|p| !found.contains(*p))
+            .cloned()
+            .collect::<Vec<_>>();
+        for path in gone {
+            if let Some(w) = self.services.remove(&path) {
+                for id in w.ids {
+                    self.by_id.remove(&id);
+                }
+                if w.up {
+                    events.push(ServiceEvent::Down(path.clone()))
+                }
+                if w.announced {
+                    events.push(ServiceEvent::Gone(path))
+                }
+            }
+        }
+        for path in found {
+            if !self.services.contains_key(&path) {
+                let flags = UpdatesFlags::BEGIN_WITH_LAST;
+                let info = self.subscriber.subscribe(path.append("info"));
+                let heartbeat = self.subscriber.subscribe(path.append("heartbeat"));
+                info.updates(flags, self.tx_updates.clone());
+                heartbeat.updates(flags, self.tx_updates.clone());
+                let ids = [info.id(), heartbeat.id()];
+                self.by_id.insert(ids[0], (path.clone(), false));
+                self.by_id.insert(ids[1], (path.clone(), true));
+                let w = Watched {
+                    _info: info,
+                    _heartbeat: heartbeat,
+                    ids,
+                    current: None,
+                    last_beat: None,
+                    up: false,
+                    announced: false,
+                };
+                self.services.insert(path, w);
+            }
+        }
+        Ok(())
+    }
+
+    fn process(
+        &mut self,
+        mut batch: Pooled<Vec<(SubId, Event)>>,
+        events: &mut Vec<ServiceEvent>,
+    ) {
+        let now = Instant::now();
+        for (id, ev) in batch.drain(..) {
+            let (path, is_beat) = match self.by_id.get(&id) {
+                Some(p) => p,
+                None => continue,
+            };
+            let w = match self.services.get_mut(path) {
+                Some(w) => w,
+                None => continue,
+            };
+            match (*is_beat, ev) {
+                (true, Event::Update(_)) => w.last_beat = Some(now),
+                (true, Event::Unsubscribed(_)) => w.last_beat = None,
+                (false, Event::Update(v)) => match ServiceInfo::decode(&v) {
+                    None => warn!("invalid service info for {}: {}", path, v),
+                    Some(info) => {
+                        if w.current.as_ref() != Some(&info) {
+                            w.current = Some(info.clone());
+                            if w.up && w.alive(now) {
+                                events.push(ServiceEvent::Up(path.clone(), info))
+                            }
+                        }
+                    }
+                },
+                (false, Event::Unsubscribed(_)) => (),
+            }
+            w.refresh(path, now, events)
+        }
+    }
+
+    fn check_stale(&mut self, events: &mut Vec<ServiceEvent>) {
+        let now = Instant::now();
+        for (path, w) in self.services.iter_mut() {
+            w.refresh(path, now, events)
+        }
+    }
+
+    async fn run(
+        mut self,
+        mut updates: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+        mut tx: mpsc::Sender<ServiceEvent>,
+    ) {
+        let mut poll = time::interval(DISCOVERY_POLL);
+        let mut events = vec![];
+        while !tx.is_closed() {
+            select_biased! {
+                _ = poll.tick().fuse() => {
+                    if let Err(e) = self.poll(&mut events).await {
+                        warn!("failed to list services {}", e)
+                    }
+                    self.check_stale(&mut events)
+                },
+                batch = updates.select_next_some() => self.process(batch, &mut events),
+            }
+            for ev in events.drain(..) {
+                if tx.send(ev).await.is_err() {
+                    return;
+                }
+            }
+        }
+    }
+}
+
+/// Discover the services registered at paths matching `glob`, e.g.
+/// `/services/*`. The stream reports each service as it comes up,
+/// goes down, and disappears, until it is dropped. New services are
+/// found within `DISCOVERY_POLL`, and a service is reported down
+/// within `DISCOVERY_POLL` of missing `MISSED_HEARTBEATS` heartbeats.
+pub fn discover(
+    subscriber: &Subscriber,
+    glob: &Glob,
+) -> Result<impl Stream<Item = ServiceEvent> + Unpin> {
+    let beats = Glob::new(Chars::from(format!("{}/heartbeat", glob.raw())))?;
+    let (tx_updates, updates) = mpsc::channel(10);
+    let (tx, rx) = mpsc::channel(10);
+    let t = Discovery {
+        subscriber: subscriber.clone(),
+        filter: GlobSet::new(true, iter::once(beats))?,
+        services: HashMap::new(),
+        by_id: HashMap::new(),
+        tx_updates,
+    };
+    task::spawn(t.run(updates, tx));
+    Ok(rx)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use tokio::runtime::Runtime;
+
+    #[test]
+    fn service_info() {
+        let mut info = ServiceInfo::new("pricing", "1.2.3");
+        info.heartbeat = Duration::from_millis(250);
+        info.metadata.insert("rpc".into(), Value::from("/services/pricing/rpc"));
+        info.metadata.insert("shards".into(), Value::U64(4));
+        assert_eq!(ServiceInfo::decode(&info.encode()), Some(info));
+        assert_eq!(ServiceInfo::decode(&Value::U64(42)), None);
+    }
+
+    #[test]
+    fn discover_services() {
+        Runtime::new().unwrap().block_on(async {
+            let ctx = Ctx::new().await;
+            let to = Duration::from_secs(10);
+            let glob = Glob::new(Chars::from("/services/*")).unwrap();
+            let mut events = discover(&ctx.subscriber, &glob).unwrap();
+            let mut info = ServiceInfo::new("test", "1");
+            info.heartbeat = Duration::from_millis(100);
+            let base = Path::from("/services/test");
+            let service = Service::register(&ctx.publisher, base.clone(), info.clone())
+                .await
+                .unwrap();
+            let ev = time::timeout(to, events.next()).await.unwrap();
+            assert_eq!(ev, Some(ServiceEvent::Up(base.clone(), info)));
+            drop(service);
+            let ev = time::timeout(to, events.next()).await.unwrap();
+            assert_eq!(ev, Some(ServiceEvent::Down(base.clone())));
+            let ev = time::timeout(to, events.next()).await.unwrap();
+            assert_eq!(ev, Some(ServiceEvent::Gone(base)));
+        })
+    }
+}
@@ -0,0 +1,278 @@
+//! Many writer topics built on top of netidx.
+//!
+//! netidx values have a single writer, the publisher. A topic lets
+//! many clients publish messages to one logical stream. A `Broker`
+//! publishes three values under the topic's base path,
+//!
+//! * `base/publish`: clients write messages here. If the write asks
+//! for a reply the reply is the sequence number assigned to the
+//! message.
+//! * `base/stream`: every message is republished here, in the order
+//! the broker received it, as `[seq, message]`. Sequence numbers
+//! start at 1 and increase by 1 for each message.
+//! * `base/history`: an array of the last N `[seq, message]` pairs
+//! retained by the broker, so late joiners can catch up. Empty if the
+//! broker doesn't retain messages.
+//!
+//! Messages written by one client are delivered in the order that
+//! client wrote them. There is no ordering guarantee between
+//! messages written by different clients beyond the order in which
+//! the broker received them.
+use anyhow::Result;
+use futures::{
+    channel::{mpsc, oneshot},
+    prelude::*,
+    select_biased,
+};
+use log::warn;
+use netidx::{
+    path::Path,
+    pool::Pooled,
+    publisher::{Publisher, Val, Value, WriteRequest},
+    subscriber::{Dval, Event, Subscriber, UpdatesFlags},
+};
+use std::collections::VecDeque;
+use tokio::task;
+
+/// A message published to a topic
+#[derive(Debug, Clone, PartialEq)]
+pub struct Message {
+    pub seq: u64,
+    pub value: Value,
+}
+
+impl Message {
+    pub(crate) fn encode(&self) -> Value {
+        Value::from(vec![Value::U64(self.seq), self.value.clone()])
+    }
+
+    pub(crate) fn decode(v: &Value) -> Option<Message> {
+        match v {
+            Value::Array(a) if a.len() == 2 => match &a[0] {
+                Value::U64(seq) => Some(Message { seq: *seq, value: a[1].clone() }),
+                _ => None,
+            },
+            _ => None,
+        }
+    }
+}
+
+/// The broker of a topic. The topic is served until the broker is
+/// dropped.
+pub struct Broker {
+    _stop: oneshot::Sender<()>,
+}
+
+impl Broker {
+    /// Start a broker for the topic at `base`, retaining the last
+    /// `retain` messages for late joiners.
+    pub async fn new(publisher: &Publisher, base: Path, retain: usize) -> Result<Broker> {
+        let publish = publisher.publish(base.append("publish"), Value::Null)?;
+        let stream = publisher.publish(base.append("stream"), Value::Null)?;
+        let history =
+            publisher.publish(base.append("history"), Value::from(Vec::<Value>::new()))?;
+        let (tx_writes, writes) = mpsc::channel(3);
+        publisher.writes(publish.id(), tx_writes);
+        publisher.flushed().await;
+        let (_stop, stop) = oneshot::channel();
+        let t = BrokerTask {
+            publisher: publisher.clone(),
+            _publish: publish,
+            stream,
+            history,
+            retain,
+            retained: VecDeque::new(),
+            seq: 0,
+        };
+        task::spawn(t.run(writes, stop));
+        Ok(Broker { _stop })
+    }
+}
+
+struct BrokerTask {
+    publisher: Publisher,
+    _publish: Val,
+    stream: Val,
+    history: Val,
+    retain: usize,
+    retained: VecDeque<Value>,
+    seq: u64,
+}
+
+impl BrokerTask {
+    async fn process(&mut self, mut reqs: Pooled<Vec<WriteRequest>>) {
+        let mut batch = self.publisher.start_batch();
+        for req in reqs.drain(..) {
+            self.seq += 1;
+            let m = Message { seq: self.seq, value: req.value }.encode();
+            self.stream.update(&mut batch, m.clone());
+            self.retained.push_back(m);
+            while self.retained.len() > self.retain {
+                self.retained.pop_front();
+            }
+            if let Some(reply) = req.send_result {
+                reply.send(Value::U64(self.seq))
+            }
+        }
+        if self.retain > 0 {
+            let retained = Vec::from(self.retained.clone());
+            self.history.update(&mut batch, Value::from(retained));
+        }
+        batch.commit(None).await
+    }
+
+    async fn run(
+        mut self,
+        mut writes: mpsc::Receiver<Pooled<Vec<WriteRequest>>>,
+        stop: oneshot::Receiver<()>,
+    ) {
+        let mut stop = stop.fuse();
+        loop {
+            select_biased! {
+                _ = stop => break,
+                reqs = writes.next() => match reqs {
+                    None => break,
+                    Some(reqs) => self.process(reqs).await,
+                },
+            }
+        }
+    }
+}
+
+/// A client of a topic, it can both publish messages and receive
+/// them.
+#[derive(Debug, Clone)]
+pub struct Client {
+    publish: Dval,
+    stream: Dval,
+    history: Dval,
+}
+
+impl Client {
+    /// Connect to the topic at `base`
+    pub fn new(subscriber: &Subscriber, base: &Path) -> Client {
+        Client {
+            publish: subscriber.subscribe(base.append("publish")),
+            stream: subscriber.subscribe(base.append("stream")),
+            history: subscriber.subscribe(base.append("history")),
+        }
+    }
+
+    /// Publish a message to the topic. Returns false if the message
+    /// could not be queued because the topic is not currently
+    /// reachable.
+    pub fn publish(&self, v: Value) -> bool {
+        self.publish.write(v)
+    }
+
+    /// Publish a message to the topic and wait for the broker to
+    /// assign it a sequence number.
+    pub async fn publish_confirmed(&self, v: Value) -> Result<u64> {
+        match self.publish.write_with_recipt(v).await? {
+            Value::U64(seq) => Ok(seq),
+            v => bail!("unexpected reply from broker {}", v),
+        }
+    }
+
+    /// Receive messages from the topic. If `catch_up` is true the
+    /// stream will begin with the messages retained by the broker,
+    /// otherwise it will begin with the next message published. After
+    /// that every message published to the topic will be delivered in
+    /// sequence order, with no duplicates. If messages are lost,
+    /// e.g. because the broker restarted, the gap will be visible in
+    /// the sequence numbers.
+    pub fn messages(&self, catch_up: bool) -> mpsc::Receiver<Message> {
+        let (tx_up, mut rx_up) = mpsc::channel(3);
+        // Every message is delivered both in the stream and in the
+        // history, and the broker updates both in the same batch. So
+        // once we have the first history snapshot, the union of the
+        // two can't miss anything. Stream messages that arrive before
+        // the first snapshot are held until it arrives, and then only
+        // the ones newer than the snapshot are delivered.
+        let history = self.history.id();
+        if catch_up {
+            self.history.updates(UpdatesFlags::BEGIN_WITH_LAST, tx_up.clone());
+        }
+        self.stream.updates(UpdatesFlags::empty(), tx_up);
+        let (mut tx, rx) = mpsc::channel(100);
+        task::spawn(async move {
+            let mut last = 0;
+            let mut caught_up = !catch_up;
+            let mut held: Vec<Message> = Vec::new();
+            while let Some(mut batch) = rx_up.next().await {
+                for (id, ev) in batch.drain(..) {
+                    let msgs = match ev {
+                        Event::Unsubscribed(_) => vec![],
+                        Event::Update(Value::Array(a)) if id == history => {
+                            let mut msgs =
+                                a.iter().filter_map(Message::decode).collect::<Vec<_>>();
+                            if !caught_up {
+                                caught_up = true;
+                                msgs.extend(held.drain(..));
+                            }
+                            msgs
+                        }
+                        Event::Update(v) => match Message::decode(&v) {
+                            Some(m) if !caught_up => {
+                                held.push(m);
+                                vec![]
+                            }
+                            m => m.into_iter().collect(),
+                        },
+                    };
+                    for m in msgs {
+                        if m.seq < last && m.seq == 1 {
+                            warn!("topic sequence reset from {}, broker restarted?", last);
+                        } else if m.seq <= last {
+                            continue;
+                        }
+                        last = m.seq;
+                        if tx.send(m).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use std::time::Duration;
+    use tokio::{runtime::Runtime, time};
+
+    #[test]
+    fn fan_in() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let base = Path::from("/topic");
+            let _broker = Broker::new(&ctx.publisher, base.clone(), 2).await.unwrap();
+            let a = Client::new(&ctx.subscriber, &base);
+            let b = Client::new(&ctx.subscriber, &base);
+            for i in 1..4u64 {
+                let v = Value::U64(i * 10);
+                assert_eq!(a.publish_confirmed(v).await.unwrap(), i);
+            }
+            let mut msgs = b.messages(true);
+            let to = Duration::from_secs(5);
+            // depending on timing the snapshot we catch up from may
+            // be older than the last publish, but it must retain 2
+            // messages, and there must be no gaps or duplicates.
+            let mut seqs = vec![];
+            while seqs.last() != Some(&3) {
+                let m = time::timeout(to, msgs.next()).await.unwrap().unwrap();
+                assert_eq!(m.value, Value::U64(m.seq * 10));
+                seqs.push(m.seq);
+            }
+            assert!(seqs.len() >= 2);
+            assert!(seqs.windows(2).all(|w| w[1] == w[0] + 1));
+            assert_eq!(b.publish_confirmed(Value::U64(40)).await.unwrap(), 4);
+            let m = time::timeout(to, msgs.next()).await.unwrap().unwrap();
+            assert_eq!(m, Message { seq: 4, value: Value::U64(40) });
+        })
+    }
+}
@@ -1,10 +1,23 @@
-use netidx::{chars::Chars, protocol::value::Value};
+use anyhow::Result;
+use bytes::{Buf, BufMut};
+use futures::{channel::mpsc, SinkExt, StreamExt};
+use netidx::{
+    chars::Chars,
+    pack::{self, PackError},
+    path::Path,
+    protocol::value::Value,
+    publisher::{Id, Publisher, UpdateBatch, Val},
+    subscriber::{Dval, Event, Subscriber, UpdatesFlags},
+};
 use netidx_bscript::expr::{Expr, ExprKind};
 use std::{
     boxed,
     cmp::{PartialEq, PartialOrd},
     default::Default,
+    result,
+    time::Duration,
 };
+use tokio::task;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Keybind {
@@ -955,3 +968,273 @@ pub struct Widget {
     #[serde(default)]
     pub kind: WidgetKind,
 }
+
+/// The last path component of the path a view is published at
+pub const VIEW: &str = ".view";
+
+/// The version of the view definition format written by this crate,
+/// see `ViewDef`.
+pub const VERSION: u32 = 1;
+
+/// The path the view of `path` is published at
+pub fn view_path(path: &Path) -> Path {
+    path.append(VIEW)
+}
+
+/// A view definition, as served from the namespace. The browser
+/// displays the view published at `path/.view` (see `view_path`)
+/// when it navigates to `path`. A view may be published as,
+///
+/// - a string containing a json `ViewDef`, this is what `to_value`
+/// produces, and what a person can write by hand.
+/// - a string containing a json `Widget`. This is the original
+/// format, it is version 0, and the browser still saves views this
+/// way.
+/// - bytes containing a packed `ViewDef`
+///
+/// `from_value` accepts all three. A definition with a version newer
+/// than `VERSION` is rejected, so old clients fail loudly instead of
+/// silently misinterpreting a view.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ViewDef {
+    #[serde(default)]
+    pub version: u32,
+    /// A title for the view, e.g. for the browser window
+    #[serde(default)]
+    pub title: Option<String>,
+    pub root: Widget,
+}
+
+impl From<Widget> for ViewDef {
+    fn from(root: Widget) -> Self {
+        ViewDef { version: VERSION, title: None, root }
+    }
+}
+
+impl ViewDef {
+    pub fn new(root: Widget) -> Self {
+        Self::from(root)
+    }
+
+    pub fn title<T: Into<String>>(mut self, title: T) -> Self {
+        self.title = Some(title.into());
+        self
+    }
+
+    fn check_version(self) -> Result<Self> {
+        if self.version > VERSION {
+            bail!("view version {} is newer than supported {}", self.version, VERSION)
+        }
+        Ok(self)
+    }
+
+    /// Encode the definition as a json string value
+    pub fn to_value(&self) -> Result<Value> {
+        Ok(Value::String(Chars::from(serde_json::to_string(self)?)))
+    }
+
+    /// Decode a definition in any of the published formats
+    pub fn from_value(v: Value) -> Result<Self> {
+        match v {
+            Value::String(s) => {
+                let json = serde_json::from_str::<serde_json::Value>(&s)?;
+                match json.get("root") {
+                    Some(_) => serde_json::from_value::<ViewDef>(json)?.check_version(),
+                    None => {
+                        let root = serde_json::from_value::<Widget>(json)?;
+                        Ok(ViewDef { version: 0, title: None, root })
+                    }
+                }
+            }
+            Value::Bytes(mut b) => {
+                <ViewDef as pack::Pack>::decode(&mut b)?.check_version()
+            }
+            v => bail!("expected a view definition, got {}", v),
+        }
+    }
+}
+
+// the widget tree is packed as it's json representation, because
+// expressions are only serializable as their source text
+impl pack::Pack for ViewDef {
+    fn encoded_len(&self) -> usize {
+        let json = serde_json::to_string(&self.root).unwrap_or_default();
+        pack::len_wrapped_len(
+            <u32 as pack::Pack>::encoded_len(&self.version)
+                + <Option<String> as pack::Pack>::encoded_len(&self.title)
+                + <String as pack::Pack>::encoded_len(&json),
+        )
+    }
+
+    fn encode(&self, buf: &mut impl BufMut) -> result::Result<(), PackError> {
+        let json =
+            serde_json::to_string(&self.root).map_err(|_| PackError::InvalidFormat)?;
+        pack::len_wrapped_encode(buf, self, |buf| {
+            <u32 as pack::Pack>::encode(&self.version, buf)?;
+            <Option<String> as pack::Pack>::encode(&self.title, buf)?;
+            <String as pack::Pack>::encode(&json, buf)
+        })
+    }
+
+    fn decode(buf: &mut impl Buf) -> result::Result<Self, PackError> {
+        pack::len_wrapped_decode(buf, |buf| {
+            let version = <u32 as pack::Pack>::decode(buf)?;
+            let title = <Option<String> as pack::Pack>::decode(buf)?;
+            let json = <String as pack::Pack>::decode(buf)?;
+            let root = serde_json::from_str::<Widget>(&json)
+                .map_err(|_| PackError::InvalidFormat)?;
+            Ok(ViewDef { version, title, root })
+        })
+    }
+}
+
+/// A view published in the namespace, it stays published until it
+/// is dropped.
+pub struct PublishedView {
+    val: Val,
+}
+
+impl PublishedView {
+    /// Publish `def` as the view of `path`
+    pub fn new(publisher: &Publisher, path: &Path, def: &ViewDef) -> Result<Self> {
+        let val = publisher.publish(view_path(path), def.to_value()?)?;
+        Ok(PublishedView { val })
+    }
+
+    /// Queue replacing the published definition with `def`.
+    /// Clients subscribed to the view will receive the new one.
+    pub fn update(&self, batch: &mut UpdateBatch, def: &ViewDef) -> Result<()> {
+        Ok(self.val.update(batch, def.to_value()?))
+    }
+
+    pub fn id(&self) -> Id {
+        self.val.id()
+    }
+}
+
+/// Fetch the current view of `path`
+pub async fn fetch(
+    subscriber: &Subscriber,
+    path: &Path,
+    timeout: Option<Duration>,
+) -> Result<ViewDef> {
+    let val = subscriber.subscribe_nondurable_one(view_path(path), timeout).await?;
+    match val.last() {
+        Event::Update(v) => ViewDef::from_value(v),
+        Event::Unsubscribed(_) => bail!("the view of {} was unpublished", path),
+    }
+}
+
+/// A durable subscription to the view of a path
+#[derive(Debug, Clone)]
+pub struct ViewSubscription {
+    dval: Dval,
+}
+
+impl ViewSubscription {
+    pub fn new(subscriber: &Subscriber, path: &Path) -> Self {
+        ViewSubscription { dval: subscriber.subscribe(view_path(path)) }
+    }
+
+    /// The current definition, or None if the view isn't currently
+    /// subscribed.
+    pub fn current(&self) -> Option<Result<ViewDef>> {
+        match self.dval.last() {
+            Event::Update(v) => Some(ViewDef::from_value(v)),
+            Event::Unsubscribed(_) => None,
+        }
+    }
+
+    /// Receive every new definition, starting with the current one.
+    /// Definitions that fail to decode are delivered as errors, so
+    /// the caller can keep displaying the last good one.
+    pub fn updates(&self) -> mpsc::Receiver<Result<ViewDef>> {
+        let (tx_up, mut rx_up) = mpsc::channel(3);
+        self.dval.updates(UpdatesFlags::BEGIN_WITH_LAST, tx_up);
+        let (mut tx, rx) = mpsc::channel(3);
+        task::spawn(async move {
+            while let Some(mut batch) = rx_up.next().await {
+                for (_, ev) in batch.drain(..) {
+                    if let Event::Update(v) = ev {
+                        if tx.send(ViewDef::from_value(v)).await.is_err() {
+                            return;
+                        }
+                    }
+                }
+            }
+        });
+        rx
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::channel::test::Ctx;
+    use bytes::BytesMut;
+    use tokio::{runtime::Runtime, time};
+
+    fn text(s: &str) -> Expr {
+        ExprKind::Constant(Value::String(Chars::from(String::from(s)))).to_expr()
+    }
+
+    fn label(s: &str) -> Widget {
+        let label = Label { text: text(s), ..Label::default() };
+        Widget { props: None, kind: WidgetKind::Label(label) }
+    }
+
+    fn label_text(def: &ViewDef) -> Expr {
+        match &def.root.kind {
+            WidgetKind::Label(l) => l.text.clone(),
+            k => panic!("expected a label got {:?}", k),
+        }
+    }
+
+    #[test]
+    fn formats() {
+        let def = ViewDef::new(label("hello")).title("greeting");
+        let d = ViewDef::from_value(def.to_value().unwrap()).unwrap();
+        assert_eq!((d.version, d.title.as_deref()), (VERSION, Some("greeting")));
+        assert_eq!(label_text(&d), text("hello"));
+        // version 0, a bare widget
+        let json = serde_json::to_string(&def.root).unwrap();
+        let d = ViewDef::from_value(Value::String(Chars::from(json))).unwrap();
+        assert_eq!((d.version, d.title), (0, None));
+        assert_eq!(label_text(&d), text("hello"));
+        // packed
+        let mut buf = BytesMut::new();
+        pack::Pack::encode(&def, &mut buf).unwrap();
+        assert_eq!(buf.len(), pack::Pack::encoded_len(&def));
+        let d = ViewDef::from_value(Value::Bytes(buf.freeze())).unwrap();
+        assert_eq!((d.version, d.title.as_deref()), (VERSION, Some("greeting")));
+        assert_eq!(label_text(&d), text("hello"));
+        // from a newer version
+        let newer = ViewDef { version: VERSION + 1, ..def };
+        assert!(ViewDef::from_value(newer.to_value().unwrap()).is_err());
+        assert!(ViewDef::from_value(Value::U64(42)).is_err());
+    }
+
+    #[test]
+    fn publish_subscribe() {
+        Runtime::new().unwrap().block_on(async move {
+            let ctx = Ctx::new().await;
+            let path = Path::from("/app");
+            let def = ViewDef::new(label("v1"));
+            let view = PublishedView::new(&ctx.publisher, &path, &def).unwrap();
+            ctx.publisher.flushed().await;
+            let to = Duration::from_secs(5);
+            let d = fetch(&ctx.subscriber, &path, Some(to)).await.unwrap();
+            assert_eq!(label_text(&d), text("v1"));
+            let sub = ViewSubscription::new(&ctx.subscriber, &path);
+            let mut updates = sub.updates();
+            let d = time::timeout(to, updates.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(label_text(&d), text("v1"));
+            let mut batch = ctx.publisher.start_batch();
+            view.update(&mut batch, &ViewDef::new(label("v2"))).unwrap();
+            batch.commit(None).await;
+            let d = time::timeout(to, updates.next()).await.unwrap().unwrap().unwrap();
+            assert_eq!(label_text(&d), text("v2"));
+            assert_eq!(label_text(&sub.current().unwrap().unwrap()), text("v2"));
+        })
+    }
+}
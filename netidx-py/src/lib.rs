@@ -0,0 +1,67 @@
+//! Python bindings for the netidx subscriber and publisher.
+//!
+//! ```python
+//! import asyncio, netidx
+//!
+//! async def main():
+//!     sub = netidx.Subscriber()
+//!     dv = sub.subscribe("/local/foo")
+//!     async for batch in sub.updates([dv]):
+//!         for path, value in batch:
+//!             print(path, value)
+//!
+//! asyncio.run(main())
+//! ```
+//!
+//! Updates are delivered in batches. While python is busy with one
+//! batch, everything that arrives is queued up and handed over in
+//! the next, so the GIL is taken once per batch instead of once per
+//! update. All the networking runs on a shared tokio runtime, and
+//! the async methods return awaitables that can be used from
+//! asyncio.
+mod publisher;
+mod subscriber;
+mod value;
+
+use anyhow::Result;
+use netidx::{config::Config, resolver_client::DesiredAuth};
+use pyo3::{create_exception, exceptions::PyException, prelude::*};
+
+create_exception!(netidx, NetidxError, PyException, "An error from netidx");
+
+pub(crate) fn err(e: anyhow::Error) -> PyErr {
+    NetidxError::new_err(format!("{:#}", e))
+}
+
+// load the config, or the default config, and parse the desired auth
+pub(crate) fn config(
+    config: Option<&str>,
+    auth: Option<&str>,
+) -> Result<(Config, DesiredAuth)> {
+    let cfg = match config {
+        None => Config::load_default()?,
+        Some(path) => Config::load(path)?,
+    };
+    let auth = match auth {
+        None => cfg.default_auth(),
+        Some(auth) => auth.parse()?,
+    };
+    Ok((cfg, auth))
+}
+
+#[pymodule]
+#[pyo3(name = "netidx")]
+fn netidx_py(m: &Bound<'_, PyModule>) -> PyResult<()> {
+    m.add("NetidxError", m.py().get_type_bound::<NetidxError>())?;
+    m.add_class::<value::OkValue>()?;
+    m.add_class::<value::Error>()?;
+    m.add_class::<subscriber::Subscriber>()?;
+    m.add_class::<subscriber::Dval>()?;
+    m.add_class::<subscriber::Updates>()?;
+    m.add_class::<subscriber::Unsubscribed>()?;
+    m.add_class::<publisher::Publisher>()?;
+    m.add_class::<publisher::Val>()?;
+    m.add_class::<publisher::UpdateBatch>()?;
+    m.add_class::<publisher::Writes>()?;
+    Ok(())
+}
@@ -0,0 +1,185 @@
+use crate::{
+    err,
+    value::{from_py, to_py},
+};
+use futures::{channel::mpsc, prelude::*};
+use netidx::{
+    path::Path,
+    pool::Pooled,
+    publisher::{self, BindCfg, WriteRequest},
+};
+use pyo3::{
+    exceptions::{PyStopAsyncIteration, PyValueError},
+    prelude::*,
+    types::PyList,
+};
+use pyo3_async_runtimes::tokio::{future_into_py, get_runtime};
+use std::sync::{Arc, Mutex};
+
+/// A netidx publisher
+#[pyclass(module = "netidx", frozen)]
+pub struct Publisher(publisher::Publisher);
+
+#[pymethods]
+impl Publisher {
+    /// Create a publisher. `config` and `auth` are the same as for
+    /// `Subscriber`. `bind` is the bind config, e.g. "local" or
+    /// "192.168.0.0/16", if it is omitted the default from the config
+    /// is used.
+    #[new]
+    #[pyo3(signature = (config=None, auth=None, bind=None, max_clients=768))]
+    fn new(
+        py: Python<'_>,
+        config: Option<&str>,
+        auth: Option<&str>,
+        bind: Option<&str>,
+        max_clients: usize,
+    ) -> PyResult<Self> {
+        let (cfg, auth) = crate::config(config, auth).map_err(err)?;
+        let bind = match bind {
+            None => cfg.default_bind_config.clone(),
+            Some(bind) => bind.parse::<BindCfg>().map_err(err)?,
+        };
+        let publisher = py.allow_threads(|| {
+            get_runtime().block_on(publisher::Publisher::new(
+                cfg,
+                auth,
+                bind,
+                max_clients,
+            ))
+        });
+        Ok(Publisher(publisher.map_err(err)?))
+    }
+
+    /// Publish `value` at `path`. The value is published until the
+    /// returned `Val` is garbage collected.
+    fn publish(&self, path: String, value: &Bound<'_, PyAny>) -> PyResult<Val> {
+        let value = from_py(value)?;
+        let _rt = get_runtime().enter();
+        let path = Path::from(path);
+        let val = self.0.publish(path.clone(), value).map_err(err)?;
+        Ok(Val { val, path, publisher: self.0.clone() })
+    }
+
+    /// Start a new batch of updates
+    fn start_batch(&self) -> UpdateBatch {
+        UpdateBatch(Mutex::new(Some(self.0.start_batch())))
+    }
+
+    /// Wait until all the updates queued so far have been sent
+    fn flushed<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let publisher = self.0.clone();
+        future_into_py(py, async move {
+            publisher.flushed().await;
+            Ok(())
+        })
+    }
+
+    /// Return an async iterator of the writes to `vals`. Each item is
+    /// a list of `(path, value)` pairs. Values that are not passed
+    /// here don't accept writes.
+    fn writes(&self, vals: Vec<PyRef<'_, Val>>) -> Writes {
+        let (tx, rx) = mpsc::channel(3);
+        for val in vals {
+            self.0.writes(val.val.id(), tx.clone());
+        }
+        Writes(Arc::new(tokio::sync::Mutex::new(rx)))
+    }
+}
+
+/// A published value
+#[pyclass(module = "netidx", frozen)]
+pub struct Val {
+    val: publisher::Val,
+    path: Path,
+    publisher: publisher::Publisher,
+}
+
+#[pymethods]
+impl Val {
+    #[getter]
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// Update the value. Updates made this way are batched up and
+    /// sent automatically, use `UpdateBatch` for explicit control.
+    fn update(&self, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let value = from_py(value)?;
+        let _rt = get_runtime().enter();
+        self.val.update_auto(&self.publisher, value);
+        Ok(())
+    }
+
+    fn __repr__(&self) -> String {
+        format!("netidx.Val({:?})", &*self.path)
+    }
+}
+
+/// A batch of updates, nothing is sent until it is committed
+#[pyclass(module = "netidx", frozen)]
+pub struct UpdateBatch(Mutex<Option<publisher::UpdateBatch>>);
+
+#[pymethods]
+impl UpdateBatch {
+    /// Queue an update of `val` to `value`
+    fn update(&self, val: PyRef<'_, Val>, value: &Bound<'_, PyAny>) -> PyResult<()> {
+        let value = from_py(value)?;
+        match &mut *self.0.lock().unwrap() {
+            None => Err(PyValueError::new_err("the batch was already committed")),
+            Some(batch) => {
+                val.val.update(batch, value);
+                Ok(())
+            }
+        }
+    }
+
+    /// Send the queued updates, returns an awaitable that completes
+    /// when they have been sent to every subscriber.
+    fn commit<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        match self.0.lock().unwrap().take() {
+            None => Err(PyValueError::new_err("the batch was already committed")),
+            Some(batch) => future_into_py(py, async move {
+                batch.commit(None).await;
+                Ok(())
+            }),
+        }
+    }
+
+    fn __len__(&self) -> usize {
+        self.0.lock().unwrap().as_ref().map(|b| b.len()).unwrap_or(0)
+    }
+}
+
+/// An async iterator of batches of writes
+#[pyclass(module = "netidx", frozen)]
+pub struct Writes(Arc<tokio::sync::Mutex<mpsc::Receiver<Pooled<Vec<WriteRequest>>>>>);
+
+#[pymethods]
+impl Writes {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let rx = self.0.clone();
+        future_into_py(py, async move {
+            let mut rx = rx.lock().await;
+            let mut batches = match rx.next().await {
+                None => return Err(PyStopAsyncIteration::new_err(())),
+                Some(batch) => vec![batch],
+            };
+            while let Ok(Some(batch)) = rx.try_next() {
+                batches.push(batch)
+            }
+            drop(rx);
+            Python::with_gil(|py| {
+                let mut items = Vec::new();
+                for req in batches.iter().flat_map(|b| b.iter()) {
+                    items.push((&*req.path, to_py(py, &req.value)?).into_py(py))
+                }
+                Ok(PyList::new_bound(py, items).unbind())
+            })
+        })
+    }
+}
@@ -0,0 +1,176 @@
+use crate::{
+    err,
+    value::{from_py, to_py},
+};
+use futures::{channel::mpsc, prelude::*};
+use fxhash::FxHashMap;
+use netidx::{
+    path::Path,
+    pool::Pooled,
+    subscriber::{self, Event, SubId, UpdatesFlags},
+};
+use pyo3::{exceptions::PyStopAsyncIteration, prelude::*, types::PyList};
+use pyo3_async_runtimes::tokio::{future_into_py, get_runtime};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+
+type Batch = Pooled<Vec<(SubId, Event)>>;
+
+/// The value of a subscription that ended, `reason` says why
+#[pyclass(module = "netidx", frozen)]
+pub struct Unsubscribed {
+    #[pyo3(get)]
+    reason: String,
+}
+
+#[pymethods]
+impl Unsubscribed {
+    fn __repr__(&self) -> String {
+        format!("netidx.Unsubscribed({:?})", self.reason)
+    }
+}
+
+fn event_to_py(py: Python<'_>, ev: &Event) -> PyResult<PyObject> {
+    match ev {
+        Event::Update(v) => to_py(py, v),
+        Event::Unsubscribed(reason) => {
+            let reason = format!("{:?}", reason);
+            Ok(Py::new(py, Unsubscribed { reason })?.into_py(py))
+        }
+    }
+}
+
+/// A netidx subscriber
+#[pyclass(module = "netidx", frozen)]
+pub struct Subscriber(subscriber::Subscriber);
+
+#[pymethods]
+impl Subscriber {
+    /// Create a subscriber. `config` is the path to the client
+    /// config, if it is omitted the default config is loaded. `auth`
+    /// is one of "anonymous", "local", "krb5", or "tls", if it is
+    /// omitted the default from the config is used.
+    #[new]
+    #[pyo3(signature = (config=None, auth=None))]
+    fn new(config: Option<&str>, auth: Option<&str>) -> PyResult<Self> {
+        let (cfg, auth) = crate::config(config, auth).map_err(err)?;
+        let _rt = get_runtime().enter();
+        Ok(Subscriber(subscriber::Subscriber::new(cfg, auth).map_err(err)?))
+    }
+
+    /// Subscribe to `path`. The subscription is durable, it is
+    /// retried until it succeeds, and if it dies it is resubscribed.
+    fn subscribe(&self, path: String) -> Dval {
+        let _rt = get_runtime().enter();
+        let path = Path::from(path);
+        Dval { dval: self.0.subscribe(path.clone()), path }
+    }
+
+    /// Return an async iterator of the updates to `dvals`. Each item
+    /// is a list of `(path, value)` pairs, holding everything that
+    /// arrived since the last item was taken. If `begin_with_last` is
+    /// true the current value of each subscription comes first. If
+    /// `dedup` is true an update equal to the previous value of the
+    /// same subscription is dropped.
+    #[pyo3(signature = (dvals, begin_with_last=true, dedup=false))]
+    fn updates(
+        &self,
+        dvals: Vec<PyRef<'_, Dval>>,
+        begin_with_last: bool,
+        dedup: bool,
+    ) -> Updates {
+        let mut flags = UpdatesFlags::empty();
+        if begin_with_last {
+            flags |= UpdatesFlags::BEGIN_WITH_LAST;
+        }
+        if dedup {
+            flags |= UpdatesFlags::DEDUP;
+        }
+        let (tx, rx) = mpsc::channel(3);
+        let mut paths = FxHashMap::default();
+        for dv in dvals {
+            paths.insert(dv.dval.id(), dv.path.clone());
+            dv.dval.updates(flags, tx.clone());
+        }
+        Updates { rx: Arc::new(Mutex::new(rx)), paths: Arc::new(paths) }
+    }
+}
+
+/// A durable subscription
+#[pyclass(module = "netidx", frozen)]
+pub struct Dval {
+    dval: subscriber::Dval,
+    path: Path,
+}
+
+#[pymethods]
+impl Dval {
+    #[getter]
+    fn path(&self) -> &str {
+        &self.path
+    }
+
+    /// The last value received, or `Unsubscribed` if the
+    /// subscription isn't currently alive.
+    fn last(&self, py: Python<'_>) -> PyResult<PyObject> {
+        event_to_py(py, &self.dval.last())
+    }
+
+    /// Wait until the subscription succeeds
+    fn wait_subscribed<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let dval = self.dval.clone();
+        future_into_py(py, async move { dval.wait_subscribed().await.map_err(err) })
+    }
+
+    /// Write `value` to the publisher. Returns true if the write was
+    /// sent, and false if it was queued to be sent when the
+    /// subscription succeeds.
+    fn write(&self, value: &Bound<'_, PyAny>) -> PyResult<bool> {
+        Ok(self.dval.write(from_py(value)?))
+    }
+
+    fn __repr__(&self) -> String {
+        format!("netidx.Dval({:?})", &*self.path)
+    }
+}
+
+/// An async iterator of batches of updates
+#[pyclass(module = "netidx", frozen)]
+pub struct Updates {
+    rx: Arc<Mutex<mpsc::Receiver<Batch>>>,
+    paths: Arc<FxHashMap<SubId, Path>>,
+}
+
+#[pymethods]
+impl Updates {
+    fn __aiter__(slf: PyRef<'_, Self>) -> PyRef<'_, Self> {
+        slf
+    }
+
+    fn __anext__<'py>(&self, py: Python<'py>) -> PyResult<Bound<'py, PyAny>> {
+        let rx = self.rx.clone();
+        let paths = self.paths.clone();
+        future_into_py(py, async move {
+            let mut rx = rx.lock().await;
+            let mut batches = match rx.next().await {
+                None => return Err(PyStopAsyncIteration::new_err(())),
+                Some(batch) => vec![batch],
+            };
+            // take everything else that is ready so the gil is only
+            // taken once for all of it
+            while let Ok(Some(batch)) = rx.try_next() {
+                batches.push(batch)
+            }
+            drop(rx);
+            Python::with_gil(|py| {
+                let mut items = Vec::new();
+                for (id, ev) in batches.iter().flat_map(|b| b.iter()) {
+                    if let Some(path) = paths.get(id) {
+                        items.push((&**path, event_to_py(py, ev)?).into_py(py))
+                    }
+                }
+                Ok(PyList::new_bound(py, items).unbind())
+            })
+        })
+    }
+}
@@ -0,0 +1,223 @@
+//! Conversion between netidx values and python objects.
+//!
+//! | netidx                 | python                            |
+//! |------------------------|-----------------------------------|
+//! | integers               | `int`                             |
+//! | `F32`, `F64`           | `float`                           |
+//! | `Decimal`              | `decimal.Decimal`                 |
+//! | `DateTime`             | `datetime.datetime` in UTC        |
+//! | `Duration`             | `datetime.timedelta`              |
+//! | `String`               | `str`                             |
+//! | `Bytes`                | `bytes`                           |
+//! | `True`, `False`        | `bool`                            |
+//! | `Null`                 | `None`                            |
+//! | `Ok`                   | `netidx.Ok`                       |
+//! | `Error`, `ErrorInfo`   | `netidx.Error`                    |
+//! | `Array`                | `list`, tuples are also accepted  |
+//!
+//! Python ints become `I64`, or `U64` if they are too big for an
+//! `I64`. Naive datetimes are taken to be in UTC.
+use bytes::Bytes;
+use chrono::prelude::*;
+use netidx::{chars::Chars, protocol::value::ErrorInfo, publisher::Value};
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{IntoPyDict, PyBool, PyBytes, PyFloat, PyList, PyLong, PyString, PyTuple},
+};
+use rust_decimal::Decimal;
+use std::{str::FromStr, sync::Arc, time::Duration};
+
+/// The netidx `Ok` value
+#[pyclass(module = "netidx", name = "Ok", frozen)]
+pub struct OkValue;
+
+#[pymethods]
+impl OkValue {
+    #[new]
+    fn new() -> Self {
+        OkValue
+    }
+
+    fn __repr__(&self) -> &'static str {
+        "netidx.Ok()"
+    }
+
+    fn __eq__(&self, other: &Bound<'_, PyAny>) -> bool {
+        other.is_instance_of::<OkValue>()
+    }
+}
+
+/// A netidx error value. `code` and `payload` are only set for
+/// errors that carry them.
+#[pyclass(module = "netidx", frozen)]
+pub struct Error {
+    #[pyo3(get)]
+    message: String,
+    #[pyo3(get)]
+    code: Option<u32>,
+    payload: Option<PyObject>,
+}
+
+#[pymethods]
+impl Error {
+    #[new]
+    #[pyo3(signature = (message, code=None, payload=None))]
+    fn new(message: String, code: Option<u32>, payload: Option<PyObject>) -> Self {
+        Error { message, code, payload }
+    }
+
+    #[getter]
+    fn payload(&self, py: Python<'_>) -> Option<PyObject> {
+        self.payload.as_ref().map(|p| p.clone_ref(py))
+    }
+
+    fn __repr__(&self) -> String {
+        match self.code {
+            None => format!("netidx.Error({:?})", self.message),
+            Some(code) => format!("netidx.Error({:?}, code={})", self.message, code),
+        }
+    }
+}
+
+fn micros(py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+    py.import_bound("datetime")?.getattr("timedelta")?.call((0, 0, 1), None)
+}
+
+fn epoch(py: Python<'_>) -> PyResult<Bound<'_, PyAny>> {
+    let datetime = py.import_bound("datetime")?;
+    let utc = datetime.getattr("timezone")?.getattr("utc")?;
+    datetime.getattr("datetime")?.call((1970, 1, 1, 0, 0, 0, 0, utc), None)
+}
+
+/// Convert a netidx value to a python object
+pub fn to_py(py: Python<'_>, v: &Value) -> PyResult<PyObject> {
+    Ok(match v {
+        Value::U32(i) | Value::V32(i) => (*i).into_py(py),
+        Value::I32(i) | Value::Z32(i) => (*i).into_py(py),
+        Value::U64(i) | Value::V64(i) => (*i).into_py(py),
+        Value::I64(i) | Value::Z64(i) => (*i).into_py(py),
+        Value::F32(f) => (*f).into_py(py),
+        Value::F64(f) => (*f).into_py(py),
+        Value::Decimal(d) => {
+            let decimal = py.import_bound("decimal")?.getattr("Decimal")?;
+            decimal.call1((d.to_string(),))?.into_py(py)
+        }
+        Value::DateTime(dt) => {
+            let us = micros(py)?.call_method1("__mul__", (dt.timestamp_micros(),))?;
+            epoch(py)?.call_method1("__add__", (us,))?.into_py(py)
+        }
+        Value::Duration(d) => {
+            let us = u64::try_from(d.as_micros()).unwrap_or(u64::MAX);
+            micros(py)?.call_method1("__mul__", (us,))?.into_py(py)
+        }
+        Value::String(s) => (&**s).into_py(py),
+        Value::Bytes(b) => PyBytes::new_bound(py, &b[..]).into_py(py),
+        Value::True => true.into_py(py),
+        Value::False => false.into_py(py),
+        Value::Null => py.None(),
+        Value::Ok => Py::new(py, OkValue)?.into_py(py),
+        Value::Error(e) => {
+            Py::new(py, Error { message: e.to_string(), code: None, payload: None })?
+                .into_py(py)
+        }
+        Value::ErrorInfo(e) => {
+            let payload = e.payload.as_ref().map(|p| to_py(py, p)).transpose()?;
+            let e = Error { message: e.message.to_string(), code: Some(e.code), payload };
+            Py::new(py, e)?.into_py(py)
+        }
+        Value::Array(a) => {
+            let elts = a.iter().map(|v| to_py(py, v)).collect::<PyResult<Vec<_>>>()?;
+            PyList::new_bound(py, elts).into_py(py)
+        }
+    })
+}
+
+/// Convert a python object to a netidx value
+pub fn from_py(ob: &Bound<'_, PyAny>) -> PyResult<Value> {
+    let py = ob.py();
+    if ob.is_none() {
+        return Ok(Value::Null);
+    }
+    // bool is a subclass of int, so it must come first
+    if ob.is_instance_of::<PyBool>() {
+        return Ok(if ob.extract::<bool>()? { Value::True } else { Value::False });
+    }
+    if ob.is_instance_of::<PyLong>() {
+        return match ob.extract::<i64>() {
+            Ok(i) => Ok(Value::I64(i)),
+            Err(_) => Ok(Value::U64(ob.extract::<u64>()?)),
+        };
+    }
+    if ob.is_instance_of::<PyFloat>() {
+        return Ok(Value::F64(ob.extract::<f64>()?));
+    }
+    if let Ok(s) = ob.downcast::<PyString>() {
+        return Ok(Value::String(Chars::from(s.to_str()?.to_string())));
+    }
+    if let Ok(b) = ob.downcast::<PyBytes>() {
+        return Ok(Value::Bytes(Bytes::copy_from_slice(b.as_bytes())));
+    }
+    if let Ok(l) = ob.downcast::<PyList>() {
+        let elts = l.iter().map(|v| from_py(&v)).collect::<PyResult<Vec<_>>>()?;
+        return Ok(Value::Array(Arc::from(elts)));
+    }
+    if let Ok(t) = ob.downcast::<PyTuple>() {
+        let elts = t.iter().map(|v| from_py(&v)).collect::<PyResult<Vec<_>>>()?;
+        return Ok(Value::Array(Arc::from(elts)));
+    }
+    if ob.is_instance_of::<OkValue>() {
+        return Ok(Value::Ok);
+    }
+    if let Ok(e) = ob.downcast::<Error>() {
+        let e = e.get();
+        return Ok(match e.code {
+            None => Value::Error(Chars::from(e.message.clone())),
+            Some(code) => {
+                let payload = match &e.payload {
+                    None => None,
+                    Some(p) => Some(from_py(p.bind(py))?),
+                };
+                let message = Chars::from(e.message.clone());
+                Value::ErrorInfo(Arc::new(ErrorInfo { code, message, payload }))
+            }
+        });
+    }
+    let datetime = py.import_bound("datetime")?;
+    if ob.is_instance(&datetime.getattr("datetime")?)? {
+        let ob = if ob.getattr("tzinfo")?.is_none() {
+            let utc = datetime.getattr("timezone")?.getattr("utc")?;
+            let kw = [("tzinfo", utc)].into_py_dict_bound(py);
+            ob.call_method("replace", (), Some(&kw))?
+        } else {
+            ob.clone()
+        };
+        let d = ob.call_method1("__sub__", (epoch(py)?,))?;
+        let us: i64 = d.call_method1("__floordiv__", (micros(py)?,))?.extract()?;
+        let secs = us.div_euclid(1_000_000);
+        let nsecs = us.rem_euclid(1_000_000) as u32 * 1000;
+        return match DateTime::<Utc>::from_timestamp(secs, nsecs) {
+            Some(dt) => Ok(Value::DateTime(dt)),
+            None => Err(PyValueError::new_err("datetime out of range")),
+        };
+    }
+    if ob.is_instance(&datetime.getattr("timedelta")?)? {
+        let us: i64 = ob.call_method1("__floordiv__", (micros(py)?,))?.extract()?;
+        return match u64::try_from(us) {
+            Ok(us) => Ok(Value::Duration(Duration::from_micros(us))),
+            Err(_) => Err(PyValueError::new_err("negative durations are not supported")),
+        };
+    }
+    let decimal = py.import_bound("decimal")?.getattr("Decimal")?;
+    if ob.is_instance(&decimal)? {
+        let s = ob.str()?;
+        return match Decimal::from_str(s.to_str()?) {
+            Ok(d) => Ok(Value::Decimal(d)),
+            Err(e) => Err(PyValueError::new_err(e.to_string())),
+        };
+    }
+    Err(PyValueError::new_err(format!(
+        "can't convert {} to a netidx value",
+        ob.get_type().name()?
+    )))
+}
@@ -0,0 +1,94 @@
+use anyhow::Result;
+use netidx::resolver_server::{
+    acl::{self, Acl, Permissions},
+    config::Config,
+};
+use std::{convert::TryFrom, process};
+use structopt::StructOpt;
+use tokio::runtime::Runtime;
+
+#[derive(StructOpt, Debug)]
+pub(super) enum AclCmd {
+    #[structopt(name = "check", about = "explain the permissions of a user at a path")]
+    Check {
+        #[structopt(short = "c", long = "config", help = "path to the server config")]
+        config: String,
+        #[structopt(
+            long = "id",
+            help = "index of the member server to evaluate as",
+            default_value = "0"
+        )]
+        id: usize,
+        #[structopt(
+            long = "group",
+            help = "use these groups instead of looking up the user, the first is primary"
+        )]
+        groups: Vec<String>,
+        #[structopt(name = "user", help = "the user, or \"\" for anonymous")]
+        user: String,
+        #[structopt(name = "path")]
+        path: String,
+        #[structopt(
+            name = "action",
+            help = "subscribe, write, list, publish, publish-default, delegate, or perm bits"
+        )]
+        action: String,
+    },
+    #[structopt(name = "lint", about = "find invalid, unreachable, or shadowed rules")]
+    Lint {
+        #[structopt(short = "c", long = "config", help = "path to the server config")]
+        config: String,
+    },
+}
+
+fn parse_action(s: &str) -> Result<Permissions> {
+    Ok(match s {
+        "subscribe" => Permissions::SUBSCRIBE,
+        "write" => Permissions::WRITE,
+        "list" => Permissions::LIST,
+        "publish" => Permissions::PUBLISH,
+        "publish-default" => Permissions::PUBLISH_DEFAULT,
+        "delegate" => Permissions::DELEGATE,
+        s => match Permissions::try_from(s)? {
+            p if p.contains(Permissions::DENY) => bail!("an action can't be a deny"),
+            p if p.is_empty() => bail!("an action must request some permission"),
+            p => p,
+        },
+    })
+}
+
+pub(super) fn run(cmd: AclCmd) {
+    match cmd {
+        AclCmd::Check { config, id, groups, user, path, action } => {
+            let config = Config::load(config).expect("failed to load server config");
+            let desired = parse_action(&action).expect("invalid action");
+            let rt = Runtime::new().expect("failed to init runtime");
+            let explanation = rt.block_on(async {
+                let mut acl = Acl::new(&config, id).expect("invalid permissions");
+                let user = if user == "" {
+                    acl.anonymous()
+                } else if groups.is_empty() {
+                    acl.user(&user).expect("failed to look up user")
+                } else {
+                    let primary = &groups[0];
+                    acl.user_with_groups(&user, primary, groups[1..].iter().map(|s| &**s))
+                };
+                acl.explain(&user, &path, desired)
+            });
+            println!("{}", explanation);
+            if !explanation.allowed() {
+                process::exit(1)
+            }
+        }
+        AclCmd::Lint { config } => {
+            let config = Config::load(config).expect("failed to load server config");
+            let lints = acl::lint(&config);
+            for lint in lints.iter() {
+                println!("{}", lint);
+            }
+            if !lints.is_empty() {
+                process::exit(1)
+            }
+        }
+    }
+}
@@ -0,0 +1,382 @@
+use anyhow::Result;
+use chrono::prelude::*;
+use netidx::{
+    chars::Chars,
+    path::Path,
+    protocol::glob::{Glob, GlobSet},
+    subscriber::Value,
+};
+use netidx_archive::{ArchiveReader, Seek};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    ops::Bound,
+    path::PathBuf,
+    str::FromStr,
+};
+use structopt::StructOpt;
+
+#[derive(StructOpt, Debug)]
+pub(super) enum ArchiveCmd {
+    #[structopt(
+        name = "export",
+        about = "export archived values as a table aligned on a time grid"
+    )]
+    Export(ExportParams),
+}
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Format {
+    Csv,
+    Parquet,
+}
+
+impl FromStr for Format {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.trim() {
+            "csv" => Ok(Format::Csv),
+            "parquet" => Ok(Format::Parquet),
+            s => bail!("unknown format {}, expected csv or parquet", s),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(super) struct ExportParams {
+    #[structopt(long = "archive", help = "the archive file to read")]
+    archive: PathBuf,
+    #[structopt(
+        long = "glob",
+        help = "export paths matching glob, may be repeated (default all paths)"
+    )]
+    globs: Vec<String>,
+    #[structopt(
+        long = "start",
+        help = "the time to start at, a date or e.g. -1h",
+        default_value = "beginning"
+    )]
+    start: String,
+    #[structopt(
+        long = "end",
+        help = "the time to end at, a date or e.g. -1h",
+        default_value = "end"
+    )]
+    end: String,
+    #[structopt(
+        long = "align",
+        help = "the time between rows, e.g. 1s, 500ms, 5m",
+        default_value = "1s"
+    )]
+    align: String,
+    #[structopt(long = "format", help = "csv or parquet", default_value = "csv")]
+    format: Format,
+    #[structopt(
+        short = "o",
+        long = "output",
+        help = "write to a file instead of stdout"
+    )]
+    output: Option<PathBuf>,
+}
+
+fn parse_bound(s: &str) -> Result<Bound<DateTime<Utc>>> {
+    match s.parse::<Seek>()? {
+        Seek::Beginning | Seek::End => Ok(Bound::Unbounded),
+        Seek::Absolute(ts) => Ok(Bound::Included(ts)),
+        Seek::TimeRelative(offset) => Ok(Bound::Included(Utc::now() + offset)),
+        Seek::BatchRelative(_) => bail!("{} is not a valid time", s),
+    }
+}
+
+fn parse_step(s: &str) -> Result<chrono::Duration> {
+    let s = s.trim();
+    let i = s.find(|c: char| c.is_ascii_alphabetic()).unwrap_or(s.len());
+    let quantity = match s[..i].parse::<f64>() {
+        Ok(q) if q > 0. => q,
+        _ => bail!("{} is not a valid step, expected e.g. 1s", s),
+    };
+    let scale = match &s[i..] {
+        "us" => 1e3,
+        "ms" => 1e6,
+        "s" | "" => 1e9,
+        "m" => 60e9,
+        "h" => 3600e9,
+        "d" => 86400e9,
+        u => bail!("unknown unit {}, expected us, ms, s, m, h, or d", u),
+    };
+    match (quantity * scale).trunc() as i64 {
+        0 => bail!("the step must be at least 1ns"),
+        ns => Ok(chrono::Duration::nanoseconds(ns)),
+    }
+}
+
+fn timestamp(ts: DateTime<Utc>) -> String {
+    ts.to_rfc3339_opts(SecondsFormat::AutoSi, true)
+}
+
+fn text(v: &Value) -> String {
+    match v {
+        Value::DateTime(ts) => timestamp(*ts),
+        v => v.to_string_naked(),
+    }
+}
+
+fn write_csv_field(out: &mut impl Write, s: &str) -> Result<()> {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        write!(out, "\"{}\"", s.replace('"', "\"\""))?
+    } else {
+        write!(out, "{}", s)?
+    }
+    Ok(())
+}
+
+fn write_csv(
+    mut out: impl Write,
+    columns: &[Path],
+    rows: impl Iterator<Item = (DateTime<Utc>, Vec<Option<Value>>)>,
+) -> Result<()> {
+    write!(out, "time")?;
+    for path in columns {
+        write!(out, ",")?;
+        write_csv_field(&mut out, path)?;
+    }
+    writeln!(out)?;
+    for (ts, row) in rows {
+        write!(out, "{}", timestamp(ts))?;
+        for v in row {
+            write!(out, ",")?;
+            match v {
+                None | Some(Value::Null) => (),
+                Some(v) => write_csv_field(&mut out, &text(&v))?,
+            }
+        }
+        writeln!(out)?;
+    }
+    Ok(out.flush()?)
+}
+
+#[cfg(feature = "parquet")]
+mod parquet_out {
+    use super::text;
+    use anyhow::Result;
+    use arrow_array::{
+        builder::{
+            BooleanBuilder, Float64Builder, Int64Builder, StringBuilder,
+            TimestampMicrosecondBuilder,
+        },
+        ArrayRef, RecordBatch,
+    };
+    use arrow_schema::{DataType, Field, Schema, TimeUnit};
+    use chrono::prelude::*;
+    use netidx::{path::Path, subscriber::Value};
+    use parquet::arrow::ArrowWriter;
+    use std::{fs::File, sync::Arc};
+
+    const ROW_GROUP: usize = 65536;
+
+    /// The type of a column, the narrowest one that can hold every
+    /// value the path had in the exported range.
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub(super) enum Kind {
+        Empty,
+        Bool,
+        Int,
+        Float,
+        Time,
+        Str,
+    }
+
+    impl Kind {
+        pub(super) fn merge(self, v: &Value) -> Kind {
+            let k = match v {
+                Value::Null => return self,
+                Value::True | Value::False => Kind::Bool,
+                Value::U32(_)
+                | Value::V32(_)
+                | Value::I32(_)
+                | Value::Z32(_)
+                | Value::U64(_)
+                | Value::V64(_)
+                | Value::I64(_)
+                | Value::Z64(_) => Kind::Int,
+                Value::F32(_) | Value::F64(_) => Kind::Float,
+                Value::DateTime(_) => Kind::Time,
+                _ => Kind::Str,
+            };
+            match (self, k) {
+                (Kind::Empty, k) => k,
+                (s, k) if s == k => s,
+                (Kind::Int, Kind::Float) | (Kind::Float, Kind::Int) => Kind::Float,
+                (_, _) => Kind::Str,
+            }
+        }
+
+        fn data_type(self) -> DataType {
+            match self {
+                Kind::Bool => DataType::Boolean,
+                Kind::Int => DataType::Int64,
+                Kind::Float => DataType::Float64,
+                Kind::Time => {
+                    DataType::Timestamp(TimeUnit::Microsecond, Some("UTC".into()))
+                }
+                Kind::Empty | Kind::Str => DataType::Utf8,
+            }
+        }
+    }
+
+    enum Col {
+        Bool(BooleanBuilder),
+        Int(Int64Builder),
+        Float(Float64Builder),
+        Time(TimestampMicrosecondBuilder),
+        Str(StringBuilder),
+    }
+
+    impl Col {
+        fn new(kind: Kind) -> Self {
+            match kind {
+                Kind::Bool => Col::Bool(BooleanBuilder::new()),
+                Kind::Int => Col::Int(Int64Builder::new()),
+                Kind::Float => Col::Float(Float64Builder::new()),
+                Kind::Time => Col::Time(TimestampMicrosecondBuilder::new()),
+                Kind::Empty | Kind::Str => Col::Str(StringBuilder::new()),
+            }
+        }
+
+        fn append(&mut self, v: Option<&Value>) {
+            match (self, v) {
+                (Col::Bool(b), Some(Value::True)) => b.append_value(true),
+                (Col::Bool(b), Some(Value::False)) => b.append_value(false),
+                (Col::Int(b), Some(v)) if v.number() => {
+                    b.append_option(v.clone().cast_to::<i64>().ok())
+                }
+                (Col::Float(b), Some(v)) if v.number() => {
+                    b.append_option(v.clone().cast_to::<f64>().ok())
+                }
+                (Col::Time(b), Some(Value::DateTime(ts))) => {
+                    b.append_value(ts.timestamp_micros())
+                }
+                (Col::Str(b), Some(v)) if v != &Value::Null => b.append_value(text(v)),
+                (Col::Bool(b), _) => b.append_null(),
+                (Col::Int(b), _) => b.append_null(),
+                (Col::Float(b), _) => b.append_null(),
+                (Col::Time(b), _) => b.append_null(),
+                (Col::Str(b), _) => b.append_null(),
+            }
+        }
+
+        fn finish(&mut self) -> ArrayRef {
+            match self {
+                Col::Bool(b) => Arc::new(b.finish()),
+                Col::Int(b) => Arc::new(b.finish()),
+                Col::Float(b) => Arc::new(b.finish()),
+                Col::Time(b) => Arc::new(b.finish().with_timezone("UTC")),
+                Col::Str(b) => Arc::new(b.finish()),
+            }
+        }
+    }
+
+    pub(super) fn write(
+        out: File,
+        columns: &[(Path, Kind)],
+        rows: impl Iterator<Item = (DateTime<Utc>, Vec<Option<Value>>)>,
+    ) -> Result<()> {
+        let fields = std::iter::once(Field::new("time", Kind::Time.data_type(), false))
+            .chain(columns.iter().map(|(p, k)| Field::new(&**p, k.data_type(), true)))
+            .collect::<Vec<_>>();
+        let schema = Arc::new(Schema::new(fields));
+        let mut writer = ArrowWriter::try_new(out, schema.clone(), None)?;
+        let mut time = Col::new(Kind::Time);
+        let mut cols = columns.iter().map(|(_, k)| Col::new(*k)).collect::<Vec<_>>();
+        let mut n = 0;
+        let mut flush = |time: &mut Col, cols: &mut Vec<Col>| -> Result<()> {
+            let arrays = std::iter::once(time.finish())
+                .chain(cols.iter_mut().map(|c| c.finish()))
+                .collect::<Vec<_>>();
+            Ok(writer.write(&RecordBatch::try_new(schema.clone(), arrays)?)?)
+        };
+        for (ts, row) in rows {
+            time.append(Some(&Value::DateTime(ts)));
+            for (c, v) in cols.iter_mut().zip(row.iter()) {
+                c.append(v.as_ref())
+            }
+            n += 1;
+            if n >= ROW_GROUP {
+                flush(&mut time, &mut cols)?;
+                n = 0;
+            }
+        }
+        if n > 0 {
+            flush(&mut time, &mut cols)?;
+        }
+        writer.close()?;
+        Ok(())
+    }
+}
+
+fn export(p: ExportParams) -> Result<()> {
+    let reader = ArchiveReader::open(&p.archive)?;
+    let globs = if p.globs.is_empty() { vec!["/**".into()] } else { p.globs };
+    let globs = globs
+        .into_iter()
+        .map(|g| Glob::new(Chars::from(g)))
+        .collect::<Result<Vec<_>>>()?;
+    let filter = GlobSet::new(true, globs)?;
+    let range = (parse_bound(&p.start)?, parse_bound(&p.end)?);
+    let step = parse_step(&p.align)?;
+    let mut rows = reader.aligned(&filter, range, step)?;
+    match p.format {
+        Format::Csv => {
+            let columns = rows.columns().to_vec();
+            match &p.output {
+                Some(file) => {
+                    write_csv(BufWriter::new(File::create(file)?), &columns, &mut rows)?
+                }
+                None => write_csv(io::stdout().lock(), &columns, &mut rows)?,
+            }
+        }
+        #[cfg(not(feature = "parquet"))]
+        Format::Parquet => {
+            bail!("parquet support is not enabled, rebuild with --features parquet")
+        }
+        #[cfg(feature = "parquet")]
+        Format::Parquet => {
+            use fxhash::FxHashMap;
+            use netidx::subscriber::Event;
+            use parquet_out::Kind;
+            let file = match &p.output {
+                Some(file) => File::create(file)?,
+                None => bail!("parquet output requires --output"),
+            };
+            // scan the range first to find the type of each column
+            let mut kinds = rows
+                .columns()
+                .iter()
+                .map(|p| (p.clone(), Kind::Empty))
+                .collect::<FxHashMap<_, _>>();
+            let mut events = reader.range(&filter, range)?;
+            for (_, path, ev) in &mut events {
+                if let (Some(k), Event::Update(v)) = (kinds.get_mut(&path), ev) {
+                    *k = k.merge(&v)
+                }
+            }
+            if let Some(e) = events.error() {
+                bail!("failed to read the archive {}", e)
+            }
+            let columns =
+                rows.columns().iter().map(|p| (p.clone(), kinds[p])).collect::<Vec<_>>();
+            parquet_out::write(file, &columns, &mut rows)?
+        }
+    }
+    if let Some(e) = rows.error() {
+        bail!("failed to read the archive {}", e)
+    }
+    Ok(())
+}
+
+pub(super) fn run(cmd: ArchiveCmd) {
+    match cmd {
+        ArchiveCmd::Export(p) => export(p).expect("export failed"),
+    }
+}
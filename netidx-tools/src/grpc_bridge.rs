@@ -0,0 +1,596 @@
+//! A bridge between netidx rpcs and grpc.
+//!
+//! With `--grpc-addr` every netidx rpc is callable over grpc through
+//! the service `netidx.Rpc`,
+//!
+//! ```proto
+//! service Rpc { rpc Call(CallRequest) returns (CallReply); }
+//! message CallRequest { string path = 1; google.protobuf.Struct args = 2; }
+//! message CallReply { google.protobuf.Value value = 1; }
+//! ```
+//!
+//! With `--import url=/base` the unary methods of every service the
+//! grpc server at `url` lists via reflection are published as netidx
+//! rpcs at `/base/<service>/<method>`, with one argument per field of
+//! the method's input message. Messages are represented in netidx as
+//! sorted arrays of `[field, value]` pairs, repeated fields as
+//! arrays, maps as arrays of `[key, value]` pairs, and enums by name.
+use crate::json::{json_to_value, value_to_json};
+use anyhow::Result;
+use arcstr::ArcStr;
+use bytes::Bytes;
+use futures::{channel::mpsc, future, prelude::*, stream};
+use fxhash::FxHashMap;
+use log::{info, warn};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    publisher::{BindCfg, ErrorInfo, Publisher, PublisherBuilder, Value},
+    resolver_client::DesiredAuth,
+    subscriber::Subscriber,
+};
+use netidx_protocols::rpc::{
+    client,
+    server::{ArgSpec, Proc, RpcCall},
+};
+use parking_lot::Mutex;
+use prost::Message;
+use prost_reflect::{
+    DescriptorPool, DynamicMessage, FieldDescriptor, Kind, MapKey, MessageDescriptor,
+    MethodDescriptor, Value as PValue,
+};
+use prost_types::{value::Kind as JKind, FileDescriptorProto, FileDescriptorSet};
+use serde_json::Value as JValue;
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    str::FromStr,
+    sync::Arc,
+};
+use structopt::StructOpt;
+use tokio::{runtime::Runtime, task};
+use tonic::{
+    codec::{Codec, DecodeBuf, Decoder, EncodeBuf, Encoder, ProstCodec},
+    codegen::{empty_body, http, Body, BoxFuture, Context, Poll, Service, StdError},
+    server::{Grpc, NamedService, UnaryService},
+    transport::{Channel, Endpoint, Server},
+    Status,
+};
+use tonic_reflection::pb::{
+    server_reflection_client::ServerReflectionClient,
+    server_reflection_request::MessageRequest,
+    server_reflection_response::MessageResponse, ServerReflectionRequest,
+};
+
+#[derive(Debug, Clone)]
+pub(super) struct Import {
+    url: String,
+    base: Path,
+}
+
+impl FromStr for Import {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.rfind("=/") {
+            None => bail!("expected url=/base/path"),
+            Some(i) => {
+                let base = Path::from(String::from(&s[i + 1..]));
+                Ok(Import { url: String::from(&s[..i]), base })
+            }
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(super) struct Params {
+    #[structopt(
+        short = "b",
+        long = "bind",
+        help = "configure the bind address e.g. local, 192.168.0.0/16"
+    )]
+    bind: Option<BindCfg>,
+    #[structopt(
+        long = "grpc-addr",
+        help = "serve netidx rpcs over grpc on this address"
+    )]
+    grpc_addr: Option<SocketAddr>,
+    #[structopt(
+        long = "import",
+        help = "publish the grpc services at url as netidx rpcs, url=/base (repeatable)"
+    )]
+    imports: Vec<Import>,
+}
+
+// netidx -> protobuf
+
+fn map_key(kind: &Kind, v: Value) -> Result<MapKey> {
+    Ok(match kind {
+        Kind::Bool => MapKey::Bool(v.cast_to::<bool>()?),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => MapKey::I32(v.cast_to::<i32>()?),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => MapKey::I64(v.cast_to::<i64>()?),
+        Kind::Uint32 | Kind::Fixed32 => MapKey::U32(v.cast_to::<u32>()?),
+        Kind::Uint64 | Kind::Fixed64 => MapKey::U64(v.cast_to::<u64>()?),
+        Kind::String => MapKey::String(v.cast_to::<String>()?),
+        k => bail!("invalid map key type {:?}", k),
+    })
+}
+
+fn to_kind(kind: &Kind, v: Value) -> Result<PValue> {
+    Ok(match kind {
+        Kind::Double => PValue::F64(v.cast_to::<f64>()?),
+        Kind::Float => PValue::F32(v.cast_to::<f32>()?),
+        Kind::Int32 | Kind::Sint32 | Kind::Sfixed32 => PValue::I32(v.cast_to::<i32>()?),
+        Kind::Int64 | Kind::Sint64 | Kind::Sfixed64 => PValue::I64(v.cast_to::<i64>()?),
+        Kind::Uint32 | Kind::Fixed32 => PValue::U32(v.cast_to::<u32>()?),
+        Kind::Uint64 | Kind::Fixed64 => PValue::U64(v.cast_to::<u64>()?),
+        Kind::Bool => PValue::Bool(v.cast_to::<bool>()?),
+        Kind::String => PValue::String(v.cast_to::<String>()?),
+        Kind::Bytes => match v {
+            Value::Bytes(b) => PValue::Bytes(b),
+            Value::String(s) => PValue::Bytes(Bytes::copy_from_slice(s.as_bytes())),
+            v => bail!("can't convert {} to bytes", v),
+        },
+        Kind::Enum(e) => match v {
+            Value::String(s) => match e.get_value_by_name(&s) {
+                Some(ev) => PValue::EnumNumber(ev.number()),
+                None => bail!("{} is not a member of {}", s, e.full_name()),
+            },
+            v => PValue::EnumNumber(v.cast_to::<i32>()?),
+        },
+        Kind::Message(m) => PValue::Message(to_message(m, v)?),
+    })
+}
+
+fn to_field(field: &FieldDescriptor, v: Value) -> Result<PValue> {
+    if field.is_map() {
+        let entry = field.kind();
+        let entry = entry.as_message().ok_or_else(|| anyhow!("invalid map entry"))?;
+        let (kf, vf) = (entry.map_entry_key_field(), entry.map_entry_value_field());
+        let mut map = HashMap::new();
+        for (k, v) in v.cast_to::<Vec<(Value, Value)>>()? {
+            map.insert(map_key(&kf.kind(), k)?, to_kind(&vf.kind(), v)?);
+        }
+        Ok(PValue::Map(map))
+    } else if field.is_list() {
+        let kind = field.kind();
+        let elts = v.cast_to::<Vec<Value>>()?;
+        Ok(PValue::List(
+            elts.into_iter().map(|v| to_kind(&kind, v)).collect::<Result<_>>()?,
+        ))
+    } else {
+        to_kind(&field.kind(), v)
+    }
+}
+
+fn set_field(msg: &mut DynamicMessage, name: &str, v: Value) -> Result<()> {
+    let desc = msg.descriptor();
+    match desc.get_field_by_name(name) {
+        None => bail!("{} has no field {}", desc.full_name(), name),
+        Some(field) => Ok(msg.try_set_field(&field, to_field(&field, v)?)?),
+    }
+}
+
+fn to_message(desc: &MessageDescriptor, v: Value) -> Result<DynamicMessage> {
+    let mut msg = DynamicMessage::new(desc.clone());
+    for (name, v) in v.cast_to::<Vec<(String, Value)>>()? {
+        set_field(&mut msg, &name, v)?
+    }
+    Ok(msg)
+}
+
+// protobuf -> netidx
+
+fn from_map_key(k: &MapKey) -> Value {
+    match k {
+        MapKey::Bool(b) => Value::from(*b),
+        MapKey::I32(i) => Value::I32(*i),
+        MapKey::I64(i) => Value::I64(*i),
+        MapKey::U32(i) => Value::U32(*i),
+        MapKey::U64(i) => Value::U64(*i),
+        MapKey::String(s) => Value::String(Chars::from(s.clone())),
+    }
+}
+
+fn from_kind(kind: &Kind, v: &PValue) -> Value {
+    match v {
+        PValue::Bool(b) => Value::from(*b),
+        PValue::I32(i) => Value::I32(*i),
+        PValue::I64(i) => Value::I64(*i),
+        PValue::U32(i) => Value::U32(*i),
+        PValue::U64(i) => Value::U64(*i),
+        PValue::F32(f) => Value::F32(*f),
+        PValue::F64(f) => Value::F64(*f),
+        PValue::String(s) => Value::String(Chars::from(s.clone())),
+        PValue::Bytes(b) => Value::Bytes(b.clone()),
+        PValue::EnumNumber(n) => match kind.as_enum().and_then(|e| e.get_value(*n)) {
+            Some(ev) => Value::String(Chars::from(String::from(ev.name()))),
+            None => Value::I32(*n),
+        },
+        PValue::Message(m) => from_message(m),
+        PValue::List(l) => Value::Array(l.iter().map(|v| from_kind(kind, v)).collect()),
+        PValue::Map(m) => {
+            let vkind = kind.as_message().map(|e| e.map_entry_value_field().kind());
+            let vkind = vkind.as_ref().unwrap_or(kind);
+            let mut pairs = m
+                .iter()
+                .map(|(k, v)| (from_map_key(k), from_kind(vkind, v)))
+                .collect::<Vec<_>>();
+            pairs.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+            Value::Array(
+                pairs.into_iter().map(|(k, v)| Value::Array(vec![k, v].into())).collect(),
+            )
+        }
+    }
+}
+
+fn from_message(m: &DynamicMessage) -> Value {
+    let mut fields = m
+        .fields()
+        .map(|(f, v)| (String::from(f.name()), from_kind(&f.kind(), v)))
+        .collect::<Vec<_>>();
+    fields.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+    Value::Array(
+        fields
+            .into_iter()
+            .map(|(k, v)| Value::Array(vec![Value::String(Chars::from(k)), v].into()))
+            .collect(),
+    )
+}
+
+// google.protobuf.Value <-> json
+
+fn pb_to_json(v: prost_types::Value) -> JValue {
+    match v.kind {
+        None | Some(JKind::NullValue(_)) => JValue::Null,
+        Some(JKind::BoolValue(b)) => JValue::Bool(b),
+        Some(JKind::NumberValue(n)) => JValue::from(n),
+        Some(JKind::StringValue(s)) => JValue::String(s),
+        Some(JKind::ListValue(l)) => {
+            JValue::Array(l.values.into_iter().map(pb_to_json).collect())
+        }
+        Some(JKind::StructValue(s)) => JValue::Object(
+            s.fields.into_iter().map(|(k, v)| (k, pb_to_json(v))).collect(),
+        ),
+    }
+}
+
+fn json_to_pb(v: JValue) -> prost_types::Value {
+    let kind = match v {
+        JValue::Null => JKind::NullValue(0),
+        JValue::Bool(b) => JKind::BoolValue(b),
+        JValue::Number(n) => JKind::NumberValue(n.as_f64().unwrap_or(f64::NAN)),
+        JValue::String(s) => JKind::StringValue(s),
+        JValue::Array(a) => JKind::ListValue(prost_types::ListValue {
+            values: a.into_iter().map(json_to_pb).collect(),
+        }),
+        JValue::Object(m) => JKind::StructValue(prost_types::Struct {
+            fields: m.into_iter().map(|(k, v)| (k, json_to_pb(v))).collect(),
+        }),
+    };
+    prost_types::Value { kind: Some(kind) }
+}
+
+// serving netidx rpcs over grpc
+
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct CallRequest {
+    #[prost(string, tag = "1")]
+    path: String,
+    #[prost(message, optional, tag = "2")]
+    args: Option<prost_types::Struct>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub(super) struct CallReply {
+    #[prost(message, optional, tag = "1")]
+    value: Option<prost_types::Value>,
+}
+
+#[derive(Clone)]
+struct RpcService {
+    subscriber: Subscriber,
+    procs: Arc<Mutex<FxHashMap<Path, client::Proc>>>,
+}
+
+impl RpcService {
+    async fn call(&self, req: CallRequest) -> Result<CallReply, Status> {
+        let path = Path::from(req.path);
+        let proc = self.procs.lock().get(&path).cloned();
+        let proc = match proc {
+            Some(proc) => proc,
+            None => {
+                let proc = client::Proc::new(&self.subscriber, path.clone())
+                    .await
+                    .map_err(|e| Status::not_found(e.to_string()))?;
+                self.procs.lock().insert(path, proc.clone());
+                proc
+            }
+        };
+        let args = req.args.map(|s| s.fields).unwrap_or_default();
+        let args = args.into_iter().map(|(k, v)| (k, json_to_value(pb_to_json(v))));
+        match proc.call(args).await.map_err(|e| Status::unavailable(e.to_string()))? {
+            Value::Error(e) => Err(Status::internal(e.to_string())),
+            Value::ErrorInfo(e) => Err(match e.code {
+                ErrorInfo::INVALID_ARGUMENT | ErrorInfo::UNKNOWN_ARGUMENT => {
+                    Status::invalid_argument(e.message.to_string())
+                }
+                ErrorInfo::PERMISSION_DENIED => {
+                    Status::permission_denied(e.message.to_string())
+                }
+                _ => Status::internal(e.message.to_string()),
+            }),
+            v => Ok(CallReply { value: Some(json_to_pb(value_to_json(&v))) }),
+        }
+    }
+}
+
+struct CallSvc(RpcService);
+
+impl UnaryService<CallRequest> for CallSvc {
+    type Response = CallReply;
+    type Future = BoxFuture<tonic::Response<CallReply>, Status>;
+
+    fn call(&mut self, req: tonic::Request<CallRequest>) -> Self::Future {
+        let t = self.0.clone();
+        Box::pin(async move { t.call(req.into_inner()).await.map(tonic::Response::new) })
+    }
+}
+
+impl<B> Service<http::Request<B>> for RpcService
+where
+    B: Body + Send + 'static,
+    B::Error: Into<StdError> + Send + 'static,
+{
+    type Response = http::Response<tonic::body::BoxBody>;
+    type Error = std::convert::Infallible;
+    type Future = BoxFuture<Self::Response, Self::Error>;
+
+    fn poll_ready(&mut self, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, req: http::Request<B>) -> Self::Future {
+        let t = self.clone();
+        match req.uri().path() {
+            "/netidx.Rpc/Call" => Box::pin(async move {
+                let mut grpc = Grpc::new(ProstCodec::<CallReply, CallRequest>::default());
+                Ok(grpc.unary(CallSvc(t), req).await)
+            }),
+            _ => Box::pin(async move {
+                Ok(http::Response::builder()
+                    .status(200)
+                    .header("grpc-status", "12")
+                    .header("content-type", "application/grpc")
+                    .body(empty_body())
+                    .unwrap())
+            }),
+        }
+    }
+}
+
+impl NamedService for RpcService {
+    const NAME: &'static str = "netidx.Rpc";
+}
+
+// calling grpc services as netidx rpcs
+
+#[derive(Clone)]
+struct DynamicCodec(MessageDescriptor);
+
+struct DynamicEncoder;
+
+impl Encoder for DynamicEncoder {
+    type Item = DynamicMessage;
+    type Error = Status;
+
+    fn encode(
+        &mut self,
+        item: DynamicMessage,
+        dst: &mut EncodeBuf<'_>,
+    ) -> Result<(), Status> {
+        item.encode(dst).map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+struct DynamicDecoder(MessageDescriptor);
+
+impl Decoder for DynamicDecoder {
+    type Item = DynamicMessage;
+    type Error = Status;
+
+    fn decode(
+        &mut self,
+        src: &mut DecodeBuf<'_>,
+    ) -> Result<Option<DynamicMessage>, Status> {
+        DynamicMessage::decode(self.0.clone(), src)
+            .map(Some)
+            .map_err(|e| Status::internal(e.to_string()))
+    }
+}
+
+impl Codec for DynamicCodec {
+    type Encode = DynamicMessage;
+    type Decode = DynamicMessage;
+    type Encoder = DynamicEncoder;
+    type Decoder = DynamicDecoder;
+
+    fn encoder(&mut self) -> DynamicEncoder {
+        DynamicEncoder
+    }
+
+    fn decoder(&mut self) -> DynamicDecoder {
+        DynamicDecoder(self.0.clone())
+    }
+}
+
+async fn reflect(
+    client: &mut ServerReflectionClient<Channel>,
+    req: MessageRequest,
+) -> Result<MessageResponse> {
+    let req = ServerReflectionRequest { host: String::new(), message_request: Some(req) };
+    let mut replies =
+        client.server_reflection_info(stream::iter([req])).await?.into_inner();
+    match replies.message().await?.and_then(|r| r.message_response) {
+        Some(MessageResponse::ErrorResponse(e)) => {
+            bail!("reflection failed {}", e.error_message)
+        }
+        Some(r) => Ok(r),
+        None => bail!("no reflection response"),
+    }
+}
+
+/// ask the server at `channel` for its services, and build a
+/// descriptor pool containing all of them.
+async fn load_services(channel: Channel) -> Result<DescriptorPool> {
+    let mut client = ServerReflectionClient::new(channel);
+    let services =
+        match reflect(&mut client, MessageRequest::ListServices(String::new())).await? {
+            MessageResponse::ListServicesResponse(l) => l.service,
+            _ => bail!("unexpected reflection response"),
+        };
+    let mut seen = HashSet::new();
+    let mut files = vec![];
+    for svc in services {
+        if svc.name.starts_with("grpc.reflection.") {
+            continue;
+        }
+        match reflect(&mut client, MessageRequest::FileContainingSymbol(svc.name)).await?
+        {
+            MessageResponse::FileDescriptorResponse(r) => {
+                for buf in r.file_descriptor_proto {
+                    let file = FileDescriptorProto::decode(&*buf)?;
+                    if seen.insert(file.name.clone()) {
+                        files.push(file)
+                    }
+                }
+            }
+            _ => bail!("unexpected reflection response"),
+        }
+    }
+    Ok(DescriptorPool::from_file_descriptor_set(FileDescriptorSet { file: files })?)
+}
+
+async fn call_method(
+    channel: Channel,
+    method: MethodDescriptor,
+    args: impl IntoIterator<Item = (ArcStr, Value)>,
+) -> Result<Value> {
+    let mut req = DynamicMessage::new(method.input());
+    for (name, v) in args {
+        if v != Value::Null {
+            set_field(&mut req, &name, v)?
+        }
+    }
+    let path = format!("/{}/{}", method.parent_service().full_name(), method.name());
+    let mut grpc = tonic::client::Grpc::new(channel);
+    grpc.ready().await?;
+    let reply = grpc
+        .unary(
+            tonic::Request::new(req),
+            http::uri::PathAndQuery::from_str(&path)?,
+            DynamicCodec(method.output()),
+        )
+        .await?;
+    Ok(from_message(reply.get_ref()))
+}
+
+type Calls = mpsc::Sender<(Channel, MethodDescriptor, RpcCall)>;
+
+async fn import(publisher: &Publisher, imp: &Import, calls: Calls) -> Result<Vec<Proc>> {
+    let channel = Endpoint::from_shared(imp.url.clone())?.connect().await?;
+    let pool = load_services(channel.clone()).await?;
+    let mut procs = vec![];
+    for svc in pool.services() {
+        for method in svc.methods() {
+            if method.is_client_streaming() || method.is_server_streaming() {
+                continue;
+            }
+            let path = imp.base.append(svc.full_name()).append(method.name());
+            let doc = format!(
+                "{}.{}({}) returns ({})",
+                svc.full_name(),
+                method.name(),
+                method.input().full_name(),
+                method.output().full_name()
+            );
+            let args = method
+                .input()
+                .fields()
+                .map(|f| ArgSpec {
+                    name: ArcStr::from(f.name()),
+                    doc: Value::String(Chars::from(format!("{:?}", f.kind()))),
+                    default_value: Value::Null,
+                    schema: None,
+                })
+                .collect::<Vec<_>>();
+            let (channel, m) = (channel.clone(), method.clone());
+            let map = move |c: RpcCall| Some((channel.clone(), m.clone(), c));
+            let proc = Proc::new(
+                publisher,
+                path.clone(),
+                Value::from(doc),
+                args,
+                map,
+                Some(calls.clone()),
+            )?;
+            info!("published {}", path);
+            procs.push(proc)
+        }
+    }
+    Ok(procs)
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, params: Params) -> Result<()> {
+    if params.grpc_addr.is_none() && params.imports.is_empty() {
+        bail!("nothing to do, specify --grpc-addr and or --import")
+    }
+    let subscriber = Subscriber::new(config.clone(), auth.clone())?;
+    if let Some(addr) = params.grpc_addr {
+        let svc =
+            RpcService { subscriber, procs: Arc::new(Mutex::new(HashMap::default())) };
+        task::spawn(async move {
+            info!("serving netidx rpcs over grpc on {}", addr);
+            if let Err(e) = Server::builder().add_service(svc).serve(addr).await {
+                warn!("grpc server failed {}", e)
+            }
+        });
+    }
+    let mut builder = PublisherBuilder::new();
+    builder.config(config).desired_auth(auth);
+    if let Some(b) = params.bind {
+        builder.bind_cfg(b);
+    }
+    let publisher = builder.build().await?;
+    let (calls_tx, mut calls_rx) = mpsc::channel(100);
+    let mut procs = vec![];
+    for imp in &params.imports {
+        procs.extend(import(&publisher, imp, calls_tx.clone()).await?);
+    }
+    drop(calls_tx);
+    while let Some((channel, method, mut call)) = calls_rx.next().await {
+        task::spawn(async move {
+            let args = call.args.drain().collect::<Vec<_>>();
+            match call_method(channel, method, args).await {
+                Ok(v) => call.reply.send(v),
+                Err(e) => {
+                    call.reply.send(Value::coded_err(ErrorInfo::FAILED, e.to_string()))
+                }
+            }
+        });
+    }
+    // the grpc server may still be running
+    future::pending::<()>().await;
+    drop(procs);
+    Ok(())
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, params).await {
+            eprintln!("grpc bridge failed {}", e)
+        }
+    })
+}
@@ -0,0 +1,72 @@
+//! Conversions between json and netidx values.
+//!
+//! Numbers, strings, booleans, null, and arrays map directly. Json
+//! objects become sorted arrays of `[key, value]` pairs, the same
+//! representation bscript uses for structs. Values without a json
+//! equivalent are converted to the closest thing, decimals and
+//! timestamps become strings, durations become seconds, bytes become
+//! an array of numbers, and errors become `{"error": msg}`.
+use netidx::{chars::Chars, publisher::Value};
+use serde_json::Value as JValue;
+
+pub(crate) fn json_to_value(j: JValue) -> Value {
+    match j {
+        JValue::Null => Value::Null,
+        JValue::Bool(true) => Value::True,
+        JValue::Bool(false) => Value::False,
+        JValue::Number(n) => match (n.as_i64(), n.as_u64()) {
+            (Some(i), _) => Value::I64(i),
+            (None, Some(u)) => Value::U64(u),
+            (None, None) => Value::F64(n.as_f64().unwrap_or(f64::NAN)),
+        },
+        JValue::String(s) => Value::String(Chars::from(s)),
+        JValue::Array(a) => Value::Array(a.into_iter().map(json_to_value).collect()),
+        // objects become sorted arrays of [key, value] pairs, like
+        // structs in bscript
+        JValue::Object(m) => {
+            let mut pairs = m.into_iter().collect::<Vec<_>>();
+            pairs.sort_by(|(k0, _), (k1, _)| k0.cmp(k1));
+            Value::Array(
+                pairs
+                    .into_iter()
+                    .map(|(k, v)| {
+                        Value::Array(
+                            vec![Value::String(Chars::from(k)), json_to_value(v)].into(),
+                        )
+                    })
+                    .collect(),
+            )
+        }
+    }
+}
+
+pub(crate) fn value_to_json(v: &Value) -> JValue {
+    match v {
+        Value::Null => JValue::Null,
+        Value::True => JValue::Bool(true),
+        Value::False => JValue::Bool(false),
+        Value::U32(n) | Value::V32(n) => JValue::from(*n),
+        Value::I32(n) | Value::Z32(n) => JValue::from(*n),
+        Value::U64(n) | Value::V64(n) => JValue::from(*n),
+        Value::I64(n) | Value::Z64(n) => JValue::from(*n),
+        Value::F32(n) => JValue::from(*n),
+        Value::F64(n) => JValue::from(*n),
+        Value::Decimal(d) => JValue::String(d.to_string()),
+        Value::DateTime(d) => JValue::String(d.to_rfc3339()),
+        Value::Duration(d) => JValue::from(d.as_secs_f64()),
+        Value::String(s) => JValue::String(String::from(&**s)),
+        Value::Bytes(b) => JValue::Array(b.iter().map(|b| JValue::from(*b)).collect()),
+        Value::Ok => JValue::String(String::from("ok")),
+        Value::Error(e) => {
+            let mut m = serde_json::Map::new();
+            m.insert(String::from("error"), JValue::String(String::from(&**e)));
+            JValue::Object(m)
+        }
+        Value::ErrorInfo(e) => {
+            let mut m = serde_json::Map::new();
+            m.insert(String::from("error"), JValue::String(e.to_string()));
+            JValue::Object(m)
+        }
+        Value::Array(a) => JValue::Array(a.iter().map(value_to_json).collect()),
+    }
+}
@@ -1,12 +1,25 @@
 #![recursion_limit = "2048"]
+mod acl;
+mod archive;
+mod prometheus_bridge;
 mod publisher;
 mod resolver;
+mod rpc;
 mod stress_channel_publisher;
 mod stress_channel_subscriber;
 mod stress_publisher;
 mod stress_subscriber;
 mod subscriber;
+mod top;
 
+#[cfg(feature = "grpc")]
+mod grpc_bridge;
+#[cfg(any(feature = "mqtt", feature = "ws"))]
+mod json;
+#[cfg(feature = "mqtt")]
+mod mqtt_bridge;
+#[cfg(feature = "ws")]
+mod ws_gateway;
 #[cfg(unix)]
 mod activation;
 #[cfg(unix)]
@@ -15,6 +28,8 @@ mod container;
 mod recorder;
 #[cfg(unix)]
 mod resolver_server;
+#[cfg(unix)]
+mod service;
 
 #[macro_use]
 extern crate anyhow;
@@ -44,6 +59,10 @@ enum Opt {
     #[cfg(unix)]
     #[structopt(name = "resolver-server", about = "run a resolver")]
     ResolverServer(resolver_server::Params),
+    #[structopt(name = "acl", about = "check and lint resolver server permissions")]
+    Acl(acl::AclCmd),
+    #[structopt(name = "archive", about = "work with archive files")]
+    Archive(archive::ArchiveCmd),
     #[structopt(name = "resolver", about = "query the resolver")]
     Resolver {
         #[structopt(flatten)]
@@ -51,6 +70,13 @@ enum Opt {
         #[structopt(subcommand)]
         cmd: resolver::ResolverCmd,
     },
+    #[structopt(name = "rpc", about = "call and discover rpcs")]
+    Rpc {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(subcommand)]
+        cmd: rpc::RpcCmd,
+    },
     #[structopt(name = "publisher", about = "publish data")]
     Publisher {
         #[structopt(flatten)]
@@ -65,6 +91,13 @@ enum Opt {
         #[structopt(flatten)]
         params: subscriber::Params,
     },
+    #[structopt(name = "top", about = "watch the update rates of paths")]
+    Top {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: top::Params,
+    },
     #[cfg(unix)]
     #[structopt(name = "container", about = "a hierarchical database in netidx")]
     Container {
@@ -89,6 +122,37 @@ enum Opt {
         #[structopt(flatten)]
         params: activation::Params,
     },
+    #[cfg(feature = "grpc")]
+    #[structopt(name = "grpc-bridge", about = "bridge netidx rpcs and grpc services")]
+    GrpcBridge {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: grpc_bridge::Params,
+    },
+    #[cfg(feature = "mqtt")]
+    #[structopt(name = "mqtt-bridge", about = "bridge mqtt topics and netidx paths")]
+    MqttBridge {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: mqtt_bridge::Params,
+    },
+    #[structopt(name = "prometheus-bridge", about = "serve netidx values to prometheus")]
+    PrometheusBridge {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: prometheus_bridge::Params,
+    },
+    #[cfg(feature = "ws")]
+    #[structopt(name = "ws-gateway", about = "serve netidx to browsers over websockets")]
+    WsGateway {
+        #[structopt(flatten)]
+        common: ClientParams,
+        #[structopt(flatten)]
+        params: ws_gateway::Params,
+    },
     #[structopt(name = "stress", about = "stress test")]
     Stress {
         #[structopt(flatten)]
@@ -103,10 +167,16 @@ fn main() {
     match Opt::from_args() {
         #[cfg(unix)]
         Opt::ResolverServer(p) => resolver_server::run(p),
+        Opt::Acl(cmd) => acl::run(cmd),
+        Opt::Archive(cmd) => archive::run(cmd),
         Opt::Resolver { common, cmd } => {
             let (cfg, auth) = common.load();
             resolver::run(cfg, auth, cmd)
         }
+        Opt::Rpc { common, cmd } => {
+            let (cfg, auth) = common.load();
+            rpc::run(cfg, auth, cmd)
+        }
         Opt::Publisher { common, params } => {
             let (cfg, auth) = common.load();
             publisher::run(cfg, auth, params)
@@ -115,6 +185,10 @@ fn main() {
             let (cfg, auth) = common.load();
             subscriber::run(cfg, auth, params)
         }
+        Opt::Top { common, params } => {
+            let (cfg, auth) = common.load();
+            top::run(cfg, auth, params)
+        }
         #[cfg(unix)]
         Opt::Container { common, params } => {
             let (cfg, auth) = common.load();
@@ -130,6 +204,25 @@ fn main() {
             let (cfg, auth) = common.load();
             activation::run(cfg, auth, params)
         }
+        #[cfg(feature = "grpc")]
+        Opt::GrpcBridge { common, params } => {
+            let (cfg, auth) = common.load();
+            grpc_bridge::run(cfg, auth, params)
+        }
+        #[cfg(feature = "mqtt")]
+        Opt::MqttBridge { common, params } => {
+            let (cfg, auth) = common.load();
+            mqtt_bridge::run(cfg, auth, params)
+        }
+        Opt::PrometheusBridge { common, params } => {
+            let (cfg, auth) = common.load();
+            prometheus_bridge::run(cfg, auth, params)
+        }
+        #[cfg(feature = "ws")]
+        Opt::WsGateway { common, params } => {
+            let (cfg, auth) = common.load();
+            ws_gateway::run(cfg, auth, params)
+        }
         Opt::Stress { common, cmd } => {
             let (cfg, auth) = common.load();
             match cmd {
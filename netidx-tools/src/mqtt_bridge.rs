@@ -0,0 +1,594 @@
+use crate::json::{json_to_value, value_to_json};
+use anyhow::Result;
+use bytes::Bytes;
+use futures::{
+    channel::mpsc::{self, Receiver, Sender},
+    prelude::*,
+    select_biased,
+    stream::{self, SelectAll},
+};
+use fxhash::FxHashMap;
+use log::{info, warn};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    pool::Pooled,
+    protocol::glob::{Glob, GlobSet},
+    publisher::{
+        BindCfg, DefaultHandle, Id, Publisher, PublisherBuilder, Val, Value, WriteRequest,
+    },
+    resolver_client::DesiredAuth,
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags},
+};
+use rumqttc::{AsyncClient, Event as MqttEvent, MqttOptions, Packet, Publish, QoS};
+use serde_json::Value as JValue;
+use std::{collections::HashSet, str::FromStr, time::Duration};
+use structopt::StructOpt;
+use tokio::{runtime::Runtime, task, time};
+
+/// One part of a path template, a literal path component or a
+/// reference to the nth wildcard in the topic filter.
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Lit(String),
+    Capture(usize),
+}
+
+/// Maps mqtt topics matching `filter` to netidx paths built from
+/// `template`, and back. e.g. `sensors/+/#=/sensors/{1}/data/{2}`.
+#[derive(Debug, Clone)]
+pub(super) struct Import {
+    filter: String,
+    template: Vec<Part>,
+    base: Path,
+}
+
+impl FromStr for Import {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let i = match s.find("=/") {
+            Some(i) => i,
+            None => bail!("expected topic-filter=/path/template"),
+        };
+        let (filter, template) = (&s[..i], &s[i + 1..]);
+        let levels = filter.split('/').collect::<Vec<_>>();
+        let nwild = levels.iter().filter(|l| **l == "+" || **l == "#").count();
+        for (i, l) in levels.iter().enumerate() {
+            if *l == "#" && i != levels.len() - 1 {
+                bail!("# must be the last level of the topic filter")
+            }
+            if (l.contains('+') || l.contains('#')) && l.len() > 1 {
+                bail!("wildcards must occupy an entire level")
+            }
+        }
+        let mut base = Path::root();
+        let mut in_base = true;
+        let mut seen = HashSet::new();
+        let mut tmpl = vec![];
+        for part in Path::parts(template) {
+            if part.starts_with('{') && part.ends_with('}') {
+                let n = part[1..part.len() - 1].parse::<usize>()?;
+                if n == 0 || n > nwild {
+                    bail!("capture {} does not refer to a wildcard", part)
+                }
+                if !seen.insert(n) {
+                    bail!("capture {} is used more than once", part)
+                }
+                in_base = false;
+                tmpl.push(Part::Capture(n))
+            } else {
+                if in_base {
+                    base = base.append(part);
+                }
+                tmpl.push(Part::Lit(part.into()))
+            }
+        }
+        if seen.len() != nwild {
+            bail!("every wildcard in the topic filter must be captured")
+        }
+        if base == Path::root() {
+            bail!("the path template must begin with a literal")
+        }
+        if levels.last() == Some(&"#") && tmpl.last() != Some(&Part::Capture(nwild)) {
+            bail!("the capture of # must be the last part of the path template")
+        }
+        Ok(Import { filter: filter.into(), template: tmpl, base })
+    }
+}
+
+impl Import {
+    fn topic_to_path(&self, topic: &str) -> Option<Path> {
+        let mut captures = vec![];
+        let mut levels = topic.split('/');
+        for f in self.filter.split('/') {
+            match f {
+                "#" => {
+                    captures.push(levels.by_ref().collect::<Vec<_>>().join("/"));
+                }
+                "+" => captures.push(levels.next()?.into()),
+                f if Some(f) == levels.next() => (),
+                _ => return None,
+            }
+        }
+        if levels.next().is_some() {
+            return None;
+        }
+        let mut path = Path::root();
+        for p in &self.template {
+            match p {
+                Part::Lit(s) => path = path.append(s),
+                Part::Capture(n) => path = path.append(&captures[n - 1]),
+            }
+        }
+        Some(path)
+    }
+
+    fn path_to_topic(&self, path: &Path) -> Option<String> {
+        let parts = Path::parts(path).collect::<Vec<_>>();
+        let mut captures = FxHashMap::default();
+        let mut i = 0;
+        for p in &self.template {
+            match p {
+                Part::Lit(s) if parts.get(i) == Some(&s.as_str()) => i += 1,
+                Part::Lit(_) => return None,
+                Part::Capture(n) if self.is_multi(*n) => {
+                    if i >= parts.len() {
+                        return None;
+                    }
+                    captures.insert(*n, parts[i..].join("/"));
+                    i = parts.len()
+                }
+                Part::Capture(n) => {
+                    captures.insert(*n, String::from(*parts.get(i)?));
+                    i += 1
+                }
+            }
+        }
+        if i != parts.len() {
+            return None;
+        }
+        let mut n = 0;
+        let topic = self
+            .filter
+            .split('/')
+            .map(|l| match l {
+                "+" | "#" => {
+                    n += 1;
+                    captures[&n].clone()
+                }
+                l => l.into(),
+            })
+            .collect::<Vec<_>>();
+        Some(topic.join("/"))
+    }
+
+    fn is_multi(&self, n: usize) -> bool {
+        self.filter.ends_with('#')
+            && n == self.filter.matches(|c| c == '+' || c == '#').count()
+    }
+}
+
+/// Exports every path published under `base` to the topic
+/// `prefix/<path relative to base>`.
+#[derive(Debug, Clone)]
+pub(super) struct Export {
+    base: Path,
+    prefix: String,
+}
+
+impl FromStr for Export {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s.find('=') {
+            None => bail!("expected /path=topic/prefix"),
+            Some(i) => {
+                let base = Path::from(String::from(&s[..i]));
+                if !Path::is_absolute(&base) {
+                    bail!("the exported path must be absolute")
+                }
+                Ok(Export { base, prefix: s[i + 1..].trim_end_matches('/').into() })
+            }
+        }
+    }
+}
+
+impl Export {
+    fn topic(&self, path: &Path) -> Option<String> {
+        if !Path::is_parent(&self.base, path) || self.base == *path {
+            return None;
+        }
+        let rel = Path::parts(&path[self.base.len()..]).collect::<Vec<_>>().join("/");
+        if self.prefix.is_empty() {
+            Some(rel)
+        } else {
+            Some(format!("{}/{}", self.prefix, rel))
+        }
+    }
+}
+
+/// How values are encoded in mqtt payloads
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) enum Encoding {
+    Json,
+    Cbor,
+}
+
+impl FromStr for Encoding {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "json" => Ok(Encoding::Json),
+            "cbor" => Ok(Encoding::Cbor),
+            s => bail!("unknown encoding {}, expected json or cbor", s),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(super) struct Params {
+    #[structopt(
+        short = "b",
+        long = "bind",
+        help = "configure the bind address e.g. local, 192.168.0.0/16"
+    )]
+    bind: Option<BindCfg>,
+    #[structopt(
+        long = "host",
+        help = "the mqtt broker host",
+        default_value = "localhost"
+    )]
+    host: String,
+    #[structopt(long = "port", help = "the mqtt broker port", default_value = "1883")]
+    port: u16,
+    #[structopt(long = "client-id", help = "the mqtt client id (default random)")]
+    client_id: Option<String>,
+    #[structopt(
+        long = "qos",
+        help = "the qos to subscribe and publish with (0, 1, or 2)",
+        default_value = "1"
+    )]
+    qos: u8,
+    #[structopt(long = "retain", help = "set the retain flag on exported messages")]
+    retain: bool,
+    #[structopt(
+        long = "encoding",
+        help = "encode payloads as json, or as cbor which preserves netidx types",
+        default_value = "json"
+    )]
+    encoding: Encoding,
+    #[structopt(
+        long = "import",
+        help = "import topics matching filter, e.g. sensors/+/#=/sensors/{1}/{2} (repeatable)"
+    )]
+    imports: Vec<Import>,
+    #[structopt(
+        long = "export",
+        help = "export paths under base to topics under prefix, e.g. /app=app (repeatable)"
+    )]
+    exports: Vec<Export>,
+    #[structopt(
+        long = "poll-interval",
+        help = "how often to look for new exported paths in seconds",
+        default_value = "10"
+    )]
+    poll_interval: u64,
+}
+
+/// With the json encoding, payloads that are valid json are
+/// converted structurally, otherwise valid utf8 becomes a string and
+/// anything else bytes. With the cbor encoding, payloads that are
+/// valid cbor are decoded, and anything else becomes bytes.
+fn payload_to_value(encoding: Encoding, payload: &Bytes) -> Value {
+    match encoding {
+        Encoding::Cbor => match Value::from_cbor(payload) {
+            Ok(v) => v,
+            Err(_) => Value::Bytes(payload.clone()),
+        },
+        Encoding::Json => match serde_json::from_slice::<JValue>(payload) {
+            Ok(j) => json_to_value(j),
+            Err(_) => match std::str::from_utf8(payload) {
+                Ok(s) => Value::String(Chars::from(String::from(s))),
+                Err(_) => Value::Bytes(payload.clone()),
+            },
+        },
+    }
+}
+
+fn value_to_payload(encoding: Encoding, v: &Value) -> Vec<u8> {
+    match (encoding, v) {
+        (Encoding::Cbor, v) => v.to_cbor(),
+        (Encoding::Json, Value::Bytes(b)) => b.to_vec(),
+        (Encoding::Json, v) => serde_json::to_vec(&value_to_json(v)).unwrap_or_default(),
+    }
+}
+
+fn qos(n: u8) -> Result<QoS> {
+    match n {
+        0 => Ok(QoS::AtMostOnce),
+        1 => Ok(QoS::AtLeastOnce),
+        2 => Ok(QoS::ExactlyOnce),
+        n => bail!("invalid qos {}", n),
+    }
+}
+
+struct Published {
+    val: Val,
+    topic: String,
+}
+
+struct Ctx {
+    params: Params,
+    qos: QoS,
+    client: AsyncClient,
+    publisher: Publisher,
+    subscriber: Subscriber,
+    published: FxHashMap<Path, Id>,
+    by_id: FxHashMap<Id, Published>,
+    exported: FxHashMap<Path, Dval>,
+    topics: FxHashMap<SubId, String>,
+    writes_tx: Sender<Pooled<Vec<WriteRequest>>>,
+    updates_tx: Sender<Pooled<Vec<(SubId, Event)>>>,
+}
+
+impl Ctx {
+    fn publish(&mut self, path: Path, topic: String, v: Value) -> Result<()> {
+        let val = self.publisher.publish(path.clone(), v)?;
+        let id = val.id();
+        self.publisher.writes(id, self.writes_tx.clone());
+        self.published.insert(path, id);
+        self.by_id.insert(id, Published { val, topic });
+        Ok(())
+    }
+
+    async fn incoming(&mut self, msg: Publish) -> Result<()> {
+        let path =
+            match self.params.imports.iter().find_map(|i| i.topic_to_path(&msg.topic)) {
+                Some(path) => path,
+                None => return Ok(()),
+            };
+        let v = payload_to_value(self.params.encoding, &msg.payload);
+        match self.published.get(&path) {
+            Some(id) => {
+                let mut batch = self.publisher.start_batch();
+                self.by_id[id].val.update(&mut batch, v);
+                batch.commit(None).await
+            }
+            None => {
+                self.publish(path, msg.topic, v)?;
+                self.publisher.flushed().await
+            }
+        }
+        Ok(())
+    }
+
+    // a subscriber asked for a path that doesn't exist yet, publish it
+    // if it maps to a topic so it can be written to.
+    fn default_request(&mut self, path: Path) -> Result<()> {
+        if !self.published.contains_key(&path) {
+            let topic = self.params.imports.iter().find_map(|i| i.path_to_topic(&path));
+            if let Some(topic) = topic {
+                self.publish(path, topic, Value::Null)?
+            }
+        }
+        Ok(())
+    }
+
+    async fn writes(&mut self, mut batch: Pooled<Vec<WriteRequest>>) {
+        for req in batch.drain(..) {
+            if let Some(p) = self.by_id.get(&req.id) {
+                let payload = value_to_payload(self.params.encoding, &req.value);
+                let res = self
+                    .client
+                    .publish(&p.topic, self.qos, self.params.retain, payload)
+                    .await;
+                if let Some(reply) = req.send_result {
+                    reply.send(match res {
+                        Ok(()) => Value::Ok,
+                        Err(e) => Value::Error(Chars::from(format!("{}", e))),
+                    })
+                }
+            }
+        }
+    }
+
+    async fn updates(&mut self, mut batch: Pooled<Vec<(SubId, Event)>>) {
+        for (id, ev) in batch.drain(..) {
+            if let (Some(topic), Event::Update(v)) = (self.topics.get(&id), ev) {
+                let payload = value_to_payload(self.params.encoding, &v);
+                let r = self.client.publish(topic, self.qos, self.params.retain, payload);
+                if let Err(e) = r.await {
+                    warn!("failed to publish {} {}", topic, e)
+                }
+            }
+        }
+    }
+
+    async fn poll_exports(&mut self, globs: &GlobSet) -> Result<()> {
+        let batches = self.subscriber.resolver().list_matching(globs).await?;
+        for batch in batches.iter() {
+            for path in batch.iter() {
+                if self.exported.contains_key(path) || self.published.contains_key(path) {
+                    continue;
+                }
+                let topic = self.params.exports.iter().find_map(|e| e.topic(path));
+                if let Some(topic) = topic {
+                    let dv = self.subscriber.subscribe(path.clone());
+                    dv.updates(UpdatesFlags::BEGIN_WITH_LAST, self.updates_tx.clone());
+                    self.topics.insert(dv.id(), topic);
+                    self.exported.insert(path.clone(), dv);
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+// drive the mqtt event loop, resubscribing after every reconnect
+async fn mqtt_loop(
+    mut eventloop: rumqttc::EventLoop,
+    client: AsyncClient,
+    filters: Vec<String>,
+    qos: QoS,
+    mut tx: Sender<Publish>,
+) {
+    loop {
+        match eventloop.poll().await {
+            Ok(MqttEvent::Incoming(Packet::ConnAck(_))) => {
+                info!("connected to mqtt broker");
+                for f in &filters {
+                    if let Err(e) = client.try_subscribe(f, qos) {
+                        warn!("failed to subscribe to {} {}", f, e)
+                    }
+                }
+            }
+            Ok(MqttEvent::Incoming(Packet::Publish(p))) => {
+                if tx.send(p).await.is_err() {
+                    break;
+                }
+            }
+            Ok(_) => (),
+            Err(e) => {
+                warn!("mqtt connection error {}, retrying", e);
+                time::sleep(Duration::from_secs(1)).await
+            }
+        }
+    }
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, params: Params) -> Result<()> {
+    if params.imports.is_empty() && params.exports.is_empty() {
+        bail!("nothing to do, specify at least one --import or --export")
+    }
+    let qos = qos(params.qos)?;
+    let mut builder = PublisherBuilder::new();
+    builder.config(config.clone()).desired_auth(auth.clone());
+    if let Some(b) = params.bind {
+        builder.bind_cfg(b);
+    }
+    let publisher = builder.build().await?;
+    let subscriber = Subscriber::new(config, auth)?;
+    let client_id = params
+        .client_id
+        .clone()
+        .unwrap_or_else(|| format!("netidx-{}", uuid::Uuid::new_v4()));
+    let mut opts = MqttOptions::new(client_id, params.host.clone(), params.port);
+    opts.set_keep_alive(Duration::from_secs(5));
+    let (client, eventloop) = AsyncClient::new(opts, 1000);
+    let (mqtt_tx, mut mqtt_rx) = mpsc::channel(1000);
+    let filters = params.imports.iter().map(|i| i.filter.clone()).collect();
+    task::spawn(mqtt_loop(eventloop, client.clone(), filters, qos, mqtt_tx));
+    let mut defaults: SelectAll<DefaultHandle> = stream::select_all(
+        params
+            .imports
+            .iter()
+            .map(|i| publisher.publish_default(i.base.clone()))
+            .collect::<Result<Vec<_>>>()?,
+    );
+    let globs = params
+        .exports
+        .iter()
+        .map(|e| Glob::new(Chars::from(format!("{}/**", e.base))))
+        .collect::<Result<Vec<_>>>()?;
+    let globs = if globs.is_empty() { None } else { Some(GlobSet::new(true, globs)?) };
+    let (writes_tx, mut writes_rx): (_, Receiver<Pooled<Vec<WriteRequest>>>) =
+        mpsc::channel(3);
+    let (updates_tx, mut updates_rx): (_, Receiver<Pooled<Vec<(SubId, Event)>>>) =
+        mpsc::channel(3);
+    let mut interval = time::interval(Duration::from_secs(params.poll_interval.max(1)));
+    let mut ctx = Ctx {
+        params,
+        qos,
+        client,
+        publisher,
+        subscriber,
+        published: FxHashMap::default(),
+        by_id: FxHashMap::default(),
+        exported: FxHashMap::default(),
+        topics: FxHashMap::default(),
+        writes_tx,
+        updates_tx,
+    };
+    loop {
+        select_biased! {
+            msg = mqtt_rx.select_next_some() => if let Err(e) = ctx.incoming(msg).await {
+                warn!("failed to import message {}", e)
+            },
+            (path, reply) = defaults.select_next_some() => {
+                if let Err(e) = ctx.default_request(path) {
+                    warn!("failed to publish requested path {}", e)
+                }
+                let _ = reply.send(());
+            },
+            batch = writes_rx.select_next_some() => ctx.writes(batch).await,
+            batch = updates_rx.select_next_some() => ctx.updates(batch).await,
+            _ = interval.tick().fuse() => if let Some(globs) = &globs {
+                if let Err(e) = ctx.poll_exports(globs).await {
+                    warn!("failed to list exported paths {}", e)
+                }
+            },
+            complete => break Ok(()),
+        }
+    }
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, params).await {
+            eprintln!("mqtt bridge failed {}", e)
+        }
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn import_parse() {
+        assert!("sensors/+".parse::<Import>().is_err());
+        assert!("sensors/#/x=/sensors/{1}".parse::<Import>().is_err());
+        assert!("sensors/a+=/sensors/{1}".parse::<Import>().is_err());
+        assert!("sensors/+=/{1}".parse::<Import>().is_err());
+        assert!("sensors/+=/sensors/{2}".parse::<Import>().is_err());
+        assert!("sensors/+=/sensors/{1}/{1}".parse::<Import>().is_err());
+        assert!("sensors/+/+=/sensors/{1}".parse::<Import>().is_err());
+        assert!("sensors/+/#=/sensors/{2}/{1}".parse::<Import>().is_err());
+        let i = "sensors/+/#=/sensors/{1}/data/{2}".parse::<Import>().unwrap();
+        assert_eq!(i.base, Path::from("/sensors"));
+    }
+
+    #[test]
+    fn import_mapping() {
+        let i = "sensors/+/#=/sensors/{1}/data/{2}".parse::<Import>().unwrap();
+        let path = Path::from("/sensors/kitchen/data/temp/c");
+        assert_eq!(i.topic_to_path("sensors/kitchen/temp/c"), Some(path.clone()));
+        assert_eq!(i.path_to_topic(&path).as_deref(), Some("sensors/kitchen/temp/c"));
+        assert_eq!(i.topic_to_path("lights/kitchen/on"), None);
+        assert_eq!(i.path_to_topic(&Path::from("/sensors/kitchen/other/c")), None);
+        assert_eq!(i.path_to_topic(&Path::from("/sensors/kitchen/data")), None);
+        let i = "home/+/+=/rooms/{2}/{1}".parse::<Import>().unwrap();
+        let path = Path::from("/rooms/kitchen/lights");
+        assert_eq!(i.topic_to_path("home/lights/kitchen"), Some(path.clone()));
+        assert_eq!(i.path_to_topic(&path).as_deref(), Some("home/lights/kitchen"));
+        assert_eq!(i.topic_to_path("home/lights/kitchen/on"), None);
+        assert_eq!(i.topic_to_path("home/lights"), None);
+        assert_eq!(i.path_to_topic(&Path::from("/rooms/kitchen/lights/on")), None);
+    }
+
+    #[test]
+    fn export_topic() {
+        let e = "/app/sensors=site/a/".parse::<Export>().unwrap();
+        let topic = e.topic(&Path::from("/app/sensors/kitchen/temp"));
+        assert_eq!(topic.as_deref(), Some("site/a/kitchen/temp"));
+        assert_eq!(e.topic(&Path::from("/app/sensors")), None);
+        assert_eq!(e.topic(&Path::from("/app/sensorsx/temp")), None);
+        let e = "/app=".parse::<Export>().unwrap();
+        assert_eq!(e.topic(&Path::from("/app/temp")).as_deref(), Some("temp"));
+        assert!("app=site".parse::<Export>().is_err());
+    }
+}
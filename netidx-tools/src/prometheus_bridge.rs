@@ -0,0 +1,100 @@
+use anyhow::Result;
+use fxhash::FxHashMap;
+use log::{info, warn};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    protocol::glob::{Glob, GlobSet},
+    resolver_client::DesiredAuth,
+    subscriber::{Dval, Event, Subscriber},
+};
+use netidx_protocols::metrics;
+use parking_lot::Mutex;
+use std::{net::SocketAddr, sync::Arc, time::Duration};
+use structopt::StructOpt;
+use tokio::{runtime::Runtime, time};
+
+#[derive(StructOpt, Debug)]
+pub(super) struct Params {
+    #[structopt(
+        long = "bind",
+        help = "the address to serve /metrics on",
+        default_value = "127.0.0.1:9184"
+    )]
+    bind: SocketAddr,
+    #[structopt(
+        long = "base",
+        help = "metric names are relative to base",
+        default_value = "/"
+    )]
+    base: Path,
+    #[structopt(
+        long = "poll-interval",
+        help = "how often to look for new paths in seconds",
+        default_value = "10"
+    )]
+    poll_interval: u64,
+    #[structopt(long = "glob", help = "export paths matching glob (repeatable)")]
+    globs: Vec<String>,
+}
+
+type Subs = Arc<Mutex<FxHashMap<Path, Dval>>>;
+
+fn render(base: &Path, subs: &Subs) -> String {
+    let subs = subs.lock();
+    let samples = subs.iter().filter_map(|(path, dv)| match dv.last() {
+        Event::Unsubscribed(_) => None,
+        Event::Update(v) => metrics::sample(base, path, &v),
+    });
+    metrics::render(&samples.collect::<Vec<_>>())
+}
+
+async fn poll(subscriber: &Subscriber, globs: &GlobSet, subs: &Subs) -> Result<()> {
+    let batches = subscriber.resolver().list_matching(globs).await?;
+    let mut subs = subs.lock();
+    for batch in batches.iter() {
+        for path in batch.iter() {
+            if !subs.contains_key(path) {
+                subs.insert(path.clone(), subscriber.subscribe(path.clone()));
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, p: Params) -> Result<()> {
+    if p.globs.is_empty() {
+        bail!("at least one glob is required")
+    }
+    let globs = p
+        .globs
+        .into_iter()
+        .map(|g| Glob::new(Chars::from(g)))
+        .collect::<Result<Vec<_>>>()?;
+    let globs = GlobSet::new(true, globs)?;
+    let subscriber = Subscriber::new(config, auth)?;
+    let subs: Subs = Arc::new(Mutex::new(FxHashMap::default()));
+    let (addr, _server) = {
+        let base = p.base.clone();
+        let subs = subs.clone();
+        metrics::serve(p.bind, move || render(&base, &subs)).await?
+    };
+    info!("serving metrics on http://{}/metrics", addr);
+    let mut interval = time::interval(Duration::from_secs(p.poll_interval.max(1)));
+    loop {
+        interval.tick().await;
+        if let Err(e) = poll(&subscriber, &globs, &subs).await {
+            warn!("failed to list matching paths {}", e)
+        }
+    }
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, params).await {
+            eprintln!("prometheus bridge failed {}", e)
+        }
+    })
+}
@@ -9,6 +9,7 @@ use netidx::{
     config::Config,
     path::Path,
     pool::Pooled,
+    protocol::value_parser::ValueStreamParser,
     publisher::{
         BindCfg, DesiredAuth, Id, Publisher, PublisherBuilder, Typ, Val, Value,
         WriteRequest,
@@ -19,7 +20,7 @@ use parking_lot::Mutex;
 use std::{collections::HashMap, convert::From, sync::Arc, time::Duration};
 use structopt::StructOpt;
 use tokio::{
-    io::{stdin, stdout, AsyncBufReadExt, AsyncWriteExt, BufReader},
+    io::{stdin, stdout, AsyncBufReadExt, AsyncWriteExt, BufReader, Stdin},
     runtime::Runtime,
     signal, task,
 };
@@ -37,6 +38,11 @@ pub(super) struct Params {
         help = "require subscribers to consume values before timeout (seconds)"
     )]
     timeout: Option<u64>,
+    #[structopt(
+        long = "watch-addr",
+        help = "rebind if the bind address changes, checking every (seconds)"
+    )]
+    watch_addr: Option<u64>,
 }
 
 macro_rules! tryc {
@@ -79,6 +85,41 @@ async fn handle_writes_loop(
     Ok(())
 }
 
+// Array and Result values may span multiple lines, keep reading
+// lines until the value that starts with `first` is complete.
+async fn read_value(
+    stdin: &mut BufReader<Stdin>,
+    parser: &mut ValueStreamParser,
+    first: &str,
+) -> Result<Value> {
+    let mut buf = String::from(first);
+    buf.push('\n');
+    loop {
+        let mut vals = parser.feed(buf.as_bytes());
+        if let Some(e) = parser.take_errors().pop() {
+            parser.finish();
+            return Err(e);
+        }
+        if vals.is_empty() {
+            buf.clear();
+            if stdin.read_line(&mut buf).await? == 0 {
+                vals = parser.finish();
+                if let Some(e) = parser.take_errors().pop() {
+                    return Err(e);
+                }
+            }
+        }
+        if !vals.is_empty() {
+            let trailing = parser.pending() > 0;
+            parser.finish();
+            if vals.len() > 1 || trailing {
+                bail!("trailing data after value")
+            }
+            return Ok(vals.pop().unwrap());
+        }
+    }
+}
+
 pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
     let rt = Runtime::new().expect("failed to init runtime");
     rt.block_on(async {
@@ -91,10 +132,14 @@ pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
         if let Some(b) = params.bind {
             builder.bind_cfg(b);
         }
+        if let Some(secs) = params.watch_addr {
+            builder.watch_addr(Duration::from_secs(secs));
+        }
         let publisher = builder.build().await.expect("creating publisher");
         let (writes_tx, writes_rx) = mpsc::channel(100);
         let mut buf = String::new();
         let mut stdin = BufReader::new(stdin());
+        let mut parser = ValueStreamParser::new();
         fn publish(
             by_path: &mut HashMap<Path, Arc<Val>>,
             by_id: &ById,
@@ -162,7 +207,13 @@ pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
                         "missing value",
                         m.next().ok_or_else(|| anyhow!("malformed data"))
                     );
-                    tryc!("parse val", typ.parse(v))
+                    match typ {
+                        Typ::Array | Typ::Result => tryc!(
+                            "parse val",
+                            read_value(&mut stdin, &mut parser, v).await
+                        ),
+                        typ => tryc!("parse val", typ.parse(v)),
+                    }
                 };
                 match by_path.get(path) {
                     Some(p) => {
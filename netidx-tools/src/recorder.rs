@@ -1,8 +1,10 @@
+use crate::service;
 use anyhow::{Error, Result};
 use arcstr::ArcStr;
 use chrono::prelude::*;
 use futures::{
     channel::{mpsc, oneshot},
+    executor,
     future::{self, Fuse},
     prelude::*,
     select_biased,
@@ -19,15 +21,17 @@ use netidx::{
         value::FromValue,
     },
     publisher::{
-        self, BindCfg, ClId, PublishFlags, Publisher, PublisherBuilder, UpdateBatch, Val,
-        Value, WriteRequest,
+        self, BindCfg, ClId, PublishFlags, Publisher, PublisherBuilder, SendResult,
+        UpdateBatch, Val, Value, WriteRequest,
     },
     resolver_client::{ChangeTracker, DesiredAuth, ResolverRead},
     subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags},
     utils,
 };
 use netidx_archive::{
-    ArchiveReader, ArchiveWriter, BatchItem, Cursor, Id, MonotonicTimestamper,
+    federated::FederatedReader,
+    tiered::{DirStore, Tiered},
+    ArchiveReader, ArchiveWriter, Backend, BatchItem, Cursor, Id, MonotonicTimestamper,
     RecordTooLarge, Seek, Timestamp, BATCH_POOL,
 };
 use netidx_protocols::{
@@ -39,9 +43,14 @@ use std::{
     collections::{BTreeMap, HashMap, HashSet, VecDeque},
     mem,
     ops::Bound,
+    path::{Path as FilePath, PathBuf},
     str::FromStr,
-    sync::Arc,
-    time::Duration,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 use structopt::StructOpt;
 use tokio::{runtime::Runtime, sync::broadcast, task, time};
@@ -81,6 +90,24 @@ pub(super) struct Params {
         default_value = "30"
     )]
     flush_interval: u64,
+    #[structopt(
+        long = "stats-interval",
+        help = "How often to update stats under publish-base/stats (seconds), 0 disable (5)",
+        default_value = "5"
+    )]
+    stats_interval: u64,
+    #[structopt(
+        long = "write-queue",
+        help = "How many batches may wait to be written before recording stalls (100)",
+        default_value = "100"
+    )]
+    write_queue: usize,
+    #[structopt(
+        long = "backend",
+        help = "How to flush the archive, portable or io_uring (portable)",
+        default_value = "portable"
+    )]
+    backend: Backend,
     #[structopt(
         long = "shards",
         help = "how many other recorder shards to expect",
@@ -99,8 +126,34 @@ pub(super) struct Params {
         default_value = "64"
     )]
     max_sessions_per_client: usize,
+    #[structopt(
+        long = "export-dir",
+        help = "enable the export rpc, writing exported archives to this directory"
+    )]
+    export_dir: Option<PathBuf>,
     #[structopt(long = "archive", help = "path to the archive file")]
-    archive: String,
+    archive: Option<String>,
+    #[structopt(
+        long = "archive-dir",
+        help = "publish all the archives in this directory as one instead of --archive"
+    )]
+    archive_dir: Option<PathBuf>,
+    #[structopt(
+        long = "segment-dir",
+        help = "record to, and publish, rotating segments in this directory instead of --archive"
+    )]
+    segment_dir: Option<PathBuf>,
+    #[structopt(
+        long = "offload-dir",
+        help = "move closed segments to this directory, required with --segment-dir"
+    )]
+    offload_dir: Option<PathBuf>,
+    #[structopt(
+        long = "rotate-interval",
+        help = "How often to close the current segment and start a new one (seconds), 0 only on exit (86400)",
+        default_value = "86400"
+    )]
+    rotate_interval: u64,
     #[structopt(long = "spec", help = "glob pattern to archive, can be repeated")]
     spec: Vec<String>,
 }
@@ -111,6 +164,83 @@ enum BCastMsg {
     Stop,
 }
 
+// what sessions play back, either one archive, or a set of archives
+// merged into one, see --archive-dir and --segment-dir
+#[derive(Clone)]
+enum Reader {
+    Single(ArchiveReader),
+    Federated(FederatedReader),
+}
+
+impl Reader {
+    fn rescan(&self) -> Result<()> {
+        match self {
+            Reader::Single(_) => Ok(()),
+            Reader::Federated(r) => r.rescan(),
+        }
+    }
+
+    fn check_remap_rescan(&self) -> Result<()> {
+        match self {
+            Reader::Single(r) => r.check_remap_rescan(),
+            Reader::Federated(r) => r.check_remap_rescan(),
+        }
+    }
+
+    fn path_for_id(&self, id: &Id) -> Option<Path> {
+        match self {
+            Reader::Single(r) => r.path_for_id(id),
+            Reader::Federated(r) => r.path_for_id(id),
+        }
+    }
+
+    fn seek(&self, cursor: &mut Cursor, seek: Seek) {
+        match self {
+            Reader::Single(r) => r.seek(cursor, seek),
+            Reader::Federated(r) => r.seek(cursor, seek),
+        }
+    }
+
+    fn get_index(&self) -> Pooled<Vec<(Id, Path)>> {
+        match self {
+            Reader::Single(r) => r.get_index(),
+            Reader::Federated(r) => r.get_index(),
+        }
+    }
+
+    fn build_image(&self, cursor: &Cursor) -> Result<Pooled<HashMap<Id, Event>>> {
+        match self {
+            Reader::Single(r) => r.build_image(cursor),
+            Reader::Federated(r) => r.build_image(cursor),
+        }
+    }
+
+    fn read_deltas(
+        &self,
+        cursor: &mut Cursor,
+        n: usize,
+    ) -> Result<Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>>> {
+        match self {
+            Reader::Single(r) => r.read_deltas(cursor, n),
+            Reader::Federated(r) => r.read_deltas(cursor, n),
+        }
+    }
+
+    fn export(
+        &self,
+        filter: &GlobSet,
+        range: (Bound<DateTime<Utc>>, Bound<DateTime<Utc>>),
+        dest: &FilePath,
+    ) -> Result<usize> {
+        match self {
+            Reader::Single(r) => r.export(filter, range, dest),
+            Reader::Federated(_) => {
+                bail!("export is not supported with --archive-dir or --segment-dir")
+            }
+        }
+    }
+}
+
 mod publish {
     use netidx_protocols::rpc::server::{RpcCall, RpcReply};
 
@@ -123,6 +253,14 @@ mod publish {
     static POS_DOC: &'static str = "The current playback position. Null if the archive is empty, or the timestamp of the current record. Set to any timestamp where start <= t <= end to seek. Set to [+-][0-9]+ to seek a specific number of batches, e.g. +1 to single step forward -1 to single step back. Set to [+-][0-9]+[yMdhmsu] to step forward or back that amount of time, e.g. -1y step back 1 year. -1u to step back 1 microsecond. set to 'beginning' to seek to the beginning and 'end' to seek to the end. By default the initial position is set to 'beginning' when opening the archive.";
     static PLAY_AFTER_DOC: &'static str =
         "Start playing after waiting the specified timeout";
+    static STEP_DOC: &'static str = "Step the playback position and return [pos, batch], where pos is the new position, and batch is the contents of the batch at the new position as a list of [path, value] pairs. The batch is empty if no batch is exactly at the new position, e.g. after a time step.";
+    static STEP_BY_DOC: &'static str = "How far to step. [+-][0-9]+ to step a number of batches, or [+-][0-9]+[.]?[0-9]*[yMdhmsu] to step an amount of time, e.g. -1.5s. Default +1";
+    static STEP_FRAME_DOC: &'static str = "Instead of seeking, publish exactly the next batch as if it were played, and then pause. Only a step of +1 is allowed. Default false";
+    static EXPORT_START_DOC: &'static str = "The timestamp you want the export to start at, or Unbounded for the beginning of the archive. Accepts the same offsets as session start. Default Unbounded.";
+    static EXPORT_END_DOC: &'static str = "The timestamp you want the export to end at, or Unbounded for the end of the archive. Accepts the same offsets as session end. Default Unbounded.";
+    static EXPORT_FILTER_DOC: &'static str =
+        "A glob, or a list of globs, selecting the paths to export. Default /**";
+    static EXPORT_FILE_DOC: &'static str = "The name of the archive file to create in the recorder's export directory. It must not already exist.";
 
     fn session_base(publish_base: &Path, id: Uuid) -> Path {
         use uuid::fmt::Simple;
@@ -232,6 +370,14 @@ mod publish {
                 State::Pause | State::Tail => false,
             }
         }
+
+        fn name(&self) -> &'static str {
+            match self {
+                State::Play => "play",
+                State::Pause => "pause",
+                State::Tail => "tail",
+            }
+        }
     }
 
     #[derive(Debug)]
@@ -255,6 +401,7 @@ mod publish {
         state_ctl: Val,
         _pos_doc: Val,
         pos_ctl: Val,
+        _step: Proc,
     }
 
     impl Controls {
@@ -262,6 +409,7 @@ mod publish {
             session_base: &Path,
             publisher: &Publisher,
             control_tx: &mpsc::Sender<Pooled<Vec<WriteRequest>>>,
+            step_tx: &mpsc::Sender<(StepReq, RpcReply)>,
         ) -> Result<Self> {
             let _start_doc = publisher.publish(
                 session_base.append("control/start/doc"),
@@ -313,6 +461,15 @@ mod publish {
                 Value::Null,
             )?;
             publisher.writes(pos_ctl.id(), control_tx.clone());
+            let _step = define_rpc!(
+                publisher,
+                session_base.append("control/step"),
+                STEP_DOC,
+                StepReq::new,
+                Some(step_tx.clone()),
+                by: Value = "+1"; STEP_BY_DOC,
+                frame: bool = false; STEP_FRAME_DOC
+            )?;
             publisher.flushed().await;
             Ok(Controls {
                 _start_doc,
@@ -325,10 +482,58 @@ mod publish {
                 state_ctl,
                 _pos_doc,
                 pos_ctl,
+                _step,
             })
         }
     }
 
+    #[derive(Debug, Clone, Copy)]
+    enum Step {
+        Batches(i64),
+        Time(chrono::Duration),
+    }
+
+    fn parse_step(v: Value) -> Result<Step> {
+        if v.number() {
+            return Ok(Step::Batches(v.cast_to::<i64>()?));
+        }
+        let s = v.cast_to::<Chars>()?;
+        match s.trim().parse::<i64>() {
+            Ok(n) => Ok(Step::Batches(n)),
+            Err(_) => match s.parse::<Seek>()? {
+                Seek::TimeRelative(d) => Ok(Step::Time(d)),
+                Seek::BatchRelative(n) => Ok(Step::Batches(n as i64)),
+                Seek::Beginning | Seek::End | Seek::Absolute(_) => {
+                    bail!("expected a number of batches or a time offset")
+                }
+            },
+        }
+    }
+
+    #[derive(Debug)]
+    struct StepReq {
+        by: Step,
+        frame: bool,
+    }
+
+    impl StepReq {
+        fn new(mut req: RpcCall, by: Value, frame: bool) -> Option<(StepReq, RpcReply)> {
+            let by = match parse_step(by) {
+                Ok(by) => by,
+                Err(e) => rpc_err!(req.reply, format!("invalid step {}", e)),
+            };
+            if frame {
+                match by {
+                    Step::Batches(1) => (),
+                    Step::Batches(_) | Step::Time(_) => {
+                        rpc_err!(req.reply, "frame mode only steps forward one batch")
+                    }
+                }
+            }
+            Some((StepReq { by, frame }, req.reply))
+        }
+    }
+
     struct NewSessionConfig {
         client: ClId,
         start: Bound<DateTime<Utc>>,
@@ -374,6 +579,59 @@ mod publish {
         }
     }
 
+    struct ExportConfig {
+        start: Bound<DateTime<Utc>>,
+        end: Bound<DateTime<Utc>>,
+        filter: GlobSet,
+        file: PathBuf,
+    }
+
+    impl ExportConfig {
+        fn new(
+            mut req: RpcCall,
+            export_dir: &PathBuf,
+            start: Value,
+            end: Value,
+            filter: Value,
+            file: Option<Chars>,
+        ) -> Option<(ExportConfig, RpcReply)> {
+            let start = match parse_bound(start) {
+                Ok(s) => s,
+                Err(e) => rpc_err!(req.reply, format!("invalid start {}", e)),
+            };
+            let end = match parse_bound(end) {
+                Ok(s) => s,
+                Err(e) => rpc_err!(req.reply, format!("invalid end {}", e)),
+            };
+            let filter = match parse_filter(filter) {
+                Ok(f) => f,
+                Err(e) => rpc_err!(req.reply, format!("invalid filter {}", e)),
+            };
+            let file = match file {
+                None => rpc_err!(req.reply, "file is required"),
+                Some(file) => {
+                    let name = FilePath::new(&*file);
+                    match name.file_name() {
+                        Some(n) if n == name.as_os_str() => export_dir.join(name),
+                        Some(_) | None => {
+                            rpc_err!(req.reply, format!("invalid file name {}", file))
+                        }
+                    }
+                }
+            };
+            Some((ExportConfig { start, end, filter, file }, req.reply))
+        }
+    }
+
+    fn parse_filter(v: Value) -> Result<GlobSet> {
+        let globs = match v {
+            Value::String(c) => vec![c],
+            v => v.cast_to::<Vec<Chars>>()?,
+        };
+        let globs = globs.into_iter().map(Glob::new).collect::<Result<Vec<_>>>()?;
+        GlobSet::new(true, globs)
+    }
+
     struct T {
         controls: Controls,
         publisher: Publisher,
@@ -382,18 +640,22 @@ mod publish {
         cursor: Cursor,
         speed: Speed,
         state: State,
-        archive: ArchiveReader,
+        archive: Reader,
         data_base: Path,
+        status: Arc<Mutex<SessionStatus>>,
     }
 
     impl T {
         async fn new(
             publisher: Publisher,
-            archive: ArchiveReader,
+            archive: Reader,
             session_base: Path,
+            status: Arc<Mutex<SessionStatus>>,
             control_tx: &mpsc::Sender<Pooled<Vec<WriteRequest>>>,
+            step_tx: &mpsc::Sender<(StepReq, RpcReply)>,
         ) -> Result<T> {
-            let controls = Controls::new(&session_base, &publisher, &control_tx).await?;
+            let controls =
+                Controls::new(&session_base, &publisher, &control_tx, &step_tx).await?;
             Ok(T {
                 controls,
                 publisher,
@@ -408,6 +670,7 @@ mod publish {
                 state: State::Pause,
                 archive,
                 data_base: session_base.append("data"),
+                status,
             })
         }
 
@@ -482,7 +745,7 @@ mod publish {
             let mut pbatch = self.publisher.start_batch();
             for BatchItem(id, ev) in batch.1.drain(..) {
                 let v = match ev {
-                    Event::Unsubscribed => Value::Null,
+                    Event::Unsubscribed(_) => Value::Null,
                     Event::Update(v) => v,
                 };
                 match self.published.get(&id) {
@@ -498,6 +761,7 @@ mod publish {
                     }
                 }
             }
+            self.status.lock().pos = Value::DateTime(batch.0);
             self.controls.pos_ctl.update(&mut pbatch, Value::DateTime(batch.0));
             Ok(pbatch.commit(None).await)
         }
@@ -575,14 +839,8 @@ mod publish {
                 (s0, s1) if s0 == s1 => (),
                 (_, state) => {
                     self.state = state;
-                    self.controls.state_ctl.update(
-                        cbatch,
-                        Value::from(match state {
-                            State::Play => "play",
-                            State::Pause => "pause",
-                            State::Tail => "tail",
-                        }),
-                    );
+                    self.status.lock().state = state;
+                    self.controls.state_ctl.update(cbatch, Value::from(state.name()));
                 }
             }
         }
@@ -714,19 +972,18 @@ mod publish {
             let mut img =
                 task::block_in_place(|| self.archive.build_image(&self.cursor))?;
             let mut idx = task::block_in_place(|| self.archive.get_index());
-            self.controls.pos_ctl.update(
-                pbatch,
-                match self.cursor.current() {
-                    Some(ts) => Value::DateTime(ts),
-                    None => match self.cursor.start() {
-                        Bound::Unbounded => Value::Null,
-                        Bound::Included(ts) | Bound::Excluded(ts) => Value::DateTime(ts),
-                    },
+            let pos = match self.cursor.current() {
+                Some(ts) => Value::DateTime(ts),
+                None => match self.cursor.start() {
+                    Bound::Unbounded => Value::Null,
+                    Bound::Included(ts) | Bound::Excluded(ts) => Value::DateTime(ts),
                 },
-            );
+            };
+            self.status.lock().pos = pos.clone();
+            self.controls.pos_ctl.update(pbatch, pos);
             for (id, path) in idx.drain(..) {
                 let v = match img.remove(&id) {
-                    None | Some(Event::Unsubscribed) => Value::Null,
+                    None | Some(Event::Unsubscribed(_)) => Value::Null,
                     Some(Event::Update(v)) => v,
                 };
                 match self.published.get(&id) {
@@ -744,22 +1001,144 @@ mod publish {
             Ok(())
         }
 
-        fn seek(&mut self, pbatch: &mut UpdateBatch, seek: Seek) -> Result<()> {
-            let current = match &mut self.speed {
+        // the batches read ahead of the cursor by playback
+        fn read_ahead(
+            &mut self,
+        ) -> &mut Pooled<VecDeque<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>> {
+            match &mut self.speed {
                 Speed::Unlimited(v) => v,
                 Speed::Limited { current, next, .. } => {
                     *next = time::Instant::now();
                     current
                 }
-            };
+            }
+        }
+
+        fn seek(&mut self, pbatch: &mut UpdateBatch, seek: Seek) -> Result<()> {
+            let current = self.read_ahead();
             if let Some((ts, _)) = current.pop_front() {
+                current.clear();
                 self.cursor.set_current(ts);
-                current.clear()
             }
             self.archive.seek(&mut self.cursor, seek);
             self.reimage(pbatch)
         }
 
+        fn step(&mut self, pbatch: &mut UpdateBatch, step: Step) -> Result<()> {
+            match step {
+                Step::Time(offset) => self.seek(pbatch, Seek::TimeRelative(offset)),
+                Step::Batches(mut n) => {
+                    let current = self.read_ahead();
+                    if let Some((ts, _)) = current.pop_front() {
+                        current.clear();
+                        self.cursor.set_current(ts);
+                    }
+                    // a batch relative seek can only move 127 batches
+                    while n != 0 {
+                        let by = n.clamp(i8::MIN as i64, i8::MAX as i64);
+                        self.archive
+                            .seek(&mut self.cursor, Seek::BatchRelative(by as i8));
+                        n -= by;
+                    }
+                    self.reimage(pbatch)
+                }
+            }
+        }
+
+        // the next batch playback would publish
+        fn next_frame(
+            &mut self,
+        ) -> Result<Option<(DateTime<Utc>, Pooled<Vec<BatchItem>>)>> {
+            let current = self.read_ahead();
+            match current.pop_front() {
+                Some((ts, batch)) => {
+                    current.clear();
+                    self.cursor.set_current(ts);
+                    Ok(Some((ts, batch)))
+                }
+                None => {
+                    let archive = &self.archive;
+                    let cursor = &mut self.cursor;
+                    let mut batches =
+                        task::block_in_place(|| archive.read_deltas(cursor, 1))?;
+                    Ok(batches.pop_front())
+                }
+            }
+        }
+
+        fn batch_contents(&self, batch: &[BatchItem]) -> Value {
+            let contents = batch
+                .iter()
+                .filter_map(|BatchItem(id, ev)| {
+                    let path = self.archive.path_for_id(id)?;
+                    let v = match ev {
+                        Event::Unsubscribed(_) => Value::Null,
+                        Event::Update(v) => v.clone(),
+                    };
+                    Some(Value::from(vec![Value::from(path), v]))
+                })
+                .collect::<Vec<_>>();
+            Value::from(contents)
+        }
+
+        // the contents of the batch exactly at the current position
+        fn contents_at_pos(&self) -> Result<Value> {
+            let mut batches = match self.cursor.current() {
+                None => return Ok(Value::from(Vec::<Value>::new())),
+                Some(ts) => {
+                    let mut cursor = Cursor::new();
+                    cursor.set_start(Bound::Included(ts));
+                    cursor.set_end(Bound::Included(ts));
+                    task::block_in_place(|| self.archive.read_deltas(&mut cursor, 1))?
+                }
+            };
+            Ok(match batches.pop_front() {
+                None => Value::from(Vec::<Value>::new()),
+                Some((_, batch)) => self.batch_contents(&batch),
+            })
+        }
+
+        async fn process_step(
+            &mut self,
+            session_id: Uuid,
+            cluster: &Cluster<ClusterCmd>,
+            req: StepReq,
+            mut reply: RpcReply,
+        ) -> Result<()> {
+            info!("step {}: {:?}", session_id, req);
+            let mut cbatch = self.publisher.start_batch();
+            let contents = if req.frame {
+                self.set_state(&mut cbatch, State::Pause);
+                cluster.send_cmd(&ClusterCmd::SetState(State::Pause));
+                match self.next_frame()? {
+                    None => Value::from(Vec::<Value>::new()),
+                    Some((ts, mut batch)) => {
+                        let contents = self.batch_contents(&batch);
+                        self.process_batch((ts, &mut *batch)).await?;
+                        contents
+                    }
+                }
+            } else {
+                self.step(&mut cbatch, req.by)?;
+                match self.state {
+                    State::Pause | State::Play => (),
+                    State::Tail => self.set_state(&mut cbatch, State::Play),
+                }
+                self.contents_at_pos()?
+            };
+            let pos = match self.cursor.current() {
+                None => Value::Null,
+                Some(ts) => {
+                    // the other members of the cluster seek to where we ended up
+                    cluster.send_cmd(&ClusterCmd::SeekTo(Seek::Absolute(ts).to_string()));
+                    Value::DateTime(ts)
+                }
+            };
+            cbatch.commit(None).await;
+            reply.send(Value::from(vec![pos, contents]));
+            Ok(())
+        }
+
         fn set_speed(&mut self, cbatch: &mut UpdateBatch, new_rate: Option<f64>) {
             match new_rate {
                 None => {
@@ -802,23 +1181,36 @@ mod publish {
 
     async fn session(
         mut bcast: broadcast::Receiver<BCastMsg>,
-        archive: ArchiveReader,
+        archive: Reader,
         subscriber: Subscriber,
         publisher: Publisher,
         publish_base: Path,
         session_id: Uuid,
+        status: Arc<Mutex<SessionStatus>>,
         shards: usize,
         cfg: Option<NewSessionConfig>,
     ) -> Result<()> {
         let (control_tx, control_rx) = mpsc::channel(3);
+        let (step_tx, step_rx) = mpsc::channel(3);
         let (events_tx, mut events_rx) = mpsc::unbounded();
         publisher.events(events_tx);
         let session_base = session_base(&publish_base, session_id);
         let mut cluster =
             Cluster::new(&publisher, subscriber, session_base.append("cluster"), shards)
                 .await?;
+        if let Err(e) = task::block_in_place(|| archive.rescan()) {
+            warn!("failed to look for new archives {}", e)
+        }
         archive.check_remap_rescan()?;
-        let mut t = T::new(publisher.clone(), archive, session_base, &control_tx).await?;
+        let mut t = T::new(
+            publisher.clone(),
+            archive,
+            session_base,
+            status,
+            &control_tx,
+            &step_tx,
+        )
+        .await?;
         let mut batch = publisher.start_batch();
         t.seek(&mut batch, Seek::Beginning)?;
         if let Some(cfg) = cfg {
@@ -826,6 +1218,7 @@ mod publish {
         }
         batch.commit(None).await;
         let mut control_rx = control_rx.fuse();
+        let mut step_rx = step_rx.fuse();
         let mut idle_check = time::interval(std::time::Duration::from_secs(30));
         let mut idle = false;
         let mut used = 0;
@@ -869,6 +1262,9 @@ mod publish {
                         t.process_control_batch(session_id, &cluster, batch).await?
                     }
                 },
+                (req, reply) = step_rx.select_next_some() => {
+                    t.process_step(session_id, &cluster, req, reply).await?
+                },
                 r = t.next().fuse() => match r {
                     Err(e) => break Err(e),
                     Ok((ts, mut batch)) => { t.process_batch((ts, &mut *batch)).await?; }
@@ -877,11 +1273,19 @@ mod publish {
         }
     }
 
+    // what the stats report about a session
+    #[derive(Debug, Clone)]
+    struct SessionStatus {
+        state: State,
+        pos: Value,
+    }
+
     struct SessionsInner {
         max_total: usize,
         max_by_client: usize,
         total: usize,
         by_client: FxHashMap<ClId, usize>,
+        status: FxHashMap<Uuid, Arc<Mutex<SessionStatus>>>,
     }
 
     #[derive(Clone)]
@@ -894,17 +1298,20 @@ mod publish {
                 max_by_client,
                 total: 0,
                 by_client: HashMap::default(),
+                status: HashMap::default(),
             })))
         }
 
-        fn add_session(&self, client: ClId) -> Option<Session> {
+        fn add_session(&self, client: ClId, id: Uuid) -> Option<Session> {
             let mut inner = self.0.lock();
             let inner = &mut *inner;
             let by_client = inner.by_client.entry(client).or_insert(0);
             if inner.total < inner.max_total && *by_client < inner.max_by_client {
                 inner.total += 1;
                 *by_client += 1;
-                Some(Session(self.clone(), client))
+                let status = SessionStatus { state: State::Pause, pos: Value::Null };
+                inner.status.insert(id, Arc::new(Mutex::new(status)));
+                Some(Session(self.clone(), client, id))
             } else {
                 None
             }
@@ -912,14 +1319,26 @@ mod publish {
 
         fn delete_session(&self, session: &Session) {
             let mut inner = self.0.lock();
+            inner.status.remove(&session.2);
             if let Some(c) = inner.by_client.get_mut(&session.1) {
                 *c -= 1;
                 inner.total -= 1;
             }
         }
+
+        fn status(&self, id: &Uuid) -> Arc<Mutex<SessionStatus>> {
+            self.0.lock().status[id].clone()
+        }
+
+        fn snapshot(&self) -> (usize, Vec<(Uuid, SessionStatus)>) {
+            let inner = self.0.lock();
+            let status =
+                inner.status.iter().map(|(id, s)| (*id, s.lock().clone())).collect();
+            (inner.total, status)
+        }
     }
 
-    struct Session(Sessions, ClId);
+    struct Session(Sessions, ClId, Uuid);
 
     impl Drop for Session {
         fn drop(&mut self) {
@@ -927,13 +1346,55 @@ mod publish {
         }
     }
 
+    // operational stats, published under publish_base/stats/publish
+    struct Stats {
+        base: Path,
+        sessions: Val,
+        by_session: FxHashMap<Uuid, (Val, Val)>,
+    }
+
+    impl Stats {
+        fn new(publisher: &Publisher, publish_base: &Path) -> Result<Self> {
+            let base = publish_base.append("stats/publish");
+            let sessions = publisher.publish(base.append("sessions"), Value::U64(0))?;
+            Ok(Stats { base, sessions, by_session: HashMap::default() })
+        }
+
+        async fn update(
+            &mut self,
+            publisher: &Publisher,
+            sessions: &Sessions,
+        ) -> Result<()> {
+            let (total, status) = sessions.snapshot();
+            let mut batch = publisher.start_batch();
+            self.sessions.update_changed(&mut batch, total as u64);
+            self.by_session.retain(|id, _| status.iter().any(|(i, _)| i == id));
+            for (id, st) in status {
+                match self.by_session.get(&id) {
+                    Some((state, pos)) => {
+                        state.update_changed(&mut batch, st.state.name());
+                        pos.update_changed(&mut batch, st.pos);
+                    }
+                    None => {
+                        let base = session_base(&self.base.append("session"), id);
+                        let state = Value::from(st.state.name());
+                        let state = publisher.publish(base.append("state"), state)?;
+                        let pos = publisher.publish(base.append("pos"), st.pos)?;
+                        self.by_session.insert(id, (state, pos));
+                    }
+                }
+            }
+            Ok(batch.commit(None).await)
+        }
+    }
+
     async fn start_session(
         publisher: Publisher,
         session_id: Uuid,
         session_token: Session,
         bcast: &broadcast::Sender<BCastMsg>,
         subscriber: &Subscriber,
-        archive: &ArchiveReader,
+        archive: &Reader,
         shards: usize,
         publish_base: &Path,
         cfg: Option<NewSessionConfig>,
@@ -943,6 +1404,7 @@ mod publish {
         let publish_base = publish_base.clone();
         let subscriber = subscriber.clone();
         let publisher_cl = publisher.clone();
+        let status = session_token.0.status(&session_id);
         task::spawn(async move {
             let res = session(
                 bcast,
@@ -951,6 +1413,7 @@ mod publish {
                 publisher_cl,
                 publish_base,
                 session_id,
+                status,
                 shards,
                 cfg,
             )
@@ -970,23 +1433,24 @@ mod publish {
 
     pub(super) async fn run(
         bcast: broadcast::Sender<BCastMsg>,
-        archive: ArchiveReader,
+        archive: Reader,
         resolver: Config,
         desired_auth: DesiredAuth,
-        bind_cfg: Option<BindCfg>,
+        publisher: Publisher,
         publish_base: Path,
         shards: usize,
         max_sessions: usize,
         max_sessions_per_client: usize,
+        export_dir: Option<PathBuf>,
+        stats_interval: Option<Duration>,
     ) -> Result<()> {
         let sessions: Sessions = Sessions::new(max_sessions, max_sessions_per_client);
+        let mut stats = match stats_interval {
+            None => None,
+            Some(_) => Some(Stats::new(&publisher, &publish_base)?),
+        };
+        let mut stats_timer = stats_interval.map(time::interval);
         let subscriber = Subscriber::new(resolver.clone(), desired_auth.clone())?;
-        let mut builder = PublisherBuilder::new();
-        builder.config(resolver.clone()).desired_auth(desired_auth.clone());
-        if let Some(b) = bind_cfg {
-            builder.bind_cfg(b);
-        }
-        let publisher = builder.build().await?;
         let (control_tx, control_rx) = mpsc::channel(3);
         let _new_session: Result<Proc> = define_rpc!(
             &publisher,
@@ -1002,6 +1466,27 @@ mod publish {
             play_after: Option<Duration> = None::<Duration>; PLAY_AFTER_DOC
         );
         let _new_session = _new_session?;
+        let (export_tx, export_rx) = mpsc::channel(3);
+        let _export = match export_dir {
+            None => None,
+            Some(export_dir) => {
+                let _export: Result<Proc> = define_rpc!(
+                    &publisher,
+                    publish_base.append("export"),
+                    "export a time range of the archive to a new archive file",
+                    |req, start, end, filter, file| {
+                        ExportConfig::new(req, &export_dir, start, end, filter, file)
+                    },
+                    Some(export_tx),
+                    start: Value = "Unbounded"; EXPORT_START_DOC,
+                    end: Value = "Unbounded"; EXPORT_END_DOC,
+                    filter: Value = "/**"; EXPORT_FILTER_DOC,
+                    file: Option<Chars> = Value::Null; EXPORT_FILE_DOC
+                );
+                Some(_export?)
+            }
+        };
+        let mut export_rx = export_rx.fuse();
         let mut cluster = Cluster::<(ClId, Uuid)>::new(
             &publisher,
             subscriber.clone(),
@@ -1023,12 +1508,19 @@ mod publish {
                         warn!("failed to poll cluster members, will retry {}", e)
                     }
                 },
+                _ = record::maybe_interval(&mut stats_timer).fuse() => {
+                    if let Some(stats) = &mut stats {
+                        if let Err(e) = stats.update(&publisher, &sessions).await {
+                            warn!("failed to update stats {}", e)
+                        }
+                    }
+                },
                 cmds = cluster.wait_cmds().fuse() => match cmds {
                     Err(e) => {
                         error!("received unparsable cluster commands {}", e)
                     }
                     Ok(cmds) => for (client, session_id) in cmds {
-                        match sessions.add_session(client) {
+                        match sessions.add_session(client, session_id) {
                             None => {
                                 error!("can't start session requested by cluster member, too many sessions")
                             },
@@ -1051,16 +1543,38 @@ mod publish {
                         }
                     }
                 },
+                m = export_rx.next() => if let Some((cfg, mut reply)) = m {
+                    let archive = archive.clone();
+                    task::spawn(async move {
+                        let ExportConfig { start, end, filter, file } = cfg;
+                        info!("export {:?} to {}", (start, end), file.display());
+                        let res = task::spawn_blocking(move || {
+                            archive.export(&filter, (start, end), &file)
+                        })
+                        .await;
+                        match res {
+                            Ok(Ok(n)) => reply.send(Value::from(n as u64)),
+                            Ok(Err(e)) => {
+                                warn!("export failed {}", e);
+                                reply.send(Value::Error(Chars::from(format!("{}", e))))
+                            }
+                            Err(e) => {
+                                warn!("export task failed {}", e);
+                                reply.send(Value::Error(Chars::from(format!("{}", e))))
+                            }
+                        }
+                    });
+                },
                 m = control_rx.next() => match m {
                     None => break Ok(()),
                     Some((cfg, mut reply)) => {
-                        match sessions.add_session(cfg.client) {
+                        let session_id = Uuid::new_v4();
+                        match sessions.add_session(cfg.client, session_id) {
                             None => {
                                 let m = format!("too many sessions, client {:?}", cfg.client);
                                 reply.send(Value::Error(Chars::from(m)));
                             },
                             Some(session_token) => {
-                                let session_id = Uuid::new_v4();
                                 let client = cfg.client;
                                 info!("start session {}", session_id);
                                 let r = start_session(
@@ -1136,7 +1650,7 @@ mod record {
         }
     }
 
-    async fn maybe_interval(poll: &mut Option<time::Interval>) {
+    pub(super) async fn maybe_interval(poll: &mut Option<time::Interval>) {
         match poll {
             None => future::pending().await,
             Some(poll) => {
@@ -1201,54 +1715,674 @@ mod record {
         }
     }
 
+    static ENABLED_DOC: &'static str = "Whether recording is enabled. Set to false to drop all subscriptions and stop recording, set to true to start recording again.";
+    static PAUSED_DOC: &'static str = "Whether recording is paused. While paused subscriptions are kept warm but nothing is written to the archive. If images are being written then an image is written when recording resumes.";
+    static SPEC_DOC: &'static str = "The globs being recorded, as a list of [glob, enabled] pairs. Set to a list of globs, or a list of [glob, enabled] pairs to change what is recorded. Paths that no longer match an enabled glob are unsubscribed.";
+
+    struct Controls {
+        _enabled_doc: Val,
+        enabled_ctl: Val,
+        _paused_doc: Val,
+        paused_ctl: Val,
+        _spec_doc: Val,
+        spec_ctl: Val,
+        batches: Val,
+        bytes: Val,
+    }
+
+    impl Controls {
+        async fn new(
+            base: &Path,
+            publisher: &Publisher,
+            spec: &Vec<(Glob, bool)>,
+            control_tx: &mpsc::Sender<Pooled<Vec<WriteRequest>>>,
+        ) -> Result<Self> {
+            let _enabled_doc = publisher.publish(
+                base.append("enabled/doc"),
+                Value::String(Chars::from(ENABLED_DOC)),
+            )?;
+            let _paused_doc = publisher.publish(
+                base.append("paused/doc"),
+                Value::String(Chars::from(PAUSED_DOC)),
+            )?;
+            let _spec_doc = publisher
+                .publish(base.append("spec/doc"), Value::String(Chars::from(SPEC_DOC)))?;
+            let enabled_ctl = publisher.publish_with_flags(
+                PublishFlags::USE_EXISTING,
+                base.append("enabled/current"),
+                Value::True,
+            )?;
+            publisher.writes(enabled_ctl.id(), control_tx.clone());
+            let paused_ctl = publisher.publish_with_flags(
+                PublishFlags::USE_EXISTING,
+                base.append("paused/current"),
+                Value::False,
+            )?;
+            publisher.writes(paused_ctl.id(), control_tx.clone());
+            let spec_ctl = publisher.publish_with_flags(
+                PublishFlags::USE_EXISTING,
+                base.append("spec/current"),
+                spec_to_value(spec),
+            )?;
+            publisher.writes(spec_ctl.id(), control_tx.clone());
+            let batches =
+                publisher.publish(base.append("stats/batches"), Value::U64(0))?;
+            let bytes = publisher.publish(base.append("stats/bytes"), Value::U64(0))?;
+            publisher.flushed().await;
+            Ok(Controls {
+                _enabled_doc,
+                enabled_ctl,
+                _paused_doc,
+                paused_ctl,
+                _spec_doc,
+                spec_ctl,
+                batches,
+                bytes,
+            })
+        }
+    }
+
+    fn spec_to_value(spec: &Vec<(Glob, bool)>) -> Value {
+        spec.iter()
+            .map(|(g, enabled)| (g.raw().clone(), *enabled))
+            .collect::<Vec<_>>()
+            .into()
+    }
+
+    fn parse_spec(v: Value) -> Result<Vec<(Glob, bool)>> {
+        let spec = match v.clone().cast_to::<Vec<(Chars, bool)>>() {
+            Ok(spec) => spec,
+            Err(_) => v
+                .cast_to::<Vec<Chars>>()
+                .map_err(|_| {
+                    anyhow!("expected a list of globs or [glob, enabled] pairs")
+                })?
+                .into_iter()
+                .map(|g| (g, true))
+                .collect(),
+        };
+        spec.into_iter().map(|(g, enabled)| Ok((Glob::new(g)?, enabled))).collect()
+    }
+
+    fn active_spec(spec: &Vec<(Glob, bool)>) -> Vec<Glob> {
+        spec.iter().filter(|(_, enabled)| *enabled).map(|(g, _)| g.clone()).collect()
+    }
+
+    fn reply_err(req: WriteRequest, e: impl std::fmt::Display) {
+        if let Some(reply) = req.send_result {
+            reply.send(Value::Error(Chars::from(format!("{}", e))))
+        }
+    }
+
+    fn reply_ok(req: WriteRequest) {
+        if let Some(reply) = req.send_result {
+            reply.send(Value::Ok)
+        }
+    }
+
+    fn write_image(
+        archive: &mut ArchiveWriter,
+        timest: &mut MonotonicTimestamper,
+        image: &FxHashMap<SubId, Event>,
+        by_subid: &FxHashMap<SubId, Id>,
+    ) -> Result<()> {
+        let mut b = BATCH_POOL.take();
+        let ts = timest.timestamp();
+        for (id, ev) in image.iter() {
+            b.push(BatchItem(by_subid[id], ev.clone()));
+        }
+        archive.add_batch(true, ts, &b)
+    }
+
+    type Batches = Vec<Pooled<Vec<(SubId, Event)>>>;
+
+    #[derive(Debug, Default)]
+    struct Stats {
+        paths: AtomicU64,
+        batches: AtomicU64,
+        bytes: AtomicU64,
+        archive_size: AtomicU64,
+        last_flush: Mutex<Option<DateTime<Utc>>>,
+    }
+
+    // operational stats, published under publish_base/stats/record
+    struct StatsVals {
+        paths: Val,
+        batches: Val,
+        batches_per_sec: Val,
+        archive_size: Val,
+        last_flush: Val,
+        last_batches: u64,
+        last_update: time::Instant,
+    }
+
+    impl StatsVals {
+        fn new(publisher: &Publisher, publish_base: &Path) -> Result<Self> {
+            let base = publish_base.append("stats/record");
+            let paths = publisher.publish(base.append("paths"), Value::U64(0))?;
+            let batches = publisher.publish(base.append("batches"), Value::U64(0))?;
+            let batches_per_sec =
+                publisher.publish(base.append("batches_per_sec"), Value::F64(0.))?;
+            let archive_size =
+                publisher.publish(base.append("archive_size"), Value::U64(0))?;
+            let last_flush = publisher.publish(base.append("last_flush"), Value::Null)?;
+            Ok(StatsVals {
+                paths,
+                batches,
+                batches_per_sec,
+                archive_size,
+                last_flush,
+                last_batches: 0,
+                last_update: time::Instant::now(),
+            })
+        }
+
+        async fn update(&mut self, publisher: &Publisher, stats: &Stats) {
+            let now = time::Instant::now();
+            let elapsed = now.duration_since(self.last_update).as_secs_f64();
+            let batches = stats.batches.load(Ordering::Relaxed);
+            let rate = if elapsed > 0. {
+                (batches - self.last_batches) as f64 / elapsed
+            } else {
+                0.
+            };
+            self.last_batches = batches;
+            self.last_update = now;
+            let mut cbatch = publisher.start_batch();
+            self.paths.update_changed(&mut cbatch, stats.paths.load(Ordering::Relaxed));
+            self.batches.update_changed(&mut cbatch, batches);
+            self.batches_per_sec.update_changed(&mut cbatch, rate);
+            let size = stats.archive_size.load(Ordering::Relaxed);
+            self.archive_size.update_changed(&mut cbatch, size);
+            let last_flush = match *stats.last_flush.lock() {
+                None => Value::Null,
+                Some(ts) => Value::DateTime(ts),
+            };
+            self.last_flush.update_changed(&mut cbatch, last_flush);
+            cbatch.commit(None).await
+        }
+    }
+
+    #[derive(Debug)]
+    enum ToWriter {
+        // assign archive ids to newly subscribed paths
+        Paths(Vec<(Path, SubId)>),
+        // a complete batch of updates to record
+        Batch(Batches),
+        // updates that arrived while paused, they only go in the image
+        Paused(Batches),
+        // recording resumed, write an image if we are writing images,
+        // and reply to the control write that resumed it
+        Resume(Option<SendResult>),
+        // these subscriptions were dropped, remove them from the image
+        Forget(Vec<SubId>),
+        // recording was disabled
+        Clear,
+        Flush,
+    }
+
+    // rotating segments, see --segment-dir
+    pub(super) struct Rotate {
+        tiered: Tiered,
+        // the segment being written
+        name: String,
+        interval: Option<Duration>,
+        next: Option<Instant>,
+        backend: Backend,
+        // sessions play back a federation of the segments, new
+        // segments are added to it as they are started
+        playback: Option<FederatedReader>,
+    }
+
+    impl Rotate {
+        pub(super) fn new(
+            tiered: Tiered,
+            name: String,
+            interval: Option<Duration>,
+            backend: Backend,
+            playback: Option<FederatedReader>,
+        ) -> Self {
+            let next = interval.map(|i| Instant::now() + i);
+            Rotate { tiered, name, interval, next, backend, playback }
+        }
+
+        fn close(&self) {
+            match self.tiered.close_segment(&self.name) {
+                Ok(_) => info!("closed segment {}", self.name),
+                // it will be closed by recovery the next time we start
+                Err(e) => error!("failed to close segment {}: {}", self.name, e),
+            }
+        }
+    }
+
+    // The writer owns the archive and runs on it's own thread, so
+    // that flushing, remapping, and writing large batches never
+    // stall subscription processing. Timestamps are assigned when a
+    // batch is written.
+    struct WriterCtx {
+        bcast: broadcast::Sender<BCastMsg>,
+        archive: ArchiveWriter,
+        image_frequency: Option<usize>,
+        flush_frequency: Option<usize>,
+        by_subid: FxHashMap<SubId, Id>,
+        image: FxHashMap<SubId, Event>,
+        timest: MonotonicTimestamper,
+        last_image: usize,
+        last_flush: usize,
+        initial_len: usize,
+        // bytes written to segments that were closed
+        rotated_bytes: usize,
+        rotate: Option<Rotate>,
+        stats: Arc<Stats>,
+    }
+
+    impl WriterCtx {
+        fn add_paths(&mut self, mut paths: Vec<(Path, SubId)>) -> Result<()> {
+            self.archive.add_paths(paths.iter().map(|(p, _)| p))?;
+            for (path, subid) in paths.drain(..) {
+                if !self.by_subid.contains_key(&subid) {
+                    let id = self.archive.id_for_path(&path).unwrap();
+                    self.by_subid.insert(subid, id);
+                }
+            }
+            self.stats.paths.store(self.by_subid.len() as u64, Ordering::Relaxed);
+            Ok(())
+        }
+
+        fn update_image(&mut self, mut batches: Batches) {
+            if self.image_frequency.is_some() {
+                for mut batch in batches.drain(..) {
+                    for (subid, ev) in batch.drain(..) {
+                        if self.by_subid.contains_key(&subid) {
+                            self.image.insert(subid, ev);
+                        }
+                    }
+                }
+            }
+        }
+
+        fn write_image(&mut self) -> Result<()> {
+            write_image(
+                &mut self.archive,
+                &mut self.timest,
+                &self.image,
+                &self.by_subid,
+            )?;
+            self.last_image = self.archive.len();
+            Ok(())
+        }
+
+        fn flush(&mut self) -> Result<()> {
+            if self.archive.len() > self.last_flush {
+                self.archive.flush()?;
+                self.last_flush = self.archive.len();
+                *self.stats.last_flush.lock() = Some(Utc::now());
+            }
+            Ok(())
+        }
+
+        fn write_batch(&mut self, mut batches: Batches) -> Result<()> {
+            let mut overflow = Vec::new();
+            let mut tbatch = BATCH_POOL.take();
+            for mut batch in batches.drain(..) {
+                for (subid, ev) in batch.drain(..) {
+                    // subscriptions dropped by disabling recording may
+                    // still have updates in flight
+                    if let Some(id) = self.by_subid.get(&subid) {
+                        if self.image_frequency.is_some() {
+                            self.image.insert(subid, ev.clone());
+                        }
+                        tbatch.push(BatchItem(*id, ev));
+                    }
+                }
+            }
+            if tbatch.is_empty() {
+                return Ok(());
+            }
+            loop {
+                // handle batches >4 GiB
+                let ts = self.timest.timestamp();
+                match self.archive.add_batch(false, ts, &tbatch) {
+                    Err(e) if e.is::<RecordTooLarge>() => {
+                        let at = tbatch.len() >> 1;
+                        overflow.push(tbatch.split_off(at));
+                    }
+                    Err(e) => bail!(e),
+                    Ok(()) => {
+                        self.stats.batches.fetch_add(1, Ordering::Relaxed);
+                        let m = BCastMsg::Batch(ts, Arc::new(tbatch));
+                        let _ = self.bcast.send(m);
+                        match overflow.pop() {
+                            None => break,
+                            Some(b) => {
+                                tbatch = Pooled::orphan(b);
+                            }
+                        }
+                    }
+                }
+            }
+            match self.image_frequency {
+                None => (),
+                Some(freq) if self.archive.len() - self.last_image < freq => (),
+                Some(_) => self.write_image()?,
+            }
+            match self.flush_frequency {
+                None => (),
+                Some(freq) if self.archive.len() - self.last_flush < freq => (),
+                Some(_) => self.flush()?,
+            }
+            let bytes =
+                (self.rotated_bytes + self.archive.len() - self.initial_len) as u64;
+            self.stats.bytes.store(bytes, Ordering::Relaxed);
+            self.stats.archive_size.store(self.archive.len() as u64, Ordering::Relaxed);
+            Ok(())
+        }
+
+        // close the current segment and start a new one if it's time
+        fn maybe_rotate(&mut self) -> Result<()> {
+            let rot = match &mut self.rotate {
+                Some(rot) if rot.next.map(|n| Instant::now() >= n).unwrap_or(false) => {
+                    rot
+                }
+                Some(_) | None => return Ok(()),
+            };
+            let (name, mut archive) = rot.tiered.new_segment(rot.backend)?;
+            // the new segment needs every path we are recording
+            let paths = self
+                .by_subid
+                .iter()
+                .filter_map(|(subid, id)| {
+                    Some((self.archive.path_for_id(id)?.clone(), *subid))
+                })
+                .collect::<Vec<_>>();
+            archive.add_paths(paths.iter().map(|(p, _)| p))?;
+            if let Some(playback) = &rot.playback {
+                playback.add_reader(rot.tiered.dir().join(&name), archive.reader()?)
+            }
+            self.by_subid = paths
+                .into_iter()
+                .map(|(path, subid)| (subid, archive.id_for_path(&path).unwrap()))
+                .collect();
+            self.rotated_bytes += self.archive.len() - self.initial_len;
+            self.initial_len = archive.len();
+            self.last_image = archive.len();
+            self.last_flush = archive.len();
+            // dropping the writer flushes and closes it
+            drop(mem::replace(&mut self.archive, archive));
+            rot.close();
+            rot.name = name;
+            rot.next = rot.interval.map(|i| Instant::now() + i);
+            self.timest.reset();
+            *self.stats.last_flush.lock() = Some(Utc::now());
+            self.stats.archive_size.store(self.archive.len() as u64, Ordering::Relaxed);
+            if self.image_frequency.is_some() {
+                self.write_image()?
+            }
+            Ok(())
+        }
+
+        fn run(mut self, mut rx: mpsc::Receiver<ToWriter>) -> Result<()> {
+            while let Some(m) = executor::block_on(rx.next()) {
+                match m {
+                    ToWriter::Paths(paths) => self.add_paths(paths)?,
+                    ToWriter::Batch(batches) => self.write_batch(batches)?,
+                    ToWriter::Paused(batches) => self.update_image(batches),
+                    ToWriter::Resume(reply) => {
+                        let res = match self.image_frequency {
+                            None => Ok(()),
+                            Some(_) => self.write_image(),
+                        };
+                        match res {
+                            Ok(()) => {
+                                if let Some(reply) = reply {
+                                    reply.send(Value::Ok)
+                                }
+                            }
+                            Err(e) => {
+                                error!("failed to write image on resume: {}", e);
+                                if let Some(reply) = reply {
+                                    let e = format!("failed to write image: {}", e);
+                                    reply.send(Value::Error(Chars::from(e)))
+                                }
+                            }
+                        }
+                    }
+                    ToWriter::Forget(mut ids) => {
+                        for id in ids.drain(..) {
+                            self.image.remove(&id);
+                        }
+                    }
+                    ToWriter::Clear => {
+                        self.image.clear();
+                        self.by_subid.clear();
+                        self.stats.paths.store(0, Ordering::Relaxed);
+                    }
+                    ToWriter::Flush => self.flush()?,
+                }
+                self.maybe_rotate()?
+            }
+            let WriterCtx { archive, rotate, .. } = self;
+            drop(archive);
+            if let Some(rot) = rotate {
+                rot.close()
+            }
+            Ok(())
+        }
+    }
+
+    struct Writer {
+        tx: mpsc::Sender<ToWriter>,
+        thread: Option<thread::JoinHandle<Result<()>>>,
+        stats: Arc<Stats>,
+    }
+
+    impl Writer {
+        fn start(
+            bcast: broadcast::Sender<BCastMsg>,
+            archive: ArchiveWriter,
+            image_frequency: Option<usize>,
+            flush_frequency: Option<usize>,
+            queue: usize,
+            rotate: Option<Rotate>,
+        ) -> Result<Writer> {
+            let (tx, rx) = mpsc::channel(queue);
+            let stats = Arc::new(Stats::default());
+            stats.archive_size.store(archive.len() as u64, Ordering::Relaxed);
+            let ctx = WriterCtx {
+                bcast,
+                image_frequency,
+                flush_frequency: flush_frequency.map(|f| archive.block_size() * f),
+                by_subid: HashMap::default(),
+                image: HashMap::default(),
+                timest: MonotonicTimestamper::new(),
+                last_image: archive.len(),
+                last_flush: archive.len(),
+                initial_len: archive.len(),
+                rotated_bytes: 0,
+                rotate,
+                archive,
+                stats: stats.clone(),
+            };
+            let thread = thread::Builder::new()
+                .name("archive-writer".into())
+                .spawn(move || ctx.run(rx))?;
+            Ok(Writer { tx, thread: Some(thread), stats })
+        }
+
+        // queue `m` for the writer, waiting if the queue is full. If
+        // the writer has died return the error it died with.
+        async fn send(&mut self, m: ToWriter) -> Result<()> {
+            match self.tx.send(m).await {
+                Ok(()) => Ok(()),
+                Err(_) => {
+                    self.stop()?;
+                    bail!("archive writer exited unexpectedly")
+                }
+            }
+        }
+
+        // wait for the writer to finish everything queued, close the
+        // archive, and exit.
+        fn stop(&mut self) -> Result<()> {
+            self.tx.close_channel();
+            match self.thread.take() {
+                None => Ok(()),
+                Some(thread) => match task::block_in_place(|| thread.join()) {
+                    Ok(res) => res,
+                    Err(_) => bail!("archive writer panicked"),
+                },
+            }
+        }
+    }
+
     pub(super) async fn run(
         bcast: broadcast::Sender<BCastMsg>,
-        mut archive: ArchiveWriter,
+        archive: ArchiveWriter,
         resolver: Config,
         desired_auth: DesiredAuth,
+        publish: Option<(Publisher, Path)>,
         poll_interval: Option<time::Duration>,
         image_frequency: Option<usize>,
         flush_frequency: Option<usize>,
         flush_interval: Option<time::Duration>,
+        stats_interval: Option<time::Duration>,
+        write_queue: usize,
+        rotate: Option<Rotate>,
         spec: Vec<Glob>,
     ) -> Result<()> {
         let (tx_batch, rx_batch) = mpsc::channel(10);
-        let (tx_list, rx_list) = mpsc::unbounded();
+        let (control_tx, control_rx) = mpsc::channel(3);
+        let mut control_rx = control_rx.fuse();
         let mut rx_batch = utils::Batched::new(rx_batch.fuse(), 10);
-        let mut by_subid: FxHashMap<SubId, Id> = HashMap::default();
-        let mut image: FxHashMap<SubId, Event> = HashMap::default();
         let mut subscribed: HashMap<Path, Dval> = HashMap::new();
         let subscriber = Subscriber::new(resolver, desired_auth)?;
-        let flush_frequency = flush_frequency.map(|f| archive.block_size() * f);
         let mut bcast_rx = bcast.subscribe();
+        let mut writer = Writer::start(
+            bcast,
+            archive,
+            image_frequency,
+            flush_frequency,
+            write_queue,
+            rotate,
+        )?;
         let mut poll = poll_interval.map(time::interval);
         let mut flush = flush_interval.map(time::interval);
         let mut to_add = Vec::new();
-        let mut timest = MonotonicTimestamper::new();
-        let mut last_image = archive.len();
-        let mut last_flush = archive.len();
         let mut pending_list: Option<Fuse<oneshot::Receiver<Lst>>> = None;
-        let mut pending_batches: Vec<Pooled<Vec<(SubId, Event)>>> = Vec::new();
-        start_list_task(rx_list, subscriber.resolver(), spec);
+        let mut pending_batches: Batches = Vec::new();
+        let mut spec = spec.into_iter().map(|g| (g, true)).collect::<Vec<_>>();
+        let mut enabled = true;
+        let mut paused = false;
+        let controls = match &publish {
+            None => None,
+            Some((publisher, base)) => {
+                let base = base.append("record/control");
+                Some(Controls::new(&base, publisher, &spec, &control_tx).await?)
+            }
+        };
+        let mut stats = match (&publish, stats_interval) {
+            (Some((publisher, base)), Some(_)) => Some(StatsVals::new(publisher, base)?),
+            (_, _) => None,
+        };
+        let mut stats_timer = stats_interval.map(time::interval);
+        let (tx, rx) = mpsc::unbounded();
+        let mut tx_list = Some(tx);
+        start_list_task(rx, subscriber.resolver(), active_spec(&spec));
         loop {
             select_biased! {
                 m = bcast_rx.recv().fuse() => match m {
                     Err(_) | Ok(BCastMsg::Batch(_, _)) => (),
                     Ok(BCastMsg::Stop) => break,
                 },
+                reqs = control_rx.next() => if let Some(mut reqs) = reqs {
+                    let (publisher, controls) = match (&publish, &controls) {
+                        (Some((publisher, _)), Some(controls)) => (publisher, controls),
+                        (_, _) => continue,
+                    };
+                    let mut cbatch = publisher.start_batch();
+                    let mut restart_list = false;
+                    for req in reqs.drain(..) {
+                        if req.id == controls.enabled_ctl.id() {
+                            info!("set recording enabled: {}", req.value);
+                            match req.value.clone().cast_to::<bool>() {
+                                Err(e) => reply_err(req, e),
+                                Ok(en) => {
+                                    if en != enabled {
+                                        enabled = en;
+                                        restart_list = true;
+                                        if !enabled {
+                                            subscribed.clear();
+                                            writer.send(ToWriter::Clear).await?;
+                                        }
+                                    }
+                                    controls.enabled_ctl.update(&mut cbatch, en);
+                                    reply_ok(req);
+                                }
+                            }
+                        } else if req.id == controls.paused_ctl.id() {
+                            info!("set recording paused: {}", req.value);
+                            match req.value.clone().cast_to::<bool>() {
+                                Err(e) => reply_err(req, e),
+                                Ok(p) => {
+                                    if paused && !p {
+                                        let reply = req.send_result;
+                                        writer.send(ToWriter::Resume(reply)).await?;
+                                    } else {
+                                        reply_ok(req);
+                                    }
+                                    paused = p;
+                                    controls.paused_ctl.update(&mut cbatch, p);
+                                }
+                            }
+                        } else if req.id == controls.spec_ctl.id() {
+                            info!("set recording spec: {}", req.value);
+                            match parse_spec(req.value.clone()) {
+                                Err(e) => reply_err(req, e),
+                                Ok(new_spec) => {
+                                    let globs = GlobSet::new(true, active_spec(&new_spec))?;
+                                    let mut forget = Vec::new();
+                                    subscribed.retain(|path, dv| {
+                                        globs.is_match(path) || {
+                                            forget.push(dv.id());
+                                            false
+                                        }
+                                    });
+                                    writer.send(ToWriter::Forget(forget)).await?;
+                                    spec = new_spec;
+                                    restart_list = true;
+                                    controls.spec_ctl.update(&mut cbatch, spec_to_value(&spec));
+                                    reply_ok(req);
+                                }
+                            }
+                        }
+                    }
+                    if restart_list {
+                        pending_list = None;
+                        tx_list = None;
+                        if enabled {
+                            let (tx, rx) = mpsc::unbounded();
+                            start_list_task(rx, subscriber.resolver(), active_spec(&spec));
+                            let (reply, pending) = oneshot::channel();
+                            let _ = tx.unbounded_send(reply);
+                            pending_list = Some(pending.fuse());
+                            tx_list = Some(tx);
+                        }
+                    }
+                    cbatch.commit(None).await
+                },
                 _ = maybe_interval(&mut poll).fuse() => {
-                    if pending_list.is_none() {
+                    if let (None, Some(tx_list)) = (&pending_list, &tx_list) {
                         let (tx, rx) = oneshot::channel();
                         let _ = tx_list.unbounded_send(tx);
                         pending_list = Some(rx.fuse());
                     }
                 },
                 _ = maybe_interval(&mut flush).fuse() => {
-                    if archive.len() > last_flush {
-                        task::block_in_place(|| -> Result<()> {
-                            archive.flush()?;
-                            Ok(last_flush = archive.len())
-                        })?;
+                    writer.send(ToWriter::Flush).await?
+                }
+                _ = maybe_interval(&mut stats_timer).fuse() => {
+                    if let (Some((publisher, _)), Some(stats)) = (&publish, &mut stats) {
+                        stats.update(publisher, &writer.stats).await
                     }
                 }
                 r = wait_list(&mut pending_list).fuse() => {
@@ -1269,15 +2403,8 @@ mod record {
                                 }
                             }
                         }
-                        task::block_in_place(|| {
-                            let i = to_add.iter().map(|(ref p, _)| p);
-                            archive.add_paths(i)
-                        })?;
-                        for (path, subid) in to_add.drain(..) {
-                            if !by_subid.contains_key(&subid) {
-                                let id = archive.id_for_path(&path).unwrap();
-                                by_subid.insert(subid, id);
-                            }
+                        if !to_add.is_empty() {
+                            writer.send(ToWriter::Paths(mem::take(&mut to_add))).await?
                         }
                     }
                 },
@@ -1286,85 +2413,34 @@ mod record {
                     Some(utils::BatchItem::InBatch(batch)) => {
                         pending_batches.push(batch);
                     },
+                    Some(utils::BatchItem::EndBatch) if !enabled => {
+                        pending_batches.clear();
+                    }
+                    Some(utils::BatchItem::EndBatch) if paused => {
+                        // keep the image up to date so that it is
+                        // correct when we resume
+                        let batches = mem::take(&mut pending_batches);
+                        writer.send(ToWriter::Paused(batches)).await?
+                    }
                     Some(utils::BatchItem::EndBatch) => {
-                        let mut overflow = Vec::new();
-                        let mut tbatch = BATCH_POOL.take();
-                        task::block_in_place(|| -> Result<()> {
-                            for mut batch in pending_batches.drain(..) {
-                                for (subid, ev) in batch.drain(..) {
-                                    if image_frequency.is_some() {
-                                        image.insert(subid, ev.clone());
-                                    }
-                                    tbatch.push(BatchItem(by_subid[&subid], ev));
-                                }
-                            }
-                            loop { // handle batches >4 GiB
-                                let ts = timest.timestamp();
-                                match archive.add_batch(false, ts, &tbatch) {
-                                    Err(e) if e.is::<RecordTooLarge>() => {
-                                        let at = tbatch.len() >> 1;
-                                        overflow.push(tbatch.split_off(at));
-                                    }
-                                    Err(e) => bail!(e),
-                                    Ok(()) => {
-                                        let m = BCastMsg::Batch(ts, Arc::new(tbatch));
-                                        let _ = bcast.send(m);
-                                        match overflow.pop() {
-                                            None => break,
-                                            Some(b) => { tbatch = Pooled::orphan(b); }
-                                        }
-                                    }
-                                }
-                            }
-                            match image_frequency {
-                                None => (),
-                                Some(freq) if archive.len() - last_image < freq => (),
-                                Some(_) => {
-                                    let mut b = BATCH_POOL.take();
-                                    let ts = timest.timestamp();
-                                    for (id, ev) in image.iter() {
-                                        b.push(BatchItem(by_subid[id], ev.clone()));
-                                    }
-                                    archive.add_batch(true, ts, &b)?;
-                                    last_image = archive.len();
-                                }
-                            }
-                            match flush_frequency {
-                                None => (),
-                                Some(freq) if archive.len() - last_flush < freq => (),
-                                Some(_) => {
-                                    archive.flush()?;
-                                    last_flush = archive.len();
-                                }
-                            }
-                            Ok(())
-                        })?;
+                        let batches = mem::take(&mut pending_batches);
+                        writer.send(ToWriter::Batch(batches)).await?;
+                        if let (Some((publisher, _)), Some(controls)) = (&publish, &controls) {
+                            let mut cbatch = publisher.start_batch();
+                            let batches = writer.stats.batches.load(Ordering::Relaxed);
+                            controls.batches.update(&mut cbatch, batches);
+                            let bytes = writer.stats.bytes.load(Ordering::Relaxed);
+                            controls.bytes.update(&mut cbatch, bytes);
+                            cbatch.commit(None).await
+                        }
                     }
                 }
             }
         }
-        Ok(())
-    }
-}
-
-#[cfg(unix)]
-async fn should_exit() -> Result<()> {
-    use tokio::signal::unix::{signal, SignalKind};
-    let mut term = signal(SignalKind::terminate())?;
-    let mut quit = signal(SignalKind::quit())?;
-    let mut intr = signal(SignalKind::interrupt())?;
-    select_biased! {
-        _ = term.recv().fuse() => Ok(()),
-        _ = quit.recv().fuse() => Ok(()),
-        _ = intr.recv().fuse() => Ok(()),
+        writer.stop()
     }
 }
 
-#[cfg(windows)]
-async fn should_exit() -> Result<()> {
-    Ok(signal::ctrl_c().await?)
-}
-
 async fn run_async(
     config: Config,
     publish_args: Option<(Option<BindCfg>, Path)>,
@@ -1373,25 +2449,65 @@ async fn run_async(
     poll_interval: Option<time::Duration>,
     flush_frequency: Option<usize>,
     flush_interval: Option<time::Duration>,
+    stats_interval: Option<time::Duration>,
+    write_queue: usize,
+    backend: Backend,
     shards: usize,
     max_sessions: usize,
     max_sessions_per_client: usize,
-    archive: String,
+    export_dir: Option<PathBuf>,
+    archive: Option<String>,
+    archive_dir: Option<PathBuf>,
+    segments: Option<Tiered>,
+    rotate_interval: Option<time::Duration>,
     spec: Vec<Glob>,
 ) {
     let mut wait = Vec::new();
     let (bcast_tx, bcast_rx) = broadcast::channel(100);
     drop(bcast_rx);
-    let writer = if spec.is_empty() {
-        None
-    } else {
-        Some(ArchiveWriter::open(archive.as_str()).unwrap())
+    let (writer, segment) = match (spec.is_empty(), &segments) {
+        (true, _) => (None, None),
+        (false, None) => {
+            let archive = archive.as_ref().unwrap();
+            let writer = ArchiveWriter::open_with_backend(archive.as_str(), backend);
+            (Some(writer.unwrap()), None)
+        }
+        (false, Some(tiered)) => {
+            let (name, writer) = tiered.new_segment(backend).unwrap();
+            (Some(writer), Some(name))
+        }
+    };
+    let mut playback = None;
+    let publish_args = match publish_args {
+        None => None,
+        Some((bind_cfg, publish_base)) => {
+            let mut builder = PublisherBuilder::new();
+            builder.config(config.clone()).desired_auth(auth.clone());
+            if let Some(b) = bind_cfg {
+                builder.bind_cfg(b);
+            }
+            Some((builder.build().await.unwrap(), publish_base))
+        }
     };
-    if let Some((bind_cfg, publish_base)) = publish_args {
-        let reader = writer
-            .as_ref()
-            .map(|w| w.reader().unwrap())
-            .unwrap_or_else(|| ArchiveReader::open(archive.as_str()).unwrap());
+    if let Some((publisher, publish_base)) = publish_args.clone() {
+        let reader = match (&writer, &archive, &archive_dir, &segments) {
+            (_, _, _, Some(tiered)) => {
+                let r = FederatedReader::open_tiered(tiered.clone()).unwrap();
+                if let (Some(w), Some(name)) = (&writer, &segment) {
+                    r.add_reader(tiered.dir().join(name), w.reader().unwrap())
+                }
+                playback = Some(r.clone());
+                Reader::Federated(r)
+            }
+            (Some(w), _, _, None) => Reader::Single(w.reader().unwrap()),
+            (None, Some(archive), _, None) => {
+                Reader::Single(ArchiveReader::open(archive.as_str()).unwrap())
+            }
+            (None, None, Some(dir), None) => {
+                Reader::Federated(FederatedReader::open_dir(dir).unwrap())
+            }
+            (None, None, None, None) => unreachable!(),
+        };
         let bcast_tx = bcast_tx.clone();
         let config = config.clone();
         let auth = auth.clone();
@@ -1401,11 +2517,13 @@ async fn run_async(
                 reader,
                 config,
                 auth,
-                bind_cfg,
+                publisher,
                 publish_base,
                 shards,
                 max_sessions,
                 max_sessions_per_client,
+                export_dir,
+                stats_interval,
             )
             .await;
             match res {
@@ -1416,16 +2534,30 @@ async fn run_async(
     }
     if !spec.is_empty() {
         let bcast_tx = bcast_tx.clone();
+        let rotate = match (segments, segment) {
+            (Some(tiered), Some(name)) => Some(record::Rotate::new(
+                tiered,
+                name,
+                rotate_interval,
+                backend,
+                playback,
+            )),
+            (_, _) => None,
+        };
         wait.push(task::spawn(async move {
             let res = record::run(
                 bcast_tx,
                 writer.unwrap(),
                 config,
                 auth,
+                publish_args,
                 poll_interval,
                 image_frequency,
                 flush_frequency,
                 flush_interval,
+                stats_interval,
+                write_queue,
+                rotate,
                 spec,
             )
             .await;
@@ -1436,14 +2568,18 @@ async fn run_async(
         }));
     }
     let mut dead = future::join_all(wait).fuse();
+    service::ready("running");
+    let watchdog = task::spawn(service::watchdog());
     loop {
         select_biased! {
-            _ = should_exit().fuse() => {
+            _ = service::should_exit().fuse() => {
+                service::stopping("flushing the archive");
                 let _ = bcast_tx.send(BCastMsg::Stop);
             },
             _ = dead => break
         }
     }
+    watchdog.abort();
 }
 
 pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
@@ -1461,6 +2597,11 @@ pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
     } else {
         Some(time::Duration::from_secs(params.flush_interval))
     };
+    let stats_interval = if params.stats_interval == 0 {
+        None
+    } else {
+        Some(time::Duration::from_secs(params.stats_interval))
+    };
     let publish_args = match (params.bind, params.publish_base) {
         (None, None) => None,
         (None, Some(publish_base)) => Some((None, publish_base)),
@@ -1480,6 +2621,33 @@ pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
     if params.spec.is_empty() && publish_args.is_none() {
         panic!("you must specify a publish config, some paths to log, or both")
     }
+    match (&params.archive, &params.archive_dir, &params.segment_dir) {
+        (Some(_), None, None) | (None, None, Some(_)) => (),
+        (None, Some(_), None) if params.spec.is_empty() => (),
+        (None, Some(_), None) => panic!("recording is not supported with --archive-dir"),
+        (_, _, _) => panic!(
+            "you must specify exactly one of --archive, --archive-dir, or --segment-dir"
+        ),
+    }
+    let segments = match (params.segment_dir, params.offload_dir) {
+        (None, None) => None,
+        (None, Some(_)) => panic!("--offload-dir requires --segment-dir"),
+        (Some(_), None) => panic!("you must specify --offload-dir with --segment-dir"),
+        (Some(dir), Some(offload_dir)) => {
+            let store = Arc::new(DirStore::new(offload_dir).unwrap());
+            let tiered = Tiered::open(dir, store).unwrap();
+            // only the recorder writing the segments may close them
+            if !params.spec.is_empty() {
+                tiered.recover().unwrap()
+            }
+            Some(tiered)
+        }
+    };
+    let rotate_interval = if params.rotate_interval == 0 {
+        None
+    } else {
+        Some(time::Duration::from_secs(params.rotate_interval))
+    };
     let spec = params
         .spec
         .into_iter()
@@ -1496,10 +2664,17 @@ pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
         poll_interval,
         flush_frequency,
         flush_interval,
+        stats_interval,
+        params.write_queue,
+        params.backend,
         params.shards,
         params.max_sessions,
         params.max_sessions_per_client,
+        params.export_dir,
         params.archive,
+        params.archive_dir,
+        segments,
+        rotate_interval,
         spec,
     ))
 }
@@ -3,10 +3,14 @@ use netidx::{
     chars::Chars,
     config::Config,
     path::Path,
-    protocol::glob::{Glob, GlobSet},
+    pool::Pooled,
+    protocol::{
+        glob::{Glob, GlobSet},
+        resolver::{Auth, Referral},
+    },
     resolver_client::{ChangeTracker, DesiredAuth, ResolverRead, ResolverWrite},
 };
-use std::{collections::HashSet, iter, net::SocketAddr, time::Duration};
+use std::{collections::HashSet, iter, net::SocketAddr, process, time::Duration};
 use structopt::StructOpt;
 use tokio::{runtime::Runtime, time};
 
@@ -14,7 +18,11 @@ use tokio::{runtime::Runtime, time};
 pub(super) enum ResolverCmd {
     #[structopt(name = "resolve", about = "resolve an in the resolver server")]
     Resolve { path: Vec<Path> },
-    #[structopt(name = "list", about = "list entries in the resolver server")]
+    #[structopt(
+        name = "list",
+        alias = "ls",
+        about = "list entries in the resolver server"
+    )]
     List {
         #[structopt(
             long = "no-structure",
@@ -25,12 +33,44 @@ pub(super) enum ResolverCmd {
         #[structopt(
             long = "watch",
             short = "w",
-            help = "poll the resolver for new paths matching the specified pattern"
+            help = "poll the resolver and print add and remove events for paths matching the pattern"
         )]
         watch: bool,
+        #[structopt(
+            long = "interval",
+            help = "how often to poll the resolver in watch mode (seconds)",
+            default_value = "5"
+        )]
+        interval: u64,
+        #[structopt(
+            long = "recursive",
+            short = "r",
+            help = "list everything under the path, not just it's children"
+        )]
+        recursive: bool,
+        #[structopt(
+            long = "max-depth",
+            help = "don't list paths more than this many levels below the base of the pattern"
+        )]
+        max_depth: Option<usize>,
         #[structopt(name = "pattern")]
         path: Option<String>,
     },
+    #[structopt(
+        name = "trace",
+        about = "resolve a path, printing the referrals followed and the referral cache"
+    )]
+    Trace {
+        #[structopt(
+            long = "timeout",
+            short = "t",
+            help = "how long each hop may take (seconds)",
+            default_value = "10"
+        )]
+        timeout: u64,
+        #[structopt(name = "path")]
+        path: Path,
+    },
     #[structopt(name = "table", about = "table descriptor for path")]
     Table {
         #[structopt(name = "path")]
@@ -50,6 +90,34 @@ pub(super) enum ResolverCmd {
         #[structopt(name = "socketaddr")]
         socketaddr: SocketAddr,
     },
+    #[structopt(name = "delegate", about = "delegate a subtree to other resolvers")]
+    Delegate {
+        #[structopt(
+            long = "ttl",
+            help = "how long clients may cache the delegation in seconds",
+            default_value = "60"
+        )]
+        ttl: u16,
+        #[structopt(long = "krb5-spn", help = "the servers use kerberos with this spn")]
+        spn: Option<String>,
+        #[structopt(long = "local", help = "the servers use local auth at this path")]
+        local: Option<String>,
+        #[structopt(long = "tls-name", help = "the servers use tls with this name")]
+        tls_name: Option<String>,
+        #[structopt(name = "path")]
+        path: Path,
+        #[structopt(name = "socketaddr")]
+        socketaddr: SocketAddr,
+        #[structopt(name = "servers", required = true, help = "the child servers")]
+        servers: Vec<SocketAddr>,
+    },
+    #[structopt(name = "undelegate", about = "remove the delegation of a subtree")]
+    Undelegate {
+        #[structopt(name = "path")]
+        path: Path,
+        #[structopt(name = "socketaddr")]
+        socketaddr: SocketAddr,
+    },
 }
 
 pub(super) fn run(config: Config, auth: DesiredAuth, cmd: ResolverCmd) {
@@ -75,39 +143,103 @@ pub(super) fn run(config: Config, auth: DesiredAuth, cmd: ResolverCmd) {
                     }
                 }
             }
-            ResolverCmd::List { watch, no_structure, path } => {
+            ResolverCmd::List {
+                watch,
+                no_structure,
+                interval,
+                recursive,
+                max_depth,
+                path,
+            } => {
                 let resolver = ResolverRead::new(config, auth);
                 let pat = {
                     let path =
                         path.map(|p| Path::from(ArcStr::from(p))).unwrap_or(Path::root());
                     if !Glob::is_glob(&*path) {
-                        path.append("*")
+                        path.append(if recursive { "**" } else { "*" })
                     } else {
                         path
                     }
                 };
                 let glob = Glob::new(Chars::from(String::from(&*pat))).unwrap();
-                let mut ct = ChangeTracker::new(Path::from(ArcStr::from(glob.base())));
+                let base = Path::from(ArcStr::from(glob.base()));
+                let mut ct = ChangeTracker::new(base.clone());
                 let globs = GlobSet::new(no_structure, iter::once(glob)).unwrap();
+                let in_depth = |p: &Path| match max_depth {
+                    None => true,
+                    Some(d) => Path::levels(p).saturating_sub(Path::levels(&base)) <= d,
+                };
                 let mut paths = HashSet::new();
                 loop {
                     if resolver.check_changed(&mut ct).await.unwrap() {
+                        let mut current = HashSet::new();
+                        let mut added = Vec::new();
                         for b in resolver.list_matching(&globs).await.unwrap().iter() {
-                            for p in b.iter() {
-                                if !paths.contains(p) {
-                                    paths.insert(p.clone());
-                                    println!("{}", p);
+                            for p in b.iter().filter(|p| in_depth(p)) {
+                                if current.insert(p.clone()) && !paths.contains(p) {
+                                    added.push(p.clone());
                                 }
                             }
                         }
+                        if !watch {
+                            for p in added {
+                                println!("{}", p);
+                            }
+                            break;
+                        }
+                        let mut removed = paths.difference(&current).collect::<Vec<_>>();
+                        removed.sort();
+                        for p in removed {
+                            println!("remove {}", p);
+                        }
+                        for p in added {
+                            println!("add {}", p);
+                        }
+                        paths = current;
                     }
                     if watch {
-                        time::sleep(Duration::from_secs(5)).await
+                        time::sleep(Duration::from_secs(interval)).await
                     } else {
                         break;
                     }
                 }
             }
+            ResolverCmd::Trace { timeout, path } => {
+                let resolver = ResolverRead::new(config, auth);
+                let timeout = Duration::from_secs(timeout);
+                let trace = resolver.resolve_with_trace(path, timeout).await;
+                for (i, hop) in trace.hops.iter().enumerate() {
+                    let addrs =
+                        hop.referral.addrs.iter().map(|(a, _)| *a).collect::<Vec<_>>();
+                    match hop.elapsed {
+                        Some(e) => {
+                            println!("{}: {} {:?} {:?}", i, hop.referral.path, addrs, e)
+                        }
+                        None => {
+                            println!("{}: {} {:?} no answer", i, hop.referral.path, addrs)
+                        }
+                    }
+                }
+                println!("referral cache:");
+                for r in resolver.referrals() {
+                    let ttl = match r.ttl_remaining {
+                        None => String::from("forever"),
+                        Some(t) if t.is_zero() => String::from("expired"),
+                        Some(t) => format!("{:?}", t),
+                    };
+                    println!("{} ttl: {} hits: {}", r.referral.path, ttl, r.hits);
+                }
+                match trace.result {
+                    Ok(res) => {
+                        let ids = res.publishers.iter().map(|p| p.id).collect::<Vec<_>>();
+                        println!("resolved: {:?}", ids)
+                    }
+                    Err(e) => {
+                        eprintln!("resolve failed: {}", e);
+                        process::exit(1)
+                    }
+                }
+            }
             ResolverCmd::Table { path } => {
                 let resolver = ResolverRead::new(config, auth);
                 let path = path.unwrap_or_else(|| Path::from("/"));
@@ -129,6 +261,33 @@ pub(super) fn run(config: Config, auth: DesiredAuth, cmd: ResolverCmd) {
                 let resolver = ResolverWrite::new(config, auth, socketaddr).unwrap();
                 resolver.unpublish(vec![path]).await.unwrap();
             }
+            ResolverCmd::Delegate {
+                ttl,
+                spn,
+                local,
+                tls_name,
+                path,
+                socketaddr,
+                servers,
+            } => {
+                let server_auth = match (spn, local, tls_name) {
+                    (None, None, None) => Auth::Anonymous,
+                    (Some(spn), None, None) => Auth::Krb5 { spn: Chars::from(spn) },
+                    (None, Some(path), None) => Auth::Local { path: Chars::from(path) },
+                    (None, None, Some(name)) => Auth::Tls { name: Chars::from(name) },
+                    (_, _, _) => panic!("at most one server auth mechanism may be given"),
+                };
+                let addrs =
+                    servers.into_iter().map(|a| (a, server_auth.clone())).collect();
+                let referral =
+                    Referral { path, ttl: Some(ttl), addrs: Pooled::orphan(addrs) };
+                let resolver = ResolverWrite::new(config, auth, socketaddr).unwrap();
+                resolver.delegate(vec![referral]).await.unwrap();
+            }
+            ResolverCmd::Undelegate { path, socketaddr } => {
+                let resolver = ResolverWrite::new(config, auth, socketaddr).unwrap();
+                resolver.undelegate(vec![path]).await.unwrap();
+            }
         }
     });
 }
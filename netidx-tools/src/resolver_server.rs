@@ -1,8 +1,9 @@
+use crate::service;
 use daemonize::Daemonize;
-use futures::future;
 use netidx::resolver_server::{config::Config, Server};
+use std::time::Duration;
 use structopt::StructOpt;
-use tokio::runtime::Runtime;
+use tokio::{runtime::Runtime, task};
 
 #[derive(StructOpt, Debug)]
 pub(crate) struct Params {
@@ -21,6 +22,12 @@ pub(crate) struct Params {
         default_value = "0"
     )]
     id: usize,
+    #[structopt(
+        long = "drain-timeout",
+        help = "on SIGTERM or SIGINT wait this many seconds for clients to move",
+        default_value = "30"
+    )]
+    drain_timeout: u64,
 }
 
 pub(crate) fn run(params: Params) {
@@ -37,7 +44,18 @@ pub(crate) fn run(params: Params) {
         let server = Server::new(config, params.delay_reads, params.id)
             .await
             .expect("starting server");
-        future::pending::<()>().await;
-        drop(server)
+        service::ready(&format!("serving on {}", server.local_addr()));
+        let watchdog = task::spawn(service::watchdog());
+        service::should_exit().await.expect("failed to wait for signals");
+        service::stopping("draining clients");
+        let timeout = Duration::from_secs(params.drain_timeout);
+        match server.shutdown(timeout).await {
+            Ok(()) => service::status("drained"),
+            Err(e) => {
+                eprintln!("resolver server shutdown failed {}", e);
+                service::status(&format!("shutdown failed {}", e))
+            }
+        }
+        watchdog.abort();
     });
 }
@@ -0,0 +1,262 @@
+use crate::json::value_to_json;
+use anyhow::{Error, Result};
+use futures::future;
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    protocol::glob::{Glob, GlobSet},
+    resolver_client::DesiredAuth,
+    subscriber::{Event, Schema, Subscriber, Typ, Value},
+};
+use netidx_protocols::rpc::client::Proc;
+use std::{collections::BTreeMap, iter, process, str::FromStr, time::Duration};
+use structopt::StructOpt;
+use tokio::{runtime::Runtime, time};
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum Format {
+    Netidx,
+    Json,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "netidx" => Ok(Format::Netidx),
+            "json" => Ok(Format::Json),
+            s => bail!("unknown format {}, expected netidx or json", s),
+        }
+    }
+}
+
+impl Format {
+    fn print(&self, v: &Value) {
+        match self {
+            Format::Netidx => println!("{}", v),
+            Format::Json => println!("{}", value_to_json(v)),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(super) enum RpcCmd {
+    #[structopt(name = "call", about = "call an rpc, or discover rpcs")]
+    Call {
+        #[structopt(
+            long = "list",
+            short = "l",
+            help = "list the procedures under path instead of calling it"
+        )]
+        list: bool,
+        #[structopt(
+            long = "describe",
+            short = "d",
+            help = "print the procedure's doc and arguments instead of calling it"
+        )]
+        describe: bool,
+        #[structopt(
+            long = "format",
+            short = "f",
+            help = "how to print the reply, netidx or json",
+            default_value = "netidx"
+        )]
+        format: Format,
+        #[structopt(
+            long = "timeout",
+            short = "t",
+            help = "give up on subscriptions after this many seconds",
+            default_value = "10"
+        )]
+        timeout: u64,
+        #[structopt(name = "path")]
+        path: Path,
+        #[structopt(name = "args", help = "arguments as name=value")]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Debug)]
+struct ArgSpec {
+    doc: Value,
+    default: Value,
+    schema: Option<Schema>,
+}
+
+#[derive(Debug)]
+struct ProcSpec {
+    doc: Value,
+    args: BTreeMap<String, ArgSpec>,
+}
+
+impl ProcSpec {
+    fn describe(&self, path: &Path, format: Format) {
+        match format {
+            Format::Netidx => {
+                println!("{}: {}", path, self.doc);
+                for (name, spec) in &self.args {
+                    match &spec.schema {
+                        None => println!("  {}={}: {}", name, spec.default, spec.doc),
+                        Some(s) => {
+                            println!("  {}: {}={}: {}", name, s, spec.default, spec.doc)
+                        }
+                    }
+                }
+            }
+            Format::Json => {
+                let args = self
+                    .args
+                    .iter()
+                    .map(|(name, spec)| {
+                        serde_json::json!({
+                            "name": name,
+                            "doc": value_to_json(&spec.doc),
+                            "default": value_to_json(&spec.default),
+                            "schema": spec.schema.as_ref().map(|s| s.to_string()),
+                        })
+                    })
+                    .collect::<Vec<_>>();
+                let v = serde_json::json!({
+                    "path": &**path,
+                    "doc": value_to_json(&self.doc),
+                    "args": args,
+                });
+                println!("{}", v)
+            }
+        }
+    }
+
+    // Parse `name=value`. If the argument's schema has a type, or it
+    // has a non null default, then the value is parsed as that type,
+    // otherwise it is parsed as a netidx value, and failing that it
+    // is taken as a string.
+    fn parse_arg(&self, arg: &str) -> Result<(String, Value)> {
+        let (name, val) = match arg.split_once('=') {
+            Some((name, val)) => (name.trim(), val),
+            None => bail!("expected name=value, got {}", arg),
+        };
+        let spec = match self.args.get(name) {
+            Some(spec) => spec,
+            None => {
+                let names = self.args.keys().map(|s| s.as_str()).collect::<Vec<_>>();
+                bail!("no such argument {}, expected one of {:?}", name, names)
+            }
+        };
+        let typed = match (spec.schema.as_ref().and_then(|s| s.typ), &spec.default) {
+            (Some(typ), _) => typ.parse(val).ok(),
+            (None, Value::Null) => None,
+            (None, v) => Typ::get(v).parse(val).ok(),
+        };
+        let v = typed
+            .or_else(|| val.parse::<Value>().ok())
+            .unwrap_or_else(|| Value::from(String::from(val)));
+        Ok((String::from(name), v))
+    }
+}
+
+async fn current(subscriber: &Subscriber, path: Path, timeout: Duration) -> Value {
+    match subscriber.subscribe_nondurable_one(path, Some(timeout)).await {
+        Err(_) => Value::Null,
+        Ok(v) => match v.last() {
+            Event::Unsubscribed(_) => Value::Null,
+            Event::Update(v) => v,
+        },
+    }
+}
+
+async fn list_matching(subscriber: &Subscriber, pat: String) -> Result<Vec<Path>> {
+    let pat = GlobSet::new(true, iter::once(Glob::new(Chars::from(pat))?))?;
+    let batches = subscriber.resolver().list_matching(&pat).await?;
+    Ok(batches.iter().flat_map(|b| b.iter().cloned()).collect())
+}
+
+// read the proc's doc, and the doc and default value of each
+// argument, from the metadata published along with it
+async fn spec(
+    subscriber: &Subscriber,
+    path: &Path,
+    timeout: Duration,
+) -> Result<ProcSpec> {
+    subscriber
+        .subscribe_nondurable_one(path.clone(), Some(timeout))
+        .await
+        .map_err(|e| anyhow!("no procedure at {}: {}", path, e))?;
+    let vals = list_matching(subscriber, format!("{}/*/val", path)).await?;
+    let args = future::join_all(vals.into_iter().map(|val| async move {
+        let base = Path::from(String::from(Path::dirname(&val).unwrap_or("/")));
+        let name = String::from(Path::basename(&base).unwrap_or(""));
+        let doc = current(subscriber, base.append("doc"), timeout).await;
+        let schema = time::timeout(timeout, subscriber.schema(&val)).await;
+        let schema = schema.ok().and_then(|r| r.ok()).flatten();
+        let default = current(subscriber, val, timeout).await;
+        (name, ArgSpec { doc, default, schema })
+    }))
+    .await;
+    let doc = current(subscriber, path.append("doc"), timeout).await;
+    Ok(ProcSpec { doc, args: args.into_iter().collect() })
+}
+
+// Procedures publish `proc/doc` and `proc/arg/{val,doc}`, so a doc
+// without a val sibling belongs to a procedure
+async fn list(subscriber: &Subscriber, base: &Path) -> Result<Vec<Path>> {
+    let docs = list_matching(subscriber, format!("{}/**/doc", base)).await?;
+    let vals = list_matching(subscriber, format!("{}/**/val", base)).await?;
+    let mut procs = docs
+        .iter()
+        .filter_map(|doc| Path::dirname(doc))
+        .filter(|dir| !vals.iter().any(|v| Path::dirname(v) == Some(*dir)))
+        .map(|dir| Path::from(String::from(dir)))
+        .collect::<Vec<_>>();
+    procs.sort();
+    procs.dedup();
+    Ok(procs)
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, cmd: RpcCmd) -> Result<()> {
+    let subscriber = Subscriber::new(config, auth)?;
+    match cmd {
+        RpcCmd::Call { list: true, format, timeout, path, .. } => {
+            let timeout = Duration::from_secs(timeout);
+            for proc in list(&subscriber, &path).await? {
+                match format {
+                    Format::Netidx => {
+                        let doc = current(&subscriber, proc.append("doc"), timeout).await;
+                        println!("{}: {}", proc, doc)
+                    }
+                    Format::Json => println!("{}", serde_json::json!(&*proc)),
+                }
+            }
+        }
+        RpcCmd::Call { describe: true, format, timeout, path, .. } => {
+            spec(&subscriber, &path, Duration::from_secs(timeout))
+                .await?
+                .describe(&path, format)
+        }
+        RpcCmd::Call { format, timeout, path, args, .. } => {
+            let spec = spec(&subscriber, &path, Duration::from_secs(timeout)).await?;
+            let args =
+                args.iter().map(|a| spec.parse_arg(a)).collect::<Result<Vec<_>>>()?;
+            let proc = Proc::new(&subscriber, path).await?;
+            match proc.call(args).await? {
+                v @ (Value::Error(_) | Value::ErrorInfo(_)) => {
+                    format.print(&v);
+                    bail!("the procedure returned an error")
+                }
+                v => format.print(&v),
+            }
+        }
+    }
+    Ok(())
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, cmd: RpcCmd) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, cmd).await {
+            eprintln!("rpc failed {}", e);
+            process::exit(1)
+        }
+    })
+}
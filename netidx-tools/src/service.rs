@@ -0,0 +1,90 @@
+//! Integration with the service manager for the long running tools.
+//!
+//! When started by systemd with `Type=notify` the tools report when
+//! they are ready, when they are stopping, and a short status, and
+//! they send watchdog keep alives if `WatchdogSec` is set. Outside
+//! of systemd, or on other platforms, all of this does nothing.
+use anyhow::Result;
+use futures::{prelude::*, select_biased};
+use log::warn;
+use std::{env, time::Duration};
+use tokio::time;
+
+#[cfg(target_os = "linux")]
+fn notify(msg: &str) {
+    use std::os::{
+        linux::net::SocketAddrExt,
+        unix::net::{SocketAddr, UnixDatagram},
+    };
+    let path = match env::var("NOTIFY_SOCKET") {
+        Ok(path) => path,
+        Err(_) => return,
+    };
+    let res = (|| -> Result<()> {
+        let addr = match path.strip_prefix('@') {
+            Some(name) => SocketAddr::from_abstract_name(name)?,
+            None => SocketAddr::from_pathname(&path)?,
+        };
+        UnixDatagram::unbound()?.send_to_addr(msg.as_bytes(), &addr)?;
+        Ok(())
+    })();
+    if let Err(e) = res {
+        warn!("failed to notify the service manager {}", e)
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn notify(_msg: &str) {}
+
+/// Tell the service manager we are ready to serve
+pub(crate) fn ready(status: &str) {
+    notify(&format!("READY=1\nSTATUS={}", status))
+}
+
+/// Tell the service manager we are shutting down
+pub(crate) fn stopping(status: &str) {
+    notify(&format!("STOPPING=1\nSTATUS={}", status))
+}
+
+/// Update the status shown by e.g. `systemctl status`
+pub(crate) fn status(status: &str) {
+    notify(&format!("STATUS={}", status))
+}
+
+// the watchdog interval the service manager asked for, if any
+fn watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = env::var("WATCHDOG_PID") {
+        if pid.parse::<u32>().ok() != Some(std::process::id()) {
+            return None;
+        }
+    }
+    let usec = env::var("WATCHDOG_USEC").ok()?.parse::<u64>().ok()?;
+    (usec > 0).then(|| Duration::from_micros(usec))
+}
+
+/// Send keep alives to the service manager at half the watchdog
+/// interval for as long as the runtime is responsive. Returns
+/// immediately if the watchdog isn't enabled.
+pub(crate) async fn watchdog() {
+    if let Some(interval) = watchdog_interval() {
+        let mut interval = time::interval(interval / 2);
+        loop {
+            interval.tick().await;
+            notify("WATCHDOG=1")
+        }
+    }
+}
+
+/// Wait until we are asked to stop, by SIGTERM (e.g. `systemctl
+/// stop`), SIGQUIT, or SIGINT
+pub(crate) async fn should_exit() -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut term = signal(SignalKind::terminate())?;
+    let mut quit = signal(SignalKind::quit())?;
+    let mut intr = signal(SignalKind::interrupt())?;
+    select_biased! {
+        _ = term.recv().fuse() => Ok(()),
+        _ = quit.recv().fuse() => Ok(()),
+        _ = intr.recv().fuse() => Ok(()),
+    }
+}
@@ -17,7 +17,10 @@ use netidx::{
     config::Config,
     path::Path,
     pool::Pooled,
-    protocol::value_parser::{escaped_string, value, VAL_ESC},
+    protocol::{
+        value::{DisplayTz, ValueFormat},
+        value_parser::{escaped_string, value, VAL_ESC},
+    },
     resolver_client::DesiredAuth,
     subscriber::{Dval, Event, SubId, Subscriber, Typ, UpdatesFlags, Value},
     utils::{splitn_escaped, BatchItem, Batched},
@@ -49,12 +52,35 @@ pub(super) struct Params {
     no_stdin: bool,
     #[structopt(short = "r", long = "raw", help = "don't print the path or the type")]
     raw: bool,
+    #[structopt(
+        long = "precision",
+        help = "print floats, decimals, and durations with this many decimal places"
+    )]
+    precision: Option<usize>,
+    #[structopt(long = "thousands", help = "separate thousands with this character")]
+    thousands: Option<char>,
+    #[structopt(long = "si", help = "scale numbers by SI prefixes, e.g. 1.5k")]
+    si: bool,
+    #[structopt(long = "humanize", help = "print durations as e.g. 1h 2m 3s")]
+    humanize: bool,
+    #[structopt(
+        long = "tz",
+        default_value = "utc",
+        help = "print datetimes in this timezone, utc, local, or an offset like +05:30"
+    )]
+    tz: DisplayTz,
     #[structopt(
         short = "t",
         long = "subscribe-timeout",
         help = "cancel subscription unless it succeeds within timeout"
     )]
     subscribe_timeout: Option<u64>,
+    #[structopt(
+        short = "s",
+        long = "schema",
+        help = "print the schema published for each path instead of subscribing"
+    )]
+    schema: bool,
     #[structopt(name = "paths")]
     paths: Vec<String>,
 }
@@ -138,17 +164,18 @@ impl Write for BytesWriter<'_> {
     }
 }
 
-struct WVal<'a>(&'a Value);
+struct WVal<'a>(&'a Value, &'a ValueFormat);
 
 impl<'a> fmt::Display for WVal<'a> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        self.0.fmt_naked(f)
+        self.0.fmt_with(f, self.1)
     }
 }
 
 #[derive(Debug, Clone)]
 struct Out<'a> {
     raw: bool,
+    fmt: &'a ValueFormat,
     path: &'a str,
     value: Event,
 }
@@ -156,7 +183,7 @@ struct Out<'a> {
 impl<'a> Out<'a> {
     fn write(&self, to_stdout: &mut BytesMut) {
         match &self.value {
-            Event::Unsubscribed => {
+            Event::Unsubscribed(_) => {
                 if !self.raw {
                     to_stdout.extend_from_slice(b"Unsubscribed");
                     to_stdout.extend_from_slice(b"|");
@@ -167,14 +194,14 @@ impl<'a> Out<'a> {
             Event::Update(v) => {
                 if self.raw {
                     let w = &mut BytesWriter(to_stdout);
-                    writeln!(w, "{}", WVal(v)).unwrap()
+                    writeln!(w, "{}", WVal(v, self.fmt)).unwrap()
                 } else {
                     to_stdout.extend_from_slice(self.path.as_bytes());
                     to_stdout.extend_from_slice(b"|");
                     let typ = Typ::get(v);
                     let w = &mut BytesWriter(to_stdout);
                     write!(w, "{}|", typ).unwrap();
-                    writeln!(w, "{}", WVal(v)).unwrap()
+                    writeln!(w, "{}", WVal(v, self.fmt)).unwrap()
                 }
             }
         }
@@ -197,6 +224,7 @@ struct Ctx {
     oneshot: bool,
     requests_finished: bool,
     raw: bool,
+    fmt: ValueFormat,
     subscribe_timeout: Option<Duration>,
 }
 
@@ -242,6 +270,13 @@ impl Ctx {
             oneshot: p.oneshot,
             requests_finished: false,
             raw: p.raw,
+            fmt: {
+                let mut fmt =
+                    ValueFormat::new().naked(true).si(p.si).humanize(p.humanize).tz(p.tz);
+                fmt.precision = p.precision;
+                fmt.thousands = p.thousands;
+                fmt
+            },
         }
     }
 
@@ -382,7 +417,7 @@ impl Ctx {
                         if self.subscribe_timeout.is_some() {
                             self.subscribe_ts.remove(path);
                         }
-                        Out { raw: self.raw, path: &**path, value }
+                        Out { raw: self.raw, fmt: &self.fmt, path: &**path, value }
                             .write(&mut self.to_stdout);
                         if self.oneshot {
                             if let Some(path) = self.paths.get(&id).cloned() {
@@ -400,8 +435,22 @@ impl Ctx {
     }
 }
 
+async fn print_schemas(subscriber: &Subscriber, paths: &[String]) {
+    for path in paths {
+        let path = Path::from(path.clone());
+        match subscriber.schema(&path).await {
+            Ok(Some(schema)) => println!("{}: {}", path, schema),
+            Ok(None) => println!("{}: no schema", path),
+            Err(e) => eprintln!("{}: failed to read schema {}", path, e),
+        }
+    }
+}
+
 async fn subscribe(cfg: Config, auth: DesiredAuth, p: Params) {
     let subscriber = Subscriber::new(cfg, auth).expect("create subscriber");
+    if p.schema {
+        return print_schemas(&subscriber, &p.paths).await;
+    }
     let mut ctx = Ctx::new(subscriber, p);
     let mut tick = time::interval(Duration::from_secs(1));
     loop {
@@ -0,0 +1,281 @@
+use anyhow::{Error, Result};
+use chrono::Utc;
+use futures::{prelude::*, select_biased};
+use log::warn;
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    protocol::glob::{Glob, GlobSet},
+    resolver_client::DesiredAuth,
+    subscriber::{Dval, Event, SubStats, Subscriber, SubscriberBuilder},
+};
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    io::{self, Write},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+use structopt::StructOpt;
+use tokio::{runtime::Runtime, time};
+
+#[derive(Debug, Clone, Copy)]
+pub(super) enum SortBy {
+    Rate,
+    Bytes,
+    Path,
+    Stale,
+}
+
+impl FromStr for SortBy {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "rate" => Ok(SortBy::Rate),
+            "bytes" => Ok(SortBy::Bytes),
+            "path" => Ok(SortBy::Path),
+            "stale" => Ok(SortBy::Stale),
+            s => bail!("unknown sort {}, expected rate, bytes, path, or stale", s),
+        }
+    }
+}
+
+#[derive(StructOpt, Debug)]
+pub(super) struct Params {
+    #[structopt(
+        long = "interval",
+        short = "i",
+        help = "refresh the display every interval seconds",
+        default_value = "1"
+    )]
+    interval: u64,
+    #[structopt(
+        long = "poll-interval",
+        help = "how often to look for new paths in seconds",
+        default_value = "10"
+    )]
+    poll_interval: u64,
+    #[structopt(
+        long = "sort",
+        short = "s",
+        help = "sort by rate, bytes, path, or stale",
+        default_value = "rate"
+    )]
+    sort: SortBy,
+    #[structopt(
+        long = "rows",
+        short = "n",
+        help = "show at most this many rows",
+        default_value = "40"
+    )]
+    rows: usize,
+    #[structopt(
+        long = "threshold",
+        help = "alarm mode, instead of the table print a line when a path \
+                hasn't updated for this many seconds, and when it recovers"
+    )]
+    threshold: Option<u64>,
+    #[structopt(name = "globs", help = "watch paths matching these globs")]
+    globs: Vec<String>,
+}
+
+struct Row {
+    dv: Dval,
+    prev: SubStats,
+    rate: f64,
+    bytes: f64,
+    last_update: Instant,
+    alarmed: bool,
+}
+
+impl Row {
+    fn new(dv: Dval, now: Instant) -> Self {
+        Row {
+            dv,
+            prev: SubStats::default(),
+            rate: 0.,
+            bytes: 0.,
+            last_update: now,
+            alarmed: false,
+        }
+    }
+
+    // update the rates from the counts received since the last
+    // sample, return true if the path updated
+    fn sample(&mut self, now: Instant, elapsed: f64) -> bool {
+        let cur = self.dv.stats().unwrap_or_default();
+        // the counts start over when the dval resubscribes
+        let prev = if cur.updates < self.prev.updates || cur.bytes < self.prev.bytes {
+            SubStats::default()
+        } else {
+            self.prev
+        };
+        let n = cur.updates - prev.updates;
+        self.rate = n as f64 / elapsed;
+        self.bytes = (cur.bytes - prev.bytes) as f64 / elapsed;
+        self.prev = cur;
+        if n > 0 {
+            self.last_update = now;
+        }
+        n > 0
+    }
+
+    fn stale(&self, now: Instant) -> Duration {
+        now.saturating_duration_since(self.last_update)
+    }
+}
+
+fn last(dv: &Dval, width: usize) -> String {
+    let s = match dv.last() {
+        Event::Unsubscribed(_) => String::from("#unsubscribed"),
+        Event::Update(v) => v.to_string(),
+    };
+    if s.chars().count() <= width {
+        s
+    } else {
+        let mut s = s.chars().take(width.saturating_sub(3)).collect::<String>();
+        s.push_str("...");
+        s
+    }
+}
+
+fn human(n: f64) -> String {
+    if n >= 1e9 {
+        format!("{:.1}G", n / 1e9)
+    } else if n >= 1e6 {
+        format!("{:.1}M", n / 1e6)
+    } else if n >= 1e3 {
+        format!("{:.1}k", n / 1e3)
+    } else {
+        format!("{:.0}", n)
+    }
+}
+
+fn render(p: &Params, rows: &BTreeMap<Path, Row>, now: Instant) -> String {
+    let mut sorted = rows.iter().collect::<Vec<_>>();
+    match p.sort {
+        SortBy::Path => (),
+        SortBy::Rate => sorted.sort_by(|(_, r0), (_, r1)| r1.rate.total_cmp(&r0.rate)),
+        SortBy::Bytes => sorted.sort_by(|(_, r0), (_, r1)| r1.bytes.total_cmp(&r0.bytes)),
+        SortBy::Stale => sorted.sort_by_key(|(_, r)| std::cmp::Reverse(r.stale(now))),
+    }
+    let (rate, bytes) =
+        rows.values().fold((0., 0.), |(rate, bytes), r| (rate + r.rate, bytes + r.bytes));
+    let width = sorted.iter().take(p.rows).map(|(path, _)| path.len()).max();
+    let width = width.unwrap_or(0).clamp(4, 60);
+    let mut out = String::from("\x1b[2J\x1b[H");
+    let _ = writeln!(
+        out,
+        "{} paths, {} updates/s, {}B/s\n",
+        rows.len(),
+        human(rate),
+        human(bytes)
+    );
+    let _ = writeln!(
+        out,
+        "{:<width$} {:>8} {:>8} {:>8}  LAST",
+        "PATH",
+        "UPD/S",
+        "BYTES/S",
+        "STALE",
+        width = width
+    );
+    for (path, r) in sorted.into_iter().take(p.rows) {
+        let _ = writeln!(
+            out,
+            "{:<width$} {:>8} {:>8} {:>7}s  {}",
+            &**path,
+            human(r.rate),
+            human(r.bytes),
+            r.stale(now).as_secs(),
+            last(&r.dv, 40),
+            width = width
+        );
+    }
+    out
+}
+
+fn alarm(threshold: Duration, path: &Path, row: &mut Row, updated: bool, now: Instant) {
+    let stale = row.stale(now);
+    if !row.alarmed && stale >= threshold {
+        row.alarmed = true;
+        println!("{} STALE {} no updates for {}s", Utc::now(), path, stale.as_secs())
+    } else if row.alarmed && updated {
+        row.alarmed = false;
+        println!("{} RECOVERED {}", Utc::now(), path)
+    }
+}
+
+async fn poll(
+    subscriber: &Subscriber,
+    globs: &GlobSet,
+    rows: &mut BTreeMap<Path, Row>,
+) -> Result<()> {
+    let batches = subscriber.resolver().list_matching(globs).await?;
+    let now = Instant::now();
+    for batch in batches.iter() {
+        for path in batch.iter() {
+            if !rows.contains_key(path) {
+                rows.insert(
+                    path.clone(),
+                    Row::new(subscriber.subscribe(path.clone()), now),
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, p: Params) -> Result<()> {
+    if p.globs.is_empty() {
+        bail!("at least one glob is required")
+    }
+    let globs = p
+        .globs
+        .iter()
+        .map(|g| Glob::new(Chars::from(g.clone())))
+        .collect::<Result<Vec<_>>>()?;
+    let globs = GlobSet::new(true, globs)?;
+    let subscriber =
+        SubscriberBuilder::new().config(config).desired_auth(auth).stats(true).build()?;
+    let mut rows: BTreeMap<Path, Row> = BTreeMap::new();
+    let mut polling = time::interval(Duration::from_secs(p.poll_interval.max(1)));
+    let mut refresh = time::interval(Duration::from_secs(p.interval.max(1)));
+    let mut last_refresh = Instant::now();
+    loop {
+        select_biased! {
+            _ = polling.tick().fuse() => {
+                if let Err(e) = poll(&subscriber, &globs, &mut rows).await {
+                    warn!("failed to list matching paths {}", e)
+                }
+            },
+            _ = refresh.tick().fuse() => {
+                let now = Instant::now();
+                let elapsed = (now - last_refresh).as_secs_f64().max(1e-3);
+                last_refresh = now;
+                for (path, row) in rows.iter_mut() {
+                    let updated = row.sample(now, elapsed);
+                    if let Some(threshold) = p.threshold {
+                        alarm(Duration::from_secs(threshold), path, row, updated, now)
+                    }
+                }
+                if p.threshold.is_none() {
+                    let mut stdout = io::stdout().lock();
+                    stdout.write_all(render(&p, &rows, now).as_bytes())?;
+                    stdout.flush()?;
+                }
+            }
+        }
+    }
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, params).await {
+            eprintln!("top failed {}", e)
+        }
+    })
+}
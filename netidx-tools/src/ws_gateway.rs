@@ -0,0 +1,612 @@
+//! A websocket gateway to netidx, so that browsers can subscribe,
+//! write, list, and call rpcs without native bindings.
+//!
+//! Each websocket message is one json object with a `type` field.
+//! Requests from the browser,
+//!
+//! - `{"type": "subscribe", "path": "/foo"}`
+//! - `{"type": "unsubscribe", "path": "/foo"}`
+//! - `{"type": "write", "path": "/foo", "value": 42, "id": 1}`, `id` is optional,
+//!   if present a `wrote` reply is sent with the result
+//! - `{"type": "list", "path": "/foo", "id": 2}`
+//! - `{"type": "call", "path": "/rpc", "args": {"arg": 1}, "id": 3}`
+//!
+//! Replies from the gateway,
+//!
+//! - `{"type": "update", "path": "/foo", "value": 42}`
+//! - `{"type": "unsubscribed", "path": "/foo"}`
+//! - `{"type": "wrote", "id": 1, "value": null}`
+//! - `{"type": "listed", "id": 2, "paths": ["/foo/bar"]}`
+//! - `{"type": "called", "id": 3, "value": "ok"}`
+//! - `{"type": "error", "id": 3, "path": "/rpc", "message": "..."}`
+//!
+//! Values are converted as described in `json`. All sessions share
+//! one subscriber, so many browsers watching the same path cost one
+//! netidx subscription.
+//!
+//! Clients that speak CBOR may send the same messages as binary
+//! websocket messages holding a CBOR map with the same fields, in
+//! which case values are in the netidx CBOR encoding (see
+//! `netidx::protocol::cbor`) and keep their exact types. If a client
+//! connects with `?encoding=cbor` replies are sent that way too.
+//!
+//! If a token file is given, browsers must connect with
+//! `?token=<token>`, and are restricted to the paths their token
+//! grants. The token file is a json object mapping tokens to grants,
+//! `{"<token>": {"user": "eric", "read": ["/**"], "write": ["/app/**"]}}`.
+//! Calling an rpc requires both read and write access to it.
+use crate::json::{json_to_value, value_to_json};
+use anyhow::Result;
+use futures::{channel::mpsc, prelude::*, select_biased, stream::SplitSink};
+use fxhash::{FxHashMap, FxHashSet};
+use log::{info, warn};
+use netidx::{
+    chars::Chars,
+    config::Config,
+    path::Path,
+    pool::Pooled,
+    protocol::{
+        cbor,
+        glob::{Glob, GlobSet},
+    },
+    resolver_client::DesiredAuth,
+    subscriber::{Dval, Event, SubId, Subscriber, UpdatesFlags, Value},
+};
+use netidx_protocols::rpc::client::Proc;
+use serde::{Deserialize as _, Deserializer, Serialize as _, Serializer};
+use serde_derive::{Deserialize, Serialize};
+use serde_json::Value as JValue;
+use std::{
+    collections::HashMap,
+    fs,
+    net::SocketAddr,
+    path::{Path as FilePath, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use structopt::StructOpt;
+use tokio::{
+    net::{TcpListener, TcpStream},
+    runtime::Runtime,
+    task, time,
+};
+use tokio_tungstenite::{
+    accept_hdr_async_with_config,
+    tungstenite::{
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{self, StatusCode},
+        protocol::WebSocketConfig,
+        Message,
+    },
+    WebSocketStream,
+};
+
+static WRITE_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(StructOpt, Debug)]
+pub(super) struct Params {
+    #[structopt(
+        long = "bind",
+        help = "the address to accept websocket connections on",
+        default_value = "127.0.0.1:4654"
+    )]
+    bind: SocketAddr,
+    #[structopt(
+        long = "tokens",
+        help = "require tokens, and grant paths, from this file"
+    )]
+    tokens: Option<PathBuf>,
+    #[structopt(
+        long = "max-sessions",
+        help = "the maximum number of concurrent sessions",
+        default_value = "1024"
+    )]
+    max_sessions: usize,
+    #[structopt(
+        long = "max-subscriptions",
+        help = "the maximum number of subscriptions per session",
+        default_value = "10000"
+    )]
+    max_subscriptions: usize,
+    #[structopt(
+        long = "max-pending",
+        help = "the maximum number of writes, lists, and calls in flight per session",
+        default_value = "64"
+    )]
+    max_pending: usize,
+    #[structopt(
+        long = "max-message",
+        help = "the maximum size of a websocket message in bytes",
+        default_value = "1048576"
+    )]
+    max_message: usize,
+}
+
+#[derive(Debug, Deserialize)]
+struct GrantCfg {
+    user: String,
+    #[serde(default)]
+    read: Vec<String>,
+    #[serde(default)]
+    write: Vec<String>,
+}
+
+/// What a session may touch. None means everything.
+#[derive(Debug)]
+struct Grant {
+    user: String,
+    read: Option<GlobSet>,
+    write: Option<GlobSet>,
+}
+
+impl Grant {
+    fn anonymous() -> Self {
+        Grant { user: String::from("anonymous"), read: None, write: None }
+    }
+
+    fn can_read(&self, path: &Path) -> bool {
+        self.read.as_ref().map(|g| g.is_match(path)).unwrap_or(true)
+    }
+
+    fn can_write(&self, path: &Path) -> bool {
+        self.write.as_ref().map(|g| g.is_match(path)).unwrap_or(true)
+    }
+}
+
+fn load_tokens(path: &FilePath) -> Result<HashMap<String, Arc<Grant>>> {
+    fn globs(v: Vec<String>) -> Result<Option<GlobSet>> {
+        let globs = v
+            .into_iter()
+            .map(|g| Glob::new(Chars::from(g)))
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Some(GlobSet::new(true, globs)?))
+    }
+    let cfg: HashMap<String, GrantCfg> = serde_json::from_slice(&fs::read(path)?)?;
+    cfg.into_iter()
+        .map(|(token, g)| {
+            let grant =
+                Grant { user: g.user, read: globs(g.read)?, write: globs(g.write)? };
+            Ok((token, Arc::new(grant)))
+        })
+        .collect()
+}
+
+fn from_json<'de, D: Deserializer<'de>>(d: D) -> std::result::Result<Value, D::Error> {
+    Ok(json_to_value(JValue::deserialize(d)?))
+}
+
+fn args_from_json<'de, D: Deserializer<'de>>(
+    d: D,
+) -> std::result::Result<Vec<(String, Value)>, D::Error> {
+    let args = serde_json::Map::<String, JValue>::deserialize(d)?;
+    Ok(args.into_iter().map(|(k, v)| (k, json_to_value(v))).collect())
+}
+
+fn to_json<S: Serializer>(v: &Value, s: S) -> std::result::Result<S::Ok, S::Error> {
+    value_to_json(v).serialize(s)
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Req {
+    Subscribe {
+        path: Path,
+    },
+    Unsubscribe {
+        path: Path,
+    },
+    Write {
+        path: Path,
+        #[serde(deserialize_with = "from_json")]
+        value: Value,
+        #[serde(default)]
+        id: Option<u64>,
+    },
+    List {
+        path: Path,
+        id: u64,
+    },
+    Call {
+        path: Path,
+        #[serde(default, deserialize_with = "args_from_json")]
+        args: Vec<(String, Value)>,
+        id: u64,
+    },
+}
+
+impl Req {
+    // a cbor request is a map with the same fields as a json request
+    fn from_cbor(b: &[u8]) -> Result<Self> {
+        let mut fields = match Value::from_cbor(b)? {
+            Value::Array(pairs) => pairs
+                .iter()
+                .map(|p| match p {
+                    Value::Array(kv) if kv.len() == 2 => match &kv[0] {
+                        Value::String(k) => Ok((String::from(&**k), kv[1].clone())),
+                        _ => bail!("field names must be strings"),
+                    },
+                    _ => bail!("expected a map"),
+                })
+                .collect::<Result<FxHashMap<_, _>>>()?,
+            _ => bail!("expected a map"),
+        };
+        let mut field = |name: &str| {
+            fields.remove(name).ok_or_else(|| anyhow!("missing field {}", name))
+        };
+        let typ = field("type")?.cast_to::<String>()?;
+        let path = Path::from(field("path")?.cast_to::<String>()?);
+        let opt_id = field("id").ok().map(|v| v.cast_to::<u64>()).transpose()?;
+        let id = || opt_id.ok_or_else(|| anyhow!("missing field id"));
+        Ok(match typ.as_str() {
+            "subscribe" => Req::Subscribe { path },
+            "unsubscribe" => Req::Unsubscribe { path },
+            "write" => Req::Write { path, value: field("value")?, id: opt_id },
+            "list" => Req::List { path, id: id()? },
+            "call" => {
+                let args = match field("args") {
+                    Err(_) => vec![],
+                    Ok(args) => args.cast_to::<Vec<(String, Value)>>()?,
+                };
+                Req::Call { path, args, id: id()? }
+            }
+            typ => bail!("unknown request type {}", typ),
+        })
+    }
+}
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Rep {
+    Update {
+        path: Path,
+        #[serde(serialize_with = "to_json")]
+        value: Value,
+    },
+    Unsubscribed {
+        path: Path,
+    },
+    Wrote {
+        id: u64,
+        #[serde(serialize_with = "to_json")]
+        value: Value,
+    },
+    Listed {
+        id: u64,
+        paths: Vec<Path>,
+    },
+    Called {
+        id: u64,
+        #[serde(serialize_with = "to_json")]
+        value: Value,
+    },
+    Error {
+        id: Option<u64>,
+        path: Option<Path>,
+        message: String,
+    },
+}
+
+impl Rep {
+    fn error(id: Option<u64>, path: Option<Path>, message: impl Into<String>) -> Self {
+        Rep::Error { id, path, message: message.into() }
+    }
+
+    // a map with the same fields as the json reply
+    fn to_cbor(&self) -> Vec<u8> {
+        let s = |s: &str| Value::String(Chars::from(String::from(s)));
+        let opt = |v: Option<Value>| v.unwrap_or(Value::Null);
+        let fields: Vec<(&str, Value)> = match self {
+            Rep::Update { path, value } => {
+                vec![("type", s("update")), ("path", s(path)), ("value", value.clone())]
+            }
+            Rep::Unsubscribed { path } => {
+                vec![("type", s("unsubscribed")), ("path", s(path))]
+            }
+            Rep::Wrote { id, value } => {
+                vec![
+                    ("type", s("wrote")),
+                    ("id", Value::U64(*id)),
+                    ("value", value.clone()),
+                ]
+            }
+            Rep::Listed { id, paths } => vec![
+                ("type", s("listed")),
+                ("id", Value::U64(*id)),
+                ("paths", Value::Array(paths.iter().map(|p| s(p)).collect())),
+            ],
+            Rep::Called { id, value } => {
+                vec![
+                    ("type", s("called")),
+                    ("id", Value::U64(*id)),
+                    ("value", value.clone()),
+                ]
+            }
+            Rep::Error { id, path, message } => vec![
+                ("type", s("error")),
+                ("id", opt(id.map(Value::U64))),
+                ("path", opt(path.as_ref().map(|p| s(p)))),
+                ("message", s(message)),
+            ],
+        };
+        cbor::encode_map(fields.iter().map(|(k, v)| (*k, v)))
+    }
+}
+
+struct Ctx {
+    params: Params,
+    subscriber: Subscriber,
+    tokens: Option<HashMap<String, Arc<Grant>>>,
+    sessions: AtomicUsize,
+}
+
+struct Session {
+    ctx: Arc<Ctx>,
+    grant: Arc<Grant>,
+    subs: FxHashMap<Path, Dval>,
+    by_id: FxHashMap<SubId, Path>,
+    // ids whose updates are already routed to this session
+    registered: FxHashSet<SubId>,
+    pending: Arc<AtomicUsize>,
+    updates: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+    replies: mpsc::UnboundedSender<Rep>,
+}
+
+impl Session {
+    // run f in the background, subject to the max pending limit, and
+    // send its reply if it has one
+    fn spawn<F>(&self, id: Option<u64>, path: &Path, f: F)
+    where
+        F: Future<Output = Result<Option<Rep>>> + Send + 'static,
+    {
+        if self.pending.load(Ordering::Relaxed) >= self.ctx.params.max_pending {
+            let m = "too many requests in flight";
+            let _ = self.replies.unbounded_send(Rep::error(id, Some(path.clone()), m));
+            return;
+        }
+        self.pending.fetch_add(1, Ordering::Relaxed);
+        let pending = self.pending.clone();
+        let replies = self.replies.clone();
+        let path = path.clone();
+        task::spawn(async move {
+            let rep = match f.await {
+                Ok(rep) => rep,
+                Err(e) => Some(Rep::error(id, Some(path), e.to_string())),
+            };
+            pending.fetch_sub(1, Ordering::Relaxed);
+            if let Some(rep) = rep {
+                let _ = replies.unbounded_send(rep);
+            }
+        });
+    }
+
+    fn request(&mut self, req: Req) -> Option<Rep> {
+        match req {
+            Req::Subscribe { path } => {
+                if !self.grant.can_read(&path) {
+                    return Some(Rep::error(None, Some(path), "permission denied"));
+                }
+                if self.subs.contains_key(&path) {
+                    return None;
+                }
+                if self.subs.len() >= self.ctx.params.max_subscriptions {
+                    return Some(Rep::error(None, Some(path), "too many subscriptions"));
+                }
+                let dv = self.ctx.subscriber.subscribe(path.clone());
+                self.by_id.insert(dv.id(), path.clone());
+                let rep = if self.registered.insert(dv.id()) {
+                    dv.updates(UpdatesFlags::BEGIN_WITH_LAST, self.updates.clone());
+                    None
+                } else {
+                    match dv.last() {
+                        Event::Unsubscribed(_) => None,
+                        Event::Update(value) => {
+                            Some(Rep::Update { path: path.clone(), value })
+                        }
+                    }
+                };
+                self.subs.insert(path, dv);
+                rep
+            }
+            Req::Unsubscribe { path } => {
+                if let Some(dv) = self.subs.remove(&path) {
+                    self.by_id.remove(&dv.id());
+                }
+                None
+            }
+            Req::Write { path, value, id } => {
+                if !self.grant.can_write(&path) {
+                    return Some(Rep::error(id, Some(path), "permission denied"));
+                }
+                let dv = self.ctx.subscriber.subscribe(path.clone());
+                self.spawn(id, &path, async move {
+                    let res = dv.write_with_recipt(value);
+                    let value = time::timeout(WRITE_TIMEOUT, res).await??;
+                    Ok(id.map(|id| Rep::Wrote { id, value }))
+                });
+                None
+            }
+            Req::List { path, id } => {
+                if !self.grant.can_read(&path) {
+                    return Some(Rep::error(Some(id), Some(path), "permission denied"));
+                }
+                let resolver = self.ctx.subscriber.resolver();
+                let grant = self.grant.clone();
+                self.spawn(Some(id), &path.clone(), async move {
+                    let mut paths = resolver.list(path).await?;
+                    let paths = paths.drain(..).filter(|p| grant.can_read(p)).collect();
+                    Ok(Some(Rep::Listed { id, paths }))
+                });
+                None
+            }
+            Req::Call { path, args, id } => {
+                if !self.grant.can_read(&path) || !self.grant.can_write(&path) {
+                    return Some(Rep::error(Some(id), Some(path), "permission denied"));
+                }
+                let subscriber = self.ctx.subscriber.clone();
+                self.spawn(Some(id), &path.clone(), async move {
+                    let proc = Proc::new(&subscriber, path).await?;
+                    let value = proc.call(args).await?;
+                    Ok(Some(Rep::Called { id, value }))
+                });
+                None
+            }
+        }
+    }
+
+    fn updates(&self, mut batch: Pooled<Vec<(SubId, Event)>>) -> Vec<Rep> {
+        batch
+            .drain(..)
+            .filter_map(|(id, ev)| {
+                let path = self.by_id.get(&id)?.clone();
+                Some(match ev {
+                    Event::Unsubscribed(_) => Rep::Unsubscribed { path },
+                    Event::Update(value) => Rep::Update { path, value },
+                })
+            })
+            .collect()
+    }
+}
+
+async fn run_session(
+    ctx: Arc<Ctx>,
+    grant: Arc<Grant>,
+    cbor: bool,
+    ws: WebSocketStream<TcpStream>,
+) -> Result<()> {
+    let (mut ws_tx, mut ws_rx) = ws.split();
+    let (updates, mut updates_rx) = mpsc::channel(3);
+    let (replies, mut replies_rx) = mpsc::unbounded();
+    let mut t = Session {
+        ctx,
+        grant,
+        subs: FxHashMap::default(),
+        by_id: FxHashMap::default(),
+        registered: FxHashSet::default(),
+        pending: Arc::new(AtomicUsize::new(0)),
+        updates,
+        replies,
+    };
+    async fn send(
+        ws_tx: &mut SplitSink<WebSocketStream<TcpStream>, Message>,
+        cbor: bool,
+        rep: &Rep,
+    ) -> Result<()> {
+        let msg = if cbor {
+            Message::Binary(rep.to_cbor())
+        } else {
+            Message::Text(serde_json::to_string(rep)?)
+        };
+        Ok(ws_tx.send(msg).await?)
+    }
+    loop {
+        select_biased! {
+            rep = replies_rx.select_next_some() => send(&mut ws_tx, cbor, &rep).await?,
+            batch = updates_rx.select_next_some() => {
+                for rep in t.updates(batch) {
+                    send(&mut ws_tx, cbor, &rep).await?
+                }
+            },
+            msg = ws_rx.next() => {
+                let req = match msg {
+                    None | Some(Ok(Message::Close(_))) => break Ok(()),
+                    Some(Err(e)) => break Err(e.into()),
+                    Some(Ok(Message::Text(s))) => {
+                        serde_json::from_str::<Req>(&s).map_err(anyhow::Error::from)
+                    }
+                    Some(Ok(Message::Binary(b))) => Req::from_cbor(&b),
+                    Some(Ok(_)) => continue,
+                };
+                let rep = match req {
+                    Ok(req) => t.request(req),
+                    Err(e) => Some(Rep::error(None, None, e.to_string())),
+                };
+                if let Some(rep) = rep {
+                    send(&mut ws_tx, cbor, &rep).await?
+                }
+            },
+        }
+    }
+}
+
+fn reject(status: StatusCode, msg: &str) -> ErrorResponse {
+    let mut rep = http::Response::new(Some(String::from(msg)));
+    *rep.status_mut() = status;
+    rep
+}
+
+async fn accept(ctx: Arc<Ctx>, con: TcpStream, addr: SocketAddr) -> Result<()> {
+    let mut grant = None;
+    let mut cbor = false;
+    let check = |req: &Request, rep: Response| {
+        if ctx.sessions.load(Ordering::Relaxed) >= ctx.params.max_sessions {
+            return Err(reject(StatusCode::SERVICE_UNAVAILABLE, "too many sessions"));
+        }
+        let param = |name: &str| {
+            req.uri()
+                .query()
+                .and_then(|q| q.split('&').find_map(|kv| kv.strip_prefix(name)))
+        };
+        match param("encoding=") {
+            None | Some("json") => (),
+            Some("cbor") => cbor = true,
+            Some(_) => return Err(reject(StatusCode::BAD_REQUEST, "unknown encoding")),
+        }
+        match &ctx.tokens {
+            None => grant = Some(Arc::new(Grant::anonymous())),
+            Some(tokens) => {
+                let token = param("token=");
+                match token.and_then(|t| tokens.get(t)) {
+                    Some(g) => grant = Some(g.clone()),
+                    None => {
+                        return Err(reject(StatusCode::UNAUTHORIZED, "invalid token"))
+                    }
+                }
+            }
+        }
+        Ok(rep)
+    };
+    let mut config = WebSocketConfig::default();
+    config.max_message_size = Some(ctx.params.max_message);
+    config.max_frame_size = Some(ctx.params.max_message);
+    let ws = accept_hdr_async_with_config(con, check, Some(config)).await?;
+    let grant = grant.ok_or_else(|| anyhow!("no grant"))?;
+    info!("session from {} as {} started", addr, grant.user);
+    ctx.sessions.fetch_add(1, Ordering::Relaxed);
+    let res = run_session(ctx.clone(), grant.clone(), cbor, ws).await;
+    ctx.sessions.fetch_sub(1, Ordering::Relaxed);
+    info!("session from {} as {} ended {:?}", addr, grant.user, res);
+    res
+}
+
+async fn run_async(config: Config, auth: DesiredAuth, params: Params) -> Result<()> {
+    let tokens = params.tokens.as_ref().map(|p| load_tokens(p)).transpose()?;
+    let subscriber = Subscriber::new(config, auth)?;
+    let listener = TcpListener::bind(params.bind).await?;
+    info!("accepting websocket connections on {}", listener.local_addr()?);
+    let ctx = Arc::new(Ctx { params, subscriber, tokens, sessions: AtomicUsize::new(0) });
+    loop {
+        match listener.accept().await {
+            Err(e) => warn!("accept failed {}", e),
+            Ok((con, addr)) => {
+                let ctx = ctx.clone();
+                task::spawn(async move {
+                    if let Err(e) = accept(ctx, con, addr).await {
+                        warn!("session from {} failed {}", addr, e)
+                    }
+                });
+            }
+        }
+    }
+}
+
+pub(super) fn run(config: Config, auth: DesiredAuth, params: Params) {
+    let rt = Runtime::new().expect("failed to init runtime");
+    rt.block_on(async {
+        if let Err(e) = run_async(config, auth, params).await {
+            eprintln!("websocket gateway failed {}", e)
+        }
+    })
+}
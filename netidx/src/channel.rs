@@ -1,4 +1,9 @@
-use crate::{pack::Pack, utils};
+#[cfg(feature = "fault_injection")]
+use crate::fault::FaultInjector;
+use crate::{
+    pack::{DecodeLimits, Limit, Pack, PackError},
+    utils,
+};
 use anyhow::{anyhow, Error, Result};
 use byteorder::{BigEndian, ByteOrder};
 use bytes::{Buf, BufMut, BytesMut};
@@ -14,6 +19,8 @@ use futures::{
 };
 use log::info;
 use parking_lot::Mutex;
+#[cfg(feature = "fault_injection")]
+use std::sync::atomic::AtomicBool;
 use std::{clone::Clone, fmt::Debug, mem, ops::Deref, sync::Arc, time::Duration};
 use tokio::{
     io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
@@ -143,6 +150,8 @@ pub(crate) struct WriteChannel {
     to_flush: Sender<BytesMut>,
     buf: BytesMut,
     boundries: Vec<usize>,
+    #[cfg(feature = "fault_injection")]
+    faults: Option<(FaultInjector, Arc<AtomicBool>)>,
 }
 
 impl WriteChannel {
@@ -157,9 +166,20 @@ impl WriteChannel {
             to_flush: flush_task(ctx, socket),
             buf: BytesMut::with_capacity(BUF),
             boundries: Vec::new(),
+            #[cfg(feature = "fault_injection")]
+            faults: None,
         }
     }
 
+    #[cfg(feature = "fault_injection")]
+    pub(crate) fn set_faults(
+        &mut self,
+        faults: FaultInjector,
+        half_open: Arc<AtomicBool>,
+    ) {
+        self.faults = Some((faults, half_open));
+    }
+
     /// Queue a message for sending. This only encodes the message and
     /// writes it to the buffer, you must call flush actually send it.
     pub(crate) fn queue_send<T: Pack>(&mut self, msg: &T) -> Result<()> {
@@ -206,6 +226,12 @@ impl WriteChannel {
     /// be done on a background task. If there is sufficient room in
     /// the buffer flush will complete immediately.
     pub(crate) async fn flush(&mut self) -> Result<()> {
+        #[cfg(feature = "fault_injection")]
+        if let Some(d) = self.faults.as_ref().and_then(|(f, _)| f.delay()) {
+            if self.buf.has_remaining() {
+                time::sleep(d).await
+            }
+        }
         loop {
             if self.try_flush()? {
                 break Ok(());
@@ -221,7 +247,17 @@ impl WriteChannel {
     pub(crate) fn try_flush(&mut self) -> Result<bool> {
         while self.buf.has_remaining() {
             let boundry = self.boundries.first().copied().unwrap_or(self.buf.len());
-            let chunk = self.buf.split_to(boundry);
+            #[allow(unused_mut)]
+            let mut chunk = self.buf.split_to(boundry);
+            #[cfg(feature = "fault_injection")]
+            if let Some((faults, half_open)) = &self.faults {
+                if !faults.frame(half_open, &mut chunk) {
+                    if !self.boundries.is_empty() {
+                        self.boundries.remove(0);
+                    }
+                    continue;
+                }
+            }
             match self.to_flush.try_send(chunk) {
                 Ok(()) => {
                     if self.boundries.len() > 0 {
@@ -306,8 +342,11 @@ fn read_task<C: K5Ctx + Debug + Send + Sync + 'static, S: AsyncRead + Send + 'st
 
 pub(crate) struct ReadChannel {
     buf: BytesMut,
+    limits: DecodeLimits,
     _stop: oneshot::Sender<()>,
     incoming: stream::Fuse<Receiver<BytesMut>>,
+    #[cfg(feature = "fault_injection")]
+    kill: Option<oneshot::Receiver<()>>,
 }
 
 impl ReadChannel {
@@ -321,14 +360,53 @@ impl ReadChannel {
         let (stop_tx, stop_rx) = oneshot::channel();
         ReadChannel {
             buf: BytesMut::new(),
+            limits: DecodeLimits::default(),
             _stop: stop_tx,
             incoming: read_task(stop_rx, socket, k5ctx).fuse(),
+            #[cfg(feature = "fault_injection")]
+            kill: None,
         }
     }
 
+    /// Enforce `limits` when decoding messages from this channel
+    pub(crate) fn set_limits(&mut self, limits: DecodeLimits) {
+        self.limits = limits;
+    }
+
+    #[cfg(feature = "fault_injection")]
+    pub(crate) fn set_kill(&mut self, kill: oneshot::Receiver<()>) {
+        self.kill = Some(kill);
+    }
+
+    #[cfg(feature = "fault_injection")]
+    async fn next_chunk(&mut self) -> Result<Option<BytesMut>> {
+        let r = match &mut self.kill {
+            None => return Ok(self.incoming.next().await),
+            Some(kill) => {
+                let mut kill = kill;
+                select_biased! {
+                    r = kill => r,
+                    c = self.incoming.next() => return Ok(c),
+                }
+            }
+        };
+        match r {
+            Ok(()) => bail!("fault injected disconnect"),
+            Err(_) => {
+                self.kill = None;
+                Ok(self.incoming.next().await)
+            }
+        }
+    }
+
+    #[cfg(not(feature = "fault_injection"))]
+    async fn next_chunk(&mut self) -> Result<Option<BytesMut>> {
+        Ok(self.incoming.next().await)
+    }
+
     /// Read a load of bytes from the socket into the read buffer
     pub(crate) async fn fill_buffer(&mut self) -> Result<()> {
-        if let Some(chunk) = self.incoming.next().await {
+        if let Some(chunk) = self.next_chunk().await? {
             self.buf = chunk;
             Ok(())
         } else {
@@ -340,18 +418,15 @@ impl ReadChannel {
         if !self.buf.has_remaining() {
             self.fill_buffer().await?;
         }
-        Ok(T::decode(&mut self.buf)?)
+        let buf = &mut self.buf;
+        Ok(self.limits.scope(|| T::decode(buf))?)
     }
 
     pub(crate) async fn receive_batch<T: Pack + Debug>(
         &mut self,
         batch: &mut Vec<T>,
     ) -> Result<()> {
-        batch.push(self.receive().await?);
-        while self.buf.has_remaining() {
-            batch.push(T::decode(&mut self.buf)?);
-        }
-        Ok(())
+        self.receive_batch_fn(|t| batch.push(t)).await
     }
 
     pub(crate) async fn receive_batch_fn<T, F>(&mut self, mut f: F) -> Result<()>
@@ -360,9 +435,16 @@ impl ReadChannel {
         F: FnMut(T),
     {
         f(self.receive().await?);
-        while self.buf.has_remaining() {
-            f(T::decode(&mut self.buf)?);
-        }
+        let (buf, limits) = (&mut self.buf, self.limits);
+        limits.scope(|| {
+            let mut n = 1;
+            while buf.has_remaining() {
+                n += 1;
+                DecodeLimits::check(n, limits.max_batch_len, Limit::BatchLen)?;
+                f(T::decode(buf)?);
+            }
+            Ok::<(), PackError>(())
+        })?;
         Ok(())
     }
 }
@@ -65,6 +65,15 @@ mod file {
         pub askpass: Option<String>,
     }
 
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub(super) struct Pin {
+        pub prefix: String,
+        pub addrs: Vec<SocketAddr>,
+        #[serde(default)]
+        pub fallback: bool,
+    }
+
     #[derive(Debug, Clone, Serialize, Deserialize)]
     #[serde(deny_unknown_fields)]
     pub(super) struct Config {
@@ -76,6 +85,10 @@ mod file {
         pub(super) default_auth: super::DefaultAuthMech,
         #[serde(default)]
         pub(super) default_bind_config: Option<String>,
+        #[serde(default)]
+        pub(super) default_alt_bind_config: Option<String>,
+        #[serde(default)]
+        pub(super) pinned: Vec<Pin>,
     }
 }
 
@@ -174,6 +187,19 @@ impl Tls {
     }
 }
 
+/// A static resolution for a subtree, see `Config::pinned`
+#[derive(Debug, Clone)]
+pub struct Pin {
+    /// The root of the subtree
+    pub prefix: Path,
+    /// The addresses of the publishers of every path in the subtree
+    pub addrs: Vec<SocketAddr>,
+    /// If true the pin is only used when the resolver can't be
+    /// reached, otherwise the resolver is never asked about the
+    /// subtree.
+    pub fallback: bool,
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum DefaultAuthMech {
     Anonymous,
@@ -195,6 +221,15 @@ pub struct Config {
     pub tls: Option<Tls>,
     pub default_auth: DefaultAuthMech,
     pub default_bind_config: publisher::BindCfg,
+    pub default_alt_bind_config: Option<publisher::BindCfg>,
+    /// Subtrees that are resolved to fixed publisher addresses
+    /// instead of, or when it's unreachable, by asking the
+    /// resolver. This is meant for test rigs without a resolver
+    /// server. Since there is no resolver to vouch for the
+    /// subscriber, only publishers using anonymous auth will accept
+    /// subscriptions made this way. If more than one pin matches a
+    /// path the longest prefix wins.
+    pub pinned: Vec<Pin>,
 }
 
 impl Config {
@@ -247,15 +282,37 @@ impl Config {
         {
             bail!("can't mix loopback addrs with non loopback addrs")
         }
+        let default_bind_config = match cfg.default_bind_config {
+            None => publisher::BindCfg::default(),
+            Some(s) => s.parse()?,
+        };
+        let default_alt_bind_config = match cfg.default_alt_bind_config {
+            None => None,
+            Some(s) => {
+                let alt = s.parse()?;
+                default_bind_config.check_alt(&alt)?;
+                Some(alt)
+            }
+        };
+        let mut pinned = Vec::with_capacity(cfg.pinned.len());
+        for pin in cfg.pinned {
+            if !Path::is_absolute(&pin.prefix) {
+                bail!("pinned prefix {} must be absolute", pin.prefix)
+            }
+            if pin.addrs.is_empty() {
+                bail!("pinned prefix {} must have at least one address", pin.prefix)
+            }
+            let prefix = Path::from(pin.prefix);
+            pinned.push(Pin { prefix, addrs: pin.addrs, fallback: pin.fallback });
+        }
         Ok(Config {
             base: Path::from(cfg.base),
             addrs: cfg.addrs.into_iter().map(|(s, a)| (s, a.into())).collect(),
             tls,
             default_auth: cfg.default_auth,
-            default_bind_config: match cfg.default_bind_config {
-                None => publisher::BindCfg::default(),
-                Some(s) => s.parse()?,
-            },
+            default_bind_config,
+            default_alt_bind_config,
+            pinned,
         })
     }
 
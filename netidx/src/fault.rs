@@ -0,0 +1,136 @@
+//! Fault injection for testing failure handling, only available with
+//! the `fault_injection` feature.
+//!
+//! A `FaultInjector` is given to a `PublisherBuilder` or a
+//! `SubscriberBuilder`, and then it affects every connection that
+//! publisher or subscriber makes to it's peers after it is built. The
+//! test keeps a clone of the injector and uses it to break those
+//! connections on demand. Faults apply to whole frames, a frame is a
+//! batch of messages that was flushed together.
+//!
+//! ```no_run
+//! # use anyhow::Result;
+//! # async fn run(cfg: netidx::config::Config) -> Result<()> {
+//! use netidx::{fault::FaultInjector, subscriber::SubscriberBuilder};
+//! let faults = FaultInjector::new();
+//! let subscriber = SubscriberBuilder::new()
+//!     .config(cfg)
+//!     .fault_injector(faults.clone())
+//!     .build()?;
+//! // ... subscribe to some things
+//! // now make every connection to a publisher die
+//! faults.disconnect();
+//! # Ok(()) }
+//! ```
+use crate::channel::{ReadChannel, WriteChannel};
+use bytes::BytesMut;
+use futures::channel::oneshot;
+use parking_lot::Mutex;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+#[derive(Debug, Default)]
+struct Faults {
+    drop: usize,
+    corrupt: usize,
+    delay: Option<Duration>,
+    kill: Vec<oneshot::Sender<()>>,
+    half_open: Vec<Arc<AtomicBool>>,
+}
+
+/// A handle to control the faults injected into connections. It is
+/// cheap to clone, and all clones control the same connections.
+#[derive(Debug, Clone, Default)]
+pub struct FaultInjector(Arc<Mutex<Faults>>);
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Silently drop the next `n` frames written by any
+    /// connection. The connection is otherwise unaffected, the peer
+    /// just never receives the messages.
+    pub fn drop_next(&self, n: usize) {
+        self.0.lock().drop += n
+    }
+
+    /// Corrupt the next `n` frames written by any connection. The
+    /// peer will fail to decode them, and will close the connection.
+    pub fn corrupt_next(&self, n: usize) {
+        self.0.lock().corrupt += n
+    }
+
+    /// Wait `d` before writing anything, or stop waiting if `d` is
+    /// `None`.
+    pub fn delay_writes(&self, d: Option<Duration>) {
+        self.0.lock().delay = d
+    }
+
+    /// Immediately close every connection. New connections are not
+    /// affected.
+    pub fn disconnect(&self) {
+        for kill in self.0.lock().kill.drain(..) {
+            let _ = kill.send(());
+        }
+    }
+
+    /// Make every existing connection half open, as if the peer's
+    /// host vanished without closing it. Nothing written to the
+    /// connection reaches the peer from now on, but it isn't closed,
+    /// and whatever the peer sends is still received. New
+    /// connections are not affected, and `clear` does not undo this.
+    pub fn half_open(&self) {
+        for h in self.0.lock().half_open.drain(..) {
+            h.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Stop all pending drops, corruptions, and delays
+    pub fn clear(&self) {
+        let mut t = self.0.lock();
+        t.drop = 0;
+        t.corrupt = 0;
+        t.delay = None;
+    }
+
+    pub(crate) fn install(&self, read: &mut ReadChannel, write: &mut WriteChannel) {
+        let (tx, rx) = oneshot::channel();
+        let mut t = self.0.lock();
+        t.kill.retain(|k| !k.is_canceled());
+        t.kill.push(tx);
+        let half_open = Arc::new(AtomicBool::new(false));
+        t.half_open.retain(|h| Arc::strong_count(h) > 1);
+        t.half_open.push(half_open.clone());
+        read.set_kill(rx);
+        write.set_faults(self.clone(), half_open);
+    }
+
+    pub(crate) fn delay(&self) -> Option<Duration> {
+        self.0.lock().delay
+    }
+
+    // return false if the frame should be dropped
+    pub(crate) fn frame(&self, half_open: &AtomicBool, frame: &mut BytesMut) -> bool {
+        let mut t = self.0.lock();
+        if half_open.load(Ordering::Relaxed) {
+            false
+        } else if t.drop > 0 {
+            t.drop -= 1;
+            false
+        } else {
+            if t.corrupt > 0 {
+                t.corrupt -= 1;
+                for b in frame.iter_mut() {
+                    *b = !*b;
+                }
+            }
+            true
+        }
+    }
+}
@@ -115,10 +115,13 @@ pub(crate) mod tls;
 mod batch_channel;
 mod channel;
 pub mod config;
+#[cfg(feature = "fault_injection")]
+pub mod fault;
 mod os;
 pub mod publisher;
 pub mod resolver_client;
 pub mod resolver_server;
+mod shm;
 pub mod subscriber;
 #[cfg(test)]
 mod test;
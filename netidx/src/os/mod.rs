@@ -54,7 +54,7 @@ pub(crate) mod local_auth {
 }
 
 #[cfg(unix)]
-pub(crate) use unix::Mapper;
+pub(crate) use unix::{shm, Mapper};
 
 #[cfg(windows)]
-pub(crate) use windows::Mapper;
+pub(crate) use windows::{shm, Mapper};
@@ -276,3 +276,100 @@ pub(crate) mod local_auth {
         }
     }
 }
+
+pub(crate) mod shm {
+    use anyhow::{bail, Result};
+    use std::{
+        ffi::CString,
+        fs::File,
+        io,
+        os::unix::io::{AsRawFd, FromRawFd},
+        ptr,
+    };
+
+    /// A shared memory segment mapped into our address space
+    #[derive(Debug)]
+    pub(crate) struct Mapping {
+        ptr: *mut u8,
+        len: usize,
+    }
+
+    // the mapping is just memory, synchronization of it's contents is
+    // the responsibility of the user
+    unsafe impl Send for Mapping {}
+    unsafe impl Sync for Mapping {}
+
+    impl Drop for Mapping {
+        fn drop(&mut self) {
+            unsafe {
+                libc::munmap(self.ptr as *mut libc::c_void, self.len);
+            }
+        }
+    }
+
+    fn shm_open(name: &str, flags: libc::c_int) -> Result<File> {
+        let name = CString::new(name)?;
+        let fd = unsafe { libc::shm_open(name.as_ptr(), flags, 0o600 as libc::c_uint) };
+        if fd < 0 {
+            bail!(io::Error::last_os_error())
+        }
+        Ok(unsafe { File::from_raw_fd(fd) })
+    }
+
+    impl Mapping {
+        fn map(file: &File, len: usize) -> Result<Mapping> {
+            let ptr = unsafe {
+                libc::mmap(
+                    ptr::null_mut(),
+                    len,
+                    libc::PROT_READ | libc::PROT_WRITE,
+                    libc::MAP_SHARED,
+                    file.as_raw_fd(),
+                    0,
+                )
+            };
+            if ptr == libc::MAP_FAILED {
+                bail!(io::Error::last_os_error())
+            }
+            Ok(Mapping { ptr: ptr as *mut u8, len })
+        }
+
+        /// Create a new zero filled segment called `name` that only
+        /// our user may open.
+        pub(crate) fn create(name: &str, len: usize) -> Result<Mapping> {
+            let flags = libc::O_CREAT | libc::O_EXCL | libc::O_RDWR;
+            let file = shm_open(name, flags)?;
+            let res = file.set_len(len as u64).map_err(anyhow::Error::from);
+            match res.and_then(|()| Mapping::map(&file, len)) {
+                Ok(m) => Ok(m),
+                Err(e) => {
+                    Mapping::unlink(name);
+                    Err(e)
+                }
+            }
+        }
+
+        /// Open an existing segment called `name`, which must be
+        /// exactly `len` bytes long.
+        pub(crate) fn open(name: &str, len: usize) -> Result<Mapping> {
+            let file = shm_open(name, libc::O_RDWR)?;
+            if file.metadata()?.len() != len as u64 {
+                bail!("shared memory segment {} is the wrong size", name)
+            }
+            Mapping::map(&file, len)
+        }
+
+        /// Remove the name of a segment. Mappings of it stay valid.
+        pub(crate) fn unlink(name: &str) {
+            if let Ok(name) = CString::new(name) {
+                unsafe {
+                    libc::shm_unlink(name.as_ptr());
+                }
+            }
+        }
+
+        pub(crate) fn as_ptr(&self) -> *mut u8 {
+            self.ptr
+        }
+    }
+}
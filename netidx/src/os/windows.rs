@@ -50,3 +50,26 @@ pub(crate) mod local_auth {
         }
     }
 }
+
+pub(crate) mod shm {
+    use anyhow::{bail, Result};
+
+    #[derive(Debug)]
+    pub(crate) struct Mapping;
+
+    impl Mapping {
+        pub(crate) fn create(_name: &str, _len: usize) -> Result<Mapping> {
+            bail!("shared memory transport is not implemented on windows")
+        }
+
+        pub(crate) fn open(_name: &str, _len: usize) -> Result<Mapping> {
+            bail!("shared memory transport is not implemented on windows")
+        }
+
+        pub(crate) fn unlink(_name: &str) {}
+
+        pub(crate) fn as_ptr(&self) -> *mut u8 {
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,68 @@
+use crate::protocol::{publisher::WriteKey, value::Value};
+use arcstr::ArcStr;
+use fxhash::FxHashMap;
+use std::{
+    collections::{hash_map::Entry, VecDeque},
+    time::{Duration, Instant},
+};
+
+/// How long the publisher remembers write keys, unless
+/// `PublisherBuilder::dedup_window` says otherwise.
+pub const DEFAULT_DEDUP_WINDOW: Duration = Duration::from_secs(300);
+
+// the user the write came from, if it was authenticated, and the key
+pub(super) type DedupKey = (Option<ArcStr>, WriteKey);
+
+pub(super) enum Seen {
+    New,
+    // a duplicate, with the result of the original write, if it's known
+    Duplicate(Option<Value>),
+}
+
+// The keys of recent writes, and their results once they are known
+pub(super) struct Dedup {
+    window: Duration,
+    results: FxHashMap<DedupKey, Option<Value>>,
+    expires: VecDeque<(Instant, DedupKey)>,
+}
+
+impl Dedup {
+    pub(super) fn new(window: Duration) -> Self {
+        Self { window, results: FxHashMap::default(), expires: VecDeque::new() }
+    }
+
+    pub(super) fn set_window(&mut self, window: Duration) {
+        self.window = window;
+    }
+
+    fn expire(&mut self, now: Instant) {
+        while let Some((t, _)) = self.expires.front() {
+            if *t > now {
+                break;
+            }
+            if let Some((_, key)) = self.expires.pop_front() {
+                self.results.remove(&key);
+            }
+        }
+    }
+
+    // record the key if it is new
+    pub(super) fn check(&mut self, key: DedupKey) -> Seen {
+        let now = Instant::now();
+        self.expire(now);
+        match self.results.entry(key) {
+            Entry::Occupied(e) => Seen::Duplicate(e.get().clone()),
+            Entry::Vacant(e) => {
+                self.expires.push_back((now + self.window, e.key().clone()));
+                e.insert(None);
+                Seen::New
+            }
+        }
+    }
+
+    pub(super) fn complete(&mut self, key: &DedupKey, result: Value) {
+        if let Some(r) = self.results.get_mut(key) {
+            *r = Some(result);
+        }
+    }
+}
@@ -0,0 +1,274 @@
+use super::{Id, Publisher, PublisherWeak, Val, Value, WriteRequest};
+use crate::{chars::Chars, path::Path, pool::Pooled, protocol::schema::Schema};
+use anyhow::Result;
+use futures::{
+    channel::mpsc::{channel, Receiver, Sender},
+    prelude::*,
+    select_biased,
+};
+use fxhash::FxHashMap;
+use log::{info, warn};
+use serde_json::from_str;
+use std::{
+    collections::HashMap,
+    fs::read_to_string,
+    path::{Path as FsPath, PathBuf},
+    time::{Duration, SystemTime},
+};
+use tokio::{fs, time};
+
+/// How often the manifest file is checked for changes by default
+pub const DEFAULT_MANIFEST_RELOAD: Duration = Duration::from_secs(1);
+
+/// The on disk format, encoded as JSON
+mod file {
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub(super) struct Publication {
+        pub path: String,
+        pub value: String,
+        #[serde(default)]
+        pub writable: bool,
+        #[serde(default)]
+        pub deadband: Option<f64>,
+        #[serde(default)]
+        pub doc: Option<String>,
+    }
+
+    #[derive(Debug, Clone, Serialize, Deserialize)]
+    #[serde(deny_unknown_fields)]
+    pub(super) struct Manifest {
+        pub publications: Vec<Publication>,
+    }
+}
+
+/// A publication declared in a manifest
+#[derive(Debug, Clone, PartialEq)]
+pub struct Publication {
+    pub path: Path,
+    /// The value published initially, and whenever it changes in
+    /// the manifest.
+    pub value: Value,
+    /// If true subscribers may write the value, and writes are
+    /// published as the new value.
+    pub writable: bool,
+    /// If set, numeric writes that differ from the current value by
+    /// no more than `deadband` are accepted but not published.
+    pub deadband: Option<f64>,
+    /// Published as the doc string of the value's schema
+    pub doc: Option<Chars>,
+}
+
+/// Publications declared in a file rather than in code, see
+/// `PublisherBuilder::manifest`. The manifest is JSON,
+///
+/// ```json
+/// { "publications": [
+///     { "path": "/gw/setpoint", "value": "42.", "writable": true,
+///       "deadband": 0.5, "doc": "the temperature setpoint" },
+///     { "path": "/gw/name", "value": "\"boiler\"" }
+/// ] }
+/// ```
+///
+/// `value` is written in the netidx value syntax, the same syntax
+/// the command line tools use. `writable`, `deadband`, and `doc` are
+/// optional.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Manifest {
+    pub publications: Vec<Publication>,
+}
+
+impl Manifest {
+    /// Parse a manifest from a JSON string
+    pub fn parse(s: &str) -> Result<Manifest> {
+        let m: file::Manifest = from_str(s)?;
+        let mut paths = HashMap::new();
+        let mut publications = Vec::with_capacity(m.publications.len());
+        for p in m.publications {
+            let path = Path::from(p.path);
+            if !Path::is_absolute(&path) {
+                bail!("publication paths must be absolute {}", path)
+            }
+            if paths.insert(path.clone(), ()).is_some() {
+                bail!("{} is published more than once", path)
+            }
+            let value = match p.value.parse::<Value>() {
+                Ok(v) => v,
+                Err(e) => bail!("invalid value for {}: {}", path, e),
+            };
+            if let Some(d) = p.deadband {
+                if !d.is_finite() || d < 0. {
+                    bail!("the deadband of {} must be a non negative number", path)
+                }
+            }
+            publications.push(Publication {
+                path,
+                value,
+                writable: p.writable,
+                deadband: p.deadband,
+                doc: p.doc.map(Chars::from),
+            })
+        }
+        Ok(Manifest { publications })
+    }
+
+    /// Load a manifest from the specified file
+    pub fn load<P: AsRef<FsPath>>(file: P) -> Result<Manifest> {
+        Manifest::parse(&read_to_string(file)?)
+    }
+}
+
+struct Published {
+    publication: Publication,
+    val: Val,
+    schema: Option<Val>,
+}
+
+/// Keeps the publisher in sync with the manifest
+pub(super) struct ManifestSync {
+    file: PathBuf,
+    mtime: Option<SystemTime>,
+    by_path: HashMap<Path, Published>,
+    by_id: FxHashMap<Id, Path>,
+    tx: Sender<Pooled<Vec<WriteRequest>>>,
+    rx: Receiver<Pooled<Vec<WriteRequest>>>,
+}
+
+impl ManifestSync {
+    /// Load the manifest and publish everything in it
+    pub(super) async fn new(publisher: &Publisher, file: PathBuf) -> Result<Self> {
+        let mtime = fs::metadata(&file).await?.modified().ok();
+        let manifest = Manifest::parse(&fs::read_to_string(&file).await?)?;
+        let (tx, rx) = channel(3);
+        let mut t = ManifestSync {
+            file,
+            mtime,
+            by_path: HashMap::new(),
+            by_id: FxHashMap::default(),
+            tx,
+            rx,
+        };
+        t.apply(publisher, manifest).await?;
+        Ok(t)
+    }
+
+    async fn apply(&mut self, publisher: &Publisher, manifest: Manifest) -> Result<()> {
+        let mut batch = publisher.start_batch();
+        let mut keep = HashMap::new();
+        let res = manifest.publications.into_iter().try_for_each(|p| {
+            let mut published = match self.by_path.remove(&p.path) {
+                Some(mut cur) => {
+                    let id = cur.val.id();
+                    if cur.publication.value != p.value {
+                        cur.val.update_changed(&mut batch, p.value.clone())
+                    }
+                    if cur.publication.doc != p.doc {
+                        cur.schema = None;
+                    }
+                    match (cur.publication.writable, p.writable) {
+                        (true, false) => publisher.stop_writes(id),
+                        (false, true) => publisher.writes(id, self.tx.clone()),
+                        (true, true) | (false, false) => (),
+                    }
+                    Published { publication: p, ..cur }
+                }
+                None => {
+                    let val = publisher.publish(p.path.clone(), p.value.clone())?;
+                    if p.writable {
+                        publisher.writes(val.id(), self.tx.clone())
+                    }
+                    self.by_id.insert(val.id(), p.path.clone());
+                    Published { publication: p, val, schema: None }
+                }
+            };
+            if published.schema.is_none() {
+                if let Some(doc) = &published.publication.doc {
+                    let schema = Schema::default().doc(doc.clone());
+                    let path = &published.publication.path;
+                    published.schema = Some(publisher.publish_schema(path, &schema)?);
+                }
+            }
+            keep.insert(published.publication.path.clone(), published);
+            Ok::<_, anyhow::Error>(())
+        });
+        if let Err(e) = res {
+            // don't unpublish anything because of a bad manifest
+            self.by_path.extend(keep);
+            return Err(e);
+        }
+        for (_, gone) in self.by_path.drain() {
+            self.by_id.remove(&gone.val.id());
+        }
+        self.by_path = keep;
+        batch.commit(None).await;
+        Ok(())
+    }
+
+    async fn reload(&mut self, publisher: &Publisher) -> Result<()> {
+        let mtime = fs::metadata(&self.file).await?.modified().ok();
+        if mtime.is_some() && mtime == self.mtime {
+            return Ok(());
+        }
+        let manifest = Manifest::parse(&fs::read_to_string(&self.file).await?)?;
+        self.mtime = mtime;
+        info!("reloading publisher manifest {}", self.file.display());
+        self.apply(publisher, manifest).await
+    }
+
+    async fn process_writes(
+        &self,
+        publisher: &Publisher,
+        mut reqs: Pooled<Vec<WriteRequest>>,
+    ) {
+        let mut batch = publisher.start_batch();
+        for req in reqs.drain(..) {
+            let published = match self.by_id.get(&req.id) {
+                Some(path) => &self.by_path[path],
+                None => continue,
+            };
+            if !published.publication.writable {
+                continue;
+            }
+            let within_deadband = match published.publication.deadband {
+                None => false,
+                Some(deadband) => match publisher.current(&req.id) {
+                    Some(cur) if cur.number() && req.value.number() => {
+                        match (cur.cast_to::<f64>(), req.value.clone().cast_to::<f64>()) {
+                            (Ok(cur), Ok(new)) => (new - cur).abs() <= deadband,
+                            _ => false,
+                        }
+                    }
+                    _ => false,
+                },
+            };
+            if !within_deadband {
+                published.val.update_changed(&mut batch, req.value)
+            }
+        }
+        batch.commit(None).await
+    }
+
+    pub(super) async fn run(mut self, publisher: PublisherWeak, interval: Duration) {
+        let mut interval = time::interval(interval);
+        loop {
+            select_biased! {
+                reqs = self.rx.select_next_some() => match publisher.upgrade() {
+                    None => break,
+                    Some(publisher) => self.process_writes(&publisher, reqs).await,
+                },
+                _ = interval.tick().fuse() => match publisher.upgrade() {
+                    None => break,
+                    Some(publisher) => {
+                        if let Err(e) = self.reload(&publisher).await {
+                            warn!(
+                                "failed to reload manifest {}: {}",
+                                self.file.display(),
+                                e
+                            )
+                        }
+                    }
+                },
+            }
+        }
+    }
+}
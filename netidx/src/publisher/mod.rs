@@ -1,31 +1,45 @@
+mod dedup;
+mod manifest;
 mod server;
+mod tenant;
+mod typed;
 pub use crate::protocol::{
-    publisher::Id,
-    value::{FromValue, Typ, Value},
+    patch::Patch,
+    publisher::{Id, UnsubscribeReason},
+    schema::Schema,
+    value::{ErrorInfo, FromValue, Typ, Value, WriteOutcome},
 };
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
     config::Config,
+    pack::Pack,
     path::Path,
-    pool::{Pool, Pooled},
-    protocol::{publisher, resolver::UserInfo},
+    pool::{self, Pool, PoolConfig, Pooled},
+    protocol::{
+        delta::StrDelta, glob::GlobSet, publisher, resolver::UserInfo,
+        schema::schema_path,
+    },
     resolver_client::ResolverWrite,
     resolver_server::auth::Permissions,
     tls,
     utils::{self, ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
+pub use dedup::DEFAULT_DEDUP_WINDOW;
 use futures::{
     channel::{
         mpsc::{unbounded, Sender, UnboundedReceiver, UnboundedSender},
         oneshot,
     },
     prelude::*,
+    select_biased,
     stream::FusedStream,
 };
 use fxhash::{FxHashMap, FxHashSet};
 use get_if_addrs::get_if_addrs;
-use log::{error, info};
+use log::{error, info, warn};
+use manifest::ManifestSync;
+pub use manifest::{Manifest, Publication, DEFAULT_MANIFEST_RELOAD};
 use parking_lot::Mutex;
 use rand::{self, Rng};
 use std::{
@@ -33,15 +47,18 @@ use std::{
     collections::{hash_map::Entry, BTreeMap, BTreeSet, HashMap, HashSet},
     convert::{From, Into, TryInto},
     default::Default,
-    iter, mem,
+    fmt, iter, mem,
     net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, ToSocketAddrs},
+    path::PathBuf,
     pin::Pin,
     result,
     str::FromStr,
     sync::{Arc, Weak},
-    time::Duration,
+    time::{Duration, Instant},
 };
-use tokio::{net::TcpListener, task};
+pub use tenant::{TenantBatch, TenantPublisher};
+use tokio::{net::TcpListener, task, time};
+pub use typed::{TypedVal, TypedWriteRequest};
 
 /// Control how the publisher picks a bind address. The address we
 /// give to the resolver server must be uniquely routable back to us,
@@ -116,30 +133,7 @@ impl FromStr for BindCfg {
             match s.find("/") {
                 None => Ok(BindCfg::Exact(s.parse()?)),
                 Some(_) => {
-                    let mut parts = s.splitn(2, '/');
-                    let addr: IpAddr =
-                        parts.next().ok_or_else(|| anyhow!("expected ip"))?.parse()?;
-                    let bits: u32 = parts
-                        .next()
-                        .ok_or_else(|| anyhow!("expected netmask"))?
-                        .parse()?;
-                    if parts.next().is_some() {
-                        bail!("parse error, trailing garbage after netmask")
-                    }
-                    let netmask = match addr {
-                        IpAddr::V4(_) => {
-                            if bits > 32 {
-                                bail!("invalid netmask");
-                            }
-                            IpAddr::V4(Ipv4Addr::from(u32::MAX.wrapping_shl(32 - bits)))
-                        }
-                        IpAddr::V6(_) => {
-                            if bits > 128 {
-                                bail!("invalid netmask");
-                            }
-                            IpAddr::V6(Ipv6Addr::from(u128::MAX.wrapping_shl(128 - bits)))
-                        }
-                    };
+                    let Cidr { addr, netmask } = s.parse()?;
                     Ok(BindCfg::Match { addr, netmask })
                 }
             }
@@ -147,10 +141,43 @@ impl FromStr for BindCfg {
     }
 }
 
+// fe80::/10, which is only meaningful along with a scope id
+fn is_v6_link_local(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => false,
+        IpAddr::V6(ip) => ip.segments()[0] & 0xffc0 == 0xfe80,
+    }
+}
+
 impl BindCfg {
+    /// true if the bind config selects an ipv6 address
+    pub fn is_ipv6(&self) -> bool {
+        match self {
+            BindCfg::Local => false,
+            BindCfg::Exact(addr) => addr.is_ipv6(),
+            BindCfg::Match { addr, .. } => addr.is_ipv6(),
+        }
+    }
+
+    /// Check that `alt` can be used as the alternate bind config of
+    /// a publisher bound with `self`, see
+    /// `PublisherBuilder::alt_bind_cfg`. It must select an address
+    /// of the other family.
+    pub fn check_alt(&self, alt: &BindCfg) -> Result<()> {
+        if self.is_ipv6() == alt.is_ipv6() {
+            bail!("{:?} and its alternate {:?} are the same address family", self, alt)
+        }
+        Ok(())
+    }
+
     fn select(&self) -> Result<IpAddr> {
         match self {
             BindCfg::Local => Ok(IpAddr::V4(Ipv4Addr::LOCALHOST)),
+            BindCfg::Exact(SocketAddr::V6(addr))
+                if is_v6_link_local(IpAddr::V6(*addr.ip())) && addr.scope_id() == 0 =>
+            {
+                bail!("{} is link local, it must include a scope id", addr)
+            }
             BindCfg::Exact(addr) => {
                 if get_if_addrs()?.iter().any(|i| i.ip() == addr.ip()) {
                     Ok(addr.ip())
@@ -159,35 +186,18 @@ impl BindCfg {
                 }
             }
             BindCfg::Match { addr, netmask } => {
+                let net = Cidr { addr: *addr, netmask: *netmask };
                 let selected = get_if_addrs()?
                     .iter()
-                    .filter_map(|i| match (i.ip(), addr, netmask) {
-                        (IpAddr::V4(ip), IpAddr::V4(addr), IpAddr::V4(nm)) => {
-                            let masked = Ipv4Addr::from(
-                                u32::from_be_bytes(ip.octets())
-                                    & u32::from_be_bytes(nm.octets()),
-                            );
-                            if &masked == addr {
-                                Some(IpAddr::V4(ip))
-                            } else {
-                                None
-                            }
-                        }
-                        (IpAddr::V6(ip), IpAddr::V6(addr), IpAddr::V6(nm)) => {
-                            let masked = Ipv6Addr::from(
-                                u128::from_be_bytes(ip.octets())
-                                    & u128::from_be_bytes(nm.octets()),
-                            );
-                            if &masked == addr {
-                                Some(IpAddr::V6(ip))
-                            } else {
-                                None
-                            }
-                        }
-                        (_, _, _) => None,
-                    })
+                    .map(|i| i.ip())
+                    .filter(|ip| net.contains(*ip))
                     .collect::<Vec<_>>();
-                if selected.len() == 1 {
+                if selected.len() == 1 && is_v6_link_local(selected[0]) {
+                    bail!(
+                        "{} is link local, use an exact bind config with a scope id",
+                        selected[0]
+                    )
+                } else if selected.len() == 1 {
                     Ok(selected[0])
                 } else if selected.len() == 0 {
                     bail!("no interface matches {:?}", self);
@@ -199,18 +209,131 @@ impl BindCfg {
     }
 }
 
+/// A network, `addr/bits`, used by `PublisherBuilder::allow` and
+/// `PublisherBuilder::deny` to filter clients by address. A bare
+/// address is a network containing only that address.
+///
+/// # Examples
+/// ```
+/// use netidx::publisher::Cidr;
+/// let net = "10.0.0.0/8".parse::<Cidr>().unwrap();
+/// assert!(net.contains("10.1.2.3".parse().unwrap()));
+/// assert!(!net.contains("192.168.0.1".parse().unwrap()));
+/// ```
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Cidr {
+    pub addr: IpAddr,
+    pub netmask: IpAddr,
+}
+
+impl FromStr for Cidr {
+    type Err = Error;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.trim().splitn(2, '/');
+        let addr: IpAddr = parts.next().ok_or_else(|| anyhow!("expected ip"))?.parse()?;
+        let bits: u32 = match parts.next() {
+            Some(bits) => bits.parse()?,
+            None if addr.is_ipv4() => 32,
+            None => 128,
+        };
+        if parts.next().is_some() {
+            bail!("parse error, trailing garbage after netmask")
+        }
+        let netmask = match addr {
+            IpAddr::V4(_) => {
+                if bits > 32 {
+                    bail!("invalid netmask");
+                }
+                IpAddr::V4(Ipv4Addr::from(u32::MAX.checked_shl(32 - bits).unwrap_or(0)))
+            }
+            IpAddr::V6(_) => {
+                if bits > 128 {
+                    bail!("invalid netmask");
+                }
+                IpAddr::V6(Ipv6Addr::from(u128::MAX.checked_shl(128 - bits).unwrap_or(0)))
+            }
+        };
+        Ok(Cidr { addr, netmask })
+    }
+}
+
+impl Cidr {
+    /// true if `ip` is in this network
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (ip, self.addr, self.netmask) {
+            (IpAddr::V4(ip), IpAddr::V4(addr), IpAddr::V4(nm)) => {
+                let nm = u32::from(nm);
+                u32::from(ip) & nm == u32::from(addr) & nm
+            }
+            (IpAddr::V6(ip), IpAddr::V6(addr), IpAddr::V6(nm)) => {
+                let nm = u128::from(nm);
+                u128::from(ip) & nm == u128::from(addr) & nm
+            }
+            (_, _, _) => false,
+        }
+    }
+}
+
+#[derive(Clone)]
+struct OnAccept(Arc<dyn Fn(SocketAddr) -> bool + Send + Sync>);
+
+impl fmt::Debug for OnAccept {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OnAccept")
+    }
+}
+
+// decide which connections to accept before the handshake
+#[derive(Debug, Clone, Default)]
+struct AcceptPolicy {
+    allow: Vec<Cidr>,
+    deny: Vec<Cidr>,
+    max_clients_per_ip: Option<usize>,
+    on_accept: Option<OnAccept>,
+}
+
+impl AcceptPolicy {
+    fn acceptable(&self, addr: SocketAddr, from_ip: usize) -> bool {
+        let ip = addr.ip();
+        if self.deny.iter().any(|n| n.contains(ip)) {
+            return false;
+        }
+        if !self.allow.is_empty() && !self.allow.iter().any(|n| n.contains(ip)) {
+            return false;
+        }
+        if let Some(max) = self.max_clients_per_ip {
+            if from_ip >= max {
+                return false;
+            }
+        }
+        match &self.on_accept {
+            None => true,
+            Some(OnAccept(f)) => f(addr),
+        }
+    }
+}
+
 atomic_id!(ClId);
+atomic_id!(HookId);
 
 lazy_static! {
-    static ref BATCHES: Pool<Vec<WriteRequest>> = Pool::new(100, 10_000);
-    static ref TOPUB: Pool<HashMap<Path, Option<u32>>> = Pool::new(10, 10_000);
-    static ref TOUPUB: Pool<HashSet<Path>> = Pool::new(5, 10_000);
-    static ref TOUSUB: Pool<HashMap<Id, Subscribed>> = Pool::new(5, 10_000);
-    static ref RAWBATCH: Pool<Vec<BatchMsg>> = Pool::new(100, 100_000);
-    static ref UPDATES: Pool<Vec<publisher::From>> = Pool::new(100, 100_000);
-    static ref RAWUNSUBS: Pool<Vec<(ClId, Id)>> = Pool::new(100, 100_000);
-    static ref UNSUBS: Pool<Vec<Id>> = Pool::new(100, 100_000);
-    static ref BATCH: Pool<FxHashMap<ClId, Update>> = Pool::new(100, 1000);
+    static ref BATCHES: Pool<Vec<WriteRequest>> =
+        Pool::named("publisher::batches", 100, 10_000);
+    static ref TOPUB: Pool<HashMap<Path, Option<u32>>> =
+        Pool::named("publisher::topub", 10, 10_000);
+    static ref TOUPUB: Pool<HashSet<Path>> = Pool::named("publisher::toupub", 5, 10_000);
+    static ref TOUSUB: Pool<HashMap<Id, Subscribed>> =
+        Pool::named("publisher::tousub", 5, 10_000);
+    static ref RAWBATCH: Pool<Vec<BatchMsg>> =
+        Pool::named("publisher::rawbatch", 100, 100_000);
+    static ref UPDATES: Pool<Vec<publisher::From>> =
+        Pool::named("publisher::updates", 100, 100_000);
+    static ref RAWUNSUBS: Pool<Vec<(ClId, Id, UnsubscribeReason)>> =
+        Pool::named("publisher::rawunsubs", 100, 100_000);
+    static ref UNSUBS: Pool<Vec<(Id, UnsubscribeReason)>> =
+        Pool::named("publisher::unsubs", 100, 100_000);
+    static ref BATCH: Pool<FxHashMap<ClId, Update>> =
+        Pool::named("publisher::batch", 100, 1000);
 
     // estokes 2021: This is reasonable because there will never be
     // that many publishers in a process. Since a publisher wraps
@@ -301,6 +424,12 @@ impl SendResult {
             let _ = s.send(v);
         }
     }
+
+    /// Reply with one of the standard write outcomes, the subscriber
+    /// can convert the receipt back with `WriteOutcome::from`.
+    pub fn send_outcome(self, o: WriteOutcome) {
+        self.send(Value::from(o))
+    }
 }
 
 #[derive(Debug)]
@@ -325,7 +454,7 @@ pub enum Event {
 
 struct Update {
     updates: Pooled<Vec<publisher::From>>,
-    unsubscribes: Option<Pooled<Vec<Id>>>,
+    unsubscribes: Option<Pooled<Vec<(Id, UnsubscribeReason)>>>,
 }
 
 impl Update {
@@ -404,6 +533,38 @@ impl Val {
         Ok(batch.updates.push(BatchMsg::UpdateChanged(self.0, v.try_into()?)))
     }
 
+    /// Queue a partial update to the published value, see
+    /// `protocol::patch`. On commit the patch is applied to the
+    /// current value, subscribers that asked for patches are sent
+    /// the patch, and everyone else is sent the patched value. If
+    /// the patch doesn't apply to the current value it is logged and
+    /// ignored. If any update hooks are registered, then every
+    /// subscriber is sent the patched value, since the hooks may
+    /// have changed it.
+    pub fn update_patch(&self, batch: &mut UpdateBatch, patch: Patch) {
+        batch.updates.push(BatchMsg::Patch(self.0, patch))
+    }
+
+    /// Queue an update in the shared batch of `publisher`, which is
+    /// committed automatically according to the policy set by
+    /// `Publisher::auto_commit`. If auto commit isn't enabled, it is
+    /// enabled with the default policy (`DEFAULT_AUTO_COMMIT_DELAY`,
+    /// `DEFAULT_AUTO_COMMIT_BYTES`). Updates queued this way are
+    /// sent in the order they were queued, but they may be committed
+    /// before or after updates in explicit batches.
+    pub fn update_auto<T: Into<Value>>(&self, publisher: &Publisher, v: T) {
+        let mut pb = publisher.0.lock();
+        pb.auto_commit
+            .get_or_insert_with(|| {
+                AutoCommit::start(
+                    publisher.downgrade(),
+                    DEFAULT_AUTO_COMMIT_DELAY,
+                    DEFAULT_AUTO_COMMIT_BYTES,
+                )
+            })
+            .queue(self.0, v.into())
+    }
+
     /// Queue sending `v` as an update ONLY to the specified
     /// subscriber, and do not update `current`.
     pub fn update_subscriber<T: Into<Value>>(
@@ -428,11 +589,25 @@ impl Val {
     /// Queue unsubscribing the specified client. Like update, this
     /// will only take effect when the specified batch is committed.
     pub fn unsubscribe(&self, batch: &mut UpdateBatch, dst: ClId) {
+        self.unsubscribe_with_reason(batch, dst, UnsubscribeReason::Unspecified)
+    }
+
+    /// Queue unsubscribing the specified client, telling it why, e.g.
+    /// `Overloaded` when shedding subscribers, or `Denied` when
+    /// evicting a client that is no longer permitted. Like
+    /// `unsubscribe`, this will only take effect when the specified
+    /// batch is committed.
+    pub fn unsubscribe_with_reason(
+        &self,
+        batch: &mut UpdateBatch,
+        dst: ClId,
+        reason: UnsubscribeReason,
+    ) {
         match &mut batch.unsubscribes {
-            Some(u) => u.push((dst, self.0)),
+            Some(u) => u.push((dst, self.0, reason)),
             None => {
                 let mut u = RAWUNSUBS.take();
-                u.push((dst, self.0));
+                u.push((dst, self.0, reason));
                 batch.unsubscribes = Some(u);
             }
         }
@@ -444,10 +619,85 @@ impl Val {
     }
 }
 
+/// A request from a subscriber to materialize a path under a
+/// default publisher. See `DefaultHandle::requests` and
+/// `DefaultHandle::set_veto`.
+#[derive(Debug, Clone)]
+pub struct DefaultRequest {
+    /// The path the subscriber wants
+    pub path: Path,
+    /// The client making the request
+    pub client: ClId,
+    /// The user the resolver attested the client is, `None` if the
+    /// client is anonymous
+    pub user: Option<UserInfo>,
+}
+
+type DefaultVeto = Arc<
+    dyn Fn(DefaultRequest) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync,
+>;
+
+type UpdateHooks =
+    BTreeMap<Path, Vec<(HookId, Arc<dyn Fn(&Path, Value) -> Value + Send + Sync>)>>;
+
+// run the update hooks of every subtree containing path, from the
+// root down, and in the order they were added.
+fn apply_hooks(hooks: &UpdateHooks, path: &Path, v: Value) -> Value {
+    if hooks.is_empty() {
+        return v;
+    }
+    Path::dirnames(path).fold(v, |v, base| match hooks.get(base) {
+        None => v,
+        Some(hooks) => hooks.iter().fold(v, |v, (_, f)| f(path, v)),
+    })
+}
+
+// the message that sends `v`, the new value of `id`, to `cl`. Clients
+// that asked for deltas are sent the change from the previous value
+// if both are strings and the change is smaller than `v`. The delta
+// is computed at most once for all the clients.
+fn update_msg(
+    clients: &mut FxHashMap<ClId, Client>,
+    cl: &ClId,
+    id: Id,
+    prev: &Value,
+    v: &Value,
+    delta: &mut Option<Option<StrDelta>>,
+) -> publisher::From {
+    if let Some(c) = clients.get_mut(cl) {
+        if c.deltas && !c.diverged.remove(&id) {
+            let d = delta.get_or_insert_with(|| match (prev, v) {
+                (Value::String(prev), Value::String(v)) => StrDelta::diff(prev, v),
+                _ => None,
+            });
+            if let Some(d) = d {
+                return publisher::From::Delta(id, d.clone());
+            }
+        }
+    }
+    publisher::From::Update(id, v.clone())
+}
+
+type ComputeFn =
+    Arc<dyn Fn() -> Pin<Box<dyn Future<Output = Value> + Send>> + Send + Sync>;
+
+struct OnSubscribe {
+    compute: ComputeFn,
+    ttl: Duration,
+    computed: Option<Instant>,
+    // the subscriptions waiting for the running computation, if any
+    waiting: Option<Vec<oneshot::Sender<()>>>,
+}
+
+struct DefaultPub {
+    chan: UnboundedSender<(DefaultRequest, oneshot::Sender<()>)>,
+    veto: Option<DefaultVeto>,
+}
+
 /// A handle to the channel that will receive notifications about
 /// subscriptions to paths in a subtree with a default publisher.
 pub struct DefaultHandle {
-    chan: UnboundedReceiver<(Path, oneshot::Sender<()>)>,
+    chan: UnboundedReceiver<(DefaultRequest, oneshot::Sender<()>)>,
     path: Path,
     publisher: PublisherWeak,
 }
@@ -459,7 +709,7 @@ impl Stream for DefaultHandle {
         mut self: std::pin::Pin<&mut Self>,
         cx: &mut std::task::Context<'_>,
     ) -> std::task::Poll<Option<Self::Item>> {
-        Pin::new(&mut self.chan).poll_next(cx)
+        Pin::new(&mut self.chan).poll_next(cx).map(|r| r.map(|(req, tx)| (req.path, tx)))
     }
 }
 
@@ -470,6 +720,35 @@ impl FusedStream for DefaultHandle {
 }
 
 impl DefaultHandle {
+    /// Receive the full `DefaultRequest`, including the client and
+    /// user that made it, instead of just the path.
+    pub fn requests(self) -> DefaultRequests {
+        DefaultRequests(self)
+    }
+
+    /// Install an accept/reject hook that is called with every
+    /// request before it is delivered to the handle, and therefore
+    /// before any value is published. If the future returned by `f`
+    /// resolves to false the request is rejected, the subscriber is
+    /// told the value doesn't exist and the request is never seen
+    /// by the handle. This is the place to apply naming policy and
+    /// quotas to a default publisher.
+    ///
+    /// `f` is called without any locks held, and the future it
+    /// returns is run in it's own task, so it may call back into the
+    /// publisher. Subscriptions are delayed while the hook runs.
+    pub fn set_veto<F, Fut>(&self, f: F)
+    where
+        F: Fn(DefaultRequest) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        if let Some(pb) = self.publisher.upgrade() {
+            if let Some(d) = pb.0.lock().default.get_mut(&self.path) {
+                d.veto = Some(Arc::new(move |req| Box::pin(f(req))));
+            }
+        }
+    }
+
     /// Advertising is a middle way between fully publishing and a
     /// completely sparse namespace.
     ///
@@ -558,6 +837,35 @@ impl DefaultHandle {
     }
 }
 
+/// A stream of requests to a default publisher along with the
+/// client and user that made them, see `DefaultHandle::requests`.
+pub struct DefaultRequests(DefaultHandle);
+
+impl Stream for DefaultRequests {
+    type Item = (DefaultRequest, oneshot::Sender<()>);
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        Pin::new(&mut self.0.chan).poll_next(cx)
+    }
+}
+
+impl FusedStream for DefaultRequests {
+    fn is_terminated(&self) -> bool {
+        self.0.chan.is_terminated()
+    }
+}
+
+impl DefaultRequests {
+    /// The `DefaultHandle` this stream came from, e.g. to advertise
+    /// paths
+    pub fn handle(&self) -> &DefaultHandle {
+        &self.0
+    }
+}
+
 impl Drop for DefaultHandle {
     fn drop(&mut self) {
         if let Some(t) = self.publisher.upgrade() {
@@ -581,6 +889,7 @@ impl Drop for DefaultHandle {
 enum BatchMsg {
     UpdateChanged(Id, Value),
     Update(Option<ClId>, Id, Value),
+    Patch(Id, Patch),
 }
 
 /// A batch of updates to Vals
@@ -588,7 +897,7 @@ enum BatchMsg {
 pub struct UpdateBatch {
     origin: Publisher,
     updates: Pooled<Vec<BatchMsg>>,
-    unsubscribes: Option<Pooled<Vec<(ClId, Id)>>>,
+    unsubscribes: Option<Pooled<Vec<(ClId, Id, UnsubscribeReason)>>>,
 }
 
 impl UpdateBatch {
@@ -630,49 +939,121 @@ impl UpdateBatch {
         let fut = {
             let mut batch = BATCH.take();
             let mut pb = self.origin.0.lock();
+            let pb = &mut *pb;
             for m in self.updates.drain(..) {
                 match m {
                     BatchMsg::Update(None, id, v) => {
                         if let Some(pbl) = pb.by_id.get_mut(&id) {
+                            let v = apply_hooks(&pb.update_hooks, &pbl.path, v);
+                            let mut delta = None;
                             for cl in pbl.subscribed.iter() {
+                                let m = update_msg(
+                                    &mut pb.clients,
+                                    cl,
+                                    id,
+                                    &pbl.current,
+                                    &v,
+                                    &mut delta,
+                                );
                                 batch
                                     .entry(*cl)
                                     .or_insert_with(Update::new)
                                     .updates
-                                    .push(publisher::From::Update(id, v.clone()));
+                                    .push(m);
                             }
                             pbl.current = v;
                         }
                     }
                     BatchMsg::UpdateChanged(id, v) => {
                         if let Some(pbl) = pb.by_id.get_mut(&id) {
+                            let v = apply_hooks(&pb.update_hooks, &pbl.path, v);
                             if pbl.current != v {
+                                let mut delta = None;
                                 for cl in pbl.subscribed.iter() {
+                                    let m = update_msg(
+                                        &mut pb.clients,
+                                        cl,
+                                        id,
+                                        &pbl.current,
+                                        &v,
+                                        &mut delta,
+                                    );
                                     batch
                                         .entry(*cl)
                                         .or_insert_with(Update::new)
                                         .updates
-                                        .push(publisher::From::Update(id, v.clone()));
+                                        .push(m);
                                 }
                                 pbl.current = v;
                             }
                         }
                     }
-                    BatchMsg::Update(Some(cl), id, v) => batch
-                        .entry(cl)
-                        .or_insert_with(Update::new)
-                        .updates
-                        .push(publisher::From::Update(id, v)),
+                    BatchMsg::Patch(id, patch) => {
+                        if let Some(pbl) = pb.by_id.get_mut(&id) {
+                            let v = match patch.apply(&pbl.current) {
+                                Ok(v) => v,
+                                Err(e) => {
+                                    warn!("ignoring patch to {}: {}", pbl.path, e);
+                                    continue;
+                                }
+                            };
+                            let hooked = !pb.update_hooks.is_empty();
+                            let v = apply_hooks(&pb.update_hooks, &pbl.path, v);
+                            let mut delta = None;
+                            for cl in pbl.subscribed.iter() {
+                                let patches = !hooked
+                                    && pb
+                                        .clients
+                                        .get(cl)
+                                        .map(|c| c.patches)
+                                        .unwrap_or(false);
+                                let m = if patches {
+                                    publisher::From::Patch(id, patch.clone())
+                                } else {
+                                    update_msg(
+                                        &mut pb.clients,
+                                        cl,
+                                        id,
+                                        &pbl.current,
+                                        &v,
+                                        &mut delta,
+                                    )
+                                };
+                                batch
+                                    .entry(*cl)
+                                    .or_insert_with(Update::new)
+                                    .updates
+                                    .push(m)
+                            }
+                            pbl.current = v;
+                        }
+                    }
+                    BatchMsg::Update(Some(cl), id, v) => {
+                        let v = match pb.by_id.get(&id) {
+                            None => v,
+                            Some(pbl) => apply_hooks(&pb.update_hooks, &pbl.path, v),
+                        };
+                        if let Some(c) = pb.clients.get_mut(&cl) {
+                            if c.deltas {
+                                c.diverged.insert(id);
+                            }
+                        }
+                        batch
+                            .entry(cl)
+                            .or_insert_with(Update::new)
+                            .updates
+                            .push(publisher::From::Update(id, v))
+                    }
                 }
             }
             if let Some(usubs) = &mut self.unsubscribes {
-                for (cl, id) in usubs.drain(..) {
+                for (cl, id, reason) in usubs.drain(..) {
                     let update = batch.entry(cl).or_insert_with(Update::new);
                     match &mut update.unsubscribes {
-                        Some(u) => u.push(id),
+                        Some(u) => u.push((id, reason)),
                         None => {
                             let mut u = UNSUBS.take();
-                            u.push(id);
+                            u.push((id, reason));
                             update.unsubscribes = Some(u);
                         }
                     }
@@ -693,10 +1074,171 @@ impl UpdateBatch {
     }
 }
 
+/// The default maximum time an update queued with `Val::update_auto`
+/// waits before it is committed
+pub const DEFAULT_AUTO_COMMIT_DELAY: Duration = Duration::from_millis(1);
+
+/// The default encoded size at which the auto commit batch is
+/// committed without waiting for the delay
+pub const DEFAULT_AUTO_COMMIT_BYTES: usize = 64 * 1024;
+
+/// Statistics about the batches committed by auto commit, see
+/// `Publisher::auto_commit_stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct AutoCommitStats {
+    /// The number of batches committed
+    pub batches: u64,
+    /// The total number of updates committed
+    pub updates: u64,
+    /// The total encoded size of the updates committed
+    pub bytes: u64,
+    /// The most updates committed in one batch
+    pub max_updates: u64,
+    /// The number of batches committed because they reached the size limit
+    pub by_size: u64,
+    /// The number of batches committed because they reached the delay
+    pub by_time: u64,
+}
+
+impl AutoCommitStats {
+    /// The mean number of updates per batch
+    pub fn mean_updates(&self) -> f64 {
+        if self.batches == 0 {
+            0.
+        } else {
+            self.updates as f64 / self.batches as f64
+        }
+    }
+
+    /// The mean encoded size of a batch
+    pub fn mean_bytes(&self) -> f64 {
+        if self.batches == 0 {
+            0.
+        } else {
+            self.bytes as f64 / self.batches as f64
+        }
+    }
+}
+
+struct AutoCommit {
+    max_delay: Duration,
+    max_bytes: usize,
+    updates: Pooled<Vec<BatchMsg>>,
+    bytes: usize,
+    flush_requested: bool,
+    // true to commit now, false to start the delay
+    trigger: UnboundedSender<bool>,
+    stats: AutoCommitStats,
+}
+
+impl AutoCommit {
+    fn start(publisher: PublisherWeak, max_delay: Duration, max_bytes: usize) -> Self {
+        let (trigger, rx) = unbounded();
+        task::spawn(auto_commit_loop(publisher, rx));
+        AutoCommit {
+            max_delay,
+            max_bytes,
+            updates: RAWBATCH.take(),
+            bytes: 0,
+            flush_requested: false,
+            trigger,
+            stats: AutoCommitStats::default(),
+        }
+    }
+
+    fn queue(&mut self, id: Id, v: Value) {
+        if self.updates.is_empty() {
+            let _: Result<_, _> = self.trigger.unbounded_send(false);
+        }
+        self.bytes += v.encoded_len();
+        self.updates.push(BatchMsg::Update(None, id, v));
+        if self.bytes >= self.max_bytes && !self.flush_requested {
+            self.flush_requested = true;
+            let _: Result<_, _> = self.trigger.unbounded_send(true);
+        }
+    }
+
+    fn take(&mut self, by_size: bool) -> Pooled<Vec<BatchMsg>> {
+        let updates = mem::replace(&mut self.updates, RAWBATCH.take());
+        if !updates.is_empty() {
+            let st = &mut self.stats;
+            st.batches += 1;
+            st.updates += updates.len() as u64;
+            st.bytes += self.bytes as u64;
+            st.max_updates = st.max_updates.max(updates.len() as u64);
+            if by_size {
+                st.by_size += 1;
+            } else {
+                st.by_time += 1;
+            }
+        }
+        self.bytes = 0;
+        self.flush_requested = false;
+        updates
+    }
+}
+
+async fn auto_commit_loop(
+    publisher: PublisherWeak,
+    mut trigger: UnboundedReceiver<bool>,
+) {
+    async fn wait(deadline: Option<time::Instant>) {
+        match deadline {
+            None => future::pending().await,
+            Some(deadline) => time::sleep_until(deadline).await,
+        }
+    }
+    let mut deadline = None;
+    loop {
+        let by_size = select_biased! {
+            m = trigger.next() => match m {
+                None => break,
+                Some(true) => true,
+                Some(false) => {
+                    if deadline.is_none() {
+                        let publisher = match publisher.upgrade() {
+                            None => break,
+                            Some(publisher) => publisher,
+                        };
+                        let pb = publisher.0.lock();
+                        let delay = pb.auto_commit.as_ref().map(|a| a.max_delay);
+                        deadline = Some(time::Instant::now() + delay.unwrap_or_default());
+                    }
+                    continue
+                }
+            },
+            () = wait(deadline).fuse() => false,
+        };
+        deadline = None;
+        let publisher = match publisher.upgrade() {
+            None => break,
+            Some(publisher) => publisher,
+        };
+        let updates = match &mut publisher.0.lock().auto_commit {
+            None => break,
+            Some(ac) => ac.take(by_size),
+        };
+        if !updates.is_empty() {
+            let batch = UpdateBatch { origin: publisher, updates, unsubscribes: None };
+            batch.commit(None).await
+        }
+    }
+}
+
 struct Client {
     msg_queue: MsgQ,
     subscribed: FxHashMap<Id, Permissions>,
     user: Option<UserInfo>,
+    // the client can apply patches
+    patches: bool,
+    // the client can apply string deltas
+    deltas: bool,
+    // the client can decode `Value::ErrorInfo`
+    error_info: bool,
+    // ids where the client was last sent a value of it's own with
+    // `update_subscriber`, so it doesn't have the current value to
+    // apply a delta to
+    diverged: FxHashSet<Id>,
 }
 
 pub struct Published {
@@ -722,6 +1264,7 @@ impl Published {
 
 struct PublisherInner {
     addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     stop: Option<oneshot::Sender<()>>,
     clients: FxHashMap<ClId, Client>,
     hc_subscribed: FxHashMap<BTreeSet<ClId>, Subscribed>,
@@ -730,7 +1273,12 @@ struct PublisherInner {
     destroy_on_idle: FxHashSet<Id>,
     on_write_chans: FxHashMap<ChanWrap<Pooled<Vec<WriteRequest>>>, (ChanId, HashSet<Id>)>,
     on_event_chans: Vec<UnboundedSender<Event>>,
+    on_interest_chans: FxHashMap<Id, Vec<UnboundedSender<usize>>>,
     on_write: FxHashMap<Id, Vec<(ChanId, Sender<Pooled<Vec<WriteRequest>>>)>>,
+    on_write_subtree: BTreeMap<Path, Vec<Sender<Pooled<Vec<WriteRequest>>>>>,
+    update_hooks: UpdateHooks,
+    on_subscribe: FxHashMap<Id, OnSubscribe>,
+    auto_commit: Option<AutoCommit>,
     resolver: ResolverWrite,
     advertised: HashMap<Path, HashSet<Path>>,
     to_publish: Pooled<HashMap<Path, Option<u32>>>,
@@ -742,7 +1290,15 @@ struct PublisherInner {
     trigger_publish: UnboundedSender<Option<oneshot::Sender<()>>>,
     wait_clients: FxHashMap<Id, Vec<oneshot::Sender<()>>>,
     wait_any_client: Vec<oneshot::Sender<()>>,
-    default: BTreeMap<Path, UnboundedSender<(Path, oneshot::Sender<()>)>>,
+    default: BTreeMap<Path, DefaultPub>,
+    registered: HashMap<Path, Option<u32>>,
+    registered_default: HashMap<Path, Option<u32>>,
+    listen: Listen,
+    accept: AcceptPolicy,
+    clients_by_ip: FxHashMap<IpAddr, usize>,
+    dedup: dedup::Dedup,
+    #[cfg(feature = "fault_injection")]
+    faults: Option<crate::fault::FaultInjector>,
 }
 
 impl PublisherInner {
@@ -752,6 +1308,7 @@ impl PublisherInner {
             Some(stop) => {
                 let _ = stop.send(());
                 self.clients.clear();
+                self.clients_by_ip.clear();
                 self.by_id.clear();
                 true
             }
@@ -779,9 +1336,55 @@ impl PublisherInner {
         self.to_publish
             .insert(path.clone(), if flags.is_empty() { None } else { Some(flags.bits) });
         self.trigger_publish();
+        self.subtree_writes(id, &path);
         Ok(())
     }
 
+    fn writes(&mut self, id: Id, tx: Sender<Pooled<Vec<WriteRequest>>>) {
+        let e = self
+            .on_write_chans
+            .entry(ChanWrap(tx.clone()))
+            .or_insert_with(|| (ChanId::new(), HashSet::new()));
+        e.1.insert(id);
+        let cid = e.0;
+        let mut gc = Vec::new();
+        let ow = self.on_write.entry(id).or_insert_with(Vec::new);
+        ow.retain(|(_, c)| {
+            if c.is_closed() {
+                gc.push(ChanWrap(c.clone()));
+                false
+            } else {
+                true
+            }
+        });
+        if !ow.iter().any(|(c, _)| *c == cid) {
+            ow.push((cid, tx));
+        }
+        for c in gc {
+            self.on_write_chans.remove(&c);
+        }
+    }
+
+    // register the write channels of any subtree containing path
+    fn subtree_writes(&mut self, id: Id, path: &Path) {
+        if self.on_write_subtree.is_empty() {
+            return;
+        }
+        let mut chans = Vec::new();
+        for base in Path::dirnames(path) {
+            if let Some(subtree) = self.on_write_subtree.get_mut(base) {
+                subtree.retain(|c| !c.is_closed());
+                chans.extend(subtree.iter().cloned());
+                if subtree.is_empty() {
+                    self.on_write_subtree.remove(base);
+                }
+            }
+        }
+        for tx in chans {
+            self.writes(id, tx)
+        }
+    }
+
     fn unpublish(&mut self, path: &Path) {
         self.by_path.remove(path);
         if !self.is_advertised(path) {
@@ -799,6 +1402,7 @@ impl PublisherInner {
                 self.unpublish(path)
             }
             self.wait_clients.remove(&id);
+            self.on_subscribe.remove(&id);
             if let Some(chans) = self.on_write.remove(&id) {
                 for (_, c) in chans {
                     match self.on_write_chans.entry(ChanWrap(c)) {
@@ -819,8 +1423,78 @@ impl PublisherInner {
         }
     }
 
+    // destroy every published value whose primary path matches,
+    // return the number destroyed
+    fn destroy_matching<F: Fn(&Path) -> bool>(&mut self, matches: F) -> usize {
+        let ids = self
+            .by_id
+            .iter()
+            .filter_map(|(id, pbl)| if matches(&pbl.path) { Some(*id) } else { None })
+            .collect::<Vec<_>>();
+        for id in ids.iter() {
+            self.destroy_val(*id)
+        }
+        ids.len()
+    }
+
+    // If `id` has an on subscribe compute hook and it's value is
+    // stale then start computing it, unless that is already
+    // happening, and return a channel that fires once the fresh value
+    // has been committed.
+    fn compute_on_subscribe(
+        &mut self,
+        publisher: &PublisherWeak,
+        id: Id,
+    ) -> Option<oneshot::Receiver<()>> {
+        let os = self.on_subscribe.get_mut(&id)?;
+        if os.computed.map(|t| t.elapsed() < os.ttl).unwrap_or(false) {
+            return None;
+        }
+        let (tx, rx) = oneshot::channel();
+        match &mut os.waiting {
+            Some(waiting) => waiting.push(tx),
+            None => {
+                os.waiting = Some(vec![tx]);
+                let compute = os.compute.clone();
+                let publisher = publisher.clone();
+                task::spawn(async move {
+                    let v = compute().await;
+                    if let Some(publisher) = publisher.upgrade() {
+                        let mut batch = publisher.start_batch();
+                        batch.updates.push(BatchMsg::Update(None, id, v));
+                        batch.commit(None).await;
+                        let mut pb = publisher.0.lock();
+                        if let Some(os) = pb.on_subscribe.get_mut(&id) {
+                            os.computed = Some(Instant::now());
+                            for tx in os.waiting.take().into_iter().flatten() {
+                                let _: Result<_, _> = tx.send(());
+                            }
+                        }
+                    }
+                });
+            }
+        }
+        Some(rx)
+    }
+
     fn send_event(&mut self, event: Event) {
         self.on_event_chans.retain(|chan| chan.unbounded_send(event).is_ok());
+        match event {
+            Event::Subscribe(id, _) | Event::Unsubscribe(id, _) => self.send_interest(id),
+            Event::Destroyed(id) => {
+                self.on_interest_chans.remove(&id);
+            }
+        }
+    }
+
+    fn send_interest(&mut self, id: Id) {
+        if let Some(chans) = self.on_interest_chans.get_mut(&id) {
+            let n = self.by_id.get(&id).map(|p| p.subscribed.len()).unwrap_or(0);
+            chans.retain(|chan| chan.unbounded_send(n).is_ok());
+            if chans.is_empty() {
+                self.on_interest_chans.remove(&id);
+            }
+        }
     }
 
     fn trigger_publish(&mut self) {
@@ -856,25 +1530,193 @@ fn rand_port(current: u16) -> u16 {
     current + rng.gen_range(0u16..10u16)
 }
 
+async fn bind(
+    bind_cfg: &BindCfg,
+    resolver: &Config,
+) -> Result<(SocketAddr, TcpListener)> {
+    let ip = bind_cfg.select()?;
+    utils::check_addr(ip, &resolver.addrs)?;
+    match bind_cfg {
+        BindCfg::Exact(addr) => {
+            let l = TcpListener::bind(addr).await?;
+            Ok((l.local_addr()?, l))
+        }
+        BindCfg::Match { .. } | BindCfg::Local => {
+            let mkaddr = |ip: IpAddr, port: u16| -> Result<SocketAddr> {
+                Ok((ip, port)
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or_else(|| anyhow!("socketaddrs bug"))?)
+            };
+            let mut port = 5000;
+            loop {
+                if port >= 32768 {
+                    bail!("couldn't allocate a port");
+                }
+                port = rand_port(port);
+                let addr = mkaddr(ip, port)?;
+                match TcpListener::bind(&addr).await {
+                    Ok(l) => break Ok((l.local_addr()?, l)),
+                    Err(e) => {
+                        if e.kind() != std::io::ErrorKind::AddrInUse {
+                            bail!(e)
+                        }
+                    }
+                }
+            }
+        }
+    }
+}
+
+// everything needed to (re)start the listener
+#[derive(Clone)]
+struct Listen {
+    resolver: Config,
+    desired_auth: DesiredAuth,
+    bind_cfg: BindCfg,
+    alt_bind_cfg: Option<BindCfg>,
+    tls_ctx: Option<tls::CachedAcceptor>,
+    max_clients: usize,
+}
+
+impl Listen {
+    // bind the listener, and the alternate listener if there is one
+    async fn bind(
+        &self,
+    ) -> Result<((SocketAddr, TcpListener), Option<(SocketAddr, TcpListener)>)> {
+        let primary = bind(&self.bind_cfg, &self.resolver).await?;
+        let alt = match &self.alt_bind_cfg {
+            None => None,
+            Some(alt) => Some(bind(alt, &self.resolver).await?),
+        };
+        Ok((primary, alt))
+    }
+
+    fn start(
+        &self,
+        publisher: PublisherWeak,
+        listener: TcpListener,
+        alt_listener: Option<TcpListener>,
+        stop: oneshot::Receiver<()>,
+    ) {
+        let desired_auth = self.desired_auth.clone();
+        let tls_ctx = self.tls_ctx.clone();
+        let max_clients = self.max_clients;
+        task::spawn(async move {
+            server::start(
+                publisher,
+                listener,
+                alt_listener,
+                stop,
+                desired_auth,
+                tls_ctx,
+                max_clients,
+            )
+            .await;
+            info!("accept loop shutdown");
+        });
+    }
+}
+
+async fn watch_addr(publisher: PublisherWeak, interval: Duration) {
+    let mut interval = time::interval(interval);
+    loop {
+        interval.tick().await;
+        let publisher = match publisher.upgrade() {
+            None => break,
+            Some(publisher) => publisher,
+        };
+        let (current, bind_cfg) = {
+            let pb = publisher.0.lock();
+            if pb.stop.is_none() {
+                break;
+            }
+            (pb.addr.ip(), pb.listen.bind_cfg)
+        };
+        match bind_cfg.select() {
+            Err(e) => warn!("watch_addr: can't select an address {}", e),
+            Ok(ip) if ip == current => (),
+            Ok(ip) => {
+                info!(
+                    "watch_addr: address changed from {} to {}, rebinding",
+                    current, ip
+                );
+                if let Err(e) = publisher.rebind().await {
+                    error!("watch_addr: failed to rebind {}", e)
+                }
+            }
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct PublisherBuilder {
     config: Option<Config>,
     desired_auth: Option<DesiredAuth>,
     bind_cfg: Option<BindCfg>,
+    alt_bind_cfg: Option<BindCfg>,
     max_clients: usize,
+    watch_addr: Option<Duration>,
+    accept: AcceptPolicy,
+    pools: Option<PoolConfig>,
+    dedup_window: Duration,
+    manifest: Option<PathBuf>,
+    manifest_reload: Duration,
+    #[cfg(feature = "fault_injection")]
+    faults: Option<crate::fault::FaultInjector>,
 }
 
 impl PublisherBuilder {
     pub fn new() -> Self {
-        Self { config: None, desired_auth: None, bind_cfg: None, max_clients: 768 }
+        Self {
+            config: None,
+            desired_auth: None,
+            bind_cfg: None,
+            alt_bind_cfg: None,
+            max_clients: 768,
+            watch_addr: None,
+            accept: AcceptPolicy::default(),
+            pools: None,
+            dedup_window: DEFAULT_DEDUP_WINDOW,
+            manifest: None,
+            manifest_reload: DEFAULT_MANIFEST_RELOAD,
+            #[cfg(feature = "fault_injection")]
+            faults: None,
+        }
     }
 
     pub async fn build(&mut self) -> Result<Publisher> {
         let cfg = self.config.take().ok_or_else(|| anyhow!("config is required"))?;
+        if let Some(pools) = self.pools {
+            pool::configure("publisher::", pools)
+        }
         let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
         let bind_cfg =
             self.bind_cfg.take().unwrap_or_else(|| cfg.default_bind_config.clone());
-        Publisher::new(cfg, desired_auth, bind_cfg, self.max_clients).await
+        let alt_bind_cfg =
+            self.alt_bind_cfg.take().or_else(|| cfg.default_alt_bind_config.clone());
+        let publisher = Publisher::new_with_policy(
+            cfg,
+            desired_auth,
+            bind_cfg,
+            alt_bind_cfg,
+            self.max_clients,
+            self.accept.clone(),
+        )
+        .await?;
+        publisher.0.lock().dedup.set_window(self.dedup_window);
+        #[cfg(feature = "fault_injection")]
+        {
+            publisher.0.lock().faults = self.faults.take();
+        }
+        if let Some(interval) = self.watch_addr {
+            task::spawn(watch_addr(publisher.downgrade(), interval));
+        }
+        if let Some(file) = self.manifest.take() {
+            let sync = ManifestSync::new(&publisher, file).await?;
+            task::spawn(sync.run(publisher.downgrade(), self.manifest_reload));
+        }
+        Ok(publisher)
     }
 
     /// The netidx config to use
@@ -898,11 +1740,116 @@ impl PublisherBuilder {
         self
     }
 
+    /// Also listen on an address of the other family, for dual
+    /// stack networks. Both addresses are registered with the
+    /// resolver, and subscribers will try them in order of their
+    /// `SubscriberBuilder::prefer` setting. It is an error if `bind`
+    /// is the same family as the primary bind config. If not
+    /// specified the config default, if any, will be used.
+    pub fn alt_bind_cfg(&mut self, bind: BindCfg) -> &mut Self {
+        self.alt_bind_cfg = Some(bind);
+        self
+    }
+
     /// The maximum number of simultaneous subscribers. default 768.
     pub fn max_clients(&mut self, max_clients: usize) -> &mut Self {
         self.max_clients = max_clients;
         self
     }
+
+    /// Only accept clients with addresses in `net`. May be specified
+    /// multiple times, a client is accepted if it is in any of the
+    /// allowed networks. default allow all.
+    pub fn allow(&mut self, net: Cidr) -> &mut Self {
+        self.accept.allow.push(net);
+        self
+    }
+
+    /// Never accept clients with addresses in `net`. May be
+    /// specified multiple times, deny takes precedence over allow.
+    pub fn deny(&mut self, net: Cidr) -> &mut Self {
+        self.accept.deny.push(net);
+        self
+    }
+
+    /// The maximum number of simultaneous connections from any one ip
+    /// address. default unlimited.
+    pub fn max_clients_per_ip(&mut self, max: usize) -> &mut Self {
+        self.accept.max_clients_per_ip = Some(max);
+        self
+    }
+
+    /// Call `f` with the address of every new connection that passed
+    /// the allow, deny, and connection limit checks. If `f` returns
+    /// false the connection is closed immediately, before any
+    /// handshake or authentication takes place.
+    ///
+    /// `f` is called with the publisher lock held, it must not call
+    /// back into the publisher, and it should be fast.
+    pub fn on_accept<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(SocketAddr) -> bool + Send + Sync + 'static,
+    {
+        self.accept.on_accept = Some(OnAccept(Arc::new(f)));
+        self
+    }
+
+    /// Check every `interval` whether the address selected by the
+    /// bind config has changed, e.g. because a laptop moved to a
+    /// different network or got a new DHCP lease, and if it has call
+    /// `Publisher::rebind`. This is only useful with
+    /// `BindCfg::Match`. default off.
+    pub fn watch_addr(&mut self, interval: Duration) -> &mut Self {
+        self.watch_addr = Some(interval);
+        self
+    }
+
+    /// Override the limits of the pools the publisher uses to batch
+    /// updates and process writes, see `PoolConfig`. The pools are
+    /// shared by every publisher in the process, so the last
+    /// publisher built with this setting wins.
+    pub fn pools(&mut self, cfg: PoolConfig) -> &mut Self {
+        self.pools = Some(cfg);
+        self
+    }
+
+    /// How long to remember the keys of writes sent with an
+    /// idempotency key (see `Dval::write_keyed`). A keyed write
+    /// that arrives within `window` of an earlier write with the
+    /// same key, from the same user, is not passed on to the write
+    /// handler again. default `DEFAULT_DEDUP_WINDOW`.
+    pub fn dedup_window(&mut self, window: Duration) -> &mut Self {
+        self.dedup_window = window;
+        self
+    }
+
+    /// Publish the values declared in the manifest `file`, see
+    /// `Manifest` for the format. The publisher keeps the published
+    /// values in sync with the file, publishing new entries,
+    /// unpublishing removed ones, and updating values whose initial
+    /// value changed. Writable values are updated by writes from
+    /// subscribers. `build` fails if the manifest can't be loaded,
+    /// later errors are logged and the last good manifest stays in
+    /// effect.
+    pub fn manifest<P: Into<PathBuf>>(&mut self, file: P) -> &mut Self {
+        self.manifest = Some(file.into());
+        self
+    }
+
+    /// How often to check the manifest file for changes. default
+    /// `DEFAULT_MANIFEST_RELOAD`.
+    pub fn manifest_reload(&mut self, interval: Duration) -> &mut Self {
+        self.manifest_reload = interval;
+        self
+    }
+
+    /// Inject faults into connections to subscribers, see
+    /// `fault::FaultInjector`.
+    #[cfg(feature = "fault_injection")]
+    pub fn fault_injector(&mut self, faults: crate::fault::FaultInjector) -> &mut Self {
+        self.faults = Some(faults);
+        self
+    }
 }
 
 /// Publish values. Publisher is internally wrapped in an Arc, so
@@ -925,44 +1872,45 @@ impl Publisher {
         bind_cfg: BindCfg,
         max_clients: usize,
     ) -> Result<Publisher> {
-        let ip = bind_cfg.select()?;
-        utils::check_addr(ip, &resolver.addrs)?;
-        let (addr, listener) = match bind_cfg {
-            BindCfg::Exact(addr) => {
-                let l = TcpListener::bind(&addr).await?;
-                (l.local_addr()?, l)
-            }
-            BindCfg::Match { .. } | BindCfg::Local => {
-                let mkaddr = |ip: IpAddr, port: u16| -> Result<SocketAddr> {
-                    Ok((ip, port)
-                        .to_socket_addrs()?
-                        .next()
-                        .ok_or_else(|| anyhow!("socketaddrs bug"))?)
-                };
-                let mut port = 5000;
-                loop {
-                    if port >= 32768 {
-                        bail!("couldn't allocate a port");
-                    }
-                    port = rand_port(port);
-                    let addr = mkaddr(ip, port)?;
-                    match TcpListener::bind(&addr).await {
-                        Ok(l) => break (l.local_addr()?, l),
-                        Err(e) => {
-                            if e.kind() != std::io::ErrorKind::AddrInUse {
-                                bail!(e)
-                            }
-                        }
-                    }
-                }
-            }
+        Self::new_with_policy(
+            resolver,
+            desired_auth,
+            bind_cfg,
+            None,
+            max_clients,
+            AcceptPolicy::default(),
+        )
+        .await
+    }
+
+    async fn new_with_policy(
+        resolver: Config,
+        desired_auth: DesiredAuth,
+        bind_cfg: BindCfg,
+        alt_bind_cfg: Option<BindCfg>,
+        max_clients: usize,
+        accept: AcceptPolicy,
+    ) -> Result<Publisher> {
+        if let Some(alt) = &alt_bind_cfg {
+            bind_cfg.check_alt(alt)?
+        }
+        let listen = Listen {
+            tls_ctx: resolver.tls.clone().map(tls::CachedAcceptor::new),
+            resolver: resolver.clone(),
+            desired_auth: desired_auth.clone(),
+            bind_cfg,
+            alt_bind_cfg,
+            max_clients,
         };
-        let tls_ctx = resolver.tls.clone().map(tls::CachedAcceptor::new);
-        let resolver = ResolverWrite::new(resolver, desired_auth.clone(), addr)?;
+        let ((addr, listener), alt) = listen.bind().await?;
+        let (alt_addr, alt_listener) = alt.unzip();
+        let resolver =
+            ResolverWrite::new_with_alt_addr(resolver, desired_auth, addr, alt_addr)?;
         let (stop, receive_stop) = oneshot::channel();
         let (tx_trigger, rx_trigger) = unbounded();
         let pb = Publisher(Arc::new(Mutex::new(PublisherInner {
             addr,
+            alt_addr,
             stop: Some(stop),
             clients: HashMap::default(),
             hc_subscribed: HashMap::default(),
@@ -971,7 +1919,12 @@ impl Publisher {
             destroy_on_idle: HashSet::default(),
             on_write_chans: HashMap::default(),
             on_event_chans: Vec::new(),
+            on_interest_chans: HashMap::default(),
             on_write: HashMap::default(),
+            on_write_subtree: BTreeMap::new(),
+            update_hooks: BTreeMap::new(),
+            on_subscribe: HashMap::default(),
+            auto_commit: None,
             resolver,
             advertised: HashMap::new(),
             to_publish: TOPUB.take(),
@@ -984,22 +1937,16 @@ impl Publisher {
             wait_clients: HashMap::default(),
             wait_any_client: Vec::new(),
             default: BTreeMap::new(),
+            registered: HashMap::new(),
+            registered_default: HashMap::new(),
+            listen: listen.clone(),
+            accept,
+            clients_by_ip: HashMap::default(),
+            dedup: dedup::Dedup::new(DEFAULT_DEDUP_WINDOW),
+            #[cfg(feature = "fault_injection")]
+            faults: None,
         })));
-        task::spawn({
-            let pb_weak = pb.downgrade();
-            async move {
-                server::start(
-                    pb_weak.clone(),
-                    listener,
-                    receive_stop,
-                    desired_auth,
-                    tls_ctx,
-                    max_clients,
-                )
-                .await;
-                info!("accept loop shutdown");
-            }
-        });
+        listen.start(pb.downgrade(), listener, alt_listener, receive_stop);
         task::spawn({
             let pb_weak = pb.downgrade();
             async move {
@@ -1038,6 +1985,76 @@ impl Publisher {
         self.0.lock().addr
     }
 
+    /// get the `SocketAddr` of the alternate listener, if the
+    /// publisher has one, see `PublisherBuilder::alt_bind_cfg`
+    pub fn alt_addr(&self) -> Option<SocketAddr> {
+        self.0.lock().alt_addr
+    }
+
+    /// Bind a new listener to the address currently selected by the
+    /// bind config, move everything this publisher has published in
+    /// the resolver to the new address, and stop listening on the
+    /// old one. Finally every existing subscription is unsubscribed,
+    /// durable subscribers will then resubscribe to the new
+    /// address. Returns the new address.
+    ///
+    /// This is meant for machines whose address changes while the
+    /// publisher is running, see also `PublisherBuilder::watch_addr`.
+    /// Because the new listener is bound before the old one is
+    /// closed, `BindCfg::Exact` with a non zero port can't be
+    /// rebound.
+    pub async fn rebind(&self) -> Result<SocketAddr> {
+        let listen = self.0.lock().listen.clone();
+        let ((addr, listener), alt) = listen.bind().await?;
+        let (alt_addr, alt_listener) = alt.unzip();
+        let resolver = ResolverWrite::new_with_alt_addr(
+            listen.resolver.clone(),
+            listen.desired_auth.clone(),
+            addr,
+            alt_addr,
+        )?;
+        let (stop, receive_stop) = oneshot::channel();
+        let old_resolver = {
+            let mut inner = self.0.lock();
+            let pb = &mut *inner;
+            if pb.stop.is_none() {
+                bail!("publisher is dead")
+            }
+            if let Some(old_stop) = pb.stop.replace(stop) {
+                let _ = old_stop.send(());
+            }
+            pb.addr = addr;
+            pb.alt_addr = alt_addr;
+            for (path, flags) in pb.registered.iter() {
+                if !pb.to_unpublish.contains(path) {
+                    pb.to_publish.entry(path.clone()).or_insert(*flags);
+                }
+            }
+            for (path, flags) in pb.registered_default.iter() {
+                if !pb.to_unpublish_default.contains(path) {
+                    pb.to_publish_default.entry(path.clone()).or_insert(*flags);
+                }
+            }
+            pb.trigger_publish();
+            mem::replace(&mut pb.resolver, resolver)
+        };
+        listen.start(self.downgrade(), listener, alt_listener, receive_stop);
+        self.flushed().await;
+        if let Err(e) = old_resolver.clear().await {
+            warn!("rebind: failed to clear the old address {}", e)
+        }
+        let mut usubs = RAWUNSUBS.take();
+        for (clid, cl) in self.0.lock().clients.iter() {
+            usubs.extend(
+                cl.subscribed.keys().map(|id| (*clid, *id, UnsubscribeReason::Replaced)),
+            );
+        }
+        let mut batch = self.start_batch();
+        batch.unsubscribes = Some(usubs);
+        batch.commit(None).await;
+        Ok(addr)
+    }
+
     /// Publish `Path` with initial value `init` and flags `flags`. It
     /// is an error for the same publisher to publish the same path
     /// twice, however different publishers may publish a given path
@@ -1060,6 +2077,7 @@ impl Publisher {
         flags.remove(PublishFlags::DESTROY_ON_IDLE);
         let mut pb = self.0.lock();
         pb.publish(id, flags, path.clone())?;
+        let init = apply_hooks(&pb.update_hooks, &path, init);
         let subscribed = pb
             .hc_subscribed
             .entry(BTreeSet::new())
@@ -1124,6 +2142,39 @@ impl Publisher {
         self.publish_with_flags(PublishFlags::empty(), path, init)
     }
 
+    /// Publish `schema` as the description of `path`. The schema is
+    /// published at `path/.schema`, see `protocol::schema`, and is
+    /// unpublished when the returned `Val` is dropped. It is not
+    /// necessary for `path` to be published by this publisher.
+    pub fn publish_schema(&self, path: &Path, schema: &Schema) -> Result<Val> {
+        self.publish(schema_path(path), Value::from(schema))
+    }
+
+    /// Publish `path` with initial value `init` and flags `flags` as
+    /// a value of type `T`. Otherwise the same as
+    /// `publish_with_flags`. See `TypedVal`.
+    pub fn publish_typed_with_flags<T>(
+        &self,
+        flags: PublishFlags,
+        path: Path,
+        init: T,
+    ) -> Result<TypedVal<T>>
+    where
+        T: Into<Value> + FromValue,
+    {
+        let val = self.publish_with_flags(flags, path, init.into())?;
+        Ok(TypedVal::new(val, self.downgrade()))
+    }
+
+    /// Publish `path` with initial value `init` and no flags as a
+    /// value of type `T`. See `TypedVal`.
+    pub fn publish_typed<T>(&self, path: Path, init: T) -> Result<TypedVal<T>>
+    where
+        T: Into<Value> + FromValue,
+    {
+        self.publish_typed_with_flags(PublishFlags::empty(), path, init)
+    }
+
     /// Create an alias for an already published path
     pub fn alias(&self, id: Id, path: Path) -> Result<()> {
         self.alias_with_flags(id, PublishFlags::empty(), path)
@@ -1153,6 +2204,23 @@ impl Publisher {
         }
     }
 
+    /// Unpublish and destroy every value whose path matches `pat`,
+    /// returning the number of values destroyed. The resolver
+    /// unpublishes for all the affected paths and aliases go out in
+    /// one batch, subscribers are unsubscribed, and a `Destroyed`
+    /// event is sent for each value. Dropping a `Val` destroyed
+    /// this way later does nothing.
+    pub fn unpublish_matching(&self, pat: &GlobSet) -> usize {
+        self.0.lock().destroy_matching(|path| pat.is_match(path))
+    }
+
+    /// Unpublish and destroy `base` and every value published under
+    /// it, returning the number of values destroyed. See
+    /// `unpublish_matching`.
+    pub fn unpublish_subtree(&self, base: &Path) -> usize {
+        self.0.lock().destroy_matching(|path| Path::is_parent(base, path))
+    }
+
     /// Install a default publisher rooted at `base` with flags
     /// `flags`. Once installed, any subscription request for a child
     /// of `base`, regardless if it doesn't exist in the resolver,
@@ -1194,7 +2262,7 @@ impl Publisher {
         pb.to_unpublish.remove(base.as_ref());
         pb.to_publish_default
             .insert(base.clone(), if flags.is_empty() { None } else { Some(flags.bits) });
-        pb.default.insert(base.clone(), tx);
+        pb.default.insert(base.clone(), DefaultPub { chan: tx, veto: None });
         pb.trigger_publish();
         Ok(DefaultHandle { chan: rx, path: base, publisher: self.downgrade() })
     }
@@ -1235,6 +2303,34 @@ impl Publisher {
         UpdateBatch { origin: self.clone(), updates: RAWBATCH.take(), unsubscribes: None }
     }
 
+    /// Enable auto commit, or change it's policy if it is already
+    /// enabled. Updates queued with `Val::update_auto` go into a
+    /// batch shared by the whole publisher, which is committed by a
+    /// background task `max_delay` after the first update was queued
+    /// in it, or as soon as the encoded size of the queued updates
+    /// reaches `max_batch_bytes`, whichever comes first. This trades
+    /// a bounded amount of latency for fewer, larger, writes to the
+    /// subscribers.
+    pub fn auto_commit(&self, max_delay: Duration, max_batch_bytes: usize) {
+        let mut pb = self.0.lock();
+        match &mut pb.auto_commit {
+            Some(ac) => {
+                ac.max_delay = max_delay;
+                ac.max_bytes = max_batch_bytes;
+            }
+            None => {
+                let ac = AutoCommit::start(self.downgrade(), max_delay, max_batch_bytes);
+                pb.auto_commit = Some(ac);
+            }
+        }
+    }
+
+    /// Return statistics about the batches committed by auto commit,
+    /// or None if it isn't enabled.
+    pub fn auto_commit_stats(&self) -> Option<AutoCommitStats> {
+        self.0.lock().auto_commit.as_ref().map(|ac| ac.stats)
+    }
+
     /// Wait until all previous publish or unpublish commands have
     /// been processed by the resolver server. e.g. if you just
     /// published 100 values, and you want to know when they have been
@@ -1367,6 +2463,31 @@ impl Publisher {
         self.0.lock().by_id.get(&id).map(|p| p.subscribed.len()).unwrap_or(0)
     }
 
+    /// Get the number of clients subscribed to a published
+    /// `Val`. This is the same as `subscribed_len`.
+    pub fn subscriber_count(&self, id: Id) -> usize {
+        self.subscribed_len(&id)
+    }
+
+    /// Return a stream of the number of clients subscribed to the
+    /// specified published value. The current count is yielded
+    /// immediately, and then a new count is yielded every time a
+    /// client subscribes or unsubscribes. The stream ends when the
+    /// value is destroyed, or immediately if `id` isn't published.
+    ///
+    /// This is useful for starting expensive upstream computation
+    /// only when the first subscriber arrives, and stopping it when
+    /// the last one leaves.
+    pub fn interest(&self, id: Id) -> impl Stream<Item = usize> + Unpin + Send + 'static {
+        let (tx, rx) = unbounded();
+        let mut pb = self.0.lock();
+        if let Some(p) = pb.by_id.get(&id) {
+            let _: Result<_, _> = tx.unbounded_send(p.subscribed.len());
+            pb.on_interest_chans.entry(id).or_insert_with(Vec::new).push(tx);
+        }
+        rx
+    }
+
     /// Register `tx` to receive writes to the specified published
     /// value. You can register multiple channels, and you can
     /// register the same channel on multiple ids. If no channels are
@@ -1386,29 +2507,35 @@ impl Publisher {
     pub fn writes(&self, id: Id, tx: Sender<Pooled<Vec<WriteRequest>>>) {
         let mut pb = self.0.lock();
         if pb.by_id.contains_key(&id) {
-            let e = pb
-                .on_write_chans
-                .entry(ChanWrap(tx.clone()))
-                .or_insert_with(|| (ChanId::new(), HashSet::new()));
-            e.1.insert(id);
-            let cid = e.0;
-            let mut gc = Vec::new();
-            let ow = pb.on_write.entry(id).or_insert_with(Vec::new);
-            ow.retain(|(_, c)| {
-                if c.is_closed() {
-                    gc.push(ChanWrap(c.clone()));
-                    false
-                } else {
-                    true
-                }
-            });
-            ow.push((cid, tx));
-            for c in gc {
-                pb.on_write_chans.remove(&c);
-            }
+            pb.writes(id, tx)
         }
     }
 
+    /// Register `tx` to receive writes to every value published
+    /// under `base`, including `base` itself. This covers values that
+    /// are already published, and values published later, including
+    /// values published in response to a default publisher request,
+    /// and aliases published under `base`. The `path` member of each
+    /// `WriteRequest` identifies the value being written.
+    ///
+    /// This is the same as calling `writes` on each such value, so
+    /// values under `base` may still have other write channels
+    /// registered, and `stop_writes` still works on individual
+    /// values. To stop receiving writes for the subtree drop `tx`.
+    pub fn writes_subtree(&self, base: Path, tx: Sender<Pooled<Vec<WriteRequest>>>) {
+        let mut pb = self.0.lock();
+        let ids = pb
+            .by_path
+            .iter()
+            .filter(|(p, _)| Path::is_parent(&*base, &**p))
+            .map(|(_, id)| *id)
+            .collect::<Vec<_>>();
+        for id in ids {
+            pb.writes(id, tx.clone())
+        }
+        pb.on_write_subtree.entry(base).or_insert_with(Vec::new).push(tx);
+    }
+
     /// Stop accepting writes to the specified id
     pub fn stop_writes(&self, id: Id) {
         let mut pb = self.0.lock();
@@ -1422,6 +2549,76 @@ impl Publisher {
     pub fn events(&self, tx: UnboundedSender<Event>) {
         self.0.lock().on_event_chans.push(tx)
     }
+
+    /// Install a hook that transforms every value published under
+    /// `base`, including `base` itself, before it is sent to
+    /// subscribers. It applies to the initial value of values
+    /// published after it is added, and to every update committed
+    /// after it is added, e.g. to round, convert units, or redact
+    /// fields. Values already published keep their current value
+    /// until they are next updated.
+    ///
+    /// When several hooks cover a path they run in order from the
+    /// shallowest base to the deepest, and hooks with the same base
+    /// run in the order they were added, each receiving the output
+    /// of the previous one. For `update_changed` the hooks run before
+    /// the comparison with the current value.
+    ///
+    /// `f` is called with the publisher locked, so it must be quick,
+    /// and it must not call back into the publisher.
+    pub fn add_update_hook<F>(&self, base: Path, f: F) -> HookId
+    where
+        F: Fn(&Path, Value) -> Value + Send + Sync + 'static,
+    {
+        let id = HookId::new();
+        let mut pb = self.0.lock();
+        pb.update_hooks.entry(base).or_insert_with(Vec::new).push((id, Arc::new(f)));
+        id
+    }
+
+    /// Remove an update hook added by `add_update_hook`. Updates
+    /// committed after this returns are not transformed by it.
+    pub fn remove_update_hook(&self, id: HookId) {
+        let mut pb = self.0.lock();
+        pb.update_hooks.retain(|_, hooks| {
+            hooks.retain(|(hid, _)| *hid != id);
+            !hooks.is_empty()
+        });
+    }
+
+    /// Compute the value of `id` lazily. When a client subscribes and
+    /// the value wasn't computed in the last `ttl` the subscription
+    /// is delayed while `f` runs, and the value it returns is
+    /// committed as an update to `id`, so any existing subscribers
+    /// receive it as well. Clients subscribing within `ttl` of the
+    /// last computation get the cached value, and concurrent
+    /// subscriptions share the same computation. This is useful for
+    /// values that are expensive to compute but rarely subscribed,
+    /// the publisher doesn't need to keep updating them when nobody
+    /// is watching.
+    ///
+    /// `f` is called without any locks held, and the future it
+    /// returns is run in it's own task, so it may call back into the
+    /// publisher. Setting a new hook for `id` replaces the old one,
+    /// and the hook is removed when `id` is unpublished.
+    pub fn on_subscribe_compute<F, Fut>(&self, id: Id, ttl: Duration, f: F)
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Value> + Send + 'static,
+    {
+        let mut pb = self.0.lock();
+        if pb.by_id.contains_key(&id) {
+            let compute: ComputeFn = Arc::new(move || Box::pin(f()));
+            let os = OnSubscribe { compute, ttl, computed: None, waiting: None };
+            pb.on_subscribe.insert(id, os);
+        }
+    }
+
+    /// Remove the hook set by `on_subscribe_compute`, the value of
+    /// `id` stays what it was last computed to be.
+    pub fn stop_on_subscribe_compute(&self, id: Id) {
+        self.0.lock().on_subscribe.remove(&id);
+    }
 }
 
 async fn publish_loop(
@@ -1445,6 +2642,16 @@ async fn publish_loop(
                     mem::replace(&mut pb.to_unpublish_default, TOUPUB.take());
                 to_unsubscribe = mem::replace(&mut pb.to_unsubscribe, TOUSUB.take());
                 pb.publish_triggered = false;
+                // remember what is published so it can be moved by rebind
+                pb.registered.extend(to_publish.iter().map(|(p, f)| (p.clone(), *f)));
+                pb.registered_default
+                    .extend(to_publish_default.iter().map(|(p, f)| (p.clone(), *f)));
+                for p in to_unpublish.iter() {
+                    pb.registered.remove(p);
+                }
+                for p in to_unpublish_default.iter() {
+                    pb.registered_default.remove(p);
+                }
                 pb.resolver.clone()
             };
             if to_publish.len() > 0 {
@@ -1475,7 +2682,7 @@ async fn publish_loop(
                 let mut usubs = RAWUNSUBS.take();
                 for (id, subs) in to_unsubscribe.drain() {
                     for cl in subs.iter() {
-                        usubs.push((*cl, id));
+                        usubs.push((*cl, id, UnsubscribeReason::Unpublished));
                     }
                 }
                 let mut batch = publisher.start_batch();
@@ -1,6 +1,7 @@
 use super::{
-    ClId, Client, Event, PublisherInner, PublisherWeak, SendResult, Update, WriteRequest,
-    BATCHES,
+    dedup::{DedupKey, Seen},
+    ClId, Client, DefaultRequest, Event, PublisherInner, PublisherWeak, SendResult,
+    Update, WriteRequest, BATCHES,
 };
 use crate::{
     channel::{self, Channel, K5CtxWrap, ReadChannel, WriteChannel},
@@ -10,12 +11,16 @@ use crate::{
     pool::Pooled,
     protocol::{
         self,
-        publisher::{self, Id},
-        value::Value,
+        glob::{Glob, GlobSet},
+        publisher::{self, Id, UnsubscribeReason, ValPermissions, WriteKey},
+        value::{ErrorInfo, Value},
     },
     resolver_client::DesiredAuth,
-    resolver_server::{auth::Permissions, krb5_authentication},
-    tls,
+    resolver_server::{
+        auth::{Permissions, SUBTREE},
+        krb5_authentication,
+    },
+    shm, tls,
     utils::{self, BatchItem, Batched, ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
@@ -39,6 +44,7 @@ use std::{
     collections::{hash_map::Entry, BTreeSet, Bound, HashMap, HashSet},
     convert::From,
     default::Default,
+    io,
     iter::{self, FromIterator},
     mem,
     net::SocketAddr,
@@ -52,16 +58,21 @@ use tokio::{
 };
 
 const MAX_DEFERRED: usize = 1000000;
+// the path, the permissions, and whether an on subscribe compute
+// hook may still run for it
+type DeferredSub = (Path, Permissions, bool);
 type DeferredSubs =
-    Batched<SelectAll<Box<dyn Stream<Item = (Path, Permissions)> + Send + Sync + Unpin>>>;
+    Batched<SelectAll<Box<dyn Stream<Item = DeferredSub> + Send + Sync + Unpin>>>;
 
 fn subscribe(
     t: &mut PublisherInner,
+    publisher: &PublisherWeak,
     con: &mut WriteChannel,
     client: ClId,
     path: Path,
     permissions: Permissions,
     deferred_subs: &mut DeferredSubs,
+    compute: bool,
 ) -> Result<()> {
     match t.by_path.get(&path) {
         None => {
@@ -71,14 +82,32 @@ fn subscribe(
             ));
             loop {
                 match r.next_back() {
-                    Some((base, chan))
+                    Some((base, dp))
                         if path.starts_with(base.as_ref())
                             && deferred_subs.inner().len() < MAX_DEFERRED =>
                     {
+                        let req = DefaultRequest {
+                            path: path.clone(),
+                            client,
+                            user: t.clients.get(&client).and_then(|c| c.user.clone()),
+                        };
                         let (tx, rx) = oneshot::channel();
-                        if let Ok(()) = chan.unbounded_send((path.clone(), tx)) {
+                        let sent = match &dp.veto {
+                            None => dp.chan.unbounded_send((req, tx)).is_ok(),
+                            Some(_) if dp.chan.is_closed() => false,
+                            Some(veto) => {
+                                let (veto, chan) = (veto.clone(), dp.chan.clone());
+                                task::spawn(async move {
+                                    if veto(req.clone()).await {
+                                        let _ = chan.unbounded_send((req, tx));
+                                    }
+                                });
+                                true
+                            }
+                        };
+                        if sent {
                             let path = path.clone();
-                            let s = rx.map(move |_| (path, permissions));
+                            let s = rx.map(move |_| (path, permissions, true));
                             deferred_subs.inner_mut().push(Box::new(s.into_stream()));
                             break;
                         }
@@ -92,38 +121,156 @@ fn subscribe(
         }
         Some(id) => {
             let id = *id;
-            if let Some(ut) = t.by_id.get_mut(&id) {
-                if let Some(cl) = t.clients.get_mut(&client) {
-                    cl.subscribed.insert(id, permissions);
-                }
-                let subs = BTreeSet::from_iter(
-                    iter::once(client).chain(ut.subscribed.iter().copied()),
-                );
-                match t.hc_subscribed.entry(subs) {
-                    Entry::Occupied(e) => {
-                        ut.subscribed = Arc::clone(e.get());
-                    }
-                    Entry::Vacant(e) => {
-                        let mut s = HashSet::clone(&ut.subscribed);
-                        s.insert(client);
-                        ut.subscribed = Arc::new(s);
-                        e.insert(Arc::clone(&ut.subscribed));
-                    }
-                }
-                let m = publisher::From::Subscribed(path, id, ut.current.clone());
-                con.queue_send(&m)?;
-                if let Some(waiters) = t.wait_clients.remove(&id) {
-                    for tx in waiters {
-                        let _ = tx.send(());
-                    }
+            if compute && deferred_subs.inner().len() < MAX_DEFERRED {
+                if let Some(rx) = t.compute_on_subscribe(publisher, id) {
+                    let s = rx.map(move |_| (path, permissions, false));
+                    deferred_subs.inner_mut().push(Box::new(s.into_stream()));
+                    return Ok(());
                 }
-                t.send_event(Event::Subscribe(id, client));
+            }
+            if let Some(current) = add_subscriber(t, client, id, permissions) {
+                let perms = Some(val_permissions(t, id, permissions));
+                let m = publisher::From::Subscribed(path, id, current, perms);
+                queue_send(con, error_info(t, client), m)?;
             }
         }
     }
     Ok(())
 }
 
+// add `client` to the subscribers of `id`, returning the current
+// value, or None if `id` isn't published
+fn add_subscriber(
+    t: &mut PublisherInner,
+    client: ClId,
+    id: Id,
+    permissions: Permissions,
+) -> Option<Value> {
+    let ut = t.by_id.get_mut(&id)?;
+    if let Some(cl) = t.clients.get_mut(&client) {
+        cl.subscribed.insert(id, permissions);
+        cl.diverged.remove(&id);
+    }
+    let subs =
+        BTreeSet::from_iter(iter::once(client).chain(ut.subscribed.iter().copied()));
+    match t.hc_subscribed.entry(subs) {
+        Entry::Occupied(e) => {
+            ut.subscribed = Arc::clone(e.get());
+        }
+        Entry::Vacant(e) => {
+            let mut s = HashSet::clone(&ut.subscribed);
+            s.insert(client);
+            ut.subscribed = Arc::new(s);
+            e.insert(Arc::clone(&ut.subscribed));
+        }
+    }
+    let current = ut.current.clone();
+    if let Some(waiters) = t.wait_clients.remove(&id) {
+        for tx in waiters {
+            let _ = tx.send(());
+        }
+    }
+    t.send_event(Event::Subscribe(id, client));
+    Some(current)
+}
+
+// what a subscriber with `permissions` may do with `id`
+fn val_permissions(
+    t: &PublisherInner,
+    id: Id,
+    permissions: Permissions,
+) -> ValPermissions {
+    let writable = match t.on_write.get(&id) {
+        None => false,
+        Some(ow) => ow.iter().any(|(_, c)| !c.is_closed()),
+    };
+    ValPermissions {
+        read: true,
+        write: writable && permissions.contains(Permissions::WRITE),
+    }
+}
+
+// the most values in one SubscribedMany message
+const MAX_SUBSCRIBED_MANY: usize = 10_000;
+
+// subscribe `client` to every value published under `base` that
+// matches `glob`. Aliases are included, but each value is only
+// subscribed once. Default publishers and on subscribe computations
+// are not consulted.
+fn subscribe_many(
+    t: &mut PublisherInner,
+    con: &mut WriteChannel,
+    client: ClId,
+    base: Path,
+    glob: Chars,
+    permissions: Permissions,
+) -> Result<()> {
+    let pat = Glob::new(Chars::from(String::from(&*base.append(&*glob)))).and_then(|g| {
+        let scope = *g.scope();
+        Ok((scope, GlobSet::new(false, iter::once(g))?))
+    });
+    let matching = match pat {
+        Err(e) => {
+            debug!("subscribe many of {} with invalid glob {}: {}", base, glob, e);
+            vec![]
+        }
+        Ok((scope, pat)) => {
+            let mut seen = HashSet::new();
+            let mut matching = t
+                .by_path
+                .iter()
+                .filter(|(path, id)| {
+                    Path::is_parent(&base, path)
+                        && scope.contains(Path::levels(path))
+                        && pat.is_match(path)
+                        && seen.insert(**id)
+                })
+                .map(|(path, id)| (path.clone(), *id))
+                .collect::<Vec<_>>();
+            matching.sort();
+            matching
+        }
+    };
+    let error_info = error_info(t, client);
+    let mut chunks = matching.chunks(MAX_SUBSCRIBED_MANY).peekable();
+    if chunks.peek().is_none() {
+        con.queue_send(&publisher::From::SubscribedMany(base, vec![], true))?;
+    }
+    while let Some(chunk) = chunks.next() {
+        let subs = chunk
+            .iter()
+            .filter_map(|(path, id)| {
+                add_subscriber(t, client, *id, permissions)
+                    .map(|current| (path.clone(), *id, current))
+            })
+            .collect::<Vec<_>>();
+        let done = chunks.peek().is_none();
+        let m = publisher::From::SubscribedMany(base.clone(), subs, done);
+        queue_send(con, error_info, m)?;
+    }
+    Ok(())
+}
+
+// true if `client` can decode `Value::ErrorInfo`
+fn error_info(t: &PublisherInner, client: ClId) -> bool {
+    t.clients.get(&client).map(|c| c.error_info).unwrap_or(false)
+}
+
+// queue `m` to a client, if the client can't decode
+// `Value::ErrorInfo` any it carries are sent as `Value::Error`.
+fn queue_send(
+    con: &mut WriteChannel,
+    error_info: bool,
+    m: publisher::From,
+) -> Result<()> {
+    if error_info {
+        con.queue_send(&m)?
+    } else {
+        con.queue_send(&m.without_error_info())?
+    }
+    Ok(())
+}
+
 fn unsubscribe(t: &mut PublisherInner, client: ClId, id: Id) {
     if let Some(ut) = t.by_id.get_mut(&id) {
         let subs =
@@ -142,6 +289,7 @@ fn unsubscribe(t: &mut PublisherInner, client: ClId, id: Id) {
         let nsubs = ut.subscribed.len();
         if let Some(cl) = t.clients.get_mut(&client) {
             cl.subscribed.remove(&id);
+            cl.diverged.remove(&id);
         }
         t.send_event(Event::Unsubscribe(id, client));
         if nsubs == 0 && t.destroy_on_idle.remove(&id) {
@@ -155,7 +303,7 @@ fn write(
     con: &mut WriteChannel,
     client: ClId,
     gc_on_write: &mut Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
-    wait_write_res: &mut Vec<(Id, oneshot::Receiver<Value>)>,
+    wait_write_res: &mut Vec<(Id, Option<DedupKey>, oneshot::Receiver<Value>)>,
     write_batches: &mut FxHashMap<
         ChanId,
         (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>),
@@ -163,15 +311,16 @@ fn write(
     id: Id,
     v: Value,
     r: bool,
+    key: Option<WriteKey>,
 ) -> Result<()> {
     macro_rules! or_qwe {
-        ($v:expr, $m:expr) => {
+        ($v:expr, $code:expr, $m:expr) => {
             match $v {
                 Some(v) => v,
                 None => {
                     if r {
-                        let m = Value::Error(Chars::from($m));
-                        con.queue_send(&From::WriteResult(id, m))?
+                        let m = Value::coded_err($code, $m);
+                        queue_send(con, error_info, From::WriteResult(id, m, false))?
                     }
                     return Ok(());
                 }
@@ -179,12 +328,25 @@ fn write(
         };
     }
     use protocol::publisher::From;
-    let cl = or_qwe!(t.clients.get(&client), "cannot write to unsubscribed value");
-    let perms = or_qwe!(cl.subscribed.get(&id), "cannot write to unsubscribed value");
+    let error_info = error_info(t, client);
+    let cl = or_qwe!(
+        t.clients.get(&client),
+        ErrorInfo::NOT_SUBSCRIBED,
+        "cannot write to unsubscribed value"
+    );
+    let perms = or_qwe!(
+        cl.subscribed.get(&id),
+        ErrorInfo::NOT_SUBSCRIBED,
+        "cannot write to unsubscribed value"
+    );
     if !perms.contains(Permissions::WRITE) {
-        or_qwe!(None, "write permission denied")
+        or_qwe!(None, ErrorInfo::PERMISSION_DENIED, "write permission denied")
     }
-    let ow = or_qwe!(t.on_write.get_mut(&id), "writes not accepted");
+    let ow = or_qwe!(
+        t.on_write.get_mut(&id),
+        ErrorInfo::WRITES_NOT_ACCEPTED,
+        "writes not accepted"
+    );
     ow.retain(|(_, c)| {
         if c.is_closed() {
             gc_on_write.push(ChanWrap(c.clone()));
@@ -194,13 +356,24 @@ fn write(
         }
     });
     if ow.len() == 0 {
-        or_qwe!(None, "writes not accepted");
+        or_qwe!(None, ErrorInfo::WRITES_NOT_ACCEPTED, "writes not accepted");
+    }
+    let key = key.map(|key| (cl.user.as_ref().map(|u| u.name.clone()), key));
+    if let Some(key) = &key {
+        if let Seen::Duplicate(res) = t.dedup.check(key.clone()) {
+            debug!("suppressed duplicate write {:?}", key);
+            if r {
+                let res = res.unwrap_or(Value::Ok);
+                queue_send(con, error_info, From::WriteResult(id, res, true))?
+            }
+            return Ok(());
+        }
     }
     let send_result = if !r {
         None
     } else {
         let (send_result, wait) = SendResult::new();
-        wait_write_res.push((id, wait));
+        wait_write_res.push((id, key, wait));
         Some(send_result)
     };
     for (cid, ch) in ow.iter() {
@@ -228,15 +401,18 @@ fn check_token(
     secret: u128,
     timestamp: u64,
     permissions: u32,
+    subtree: bool,
     path: &Path,
 ) -> Result<(bool, Permissions)> {
     if token.len() < mem::size_of::<u64>() {
         bail!("error, token too short");
     }
+    // a subscribe many must present the subtree token
+    let signed = if subtree { permissions | SUBTREE } else { permissions };
     let expected = utils::make_sha3_token([
         &secret.to_be_bytes(),
         &timestamp.to_be_bytes()[..],
-        &permissions.to_be_bytes(),
+        &signed.to_be_bytes(),
         path.as_bytes(),
     ]);
     let permissions = Permissions::from_bits(permissions)
@@ -269,17 +445,20 @@ struct ClientCtx {
     publisher: PublisherWeak,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
     batch: Vec<publisher::To>,
+    unsubscribe_reasons: FxHashMap<Id, UnsubscribeReason>,
     write_batches:
         FxHashMap<ChanId, (Pooled<Vec<WriteRequest>>, Sender<Pooled<Vec<WriteRequest>>>)>,
     blocked_writes: FuturesUnordered<BlockedWriteFut>,
     flushing_updates: bool,
     flush_timeout: Option<Duration>,
     deferred_subs: DeferredSubs,
-    deferred_subs_batch: Vec<(Path, Permissions)>,
-    wait_write_res: Vec<(Id, oneshot::Receiver<Value>)>,
+    deferred_subs_batch: Vec<DeferredSub>,
+    wait_write_res: Vec<(Id, Option<DedupKey>, oneshot::Receiver<Value>)>,
     gc_on_write: Vec<ChanWrap<Pooled<Vec<WriteRequest>>>>,
     msg_sent: bool,
     tls_ctx: Option<tls::CachedAcceptor>,
+    // the client can decode `Value::ErrorInfo`
+    error_info: bool,
 }
 
 impl ClientCtx {
@@ -299,6 +478,7 @@ impl ClientCtx {
             publisher,
             secrets,
             batch: Vec::new(),
+            unsubscribe_reasons: HashMap::default(),
             write_batches: HashMap::default(),
             blocked_writes: FuturesUnordered::new(),
             flushing_updates: false,
@@ -309,6 +489,7 @@ impl ClientCtx {
             gc_on_write: Vec::new(),
             msg_sent: false,
             tls_ctx,
+            error_info: false,
         }
     }
 
@@ -354,21 +535,25 @@ impl ClientCtx {
         let hello: Hello = channel::read_raw(&mut con).await?;
         debug!("hello_client received {:?}", hello);
         match hello {
-            Hello::Anonymous => {
-                channel::write_raw(&mut con, &Hello::Anonymous).await?;
+            Hello::Anonymous(offer) => {
+                let seg = shm::accept(&con, offer);
+                let reply = Hello::Anonymous(seg.as_ref().map(|s| s.offer()));
+                channel::write_raw(&mut con, &reply).await?;
                 self.client_arrived();
-                Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                Ok(shm::channel::<ServerCtx>(con, seg))
             }
-            Hello::Local(uifo) => {
-                channel::write_raw(&mut con, &Hello::Local(None)).await?;
+            Hello::Local(uifo, offer) => {
+                let seg = shm::accept(&con, offer);
+                let reply = Hello::Local(None, seg.as_ref().map(|s| s.offer()));
+                channel::write_raw(&mut con, &reply).await?;
                 self.set_user(uifo);
                 self.client_arrived();
-                Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
+                Ok(shm::channel::<ServerCtx>(con, seg))
             }
             Hello::Krb5(uifo) => match &self.desired_auth {
                 DesiredAuth::Anonymous | DesiredAuth::Tls { .. } => bail!(NO),
                 DesiredAuth::Local => {
-                    channel::write_raw(&mut con, &Hello::Local(None)).await?;
+                    channel::write_raw(&mut con, &Hello::Local(None, None)).await?;
                     self.set_user(uifo);
                     self.client_arrived();
                     Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
@@ -386,7 +571,7 @@ impl ClientCtx {
             Hello::Tls(uifo) => match &self.desired_auth {
                 DesiredAuth::Anonymous | DesiredAuth::Krb5 { .. } => bail!(NO),
                 DesiredAuth::Local => {
-                    channel::write_raw(&mut con, &Hello::Local(None)).await?;
+                    channel::write_raw(&mut con, &Hello::Local(None, None)).await?;
                     self.set_user(uifo);
                     self.client_arrived();
                     Ok(Channel::new::<ServerCtx, TcpStream>(None, con))
@@ -436,7 +621,7 @@ impl ClientCtx {
     fn handle_deferred_sub(
         &mut self,
         con: &mut WriteChannel,
-        s: Option<BatchItem<(Path, Permissions)>>,
+        s: Option<BatchItem<DeferredSub>>,
     ) -> Result<()> {
         match s {
             None => (),
@@ -449,18 +634,20 @@ impl ClientCtx {
                 }
                 Some(t) => {
                     let mut pb = t.0.lock();
-                    for (path, perms) in self.deferred_subs_batch.drain(..) {
+                    for (path, perms, compute) in self.deferred_subs_batch.drain(..) {
                         if !pb.by_path.contains_key(path.as_ref()) {
                             let m = publisher::From::NoSuchValue(path);
                             con.queue_send(&m)?
                         } else {
                             subscribe(
                                 &mut *pb,
+                                &self.publisher,
                                 con,
                                 self.client,
                                 path,
                                 perms,
                                 &mut self.deferred_subs,
+                                compute,
                             )?
                         }
                     }
@@ -479,23 +666,33 @@ impl ClientCtx {
         let mut gc = false;
         for msg in self.batch.drain(..) {
             match msg {
-                Subscribe { path, resolver, timestamp, permissions, token } => {
+                Subscribe {
+                    path,
+                    resolver,
+                    timestamp,
+                    permissions,
+                    token,
+                    patches,
+                    deltas,
+                    glob,
+                    error_info,
+                } => {
                     gc = true;
-                    match self.desired_auth {
-                        DesiredAuth::Anonymous => subscribe(
-                            &mut *pb,
-                            con,
-                            self.client,
-                            path,
-                            Permissions::all(),
-                            &mut self.deferred_subs,
-                        )?,
+                    self.error_info = error_info;
+                    if let Some(cl) = pb.clients.get_mut(&self.client) {
+                        cl.patches = patches;
+                        cl.deltas = deltas;
+                        cl.error_info = error_info;
+                    }
+                    let permissions = match self.desired_auth {
+                        DesiredAuth::Anonymous => Permissions::all(),
                         DesiredAuth::Krb5 { .. }
                         | DesiredAuth::Local
                         | DesiredAuth::Tls { .. } => match secrets.get(&resolver) {
                             None => {
                                 debug!("denied, no stored secret for {}", resolver);
-                                con.queue_send(&From::Denied(path))?
+                                con.queue_send(&From::Denied(path))?;
+                                continue;
                             }
                             Some(secret) => {
                                 let (valid, permissions) = check_token(
@@ -504,26 +701,40 @@ impl ClientCtx {
                                     *secret,
                                     timestamp,
                                     permissions,
+                                    glob.is_some(),
                                     &path,
                                 )?;
                                 if !valid {
                                     debug!("subscribe permission denied");
-                                    con.queue_send(&From::Denied(path))?
-                                } else {
-                                    subscribe(
-                                        &mut *pb,
-                                        con,
-                                        self.client,
-                                        path,
-                                        permissions,
-                                        &mut self.deferred_subs,
-                                    )?
+                                    con.queue_send(&From::Denied(path))?;
+                                    continue;
                                 }
+                                permissions
                             }
                         },
+                    };
+                    match glob {
+                        None => subscribe(
+                            &mut *pb,
+                            &self.publisher,
+                            con,
+                            self.client,
+                            path,
+                            permissions,
+                            &mut self.deferred_subs,
+                            true,
+                        )?,
+                        Some(glob) => subscribe_many(
+                            &mut *pb,
+                            con,
+                            self.client,
+                            path,
+                            glob,
+                            permissions,
+                        )?,
                     }
                 }
-                Write(id, r, v) => write(
+                Write(id, r, v, key) => write(
                     &mut *pb,
                     con,
                     self.client,
@@ -533,11 +744,13 @@ impl ClientCtx {
                     id,
                     v,
                     r,
+                    key,
                 )?,
                 Unsubscribe(id) => {
                     gc = true;
                     unsubscribe(&mut *pb, self.client, id);
-                    con.queue_send(&From::Unsubscribed(id))?;
+                    let reason = self.unsubscribe_reasons.remove(&id).unwrap_or_default();
+                    con.queue_send(&From::Unsubscribed(id, reason))?;
                 }
             }
         }
@@ -562,14 +775,26 @@ impl ClientCtx {
                     }) as BlockedWriteFut
                 },
             ));
-            self.blocked_writes.extend(self.wait_write_res.drain(..).map(|(id, rx)| {
-                Box::pin(async move {
-                    BlockedWrite::Reply(match rx.await {
-                        Ok(v) => From::WriteResult(id, v),
-                        Err(_) => From::WriteResult(id, Value::Ok),
-                    })
-                }) as BlockedWriteFut
-            }));
+            let publisher = &self.publisher;
+            let error_info = self.error_info;
+            self.blocked_writes.extend(self.wait_write_res.drain(..).map(
+                |(id, key, rx)| {
+                    let publisher = publisher.clone();
+                    Box::pin(async move {
+                        let v = rx.await.unwrap_or(Value::Ok);
+                        // remember the result to answer duplicates with
+                        if let (Some(key), Some(pb)) = (key, publisher.upgrade()) {
+                            pb.0.lock().dedup.complete(&key, v.clone())
+                        }
+                        let m = From::WriteResult(id, v, false);
+                        if error_info {
+                            BlockedWrite::Reply(m)
+                        } else {
+                            BlockedWrite::Reply(m.without_error_info())
+                        }
+                    }) as BlockedWriteFut
+                },
+            ));
         }
         Ok(())
     }
@@ -581,10 +806,11 @@ impl ClientCtx {
     ) -> Result<()> {
         use publisher::To;
         for m in up.updates.drain(..) {
-            con.queue_send(&m)?
+            queue_send(con, self.error_info, m)?
         }
         if let Some(usubs) = &mut up.unsubscribes {
-            for id in usubs.drain(..) {
+            for (id, reason) in usubs.drain(..) {
+                self.unsubscribe_reasons.insert(id, reason);
                 self.batch.push(To::Unsubscribe(id));
             }
         }
@@ -647,6 +873,12 @@ impl ClientCtx {
         let mut hb = time::interval(HB);
         let (mut read_con, mut write_con) =
             time::timeout(HELLO_TIMEOUT, self.hello(con)).await??.split();
+        #[cfg(feature = "fault_injection")]
+        if let Some(pb) = self.publisher.upgrade() {
+            if let Some(faults) = &pb.0.lock().faults {
+                faults.install(&mut read_con, &mut write_con)
+            }
+        }
         loop {
             select_biased! {
                 r = flush(&mut write_con, self.flush_timeout).fuse() => {
@@ -685,9 +917,24 @@ impl ClientCtx {
     }
 }
 
+// accept from either listener, accept is cancel safe
+async fn accept(
+    serv: &TcpListener,
+    alt: Option<&TcpListener>,
+) -> io::Result<(TcpStream, SocketAddr)> {
+    match alt {
+        None => serv.accept().await,
+        Some(alt) => select_biased! {
+            r = serv.accept().fuse() => r,
+            r = alt.accept().fuse() => r,
+        },
+    }
+}
+
 pub(super) async fn start(
     t: PublisherWeak,
     serv: TcpListener,
+    alt: Option<TcpListener>,
     stop: oneshot::Receiver<()>,
     desired_auth: DesiredAuth,
     tls_ctx: Option<tls::CachedAcceptor>,
@@ -697,7 +944,7 @@ pub(super) async fn start(
     loop {
         select_biased! {
             _ = stop => break,
-            cl = serv.accept().fuse() => match cl {
+            cl = accept(&serv, alt.as_ref()).fuse() => match cl {
                 Err(e) => info!("accept error {}", e),
                 Ok((s, addr)) => {
                     debug!("accepted client {:?}", addr);
@@ -708,40 +955,58 @@ pub(super) async fn start(
                         Some(t) => t
                     };
                     let mut pb = t.0.lock();
+                    let from_ip = pb.clients_by_ip.get(&addr.ip()).copied().unwrap_or(0);
+                    if pb.clients.len() >= max_clients
+                        || !pb.accept.acceptable(addr, from_ip)
+                    {
+                        debug!("rejected client {:?}", addr);
+                        continue;
+                    }
                     let secrets = pb.resolver.secrets();
                     let (tx, rx) = channel(3);
                     try_cf!("nodelay", continue, s.set_nodelay(true));
-                    if pb.clients.len() < max_clients {
-                        pb.clients.insert(clid, Client {
-                            msg_queue: tx,
-                            subscribed: HashMap::default(),
-                            user: None,
-                        });
-                        let desired_auth = desired_auth.clone();
-                        let tls_ctx = tls_ctx.clone();
-                        task::spawn(async move {
-                            let ctx = ClientCtx::new(
-                                clid,
-                                secrets,
-                                t_weak.clone(),
-                                desired_auth,
-                                tls_ctx,
-                            );
-                            let r = ctx.run(s, rx).await;
-                            info!("accept_loop client shutdown {:?}", r);
-                            if let Some(t) = t_weak.upgrade() {
-                                let mut pb = t.0.lock();
-                                if let Some(cl) = pb.clients.remove(&clid) {
-                                    for (id, _) in cl.subscribed {
-                                        unsubscribe(&mut *pb, clid, id);
-                                    }
-                                    pb.hc_subscribed.retain(|_, v| {
-                                        Arc::get_mut(v).is_none()
-                                    });
+                    *pb.clients_by_ip.entry(addr.ip()).or_insert(0) += 1;
+                    pb.clients.insert(clid, Client {
+                        msg_queue: tx,
+                        subscribed: HashMap::default(),
+                        user: None,
+                        patches: false,
+                        deltas: false,
+                        error_info: false,
+                        diverged: HashSet::default(),
+                    });
+                    let desired_auth = desired_auth.clone();
+                    let tls_ctx = tls_ctx.clone();
+                    task::spawn(async move {
+                        let ctx = ClientCtx::new(
+                            clid,
+                            secrets,
+                            t_weak.clone(),
+                            desired_auth,
+                            tls_ctx,
+                        );
+                        let r = ctx.run(s, rx).await;
+                        info!("accept_loop client shutdown {:?}", r);
+                        if let Some(t) = t_weak.upgrade() {
+                            let mut pb = t.0.lock();
+                            if let Entry::Occupied(mut e) =
+                                pb.clients_by_ip.entry(addr.ip())
+                            {
+                                *e.get_mut() -= 1;
+                                if *e.get() == 0 {
+                                    e.remove();
                                 }
                             }
-                        });
-                    }
+                            if let Some(cl) = pb.clients.remove(&clid) {
+                                for (id, _) in cl.subscribed {
+                                    unsubscribe(&mut *pb, clid, id);
+                                }
+                                pb.hc_subscribed.retain(|_, v| {
+                                    Arc::get_mut(v).is_none()
+                                });
+                            }
+                        }
+                    });
                 }
             },
         }
@@ -0,0 +1,147 @@
+use super::{
+    DefaultHandle, PublishFlags, Publisher, PublisherBuilder, UpdateBatch, Val, Value,
+};
+use crate::path::Path;
+use anyhow::Result;
+use futures::future;
+use parking_lot::Mutex;
+use std::{collections::BTreeMap, convert::TryInto, sync::Arc, time::Duration};
+
+/// Host several tenants in one process, each presenting its own
+/// identity for its own subtree of the namespace. Every tenant is a
+/// full `Publisher`, with its own listener and its own connection to
+/// the resolver, built from the `PublisherBuilder` passed to
+/// `add_tenant`, so e.g. `/tenant-a` can be published with tenant
+/// A's TLS identity and `/tenant-b` with tenant B's, and the resolver
+/// checks each against the permissions of its own identity.
+///
+/// Paths are routed to the tenant with the longest base that is a
+/// parent of the path. TenantPublisher is internally wrapped in an
+/// Arc, so cloning it is virtually free.
+#[derive(Clone)]
+pub struct TenantPublisher(Arc<Mutex<BTreeMap<Path, Publisher>>>);
+
+impl Default for TenantPublisher {
+    fn default() -> Self {
+        TenantPublisher(Arc::new(Mutex::new(BTreeMap::new())))
+    }
+}
+
+impl TenantPublisher {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build a publisher from `builder` and make it responsible for
+    /// publishing everything under `base`. It is an error if `base`
+    /// already has a tenant.
+    pub async fn add_tenant(
+        &self,
+        base: Path,
+        builder: &mut PublisherBuilder,
+    ) -> Result<Publisher> {
+        if self.0.lock().contains_key(&base) {
+            bail!("{} already has a tenant", base)
+        }
+        let publisher = builder.build().await?;
+        let mut tenants = self.0.lock();
+        if tenants.contains_key(&base) {
+            bail!("{} already has a tenant", base)
+        }
+        tenants.insert(base, publisher.clone());
+        Ok(publisher)
+    }
+
+    /// Remove the tenant of `base` and shut down its publisher,
+    /// unpublishing everything it published.
+    pub async fn remove_tenant(&self, base: &Path) {
+        let publisher = self.0.lock().remove(base);
+        if let Some(publisher) = publisher {
+            publisher.shutdown().await
+        }
+    }
+
+    /// Return the publisher responsible for `path`, if any
+    pub fn tenant(&self, path: &Path) -> Option<Publisher> {
+        let tenants = self.0.lock();
+        Path::dirnames(path).filter_map(|base| tenants.get(base)).last().cloned()
+    }
+
+    /// Return the base and publisher of every tenant
+    pub fn tenants(&self) -> Vec<(Path, Publisher)> {
+        self.0.lock().iter().map(|(b, p)| (b.clone(), p.clone())).collect()
+    }
+
+    fn route(&self, path: &Path) -> Result<Publisher> {
+        self.tenant(path).ok_or_else(|| anyhow!("no tenant publishes {}", path))
+    }
+
+    /// Publish `path` with the tenant responsible for it, see
+    /// `Publisher::publish_with_flags`. The returned `Val` must be
+    /// updated with a batch from the same tenant, see `start_batch`.
+    pub fn publish_with_flags<T>(
+        &self,
+        flags: PublishFlags,
+        path: Path,
+        init: T,
+    ) -> Result<Val>
+    where
+        T: TryInto<Value>,
+        <T as TryInto<Value>>::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.route(&path)?.publish_with_flags(flags, path, init)
+    }
+
+    /// Publish `path` with the tenant responsible for it, see
+    /// `Publisher::publish`.
+    pub fn publish<T>(&self, path: Path, init: T) -> Result<Val>
+    where
+        T: TryInto<Value>,
+        <T as TryInto<Value>>::Error: std::error::Error + Send + Sync + 'static,
+    {
+        self.publish_with_flags(PublishFlags::empty(), path, init)
+    }
+
+    /// Publish a default under `base` with the tenant responsible for
+    /// it, see `Publisher::publish_default`.
+    pub fn publish_default(&self, base: Path) -> Result<DefaultHandle> {
+        self.route(&base)?.publish_default(base)
+    }
+
+    /// Start a batch of updates spanning all tenants
+    pub fn start_batch(&self) -> TenantBatch {
+        let batches = self
+            .0
+            .lock()
+            .iter()
+            .map(|(base, publisher)| (base.clone(), publisher.start_batch()))
+            .collect();
+        TenantBatch(batches)
+    }
+
+    /// Shutdown every tenant, see `Publisher::shutdown`
+    pub async fn shutdown(self) {
+        let tenants = std::mem::take(&mut *self.0.lock());
+        future::join_all(tenants.into_values().map(|p| p.shutdown())).await;
+    }
+}
+
+/// A batch of updates to values published by a `TenantPublisher`,
+/// holding one `UpdateBatch` per tenant.
+#[must_use = "update batches do nothing unless committed"]
+pub struct TenantBatch(BTreeMap<Path, UpdateBatch>);
+
+impl TenantBatch {
+    /// Return the batch of the tenant responsible for `path`. Updates
+    /// to a `Val` must go in the batch of the tenant that published
+    /// it.
+    pub fn batch(&mut self, path: &Path) -> Option<&mut UpdateBatch> {
+        let base = Path::dirnames(path).filter(|b| self.0.contains_key(*b)).last()?;
+        self.0.get_mut(base)
+    }
+
+    /// Commit the batch of every tenant, see `UpdateBatch::commit`
+    pub async fn commit(self, timeout: Option<Duration>) {
+        future::join_all(self.0.into_values().map(|b| b.commit(timeout))).await;
+    }
+}
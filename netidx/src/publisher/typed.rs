@@ -0,0 +1,128 @@
+use super::{ClId, Id, PublisherWeak, SendResult, UpdateBatch, Val, WriteRequest};
+use crate::{
+    path::Path,
+    pool::Pooled,
+    protocol::value::{ErrorInfo, FromValue, Value},
+};
+use futures::{
+    channel::mpsc::{self, Sender},
+    prelude::*,
+};
+use std::{any::type_name, marker::PhantomData};
+use tokio::task;
+
+/// A write to a `TypedVal` that was successfully converted to
+/// `T`. Otherwise the same as `WriteRequest`.
+#[derive(Debug)]
+pub struct TypedWriteRequest<T> {
+    /// the Id of the value being written
+    pub id: Id,
+    /// the path of the value being written
+    pub path: Path,
+    /// the unique id of the client requesting the write
+    pub client: ClId,
+    /// the value being written
+    pub value: T,
+    pub send_result: Option<SendResult>,
+}
+
+/// A published value of type `T`. Updates must be of type `T`, and
+/// writes are converted to `T` before they are delivered. See
+/// `Publisher::publish_typed`. When it is dropped the value will be
+/// unpublished.
+pub struct TypedVal<T> {
+    val: Val,
+    publisher: PublisherWeak,
+    t: PhantomData<fn() -> T>,
+}
+
+impl<T: Into<Value> + FromValue> TypedVal<T> {
+    pub(super) fn new(val: Val, publisher: PublisherWeak) -> Self {
+        TypedVal { val, publisher, t: PhantomData }
+    }
+
+    /// Queue an update to the published value in the specified
+    /// batch, see `Val::update`.
+    pub fn update(&self, batch: &mut UpdateBatch, v: T) {
+        self.val.update(batch, v)
+    }
+
+    /// Queue an update only if `v` is different from the current
+    /// value, see `Val::update_changed`.
+    pub fn update_changed(&self, batch: &mut UpdateBatch, v: T) {
+        self.val.update_changed(batch, v)
+    }
+
+    /// Queue sending `v` ONLY to the specified subscriber, see
+    /// `Val::update_subscriber`.
+    pub fn update_subscriber(&self, batch: &mut UpdateBatch, dst: ClId, v: T) {
+        self.val.update_subscriber(batch, dst, v)
+    }
+
+    /// Return the current value, or `None` if the publisher is dead,
+    /// or the current value can't be converted to `T`.
+    pub fn current(&self) -> Option<T> {
+        let publisher = self.publisher.upgrade()?;
+        publisher.current(&self.val.id()).and_then(|v| T::from_value(v).ok())
+    }
+
+    /// Register `tx` to receive writes to this value. Writes that
+    /// can't be converted to `T` are rejected, if the writer asked
+    /// for a reply it will receive an `ErrorInfo::INVALID_ARGUMENT`
+    /// error. Writes that are converted are delivered to `tx`, just
+    /// as `Publisher::writes` would deliver them.
+    pub fn writes(&self, mut tx: Sender<Vec<TypedWriteRequest<T>>>)
+    where
+        T: Send + 'static,
+    {
+        let publisher = match self.publisher.upgrade() {
+            Some(publisher) => publisher,
+            None => return,
+        };
+        let (tx_raw, mut rx_raw) = mpsc::channel::<Pooled<Vec<WriteRequest>>>(3);
+        publisher.writes(self.val.id(), tx_raw);
+        task::spawn(async move {
+            while let Some(mut batch) = rx_raw.next().await {
+                let mut typed = Vec::with_capacity(batch.len());
+                for req in batch.drain(..) {
+                    match T::from_value(req.value) {
+                        Ok(value) => typed.push(TypedWriteRequest {
+                            id: req.id,
+                            path: req.path,
+                            client: req.client,
+                            value,
+                            send_result: req.send_result,
+                        }),
+                        Err(e) => {
+                            if let Some(reply) = req.send_result {
+                                let m = format!("expected {}, {}", type_name::<T>(), e);
+                                reply.send(Value::coded_err(
+                                    ErrorInfo::INVALID_ARGUMENT,
+                                    m,
+                                ))
+                            }
+                        }
+                    }
+                }
+                if !typed.is_empty() && tx.send(typed).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Get the unique `Id` of this value
+    pub fn id(&self) -> Id {
+        self.val.id()
+    }
+
+    /// Get a reference to the untyped `Val`
+    pub fn val(&self) -> &Val {
+        &self.val
+    }
+
+    /// Discard the type, returning the untyped `Val`
+    pub fn into_val(self) -> Val {
+        self.val
+    }
+}
@@ -0,0 +1,120 @@
+use fxhash::FxHashMap;
+use parking_lot::Mutex;
+use rand::{seq::SliceRandom, thread_rng};
+use std::{
+    net::SocketAddr,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+/// How long a server that failed is avoided if there are other
+/// choices
+const AVOID: Duration = Duration::from_secs(30);
+
+/// The weight of a new latency sample in the moving average
+const ALPHA: f64 = 0.2;
+
+/// The health of one resolver server as seen by this client. See
+/// `ResolverRead::server_health`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ServerHealth {
+    /// The address of the resolver server
+    pub addr: SocketAddr,
+    /// True if we are currently connected to the server
+    pub connected: bool,
+    /// The moving average of the round trip time of requests to
+    /// the server, or `None` if no request has completed yet
+    pub latency: Option<Duration>,
+    /// The number of requests that succeeded
+    pub requests: u64,
+    /// The number of connection attempts and requests that failed
+    pub errors: u64,
+    /// The number of failures since the last success
+    pub consecutive_errors: u32,
+    /// When the last failure happened
+    pub last_error: Option<Instant>,
+}
+
+impl ServerHealth {
+    fn new(addr: SocketAddr) -> Self {
+        ServerHealth {
+            addr,
+            connected: false,
+            latency: None,
+            requests: 0,
+            errors: 0,
+            consecutive_errors: 0,
+            last_error: None,
+        }
+    }
+
+    /// True if the server failed recently, and hasn't succeeded
+    /// since.
+    pub fn failing(&self) -> bool {
+        self.consecutive_errors > 0
+            && self.last_error.map(|t| t.elapsed() < AVOID).unwrap_or(false)
+    }
+
+    // servers are preferred in ascending order of rank. Latencies
+    // within the same power of 2 are considered equal, so that load
+    // is still spread over servers that are about as close.
+    fn rank(&self) -> (bool, u32) {
+        let latency = match self.latency {
+            None => 0,
+            Some(l) => u64::BITS - (l.as_micros() as u64).leading_zeros(),
+        };
+        (self.failing(), latency)
+    }
+}
+
+/// The health of every resolver server a client has talked to,
+/// shared by all the connections of a client.
+#[derive(Debug, Clone, Default)]
+pub(super) struct Health(Arc<Mutex<FxHashMap<SocketAddr, ServerHealth>>>);
+
+impl Health {
+    fn with<R>(&self, addr: SocketAddr, f: impl FnOnce(&mut ServerHealth) -> R) -> R {
+        f(self.0.lock().entry(addr).or_insert_with(|| ServerHealth::new(addr)))
+    }
+
+    pub(super) fn connected(&self, addr: SocketAddr) {
+        self.with(addr, |h| h.connected = true)
+    }
+
+    pub(super) fn success(&self, addr: SocketAddr, latency: Option<Duration>) {
+        self.with(addr, |h| {
+            h.requests += 1;
+            h.consecutive_errors = 0;
+            if let Some(l) = latency {
+                h.latency = Some(match h.latency {
+                    None => l,
+                    Some(avg) => avg.mul_f64(1. - ALPHA) + l.mul_f64(ALPHA),
+                });
+            }
+        })
+    }
+
+    pub(super) fn failure(&self, addr: SocketAddr) {
+        self.with(addr, |h| {
+            h.connected = false;
+            h.errors += 1;
+            h.consecutive_errors += 1;
+            h.last_error = Some(Instant::now());
+        })
+    }
+
+    /// Put `addrs` in the order they should be tried
+    pub(super) fn order<T>(&self, addrs: &mut [(SocketAddr, T)]) {
+        addrs.shuffle(&mut thread_rng());
+        let health = self.0.lock();
+        addrs.sort_by_key(|(addr, _)| {
+            health.get(addr).map(|h| h.rank()).unwrap_or((false, 0))
+        });
+    }
+
+    pub(super) fn servers(&self) -> Vec<ServerHealth> {
+        let mut servers = self.0.lock().values().cloned().collect::<Vec<_>>();
+        servers.sort_by_key(|h| h.addr);
+        servers
+    }
+}
@@ -1,4 +1,6 @@
 pub(crate) mod common;
+mod health;
+mod pinned;
 mod read_client;
 mod write_client;
 
@@ -10,7 +12,7 @@ use crate::{
     config::Config,
     pack::Z64,
     path::Path,
-    pool::{Pool, Pooled},
+    pool::{self, Pool, PoolConfig, Pooled},
     protocol::resolver::{
         FromRead, FromWrite, Publisher, PublisherId, Referral, ToRead, ToWrite,
     },
@@ -26,7 +28,10 @@ use common::{
 };
 use futures::future;
 use fxhash::FxHashMap;
+use health::Health;
+pub use health::ServerHealth;
 use parking_lot::{Mutex, RwLock};
+use pinned::Pinned;
 use read_client::ReadClient;
 use std::{
     collections::{
@@ -42,17 +47,21 @@ use std::{
     sync::Arc,
     time::Duration,
 };
-use tokio::time::Instant;
+use tokio::{
+    runtime::Handle,
+    time::{self, Instant},
+};
 use write_client::WriteClient;
 
 const MAX_REFERRALS: usize = 128;
 
 trait ToPath {
-    fn path(&self) -> Option<&Path>;
+    /// the path the message should be routed by
+    fn path(&self) -> Option<&str>;
 }
 
 impl ToPath for ToRead {
-    fn path(&self) -> Option<&Path> {
+    fn path(&self) -> Option<&str> {
         match self {
             ToRead::List(p) | ToRead::Table(p) | ToRead::Resolve(p) => Some(p),
             ToRead::ListMatching(_) | ToRead::GetChangeNr(_) => None,
@@ -61,7 +70,7 @@ impl ToPath for ToRead {
 }
 
 impl ToPath for ToWrite {
-    fn path(&self) -> Option<&Path> {
+    fn path(&self) -> Option<&str> {
         match self {
             ToWrite::Clear | ToWrite::Heartbeat => None,
             ToWrite::Publish(p)
@@ -70,13 +79,50 @@ impl ToPath for ToWrite {
             | ToWrite::PublishDefault(p)
             | ToWrite::PublishWithFlags(p, _)
             | ToWrite::PublishDefaultWithFlags(p, _) => Some(p),
+            // the server that holds the parent of a delegated path
+            // is the one that owns the delegation
+            ToWrite::Delegate(Referral { path: p, .. }) | ToWrite::Undelegate(p) => {
+                Some(Path::dirname(p).unwrap_or("/"))
+            }
         }
     }
 }
 
+/// A referral in a client's cache, see `ResolverRead::referrals`
+#[derive(Debug, Clone)]
+pub struct CachedReferral {
+    /// The referral. The client's config is cached as the referral
+    /// for `/`
+    pub referral: Referral,
+    /// How long until the referral expires, `None` if it never does
+    pub ttl_remaining: Option<Duration>,
+    /// How many requests have been routed by the referral
+    pub hits: u64,
+}
+
+/// One hop taken while resolving a path, see
+/// `ResolverRead::resolve_with_trace`
+#[derive(Debug, Clone)]
+pub struct TraceHop {
+    /// The referral the request was routed by
+    pub referral: Referral,
+    /// How long the servers took to answer, `None` if they didn't
+    pub elapsed: Option<Duration>,
+}
+
+/// The result of `ResolverRead::resolve_with_trace`
+#[derive(Debug)]
+pub struct ResolveTrace {
+    /// The hops taken, in order. Every hop but the last was answered
+    /// with a referral.
+    pub hops: Vec<TraceHop>,
+    pub result: Result<Resolved>,
+}
+
 #[derive(Debug)]
 struct Router {
-    cached: BTreeMap<Path, (Option<Instant>, Arc<Referral>)>,
+    // referral path -> (expiration, referral, hits)
+    cached: BTreeMap<Path, (Option<Instant>, Arc<Referral>, u64)>,
 }
 
 impl Router {
@@ -101,10 +147,9 @@ impl Router {
             match v.path() {
                 None => batches.entry(None).or_insert_with(|| pool.take()).push((id, v)),
                 Some(path) => {
-                    let mut r = self.cached.range::<str, (Bound<&str>, Bound<&str>)>((
-                        Unbounded,
-                        Included(path.as_ref()),
-                    ));
+                    let mut r = self.cached.range_mut::<str, (Bound<&str>, Bound<&str>)>(
+                        (Unbounded, Included(path)),
+                    );
                     loop {
                         match r.next_back() {
                             None => {
@@ -114,22 +159,20 @@ impl Router {
                                     .push((id, v));
                                 break;
                             }
-                            Some((p, (exp, r))) => {
+                            Some((p, (exp, r, hits))) => {
                                 if !Path::is_parent(p, path) {
                                     continue;
+                                } else if exp.map(|exp| now >= exp).unwrap_or(false) {
+                                    // the delegation may have changed,
+                                    // ask the nearest live parent
+                                    gc.push(p.clone());
+                                    continue;
                                 } else {
-                                    if exp.is_none() || now < exp.unwrap() {
-                                        batches
-                                            .entry(Some(r.clone()))
-                                            .or_insert_with(|| pool.take())
-                                            .push((id, v))
-                                    } else {
-                                        gc.push(p.clone());
-                                        batches
-                                            .entry(None)
-                                            .or_insert_with(|| pool.take())
-                                            .push((id, v))
-                                    }
+                                    *hits += 1;
+                                    batches
+                                        .entry(Some(r.clone()))
+                                        .or_insert_with(|| pool.take())
+                                        .push((id, v));
                                     break;
                                 }
                             }
@@ -148,10 +191,32 @@ impl Router {
         })
     }
 
+    /// the paths of all the cached referrals that haven't expired
+    fn live(&self) -> impl Iterator<Item = &Path> {
+        let now = Instant::now();
+        self.cached.iter().filter_map(move |(p, (exp, _, _))| match exp {
+            Some(exp) if now >= *exp => None,
+            Some(_) | None => Some(p),
+        })
+    }
+
+    fn referrals(&self) -> Vec<CachedReferral> {
+        let now = Instant::now();
+        self.cached
+            .values()
+            .map(|(exp, r, hits)| CachedReferral {
+                referral: (**r).clone(),
+                ttl_remaining: exp.map(|exp| exp.saturating_duration_since(now)),
+                hits: *hits,
+            })
+            .collect()
+    }
+
     fn add_referral(&mut self, r: Arc<Referral>) -> Arc<Referral> {
         let exp = r.ttl.map(|ttl| Instant::now() + Duration::from_secs(ttl as u64));
         let key = r.path.clone();
-        self.cached.insert(key, (exp, r.clone()));
+        let hits = self.cached.get(&key).map(|(_, _, hits)| *hits).unwrap_or(0);
+        self.cached.insert(key, (exp, r.clone(), hits));
         r
     }
 }
@@ -187,8 +252,10 @@ where
         resolver: Arc<Referral>,
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
         tls: Option<tls::CachedConnector>,
+        health: Health,
     ) -> Self;
     fn send(&mut self, batch: Pooled<Vec<(usize, T)>>) -> ResponseChan<F>;
 }
@@ -198,10 +265,12 @@ impl Connection<ToRead, FromRead> for ReadClient {
         resolver: Arc<Referral>,
         desired_auth: DesiredAuth,
         _writer_addr: SocketAddr,
+        _alt_addr: Option<SocketAddr>,
         _secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
         tls: Option<tls::CachedConnector>,
+        health: Health,
     ) -> Self {
-        ReadClient::new(resolver, desired_auth, tls)
+        ReadClient::new(resolver, desired_auth, tls, health)
     }
 
     fn send(&mut self, batch: Pooled<Vec<(usize, ToRead)>>) -> ResponseChan<FromRead> {
@@ -214,10 +283,12 @@ impl Connection<ToWrite, FromWrite> for WriteClient {
         resolver: Arc<Referral>,
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
         tls: Option<tls::CachedConnector>,
+        _health: Health,
     ) -> Self {
-        WriteClient::new(resolver, desired_auth, writer_addr, secrets, tls)
+        WriteClient::new(resolver, desired_auth, writer_addr, alt_addr, secrets, tls)
     }
 
     fn send(&mut self, batch: Pooled<Vec<(usize, ToWrite)>>) -> ResponseChan<FromWrite> {
@@ -236,8 +307,11 @@ where
     default: Arc<Referral>,
     by_server: HashMap<Arc<Referral>, C>,
     writer_addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
     tls: Option<tls::CachedConnector>,
+    health: Health,
+    rt: Option<Handle>,
     phantom: PhantomData<(T, F)>,
     f_pool: Pool<Vec<F>>,
     fi_pool: Pool<Vec<(usize, F)>>,
@@ -259,12 +333,17 @@ where
         match self.by_server.get_mut(&r) {
             Some(con) => con.send(batch),
             None => {
+                // connections spawn their task, which must go to the
+                // owner's runtime, not the caller's
+                let _guard = self.rt.as_ref().map(|rt| rt.enter());
                 let mut con = C::new(
                     r.clone(),
                     self.desired_auth.clone(),
                     self.writer_addr,
+                    self.alt_addr,
                     self.secrets.clone(),
                     self.tls.clone(),
+                    self.health.clone(),
                 );
                 self.by_server.insert(r, con.clone());
                 con.send(batch)
@@ -289,6 +368,7 @@ where
         default: Config,
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         f_pool: Pool<Vec<F>>,
         fi_pool: Pool<Vec<(usize, F)>>,
         ti_pool: Pool<Vec<(usize, T)>>,
@@ -304,8 +384,11 @@ where
             default,
             by_server: HashMap::new(),
             writer_addr,
+            alt_addr,
             secrets,
             tls,
+            health: Health::default(),
+            rt: None,
             f_pool,
             fi_pool,
             ti_pool,
@@ -317,13 +400,29 @@ where
         Arc::clone(&self.0.lock().secrets)
     }
 
+    fn set_runtime(&self, rt: Handle) {
+        self.0.lock().rt = Some(rt)
+    }
+
     async fn send(
         &self,
         batch: &Pooled<Vec<T>>,
+    ) -> Result<(Pooled<FxHashMap<PublisherId, Publisher>>, Pooled<Vec<F>>)> {
+        self.send_traced(batch, None).await
+    }
+
+    // Same as send, but if trace is given then record every hop
+    // taken in it, and fail if the servers of a hop don't answer
+    // within the timeout.
+    async fn send_traced(
+        &self,
+        batch: &Pooled<Vec<T>>,
+        mut trace: Option<(&mut Vec<TraceHop>, Duration)>,
     ) -> Result<(Pooled<FxHashMap<PublisherId, Publisher>>, Pooled<Vec<F>>)> {
         let mut referrals = 0;
         loop {
             let mut waiters = Vec::new();
+            let first_hop = trace.as_ref().map(|(hops, _)| hops.len()).unwrap_or(0);
             let (mut finished, mut res) = {
                 let mut guard = self.0.lock();
                 let inner = &mut *guard;
@@ -331,14 +430,32 @@ where
                     inner.by_server.clear(); // a workable sledgehammer
                 }
                 for (r, batch) in inner.router.route_batch(&inner.ti_pool, batch) {
-                    waiters.push(inner.send_to_server(r, batch))
+                    let r = r.unwrap_or_else(|| inner.default.clone());
+                    if let Some((hops, _)) = &mut trace {
+                        hops.push(TraceHop { referral: (*r).clone(), elapsed: None });
+                    }
+                    waiters.push(inner.send_to_server(Some(r), batch))
                 }
                 (inner.fi_pool.take(), inner.f_pool.take())
             };
             let mut referral = false;
             //: Option<Pooled<FxHashMap<PublisherId, Publisher>>>
             let mut publishers = None;
-            for r in future::join_all(waiters).await {
+            let start = Instant::now();
+            let replies = match &trace {
+                None => future::join_all(waiters).await,
+                Some((_, timeout)) => {
+                    match time::timeout(*timeout, future::join_all(waiters)).await {
+                        Ok(replies) => replies,
+                        Err(_) => bail!("no answer within {:?}", timeout),
+                    }
+                }
+            };
+            let elapsed = start.elapsed();
+            for (i, r) in replies.into_iter().enumerate() {
+                if let (Some((hops, _)), Ok(_)) = (&mut trace, &r) {
+                    hops[first_hop + i].elapsed = Some(elapsed);
+                }
                 let (mut p, mut r) = r?;
                 match publishers.as_mut() {
                     None => {
@@ -389,18 +506,35 @@ impl ChangeTracker {
 }
 
 #[derive(Debug, Clone)]
-pub struct ResolverRead(ResolverWrap<ReadClient, ToRead, FromRead>);
+pub struct ResolverRead(ResolverWrap<ReadClient, ToRead, FromRead>, Arc<Pinned>);
 
 impl ResolverRead {
     pub fn new(default: Config, desired_auth: DesiredAuth) -> Self {
-        ResolverRead(ResolverWrap::new(
+        let pinned = Arc::new(Pinned::new(default.pinned.clone()));
+        let wrap = ResolverWrap::new(
             default,
             desired_auth,
             SocketAddr::new(IpAddr::V4(Ipv4Addr::new(0, 0, 0, 0)), 0),
+            None,
             RAWFROMREADPOOL.clone(),
             FROMREADPOOL.clone(),
             TOREADPOOL.clone(),
-        ))
+        );
+        ResolverRead(wrap, pinned)
+    }
+
+    /// Override the limits of the pools used to batch requests to,
+    /// and replies from, the resolver, see `PoolConfig`. The pools
+    /// are shared by every `ResolverRead` and `ResolverWrite` in the
+    /// process, and apply to ones created before and after the call.
+    pub fn configure_pools(cfg: PoolConfig) {
+        pool::configure("resolver_client::", cfg)
+    }
+
+    /// Start connections to resolver servers on `rt` instead of the
+    /// runtime of whoever first sends to them.
+    pub(crate) fn set_runtime(&self, rt: Handle) {
+        self.0.set_runtime(rt)
     }
 
     /// send the specified messages to the resolver, and return the answers (in send order)
@@ -411,11 +545,62 @@ impl ResolverRead {
         self.0.send(batch).await
     }
 
-    /// resolve the specified paths, results are in send order
+    /// resolve the specified paths, results are in send order. Paths
+    /// under a pin in the config (see `Config::pinned`) are resolved
+    /// without asking the resolver, and paths under a fallback pin
+    /// are resolved that way if asking the resolver fails.
     pub async fn resolve<I>(
         &self,
         batch: I,
     ) -> Result<(Pooled<FxHashMap<PublisherId, Publisher>>, Pooled<Vec<Resolved>>)>
+    where
+        I: IntoIterator<Item = Path>,
+    {
+        if self.1.is_empty() {
+            return self.resolve_remote(batch).await;
+        }
+        let paths = batch.into_iter().collect::<Vec<_>>();
+        let mut publishers = PUBLISHERPOOL.take();
+        let mut slots = paths
+            .iter()
+            .map(|path| self.1.resolve(path, false, &mut publishers))
+            .collect::<Vec<_>>();
+        let remote = paths
+            .iter()
+            .zip(slots.iter())
+            .filter(|(_, r)| r.is_none())
+            .map(|(path, _)| path.clone())
+            .collect::<Vec<_>>();
+        if !remote.is_empty() {
+            match self.resolve_remote(remote).await {
+                Ok((mut p, mut r)) => {
+                    publishers.extend(p.drain());
+                    let mut r = r.drain(..);
+                    for slot in slots.iter_mut().filter(|r| r.is_none()) {
+                        *slot = r.next();
+                    }
+                }
+                Err(e) => {
+                    for (path, slot) in paths.iter().zip(slots.iter_mut()) {
+                        if slot.is_none() {
+                            match self.1.resolve(path, true, &mut publishers) {
+                                Some(r) => *slot = Some(r),
+                                None => return Err(e),
+                            }
+                        }
+                    }
+                }
+            }
+        }
+        let mut out = RESOLVEDPOOL.take();
+        out.extend(slots.into_iter().flatten());
+        Ok((publishers, out))
+    }
+
+    async fn resolve_remote<I>(
+        &self,
+        batch: I,
+    ) -> Result<(Pooled<FxHashMap<PublisherId, Publisher>>, Pooled<Vec<Resolved>>)>
     where
         I: IntoIterator<Item = Path>,
     {
@@ -455,7 +640,7 @@ impl ResolverRead {
                 m => bail!("unexpected result from list {:?}", m),
             };
             from_server.sort();
-            for p in (self.0).0.lock().router.cached.keys() {
+            for p in (self.0).0.lock().router.live() {
                 if Path::is_immediate_parent(&path, p) {
                     if let Err(i) = from_server.binary_search(p) {
                         from_server.insert(i, p.clone())
@@ -527,7 +712,7 @@ impl ResolverRead {
         .await?;
         if !globset.published_only() {
             let mut refs = PATHPOOL.take();
-            for p in (self.0).0.lock().router.cached.keys() {
+            for p in (self.0).0.lock().router.live() {
                 if globset.is_match(p) {
                     refs.push(p.clone());
                 }
@@ -572,7 +757,7 @@ impl ResolverRead {
                     Ok(cn.referrals)
                 }
                 Entry::Occupied(mut e) => {
-                    if **e.get() < *cn.change_number {
+                    if *e.get() != cn.change_number {
                         res = true;
                     }
                     *e.get_mut() = cn.change_number;
@@ -585,6 +770,43 @@ impl ResolverRead {
         Ok(res)
     }
 
+    /// Resolve `path`, recording every referral followed on the way
+    /// to the server that answers. Each hop must be answered within
+    /// `timeout`, so a misconfigured delegation shows up as a failed
+    /// hop rather than a hang. This is meant for debugging, use
+    /// `resolve` for everything else.
+    pub async fn resolve_with_trace(
+        &self,
+        path: Path,
+        timeout: Duration,
+    ) -> ResolveTrace {
+        let mut hops = Vec::new();
+        let mut to = RAWTOREADPOOL.take();
+        to.push(ToRead::Resolve(path));
+        let result = match self.0.send_traced(&to, Some((&mut hops, timeout))).await {
+            Err(e) => Err(e),
+            Ok((_, mut result)) => match result.pop() {
+                Some(FromRead::Resolved(r)) => Ok(r),
+                m => Err(anyhow!("unexpected resolve response {:?}", m)),
+            },
+        };
+        ResolveTrace { hops, result }
+    }
+
+    /// Return the referrals cached by this client in path order,
+    /// including expired referrals that haven't been removed yet.
+    pub fn referrals(&self) -> Vec<CachedReferral> {
+        (self.0).0.lock().router.referrals()
+    }
+
+    /// Return this client's view of the health of every resolver
+    /// server it has tried to talk to, in order of address. When
+    /// connecting the client prefers servers that haven't failed
+    /// recently, and then servers with lower latency.
+    pub fn server_health(&self) -> Vec<ServerHealth> {
+        (self.0).0.lock().health.servers()
+    }
+
     pub async fn table(&self, path: Path) -> Result<Table> {
         let mut to = RAWTOREADPOOL.take();
         to.push(ToRead::Table(path.clone()));
@@ -596,7 +818,7 @@ impl ResolverRead {
                 FromRead::Table(mut table) => {
                     let skip = Path::levels(&path) + 1;
                     table.rows.sort();
-                    for p in (self.0).0.lock().router.cached.keys() {
+                    for p in (self.0).0.lock().router.live() {
                         if Path::is_immediate_parent(&path, p) {
                             if let Some(part) = Path::dirnames(p).skip(skip).next() {
                                 let part = Path::from(ArcStr::from(part));
@@ -622,6 +844,19 @@ impl ResolverWrite {
         default: Config,
         desired_auth: DesiredAuth,
         writer_addr: SocketAddr,
+    ) -> Result<Self> {
+        Self::new_with_alt_addr(default, desired_auth, writer_addr, None)
+    }
+
+    /// Same as `new`, but also register `alt_addr`, an address of
+    /// the other family that the publisher also listens on. The
+    /// resolver passes it on to subscribers along with
+    /// `writer_addr`.
+    pub fn new_with_alt_addr(
+        default: Config,
+        desired_auth: DesiredAuth,
+        writer_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
     ) -> Result<Self> {
         match &desired_auth {
             DesiredAuth::Local
@@ -641,6 +876,7 @@ impl ResolverWrite {
             default,
             desired_auth,
             writer_addr,
+            alt_addr,
             RAWFROMWRITEPOOL.clone(),
             FROMWRITEPOOL.clone(),
             TOWRITEPOOL.clone(),
@@ -726,6 +962,31 @@ impl ResolverWrite {
         self.send_expect(batch, FromWrite::Unpublished, ToWrite::UnpublishDefault).await
     }
 
+    /// Delegate the subtree at the path of each referral to the
+    /// resolver servers it lists, replacing any existing delegation
+    /// of the same path. This requires the delegate permission at
+    /// the path. The delegation is made on every member of the
+    /// cluster that owns the parent of the path, but it is not
+    /// persistent, it must be made again if those servers restart.
+    ///
+    /// The ttl of the referral is required, it is how long clients
+    /// may cache the delegation before asking again, and so it
+    /// bounds how long they will take to see it change or go
+    /// away. Delegations may not be nested inside each other, and
+    /// they must be below the root of the cluster that owns them.
+    pub async fn delegate<I: IntoIterator<Item = Referral>>(
+        &self,
+        batch: I,
+    ) -> Result<()> {
+        self.send_expect(batch, FromWrite::Published, ToWrite::Delegate).await
+    }
+
+    /// Remove delegations made by `delegate` or in the config
+    /// file. This requires the delegate permission at the path.
+    pub async fn undelegate<I: IntoIterator<Item = Path>>(&self, batch: I) -> Result<()> {
+        self.send_expect(batch, FromWrite::Unpublished, ToWrite::Undelegate).await
+    }
+
     // CR estokes: this is broken on complex clusters, but it's also
     // redundant, consider removing it.
     pub async fn clear(&self) -> Result<()> {
@@ -0,0 +1,86 @@
+use crate::{
+    config::Pin,
+    pack::{self, Pack},
+    path::Path,
+    pool::Pooled,
+    protocol::resolver::{
+        HashMethod, Publisher, PublisherId, PublisherRef, Resolved, TargetAuth,
+    },
+    resolver_server::auth::Permissions,
+};
+use bytes::{Bytes, BytesMut};
+use fxhash::FxHashMap;
+use parking_lot::Mutex;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+
+// the resolver field of pinned resolutions, there isn't one
+const NO_RESOLVER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), 0);
+
+/// The static resolution table built from `Config::pinned`
+#[derive(Debug)]
+pub(super) struct Pinned {
+    pins: Vec<Pin>,
+    ids: Mutex<FxHashMap<SocketAddr, PublisherId>>,
+}
+
+impl Pinned {
+    pub(super) fn new(mut pins: Vec<Pin>) -> Self {
+        // longest prefix first, so the first match is the best one
+        pins.sort_by(|p0, p1| p1.prefix.len().cmp(&p0.prefix.len()));
+        Pinned { pins, ids: Mutex::new(FxHashMap::default()) }
+    }
+
+    pub(super) fn is_empty(&self) -> bool {
+        self.pins.is_empty()
+    }
+
+    // publisher ids are allocated from the top of the id space so
+    // they can't collide with ids assigned by resolver servers
+    fn id(&self, addr: SocketAddr) -> PublisherId {
+        let mut ids = self.ids.lock();
+        let i = ids.len() as u64;
+        *ids.entry(addr).or_insert_with(|| {
+            let mut buf = BytesMut::new();
+            pack::encode_varint(u64::MAX - i, &mut buf);
+            PublisherId::decode(&mut buf.freeze()).unwrap()
+        })
+    }
+
+    /// Resolve `path` if it is pinned. Pins that are only a fallback
+    /// are considered if `fallback` is true. The publishers are
+    /// added to `publishers`.
+    pub(super) fn resolve(
+        &self,
+        path: &Path,
+        fallback: bool,
+        publishers: &mut FxHashMap<PublisherId, Publisher>,
+    ) -> Option<Resolved> {
+        let pin = self.pins.iter().find(|pin| {
+            Path::is_parent(&pin.prefix, path) && (fallback || !pin.fallback)
+        })?;
+        let refs = pin
+            .addrs
+            .iter()
+            .map(|addr| {
+                let id = self.id(*addr);
+                publishers.entry(id).or_insert_with(|| Publisher {
+                    resolver: NO_RESOLVER,
+                    id,
+                    addr: *addr,
+                    hash_method: HashMethod::Sha3_512,
+                    target_auth: TargetAuth::Anonymous,
+                    user_info: None,
+                    alt_addr: None,
+                });
+                PublisherRef { id, token: Bytes::new(), subtree_token: Bytes::new() }
+            })
+            .collect::<Vec<_>>();
+        Some(Resolved {
+            resolver: NO_RESOLVER,
+            publishers: Pooled::orphan(refs),
+            timestamp: 0,
+            flags: 0,
+            permissions: (Permissions::SUBSCRIBE | Permissions::WRITE).bits(),
+        })
+    }
+}
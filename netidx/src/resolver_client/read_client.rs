@@ -1,16 +1,19 @@
-use super::common::{
-    krb5_authentication, DesiredAuth, Response, ResponseChan, FROMREADPOOL, HELLO_TO,
-    PUBLISHERPOOL, RAWFROMREADPOOL,
+use super::{
+    common::{
+        krb5_authentication, DesiredAuth, Response, ResponseChan, FROMREADPOOL, HELLO_TO,
+        PUBLISHERPOOL, RAWFROMREADPOOL,
+    },
+    health::Health,
 };
 use crate::{
     channel::{self, Channel, K5CtxWrap},
     os::local_auth::AuthClient,
     pool::Pooled,
     protocol::resolver::{
-        Auth, AuthRead, ClientHello, FromRead, Publisher, Referral, ToRead,
+        Auth, AuthRead, ClientHello, FromRead, Publisher, PublisherId, ReadCaps,
+        Referral, ToRead,
     },
     tls,
-    utils::Either,
 };
 use anyhow::{Error, Result};
 use cross_krb5::ClientCtx;
@@ -18,10 +21,15 @@ use futures::{
     channel::{mpsc, oneshot},
     prelude::*,
 };
+use fxhash::FxHashMap;
 use log::{info, warn};
-use rand::{seq::SliceRandom, thread_rng, Rng};
-use std::{cmp::max, fmt::Debug, sync::Arc, time::Duration};
-use tokio::{net::TcpStream, task, time};
+use rand::{thread_rng, Rng};
+use std::{cmp::max, fmt::Debug, net::SocketAddr, sync::Arc, time::Duration};
+use tokio::{
+    net::TcpStream,
+    task,
+    time::{self, Instant},
+};
 
 // continue with timeout
 macro_rules! cwt {
@@ -34,15 +42,26 @@ macro_rules! cwt {
     };
 }
 
+fn caps() -> ReadCaps {
+    ReadCaps { publisher_cache: true }
+}
+
 async fn connect(
     resolver: &Referral,
     desired_auth: &DesiredAuth,
     tls: &Option<tls::CachedConnector>,
-) -> Result<Channel> {
+    health: &Health,
+) -> Result<(SocketAddr, Channel)> {
     let mut addrs = resolver.addrs.clone();
-    addrs.as_mut_slice().shuffle(&mut thread_rng());
+    health.order(&mut addrs);
     let mut n = 0;
+    // the server we tried last time around the loop, if we are back
+    // here then it failed
+    let mut attempt = None;
     loop {
+        if let Some(addr) = attempt.take() {
+            health.failure(addr);
+        }
         let (addr, auth) = &addrs[n % addrs.len()];
         let tries = n / addrs.len();
         if tries >= 3 {
@@ -53,6 +72,7 @@ async fn connect(
             time::sleep(Duration::from_secs(wait)).await;
         }
         n += 1;
+        attempt = Some(*addr);
         let mut con = cwt!("connect", TcpStream::connect(&addr));
         try_cf!("no delay", con.set_nodelay(true));
         cwt!("send version", channel::write_raw(&mut con, &3u64));
@@ -62,7 +82,10 @@ async fn connect(
         let con = match (desired_auth, auth) {
             (DesiredAuth::Anonymous, _) => {
                 let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
-                cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Anonymous)));
+                cwt!(
+                    "hello",
+                    con.send_one(&ClientHello::ReadOnly(AuthRead::Anonymous, caps()))
+                );
                 match cwt!("reply", con.receive::<AuthRead>()) {
                     AuthRead::Anonymous => (),
                     AuthRead::Local | AuthRead::Krb5 | AuthRead::Tls => {
@@ -83,7 +106,10 @@ async fn connect(
             ) => {
                 let mut con = Channel::new::<ClientCtx, TcpStream>(None, con);
                 let tok = cwt!("local token", AuthClient::token(&*path));
-                cwt!("hello", con.send_one(&ClientHello::ReadOnly(AuthRead::Local)));
+                cwt!(
+                    "hello",
+                    con.send_one(&ClientHello::ReadOnly(AuthRead::Local, caps()))
+                );
                 cwt!("token", con.send_one(&tok));
                 match cwt!("reply", con.receive::<AuthRead>()) {
                     AuthRead::Local => (),
@@ -101,7 +127,7 @@ async fn connect(
             }
             (DesiredAuth::Krb5 { upn, .. }, Auth::Krb5 { spn }) => {
                 let upn = upn.as_ref().map(|s| s.as_str());
-                let hello = ClientHello::ReadOnly(AuthRead::Krb5);
+                let hello = ClientHello::ReadOnly(AuthRead::Krb5, caps());
                 cwt!("hello", channel::write_raw(&mut con, &hello));
                 let ctx = cwt!("k5auth", krb5_authentication(upn, &*spn, &mut con));
                 match cwt!("reply", channel::read_raw::<AuthRead, _>(&mut con)) {
@@ -117,7 +143,7 @@ async fn connect(
             (DesiredAuth::Tls { .. }, Auth::Tls { name }) => {
                 let tls = tls.as_ref().ok_or_else(|| anyhow!("no tls cache"))?;
                 let ctx = task::block_in_place(|| tls.load(name))?;
-                let hello = ClientHello::ReadOnly(AuthRead::Tls);
+                let hello = ClientHello::ReadOnly(AuthRead::Tls, caps());
                 cwt!("hello", channel::write_raw(&mut con, &hello));
                 let name = rustls::ServerName::try_from(&**name)?;
                 let tls = ctx.connect(name, con).await?;
@@ -133,23 +159,33 @@ async fn connect(
                 }
             }
         };
-        break Ok(con);
+        health.connected(*addr);
+        break Ok((*addr, con));
     }
 }
 
 type Batch = (Pooled<Vec<(usize, ToRead)>>, oneshot::Sender<Response<FromRead>>);
 
-fn partition_publishers(m: FromRead) -> Either<FromRead, Publisher> {
-    match m {
-        FromRead::Publisher(p) => Either::Right(p),
-        FromRead::Denied
-        | FromRead::Error(_)
-        | FromRead::GetChangeNr(_)
-        | FromRead::List(_)
-        | FromRead::ListMatching(_)
-        | FromRead::Referral(_)
-        | FromRead::Resolved(_)
-        | FromRead::Table(_) => Either::Left(m),
+/// Publisher records the server has told us about on this
+/// connection. The server only sends a publisher once per epoch, so
+/// we must remember them until the epoch changes or we reconnect.
+#[derive(Default)]
+struct PublisherCache {
+    epoch: Option<u64>,
+    publishers: FxHashMap<PublisherId, Publisher>,
+}
+
+impl PublisherCache {
+    fn clear(&mut self) {
+        self.epoch = None;
+        self.publishers.clear();
+    }
+
+    fn set_epoch(&mut self, epoch: u64) {
+        if self.epoch != Some(epoch) {
+            self.epoch = Some(epoch);
+            self.publishers.clear();
+        }
     }
 }
 
@@ -158,8 +194,11 @@ async fn connection(
     resolver: Arc<Referral>,
     desired_auth: DesiredAuth,
     tls: Option<tls::CachedConnector>,
+    health: Health,
 ) {
     let mut con: Option<Channel> = None;
+    let mut addr: Option<SocketAddr> = None;
+    let mut cache = PublisherCache::default();
     'main: loop {
         match receiver.next().await {
             None => break,
@@ -176,8 +215,17 @@ async fn connection(
                     tries += 1;
                     let c = match con {
                         Some(ref mut c) => c,
-                        None => match connect(&resolver, &desired_auth, &tls).await {
-                            Ok(c) => {
+                        None => match connect(&resolver, &desired_auth, &tls, &health)
+                            .await
+                        {
+                            Ok((a, c)) => {
+                                if let Some(prev) = addr {
+                                    if prev != a {
+                                        info!("resolver read failover {} -> {}", prev, a)
+                                    }
+                                }
+                                cache.clear();
+                                addr = Some(a);
                                 con = Some(c);
                                 con.as_mut().unwrap()
                             }
@@ -190,10 +238,15 @@ async fn connection(
                     };
                     let mut timeout =
                         max(HELLO_TO, Duration::from_micros(tx_batch.len() as u64 * 50));
+                    // lists take time proportional to the size of the
+                    // namespace, so they don't say much about latency
+                    let mut listing = false;
+                    let start = Instant::now();
                     for (_, m) in &*tx_batch {
                         match m {
                             ToRead::List(_) | ToRead::ListMatching(_) => {
                                 timeout += HELLO_TO;
+                                listing = true;
                             }
                             _ => (),
                         }
@@ -209,35 +262,65 @@ async fn connection(
                     match c.flush_timeout(timeout).await {
                         Err(e) => {
                             warn!("read connection send error: {}", e);
+                            addr.iter().for_each(|a| health.failure(*a));
                             con = None;
                         }
                         Ok(()) => {
                             let mut rx_batch = RAWFROMREADPOOL.take();
                             let mut publishers = PUBLISHERPOOL.take();
+                            let mut shutdown = false;
                             while rx_batch.len() < tx_batch.len() {
-                                let f =
-                                    c.receive_batch_fn(|m| {
-                                        match partition_publishers(m) {
-                                            Either::Left(m) => rx_batch.push(m),
-                                            Either::Right(p) => {
-                                                publishers.insert(p.id, p);
+                                let f = c.receive_batch_fn(|m| match m {
+                                    FromRead::Publisher(p) => {
+                                        cache.publishers.insert(p.id, p.clone());
+                                        publishers.insert(p.id, p);
+                                    }
+                                    FromRead::PublisherEpoch(e) => cache.set_epoch(e),
+                                    FromRead::Shutdown => shutdown = true,
+                                    FromRead::Resolved(r) => {
+                                        for pref in r.publishers.iter() {
+                                            if !publishers.contains_key(&pref.id) {
+                                                if let Some(p) =
+                                                    cache.publishers.get(&pref.id)
+                                                {
+                                                    publishers.insert(pref.id, p.clone());
+                                                }
                                             }
                                         }
-                                    });
+                                        rx_batch.push(FromRead::Resolved(r))
+                                    }
+                                    m @ (FromRead::Denied
+                                    | FromRead::Error(_)
+                                    | FromRead::GetChangeNr(_)
+                                    | FromRead::List(_)
+                                    | FromRead::ListMatching(_)
+                                    | FromRead::Referral(_)
+                                    | FromRead::Table(_)) => rx_batch.push(m),
+                                });
                                 match time::timeout(timeout, f).await {
                                     Ok(Ok(())) => (),
                                     Ok(Err(e)) => {
-                                        warn!("read connection failed {}", e);
+                                        if shutdown {
+                                            info!("resolver server shutting down")
+                                        } else {
+                                            warn!("read connection failed {}", e)
+                                        }
+                                        addr.iter().for_each(|a| health.failure(*a));
                                         con = None;
                                         continue 'batch;
                                     }
                                     Err(e) => {
                                         warn!("read connection timeout: {}", e);
+                                        addr.iter().for_each(|a| health.failure(*a));
                                         con = None;
                                         continue 'batch;
                                     }
                                 }
                             }
+                            if let Some(a) = addr {
+                                let latency = (!listing).then(|| start.elapsed());
+                                health.success(a, latency);
+                            }
                             let mut result = FROMREADPOOL.take();
                             result.extend(
                                 rx_batch
@@ -246,6 +329,10 @@ async fn connection(
                                     .map(|(i, m)| (tx_batch[i].0, m)),
                             );
                             let _ = reply.send((publishers, result));
+                            if shutdown {
+                                addr.iter().for_each(|a| health.failure(*a));
+                                con = None;
+                            }
                             break;
                         }
                     }
@@ -263,10 +350,11 @@ impl ReadClient {
         resolver: Arc<Referral>,
         desired_auth: DesiredAuth,
         tls: Option<tls::CachedConnector>,
+        health: Health,
     ) -> Self {
         let (to_tx, to_rx) = mpsc::unbounded();
         task::spawn(async move {
-            connection(to_rx, resolver, desired_auth, tls).await;
+            connection(to_rx, resolver, desired_auth, tls, health).await;
             info!("read task shutting down")
         });
         Self(to_tx)
@@ -57,6 +57,7 @@ struct Connection {
     resolver_addr: SocketAddr,
     resolver_auth: Auth,
     write_addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     published: Arc<RwLock<HashMap<Path, ToWrite>>>,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
     security_context: Option<K5CtxWrap<ClientCtx>>,
@@ -148,6 +149,7 @@ impl Connection {
             let h = ClientHello::WriteOnly(ClientHelloWrite {
                 write_addr: self.write_addr,
                 auth,
+                alt_addr: self.alt_addr,
             });
             debug!("write_con connection established hello {:?}", h);
             h
@@ -412,6 +414,7 @@ impl Connection {
         resolver_addr: SocketAddr,
         resolver_auth: Auth,
         write_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         published: Arc<RwLock<HashMap<Path, ToWrite>>>,
         desired_auth: DesiredAuth,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
@@ -422,6 +425,7 @@ impl Connection {
             resolver_addr,
             resolver_auth,
             write_addr,
+            alt_addr,
             published,
             secrets,
             desired_auth,
@@ -469,6 +473,7 @@ async fn write_mgr(
     desired_auth: DesiredAuth,
     secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
     write_addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     tls: Option<tls::CachedConnector>,
 ) -> Result<()> {
     let published: Arc<RwLock<HashMap<Path, ToWrite>>> =
@@ -490,6 +495,7 @@ async fn write_mgr(
                     addr,
                     auth,
                     write_addr,
+                    alt_addr,
                     published,
                     desired_auth,
                     secrets,
@@ -517,7 +523,9 @@ async fn write_mgr(
                     ToWrite::Unpublish(_)
                     | ToWrite::UnpublishDefault(_)
                     | ToWrite::Clear
-                    | ToWrite::Heartbeat => (),
+                    | ToWrite::Heartbeat
+                    | ToWrite::Delegate(_)
+                    | ToWrite::Undelegate(_) => (),
                 }
             }
         }
@@ -544,13 +552,22 @@ impl WriteClient {
         resolver: Arc<Referral>,
         desired_auth: DesiredAuth,
         write_addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         secrets: Arc<RwLock<FxHashMap<SocketAddr, u128>>>,
         tls: Option<tls::CachedConnector>,
     ) -> Self {
         let (to_tx, to_rx) = mpsc::unbounded();
         task::spawn(async move {
-            let r =
-                write_mgr(to_rx, resolver, desired_auth, secrets, write_addr, tls).await;
+            let r = write_mgr(
+                to_rx,
+                resolver,
+                desired_auth,
+                secrets,
+                write_addr,
+                alt_addr,
+                tls,
+            )
+            .await;
             info!("write manager exited {:?}", r);
         });
         Self(to_tx)
@@ -0,0 +1,248 @@
+//! Offline evaluation and linting of resolver server permissions.
+//!
+//! [Acl] evaluates permissions with the same engine the resolver
+//! server uses, but it also records which rules were applied, so it
+//! can explain why a user can or can't do something at a path.
+//! [lint] looks for rules in a config that can never take effect.
+pub use super::auth::Permissions;
+use super::{
+    auth::{PMap, UserDb, UserInfo, ANONYMOUS},
+    config::Config,
+};
+use crate::{os::Mapper, path::Path};
+use anyhow::Result;
+use arcstr::ArcStr;
+use std::{collections::BTreeMap, convert::TryFrom, fmt, net::SocketAddr, sync::Arc};
+
+/// A user whose permissions may be evaluated by an [Acl]
+#[derive(Debug, Clone)]
+pub struct User(Arc<UserInfo>);
+
+/// One permission rule that applied to the user during an evaluation
+#[derive(Debug, Clone)]
+pub struct Step {
+    /// the path the rule is attached to
+    pub path: Path,
+    /// the user or group the rule is for, anonymous is ""
+    pub entity: ArcStr,
+    /// the permissions the rule grants, or denies if it contains DENY
+    pub rule: Permissions,
+}
+
+/// The result of evaluating the permissions of a user at a path
+#[derive(Debug, Clone)]
+pub struct Explanation {
+    pub path: Path,
+    pub desired: Permissions,
+    pub effective: Permissions,
+    /// the rules that applied, in the order they were applied
+    pub steps: Vec<Step>,
+}
+
+impl Explanation {
+    pub fn allowed(&self) -> bool {
+        self.effective & self.desired == self.desired
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for s in &self.steps {
+            let verb =
+                if s.rule.contains(Permissions::DENY) { "denies" } else { "grants" };
+            let rule = s.rule & !Permissions::DENY;
+            writeln!(f, "{}: {:?} {} {}", s.path, &*s.entity, verb, rule)?
+        }
+        writeln!(f, "effective permissions at {}: {}", self.path, self.effective)?;
+        if self.allowed() {
+            write!(f, "allowed")
+        } else {
+            write!(f, "denied, missing {}", self.desired & !self.effective)
+        }
+    }
+}
+
+/// Evaluate permissions the way a member of a resolver cluster would
+pub struct Acl {
+    resolver: SocketAddr,
+    users: UserDb,
+    pmap: PMap,
+}
+
+impl Acl {
+    /// Load the permissions of `cfg` as member server `member` would
+    /// see them. Group membership will be looked up using the
+    /// member's id map command. Like the resolver server, this must
+    /// be called from a multi threaded tokio runtime.
+    pub fn new(cfg: &Config, member: usize) -> Result<Self> {
+        let m = cfg
+            .member_servers
+            .get(member)
+            .ok_or_else(|| anyhow!("no such member server {}", member))?;
+        let mut users = UserDb::new(Mapper::new(cfg, m)?);
+        let pmap = PMap::from_file(&cfg.perms, &mut users, cfg.root(), &cfg.children)?;
+        Ok(Self { resolver: m.addr, users, pmap })
+    }
+
+    pub fn anonymous(&self) -> User {
+        User(ANONYMOUS.clone())
+    }
+
+    /// Look up `user` and it's groups with the id map command
+    pub fn user(&mut self, user: &str) -> Result<User> {
+        Ok(User(self.users.ifo(self.resolver, Some(user))?))
+    }
+
+    /// Build `user` from the specified groups instead of looking it
+    /// up, useful when the user isn't known to this machine.
+    pub fn user_with_groups<'a>(
+        &mut self,
+        user: &str,
+        primary_group: &'a str,
+        groups: impl IntoIterator<Item = &'a str>,
+    ) -> User {
+        User(self.users.ifo_with_groups(self.resolver, user, primary_group, groups))
+    }
+
+    /// Evaluate the permissions of `user` at `path`, and explain how
+    /// they were arrived at.
+    pub fn explain(&self, user: &User, path: &str, desired: Permissions) -> Explanation {
+        let mut steps = Vec::new();
+        let effective = self.pmap.evaluate(path, &user.0, |path, e, rule| {
+            // a user's name may also be the name of one of it's groups
+            let entity = self.users.name(&e);
+            let dup = steps
+                .iter()
+                .rev()
+                .take_while(|s: &&Step| &*s.path == path)
+                .any(|s| &*s.entity == entity);
+            if !dup {
+                let path = Path::from(ArcStr::from(path));
+                steps.push(Step { path, entity: ArcStr::from(entity), rule })
+            }
+        });
+        Explanation { path: Path::from(ArcStr::from(path)), desired, effective, steps }
+    }
+}
+
+/// A problem with a permission rule
+#[derive(Debug, Clone)]
+pub struct Lint {
+    pub path: Path,
+    /// the entity of the rule, or None if the problem is with the path
+    pub entity: Option<ArcStr>,
+    pub message: String,
+}
+
+impl fmt::Display for Lint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.entity {
+            None => write!(f, "{}: {}", self.path, self.message),
+            Some(e) => write!(f, "{}: {:?} {}", self.path, &**e, self.message),
+        }
+    }
+}
+
+/// Find permission rules in `cfg` that are invalid, unreachable, or
+/// shadowed by other rules. Unlike `Config::load` and [Acl::new]
+/// this doesn't stop at the first problem, it reports all of them.
+///
+/// A grant is reported as shadowed when an ancestor already grants
+/// the same entity the same permissions and no rule in between
+/// denies any of them to anyone. It is still possible for a group
+/// deny to make such a rule matter for some users, so shadowed
+/// rules are worth a look, not necessarily wrong.
+pub fn lint(cfg: &Config) -> Vec<Lint> {
+    let root = cfg.root();
+    let mut lints = Vec::new();
+    let mut rules: BTreeMap<Path, Vec<(ArcStr, Permissions)>> = BTreeMap::new();
+    let mut paths = cfg.perms.0.iter().collect::<Vec<_>>();
+    paths.sort_by(|(p0, _), (p1, _)| p0.cmp(p1));
+    for (path, tbl) in paths {
+        let path = Path::from(path);
+        if !Path::is_parent(root, &path) {
+            let message = format!("unreachable, not under the root {}", root);
+            lints.push(Lint { path, entity: None, message });
+            continue;
+        }
+        if let Some(child) = cfg.children.keys().find(|c| Path::is_parent(c, &path)) {
+            let message = format!("unreachable, delegated to the child {}", child);
+            lints.push(Lint { path, entity: None, message });
+            continue;
+        }
+        let mut tbl = tbl.iter().collect::<Vec<_>>();
+        tbl.sort();
+        let mut entry = Vec::with_capacity(tbl.len());
+        for (ent, perm) in tbl {
+            let entity = Some(ArcStr::from(ent));
+            match Permissions::try_from(perm.as_str()) {
+                Err(e) => {
+                    let message = format!("invalid permissions {:?}, {}", perm, e);
+                    lints.push(Lint { path: path.clone(), entity, message });
+                }
+                Ok(p) if (p & !Permissions::DENY).is_empty() => {
+                    let message = "has no effect, it grants or denies nothing".into();
+                    lints.push(Lint { path: path.clone(), entity, message });
+                }
+                Ok(p) => entry.push((ArcStr::from(ent), p)),
+            }
+        }
+        rules.insert(path, entry);
+    }
+    for (path, entry) in rules.iter() {
+        let chain = Path::dirnames(path)
+            .filter_map(|p| rules.get_key_value(p))
+            .collect::<Vec<_>>();
+        for (ent, p) in entry.iter() {
+            let entity = Some(ent.clone());
+            if p.contains(Permissions::DENY) {
+                let granted = chain.iter().fold(Permissions::empty(), |acc, (_, e)| {
+                    e.iter()
+                        .filter(|(_, p)| !p.contains(Permissions::DENY))
+                        .fold(acc, |acc, (_, p)| acc | *p)
+                });
+                let p = *p & !Permissions::DENY;
+                if (p & granted).is_empty() {
+                    let message = format!(
+                        "unreachable, nothing at or above this path grants {}",
+                        p
+                    );
+                    lints.push(Lint { path: path.clone(), entity, message });
+                }
+            } else {
+                // the nearest ancestor from which the entity
+                // inherits all of p without any intervening deny
+                let mut inherited = Permissions::empty();
+                let mut from = None;
+                for (apath, e) in chain.iter().filter(|(a, _)| *a != path) {
+                    let (g, d) = e.iter().fold(
+                        (Permissions::empty(), Permissions::empty()),
+                        |(g, d), (aent, ap)| {
+                            if ap.contains(Permissions::DENY) {
+                                (g, d | *ap)
+                            } else if aent == ent {
+                                (g | *ap, d)
+                            } else {
+                                (g, d)
+                            }
+                        },
+                    );
+                    inherited = (inherited | g) & !d;
+                    if inherited & *p == *p {
+                        from = Some(*apath);
+                    } else {
+                        from = None;
+                    }
+                }
+                let denied_here = entry
+                    .iter()
+                    .any(|(_, dp)| dp.contains(Permissions::DENY) && dp.intersects(*p));
+                if let (Some(from), false) = (from, denied_here) {
+                    let message = format!("shadowed, {} already grants {}", from, p);
+                    lints.push(Lint { path: path.clone(), entity, message });
+                }
+            }
+        }
+    }
+    lints
+}
@@ -0,0 +1,243 @@
+//! An audit log for the resolver server. Authentication successes
+//! and failures, permission denials, and administrative actions
+//! (delegating and undelegating part of the namespace) are recorded
+//! as `AuditEvent`s and handed to an `AuditSink`. The built in sinks
+//! write one JSON object per line to a file, or send the same JSON to
+//! the local syslog daemon. Which one is used is set by `audit` in
+//! the member server config, or an application can supply it's own
+//! sink with `Server::with_audit_sink`.
+use super::{auth::UserInfo, config::AuditLog};
+use crate::path::Path;
+use anyhow::Result;
+use arcstr::ArcStr;
+use log::warn;
+use parking_lot::Mutex;
+use std::{
+    fmt::Debug,
+    fs::{File, OpenOptions},
+    io::Write,
+    net::SocketAddr,
+    sync::Arc,
+    time::SystemTime,
+};
+
+pub use super::metrics::Mech;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditKind {
+    AuthSuccess,
+    AuthFailure,
+    Denied,
+    Admin,
+}
+
+/// One entry in the audit log
+#[derive(Debug, Clone, Serialize)]
+pub struct AuditEvent {
+    /// seconds since the unix epoch
+    pub timestamp: f64,
+    pub kind: AuditKind,
+    /// the authenticated user, `None` for anonymous users, and for
+    /// failed authentications
+    pub user: Option<ArcStr>,
+    pub mechanism: Mech,
+    /// the address the client connected from
+    pub addr: SocketAddr,
+    /// what was denied, or done, e.g. `subscribe` or `delegate`
+    pub action: Option<&'static str>,
+    pub path: Option<Path>,
+    /// why authentication failed
+    pub detail: Option<String>,
+}
+
+/// Somewhere to put audit events. `record` is called from the
+/// server's tasks as events happen, so it should not block for long.
+pub trait AuditSink: Debug + Send + Sync + 'static {
+    fn record(&self, event: &AuditEvent);
+}
+
+/// Append events to a file as JSON lines
+#[derive(Debug)]
+pub struct JsonLinesSink(Mutex<File>);
+
+impl JsonLinesSink {
+    pub fn new(path: &str) -> Result<Self> {
+        Ok(Self(Mutex::new(OpenOptions::new().create(true).append(true).open(path)?)))
+    }
+}
+
+impl AuditSink for JsonLinesSink {
+    fn record(&self, event: &AuditEvent) {
+        let mut line = match serde_json::to_vec(event) {
+            Ok(line) => line,
+            Err(e) => return warn!("failed to encode audit event {}", e),
+        };
+        line.push(b'\n');
+        // one write per event, so concurrent writers don't interleave
+        if let Err(e) = self.0.lock().write_all(&line) {
+            warn!("failed to write audit event {}", e)
+        }
+    }
+}
+
+/// Send events to the local syslog daemon, with facility authpriv
+#[derive(Debug)]
+pub struct SyslogSink {
+    #[cfg(unix)]
+    socket: Mutex<std::os::unix::net::UnixDatagram>,
+}
+
+#[cfg(unix)]
+impl SyslogSink {
+    const PATHS: [&'static str; 3] = ["/dev/log", "/var/run/syslog", "/var/run/log"];
+    const AUTHPRIV: u8 = 10;
+
+    fn connect() -> Result<std::os::unix::net::UnixDatagram> {
+        let socket = std::os::unix::net::UnixDatagram::unbound()?;
+        for path in Self::PATHS {
+            if socket.connect(path).is_ok() {
+                return Ok(socket);
+            }
+        }
+        bail!("can't find the syslog socket")
+    }
+
+    pub fn new() -> Result<Self> {
+        Ok(Self { socket: Mutex::new(Self::connect()?) })
+    }
+}
+
+#[cfg(not(unix))]
+impl SyslogSink {
+    pub fn new() -> Result<Self> {
+        bail!("syslog is only supported on unix")
+    }
+}
+
+impl AuditSink for SyslogSink {
+    #[cfg(unix)]
+    fn record(&self, event: &AuditEvent) {
+        let severity = match event.kind {
+            AuditKind::AuthSuccess => 6,                     // info
+            AuditKind::Admin => 5,                           // notice
+            AuditKind::AuthFailure | AuditKind::Denied => 4, // warning
+        };
+        let json = match serde_json::to_string(event) {
+            Ok(json) => json,
+            Err(e) => return warn!("failed to encode audit event {}", e),
+        };
+        // the daemon adds the timestamp and hostname
+        let msg = format!(
+            "<{}>netidx-resolver[{}]: {}",
+            Self::AUTHPRIV * 8 + severity,
+            std::process::id(),
+            json
+        );
+        let mut socket = self.socket.lock();
+        if socket.send(msg.as_bytes()).is_err() {
+            // the daemon may have restarted
+            match Self::connect() {
+                Err(e) => return warn!("failed to reconnect to syslog {}", e),
+                Ok(s) => *socket = s,
+            }
+            if let Err(e) = socket.send(msg.as_bytes()) {
+                warn!("failed to send audit event to syslog {}", e)
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn record(&self, _event: &AuditEvent) {}
+}
+
+/// Where an audited request came from
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Source {
+    pub(super) mech: Mech,
+    pub(super) addr: SocketAddr,
+}
+
+#[derive(Debug, Clone)]
+pub(super) struct Audit(Option<Arc<dyn AuditSink>>);
+
+impl Audit {
+    pub(super) fn new(
+        log: &Option<AuditLog>,
+        sink: Option<Arc<dyn AuditSink>>,
+    ) -> Result<Self> {
+        Ok(match (sink, log) {
+            (Some(sink), _) => Audit(Some(sink)),
+            (None, None) => Audit(None),
+            (None, Some(AuditLog::JsonLines(path))) => {
+                Audit(Some(Arc::new(JsonLinesSink::new(path)?)))
+            }
+            (None, Some(AuditLog::Syslog)) => Audit(Some(Arc::new(SyslogSink::new()?))),
+        })
+    }
+
+    fn record(
+        &self,
+        kind: AuditKind,
+        src: Source,
+        uifo: Option<&UserInfo>,
+        action: Option<&'static str>,
+        path: Option<&Path>,
+        detail: Option<String>,
+    ) {
+        if let Some(sink) = &self.0 {
+            let timestamp = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs_f64())
+                .unwrap_or(0.);
+            sink.record(&AuditEvent {
+                timestamp,
+                kind,
+                user: uifo.and_then(|u| u.user_info.as_ref()).map(|u| u.name.clone()),
+                mechanism: src.mech,
+                addr: src.addr,
+                action,
+                path: path.cloned(),
+                detail,
+            })
+        }
+    }
+
+    /// record the outcome of an authentication handshake
+    pub(super) fn auth<T>(
+        &self,
+        src: Source,
+        res: &Result<T>,
+        uifo: impl FnOnce(&T) -> &UserInfo,
+    ) {
+        match res {
+            Ok(t) => {
+                self.record(AuditKind::AuthSuccess, src, Some(uifo(t)), None, None, None)
+            }
+            Err(e) => {
+                let detail = Some(e.to_string());
+                self.record(AuditKind::AuthFailure, src, None, None, None, detail)
+            }
+        }
+    }
+
+    pub(super) fn denied(
+        &self,
+        src: Source,
+        uifo: &UserInfo,
+        action: &'static str,
+        path: &Path,
+    ) {
+        self.record(AuditKind::Denied, src, Some(uifo), Some(action), Some(path), None)
+    }
+
+    pub(super) fn admin(
+        &self,
+        src: Source,
+        uifo: &UserInfo,
+        action: &'static str,
+        path: &Path,
+    ) {
+        self.record(AuditKind::Admin, src, Some(uifo), Some(action), Some(path), None)
+    }
+}
@@ -12,7 +12,7 @@ use netidx_netproto::resolver;
 use std::{
     collections::{BTreeMap, Bound, HashMap},
     convert::TryFrom,
-    iter,
+    fmt, iter,
     net::SocketAddr,
     sync::Arc,
 };
@@ -25,9 +25,15 @@ bitflags! {
         const LIST             = 0x08;
         const PUBLISH          = 0x10;
         const PUBLISH_DEFAULT  = 0x20;
+        const DELEGATE         = 0x40;
     }
 }
 
+/// Set in the permission bits signed into a subtree token, see
+/// `PublisherRef::subtree_token`. It isn't a valid permission, so a
+/// subtree token can never pass for a normal token or vice versa.
+pub(crate) const SUBTREE: u32 = 0x8000_0000;
+
 impl TryFrom<&str> for Permissions {
     type Error = Error;
 
@@ -57,9 +63,12 @@ impl TryFrom<&str> for Permissions {
                 'd' => {
                     p |= Permissions::PUBLISH_DEFAULT;
                 }
+                'r' => {
+                    p |= Permissions::DELEGATE;
+                }
                 c => {
                     return Err(anyhow!(
-                        "unrecognized permission bit {}, valid bits are !swlpd",
+                        "unrecognized permission bit {}, valid bits are !swlpdr",
                         c
                     ))
                 }
@@ -69,6 +78,25 @@ impl TryFrom<&str> for Permissions {
     }
 }
 
+impl fmt::Display for Permissions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (p, c) in [
+            (Permissions::DENY, '!'),
+            (Permissions::SUBSCRIBE, 's'),
+            (Permissions::WRITE, 'w'),
+            (Permissions::LIST, 'l'),
+            (Permissions::PUBLISH, 'p'),
+            (Permissions::PUBLISH_DEFAULT, 'd'),
+            (Permissions::DELEGATE, 'r'),
+        ] {
+            if self.contains(p) {
+                write!(f, "{}", c)?
+            }
+        }
+        Ok(())
+    }
+}
+
 #[derive(Debug, Clone, Copy, Hash, PartialEq, Eq, PartialOrd, Ord)]
 pub struct Entity(u32);
 
@@ -131,6 +159,37 @@ impl UserDb {
         }
     }
 
+    /// the name of an entity, anonymous is named by the empty string
+    pub(crate) fn name(&self, e: &Entity) -> &str {
+        self.names.get(e).map(|s| s.as_str()).unwrap_or("")
+    }
+
+    /// Build the user info of `user` from the specified groups
+    /// instead of asking the id mapper. The result is not cached.
+    pub(crate) fn ifo_with_groups<'a>(
+        &mut self,
+        resolver: SocketAddr,
+        user: &str,
+        primary_group: &'a str,
+        groups: impl IntoIterator<Item = &'a str>,
+    ) -> Arc<UserInfo> {
+        let groups_s =
+            iter::once(primary_group).chain(groups).map(ArcStr::from).collect::<Vec<_>>();
+        let groups = groups_s.iter().map(|g| self.entity(g)).collect::<Vec<_>>();
+        Arc::new(UserInfo {
+            id: self.entity(user),
+            primary_group: self.entity(primary_group),
+            groups,
+            user_info: Some(resolver::UserInfo {
+                name: ArcStr::from(user),
+                primary_group: ArcStr::from(primary_group),
+                groups: groups_s.into(),
+                resolver,
+                token: bytes::Bytes::new(),
+            }),
+        })
+    }
+
     pub(crate) fn ifo(
         &mut self,
         resolver: SocketAddr,
@@ -216,15 +275,20 @@ impl PMap {
     ) -> bool {
         let rights_at_base = self.permissions(base_path, user);
         let mut rights = rights_at_base;
-        let mut iter = self.0.range::<str, (Bound<&str>, Bound<&str>)>((
-            Bound::Excluded(base_path),
-            Bound::Unbounded,
-        ));
-        while let Some((path, set)) = iter.next() {
-            if !Path::is_parent(base_path, path) || !scope.contains(Path::levels(&*path))
-            {
-                break;
-            }
+        // siblings such as base-x sort between base and it's children,
+        // and children that are out of scope may be followed by more
+        // that are in scope, so neither can end the search
+        let iter = self
+            .0
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Bound::Excluded(base_path),
+                Bound::Unbounded,
+            ))
+            .take_while(|(path, _)| path.starts_with(base_path))
+            .filter(|(path, _)| {
+                Path::is_parent(base_path, path) && scope.contains(Path::levels(path))
+            });
+        for (_, set) in iter {
             let deny =
                 user.entities().fold(Permissions::empty(), |dp, e| match set.get(e) {
                     None => dp,
@@ -242,6 +306,23 @@ impl PMap {
     }
 
     pub(crate) fn permissions(&self, path: &str, user: &UserInfo) -> Permissions {
+        self.evaluate(path, user, |_, _, _| ())
+    }
+
+    /// Compute the effective permissions of `user` at `path`. `trace`
+    /// is called with the path, entity, and permissions of every
+    /// rule that applies to `user`, in the order they are applied.
+    /// At each level grants are accumulated with the grants of the
+    /// levels above, and then denies at that level are removed.
+    pub(crate) fn evaluate<F>(
+        &self,
+        path: &str,
+        user: &UserInfo,
+        mut trace: F,
+    ) -> Permissions
+    where
+        F: FnMut(&str, Entity, Permissions),
+    {
         Path::dirnames(path).fold(Permissions::empty(), |p, s| match self.0.get(s) {
             None => p,
             Some(set) => {
@@ -250,6 +331,7 @@ impl PMap {
                     user.entities().fold(init, |(ap, dp), e| match set.get(e) {
                         None => (ap, dp),
                         Some(p_) => {
+                            trace(s, *e, *p_);
                             if p_.contains(Permissions::DENY) {
                                 (ap, dp | *p_)
                             } else {
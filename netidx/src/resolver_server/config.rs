@@ -59,6 +59,16 @@ impl From<file::Auth> for Auth {
     }
 }
 
+/// Where the audit log is written, see `resolver_server::audit`
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub enum AuditLog {
+    /// append JSON lines to the named file
+    JsonLines(String),
+    /// send events to the local syslog daemon
+    Syslog,
+}
+
 pub(crate) fn check_addrs<T: Clone + Into<resolver::Auth>>(
     a: &Vec<(SocketAddr, T)>,
 ) -> Result<()> {
@@ -102,7 +112,7 @@ impl Default for PMap {
 
 /// The on disk format, encoded as JSON
 pub(crate) mod file {
-    use super::{super::config::check_addrs, resolver, Chars, PMap};
+    use super::{super::config::check_addrs, resolver, AuditLog, Chars, PMap};
     use crate::{path::Path, pool::Pooled};
     use anyhow::Result;
     use std::net::SocketAddr;
@@ -199,6 +209,10 @@ pub(crate) mod file {
         pub(super) reader_ttl: u64,
         pub(super) writer_ttl: u64,
         pub(super) id_map_command: Option<String>,
+        #[serde(default)]
+        pub(super) metrics_addr: Option<SocketAddr>,
+        #[serde(default)]
+        pub(super) audit: Option<AuditLog>,
     }
 
     #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -222,6 +236,8 @@ pub struct MemberServer {
     pub(super) writer_ttl: Duration,
     #[allow(dead_code)]
     pub(crate) id_map_command: Option<String>, // default /usr/bin/id
+    pub metrics_addr: Option<SocketAddr>,
+    pub audit: Option<AuditLog>,
 }
 
 #[derive(Debug, Clone)]
@@ -348,6 +364,8 @@ impl Config {
                     reader_ttl: Duration::from_secs(m.reader_ttl),
                     writer_ttl: Duration::from_secs(m.writer_ttl),
                     id_map_command: m.id_map_command,
+                    metrics_addr: m.metrics_addr,
+                    audit: m.audit,
                 })
             })
             .collect::<Result<Vec<_>>>()?;
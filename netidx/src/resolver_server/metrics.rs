@@ -0,0 +1,232 @@
+//! A small metrics registry for the resolver server. Counters are
+//! plain atomics updated from the hot paths, and are rendered in the
+//! Prometheus text format when scraped. If `metrics_addr` is set in
+//! the member server config the metrics are served over http at
+//! `/metrics`.
+use anyhow::Result;
+use futures::{channel::oneshot, prelude::*, select_biased};
+use log::{debug, warn};
+use std::{
+    fmt::Write,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicI64, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    task, time,
+};
+
+// latency histogram bucket upper bounds in seconds
+const BUCKETS: [f64; 10] =
+    [0.0005, 0.001, 0.0025, 0.005, 0.01, 0.025, 0.05, 0.1, 0.5, 1.];
+const MECHS: usize = 4;
+const MAX_REQUEST: usize = 8192;
+const HTTP_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An authentication mechanism
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Mech {
+    Anonymous,
+    Local,
+    Krb5,
+    Tls,
+}
+
+impl Mech {
+    const ALL: [Mech; MECHS] = [Mech::Anonymous, Mech::Local, Mech::Krb5, Mech::Tls];
+
+    fn name(&self) -> &'static str {
+        match self {
+            Mech::Anonymous => "anonymous",
+            Mech::Local => "local",
+            Mech::Krb5 => "krb5",
+            Mech::Tls => "tls",
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [AtomicU64; BUCKETS.len()],
+    count: AtomicU64,
+    sum_us: AtomicU64,
+}
+
+impl Histogram {
+    fn observe(&self, d: Duration) {
+        let s = d.as_secs_f64();
+        for (i, b) in BUCKETS.iter().enumerate() {
+            if s <= *b {
+                self.buckets[i].fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        self.count.fetch_add(1, Ordering::Relaxed);
+        self.sum_us.fetch_add(d.as_micros() as u64, Ordering::Relaxed);
+    }
+
+    fn render(&self, buf: &mut String, name: &str, labels: &str) {
+        for (i, b) in BUCKETS.iter().enumerate() {
+            let n = self.buckets[i].load(Ordering::Relaxed);
+            let _ = writeln!(buf, "{}_bucket{{{},le=\"{}\"}} {}", name, labels, b, n);
+        }
+        let count = self.count.load(Ordering::Relaxed);
+        let sum = self.sum_us.load(Ordering::Relaxed) as f64 / 1e6;
+        let _ = writeln!(buf, "{}_bucket{{{},le=\"+Inf\"}} {}", name, labels, count);
+        let _ = writeln!(buf, "{}_sum{{{}}} {}", name, labels, sum);
+        let _ = writeln!(buf, "{}_count{{{}}} {}", name, labels, count);
+    }
+}
+
+#[derive(Debug, Default)]
+pub(super) struct Metrics {
+    pub(super) clients: AtomicI64,
+    pub(super) paths: AtomicI64,
+    pub(super) publishes: AtomicU64,
+    pub(super) unpublishes: AtomicU64,
+    pub(super) resolves: AtomicU64,
+    pub(super) lists: AtomicU64,
+    auth_failures: [AtomicU64; MECHS],
+    auth_latency: [Histogram; MECHS],
+}
+
+impl Metrics {
+    /// record the outcome of an authentication handshake that took `d`
+    pub(super) fn auth(&self, mech: Mech, d: Duration, ok: bool) {
+        if ok {
+            self.auth_latency[mech as usize].observe(d)
+        } else {
+            self.auth_failures[mech as usize].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(super) fn render(&self) -> String {
+        fn gauge(buf: &mut String, name: &str, help: &str, v: i64) {
+            let _ = writeln!(buf, "# HELP {} {}", name, help);
+            let _ = writeln!(buf, "# TYPE {} gauge", name);
+            let _ = writeln!(buf, "{} {}", name, v);
+        }
+        fn counter(buf: &mut String, name: &str, help: &str, v: &AtomicU64) {
+            let _ = writeln!(buf, "# HELP {} {}", name, help);
+            let _ = writeln!(buf, "# TYPE {} counter", name);
+            let _ = writeln!(buf, "{} {}", name, v.load(Ordering::Relaxed));
+        }
+        let mut buf = String::new();
+        gauge(
+            &mut buf,
+            "netidx_resolver_clients",
+            "connected clients",
+            self.clients.load(Ordering::Relaxed),
+        );
+        gauge(
+            &mut buf,
+            "netidx_resolver_paths",
+            "paths published in the store",
+            self.paths.load(Ordering::Relaxed),
+        );
+        counter(
+            &mut buf,
+            "netidx_resolver_publishes_total",
+            "publish requests",
+            &self.publishes,
+        );
+        counter(
+            &mut buf,
+            "netidx_resolver_unpublishes_total",
+            "unpublish requests",
+            &self.unpublishes,
+        );
+        counter(
+            &mut buf,
+            "netidx_resolver_resolves_total",
+            "resolve requests",
+            &self.resolves,
+        );
+        counter(&mut buf, "netidx_resolver_lists_total", "list requests", &self.lists);
+        let name = "netidx_resolver_auth_failures_total";
+        let _ = writeln!(buf, "# HELP {} failed authentication handshakes", name);
+        let _ = writeln!(buf, "# TYPE {} counter", name);
+        for mech in Mech::ALL {
+            let n = self.auth_failures[mech as usize].load(Ordering::Relaxed);
+            let _ = writeln!(buf, "{}{{mechanism=\"{}\"}} {}", name, mech.name(), n);
+        }
+        let name = "netidx_resolver_auth_seconds";
+        let _ = writeln!(buf, "# HELP {} authentication handshake latency", name);
+        let _ = writeln!(buf, "# TYPE {} histogram", name);
+        for mech in Mech::ALL {
+            let labels = format!("mechanism=\"{}\"", mech.name());
+            self.auth_latency[mech as usize].render(&mut buf, name, &labels);
+        }
+        buf
+    }
+}
+
+async fn handle(metrics: Arc<Metrics>, mut con: TcpStream) -> Result<()> {
+    let mut req = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !req.windows(4).any(|w| w == b"\r\n\r\n") {
+        let n = con.read(&mut buf).await?;
+        if n == 0 {
+            bail!("connection closed")
+        }
+        req.extend_from_slice(&buf[..n]);
+        if req.len() > MAX_REQUEST {
+            bail!("request too large")
+        }
+    }
+    let line = req.split(|c| *c == b'\r').next().unwrap_or(&[]);
+    let mut parts = line.split(|c| *c == b' ');
+    let (status, ctyp, body) = match (parts.next(), parts.next()) {
+        (Some(b"GET"), Some(b"/metrics")) => {
+            ("200 OK", "text/plain; version=0.0.4", metrics.render())
+        }
+        (Some(_), Some(_)) => {
+            ("404 Not Found", "text/plain", String::from("not found\n"))
+        }
+        (_, _) => ("400 Bad Request", "text/plain", String::from("bad request\n")),
+    };
+    let hdr = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        status,
+        ctyp,
+        body.len()
+    );
+    con.write_all(hdr.as_bytes()).await?;
+    con.write_all(body.as_bytes()).await?;
+    Ok(con.shutdown().await?)
+}
+
+/// bind `addr` and serve metrics until `stop` fires, returns the
+/// bound address.
+pub(super) async fn serve(
+    metrics: Arc<Metrics>,
+    addr: SocketAddr,
+    stop: oneshot::Receiver<()>,
+) -> Result<SocketAddr> {
+    let listener = TcpListener::bind(addr).await?;
+    let local_addr = listener.local_addr()?;
+    task::spawn(async move {
+        let mut stop = stop.fuse();
+        loop {
+            select_biased! {
+                _ = stop => break,
+                cl = listener.accept().fuse() => match cl {
+                    Err(e) => warn!("metrics accept failed: {}", e),
+                    Ok((con, _)) => {
+                        let metrics = metrics.clone();
+                        task::spawn(async move {
+                            let r = time::timeout(HTTP_TIMEOUT, handle(metrics, con)).await;
+                            debug!("metrics request finished {:?}", r)
+                        });
+                    }
+                }
+            }
+        }
+    });
+    Ok(local_addr)
+}
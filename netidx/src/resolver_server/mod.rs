@@ -1,5 +1,8 @@
+pub mod acl;
+pub mod audit;
 pub(crate) mod auth;
 pub mod config;
+mod metrics;
 pub(crate) mod secctx;
 mod shard_store;
 mod store;
@@ -14,31 +17,33 @@ use crate::{
     protocol::{
         publisher,
         resolver::{
-            AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite, FromWrite,
-            HashMethod, Publisher, PublisherId, ReadyForOwnershipCheck, Secret,
-            ServerHelloWrite, ToRead, ToWrite,
+            AuthChallenge, AuthRead, AuthWrite, ClientHello, ClientHelloWrite, FromRead,
+            FromWrite, HashMethod, Publisher, PublisherId, ReadCaps,
+            ReadyForOwnershipCheck, Secret, ServerHelloWrite, ToRead, ToWrite,
         },
     },
     tls, utils,
 };
 use anyhow::Result;
+use audit::{Audit, AuditSink, Source};
 use auth::{UserInfo, ANONYMOUS};
 use config::{Config, MemberServer};
 use cross_krb5::{AcceptFlags, K5ServerCtx, ServerCtx, Step};
 use futures::{channel::oneshot, prelude::*, select_biased};
 use fxhash::FxHashMap;
 use log::{debug, error, info, warn};
+use metrics::{Mech, Metrics};
 use netidx_core::{pack::BoundedBytes, utils::make_sha3_token};
 use parking_lot::{Mutex, RwLock};
 use rand::{thread_rng, Rng};
 use secctx::{K5SecData, LocalSecData, SecCtx, TlsSecData};
-use shard_store::Store;
+use shard_store::{PublisherCache, Store};
 use std::{
     collections::{hash_map::Entry, HashMap, HashSet},
     fmt::Debug,
-    mem,
+    iter, mem,
     net::SocketAddr,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::Duration,
 };
 use tokio::{
@@ -154,6 +159,7 @@ impl Clinfos {
                             hash_method: HashMethod::Sha3_512,
                             target_auth: hello.auth.clone().try_into()?,
                             user_info: None,
+                            alt_addr: hello.alt_addr,
                         });
                         let (tx, rx) = oneshot::channel();
                         e.insert(ClientInfo::Running {
@@ -207,6 +213,13 @@ impl Clinfos {
     }
 }
 
+// how the server, and each of its clients, should stop
+#[derive(Debug, Clone, Copy)]
+enum Stop {
+    Now,
+    Drain(Duration),
+}
+
 struct Ctx {
     clinfos: Clinfos,
     ctracker: CTracker,
@@ -216,14 +229,17 @@ struct Ctx {
     listen_addr: SocketAddr,
     store: Store,
     delay_reads: Option<Instant>,
+    metrics: Arc<Metrics>,
+    audit: Audit,
 }
 
 async fn client_loop_write(
     ctx: Arc<Ctx>,
     connection_id: CId,
     con: Channel,
-    server_stop: oneshot::Receiver<()>,
+    server_stop: oneshot::Receiver<Stop>,
     rx_stop: oneshot::Receiver<()>,
+    source: Source,
     uifo: Arc<UserInfo>,
     publisher: Arc<Publisher>,
 ) -> Result<()> {
@@ -287,6 +303,15 @@ async fn client_loop_write(
                                     c.queue_send(&FromWrite::Unpublished)?,
                                 ToWrite::UnpublishDefault(_) =>
                                     c.queue_send(&FromWrite::Unpublished)?,
+                                // delegations aren't undone by clear
+                                m @ (ToWrite::Delegate(_) | ToWrite::Undelegate(_)) =>
+                                    ctx.store.handle_batch_write(
+                                        Some(&mut *c),
+                                        Some(source),
+                                        uifo.clone(),
+                                        publisher.clone(),
+                                        iter::once(m)
+                                    ).await?,
                                 ToWrite::Clear => {
                                     ctx.store.handle_clear(
                                         uifo.clone(),
@@ -301,6 +326,7 @@ async fn client_loop_write(
                     }
                     if let Err(e) = ctx.store.handle_batch_write(
                         Some(c),
+                        Some(source),
                         uifo.clone(),
                         publisher.clone(),
                         batch.drain(..)
@@ -642,52 +668,101 @@ async fn hello_client_write(
     ctx: Arc<Ctx>,
     connection_id: CId,
     con: TcpStream,
-    server_stop: oneshot::Receiver<()>,
+    server_stop: oneshot::Receiver<Stop>,
+    addr: SocketAddr,
     hello: ClientHelloWrite,
 ) -> Result<()> {
     static NO: &str = "authentication mechanism not supported";
     info!("hello_write starting negotiation");
     debug!("hello_write client_hello: {:?}", hello);
     utils::check_addr(hello.write_addr.ip(), &[(ctx.listen_addr, ())])?;
-    let (con, uifo, publisher, rx_stop) = match hello.auth {
-        AuthWrite::Anonymous => write_client_anonymous_auth(&ctx, con, &hello).await?,
-        AuthWrite::Local => match &ctx.secctx {
-            SecCtx::Local(a) => write_client_local_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
-        },
-        AuthWrite::Krb5 { .. } => match &ctx.secctx {
-            SecCtx::Krb5(a) => write_client_krb5_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
-        },
-        AuthWrite::Tls { .. } => match &ctx.secctx {
-            SecCtx::Tls(a) => write_client_tls_auth(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
-        },
-        AuthWrite::Reuse => match &ctx.secctx {
-            SecCtx::Local(a) => write_client_reuse_local(&ctx, con, a, &hello).await?,
-            SecCtx::Krb5(a) => write_client_reuse_krb5(&ctx, con, a, &hello).await?,
-            SecCtx::Tls(a) => write_client_reuse_tls(&ctx, con, a, &hello).await?,
-            SecCtx::Anonymous => bail!(NO),
-        },
+    if let Some(alt) = hello.alt_addr {
+        utils::check_addr(alt.ip(), &[(ctx.listen_addr, ())])?;
+        if alt.is_ipv4() == hello.write_addr.is_ipv4() {
+            bail!("alt_addr must be a different address family than write_addr")
+        }
+    }
+    let mech = match (&hello.auth, &ctx.secctx) {
+        (AuthWrite::Anonymous, _) => Mech::Anonymous,
+        (AuthWrite::Local, _) => Mech::Local,
+        (AuthWrite::Krb5 { .. }, _) => Mech::Krb5,
+        (AuthWrite::Tls { .. }, _) => Mech::Tls,
+        (AuthWrite::Reuse, SecCtx::Anonymous) => Mech::Anonymous,
+        (AuthWrite::Reuse, SecCtx::Local(_)) => Mech::Local,
+        (AuthWrite::Reuse, SecCtx::Krb5(_)) => Mech::Krb5,
+        (AuthWrite::Reuse, SecCtx::Tls(_)) => Mech::Tls,
     };
-    Ok(client_loop_write(ctx, connection_id, con, server_stop, rx_stop, uifo, publisher)
-        .await?)
+    let start = Instant::now();
+    let res = async {
+        Ok::<_, anyhow::Error>(match hello.auth {
+            AuthWrite::Anonymous => {
+                write_client_anonymous_auth(&ctx, con, &hello).await?
+            }
+            AuthWrite::Local => match &ctx.secctx {
+                SecCtx::Local(a) => write_client_local_auth(&ctx, con, a, &hello).await?,
+                SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
+            },
+            AuthWrite::Krb5 { .. } => match &ctx.secctx {
+                SecCtx::Krb5(a) => write_client_krb5_auth(&ctx, con, a, &hello).await?,
+                SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
+            },
+            AuthWrite::Tls { .. } => match &ctx.secctx {
+                SecCtx::Tls(a) => write_client_tls_auth(&ctx, con, a, &hello).await?,
+                SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
+            },
+            AuthWrite::Reuse => match &ctx.secctx {
+                SecCtx::Local(a) => {
+                    write_client_reuse_local(&ctx, con, a, &hello).await?
+                }
+                SecCtx::Krb5(a) => write_client_reuse_krb5(&ctx, con, a, &hello).await?,
+                SecCtx::Tls(a) => write_client_reuse_tls(&ctx, con, a, &hello).await?,
+                SecCtx::Anonymous => bail!(NO),
+            },
+        })
+    }
+    .await;
+    ctx.metrics.auth(mech, start.elapsed(), res.is_ok());
+    let source = Source { mech, addr };
+    ctx.audit.auth(source, &res, |(_, uifo, _, _)| &**uifo);
+    let (con, uifo, publisher, rx_stop) = res?;
+    Ok(client_loop_write(
+        ctx,
+        connection_id,
+        con,
+        server_stop,
+        rx_stop,
+        source,
+        uifo,
+        publisher,
+    )
+    .await?)
 }
 
 async fn client_loop_read(
     ctx: Arc<Ctx>,
     mut con: Channel,
-    server_stop: oneshot::Receiver<()>,
+    server_stop: oneshot::Receiver<Stop>,
+    source: Source,
     uifo: Arc<UserInfo>,
+    caps: ReadCaps,
 ) -> Result<()> {
     let mut batch = READ_BATCHES.take();
+    let mut cache =
+        if caps.publisher_cache { Some(PublisherCache::default()) } else { None };
     let mut server_stop = server_stop.fuse();
     let mut act = false;
     let mut timeout =
         time::interval_at(Instant::now() + ctx.cfg.reader_ttl, ctx.cfg.reader_ttl);
     loop {
         select_biased! {
-            _ = server_stop => break Ok(()),
+            s = server_stop => {
+                // every batch we received has been answered
+                if let Ok(Stop::Drain(_)) = s {
+                    let m = con.send_one(&FromRead::Shutdown);
+                    let _ = time::timeout(ctx.cfg.hello_timeout, m).await;
+                }
+                break Ok(())
+            },
             _ = timeout.tick().fuse() => {
                 if act {
                     act = false;
@@ -700,7 +775,9 @@ async fn client_loop_read(
                 act = true;
                 ctx.store.handle_batch_read(
                     &mut con,
+                    source,
                     uifo.clone(),
+                    cache.as_mut(),
                     batch.drain(..)
                 ).await?;
             },
@@ -711,67 +788,85 @@ async fn client_loop_read(
 async fn hello_client_read(
     ctx: Arc<Ctx>,
     mut con: TcpStream,
-    server_stop: oneshot::Receiver<()>,
+    server_stop: oneshot::Receiver<Stop>,
+    addr: SocketAddr,
     hello: AuthRead,
+    caps: ReadCaps,
 ) -> Result<()> {
     static NO: &str = "authentication mechanism not supported";
-    let (con, uifo) = match hello {
-        AuthRead::Anonymous => {
-            send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Anonymous).await?;
-            (Channel::new::<ServerCtx, TcpStream>(None, con), ANONYMOUS.clone())
-        }
-        AuthRead::Local => match &ctx.secctx {
-            SecCtx::Local(a) => {
-                let tok: BoundedBytes<TOKEN_MAX> =
-                    recv(ctx.cfg.hello_timeout, &mut con).await?;
-                let cred = a.0.authenticate(&*tok)?;
-                let uifo = a.1.write().users.ifo(ctx.id, Some(&cred.user))?;
-                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Local).await?;
-                (Channel::new::<ServerCtx, TcpStream>(None, con), uifo)
-            }
-            SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
-        },
-        AuthRead::Krb5 => match &ctx.secctx {
-            SecCtx::Krb5(a) => {
-                let k5ctx =
-                    krb5_authentication(ctx.cfg.hello_timeout, Some(&*a.0), &mut con)
-                        .await?;
-                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Krb5).await?;
-                let k5ctx = K5CtxWrap::new(k5ctx);
-                let con = Channel::new::<ServerCtx, TcpStream>(Some(k5ctx.clone()), con);
-                let uifo = a.1.write().users.ifo(
-                    ctx.id,
-                    Some(&task::block_in_place(|| k5ctx.lock().client())?),
-                )?;
-                (con, uifo)
-            }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
-        },
-        AuthRead::Tls => match &ctx.secctx {
-            SecCtx::Tls(a) => {
-                let tls = a.0.accept(con).await?;
-                let uifo = get_tls_uifo(ctx.id, &tls, a)?;
-                let mut con = Channel::new::<
-                    ServerCtx,
-                    tokio_rustls::server::TlsStream<TcpStream>,
-                >(None, tls);
-                time::timeout(ctx.cfg.hello_timeout, con.send_one(&AuthRead::Tls))
-                    .await??;
-                (con, uifo)
-            }
-            SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
-        },
+    let mech = match hello {
+        AuthRead::Anonymous => Mech::Anonymous,
+        AuthRead::Local => Mech::Local,
+        AuthRead::Krb5 => Mech::Krb5,
+        AuthRead::Tls => Mech::Tls,
     };
-    Ok(client_loop_read(ctx, con, server_stop, uifo).await?)
+    let start = Instant::now();
+    let res = async {
+        Ok::<_, anyhow::Error>(match hello {
+            AuthRead::Anonymous => {
+                send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Anonymous).await?;
+                (Channel::new::<ServerCtx, TcpStream>(None, con), ANONYMOUS.clone())
+            }
+            AuthRead::Local => match &ctx.secctx {
+                SecCtx::Local(a) => {
+                    let tok: BoundedBytes<TOKEN_MAX> =
+                        recv(ctx.cfg.hello_timeout, &mut con).await?;
+                    let cred = a.0.authenticate(&*tok)?;
+                    let uifo = a.1.write().users.ifo(ctx.id, Some(&cred.user))?;
+                    send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Local).await?;
+                    (Channel::new::<ServerCtx, TcpStream>(None, con), uifo)
+                }
+                SecCtx::Anonymous | SecCtx::Krb5(_) | SecCtx::Tls(_) => bail!(NO),
+            },
+            AuthRead::Krb5 => match &ctx.secctx {
+                SecCtx::Krb5(a) => {
+                    let k5ctx =
+                        krb5_authentication(ctx.cfg.hello_timeout, Some(&*a.0), &mut con)
+                            .await?;
+                    send(ctx.cfg.hello_timeout, &mut con, &AuthRead::Krb5).await?;
+                    let k5ctx = K5CtxWrap::new(k5ctx);
+                    let con =
+                        Channel::new::<ServerCtx, TcpStream>(Some(k5ctx.clone()), con);
+                    let uifo = a.1.write().users.ifo(
+                        ctx.id,
+                        Some(&task::block_in_place(|| k5ctx.lock().client())?),
+                    )?;
+                    (con, uifo)
+                }
+                SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Tls(_) => bail!(NO),
+            },
+            AuthRead::Tls => match &ctx.secctx {
+                SecCtx::Tls(a) => {
+                    let tls = a.0.accept(con).await?;
+                    let uifo = get_tls_uifo(ctx.id, &tls, a)?;
+                    let mut con = Channel::new::<
+                        ServerCtx,
+                        tokio_rustls::server::TlsStream<TcpStream>,
+                    >(None, tls);
+                    time::timeout(ctx.cfg.hello_timeout, con.send_one(&AuthRead::Tls))
+                        .await??;
+                    (con, uifo)
+                }
+                SecCtx::Anonymous | SecCtx::Local(_) | SecCtx::Krb5(_) => bail!(NO),
+            },
+        })
+    }
+    .await;
+    ctx.metrics.auth(mech, start.elapsed(), res.is_ok());
+    let source = Source { mech, addr };
+    ctx.audit.auth(source, &res, |(_, uifo)| &**uifo);
+    let (con, uifo) = res?;
+    Ok(client_loop_read(ctx, con, server_stop, source, uifo, caps).await?)
 }
 
 async fn hello_client(
     ctx: Arc<Ctx>,
     connection_id: CId,
     mut s: TcpStream,
-    server_stop: oneshot::Receiver<()>,
+    server_stop: oneshot::Receiver<Stop>,
 ) -> Result<()> {
     s.set_nodelay(true)?;
+    let addr = s.peer_addr()?;
     send(ctx.cfg.hello_timeout, &mut s, &3u64).await?;
     let version: u64 = recv(ctx.cfg.hello_timeout, &mut s).await?;
     if version != 3 {
@@ -779,16 +874,17 @@ async fn hello_client(
     }
     let hello: ClientHello = recv(ctx.cfg.hello_timeout, &mut s).await?;
     match hello {
-        ClientHello::ReadOnly(hello) => {
+        ClientHello::ReadOnly(hello, caps) => {
             if let Some(t) = ctx.delay_reads {
                 if Instant::now() < t {
                     bail!("no read clients allowed yet");
                 }
             }
-            Ok(hello_client_read(ctx, s, server_stop, hello).await?)
+            Ok(hello_client_read(ctx, s, server_stop, addr, hello, caps).await?)
         }
         ClientHello::WriteOnly(hello) => {
-            Ok(hello_client_write(ctx, connection_id, s, server_stop, hello).await?)
+            Ok(hello_client_write(ctx, connection_id, s, server_stop, addr, hello)
+                .await?)
         }
     }
 }
@@ -796,9 +892,10 @@ async fn hello_client(
 async fn server_loop(
     cfg: Config,
     delay_reads: bool,
-    stop: oneshot::Receiver<()>,
-    ready: oneshot::Sender<SocketAddr>,
+    stop: oneshot::Receiver<Stop>,
+    ready: oneshot::Sender<(SocketAddr, Option<SocketAddr>)>,
     id: usize,
+    audit: Option<Arc<dyn AuditSink>>,
 ) -> Result<()> {
     debug!("server task start I am id: {}", id);
     let member = cfg.member_servers[id].clone();
@@ -809,16 +906,29 @@ async fn server_loop(
     debug!("creating security context");
     let secctx = SecCtx::new(&cfg, &member).await?;
     debug!("creating resolver store");
+    let metrics = Arc::new(Metrics::default());
+    let audit = Audit::new(&member.audit, audit)?;
     let store = Store::new(
         cfg.parent.clone().map(|s| s.into()),
         cfg.children.iter().map(|(p, s)| (p.clone(), s.clone().into())).collect(),
         secctx.clone(),
         id,
+        metrics.clone(),
+        audit.clone(),
     );
     debug!("creating tcp listener on {:?}", id);
     let listener = TcpListener::bind(id).await?;
     let listen_addr = listener.local_addr()?;
     debug!("my listen addr is {:?}", listen_addr);
+    let (_metrics_stop, metrics_addr) = match member.metrics_addr {
+        None => (None, None),
+        Some(addr) => {
+            let (tx, rx) = oneshot::channel();
+            let addr = metrics::serve(metrics.clone(), addr, rx).await?;
+            debug!("serving metrics on {:?}", addr);
+            (Some(tx), Some(addr))
+        }
+    };
     let ctx = Arc::new(Ctx {
         cfg: member,
         secctx,
@@ -828,26 +938,24 @@ async fn server_loop(
         delay_reads,
         listen_addr,
         store,
+        metrics,
+        audit,
     });
     let mut stop = stop.fuse();
-    let mut client_stops: Vec<oneshot::Sender<()>> = Vec::new();
+    let mut client_stops: Vec<oneshot::Sender<Stop>> = Vec::new();
     let max_connections = ctx.cfg.max_connections;
     debug!("signaling ready");
-    let _ = ready.send(ctx.listen_addr);
-    loop {
+    let _ = ready.send((ctx.listen_addr, metrics_addr));
+    let how = loop {
         select_biased! {
-            _ = stop => {
-                for cl in client_stops.drain(..) {
-                    let _ = cl.send(());
-                }
-                return Ok(())
-            },
+            s = stop => break s.unwrap_or(Stop::Now),
             cl = listener.accept().fuse() => match cl {
                 Err(e) => warn!("accept failed: {}", e),
                 Ok((client, _)) => {
                     let (tx, rx) = oneshot::channel();
                     client_stops.push(tx);
                     let connection_id = ctx.ctracker.open();
+                    ctx.metrics.clients.fetch_add(1, Ordering::Relaxed);
                     task::spawn({
                         let ctx = Arc::clone(&ctx);
                         async move {
@@ -858,6 +966,7 @@ async fn server_loop(
                                 rx
                             ).await;
                             ctx.ctracker.close(connection_id);
+                            ctx.metrics.clients.fetch_sub(1, Ordering::Relaxed);
                             info!("server_loop client shutting down {:?}", r);
                         }
                     });
@@ -868,43 +977,100 @@ async fn server_loop(
                 }
             },
         }
+    };
+    drop(listener);
+    for cl in client_stops.drain(..) {
+        let _ = cl.send(how);
+    }
+    if let Stop::Drain(timeout) = how {
+        info!("draining {} connections", ctx.ctracker.num_open());
+        let deadline = Instant::now() + timeout;
+        while ctx.ctracker.num_open() > 0 && Instant::now() < deadline {
+            time::sleep(Duration::from_millis(10u64)).await;
+        }
     }
+    Ok(())
 }
 
 #[derive(Debug)]
 pub struct Server {
-    stop: Option<oneshot::Sender<()>>,
+    stop: Option<oneshot::Sender<Stop>>,
+    task: Option<task::JoinHandle<Result<()>>>,
     local_addr: SocketAddr,
+    metrics_addr: Option<SocketAddr>,
 }
 
 impl Drop for Server {
     fn drop(&mut self) {
         if let Some(stop) = mem::replace(&mut self.stop, None) {
-            let _ = stop.send(());
+            let _ = stop.send(Stop::Now);
         }
     }
 }
 
 impl Server {
     pub async fn new(cfg: Config, delay_reads: bool, id: usize) -> Result<Server> {
+        Self::start(cfg, delay_reads, id, None).await
+    }
+
+    /// Like `new`, but send the audit log to `sink` instead of
+    /// where the member server config says.
+    pub async fn with_audit_sink(
+        cfg: Config,
+        delay_reads: bool,
+        id: usize,
+        sink: Arc<dyn AuditSink>,
+    ) -> Result<Server> {
+        Self::start(cfg, delay_reads, id, Some(sink)).await
+    }
+
+    async fn start(
+        cfg: Config,
+        delay_reads: bool,
+        id: usize,
+        audit: Option<Arc<dyn AuditSink>>,
+    ) -> Result<Server> {
         let (send_stop, recv_stop) = oneshot::channel();
         let (send_ready, recv_ready) = oneshot::channel();
-        let jh = task::spawn(async move {
-            let res = server_loop(cfg, delay_reads, recv_stop, send_ready, id).await;
+        let mut jh = task::spawn(async move {
+            let res =
+                server_loop(cfg, delay_reads, recv_stop, send_ready, id, audit).await;
             match &res {
                 Ok(_) => info!("resolver server shutdown"),
                 Err(e) => error!("resolver server failed {}", e),
             }
             res
         });
-        let local_addr = select_biased! {
-            _ = jh.fuse() => bail!("resolver server shutdown"),
+        let (local_addr, metrics_addr) = select_biased! {
+            _ = (&mut jh).fuse() => bail!("resolver server shutdown"),
             a = recv_ready.fuse() => a?,
         };
-        Ok(Server { stop: Some(send_stop), local_addr })
+        Ok(Server { stop: Some(send_stop), task: Some(jh), local_addr, metrics_addr })
+    }
+
+    /// Shut down gracefully, so that members of a cluster can be
+    /// restarted one at a time without clients noticing. The server
+    /// stops accepting connections, finishes answering the requests
+    /// it has received, tells read clients to move to another
+    /// member, and then waits up to `timeout` for its connections to
+    /// close. Dropping the server shuts it down immediately instead.
+    pub async fn shutdown(mut self, timeout: Duration) -> Result<()> {
+        if let Some(stop) = self.stop.take() {
+            let _ = stop.send(Stop::Drain(timeout));
+        }
+        match self.task.take() {
+            None => Ok(()),
+            Some(jh) => jh.await?,
+        }
     }
 
     pub fn local_addr(&self) -> &SocketAddr {
         &self.local_addr
     }
+
+    /// The address metrics are being served on, if `metrics_addr`
+    /// is set in the member server config.
+    pub fn metrics_addr(&self) -> Option<&SocketAddr> {
+        self.metrics_addr.as_ref()
+    }
 }
@@ -1,5 +1,7 @@
 use super::{
+    audit::{Audit, Source},
     auth::{Permissions, UserInfo},
+    metrics::Metrics,
     secctx::SecCtx,
     store::{self, COLS_POOL, MAX_READ_BATCH, MAX_WRITE_BATCH, PATH_POOL, REF_POOL},
 };
@@ -26,7 +28,7 @@ use futures::{
     prelude::*,
     select,
 };
-use fxhash::FxHashMap;
+use fxhash::{FxHashMap, FxHashSet};
 use log::info;
 use std::{
     collections::{hash_map::DefaultHasher, BTreeMap, HashMap, HashSet, VecDeque},
@@ -34,7 +36,7 @@ use std::{
     iter,
     net::SocketAddr,
     result,
-    sync::Arc,
+    sync::{atomic::Ordering, Arc},
     time::SystemTime,
 };
 use tokio::task;
@@ -59,8 +61,23 @@ lazy_static! {
     static ref WRITE_SHARD_BATCH: Pool<Vec<Pooled<WriteB>>> = Pool::new(1000, 1024);
 }
 
+/// The most publishers we will remember having sent to a caching
+/// client before starting a new epoch
+const MAX_CACHED_PUBLISHERS: usize = 100_000;
+
+/// Tracks the publishers a read client that supports the publisher
+/// cache already knows about, so we only send each one once per
+/// epoch.
+#[derive(Debug, Default)]
+pub(super) struct PublisherCache {
+    epoch: u64,
+    sent_epoch: bool,
+    known: FxHashSet<PublisherId>,
+}
+
 struct ReadRequest {
     uifo: Arc<UserInfo>,
+    source: Source,
     batch: Pooled<ReadB>,
 }
 
@@ -71,6 +88,8 @@ struct ReadResponse {
 
 struct WriteRequest {
     uifo: Arc<UserInfo>,
+    // None when the server itself is cleaning up after a publisher
+    source: Option<Source>,
     publisher: Arc<Publisher>,
     batch: Pooled<WriteB>,
 }
@@ -89,6 +108,8 @@ impl Shard {
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
         resolver: SocketAddr,
+        metrics: Arc<Metrics>,
+        audit: Audit,
     ) -> Self {
         let (read, read_rx) = unbounded();
         let (write, write_rx) = unbounded();
@@ -107,6 +128,7 @@ impl Shard {
                                 shard,
                                 &mut store,
                                 &secctx,
+                                &audit,
                                 resolver,
                                 req
                             );
@@ -116,11 +138,17 @@ impl Shard {
                     batch = write_rx.next() => match batch {
                         None => break,
                         Some((req, reply)) => {
+                            let paths = store.published_paths() as i64;
                             let r = Shard::process_write_batch(
+                                shard,
                                 &mut store,
                                 &secctx,
+                                &audit,
+                                resolver,
                                 req
                             );
+                            let delta = store.published_paths() as i64 - paths;
+                            metrics.paths.fetch_add(delta, Ordering::Relaxed);
                             let _ = reply.send(r);
                         }
                     },
@@ -141,6 +169,7 @@ impl Shard {
         shard: usize,
         store: &mut store::Store,
         secctx: &SecCtx,
+        audit: &Audit,
         resolver: SocketAddr,
         mut req: ReadRequest,
     ) -> ReadResponse {
@@ -152,8 +181,15 @@ impl Shard {
             batch: FROM_READ_POOL.take(),
         };
         let uifo = req.uifo;
+        let source = req.source;
         let secctx = secctx.read();
         let pmap = secctx.pmap();
+        // requests that go to every shard are only audited once
+        let deny = |path: &Path, action: &'static str| {
+            if shard == 0 {
+                audit.denied(source, &uifo, action, path)
+            }
+        };
         resp.batch.extend(req.batch.drain(..).map(|(id, m)| match m {
             ToRead::Resolve(path) => {
                 if let Some(r) = store.check_referral(&path) {
@@ -175,14 +211,26 @@ impl Shard {
                         Some(pmap) => {
                             let perm = pmap.permissions(&*path, &*uifo);
                             if !perm.contains(Permissions::SUBSCRIBE) {
+                                audit.denied(source, &uifo, "subscribe", &path);
                                 (id, FromRead::Denied)
                             } else {
+                                // a subscribe many of path is only
+                                // allowed if nothing beneath it
+                                // takes away any of perm
+                                let subtree = !store.delegates_beneath(&path)
+                                    && pmap.allowed_in_scope(
+                                        &*path,
+                                        &Scope::Subtree,
+                                        perm,
+                                        &*uifo,
+                                    );
                                 let (flags, publishers) = store.resolve_and_sign(
                                     &mut resp.publishers,
                                     &secctx,
                                     &uifo,
                                     now,
                                     perm,
+                                    subtree,
                                     &path,
                                 );
                                 let a = Resolved {
@@ -208,6 +256,7 @@ impl Shard {
                     if allowed {
                         (id, FromRead::List(store.list(&path)))
                     } else {
+                        deny(&path, "list");
                         (id, FromRead::Denied)
                     }
                 }
@@ -223,19 +272,18 @@ impl Shard {
                         )
                     }
                 }
-                let allowed = pmap
-                    .map(|pmap| {
-                        set.iter().all(|g| {
-                            pmap.allowed_in_scope(
-                                g.base(),
-                                g.scope(),
-                                Permissions::LIST,
-                                &*uifo,
-                            )
-                        })
+                let denied = pmap.and_then(|pmap| {
+                    set.iter().find(|g| {
+                        !pmap.allowed_in_scope(
+                            g.base(),
+                            g.scope(),
+                            Permissions::LIST,
+                            &*uifo,
+                        )
                     })
-                    .unwrap_or(true);
-                if !allowed {
+                });
+                if let Some(g) = denied {
+                    deny(&Path::from(String::from(g.base())), "list");
                     let lm = ListMatching { referrals, matched: PATH_BPOOL.take() };
                     (id, FromRead::ListMatching(lm))
                 } else {
@@ -271,6 +319,7 @@ impl Shard {
                         .map(|pmap| pmap.allowed(&*path, Permissions::LIST, &*uifo))
                         .unwrap_or(true);
                     if !allowed {
+                        deny(&path, "list");
                         (id, FromRead::Denied)
                     } else {
                         let rows = store.list(&path);
@@ -284,14 +333,27 @@ impl Shard {
     }
 
     fn process_write_batch(
+        shard: usize,
         store: &mut store::Store,
         secctx: &SecCtx,
+        audit: &Audit,
+        resolver: SocketAddr,
         mut req: WriteRequest,
     ) -> Pooled<WriteR> {
         let uifo = &*req.uifo;
+        let source = req.source;
         let publisher = req.publisher;
         let secctx = secctx.read();
         let pmap = secctx.pmap();
+        // requests that go to every shard are only audited once
+        let deny = |path: &Path, action: &'static str, all: bool| match source {
+            Some(src) if !all || shard == 0 => audit.denied(src, uifo, action, path),
+            Some(_) | None => (),
+        };
+        let admin = |path: &Path, action: &'static str| match source {
+            Some(src) if shard == 0 => audit.admin(src, uifo, action, path),
+            Some(_) | None => (),
+        };
         let publish = |s: &mut store::Store,
                        path: Path,
                        default: bool,
@@ -311,10 +373,34 @@ impl Shard {
                     s.publish(path, &publisher, default, flags);
                     FromWrite::Published
                 } else {
+                    let action = if default { "publish_default" } else { "publish" };
+                    deny(&path, action, default);
                     FromWrite::Denied
                 }
             }
         };
+        // a delegation belongs to the server that holds the parent of
+        // the delegated path, the delegated path itself may already
+        // be referred elsewhere.
+        let delegate = |s: &mut store::Store,
+                        path: &Path,
+                        action: &'static str|
+         -> Option<FromWrite> {
+            let parent = Path::from(String::from(Path::dirname(path).unwrap_or("/")));
+            if !Path::is_absolute(&**path) {
+                Some(FromWrite::Error("absolute paths required".into()))
+            } else if let Some(r) = s.check_referral(&parent) {
+                Some(FromWrite::Referral(r))
+            } else if !pmap
+                .map(|p| p.allowed(&**path, Permissions::DELEGATE, uifo))
+                .unwrap_or(true)
+            {
+                deny(path, action, true);
+                Some(FromWrite::Denied)
+            } else {
+                None
+            }
+        };
         let mut resp = FROM_WRITE_POOL.take();
         resp.extend(req.batch.drain(..).map(|(id, m)| match m {
             ToWrite::Heartbeat => unreachable!(),
@@ -350,6 +436,30 @@ impl Shard {
                     (id, FromWrite::Unpublished)
                 }
             }
+            ToWrite::Delegate(r) => match delegate(store, &r.path, "delegate") {
+                Some(m) => (id, m),
+                None => {
+                    let path = r.path.clone();
+                    match store.add_child(resolver, r) {
+                        Ok(()) => {
+                            admin(&path, "delegate");
+                            (id, FromWrite::Published)
+                        }
+                        Err(e) => (id, FromWrite::Error(e.into())),
+                    }
+                }
+            },
+            ToWrite::Undelegate(path) => match delegate(store, &path, "undelegate") {
+                Some(m) => (id, m),
+                None => {
+                    if store.remove_child(&path) {
+                        admin(&path, "undelegate");
+                        (id, FromWrite::Unpublished)
+                    } else {
+                        (id, FromWrite::Error("path is not delegated".into()))
+                    }
+                }
+            },
         }));
         resp
     }
@@ -371,6 +481,7 @@ macro_rules! same {
 pub(super) struct Store {
     shards: Vec<Shard>,
     shard_mask: usize,
+    metrics: Arc<Metrics>,
 }
 
 impl Store {
@@ -379,16 +490,26 @@ impl Store {
         children: BTreeMap<Path, Referral>,
         secctx: SecCtx,
         resolver: SocketAddr,
+        metrics: Arc<Metrics>,
+        audit: Audit,
     ) -> Self {
         let shards = std::cmp::max(1, num_cpus::get().next_power_of_two());
         let shard_mask = shards - 1;
         let shards = (0..shards)
             .into_iter()
             .map(|i| {
-                Shard::new(i, parent.clone(), children.clone(), secctx.clone(), resolver)
+                Shard::new(
+                    i,
+                    parent.clone(),
+                    children.clone(),
+                    secctx.clone(),
+                    resolver,
+                    metrics.clone(),
+                    audit.clone(),
+                )
             })
             .collect();
-        Store { shards, shard_mask }
+        Store { shards, shard_mask, metrics }
     }
 
     fn shard(&self, path: &Path) -> usize {
@@ -412,7 +533,9 @@ impl Store {
     pub(super) async fn handle_batch_read(
         &self,
         con: &mut Channel,
+        source: Source,
         uifo: Arc<UserInfo>,
+        mut cache: Option<&mut PublisherCache>,
         mut msgs: impl Iterator<Item = ToRead>,
     ) -> Result<()> {
         let mut finished = false;
@@ -427,6 +550,7 @@ impl Store {
                         break;
                     }
                     Some(ToRead::Resolve(path)) => {
+                        self.metrics.resolves.fetch_add(1, Ordering::Relaxed);
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToRead::Resolve(path)));
                         c += 1;
@@ -438,6 +562,7 @@ impl Store {
                         c += 1;
                     }
                     Some(ToRead::List(path)) => {
+                        self.metrics.lists.fetch_add(1, Ordering::Relaxed);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToRead::List(path.clone())));
                         }
@@ -450,6 +575,7 @@ impl Store {
                         c += 10000;
                     }
                     Some(ToRead::ListMatching(set)) => {
+                        self.metrics.lists.fetch_add(1, Ordering::Relaxed);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToRead::ListMatching(set.clone())));
                         }
@@ -465,7 +591,7 @@ impl Store {
             let mut replies =
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
-                    let req = ReadRequest { uifo: uifo.clone(), batch };
+                    let req = ReadRequest { uifo: uifo.clone(), source, batch };
                     let _ = self.shards[i].read.unbounded_send((req, tx));
                     rx
                 }))
@@ -476,8 +602,29 @@ impl Store {
             for r in replies.iter_mut() {
                 publishers.extend(r.publishers.drain());
             }
-            for (_, p) in publishers.drain() {
-                con.queue_send(&FromRead::Publisher(p))?;
+            match &mut cache {
+                None => {
+                    for (_, p) in publishers.drain() {
+                        con.queue_send(&FromRead::Publisher(p))?;
+                    }
+                }
+                Some(cache) => {
+                    if !cache.sent_epoch
+                        || cache.known.len() + publishers.len() > MAX_CACHED_PUBLISHERS
+                    {
+                        if cache.sent_epoch {
+                            cache.epoch += 1;
+                            cache.known.clear();
+                        }
+                        cache.sent_epoch = true;
+                        con.queue_send(&FromRead::PublisherEpoch(cache.epoch))?;
+                    }
+                    for (id, p) in publishers.drain() {
+                        if cache.known.insert(id) {
+                            con.queue_send(&FromRead::Publisher(p))?;
+                        }
+                    }
+                }
             }
             let mut replies = {
                 let mut r = REPLIES.take();
@@ -506,6 +653,8 @@ impl Store {
                     match replies[0].pop_front().unwrap() {
                         (_, FromRead::Publisher(_)) => unreachable!(),
                         (_, FromRead::Resolved(_)) => unreachable!(),
+                        (_, FromRead::PublisherEpoch(_)) => unreachable!(),
+                        (_, FromRead::Shutdown) => unreachable!(),
                         (_, m @ FromRead::Referral(_)) => {
                             same!(con, replies, &m, "desynced referral");
                         }
@@ -609,6 +758,7 @@ impl Store {
     pub(super) async fn handle_batch_write(
         &self,
         mut con: Option<&mut Channel>,
+        source: Option<Source>,
         uifo: Arc<UserInfo>,
         publisher: Arc<Publisher>,
         mut msgs: impl Iterator<Item = ToWrite>,
@@ -630,28 +780,34 @@ impl Store {
                         }
                     }
                     Some(ToWrite::Publish(path)) => {
+                        self.metrics.publishes.fetch_add(1, Ordering::Relaxed);
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::Publish(path)));
                     }
                     Some(ToWrite::Unpublish(path)) => {
+                        self.metrics.unpublishes.fetch_add(1, Ordering::Relaxed);
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::Unpublish(path)));
                     }
                     Some(ToWrite::UnpublishDefault(path)) => {
+                        self.metrics.unpublishes.fetch_add(1, Ordering::Relaxed);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::UnpublishDefault(path.clone())));
                         }
                     }
                     Some(ToWrite::PublishDefault(path)) => {
+                        self.metrics.publishes.fetch_add(1, Ordering::Relaxed);
                         for b in by_shard.iter_mut() {
                             b.push((n, ToWrite::PublishDefault(path.clone())));
                         }
                     }
                     Some(ToWrite::PublishWithFlags(path, flags)) => {
+                        self.metrics.publishes.fetch_add(1, Ordering::Relaxed);
                         let s = self.shard(&path);
                         by_shard[s].push((n, ToWrite::PublishWithFlags(path, flags)));
                     }
                     Some(ToWrite::PublishDefaultWithFlags(path, flags)) => {
+                        self.metrics.publishes.fetch_add(1, Ordering::Relaxed);
                         for b in by_shard.iter_mut() {
                             b.push((
                                 n,
@@ -659,6 +815,11 @@ impl Store {
                             ));
                         }
                     }
+                    Some(m @ (ToWrite::Delegate(_) | ToWrite::Undelegate(_))) => {
+                        for b in by_shard.iter_mut() {
+                            b.push((n, m.clone()));
+                        }
+                    }
                 }
                 n += 1;
             }
@@ -670,7 +831,8 @@ impl Store {
                 join_all(by_shard.drain(..).enumerate().map(|(i, batch)| {
                     let (tx, rx) = oneshot::channel();
                     let publisher = publisher.clone();
-                    let req = WriteRequest { uifo: uifo.clone(), publisher, batch };
+                    let req =
+                        WriteRequest { uifo: uifo.clone(), source, publisher, batch };
                     let _ = self.shards[i].write.unbounded_send((req, tx));
                     rx
                 }))
@@ -741,11 +903,12 @@ impl Store {
         published_paths.shuffle(&mut thread_rng());
         let iter = published_paths.into_iter();
         // clear the vast majority of published paths using resources fairly
-        self.handle_batch_write(None, uifo.clone(), publisher.clone(), iter).await?;
+        self.handle_batch_write(None, None, uifo.clone(), publisher.clone(), iter)
+            .await?;
         // clear out anything left over that was sent to all shards,
         // e.g. default publishers.
-        self.handle_batch_write(None, uifo, publisher, iter::once(ToWrite::Clear))
-            .await?;
+        let clear = iter::once(ToWrite::Clear);
+        self.handle_batch_write(None, None, uifo, publisher, clear).await?;
         Ok(())
     }
 }
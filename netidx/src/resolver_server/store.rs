@@ -1,5 +1,5 @@
 use super::{
-    auth::{Permissions, UserInfo},
+    auth::{Permissions, UserInfo, SUBTREE},
     secctx::SecCtxDataReadGuard,
 };
 use crate::{
@@ -28,6 +28,7 @@ use std::{
     hash::Hash,
     iter::{self, FromIterator},
     net::SocketAddr,
+    result,
     sync::Arc,
 };
 
@@ -252,6 +253,18 @@ impl Store {
         }
     }
 
+    /// true if part of the subtree beneath `path` is delegated to a
+    /// child
+    pub(super) fn delegates_beneath(&self, path: &Path) -> bool {
+        self.children
+            .range::<str, (Bound<&str>, Bound<&str>)>((
+                Excluded(path.as_ref()),
+                Unbounded,
+            ))
+            .take_while(|(p, _)| p.starts_with(path.as_ref()))
+            .any(|(p, _)| Path::is_parent(path, p))
+    }
+
     pub(super) fn referrals_in_scope<T: AsRef<str> + ?Sized>(
         &self,
         refs: &mut Vec<Referral>,
@@ -277,6 +290,55 @@ impl Store {
         }
     }
 
+    /// Delegate the subtree at `r.path` to the servers in `r` at run
+    /// time, replacing any existing delegation of the same path. The
+    /// same rules apply as for children in the config file.
+    pub(super) fn add_child(
+        &mut self,
+        us: SocketAddr,
+        r: Referral,
+    ) -> result::Result<(), &'static str> {
+        let root = self.parent.as_ref().map(|r| r.path.as_ref()).unwrap_or("/");
+        if !Path::is_absolute(&r.path) {
+            return Err("absolute paths required");
+        }
+        if !Path::is_parent(root, &r.path) || Path::levels(&r.path) <= Path::levels(root)
+        {
+            return Err("delegated paths must be below the root");
+        }
+        if r.ttl.is_none() || r.ttl == Some(0) {
+            return Err("delegations require a non zero ttl");
+        }
+        if r.addrs.is_empty() {
+            return Err("delegations require at least one server");
+        }
+        if r.addrs.iter().any(|(a, _)| *a == us) {
+            return Err("can't delegate to ourself");
+        }
+        let nested = self.children.keys().any(|c| {
+            c != &r.path && (Path::is_parent(c, &r.path) || Path::is_parent(&r.path, c))
+        });
+        if nested {
+            return Err("delegations may not be nested");
+        }
+        let path = r.path.clone();
+        self.children.insert(path.clone(), r);
+        self.add_parents(path.append("z").as_ref());
+        Ok(())
+    }
+
+    /// Remove the delegation of `path`, returns false if it wasn't
+    /// delegated
+    pub(super) fn remove_child(&mut self, path: &Path) -> bool {
+        match self.children.remove(path) {
+            None => false,
+            Some(_) => {
+                self.remove_parents(path.as_ref());
+                true
+            }
+        }
+    }
+
     fn add_column<S: AsRef<str>>(&mut self, path: &S) {
         let (root, name) = match column_path_parts(path) {
             None => return,
@@ -472,6 +534,10 @@ impl Store {
         }
     }
 
+    pub(super) fn published_paths(&self) -> usize {
+        self.published_by_path.len()
+    }
+
     pub(super) fn published_for_id(&self, id: &PublisherId) -> HashSet<Path> {
         self.published_by_id.get(id).map(|s| s.clone()).unwrap_or_else(HashSet::new)
     }
@@ -543,7 +609,11 @@ impl Store {
                 let mut pubs = SIGNED_PUBS_POOL.take();
                 let refs = ids.into_iter().map(|id| {
                     self.record_publisher(sec, publishers, id);
-                    PublisherRef { id: *id, token: Bytes::new() }
+                    PublisherRef {
+                        id: *id,
+                        token: Bytes::new(),
+                        subtree_token: Bytes::new(),
+                    }
                 });
                 pubs.extend(refs);
                 (self.get_flags(p.as_ref()), pubs)
@@ -564,7 +634,11 @@ impl Store {
                 if pubs.len() == 0 {
                     pubs.extend(ids.into_iter().map(|id| {
                         self.record_publisher(None, publishers, id);
-                        PublisherRef { id: *id, token: Bytes::new() }
+                        PublisherRef {
+                            id: *id,
+                            token: Bytes::new(),
+                            subtree_token: Bytes::new(),
+                        }
                     }));
                     self.get_flags(path.as_ref())
                 } else {
@@ -572,7 +646,14 @@ impl Store {
                     by_id.extend(pubs.drain(..).map(|r| (r.id, r)));
                     by_id.extend(ids.into_iter().map(|id| {
                         self.record_publisher(None, publishers, id);
-                        (*id, PublisherRef { id: *id, token: Bytes::new() })
+                        (
+                            *id,
+                            PublisherRef {
+                                id: *id,
+                                token: Bytes::new(),
+                                subtree_token: Bytes::new(),
+                            },
+                        )
                     }));
                     pubs.extend(by_id.drain().map(|(_, r)| r));
                     self.get_flags(path.as_ref())
@@ -582,6 +663,8 @@ impl Store {
         (flags, pubs)
     }
 
+    // like resolve, but sign a token granting `perm` at `path` for
+    // each publisher, and a subtree token as well if `subtree`
     pub(super) fn resolve_and_sign(
         &self,
         publishers: &mut FxHashMap<PublisherId, Publisher>,
@@ -589,6 +672,7 @@ impl Store {
         uifo: &UserInfo,
         now: u64,
         perm: Permissions,
+        subtree: bool,
         path: &Path,
     ) -> (u32, Pooled<Vec<PublisherRef>>) {
         let sign = |id: PublisherId| {
@@ -599,16 +683,28 @@ impl Store {
                 SecCtxDataReadGuard::Tls(sec) => sec.secret(&id),
             };
             match secret {
-                None => PublisherRef { id, token: Bytes::new() },
-                Some(secret) => PublisherRef {
-                    id,
-                    token: utils::make_sha3_token([
-                        &secret.to_be_bytes(),
-                        &now.to_be_bytes()[..],
-                        &perm.bits().to_be_bytes(),
-                        path.as_bytes(),
-                    ]),
-                },
+                None => {
+                    PublisherRef { id, token: Bytes::new(), subtree_token: Bytes::new() }
+                }
+                Some(secret) => {
+                    let token = |bits: u32| {
+                        utils::make_sha3_token([
+                            &secret.to_be_bytes(),
+                            &now.to_be_bytes()[..],
+                            &bits.to_be_bytes(),
+                            path.as_bytes(),
+                        ])
+                    };
+                    PublisherRef {
+                        id,
+                        token: token(perm.bits()),
+                        subtree_token: if subtree {
+                            token(perm.bits() | SUBTREE)
+                        } else {
+                            Bytes::new()
+                        },
+                    }
+                }
             }
         };
         let (flags, mut pubs) = self.resolve_default(Some((sec, uifo)), publishers, path);
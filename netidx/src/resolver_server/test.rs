@@ -39,6 +39,7 @@ fn test_resolver_store() {
             resolver: addr,
             target_auth: TargetAuth::Anonymous,
             user_info: None,
+            alt_addr: None,
         });
         if thread_rng().gen() {
             let path = Path::from(String::from(Path::dirname(&parsed[0]).unwrap()));
@@ -48,11 +49,11 @@ fn test_resolver_store() {
         publishers.insert(addr, publisher.clone());
         for path in parsed.clone() {
             store.publish(path.clone(), &publisher, false, None);
-            if !store
-                .resolve(&mut HashMap::default(), &path)
-                .1
-                .contains(&PublisherRef { id: publisher.id, token: Bytes::new() })
-            {
+            if !store.resolve(&mut HashMap::default(), &path).1.contains(&PublisherRef {
+                id: publisher.id,
+                token: Bytes::new(),
+                subtree_token: Bytes::new(),
+            }) {
                 panic!()
             }
             if thread_rng().gen() {
@@ -116,11 +117,11 @@ fn test_resolver_store() {
     for path in parsed.clone() {
         let publisher = &publishers[&addr];
         store.unpublish(publisher, false, path.clone());
-        if store
-            .resolve(&mut HashMap::default(), &path)
-            .1
-            .contains(&PublisherRef { id: publisher.id, token: Bytes::new() })
-        {
+        if store.resolve(&mut HashMap::default(), &path).1.contains(&PublisherRef {
+            id: publisher.id,
+            token: Bytes::new(),
+            subtree_token: Bytes::new(),
+        }) {
             panic!()
         }
         if rand::thread_rng().gen_bool(0.5) {
@@ -167,11 +168,11 @@ fn test_resolver_store() {
         let publisher = &publishers[&addr];
         for path in parsed.clone() {
             store.unpublish(publisher, false, path.clone());
-            if store
-                .resolve(&mut HashMap::default(), &path)
-                .1
-                .contains(&PublisherRef { id: publisher.id, token: Bytes::new() })
-            {
+            if store.resolve(&mut HashMap::default(), &path).1.contains(&PublisherRef {
+                id: publisher.id,
+                token: Bytes::new(),
+                subtree_token: Bytes::new(),
+            }) {
                 panic!()
             }
         }
@@ -181,3 +182,113 @@ fn test_resolver_store() {
     let cols = store.columns(&Path::from("/app/test"));
     assert_eq!(cols.len(), 0);
 }
+
+#[test]
+fn test_acl() {
+    use super::{
+        acl::{self, Acl, Permissions},
+        config::Config,
+    };
+    let cfg = Config::parse(
+        r#"{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": "Anonymous"
+    }
+  ],
+  "perms": {
+    "/": { "ops": "swlpd", "": "sl" },
+    "/app": { "ops": "sw", "eric": "p" },
+    "/app/secret": { "": "!s", "eric": "!w" },
+    "/app/secret/x": { "eric": "!p", "bob": "" }
+  }
+}"#,
+    )
+    .unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut acl = Acl::new(&cfg, 0).unwrap();
+        let eric = acl.user_with_groups("eric", "eric", ["ops"]);
+        let e = acl.explain(&eric, "/app/foo", Permissions::PUBLISH);
+        assert!(e.allowed());
+        assert_eq!(e.steps.len(), 3);
+        let e = acl.explain(&eric, "/app/secret/y", Permissions::WRITE);
+        assert!(!e.allowed());
+        assert_eq!(e.steps.last().unwrap().path.as_ref(), "/app/secret");
+        assert_eq!(&*e.steps.last().unwrap().entity, "eric");
+        let anon = acl.anonymous();
+        let e = acl.explain(&anon, "/app/secret", Permissions::SUBSCRIBE);
+        assert!(!e.allowed());
+        assert_eq!(e.effective, Permissions::LIST);
+    });
+    let mut lints = acl::lint(&cfg)
+        .into_iter()
+        .map(|l| (l.path.to_string(), l.entity.map(|e| e.to_string())))
+        .collect::<Vec<_>>();
+    lints.sort();
+    assert_eq!(
+        lints,
+        vec![
+            ("/app".into(), Some("ops".into())),
+            ("/app/secret/x".into(), Some("bob".into())),
+        ]
+    );
+}
+
+#[test]
+fn test_subtree_deny() {
+    use super::{
+        auth::{PMap, Permissions, UserDb},
+        config::Config,
+    };
+    use crate::{os::Mapper, protocol::glob::Scope};
+    let cfg = Config::parse(
+        r#"{
+  "parent": null,
+  "children": [],
+  "member_servers": [
+    {
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": "Anonymous"
+    }
+  ],
+  "perms": {
+    "/": { "": "slw" },
+    "/app-old": { "": "p" },
+    "/app/a/b/c": { "": "!s" },
+    "/app/b": { "": "!l" }
+  }
+}"#,
+    )
+    .unwrap();
+    let rt = tokio::runtime::Runtime::new().unwrap();
+    rt.block_on(async {
+        let mut db = UserDb::new(Mapper::new(&cfg, &cfg.member_servers[0]).unwrap());
+        let pmap =
+            PMap::from_file(&cfg.perms, &mut db, cfg.root(), &cfg.children).unwrap();
+        let anon = db.ifo("127.0.0.1:0".parse().unwrap(), None).unwrap();
+        let s = Permissions::SUBSCRIBE;
+        let l = Permissions::LIST;
+        assert!(pmap.allowed("/app", s | l, &anon));
+        // the deny is found even though /app-old sorts before it
+        assert!(!pmap.allowed_in_scope("/app", &Scope::Subtree, s, &anon));
+        assert!(pmap.allowed_in_scope("/app/a/b/d", &Scope::Subtree, s, &anon));
+        assert!(pmap.allowed_in_scope("/app", &Scope::Finite(3), s, &anon));
+        // and out of scope denies don't hide the ones that are in scope
+        assert!(!pmap.allowed_in_scope("/app", &Scope::Finite(2), l, &anon));
+        assert!(pmap.allowed_in_scope("/app", &Scope::Finite(1), s | l, &anon));
+    });
+}
@@ -0,0 +1,423 @@
+//! An optional shared memory transport for connections between a
+//! subscriber and a publisher on the same host.
+//!
+//! The subscriber creates a segment containing two single producer
+//! single consumer byte rings, one in each direction, and offers it
+//! in it's hello. If the publisher can open it, it echoes the offer
+//! back and both sides build their `Channel` on a `ShmStream` instead
+//! of the tcp stream. Everything above the byte stream, framing,
+//! batching, and the publisher protocol, is unchanged.
+//!
+//! The tcp connection is kept open for the life of the stream. It
+//! carries wakeups, a side that finds it's ring empty (or full) sets
+//! a waiting flag in the segment, and the other side writes a byte to
+//! the socket when it sees the flag. At high message rates nobody is
+//! waiting, so no syscalls are made. It also tells us when the other
+//! process is gone.
+use crate::{channel::Channel, os::shm::Mapping, protocol::publisher::ShmOffer};
+use anyhow::{bail, Result};
+use arcstr::ArcStr;
+use cross_krb5::K5Ctx;
+use futures::{channel::oneshot, prelude::*, select_biased, task::AtomicWaker};
+use log::{debug, info};
+use rand::Rng;
+use std::{
+    cmp::min,
+    fmt::Debug,
+    io, mem,
+    pin::Pin,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+};
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    net::TcpStream,
+    task,
+};
+
+const MAGIC: u64 = 0x6e65746964780001;
+const HEADER: usize = 4096;
+
+// every segment is named this followed by a random hex id
+const PREFIX: &str = "/netidx-";
+
+/// The smallest ring size that will be offered or accepted
+pub const MIN_RING: usize = 1 << 16;
+
+/// The largest ring size that will be offered or accepted
+pub const MAX_RING: usize = 1 << 30;
+
+// keep the two sides of each ring on separate cache lines
+#[derive(Debug)]
+#[repr(C, align(128))]
+struct Padded(AtomicU64);
+
+#[derive(Debug)]
+#[repr(C)]
+struct Ring {
+    // total bytes ever written, only the writer stores
+    head: Padded,
+    // total bytes ever read, only the reader stores
+    tail: Padded,
+    reader_waiting: AtomicU32,
+    writer_waiting: AtomicU32,
+    closed: AtomicU32,
+}
+
+#[derive(Debug)]
+#[repr(C)]
+struct Header {
+    magic: AtomicU64,
+    ring_size: AtomicU64,
+    // ring 0 carries subscriber -> publisher, ring 1 the reverse
+    rings: [Ring; 2],
+}
+
+const _: () = assert!(mem::size_of::<Header>() <= HEADER);
+
+// the number of streams that have been started, so tests can tell
+// that shared memory was actually used
+#[cfg(test)]
+pub(crate) static STARTED: std::sync::atomic::AtomicUsize =
+    std::sync::atomic::AtomicUsize::new(0);
+
+fn segment_len(ring_size: usize) -> usize {
+    HEADER + 2 * ring_size
+}
+
+/// true if `con` connects two sockets on the same host
+pub(crate) fn is_local(con: &TcpStream) -> bool {
+    match (con.local_addr(), con.peer_addr()) {
+        (Ok(l), Ok(p)) => l.ip() == p.ip(),
+        (_, _) => false,
+    }
+}
+
+// removes the name of a segment we created when dropped
+#[derive(Debug)]
+struct Unlink(ArcStr);
+
+impl Drop for Unlink {
+    fn drop(&mut self) {
+        Mapping::unlink(&self.0)
+    }
+}
+
+/// A mapped segment that has not yet been turned into a stream
+#[derive(Debug)]
+pub(crate) struct Segment {
+    map: Mapping,
+    name: ArcStr,
+    ring_size: usize,
+    creator: Option<Unlink>,
+}
+
+impl Segment {
+    fn header(&self) -> &Header {
+        unsafe { &*(self.map.as_ptr() as *const Header) }
+    }
+
+    /// Create a segment to offer to a publisher. `ring_size` is
+    /// rounded up to a power of 2 between `MIN_RING` and `MAX_RING`.
+    pub(crate) fn create(ring_size: usize) -> Result<Segment> {
+        let ring_size = ring_size.clamp(MIN_RING, MAX_RING).next_power_of_two();
+        let id = rand::thread_rng().gen::<u64>();
+        let name = ArcStr::from(format!("{}{:x}", PREFIX, id));
+        let map = Mapping::create(&name, segment_len(ring_size))?;
+        let creator = Some(Unlink(name.clone()));
+        let seg = Segment { map, name, ring_size, creator };
+        let hdr = seg.header();
+        hdr.ring_size.store(ring_size as u64, Ordering::Relaxed);
+        hdr.magic.store(MAGIC, Ordering::Release);
+        Ok(seg)
+    }
+
+    /// Open a segment offered by a subscriber. The offer comes from
+    /// an untrusted peer, so only names that `create` could have made
+    /// are opened, and the name is only unlinked once the header
+    /// shows it really is a segment.
+    pub(crate) fn open(offer: &ShmOffer) -> Result<Segment> {
+        let valid_name = match offer.name.strip_prefix(PREFIX) {
+            None => false,
+            Some(id) => !id.is_empty() && id.chars().all(|c| c.is_ascii_hexdigit()),
+        };
+        if !valid_name {
+            bail!("invalid shared memory segment name {}", offer.name)
+        }
+        let ring_size = offer.ring_size as usize;
+        if !ring_size.is_power_of_two() || ring_size < MIN_RING || ring_size > MAX_RING {
+            bail!("invalid ring size {}", ring_size)
+        }
+        let map = Mapping::open(&offer.name, segment_len(ring_size))?;
+        let seg = Segment { map, name: offer.name.clone(), ring_size, creator: None };
+        let hdr = seg.header();
+        if hdr.magic.load(Ordering::Acquire) != MAGIC
+            || hdr.ring_size.load(Ordering::Relaxed) != offer.ring_size
+        {
+            bail!("invalid shared memory segment header")
+        }
+        // the name was only needed to find the segment
+        Mapping::unlink(&offer.name);
+        Ok(seg)
+    }
+
+    pub(crate) fn offer(&self) -> ShmOffer {
+        ShmOffer { name: self.name.clone(), ring_size: self.ring_size as u64 }
+    }
+
+    /// Start using the segment as the transport for the connection
+    /// `con`.
+    pub(crate) fn stream(self, con: TcpStream) -> ShmStream {
+        let Segment { map, name: _, ring_size, creator } = self;
+        // the creator writes ring 0, and the name is no longer needed
+        let (tx, rx) = if creator.is_some() { (0, 1) } else { (1, 0) };
+        drop(creator);
+        let shared = Arc::new(Shared {
+            map,
+            ring_size,
+            tx,
+            rx,
+            con,
+            read_waker: AtomicWaker::new(),
+            write_waker: AtomicWaker::new(),
+            peer_gone: AtomicBool::new(false),
+        });
+        let (stop, stop_rx) = oneshot::channel();
+        task::spawn(wakeups(shared.clone(), stop_rx));
+        #[cfg(test)]
+        STARTED.fetch_add(1, Ordering::Relaxed);
+        ShmStream { shared, _stop: stop }
+    }
+}
+
+#[derive(Debug)]
+struct Shared {
+    map: Mapping,
+    ring_size: usize,
+    tx: usize,
+    rx: usize,
+    con: TcpStream,
+    read_waker: AtomicWaker,
+    write_waker: AtomicWaker,
+    peer_gone: AtomicBool,
+}
+
+impl Shared {
+    fn ring(&self, i: usize) -> &Ring {
+        unsafe { &(*(self.map.as_ptr() as *const Header)).rings[i] }
+    }
+
+    fn data(&self, i: usize) -> *mut u8 {
+        unsafe { self.map.as_ptr().add(HEADER + i * self.ring_size) }
+    }
+
+    fn notify(&self) {
+        // if the socket buffer is full the peer already has plenty
+        // of wakeups pending
+        let _ = self.con.try_write(&[0]);
+    }
+
+    fn wake(&self) {
+        self.read_waker.wake();
+        self.write_waker.wake();
+    }
+
+    fn corrupt() -> io::Error {
+        io::Error::new(io::ErrorKind::InvalidData, "shared memory ring is corrupt")
+    }
+}
+
+async fn wakeups(shared: Arc<Shared>, stop: oneshot::Receiver<()>) {
+    let mut stop = stop.fuse();
+    let mut buf = [0u8; 64];
+    loop {
+        select_biased! {
+            _ = stop => break,
+            r = shared.con.readable().fuse() => match r {
+                Err(_) => break,
+                Ok(()) => match shared.con.try_read(&mut buf) {
+                    Ok(0) => break,
+                    Ok(_) => shared.wake(),
+                    Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+                    Err(_) => break,
+                }
+            }
+        }
+    }
+    debug!("shared memory wakeup task stopped");
+    shared.peer_gone.store(true, Ordering::SeqCst);
+    shared.wake();
+}
+
+/// A byte stream over a pair of shared memory rings
+#[derive(Debug)]
+pub(crate) struct ShmStream {
+    shared: Arc<Shared>,
+    _stop: oneshot::Sender<()>,
+}
+
+impl Drop for ShmStream {
+    fn drop(&mut self) {
+        self.shared.ring(self.shared.tx).closed.store(1, Ordering::SeqCst);
+        self.shared.notify();
+    }
+}
+
+impl AsyncRead for ShmStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        let sh = &*self.shared;
+        let ring = sh.ring(sh.rx);
+        let size = sh.ring_size as u64;
+        let mut waiting = false;
+        loop {
+            let tail = ring.tail.0.load(Ordering::Relaxed);
+            let avail = ring.head.0.load(Ordering::SeqCst).wrapping_sub(tail);
+            if avail > size {
+                return Poll::Ready(Err(Shared::corrupt()));
+            }
+            if avail > 0 {
+                if waiting {
+                    ring.reader_waiting.store(0, Ordering::Relaxed);
+                }
+                let n = min(avail as usize, buf.remaining());
+                let pos = (tail % size) as usize;
+                let first = min(n, sh.ring_size - pos);
+                unsafe {
+                    let data = sh.data(sh.rx);
+                    buf.put_slice(std::slice::from_raw_parts(data.add(pos), first));
+                    buf.put_slice(std::slice::from_raw_parts(data, n - first));
+                }
+                ring.tail.0.store(tail + n as u64, Ordering::SeqCst);
+                if ring.writer_waiting.swap(0, Ordering::SeqCst) != 0 {
+                    sh.notify()
+                }
+                break Poll::Ready(Ok(()));
+            }
+            if ring.closed.load(Ordering::SeqCst) != 0
+                || sh.peer_gone.load(Ordering::SeqCst)
+            {
+                break Poll::Ready(Ok(()));
+            }
+            if waiting {
+                break Poll::Pending;
+            }
+            // check again after announcing that we are waiting, in
+            // case the writer wrote before it could see the flag
+            sh.read_waker.register(cx.waker());
+            ring.reader_waiting.store(1, Ordering::SeqCst);
+            waiting = true;
+        }
+    }
+}
+
+impl AsyncWrite for ShmStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let sh = &*self.shared;
+        let ring = sh.ring(sh.tx);
+        let size = sh.ring_size as u64;
+        let mut waiting = false;
+        loop {
+            if ring.closed.load(Ordering::Relaxed) != 0
+                || sh.peer_gone.load(Ordering::SeqCst)
+            {
+                break Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+            }
+            let head = ring.head.0.load(Ordering::Relaxed);
+            let used = head.wrapping_sub(ring.tail.0.load(Ordering::SeqCst));
+            if used > size {
+                return Poll::Ready(Err(Shared::corrupt()));
+            }
+            if used < size {
+                if waiting {
+                    ring.writer_waiting.store(0, Ordering::Relaxed);
+                }
+                let n = min((size - used) as usize, buf.len());
+                let pos = (head % size) as usize;
+                let first = min(n, sh.ring_size - pos);
+                unsafe {
+                    let data = sh.data(sh.tx);
+                    ptr::copy_nonoverlapping(buf.as_ptr(), data.add(pos), first);
+                    ptr::copy_nonoverlapping(buf[first..].as_ptr(), data, n - first);
+                }
+                ring.head.0.store(head + n as u64, Ordering::SeqCst);
+                if ring.reader_waiting.swap(0, Ordering::SeqCst) != 0 {
+                    sh.notify()
+                }
+                break Poll::Ready(Ok(n));
+            }
+            if waiting {
+                break Poll::Pending;
+            }
+            sh.write_waker.register(cx.waker());
+            ring.writer_waiting.store(1, Ordering::SeqCst);
+            waiting = true;
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+    ) -> Poll<io::Result<()>> {
+        self.shared.ring(self.shared.tx).closed.store(1, Ordering::SeqCst);
+        self.shared.notify();
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Create a segment to offer to the publisher on the other end of
+/// `con`, if `ring_size` is set and the publisher is on this host.
+pub(crate) fn offer(con: &TcpStream, ring_size: Option<usize>) -> Option<Segment> {
+    match ring_size {
+        Some(ring_size) if is_local(con) => match Segment::create(ring_size) {
+            Ok(seg) => Some(seg),
+            Err(e) => {
+                info!("could not create shared memory segment, using tcp {}", e);
+                None
+            }
+        },
+        Some(_) | None => None,
+    }
+}
+
+/// Open the segment offered by the subscriber on the other end of
+/// `con`, if any.
+pub(crate) fn accept(con: &TcpStream, offer: Option<ShmOffer>) -> Option<Segment> {
+    match offer {
+        Some(offer) if is_local(con) => match Segment::open(&offer) {
+            Ok(seg) => Some(seg),
+            Err(e) => {
+                info!("could not open shared memory segment, using tcp {}", e);
+                None
+            }
+        },
+        Some(_) | None => None,
+    }
+}
+
+/// Build the channel for a connection that has completed it's hello,
+/// using `seg` if the other side accepted it.
+pub(crate) fn channel<C: K5Ctx + Debug + Send + Sync + 'static>(
+    con: TcpStream,
+    seg: Option<Segment>,
+) -> Channel {
+    match seg {
+        None => Channel::new::<C, TcpStream>(None, con),
+        Some(seg) => Channel::new::<C, ShmStream>(None, seg.stream(con)),
+    }
+}
@@ -0,0 +1,75 @@
+use super::Audit;
+use crate::protocol::publisher::Id;
+use fxhash::FxHashSet;
+use log::error;
+use std::{collections::HashSet, fmt, net::SocketAddr};
+
+/// Checks the messages a connection receives for evidence that some
+/// were lost, duplicated, or reordered, see
+/// `SubscriberBuilder::audit`. The publisher protocol doesn't carry
+/// sequence numbers, so the checks are local, batches are numbered
+/// as they are decoded, and every message must make sense given the
+/// messages that came before it.
+#[derive(Debug)]
+pub(super) struct Auditor {
+    mode: Audit,
+    addr: SocketAddr,
+    next_batch: u64,
+    // ids we asked the publisher to unsubscribe that we have no
+    // subscription for, updates to them are expected until the
+    // publisher confirms.
+    orphans: FxHashSet<Id>,
+}
+
+impl Auditor {
+    pub(super) fn new(mode: Audit, addr: SocketAddr) -> Option<Self> {
+        match mode {
+            Audit::Off => None,
+            Audit::Log | Audit::Panic => {
+                Some(Auditor { mode, addr, next_batch: 0, orphans: HashSet::default() })
+            }
+        }
+    }
+
+    fn violation(&self, msg: fmt::Arguments) {
+        match self.mode {
+            Audit::Off => (),
+            Audit::Log => error!("audit of connection to {}: {}", self.addr, msg),
+            Audit::Panic => panic!("audit of connection to {}: {}", self.addr, msg),
+        }
+    }
+
+    /// A batch numbered `seq` by the decoder arrived
+    pub(super) fn batch(&mut self, seq: u64) {
+        if seq != self.next_batch {
+            let expected = self.next_batch;
+            self.violation(format_args!("expected batch {} got {}", expected, seq));
+        }
+        self.next_batch = seq.wrapping_add(1);
+    }
+
+    /// We asked the publisher to unsubscribe `id`, which we have no
+    /// subscription for
+    pub(super) fn orphan(&mut self, id: Id) {
+        self.orphans.insert(id);
+    }
+
+    /// The publisher confirmed that `id` is unsubscribed
+    pub(super) fn unsubscribed(&mut self, id: Id) {
+        self.orphans.remove(&id);
+    }
+
+    /// An update arrived for `id`, which we have no subscription for
+    pub(super) fn unknown_update(&mut self, id: Id) {
+        if !self.orphans.contains(&id) {
+            self.violation(format_args!("update for {:?} which isn't subscribed", id));
+            self.orphans.insert(id);
+        }
+    }
+
+    /// A write result arrived for `id`, which we have no pending
+    /// write for
+    pub(super) fn unknown_write_result(&self, id: Id) {
+        self.violation(format_args!("write result for {:?} without a write", id))
+    }
+}
@@ -1,22 +1,25 @@
 use super::{
-    ConId, DvDead, DvState, Event, NoSuchValue, PermissionDenied, Streams, SubId,
-    SubStatus, SubscribeValRequest, Subscriber, SubscriberInner, SubscriberWeak, ToCon,
-    UpdatesFlags, Val, ValInner, ValWeak, BATCHES, DECODE_BATCHES,
+    audit::Auditor, ConId, ConnEvent, DvDead, DvState, DvalWeak, Event, History,
+    NoSuchValue, PermissionDenied, Sample, StatCounters, Streams, SubId, SubStatus,
+    SubscribeManyRequest, SubscribeValRequest, Subscriber, SubscriberInner,
+    SubscriberWeak, ToCon, UpdatesFlags, Val, ValInner, ValWeak, WriteReceipt,
+    WriteReply, BATCHES, DECODE_BATCHES,
 };
 pub use crate::protocol::value::{FromValue, Typ, Value};
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
-    batch_channel::BatchReceiver,
+    batch_channel::{BatchReceiver, BatchSender},
     channel::{self, Channel, K5CtxWrap, ReadChannel, WriteChannel},
+    pack::Pack,
     path::Path,
     pool::Pooled,
     protocol::{
         self,
-        publisher::{From, Id, To},
+        publisher::{From, Id, To, UnsubscribeReason, ValPermissions},
         resolver::TargetAuth,
     },
     resolver_client::common::krb5_authentication,
-    tls,
+    shm, tls,
     utils::{ChanId, ChanWrap},
 };
 use anyhow::{anyhow, Error, Result};
@@ -35,8 +38,9 @@ use log::info;
 use parking_lot::Mutex;
 use protocol::resolver::UserInfo;
 use std::{
+    cmp::max,
     collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
-    mem,
+    error, fmt, io, mem,
     net::SocketAddr,
     pin::Pin,
     sync::Arc,
@@ -54,6 +58,10 @@ struct Sub {
     sub_id: SubId,
     streams: Streams,
     last: Option<TArc<Mutex<Event>>>,
+    // the current value, if patches or deltas are enabled
+    current: Option<Value>,
+    history: TArc<History>,
+    stats: Option<TArc<StatCounters>>,
     val: ValWeak,
 }
 
@@ -62,36 +70,136 @@ type ByChan = FxHashMap<
     (ChanWrap<Pooled<Vec<(SubId, Event)>>>, Pooled<Vec<(SubId, Event)>>),
 >;
 
+#[derive(Debug)]
+enum Sampler {
+    Every { n: u32, seen: u32 },
+    Interval { period: Duration, next: Instant, held: Option<Value> },
+}
+
+impl Sampler {
+    fn new(sample: Sample) -> Self {
+        match sample {
+            Sample::Every(n) => Sampler::Every { n: max(1, n), seen: 0 },
+            Sample::Interval(period) => {
+                Sampler::Interval { period, next: Instant::now(), held: None }
+            }
+        }
+    }
+
+    // return true if the update should be delivered now
+    fn sample(&mut self, v: &Value) -> bool {
+        match self {
+            Sampler::Every { n, seen } => {
+                let deliver = *seen == 0;
+                *seen = (*seen + 1) % *n;
+                deliver
+            }
+            Sampler::Interval { period, next, held } => {
+                let now = Instant::now();
+                if now >= *next {
+                    *next = now + *period;
+                    *held = None;
+                    true
+                } else {
+                    *held = Some(v.clone());
+                    false
+                }
+            }
+        }
+    }
+}
+
+type Sampled = FxHashMap<(Id, ChanId), Sampler>;
+
+// the last value sent to each channel that asked for duplicates to
+// be suppressed
+type Deduped = FxHashMap<(Id, ChanId), Option<Value>>;
+
+// return true if `m` is a duplicate that shouldn't be sent
+fn is_dup(deduped: &mut Deduped, id: Id, chan_id: ChanId, sub: &Sub, m: &Value) -> bool {
+    if deduped.is_empty() {
+        return false;
+    }
+    match deduped.get_mut(&(id, chan_id)) {
+        None => false,
+        Some(Some(last)) if last == m => {
+            if let Some(stats) = &sub.stats {
+                stats.suppressed()
+            }
+            true
+        }
+        Some(last) => {
+            *last = Some(m.clone());
+            false
+        }
+    }
+}
+
+fn queue_update(
+    by_chan: &mut ByChan,
+    sampled: &mut Sampled,
+    deduped: &mut Deduped,
+    id: Id,
+    sub: &Sub,
+    m: &Value,
+) {
+    for (chan_id, c) in sub.streams.0.iter() {
+        if !sampled.is_empty() {
+            if let Some(s) = sampled.get_mut(&(id, *chan_id)) {
+                if !s.sample(m) {
+                    continue;
+                }
+            }
+        }
+        if is_dup(deduped, id, *chan_id, sub, m) {
+            continue;
+        }
+        by_chan
+            .entry(*chan_id)
+            .or_insert_with(|| (c.clone(), BATCHES.take()))
+            .1
+            .push((sub.sub_id, Event::Update(m.clone())))
+    }
+}
+
 fn unsubscribe(
     subscriber: &mut SubscriberInner,
     by_chan: &mut ByChan,
     sub: Sub,
     id: Id,
     conid: ConId,
+    reason: UnsubscribeReason,
 ) {
     for (chan_id, c) in sub.streams.0.iter() {
         by_chan
             .entry(*chan_id)
             .or_insert_with(|| (c.clone(), BATCHES.take()))
             .1
-            .push((sub.sub_id, Event::Unsubscribed))
+            .push((sub.sub_id, Event::Unsubscribed(reason)))
     }
     if let Some(last) = &sub.last {
-        *last.lock() = Event::Unsubscribed;
+        *last.lock() = Event::Unsubscribed(reason);
     }
-    if let Some(dsw) = subscriber
-        .durable_alive
-        .remove(&sub.path)
-        .or_else(|| subscriber.durable_pending.remove(&sub.path))
-    {
+    sub.history.push(&Event::Unsubscribed(reason));
+    // a live durable subscription to the path may be held by another
+    // subscription, e.g. to a different publisher in a `DvalGroup`
+    let durable = |dsw: &DvalWeak| match dsw.upgrade() {
+        None => true,
+        Some(ds) => match &ds.0.lock().sub {
+            DvState::Subscribed(v) => v.0.id == id && v.0.conid == conid,
+            DvState::Dead(_) | DvState::Failed => true,
+        },
+    };
+    let alive = match subscriber.durable_alive.get(&sub.path).map(durable) {
+        Some(true) => subscriber.durable_alive.remove(&sub.path),
+        Some(false) | None => None,
+    };
+    if let Some(dsw) = alive.or_else(|| subscriber.durable_pending.remove(&sub.path)) {
         if let Some(ds) = dsw.upgrade() {
             let mut inner = ds.0.lock();
-            inner.sub = DvState::Dead(Box::new(DvDead {
-                queued_writes: Vec::new(),
-                tries: 0,
-                next_try: Instant::now(),
-            }));
-            subscriber.durable_dead.insert(sub.path.clone(), dsw);
+            let next_try = Instant::now();
+            inner.set_state(DvState::Dead(Box::new(DvDead::new(next_try))));
+            subscriber.add_durable_dead(sub.path.clone(), dsw, next_try);
             let _ = subscriber.trigger_resub.unbounded_send(());
         }
     }
@@ -119,20 +227,25 @@ async fn hello_publisher(
     uifo: Option<UserInfo>,
     desired_auth: &DesiredAuth,
     target_auth: &TargetAuth,
+    shm_ring: Option<usize>,
 ) -> Result<Channel> {
     use protocol::publisher::Hello;
     channel::write_raw(&mut con, &3u64).await?;
-    if channel::read_raw::<u64, _>(&mut con).await? != 3 {
-        bail!("incompatible protocol version")
+    let version = channel::read_raw::<u64, _>(&mut con).await?;
+    if version != 3 {
+        bail!("incompatible protocol version {}, expected 3", version)
     }
     match (desired_auth, target_auth) {
         (DesiredAuth::Anonymous, TargetAuth::Anonymous) => {
-            channel::write_raw(&mut con, &Hello::Anonymous).await?;
-            match channel::read_raw(&mut con).await? {
-                Hello::Anonymous => (),
+            let seg = shm::offer(&con, shm_ring);
+            let hello = Hello::Anonymous(seg.as_ref().map(|s| s.offer()));
+            channel::write_raw(&mut con, &hello).await?;
+            let accepted = match channel::read_raw(&mut con).await? {
+                Hello::Anonymous(accepted) => accepted,
                 _ => bail!("unexpected response from publisher"),
-            }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            };
+            let seg = seg.filter(|s| accepted == Some(s.offer()));
+            Ok(shm::channel::<ClientCtx>(con, seg))
         }
         (
             DesiredAuth::Anonymous,
@@ -150,12 +263,15 @@ async fn hello_publisher(
             DesiredAuth::Local | DesiredAuth::Krb5 { .. } | DesiredAuth::Tls { .. },
             TargetAuth::Local,
         ) => {
-            channel::write_raw(&mut con, &Hello::Local(uifo)).await?;
-            match channel::read_raw(&mut con).await? {
-                Hello::Local(_) => (),
+            let seg = shm::offer(&con, shm_ring);
+            let hello = Hello::Local(uifo, seg.as_ref().map(|s| s.offer()));
+            channel::write_raw(&mut con, &hello).await?;
+            let accepted = match channel::read_raw(&mut con).await? {
+                Hello::Local(_, accepted) => accepted,
                 _ => bail!("unexpected response from publisher"),
-            }
-            Ok(Channel::new::<ClientCtx, TcpStream>(None, con))
+            };
+            let seg = seg.filter(|s| accepted == Some(s.offer()));
+            Ok(shm::channel::<ClientCtx>(con, seg))
         }
         (DesiredAuth::Local, TargetAuth::Krb5 { .. } | TargetAuth::Tls { .. }) => {
             bail!("local auth not supported")
@@ -196,16 +312,111 @@ async fn hello_publisher(
     }
 }
 
+// The endpoint accepted the connection, but it didn't speak the
+// protocol, or it failed authentication, so whatever is listening
+// there may not be the publisher we resolved. Network errors and
+// timeouts during the handshake are not a HandshakeFailed.
+#[derive(Debug)]
+pub(super) struct HandshakeFailed(Error);
+
+impl fmt::Display for HandshakeFailed {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "handshake failed {}", self.0)
+    }
+}
+
+impl error::Error for HandshakeFailed {}
+
+fn auth_mech(auth: &TargetAuth) -> &'static str {
+    match auth {
+        TargetAuth::Anonymous => "anonymous",
+        TargetAuth::Local => "local",
+        TargetAuth::Krb5 { .. } => "krb5",
+        TargetAuth::Tls { .. } => "tls",
+    }
+}
+
+// try each of the publisher's addresses in order, giving each
+// attempt `timeout` to succeed, return the last error if none do
+async fn connect(addrs: &[SocketAddr], timeout: Duration) -> Result<TcpStream> {
+    let mut res = Err(anyhow!("publisher has no addresses"));
+    for addr in addrs {
+        res = match time::timeout(timeout, TcpStream::connect(addr)).await {
+            Ok(Ok(soc)) => return Ok(soc),
+            Ok(Err(e)) => Err(Error::from(e)),
+            Err(e) => Err(Error::from(e)),
+        };
+        if let Err(e) = &res {
+            info!("failed to connect to publisher at {}: {}", addr, e)
+        }
+    }
+    res
+}
+
 const PERIOD: Duration = Duration::from_secs(100);
+const SLOW_FLUSH: Duration = Duration::from_secs(1);
+const UNSUPPORTED_MANY: &str = "the publisher does not support subscribe many";
+
+#[derive(Debug, Default)]
+struct HealthInner {
+    // subscriptions that timed out since the publisher last answered
+    failures: u32,
+    // when the publisher last answered, or when the first request was
+    // sent, while there are requests outstanding
+    waiting_since: Option<Instant>,
+}
+
+// How well a connection is answering subscription requests. It is
+// shared with the subscriber, so that new subscriptions can avoid a
+// connection that is half open.
+#[derive(Debug, Default)]
+pub(super) struct Health(Mutex<HealthInner>);
+
+impl Health {
+    pub(super) fn healthy(&self, stalled_after: Duration) -> bool {
+        let t = self.0.lock();
+        t.failures == 0
+            && match t.waiting_since {
+                None => true,
+                Some(ts) => ts.elapsed() < stalled_after,
+            }
+    }
+
+    fn waiting(&self) {
+        let mut t = self.0.lock();
+        if t.waiting_since.is_none() {
+            t.waiting_since = Some(Instant::now());
+        }
+    }
+
+    fn answered(&self, outstanding: bool) {
+        let mut t = self.0.lock();
+        t.failures = 0;
+        t.waiting_since = if outstanding { Some(Instant::now()) } else { None };
+    }
+
+    fn failed(&self, outstanding: bool) {
+        let mut t = self.0.lock();
+        t.failures += 1;
+        if !outstanding {
+            t.waiting_since = None;
+        }
+    }
+}
+
+// batches are numbered in the order they are decoded so they can be
+// audited
+type DecodedBatch = (u64, Pooled<Vec<From>>, bool);
 
 fn decode_task(
     mut con: ReadChannel,
     stop: oneshot::Receiver<()>,
-) -> Receiver<Result<(Pooled<Vec<From>>, bool)>> {
+) -> Receiver<Result<DecodedBatch>> {
     let (mut send, recv) = mpsc::channel(3);
     let mut stop = stop.fuse();
     task::spawn(async move {
         let mut buf = DECODE_BATCHES.take();
+        let mut seq = 0u64;
         let r: Result<(), anyhow::Error> = loop {
             select_biased! {
                 _ = stop => { break Ok(()); },
@@ -220,7 +431,9 @@ fn decode_task(
                             From::Update(_, _) => true,
                             _ => false
                         });
-                        try_cf!(send.send(Ok((batch, only_updates))).await)
+                        let n = seq;
+                        seq = seq.wrapping_add(1);
+                        try_cf!(send.send(Ok((n, batch, only_updates))).await)
                     }
                 }
             }
@@ -234,55 +447,106 @@ type BlockedChannelFut = Pin<Box<dyn Future<Output = ()> + Send + Sync + 'static
 
 pub(super) struct ConnectionCtx {
     addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     subscriber: SubscriberWeak,
     target_auth: TargetAuth,
     desired_auth: DesiredAuth,
     conid: ConId,
     tls_ctx: Option<tls::CachedConnector>,
     uifo: Option<UserInfo>,
+    shm_ring: Option<usize>,
+    // when this connection started and stopped waiting for the rate
+    // limiter, if it had to
+    limited: Option<(Instant, Instant)>,
     from_sub: BatchReceiver<ToCon>,
     pending: HashMap<Path, SubscribeValRequest>,
+    // subscribe many requests, and the values received so far
+    pending_many: HashMap<Path, (SubscribeManyRequest, Vec<(Path, Val)>)>,
     subscriptions: FxHashMap<Id, Sub>,
     msg_recvd: bool,
     pending_flushes: Vec<oneshot::Sender<()>>,
-    pending_writes: FxHashMap<Id, VecDeque<oneshot::Sender<Value>>>,
+    pending_writes: FxHashMap<Id, VecDeque<WriteReply>>,
     by_receiver: FxHashMap<ChanWrap<Pooled<Vec<(SubId, Event)>>>, ChanId>,
     by_chan: ByChan,
+    sampled: Sampled,
+    deduped: Deduped,
+    next_sample: Option<Instant>,
     gc_chan: FxHashSet<ChanId>,
     blocked_channels: FuturesUnordered<BlockedChannelFut>,
     timed_out: Vec<Path>,
+    closed: Option<oneshot::Sender<()>>,
+    audit: Option<Auditor>,
+    patches: bool,
+    deltas: bool,
+    stats: bool,
+    health: Arc<Health>,
 }
 
 impl ConnectionCtx {
     pub(super) fn new(
         addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         subscriber: SubscriberWeak,
         conid: ConId,
         tls_ctx: Option<tls::CachedConnector>,
         uifo: Option<UserInfo>,
         target_auth: TargetAuth,
         desired_auth: DesiredAuth,
+        shm_ring: Option<usize>,
+        limited: Option<(Instant, Instant)>,
+        health: Arc<Health>,
         from_sub: BatchReceiver<ToCon>,
     ) -> Self {
         Self {
             addr,
+            alt_addr,
             subscriber,
             target_auth,
             desired_auth,
             conid,
             tls_ctx,
             uifo,
+            shm_ring,
+            limited,
             from_sub,
             pending: HashMap::default(),
+            pending_many: HashMap::default(),
             subscriptions: HashMap::default(),
             msg_recvd: false,
             pending_flushes: Vec::new(),
             pending_writes: HashMap::default(),
             by_receiver: HashMap::default(),
             by_chan: HashMap::default(),
+            sampled: HashMap::default(),
+            deduped: HashMap::default(),
+            next_sample: None,
             gc_chan: HashSet::default(),
             blocked_channels: FuturesUnordered::<BlockedChannelFut>::new(),
             timed_out: Vec::new(),
+            closed: None,
+            audit: None,
+            patches: false,
+            deltas: false,
+            stats: false,
+            health,
+        }
+    }
+
+    fn conn_event(&self, ev: ConnEvent) {
+        if let Some(subscriber) = self.subscriber.upgrade() {
+            subscriber.0.lock().conn_event(ev)
+        }
+    }
+
+    // time the connection spent waiting for the rate limiter doesn't
+    // count against the deadline of a request queued before it was
+    // done waiting
+    fn deadline(&self, queued: Instant, deadline: Option<Instant>) -> Option<Instant> {
+        match (self.limited, deadline) {
+            (Some((start, end)), Some(d)) if queued < end => {
+                Some(d + (end - max(start, queued)))
+            }
+            (_, d) => d,
         }
     }
 
@@ -302,6 +566,19 @@ impl ConnectionCtx {
         for path in self.timed_out.drain(..) {
             if let Some(req) = self.pending.remove(&path) {
                 let _ = req.finished.send(Err(anyhow!("timed out")));
+                self.health.failed(!self.pending.is_empty());
+            }
+        }
+        for (base, (req, _)) in self.pending_many.iter() {
+            if let Some(deadline) = req.deadline {
+                if deadline < now {
+                    self.timed_out.push(base.clone());
+                }
+            }
+        }
+        for base in self.timed_out.drain(..) {
+            if let Some((req, _)) = self.pending_many.remove(&base) {
+                let _ = req.finished.send(Err(anyhow!("timed out")));
             }
         }
         Ok(())
@@ -313,6 +590,7 @@ impl ConnectionCtx {
         sub_id: SubId,
         mut tx: Sender<Pooled<Vec<(SubId, Event)>>>,
         flags: UpdatesFlags,
+        sample: Option<Sample>,
     ) -> Result<()> {
         if let Some(sub) = self.subscriptions.get_mut(&id) {
             let mut already_have = false;
@@ -325,13 +603,23 @@ impl ConnectionCtx {
                     self.gc_chan.insert(*id);
                 }
             }
-            if flags.contains(UpdatesFlags::BEGIN_WITH_LAST)
-                && !(already_have && flags.contains(UpdatesFlags::NO_SPURIOUS))
-            {
-                if let Some(last) = &sub.last {
-                    let m = last.lock().clone();
-                    let mut b = BATCHES.take();
-                    b.push((sub_id, m));
+            // the last value sent to the new channel
+            let mut sent = None;
+            if !(already_have && flags.contains(UpdatesFlags::NO_SPURIOUS)) {
+                let mut b = BATCHES.take();
+                if flags.contains(UpdatesFlags::BEGIN_WITH_HISTORY) {
+                    let events = sub.history.events.lock();
+                    b.extend(events.iter().map(|(_, ev)| (sub_id, ev.clone())));
+                }
+                if b.is_empty() && flags.contains(UpdatesFlags::BEGIN_WITH_LAST) {
+                    if let Some(last) = &sub.last {
+                        b.push((sub_id, last.lock().clone()));
+                    }
+                }
+                if let Some((_, Event::Update(v))) = b.last() {
+                    sent = Some(v.clone());
+                }
+                if !b.is_empty() {
                     if let Err(e) = tx.try_send(b) {
                         if e.is_disconnected() {
                             return Ok(());
@@ -350,8 +638,15 @@ impl ConnectionCtx {
             }
             if !already_have {
                 let tx = ChanWrap(tx);
-                let id = self.by_receiver.entry(tx.clone()).or_insert_with(ChanId::new);
-                sub.streams = sub.streams.add(*id, tx);
+                let chan_id =
+                    *self.by_receiver.entry(tx.clone()).or_insert_with(ChanId::new);
+                sub.streams = sub.streams.add(chan_id, tx);
+                if let Some(sample) = sample {
+                    self.sampled.insert((id, chan_id), Sampler::new(sample));
+                }
+                if flags.contains(UpdatesFlags::DEDUP) {
+                    self.deduped.insert((id, chan_id), sent);
+                }
             }
         }
         Ok(())
@@ -364,30 +659,56 @@ impl ConnectionCtx {
     ) -> Result<()> {
         for msg in batch.drain(..) {
             match msg {
-                ToCon::Subscribe(req) => {
+                ToCon::Subscribe(mut req) => {
+                    req.deadline = self.deadline(req.queued, req.deadline);
                     let path = req.path.clone();
                     let resolver = req.resolver;
                     let token = req.token.clone();
                     let permissions = req.permissions;
                     let timestamp = req.timestamp;
                     self.pending.insert(path.clone(), req);
+                    self.health.waiting();
                     write_con.queue_send(&To::Subscribe {
                         path,
                         resolver,
                         timestamp,
                         permissions,
                         token,
+                        patches: self.patches,
+                        deltas: self.deltas,
+                        glob: None,
+                        error_info: true,
                     })?
                 }
+                ToCon::SubscribeMany(mut req) => {
+                    req.deadline = self.deadline(req.queued, req.deadline);
+                    if self.pending_many.contains_key(&req.base) {
+                        let e = anyhow!("already subscribing many of {}", req.base);
+                        let _ = req.finished.send(Err(e));
+                    } else {
+                        write_con.queue_send(&To::Subscribe {
+                            path: req.base.clone(),
+                            resolver: req.resolver,
+                            timestamp: req.timestamp,
+                            permissions: req.permissions,
+                            token: req.token.clone(),
+                            patches: self.patches,
+                            deltas: self.deltas,
+                            glob: Some(req.glob.clone()),
+                            error_info: true,
+                        })?;
+                        self.pending_many.insert(req.base.clone(), (req, vec![]));
+                    }
+                }
                 ToCon::Unsubscribe(id) => {
                     info!("unsubscribe {:?}", id);
                     write_con.queue_send(&To::Unsubscribe(id))?
                 }
-                ToCon::Stream { id, sub_id, tx, flags } => {
-                    self.handle_connect_stream(id, sub_id, tx, flags)?
+                ToCon::Stream { id, sub_id, tx, flags, sample } => {
+                    self.handle_connect_stream(id, sub_id, tx, flags, sample)?
                 }
-                ToCon::Write(id, v, tx) => {
-                    write_con.queue_send(&To::Write(id, tx.is_some(), v))?;
+                ToCon::Write(id, v, key, tx) => {
+                    write_con.queue_send(&To::Write(id, tx.is_some(), v, key))?;
                     if let Some(tx) = tx {
                         self.pending_writes
                             .entry(id)
@@ -396,6 +717,94 @@ impl ConnectionCtx {
                     }
                 }
                 ToCon::Flush(tx) => self.pending_flushes.push(tx),
+                ToCon::Close(tx) => self.closed = Some(tx),
+            }
+        }
+        Ok(())
+    }
+
+    // a new subscription to `id` with first value `m`
+    fn new_sub(
+        &self,
+        path: Path,
+        sub_id: SubId,
+        id: Id,
+        m: Value,
+        permissions: Option<ValPermissions>,
+        connection: BatchSender<ToCon>,
+    ) -> (Val, Sub) {
+        let current = if self.patches || self.deltas { Some(m.clone()) } else { None };
+        let last = TArc::new(Mutex::new(Event::Update(m)));
+        let history = TArc::new(History::default());
+        let stats =
+            if self.stats { Some(TArc::new(StatCounters::default())) } else { None };
+        let val = Val(Arc::new(ValInner {
+            sub_id,
+            id,
+            conid: self.conid,
+            connection,
+            last: last.clone(),
+            history: history.clone(),
+            stats: stats.clone(),
+            publisher_user: self.uifo.clone(),
+            permissions,
+        }));
+        let sub = Sub {
+            path,
+            sub_id,
+            last: Some(last),
+            current,
+            history,
+            stats,
+            streams: Streams::new(),
+            val: val.downgrade(),
+        };
+        (val, sub)
+    }
+
+    // deliver an update to `i` that was sent as a change to it's
+    // previous value, `len` is the encoded size of the change
+    fn apply_change<F: FnOnce(&Value) -> Result<Value>>(
+        &mut self,
+        con: &mut WriteChannel,
+        i: Id,
+        len: usize,
+        f: F,
+    ) -> Result<()> {
+        match self.subscriptions.get_mut(&i) {
+            Some(sub) => {
+                if let Some(stats) = &sub.stats {
+                    stats.record(len)
+                }
+                match sub.current.as_ref().map(f) {
+                    Some(Ok(m)) => {
+                        queue_update(
+                            &mut self.by_chan,
+                            &mut self.sampled,
+                            &mut self.deduped,
+                            i,
+                            sub,
+                            &m,
+                        );
+                        sub.current = Some(m.clone());
+                        let ev = Event::Update(m);
+                        sub.history.push(&ev);
+                        if let Some(last) = &sub.last {
+                            *last.lock() = ev;
+                        }
+                    }
+                    None | Some(Err(_)) => {
+                        // we can't reconstruct the value, start over
+                        info!("failed to apply change to {}, unsubscribing", sub.path);
+                        con.queue_send(&To::Unsubscribe(i))?
+                    }
+                }
+            }
+            None => {
+                if let Some(audit) = &mut self.audit {
+                    audit.unknown_update(i)
+                }
+                con.queue_send(&To::Unsubscribe(i))?
             }
         }
         Ok(())
@@ -409,88 +818,202 @@ impl ConnectionCtx {
     ) -> Result<()> {
         for m in batch.drain(..) {
             match m {
-                From::Update(i, m) => match self.subscriptions.get(&i) {
+                From::Update(i, m) => match self.subscriptions.get_mut(&i) {
                     Some(sub) => {
-                        for (chan_id, c) in sub.streams.0.iter() {
-                            self.by_chan
-                                .entry(*chan_id)
-                                .or_insert_with(|| (c.clone(), BATCHES.take()))
-                                .1
-                                .push((sub.sub_id, Event::Update(m.clone())));
+                        if let Some(stats) = &sub.stats {
+                            stats.record(m.encoded_len())
+                        }
+                        queue_update(
+                            &mut self.by_chan,
+                            &mut self.sampled,
+                            &mut self.deduped,
+                            i,
+                            sub,
+                            &m,
+                        );
+                        if let Some(current) = &mut sub.current {
+                            *current = m.clone();
                         }
+                        let ev = Event::Update(m);
+                        sub.history.push(&ev);
                         if let Some(last) = &sub.last {
-                            *last.lock() = Event::Update(m);
+                            *last.lock() = ev;
+                        }
+                    }
+                    None => {
+                        if let Some(audit) = &mut self.audit {
+                            audit.unknown_update(i)
                         }
+                        con.queue_send(&To::Unsubscribe(i))?
                     }
-                    None => con.queue_send(&To::Unsubscribe(i))?,
                 },
+                From::Patch(i, patch) => {
+                    let len = patch.encoded_len();
+                    self.apply_change(con, i, len, |v| patch.apply(v))?
+                }
+                From::Delta(i, delta) => {
+                    let len = delta.encoded_len();
+                    self.apply_change(con, i, len, |v| delta.apply_value(v))?
+                }
                 From::Heartbeat => (),
-                From::WriteResult(id, v) => {
-                    if let Entry::Occupied(mut e) = self.pending_writes.entry(id) {
-                        let q = e.get_mut();
-                        if let Some(tx) = q.pop_front() {
-                            let _ = tx.send(v);
+                From::WriteResult(id, v, duplicate) => {
+                    match self.pending_writes.entry(id) {
+                        Entry::Occupied(mut e) => {
+                            let q = e.get_mut();
+                            match q.pop_front() {
+                                None => (),
+                                Some(WriteReply::Value(tx)) => {
+                                    let _ = tx.send(v);
+                                }
+                                Some(WriteReply::Receipt(tx)) => {
+                                    let _ =
+                                        tx.send(WriteReceipt { result: v, duplicate });
+                                }
+                                Some(WriteReply::Tracked(dv, seq)) => {
+                                    if let Some(dv) = dv.upgrade() {
+                                        dv.ack(seq, v, duplicate)
+                                    }
+                                }
+                            }
+                            if q.is_empty() {
+                                e.remove();
+                            }
                         }
-                        if q.is_empty() {
-                            e.remove();
+                        Entry::Vacant(_) => {
+                            if let Some(audit) = &self.audit {
+                                audit.unknown_write_result(id)
+                            }
                         }
                     }
                 }
                 From::NoSuchValue(path) => {
                     if let Some(r) = self.pending.remove(&path) {
+                        self.health.answered(!self.pending.is_empty());
                         let _ = r.finished.send(Err(Error::from(NoSuchValue)));
+                    } else if let Some((r, _)) = self.pending_many.remove(&path) {
+                        let _ = r.finished.send(Err(anyhow!(UNSUPPORTED_MANY)));
                     }
                 }
                 From::Denied(path) => {
                     if let Some(r) = self.pending.remove(&path) {
+                        self.health.answered(!self.pending.is_empty());
+                        let _ = r.finished.send(Err(Error::from(PermissionDenied)));
+                    } else if let Some((r, _)) = self.pending_many.remove(&path) {
                         let _ = r.finished.send(Err(Error::from(PermissionDenied)));
                     }
                 }
-                From::Unsubscribed(id) => {
+                From::SubscribedMany(base, mut subs, done) => {
+                    match self.pending_many.remove(&base) {
+                        None => {
+                            for (_, id, _) in subs.drain(..) {
+                                if !self.subscriptions.contains_key(&id) {
+                                    if let Some(audit) = &mut self.audit {
+                                        audit.orphan(id)
+                                    }
+                                    con.queue_send(&To::Unsubscribe(id))?
+                                }
+                            }
+                        }
+                        Some((req, mut vals)) => {
+                            for (path, id, m) in subs.drain(..) {
+                                match self.subscriptions.get(&id) {
+                                    // we are unsubscribing
+                                    Some(sub) => match sub.val.upgrade() {
+                                        None => (),
+                                        Some(val) => vals.push((path, val)),
+                                    },
+                                    None => {
+                                        let con = req.con.clone();
+                                        let (val, sub) = self.new_sub(
+                                            path.clone(),
+                                            SubId::new(),
+                                            id,
+                                            m,
+                                            None,
+                                            con,
+                                        );
+                                        self.subscriptions.insert(id, sub);
+                                        vals.push((path, val));
+                                    }
+                                }
+                            }
+                            if done {
+                                // if the caller is gone dropping the
+                                // values unsubscribes them
+                                let _ = req.finished.send(Ok(vals));
+                            } else {
+                                self.pending_many.insert(base, (req, vals));
+                            }
+                        }
+                    }
+                }
+                From::Unsubscribed(id, reason) => {
+                    if let Some(audit) = &mut self.audit {
+                        audit.unsubscribed(id)
+                    }
                     if let Some(s) = self.subscriptions.remove(&id) {
                         let mut t = subscriber.0.lock();
-                        unsubscribe(&mut *t, &mut self.by_chan, s, id, self.conid);
+                        unsubscribe(
+                            &mut *t,
+                            &mut self.by_chan,
+                            s,
+                            id,
+                            self.conid,
+                            reason,
+                        );
+                        if !self.sampled.is_empty() {
+                            self.sampled.retain(|(i, _), _| *i != id);
+                        }
+                        if !self.deduped.is_empty() {
+                            self.deduped.retain(|(i, _), _| *i != id);
+                        }
                     }
                 }
-                From::Subscribed(p, id, m) => match self.pending.remove(&p) {
-                    None => con.queue_send(&To::Unsubscribe(id))?,
-                    Some(req) => match self.subscriptions.get_mut(&id) {
-                        Some(sub) => match sub.val.upgrade() {
-                            Some(val) => {
-                                let _ = req.finished.send(Ok(val));
+                From::Subscribed(p, id, m, perms) => match self.pending.remove(&p) {
+                    None => match self.pending_many.remove(&p) {
+                        Some((r, _)) => {
+                            let _ = r.finished.send(Err(anyhow!(UNSUPPORTED_MANY)));
+                            if !self.subscriptions.contains_key(&id) {
+                                con.queue_send(&To::Unsubscribe(id))?
                             }
-                            None => {
-                                let _ = req.finished.send(Err(anyhow!(
-                                    "subscribe alias while unsubscribing"
-                                )));
-                            }
-                        },
+                        }
                         None => {
-                            let last = TArc::new(Mutex::new(Event::Update(m)));
-                            let s = Val(Arc::new(ValInner {
-                                sub_id: req.sub_id,
-                                id,
-                                conid: self.conid,
-                                connection: req.con,
-                                last: last.clone(),
-                            }));
-                            match req.finished.send(Ok(s.clone())) {
-                                Err(_) => con.queue_send(&To::Unsubscribe(id))?,
-                                Ok(()) => {
-                                    self.subscriptions.insert(
-                                        id,
-                                        Sub {
-                                            path: req.path,
-                                            sub_id: req.sub_id,
-                                            last: Some(last),
-                                            streams: Streams::new(),
-                                            val: s.downgrade(),
-                                        },
-                                    );
-                                }
+                            if let Some(audit) = &mut self.audit {
+                                audit.orphan(id)
                             }
+                            con.queue_send(&To::Unsubscribe(id))?
                         }
                     },
+                    Some(req) => {
+                        self.health.answered(!self.pending.is_empty());
+                        match self.subscriptions.get(&id) {
+                            Some(sub) => match sub.val.upgrade() {
+                                Some(val) => {
+                                    let _ = req.finished.send(Ok(val));
+                                }
+                                None => {
+                                    let _ = req.finished.send(Err(anyhow!(
+                                        "subscribe alias while unsubscribing"
+                                    )));
+                                }
+                            },
+                            None => {
+                                let (s, sub) = self
+                                    .new_sub(req.path, req.sub_id, id, m, perms, req.con);
+                                match req.finished.send(Ok(s)) {
+                                    Err(_) => {
+                                        if let Some(audit) = &mut self.audit {
+                                            audit.orphan(id)
+                                        }
+                                        con.queue_send(&To::Unsubscribe(id))?
+                                    }
+                                    Ok(()) => {
+                                        self.subscriptions.insert(id, sub);
+                                    }
+                                }
+                            }
+                        }
+                    }
                 },
             }
         }
@@ -504,20 +1027,72 @@ impl ConnectionCtx {
     fn process_updates_batch(&mut self, mut batch: Pooled<Vec<From>>) {
         for m in batch.drain(..) {
             if let From::Update(i, m) = m {
-                if let Some(sub) = self.subscriptions.get(&i) {
-                    for (chan_id, c) in sub.streams.0.iter() {
-                        self.by_chan
+                match self.subscriptions.get_mut(&i) {
+                    Some(sub) => {
+                        if let Some(stats) = &sub.stats {
+                            stats.record(m.encoded_len())
+                        }
+                        queue_update(
+                            &mut self.by_chan,
+                            &mut self.sampled,
+                            &mut self.deduped,
+                            i,
+                            sub,
+                            &m,
+                        );
+                        if let Some(current) = &mut sub.current {
+                            *current = m.clone();
+                        }
+                        let ev = Event::Update(m);
+                        sub.history.push(&ev);
+                        if let Some(last) = &sub.last {
+                            *last.lock() = ev;
+                        }
+                    }
+                    None => {
+                        if let Some(audit) = &mut self.audit {
+                            audit.unknown_update(i)
+                        }
+                    }
+                }
+            }
+        }
+        self.send_updates()
+    }
+
+    // deliver held samples whose interval has ended, and drop the
+    // samplers of channels that are gone.
+    fn flush_samples(&mut self) {
+        let now = Instant::now();
+        let subscriptions = &self.subscriptions;
+        let by_chan = &mut self.by_chan;
+        let deduped = &mut self.deduped;
+        self.sampled.retain(|(id, chan_id), s| {
+            let sub = match subscriptions.get(id) {
+                Some(sub) => sub,
+                None => return false,
+            };
+            let c = match sub.streams.0.iter().find(|(i, _)| i == chan_id) {
+                Some((_, c)) if !c.0.is_closed() => c,
+                Some(_) | None => return false,
+            };
+            if let Sampler::Interval { period, next, held } = s {
+                if *next <= now {
+                    if let Some(v) = held.take() {
+                        *next = now + *period;
+                        if is_dup(deduped, *id, *chan_id, sub, &v) {
+                            return true;
+                        }
+                        by_chan
                             .entry(*chan_id)
                             .or_insert_with(|| (c.clone(), BATCHES.take()))
                             .1
-                            .push((sub.sub_id, Event::Update(m.clone())))
-                    }
-                    if let Some(last) = &sub.last {
-                        *last.lock() = Event::Update(m);
+                            .push((sub.sub_id, Event::Update(v)))
                     }
                 }
             }
-        }
+            true
+        });
         self.send_updates()
     }
 
@@ -537,9 +1112,24 @@ impl ConnectionCtx {
                 }
             }
         }
+        if !self.deduped.is_empty() && !self.gc_chan.is_empty() {
+            let gc_chan = &self.gc_chan;
+            self.deduped.retain(|(_, chan_id), _| !gc_chan.contains(chan_id));
+        }
         for id in self.gc_chan.drain() {
             self.by_chan.remove(&id);
         }
+        self.next_sample = if self.sampled.is_empty() {
+            None
+        } else {
+            self.sampled
+                .values()
+                .filter_map(|s| match s {
+                    Sampler::Interval { next, held: Some(_), .. } => Some(*next),
+                    Sampler::Interval { .. } | Sampler::Every { .. } => None,
+                })
+                .min()
+        };
     }
 
     fn handle_updates(
@@ -550,7 +1140,10 @@ impl ConnectionCtx {
         if let Some(subscriber) = self.subscriber.upgrade() {
             self.msg_recvd = true;
             self.process_batch(batch, write_con, &subscriber)?;
-            if self.subscriptions.is_empty() && self.pending.is_empty() {
+            if self.subscriptions.is_empty()
+                && self.pending.is_empty()
+                && self.pending_many.is_empty()
+            {
                 let mut inner = subscriber.0.lock();
                 if self.from_sub.len() == 0 {
                     // we do this here the make sure we
@@ -572,13 +1165,13 @@ impl ConnectionCtx {
 
     async fn run(
         &mut self,
-        mut batches: Receiver<Result<(Pooled<Vec<From>>, bool)>>,
+        mut batches: Receiver<Result<DecodedBatch>>,
         write_con: &mut WriteChannel,
     ) -> Result<()> {
         async fn read_batch(
-            batches: &mut Receiver<Result<(Pooled<Vec<From>>, bool)>>,
+            batches: &mut Receiver<Result<DecodedBatch>>,
             blocked: &mut FuturesUnordered<BlockedChannelFut>,
-        ) -> Option<Result<(Pooled<Vec<From>>, bool)>> {
+        ) -> Option<Result<DecodedBatch>> {
             loop {
                 if blocked.len() > 0 {
                     let _: Option<_> = blocked.next().await;
@@ -587,10 +1180,11 @@ impl ConnectionCtx {
                 }
             }
         }
+        // returns how long the flush took
         async fn flush(
             con: &mut WriteChannel,
             pending: &mut Vec<oneshot::Sender<()>>,
-        ) -> Result<()> {
+        ) -> Result<Duration> {
             let mut flushed = || {
                 for s in pending.drain(..) {
                     let _ = s.send(());
@@ -600,30 +1194,57 @@ impl ConnectionCtx {
                 flushed();
                 future::pending().await
             } else {
+                let start = Instant::now();
                 con.flush().await?;
                 flushed();
-                Ok(())
+                Ok(start.elapsed())
+            }
+        }
+        async fn sample_timer(next: Option<Instant>) {
+            match next {
+                None => future::pending().await,
+                Some(next) => time::sleep_until(next).await,
             }
         }
         let mut periodic = time::interval_at(Instant::now() + PERIOD, PERIOD);
         loop {
             select_biased! {
-                r = flush(write_con, &mut self.pending_flushes).fuse() => r?,
+                r = flush(write_con, &mut self.pending_flushes).fuse() => {
+                    let lag = r?;
+                    if lag >= SLOW_FLUSH {
+                        self.conn_event(ConnEvent::SlowPublisher(self.addr, lag))
+                    }
+                },
                 now = periodic.tick().fuse() => self.handle_heartbeat(now)?,
+                () = sample_timer(self.next_sample).fuse() => self.flush_samples(),
                 batch = self.from_sub.recv().fuse() => match batch {
-                    Some(batch) => self.handle_from_sub(write_con, batch)?,
+                    Some(batch) => {
+                        self.handle_from_sub(write_con, batch)?;
+                        if self.closed.is_some() {
+                            write_con.flush().await?;
+                            for tx in self.pending_flushes.drain(..) {
+                                let _ = tx.send(());
+                            }
+                            break Ok(())
+                        }
+                    }
                     None => bail!("dropped"),
                 },
                 r = read_batch(
                     &mut batches,
                     &mut self.blocked_channels
                 ).fuse() => match r {
-                    Some(Ok((batch, true))) => {
-                        self.msg_recvd = true;
-                        self.process_updates_batch(batch);
+                    Some(Ok((seq, batch, only_updates))) => {
+                        if let Some(audit) = &mut self.audit {
+                            audit.batch(seq)
+                        }
+                        if only_updates {
+                            self.msg_recvd = true;
+                            self.process_updates_batch(batch);
+                        } else {
+                            self.handle_updates(write_con, batch)?
+                        }
                     },
-                    Some(Ok((batch, false))) =>
-                        self.handle_updates(write_con, batch)?,
                     Some(Err(e)) => break Err(Error::from(e)),
                     None => break Err(anyhow!("EOF")),
                 }
@@ -632,7 +1253,14 @@ impl ConnectionCtx {
     }
 
     pub(super) async fn start(mut self) -> Result<()> {
-        let soc = time::timeout(PERIOD, TcpStream::connect(self.addr)).await??;
+        let (prefer, timeout) = match self.subscriber.upgrade() {
+            None => bail!("subscriber dropped"),
+            Some(s) => {
+                let inner = s.0.lock();
+                (inner.prefer, inner.connect_timeout)
+            }
+        };
+        let soc = connect(&prefer.order(self.addr, self.alt_addr), timeout).await?;
         soc.set_nodelay(true)?;
         const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
         let con = time::timeout(
@@ -643,16 +1271,55 @@ impl ConnectionCtx {
                 self.uifo.take(),
                 &self.desired_auth,
                 &self.target_auth,
+                self.shm_ring,
             ),
         )
-        .await??;
-        let (read_con, mut write_con) = con.split();
+        .await;
+        let con = match con {
+            Ok(Ok(con)) => con,
+            Ok(Err(e)) => {
+                // io errors are the network's fault, except for
+                // invalid data, which is how tls reports a bad
+                // certificate. Anything else is a failed
+                // authentication or a protocol error.
+                let network = matches!(
+                    e.downcast_ref::<io::Error>(),
+                    Some(e) if e.kind() != io::ErrorKind::InvalidData
+                );
+                if network {
+                    return Err(e);
+                }
+                let mech = auth_mech(&self.target_auth);
+                self.conn_event(ConnEvent::AuthFailed(self.addr, mech));
+                return Err(Error::from(HandshakeFailed(e)));
+            }
+            Err(e) => return Err(Error::from(e)),
+        };
+        #[allow(unused_mut)]
+        let (mut read_con, mut write_con) = con.split();
+        if let Some(subscriber) = self.subscriber.upgrade() {
+            let mut inner = subscriber.0.lock();
+            read_con.set_limits(inner.decode_limits);
+            self.audit = Auditor::new(inner.audit, self.addr);
+            self.patches = inner.patches;
+            self.deltas = inner.deltas;
+            self.stats = inner.stats;
+            inner.conn_event(ConnEvent::Connected(self.addr, self.conid));
+            #[cfg(feature = "fault_injection")]
+            if let Some(faults) = &inner.faults {
+                faults.install(&mut read_con, &mut write_con)
+            }
+        }
         let (tx_stop, rx_stop) = oneshot::channel();
         let res = self.run(decode_task(read_con, rx_stop), &mut write_con).await;
         let _ = tx_stop.send(());
         if let Some(subscriber) = self.subscriber.upgrade() {
             let mut batch = DECODE_BATCHES.take();
-            batch.extend(self.subscriptions.keys().map(|id| From::Unsubscribed(*id)));
+            batch.extend(
+                self.subscriptions
+                    .keys()
+                    .map(|id| From::Unsubscribed(*id, UnsubscribeReason::Unspecified)),
+            );
             self.process_batch(batch, &mut write_con, &subscriber)?;
             for (_, req) in self.pending {
                 let _ = req.finished.send(Err(anyhow!("connection died")));
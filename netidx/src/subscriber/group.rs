@@ -0,0 +1,315 @@
+use super::{Event, SubId, Subscriber, SubscriberWeak, UpdatesFlags, Val};
+use crate::{
+    path::Path,
+    pool::{Pool, Pooled},
+    protocol::resolver::PublisherId,
+    resolver_client::ChangeTracker,
+};
+use anyhow::{anyhow, Result};
+use futures::{channel::mpsc, prelude::*, select_biased, stream::FuturesUnordered};
+use fxhash::{FxHashMap, FxHashSet};
+use log::{info, warn};
+use parking_lot::Mutex;
+use std::{
+    collections::{HashMap, HashSet},
+    iter,
+    net::SocketAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::time::{self, Instant};
+
+lazy_static! {
+    static ref GROUP_BATCHES: Pool<Vec<(PublisherId, GroupEvent)>> =
+        Pool::named("subscriber::group_batches", 64, 16384);
+}
+
+/// The default interval at which a `DvalGroup` asks the resolver
+/// whether the set of publishers of it's path has changed
+pub const DEFAULT_GROUP_POLL: Duration = Duration::from_secs(1);
+
+// how long to wait for one publisher to answer a subscription
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Something happened to one of the publishers in a `DvalGroup`
+#[derive(Debug, Clone, PartialEq)]
+pub enum GroupEvent {
+    /// a publisher at the address was discovered and subscribed
+    Added(SocketAddr),
+    /// the subscription to the publisher was dropped, either because
+    /// it no longer publishes the path, or because it unsubscribed
+    /// us, in which case an `Unsubscribed` update comes first. If the
+    /// publisher is still publishing it will be added again.
+    Removed,
+    /// the subscription to the publisher produced an event
+    Update(Event),
+}
+
+#[derive(Debug)]
+struct Member {
+    addr: SocketAddr,
+    val: Val,
+}
+
+#[derive(Debug)]
+struct GroupInner {
+    path: Path,
+    by_publisher: FxHashMap<PublisherId, Member>,
+    by_id: FxHashMap<SubId, PublisherId>,
+    updates: Vec<mpsc::Sender<Pooled<Vec<(PublisherId, GroupEvent)>>>>,
+}
+
+/// A subscription to `path` at every publisher currently publishing
+/// it, one `Val` per publisher. Where a `Dval` picks one publisher
+/// and fails over to another, a `DvalGroup` holds them all at once,
+/// so values from independent sources can be compared. Publishers
+/// that start publishing the path later are added, and publishers
+/// that stop are removed. See `Subscriber::subscribe_group`.
+///
+/// Publishers rejected by the subscriber's `on_connect` callback
+/// are not part of the group. When all references to the
+/// `DvalGroup` are dropped every subscription in it will be dropped.
+#[derive(Debug, Clone)]
+pub struct DvalGroup(Arc<Mutex<GroupInner>>);
+
+impl DvalGroup {
+    pub(super) fn new(subscriber: &Subscriber, path: Path, poll: Duration) -> DvalGroup {
+        let g = DvalGroup(Arc::new(Mutex::new(GroupInner {
+            path: path.clone(),
+            by_publisher: HashMap::default(),
+            by_id: HashMap::default(),
+            updates: Vec::new(),
+        })));
+        let group = Arc::downgrade(&g.0);
+        let rt = subscriber.0.lock().rt.clone();
+        let subscriber = subscriber.downgrade();
+        rt.spawn(async move {
+            if let Err(e) = run(subscriber, group, path.clone(), poll).await {
+                warn!("group {} maintenance task stopped {}", path, e)
+            }
+        });
+        g
+    }
+
+    /// the path the group is subscribed to
+    pub fn path(&self) -> Path {
+        self.0.lock().path.clone()
+    }
+
+    /// Get the subscription to the publisher `id`, if it is in the
+    /// group.
+    pub fn get(&self, id: &PublisherId) -> Option<Val> {
+        self.0.lock().by_publisher.get(id).map(|m| m.val.clone())
+    }
+
+    /// the publishers in the group, and their addresses
+    pub fn publishers(&self) -> Vec<(PublisherId, SocketAddr)> {
+        self.0.lock().by_publisher.iter().map(|(id, m)| (*id, m.addr)).collect()
+    }
+
+    /// the last event from every publisher in the group
+    pub fn last(&self) -> Vec<(PublisherId, Event)> {
+        self.0.lock().by_publisher.iter().map(|(id, m)| (*id, m.val.last())).collect()
+    }
+
+    /// Register `tx` to receive events from every publisher in the
+    /// group. When `tx` is registered it will immediately receive an
+    /// `Added` event for every publisher already in the group,
+    /// followed by the current value from each of them, as if they
+    /// had just been discovered. Events from different publishers are
+    /// delivered to the same channel in batches, keyed by publisher.
+    pub fn updates(&self, mut tx: mpsc::Sender<Pooled<Vec<(PublisherId, GroupEvent)>>>) {
+        let mut g = self.0.lock();
+        let mut batch = GROUP_BATCHES.take();
+        for (id, m) in g.by_publisher.iter() {
+            batch.push((*id, GroupEvent::Added(m.addr)));
+            match m.val.last() {
+                Event::Unsubscribed(_) => (),
+                e @ Event::Update(_) => batch.push((*id, GroupEvent::Update(e))),
+            }
+        }
+        if batch.is_empty() {
+            g.updates.push(tx)
+        } else {
+            match tx.try_send(batch) {
+                Err(e) if e.is_disconnected() => (),
+                Ok(()) | Err(_) => g.updates.push(tx),
+            }
+        }
+    }
+}
+
+impl GroupInner {
+    async fn send(g: &Mutex<Self>, batch: Pooled<Vec<(PublisherId, GroupEvent)>>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut chans = g.lock().updates.clone();
+        let mut failed = false;
+        for c in chans.iter_mut() {
+            let mut b = GROUP_BATCHES.take();
+            b.extend(batch.iter().cloned());
+            failed |= c.send(b).await.is_err();
+        }
+        if failed {
+            g.lock().updates.retain(|c| !c.is_closed());
+        }
+    }
+
+    fn remove(&mut self, id: &PublisherId) -> bool {
+        match self.by_publisher.remove(id) {
+            None => false,
+            Some(m) => {
+                self.by_id.remove(&m.val.id());
+                true
+            }
+        }
+    }
+}
+
+struct GroupSync {
+    path: Path,
+    ct: ChangeTracker,
+    // a member was lost, or a subscription failed, so the group
+    // must be reconciled even if the resolver says nothing changed
+    dirty: bool,
+    tx_up: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+}
+
+impl GroupSync {
+    // reconcile the group with the publishers the resolver knows
+    async fn sync(
+        &mut self,
+        subscriber: &Subscriber,
+        group: &Mutex<GroupInner>,
+    ) -> Result<()> {
+        let resolver = subscriber.resolver();
+        if !resolver.check_changed(&mut self.ct).await? && !self.dirty {
+            return Ok(());
+        }
+        self.dirty = false;
+        let (publishers, mut resolved) =
+            resolver.resolve(iter::once(self.path.clone())).await?;
+        let resolved = resolved.pop().ok_or_else(|| anyhow!("empty resolution"))?;
+        let current =
+            resolved.publishers.iter().map(|pref| pref.id).collect::<FxHashSet<_>>();
+        let mut batch = GROUP_BATCHES.take();
+        let mut pending = FuturesUnordered::new();
+        {
+            let mut g = group.lock();
+            let removed = g
+                .by_publisher
+                .keys()
+                .filter(|id| !current.contains(*id))
+                .copied()
+                .collect::<Vec<_>>();
+            for id in removed {
+                g.remove(&id);
+                batch.push((id, GroupEvent::Removed));
+            }
+            // publishers sharing an address are the same publisher
+            // under a stale record, the connection can only hold one
+            let mut addrs =
+                g.by_publisher.values().map(|m| m.addr).collect::<HashSet<_>>();
+            for pref in resolved.publishers.iter() {
+                let pb = match publishers.get(&pref.id) {
+                    Some(pb) => pb,
+                    None => continue,
+                };
+                if g.by_publisher.contains_key(&pref.id)
+                    || !subscriber.0.lock().acceptable(pb)
+                    || !addrs.insert(pb.addr)
+                {
+                    continue;
+                }
+                let deadline = Instant::now() + SUBSCRIBE_TIMEOUT;
+                match subscriber.subscribe_at(&self.path, pb, pref, &resolved, deadline) {
+                    // retry when the subscription in flight is done
+                    Ok(None) => self.dirty = true,
+                    Ok(Some(rx)) => {
+                        let (id, addr) = (pref.id, pb.addr);
+                        pending.push(async move {
+                            let r = match time::timeout(SUBSCRIBE_TIMEOUT, rx).await {
+                                Err(_) => Err(anyhow!("timed out")),
+                                Ok(Err(_)) => Err(anyhow!("connection died")),
+                                Ok(Ok(r)) => r,
+                            };
+                            (id, addr, r)
+                        })
+                    }
+                    Err(e) => {
+                        info!(
+                            "group {} subscribe to {} failed {}",
+                            self.path, pb.addr, e
+                        );
+                        self.dirty = true
+                    }
+                }
+            }
+        }
+        while let Some((id, addr, r)) = pending.next().await {
+            match r {
+                Err(e) => {
+                    info!("group {} subscribe to {} failed {}", self.path, addr, e);
+                    self.dirty = true
+                }
+                Ok(val) => {
+                    let mut g = group.lock();
+                    g.by_id.insert(val.id(), id);
+                    val.updates(UpdatesFlags::BEGIN_WITH_LAST, self.tx_up.clone());
+                    g.by_publisher.insert(id, Member { addr, val });
+                    batch.push((id, GroupEvent::Added(addr)));
+                }
+            }
+        }
+        GroupInner::send(group, batch).await;
+        Ok(())
+    }
+}
+
+async fn run(
+    subscriber: SubscriberWeak,
+    group: Weak<Mutex<GroupInner>>,
+    path: Path,
+    poll: Duration,
+) -> Result<()> {
+    let ct = ChangeTracker::new(path.clone());
+    let (tx_up, mut rx_up) = mpsc::channel(3);
+    let mut sync = GroupSync { path, ct, dirty: true, tx_up };
+    let mut poll = time::interval(poll);
+    loop {
+        select_biased! {
+            _ = poll.tick().fuse() => {
+                let (subscriber, group) = match (subscriber.upgrade(), group.upgrade()) {
+                    (Some(s), Some(g)) => (s, g),
+                    (_, _) => break Ok(()),
+                };
+                if let Err(e) = sync.sync(&subscriber, &group).await {
+                    warn!("failed to sync group {}, will retry {}", sync.path, e);
+                    sync.dirty = true
+                }
+            },
+            mut up = rx_up.select_next_some() => {
+                let group = match group.upgrade() {
+                    Some(g) => g,
+                    None => break Ok(()),
+                };
+                let mut batch = GROUP_BATCHES.take();
+                {
+                    let mut g = group.lock();
+                    for (sid, ev) in up.drain(..) {
+                        if let Some(id) = g.by_id.get(&sid).copied() {
+                            let unsubscribed = matches!(ev, Event::Unsubscribed(_));
+                            batch.push((id, GroupEvent::Update(ev)));
+                            if unsubscribed && g.remove(&id) {
+                                batch.push((id, GroupEvent::Removed));
+                                sync.dirty = true;
+                            }
+                        }
+                    }
+                }
+                GroupInner::send(&group, batch).await
+            },
+        }
+    }
+}
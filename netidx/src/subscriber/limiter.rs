@@ -0,0 +1,56 @@
+use parking_lot::Mutex;
+use rand::Rng;
+use std::{cmp::max, time::Duration};
+use tokio::time::{self, Instant};
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last: Instant,
+}
+
+/// A token bucket shared by everything in a subscriber that talks to
+/// the resolver or opens a new connection to a publisher. Callers
+/// that find the bucket empty reserve a future token, so they are
+/// served in the order they arrived, and then sleep until it is
+/// theirs plus up to one token interval of jitter, so that queued
+/// attempts are spread out instead of firing together.
+#[derive(Debug)]
+pub(super) struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    bucket: Mutex<Bucket>,
+}
+
+impl RateLimiter {
+    pub(super) fn new(rate: u32, burst: u32) -> Self {
+        let rate = max(1, rate) as f64;
+        let burst = max(1, burst) as f64;
+        let bucket = Mutex::new(Bucket { tokens: burst, last: Instant::now() });
+        RateLimiter { rate, burst, bucket }
+    }
+
+    /// Take a token, returning how long the caller must wait before
+    /// using it, or `None` if it may be used now.
+    fn reserve(&self) -> Option<Duration> {
+        let mut b = self.bucket.lock();
+        let now = Instant::now();
+        let elapsed = now.saturating_duration_since(b.last).as_secs_f64();
+        b.tokens = (b.tokens + elapsed * self.rate).min(self.burst);
+        b.last = now;
+        b.tokens -= 1.;
+        if b.tokens >= 0. {
+            None
+        } else {
+            Some(Duration::from_secs_f64(-b.tokens / self.rate))
+        }
+    }
+
+    /// Wait until a token is available
+    pub(super) async fn acquire(&self) {
+        if let Some(wait) = self.reserve() {
+            let jitter = rand::thread_rng().gen_range(0. ..1.) / self.rate;
+            time::sleep(wait + Duration::from_secs_f64(jitter)).await
+        }
+    }
+}
@@ -1,23 +1,34 @@
+mod audit;
 mod connection;
-pub use crate::protocol::value::{FromValue, Typ, Value};
+mod group;
+mod limiter;
+mod tree;
+pub use crate::protocol::publisher::{UnsubscribeReason, ValPermissions, WriteKey};
+pub use crate::protocol::schema::Schema;
+pub use crate::protocol::value::{ErrorInfo, FromValue, Typ, Value, WriteOutcome};
 pub use crate::resolver_client::DesiredAuth;
 use crate::{
     batch_channel::{self, BatchSender},
+    chars::Chars,
     config::Config,
-    pack::{Pack, PackError},
+    pack::{DecodeLimits, Pack, PackError},
     path::Path,
-    pool::{Pool, Pooled},
+    pool::{self, Pool, PoolConfig, Pooled},
     protocol::{
+        glob::{Glob, GlobSet},
         publisher::{From, Id},
-        resolver::{Publisher, PublisherId, Resolved, TargetAuth},
+        resolver::{Publisher, PublisherId, PublisherRef, Resolved, TargetAuth},
+        schema::schema_path,
     },
     publisher::PublishFlags,
     resolver_client::ResolverRead,
     tls,
     utils::{BatchItem, Batched, ChanId, ChanWrap},
 };
-use anyhow::{anyhow, Error, Result};
+use anyhow::{anyhow, bail, Error, Result};
+use arcstr::{literal, ArcStr};
 use bytes::{Buf, BufMut, Bytes};
+use connection::{HandshakeFailed, Health};
 use futures::{
     channel::{
         mpsc::{self, Sender, UnboundedReceiver, UnboundedSender},
@@ -28,25 +39,35 @@ use futures::{
     stream::FuturesUnordered,
 };
 use fxhash::FxHashMap;
+use limiter::RateLimiter;
 use log::{info, warn};
 use netidx_netproto::resolver::UserInfo;
 use parking_lot::Mutex;
 use rand::Rng;
+use serde::de::DeserializeOwned;
 use std::{
-    cmp::{max, Eq, PartialEq},
-    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    cmp::{max, Eq, PartialEq, Reverse},
+    collections::{hash_map::Entry, BTreeMap, HashMap, HashSet, VecDeque},
     error, fmt,
     hash::Hash,
     iter, mem,
     net::SocketAddr,
+    pin::Pin,
     result,
-    sync::{Arc, Weak},
+    sync::{
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+        Arc, Weak,
+    },
+    task::{Context, Poll},
     time::Duration,
 };
 use tokio::{
+    runtime::Handle,
     task,
     time::{self, Instant},
 };
+pub use group::{DvalGroup, GroupEvent, DEFAULT_GROUP_POLL};
+pub use tree::{Tree, TreeEvent, DEFAULT_TREE_POLL};
 use triomphe::Arc as TArc;
 
 type StreamsInner<T> = Arc<Vec<(T, ChanWrap<Pooled<Vec<(SubId, Event)>>>)>>;
@@ -54,10 +75,12 @@ type StreamsInner<T> = Arc<Vec<(T, ChanWrap<Pooled<Vec<(SubId, Event)>>>)>>;
 lazy_static! {
     static ref HCSTREAMS: Mutex<HashSet<StreamsInner<ChanId>>> =
         Mutex::new(HashSet::new());
-    static ref HCDVSTREAMS: Mutex<HashSet<StreamsInner<UpdatesFlags>>> =
+    static ref HCDVSTREAMS: Mutex<HashSet<StreamsInner<(UpdatesFlags, Option<Sample>)>>> =
         Mutex::new(HashSet::new());
-    static ref BATCHES: Pool<Vec<(SubId, Event)>> = Pool::new(64, 16384);
-    static ref DECODE_BATCHES: Pool<Vec<From>> = Pool::new(64, 16384);
+    static ref BATCHES: Pool<Vec<(SubId, Event)>> =
+        Pool::named("subscriber::batches", 64, 16384);
+    static ref DECODE_BATCHES: Pool<Vec<From>> =
+        Pool::named("subscriber::decode_batches", 64, 16384);
 }
 
 macro_rules! hcstreams {
@@ -113,7 +136,7 @@ macro_rules! hcstreams {
 }
 
 hcstreams!(Streams, HCSTREAMS, ChanId);
-hcstreams!(DvStreams, HCDVSTREAMS, UpdatesFlags);
+hcstreams!(DvStreams, HCDVSTREAMS, (UpdatesFlags, Option<Sample>));
 
 #[derive(Debug)]
 pub struct PermissionDenied;
@@ -137,6 +160,17 @@ impl fmt::Display for NoSuchValue {
 
 impl error::Error for NoSuchValue {}
 
+#[derive(Debug)]
+pub struct Canceled;
+
+impl fmt::Display for Canceled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "subscription canceled")
+    }
+}
+
+impl error::Error for Canceled {}
+
 atomic_id!(SubId);
 atomic_id!(SubscriberId);
 atomic_id!(ConId);
@@ -159,10 +193,77 @@ bitflags! {
         /// flexibility.
         const STOP_COLLECTING_LAST = 0x02;
 
-        /// If BEGIN_WITH_LAST is set, and you reregister the same
-        /// channel, do not send the last again to that
-        /// channel.
+        /// If BEGIN_WITH_LAST or BEGIN_WITH_HISTORY is set, and you
+        /// reregister the same channel, do not send the last or the
+        /// history again to that channel.
         const NO_SPURIOUS          = 0x04;
+
+        /// if set, then the events in the subscription's history
+        /// (see `Val::set_history`) will be sent immediatly, oldest
+        /// first. If the history is empty this behaves like
+        /// BEGIN_WITH_LAST if that is also set.
+        const BEGIN_WITH_HISTORY   = 0x08;
+
+        /// If set, then an update that is equal to the last value
+        /// sent to this channel will not be sent, e.g. when a
+        /// publisher resends the same value on a timer. Other
+        /// channels registered on the same subscription are not
+        /// affected. Suppressed updates are counted in
+        /// `SubStats::suppressed`.
+        const DEDUP                = 0x10;
+    }
+}
+
+/// Deliver only a sample of the updates to a subscription to a
+/// channel, see `Val::updates_sampled`. Other channels registered on
+/// the same subscription are not affected.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Sample {
+    /// deliver the first update and then every Nth update after
+    /// it. 0 is treated as 1.
+    Every(u32),
+    /// deliver at most one update per interval. If updates arrive
+    /// faster than that the latest one is delivered at the end of
+    /// the interval, the rest are dropped.
+    Interval(Duration),
+}
+
+// Flattens the batches from a channel registered with `updates`
+// into a stream of events. Batches are reversed once so events can
+// be popped off the end, the batch goes back to the pool when it's
+// empty.
+struct UpdatesStream {
+    rx: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+    batch: Option<Pooled<Vec<(SubId, Event)>>>,
+}
+
+impl UpdatesStream {
+    fn new(rx: mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>) -> Self {
+        UpdatesStream { rx, batch: None }
+    }
+}
+
+impl Stream for UpdatesStream {
+    type Item = (SubId, Event);
+
+    fn poll_next(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Self::Item>> {
+        loop {
+            if let Some(ev) = self.batch.as_mut().and_then(|b| b.pop()) {
+                break Poll::Ready(Some(ev));
+            }
+            self.batch = None;
+            match self.rx.poll_next_unpin(cx) {
+                Poll::Pending => break Poll::Pending,
+                Poll::Ready(None) => break Poll::Ready(None),
+                Poll::Ready(Some(mut batch)) => {
+                    batch.reverse();
+                    self.batch = Some(batch);
+                }
+            }
+        }
     }
 }
 
@@ -176,50 +277,244 @@ struct SubscribeValRequest {
     resolver: SocketAddr,
     finished: oneshot::Sender<Result<Val>>,
     con: BatchSender<ToCon>,
+    // when the request was sent to the connection
+    queued: Instant,
     deadline: Option<Instant>,
 }
 
+#[derive(Debug)]
+struct SubscribeManyRequest {
+    base: Path,
+    glob: Chars,
+    timestamp: u64,
+    permissions: u32,
+    token: Bytes,
+    resolver: SocketAddr,
+    finished: oneshot::Sender<Result<Vec<(Path, Val)>>>,
+    con: BatchSender<ToCon>,
+    // when the request was sent to the connection
+    queued: Instant,
+    deadline: Option<Instant>,
+}
+
+/// The publisher's reply to a write sent with an idempotency key,
+/// see `Dval::write_keyed`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WriteReceipt {
+    /// The result of the write. For a duplicate this is the result
+    /// of the original write if the publisher still knows it,
+    /// otherwise `Value::Ok`.
+    pub result: Value,
+    /// The publisher had already received a write with the same key
+    /// recently, and did not execute this one.
+    pub duplicate: bool,
+}
+
+#[derive(Debug)]
+enum WriteReply {
+    Value(oneshot::Sender<Value>),
+    Receipt(oneshot::Sender<WriteReceipt>),
+    // a write tracked by a `Dval` with a `WriteRetry` policy, the
+    // real reply is held by the `Dval` until the write is acked
+    Tracked(DvalWeak, u64),
+}
+
+// what `Dval::prepare_write` did with a write
+enum WriteAction {
+    Send(ConId, BatchSender<ToCon>, ToCon),
+    Queued,
+    Dropped,
+}
+
+/// How long a transient subscription made by `Subscriber::write_many`
+/// keeps trying to subscribe before it gives up, and drops it's
+/// writes.
+pub const TRANSIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// What `Subscriber::write_many` did with the writes it was given
+#[derive(Debug, Default)]
+pub struct WriteResults {
+    /// The number of writes sent to a publisher
+    pub sent: usize,
+    /// The number of writes queued on a subscription that isn't
+    /// currently alive, they will be sent when it is
+    pub queued: usize,
+    /// The number of writes dropped because their subscription
+    /// failed permanently
+    pub dropped: usize,
+    /// The paths that had no durable subscription. A transient one
+    /// was made for each of them, which lives until it's writes are
+    /// sent, or until `TRANSIENT_WRITE_TIMEOUT`.
+    pub transient: Vec<Path>,
+    /// The publisher's reply to each write, in the order the writes
+    /// were given. Only filled in by
+    /// `Subscriber::write_many_with_recipt`.
+    pub receipts: Vec<(Path, oneshot::Receiver<Value>)>,
+}
+
+impl WriteResults {
+    /// Wait for all the receipts. A write that was canceled, e.g.
+    /// because it's connection died before the publisher replied, is
+    /// an error.
+    pub async fn wait(self) -> Vec<(Path, Result<Value>)> {
+        let mut res = Vec::with_capacity(self.receipts.len());
+        for (path, rx) in self.receipts {
+            let r = rx.await.map_err(|_| anyhow!("the write was canceled"));
+            res.push((path, r));
+        }
+        res
+    }
+}
+
 #[derive(Debug)]
 enum ToCon {
     Subscribe(SubscribeValRequest),
+    SubscribeMany(SubscribeManyRequest),
     Unsubscribe(Id),
     Stream {
         id: Id,
         sub_id: SubId,
         tx: Sender<Pooled<Vec<(SubId, Event)>>>,
         flags: UpdatesFlags,
+        sample: Option<Sample>,
     },
-    Write(Id, Value, Option<oneshot::Sender<Value>>),
+    Write(Id, Value, Option<WriteKey>, Option<WriteReply>),
     Flush(oneshot::Sender<()>),
+    // flush and close the connection, the sender is dropped when the
+    // connection task exits
+    Close(oneshot::Sender<()>),
+}
+
+/// Connection level events, see `Subscriber::connection_events`
+#[derive(Debug, Clone, PartialEq)]
+pub enum ConnEvent {
+    /// A connection to the publisher at the address was established
+    Connected(SocketAddr, ConId),
+    /// The connection to the publisher at the address ended, or could
+    /// not be established, for the specified reason.
+    Disconnected(SocketAddr, ArcStr),
+    /// The handshake with the publisher at the address failed using
+    /// the specified authentication mechanism. `Disconnected` follows.
+    AuthFailed(SocketAddr, &'static str),
+    /// Writing a batch to the publisher at the address took the
+    /// specified time, it isn't keeping up.
+    SlowPublisher(SocketAddr, Duration),
 }
 
-#[derive(Debug, Clone, PartialEq, PartialOrd)]
+/// With serde an `Event` is externally tagged, the same as `Value`,
+/// e.g. `{"Update": {"U64": 42}}` in json.
+#[derive(Debug, Clone, PartialEq, PartialOrd, Serialize, Deserialize)]
 pub enum Event {
-    Unsubscribed,
+    /// The subscription ended. The reason is `Unspecified` if the
+    /// connection to the publisher died, or the publisher didn't say
+    /// why.
+    Unsubscribed(UnsubscribeReason),
     Update(Value),
 }
 
+// Unsubscribed(Unspecified) keeps the original single byte encoding
 impl Pack for Event {
     fn encoded_len(&self) -> usize {
         match self {
-            Event::Unsubscribed => 1,
+            Event::Unsubscribed(UnsubscribeReason::Unspecified) => 1,
+            Event::Unsubscribed(r) => 1 + Pack::encoded_len(r),
             Event::Update(v) => Pack::encoded_len(v),
         }
     }
 
     fn encode(&self, buf: &mut impl BufMut) -> result::Result<(), PackError> {
         match self {
-            Event::Unsubscribed => Ok(buf.put_u8(0x40)),
+            Event::Unsubscribed(UnsubscribeReason::Unspecified) => Ok(buf.put_u8(0x40)),
+            Event::Unsubscribed(r) => {
+                buf.put_u8(0x41);
+                Pack::encode(r, buf)
+            }
             Event::Update(v) => Pack::encode(v, buf),
         }
     }
 
     fn decode(buf: &mut impl Buf) -> result::Result<Self, PackError> {
-        if buf.chunk()[0] == 0x40 {
-            buf.advance(1);
-            Ok(Event::Unsubscribed)
-        } else {
-            Ok(Event::Update(Pack::decode(buf)?))
+        match buf.chunk()[0] {
+            0x40 => {
+                buf.advance(1);
+                Ok(Event::Unsubscribed(UnsubscribeReason::Unspecified))
+            }
+            0x41 => {
+                buf.advance(1);
+                Ok(Event::Unsubscribed(Pack::decode(buf)?))
+            }
+            _ => Ok(Event::Update(Pack::decode(buf)?)),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct History {
+    cap: AtomicUsize,
+    events: Mutex<VecDeque<(Instant, Event)>>,
+}
+
+impl History {
+    fn push(&self, ev: &Event) {
+        if self.cap.load(Ordering::Relaxed) > 0 {
+            let mut events = self.events.lock();
+            let cap = self.cap.load(Ordering::Relaxed);
+            if cap > 0 {
+                while events.len() >= cap {
+                    events.pop_front();
+                }
+                events.push_back((Instant::now(), ev.clone()));
+            }
+        }
+    }
+
+    fn set_cap(&self, cap: usize) {
+        self.cap.store(cap, Ordering::Relaxed);
+        let mut events = self.events.lock();
+        while events.len() > cap {
+            events.pop_front();
+        }
+    }
+}
+
+/// Counts of what a subscription has received, see
+/// `SubscriberBuilder::stats`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SubStats {
+    /// The number of updates received, a patch counts as one update
+    pub updates: u64,
+    /// The encoded size of the updates received in bytes. For
+    /// patches and deltas this is the size of the change, not of the
+    /// value.
+    pub bytes: u64,
+    /// The number of updates not sent to a channel because they
+    /// were equal to the last value sent to it, see
+    /// `UpdatesFlags::DEDUP`
+    pub suppressed: u64,
+}
+
+#[derive(Debug, Default)]
+struct StatCounters {
+    updates: AtomicU64,
+    bytes: AtomicU64,
+    suppressed: AtomicU64,
+}
+
+impl StatCounters {
+    fn record(&self, bytes: usize) {
+        self.updates.fetch_add(1, Ordering::Relaxed);
+        self.bytes.fetch_add(bytes as u64, Ordering::Relaxed);
+    }
+
+    fn suppressed(&self) {
+        self.suppressed.fetch_add(1, Ordering::Relaxed);
+    }
+
+    fn get(&self) -> SubStats {
+        SubStats {
+            updates: self.updates.load(Ordering::Relaxed),
+            bytes: self.bytes.load(Ordering::Relaxed),
+            suppressed: self.suppressed.load(Ordering::Relaxed),
         }
     }
 }
@@ -231,6 +526,10 @@ struct ValInner {
     conid: ConId,
     connection: BatchSender<ToCon>,
     last: TArc<Mutex<Event>>,
+    history: TArc<History>,
+    stats: Option<TArc<StatCounters>>,
+    publisher_user: Option<UserInfo>,
+    permissions: Option<ValPermissions>,
 }
 
 impl Drop for ValInner {
@@ -263,6 +562,29 @@ impl Val {
         self.0.last.lock().clone()
     }
 
+    /// Keep the last `n` events received by this subscription, along
+    /// with the time they were received, so that consumers that
+    /// attach late can catch up. See `history` and
+    /// `UpdatesFlags::BEGIN_WITH_HISTORY`. History is off by default,
+    /// setting `n` to 0 turns it off again and discards it. Only
+    /// events received after history is turned on are kept.
+    pub fn set_history(&self, n: usize) {
+        self.0.history.set_cap(n)
+    }
+
+    /// Get the events in the history, oldest first. Empty if history
+    /// is not turned on.
+    pub fn history(&self) -> Vec<(Instant, Event)> {
+        self.0.history.events.lock().iter().cloned().collect()
+    }
+
+    /// Get the number of updates, and bytes, received by this
+    /// subscription since it was created. `None` unless stats are
+    /// turned on, see `SubscriberBuilder::stats`.
+    pub fn stats(&self) -> Option<SubStats> {
+        self.0.stats.as_ref().map(|s| s.get())
+    }
+
     /// Register `tx` to receive updates to this `Val`.
     ///
     /// You may register multiple different channels to receive
@@ -275,7 +597,37 @@ impl Val {
     /// will get an update with the current state, even though the
     /// channel registration will be ignored.
     pub fn updates(&self, flags: UpdatesFlags, tx: Sender<Pooled<Vec<(SubId, Event)>>>) {
-        let m = ToCon::Stream { tx, sub_id: self.0.sub_id, id: self.0.id, flags };
+        self.send_stream(flags, None, tx)
+    }
+
+    /// Register `tx` to receive a sample of the updates to this
+    /// `Val`, as described by `sample`. This lets a consumer that
+    /// doesn't need every update, e.g. a diagnostic display, watch a
+    /// fast moving value cheaply while other channels registered on
+    /// the same `Val` still get every update. The sampling is done in
+    /// the connection task, so unsampled updates are never sent to
+    /// `tx`.
+    ///
+    /// The sampling mode is fixed when the channel is first
+    /// registered with the `Val`, registering the same channel again
+    /// will not change it. `BEGIN_WITH_LAST` and `Unsubscribed` are
+    /// always delivered.
+    pub fn updates_sampled(
+        &self,
+        flags: UpdatesFlags,
+        sample: Sample,
+        tx: Sender<Pooled<Vec<(SubId, Event)>>>,
+    ) {
+        self.send_stream(flags, Some(sample), tx)
+    }
+
+    fn send_stream(
+        &self,
+        flags: UpdatesFlags,
+        sample: Option<Sample>,
+        tx: Sender<Pooled<Vec<(SubId, Event)>>>,
+    ) {
+        let m = ToCon::Stream { tx, sub_id: self.0.sub_id, id: self.0.id, flags, sample };
         self.0.connection.send(m);
     }
 
@@ -290,7 +642,7 @@ impl Val {
     /// update values you are subscribed to, or trigger some other
     /// observable action.
     pub fn write(&self, v: Value) {
-        self.0.connection.send(ToCon::Write(self.0.id, v, None));
+        self.0.connection.send(ToCon::Write(self.0.id, v, None, None));
     }
 
     /// This does the same thing as `write` except that it requires
@@ -303,7 +655,20 @@ impl Val {
     /// are required.
     pub fn write_with_recipt(&self, v: Value) -> oneshot::Receiver<Value> {
         let (tx, rx) = oneshot::channel();
-        self.0.connection.send(ToCon::Write(self.0.id, v, Some(tx)));
+        let reply = Some(WriteReply::Value(tx));
+        self.0.connection.send(ToCon::Write(self.0.id, v, None, reply));
+        rx
+    }
+
+    /// Write with an idempotency key, see `Dval::write_keyed`.
+    pub fn write_keyed(
+        &self,
+        v: Value,
+        key: WriteKey,
+    ) -> oneshot::Receiver<WriteReceipt> {
+        let (tx, rx) = oneshot::channel();
+        let reply = Some(WriteReply::Receipt(tx));
+        self.0.connection.send(ToCon::Write(self.0.id, v, Some(key), reply));
         rx
     }
 
@@ -312,6 +677,25 @@ impl Val {
         self.0.sub_id
     }
 
+    /// Get the user info the resolver attested to the publisher of
+    /// this value on our behalf, as it was presented to the
+    /// publisher when the connection was established. This will be
+    /// `None` for anonymous connections.
+    pub fn publisher_user(&self) -> Option<&UserInfo> {
+        self.0.publisher_user.as_ref()
+    }
+
+    /// Get our permissions for this value, as the publisher reported
+    /// them when we subscribed, e.g. to decide whether to offer the
+    /// user a way to write. Writes made without write permission
+    /// fail with `ErrorInfo::PERMISSION_DENIED` or
+    /// `ErrorInfo::WRITES_NOT_ACCEPTED`. `None` if the publisher is
+    /// too old to report permissions, or the value came from
+    /// `Subscriber::subscribe_many`.
+    pub fn permissions(&self) -> Option<ValPermissions> {
+        self.0.permissions
+    }
+
     pub async fn flush(&self) -> Result<()> {
         let (tx, rx) = oneshot::channel();
         self.0.connection.send(ToCon::Flush(tx));
@@ -321,22 +705,339 @@ impl Val {
 
 #[derive(Debug)]
 struct DvDead {
-    queued_writes: Vec<(Value, Option<oneshot::Sender<Value>>)>,
+    queued_writes: Vec<(Value, Option<WriteKey>, Option<WriteReply>)>,
     tries: usize,
     next_try: Instant,
+    since: Instant,
+    // the last attempt was denied permission
+    denied: bool,
+}
+
+impl DvDead {
+    fn new(now: Instant) -> Self {
+        DvDead {
+            queued_writes: Vec::new(),
+            tries: 0,
+            next_try: now,
+            since: now,
+            denied: false,
+        }
+    }
 }
 
 #[derive(Debug)]
 enum DvState {
     Subscribed(Val),
     Dead(Box<DvDead>), // the box ensures that DvState is tag + 1 word
+    Failed,
+}
+
+/// The state of a `Dval`, see `Dval::state` and `Dval::states`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DvalState {
+    /// The `Dval` is subscribed
+    Subscribed,
+    /// The `Dval` is not subscribed, and will be retried
+    Dead,
+    /// The `Dval` is not subscribed because the last attempt was
+    /// denied permission. Whether and when it will be retried depends
+    /// on the subscriber's `OnDenied` policy.
+    Denied,
+    /// The `Dval` gave up according to it's `GiveUp` policy, it will
+    /// never be retried. Subscribing to the path again will start a
+    /// new `Dval`.
+    Failed,
+}
+
+/// When a `Dval` should stop trying to resubscribe, see
+/// `Dval::set_give_up`. The default is to never give up.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct GiveUp {
+    /// Give up after this many consecutive failed attempts
+    pub max_tries: Option<usize>,
+    /// Give up if the `Dval` has been dead for this long
+    pub after: Option<Duration>,
+}
+
+impl GiveUp {
+    fn expired(&self, d: &DvDead, now: Instant) -> bool {
+        self.max_tries.map(|n| d.tries >= n).unwrap_or(false)
+            || self.after.map(|t| now - d.since >= t).unwrap_or(false)
+    }
+}
+
+/// Which of a publisher's addresses to try first when it listens on
+/// both an ipv4 and an ipv6 address, see `SubscriberBuilder::prefer`.
+/// If connecting to the first address fails, or times out, the other
+/// is tried. The default is `Primary`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PreferFamily {
+    /// The publisher's primary address, `Publisher::addr`
+    Primary,
+    /// The ipv4 address
+    V4,
+    /// The ipv6 address
+    V6,
+}
+
+impl Default for PreferFamily {
+    fn default() -> Self {
+        PreferFamily::Primary
+    }
+}
+
+impl PreferFamily {
+    // the addresses in the order they should be tried
+    fn order(self, addr: SocketAddr, alt: Option<SocketAddr>) -> Vec<SocketAddr> {
+        match alt {
+            None => vec![addr],
+            Some(alt) => {
+                let alt_first = match self {
+                    PreferFamily::Primary => false,
+                    PreferFamily::V4 => alt.is_ipv4(),
+                    PreferFamily::V6 => alt.is_ipv6(),
+                };
+                if alt_first {
+                    vec![alt, addr]
+                } else {
+                    vec![addr, alt]
+                }
+            }
+        }
+    }
+}
+
+type RefreshHook =
+    Arc<dyn Fn(Path) -> Pin<Box<dyn Future<Output = bool> + Send>> + Send + Sync>;
+
+/// A write that a `Dval` gave up on delivering, see `WriteRetry`
+#[derive(Debug, Clone)]
+pub struct DeadLetter {
+    pub path: Path,
+    pub value: Value,
+    pub key: Option<WriteKey>,
+    /// the number of times the write was sent
+    pub tries: usize,
+}
+
+/// At least once delivery of writes, see `Dval::set_write_retry`.
+///
+/// Writes that need a reply (`write_with_recipt` and `write_keyed`)
+/// are remembered until the publisher replies. If the connection
+/// dies first they are sent again, in order, when the `Dval`
+/// resubscribes, possibly to a different publisher. A write that has
+/// already been sent `max_tries` times, or that is still unacked when
+/// the `Dval` fails, is dropped and passed to `dead_letter`, and it's
+/// reply channel is canceled.
+///
+/// A write may be executed more than once, e.g. if the publisher
+/// received it but died before replying. Use `write_keyed` if that
+/// matters.
+#[derive(Clone)]
+pub struct WriteRetry {
+    /// The most times a write will be sent
+    pub max_tries: usize,
+    /// Called with each write that is given up on. It is called with
+    /// internal locks held, so it must not block or call into the
+    /// subscriber.
+    pub dead_letter: Option<Arc<dyn Fn(DeadLetter) + Send + Sync>>,
+}
+
+impl fmt::Debug for WriteRetry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteRetry")
+            .field("max_tries", &self.max_tries)
+            .field("dead_letter", &self.dead_letter.is_some())
+            .finish()
+    }
+}
+
+impl Default for WriteRetry {
+    fn default() -> Self {
+        WriteRetry { max_tries: 3, dead_letter: None }
+    }
+}
+
+/// What durable subscriptions do when they are denied permission,
+/// see `SubscriberBuilder::on_denied`. The default is
+/// `RetryAfter(DEFAULT_DENIED_RETRY)`. In every case the `Dval`
+/// moves to `DvalState::Denied`, and its `GiveUp` policy still
+/// applies.
+#[derive(Clone)]
+pub enum OnDenied {
+    /// Treat denial like any other failure, retry with the usual
+    /// linear backoff
+    Retry,
+    /// Retry after the specified time
+    RetryAfter(Duration),
+    /// Never retry, the `Dval` moves to `DvalState::Failed`
+    GiveUp,
+    /// Call the hook with the path, and when the future it returns
+    /// resolves retry immediately if it is true, or give up if it is
+    /// false. Use this to refresh credentials before retrying. See
+    /// `OnDenied::refresh`.
+    Refresh(RefreshHook),
+}
+
+impl fmt::Debug for OnDenied {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OnDenied::Retry => write!(f, "Retry"),
+            OnDenied::RetryAfter(d) => write!(f, "RetryAfter({:?})", d),
+            OnDenied::GiveUp => write!(f, "GiveUp"),
+            OnDenied::Refresh(_) => write!(f, "Refresh"),
+        }
+    }
+}
+
+impl Default for OnDenied {
+    fn default() -> Self {
+        OnDenied::RetryAfter(DEFAULT_DENIED_RETRY)
+    }
+}
+
+impl OnDenied {
+    /// Build an `OnDenied::Refresh` policy from an async function
+    pub fn refresh<F, Fut>(f: F) -> Self
+    where
+        F: Fn(Path) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = bool> + Send + 'static,
+    {
+        OnDenied::Refresh(Arc::new(move |path| Box::pin(f(path))))
+    }
+}
+
+/// What the subscriber does when auditing finds that messages from a
+/// publisher were lost, duplicated, or reordered, see
+/// `SubscriberBuilder::audit`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Audit {
+    /// Don't audit
+    #[default]
+    Off,
+    /// Log every violation at the error level
+    Log,
+    /// Panic on the first violation
+    Panic,
+}
+
+/// How urgently a durable subscription should be established, see
+/// `Subscriber::subscribe_with_priority` and `Dval::set_priority`.
+/// When more subscriptions are due than fit in one resubscription
+/// pass, higher priorities go first. `Background` subscriptions are
+/// additionally limited to `SubscriberBuilder::max_background`
+/// attempts in flight at once, so they trickle in without delaying
+/// anything else.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Priority {
+    Background,
+    Normal,
+    High,
+}
+
+impl Default for Priority {
+    fn default() -> Self {
+        Priority::Normal
+    }
+}
+
+// a write tracked by a `WriteRetry` policy
+#[derive(Debug)]
+struct Unacked {
+    value: Value,
+    key: Option<WriteKey>,
+    reply: WriteReply,
+    tries: usize,
 }
 
 #[derive(Debug)]
 struct DvalInner {
     sub_id: SubId,
+    path: Path,
+    subscriber: SubscriberWeak,
     sub: DvState,
     streams: DvStreams,
+    give_up: GiveUp,
+    priority: Priority,
+    states: Vec<UnboundedSender<DvalState>>,
+    // the permissions reported by the last subscription, kept while
+    // dead so a UI doesn't flicker
+    permissions: Option<ValPermissions>,
+    permission_changes: Vec<UnboundedSender<Option<ValPermissions>>>,
+    write_retry: Option<WriteRetry>,
+    unacked: BTreeMap<u64, Unacked>,
+    write_seq: u64,
+}
+
+impl DvalInner {
+    fn state(&self) -> DvalState {
+        match &self.sub {
+            DvState::Subscribed(_) => DvalState::Subscribed,
+            DvState::Dead(d) if d.denied => DvalState::Denied,
+            DvState::Dead(_) => DvalState::Dead,
+            DvState::Failed => DvalState::Failed,
+        }
+    }
+
+    fn set_state(&mut self, sub: DvState) {
+        self.sub = sub;
+        if let DvState::Subscribed(val) = &self.sub {
+            let permissions = val.permissions();
+            if permissions != self.permissions {
+                self.permissions = permissions;
+                self.permission_changes
+                    .retain(|tx| tx.unbounded_send(permissions).is_ok());
+            }
+        }
+        if let DvState::Failed = self.sub {
+            for (_, u) in mem::take(&mut self.unacked) {
+                self.dead_letter(u)
+            }
+        }
+        self.notify_state()
+    }
+
+    fn dead_letter(&self, u: Unacked) {
+        let f = self.write_retry.as_ref().and_then(|r| r.dead_letter.as_ref());
+        if let Some(f) = f {
+            let Unacked { value, key, reply: _, tries } = u;
+            f(DeadLetter { path: self.path.clone(), value, key, tries })
+        }
+    }
+
+    // send the tracked writes that were in flight when the last
+    // subscription died again, or give up on them
+    fn resend_unacked(&mut self, me: &DvalWeak, sub: &Val) {
+        let max_tries = self.write_retry.as_ref().map(|r| r.max_tries).unwrap_or(0);
+        let mut dead = vec![];
+        for (seq, u) in self.unacked.iter_mut() {
+            // writes that were never sent are still in the write queue
+            if u.tries == 0 {
+                continue;
+            }
+            if u.tries >= max_tries {
+                dead.push(*seq);
+                continue;
+            }
+            u.tries += 1;
+            let reply = Some(WriteReply::Tracked(me.clone(), *seq));
+            let m = ToCon::Write(sub.0.id, u.value.clone(), u.key, reply);
+            sub.0.connection.send(m);
+        }
+        for seq in dead {
+            if let Some(u) = self.unacked.remove(&seq) {
+                self.dead_letter(u)
+            }
+        }
+    }
+
+    fn notify_state(&mut self) {
+        let state = self.state();
+        self.states.retain(|tx| tx.unbounded_send(state).is_ok());
+        if state == DvalState::Failed {
+            self.states.clear()
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -398,11 +1099,111 @@ impl Dval {
     /// if the subscription is currently dead.
     pub fn last(&self) -> Event {
         match &self.0.lock().sub {
-            DvState::Dead(_) => Event::Unsubscribed,
+            DvState::Dead(_) | DvState::Failed => {
+                Event::Unsubscribed(UnsubscribeReason::Unspecified)
+            }
             DvState::Subscribed(val) => val.last(),
         }
     }
 
+    /// Get the current state of the `Dval`
+    pub fn state(&self) -> DvalState {
+        self.0.lock().state()
+    }
+
+    /// Get the stats of the current subscription, see
+    /// `Val::stats`. `None` if the `Dval` is not subscribed. The
+    /// counts start over from zero when the `Dval` resubscribes.
+    pub fn stats(&self) -> Option<SubStats> {
+        match &self.0.lock().sub {
+            DvState::Dead(_) | DvState::Failed => None,
+            DvState::Subscribed(val) => val.stats(),
+        }
+    }
+
+    /// Return a stream of the state of the `Dval`, beginning with
+    /// the current state and then every state change. The stream
+    /// ends after `DvalState::Failed`, or if the `Dval` is dropped.
+    pub fn states(&self) -> impl Stream<Item = DvalState> + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::unbounded();
+        let mut t = self.0.lock();
+        let state = t.state();
+        let _ = tx.unbounded_send(state);
+        if state != DvalState::Failed {
+            t.states.push(tx);
+        }
+        rx
+    }
+
+    /// Get our permissions for the value, as reported by the most
+    /// recent subscription, see `Val::permissions`. Permissions can
+    /// change when the `Dval` resubscribes, e.g. to a different
+    /// publisher. The last known permissions are kept while the
+    /// `Dval` is dead.
+    pub fn permissions(&self) -> Option<ValPermissions> {
+        self.0.lock().permissions
+    }
+
+    /// Return a stream of our permissions for the value, beginning
+    /// with the current permissions and then every time they change
+    /// on resubscription. The stream ends if the `Dval` is dropped.
+    pub fn permission_changes(
+        &self,
+    ) -> impl Stream<Item = Option<ValPermissions>> + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::unbounded();
+        let mut t = self.0.lock();
+        let _ = tx.unbounded_send(t.permissions);
+        t.permission_changes.push(tx);
+        rx
+    }
+
+    /// Fetch the schema published for this `Dval`'s path, see
+    /// `Subscriber::schema`.
+    pub async fn schema(&self) -> Result<Option<Schema>> {
+        let (subscriber, path) = {
+            let t = self.0.lock();
+            (t.subscriber.upgrade(), t.path.clone())
+        };
+        match subscriber {
+            None => bail!("the subscriber is gone"),
+            Some(subscriber) => subscriber.schema(&path).await,
+        }
+    }
+
+    /// Set the policy for giving up on this `Dval`. The policy is
+    /// checked every time a subscription attempt fails, if it has
+    /// expired the `Dval` moves to `DvalState::Failed`, it is no
+    /// longer retried, and any queued writes are dropped. By default
+    /// a `Dval` never gives up, which is right for paths that are
+    /// expected to exist, speculative subscriptions should set a
+    /// policy so they don't load the resolver forever.
+    pub fn set_give_up(&self, give_up: GiveUp) {
+        self.0.lock().give_up = give_up;
+    }
+
+    /// Return the priority of this `Dval`
+    pub fn priority(&self) -> Priority {
+        self.0.lock().priority
+    }
+
+    /// Change the priority of this `Dval`, e.g. because it scrolled
+    /// into or out of view. This only matters while the `Dval` is
+    /// waiting to be (re)subscribed, see `Priority`.
+    pub fn set_priority(&self, priority: Priority) {
+        let subscriber = self.0.lock().subscriber.upgrade();
+        match subscriber {
+            None => self.0.lock().priority = priority,
+            Some(subscriber) => {
+                let mut t = subscriber.0.lock();
+                let mut dv = self.0.lock();
+                dv.priority = priority;
+                if priority > Priority::Background {
+                    t.unthrottle(&dv.path)
+                }
+            }
+        }
+    }
+
     /// Register `tx` to receive updates to this `Dval`.
     ///
     /// You may register multiple different channels to receive
@@ -412,14 +1213,67 @@ impl Dval {
         &self,
         flags: UpdatesFlags,
         tx: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+    ) {
+        self.add_stream(flags, None, tx)
+    }
+
+    /// Register `tx` to receive a sample of the updates to this
+    /// `Dval`. See `Val::updates_sampled`. The sampling mode is kept
+    /// across resubscriptions.
+    pub fn updates_sampled(
+        &self,
+        flags: UpdatesFlags,
+        sample: Sample,
+        tx: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+    ) {
+        self.add_stream(flags, Some(sample), tx)
+    }
+
+    /// Receive updates to this `Dval` deserialized as `T`, see
+    /// `Value::cast_to_serde`. Values that can't be deserialized as
+    /// `T` are returned as errors, unsubscribe events are skipped.
+    pub fn updates_as<T: DeserializeOwned + Send + 'static>(
+        &self,
+        flags: UpdatesFlags,
+    ) -> impl Stream<Item = Result<T>> + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        self.updates(flags, tx);
+        rx.flat_map(|mut batch| {
+            let values = batch.drain(..).filter_map(|(_, ev)| match ev {
+                Event::Unsubscribed(_) => None,
+                Event::Update(v) => Some(v.cast_to_serde::<T>()),
+            });
+            stream::iter(values.collect::<Vec<_>>())
+        })
+    }
+
+    /// Return a stream of the events from this `Dval`. This is the
+    /// same as registering a channel with `updates`, except that the
+    /// batches are flattened. The stream does not keep the `Dval`
+    /// alive, if it is dropped the stream will yield `Unsubscribed`
+    /// and then nothing more.
+    pub fn stream(
+        &self,
+        flags: UpdatesFlags,
+    ) -> impl Stream<Item = Event> + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        self.updates(flags, tx);
+        UpdatesStream::new(rx).map(|(_, ev)| ev)
+    }
+
+    fn add_stream(
+        &self,
+        flags: UpdatesFlags,
+        sample: Option<Sample>,
+        tx: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
     ) {
         let mut t = self.0.lock();
         let c = ChanWrap(tx.clone());
         if !t.streams.0.iter().any(|(_, s)| &c == s) {
-            t.streams = t.streams.add(flags, c);
+            t.streams = t.streams.add((flags, sample), c);
         }
         if let DvState::Subscribed(ref sub) = t.sub {
-            let m = ToCon::Stream { tx, sub_id: t.sub_id, id: sub.0.id, flags };
+            let m = ToCon::Stream { tx, sub_id: t.sub_id, id: sub.0.id, flags, sample };
             sub.0.connection.send(m);
         }
     }
@@ -433,29 +1287,38 @@ impl Dval {
     pub async fn wait_subscribed(&self) -> Result<()> {
         match &self.0.lock().sub {
             DvState::Subscribed(_) => return Ok(()),
+            DvState::Failed => bail!("subscription failed"),
             DvState::Dead(_) => (),
         }
+        let mut states = self.states();
         let (tx, mut rx) = mpsc::channel(2);
         self.updates(UpdatesFlags::BEGIN_WITH_LAST, tx);
         loop {
-            match rx.next().await {
-                None => bail!("unexpected resub error"),
-                Some(mut batch) => {
-                    let mut subed = false;
-                    for (_, ev) in batch.drain(..) {
-                        match ev {
-                            Event::Unsubscribed => {
-                                subed = false;
-                            }
-                            Event::Update(_) => {
-                                subed = true;
+            select_biased! {
+                s = states.next() => match s {
+                    Some(DvalState::Failed) => bail!("subscription failed"),
+                    Some(DvalState::Subscribed | DvalState::Dead | DvalState::Denied)
+                        | None => (),
+                },
+                b = rx.next() => match b {
+                    None => bail!("unexpected resub error"),
+                    Some(mut batch) => {
+                        let mut subed = false;
+                        for (_, ev) in batch.drain(..) {
+                            match ev {
+                                Event::Unsubscribed(_) => {
+                                    subed = false;
+                                }
+                                Event::Update(_) => {
+                                    subed = true;
+                                }
                             }
                         }
+                        if subed {
+                            break Ok(());
+                        }
                     }
-                    if subed {
-                        break Ok(());
-                    }
-                }
+                },
             }
         }
     }
@@ -474,9 +1337,10 @@ impl Dval {
                 true
             }
             DvState::Dead(dead) => {
-                dead.queued_writes.push((v, None));
+                dead.queued_writes.push((v, None, None));
                 false
             }
+            DvState::Failed => false,
         }
     }
 
@@ -491,33 +1355,128 @@ impl Dval {
     ///
     /// If we are not currently subscribed then the write will be
     /// queued until we are. It is still possible that a write will be
-    /// dropped e.g. if the connection dies while we are writing it.
+    /// dropped e.g. if the connection dies while we are writing it,
+    /// unless a `WriteRetry` policy is set.
     pub fn write_with_recipt(&self, v: Value) -> oneshot::Receiver<Value> {
         let (tx, rx) = oneshot::channel();
+        self.queue_write(v, None, WriteReply::Value(tx));
+        rx
+    }
+
+    /// Write with an idempotency key, for commands that must be
+    /// executed at most once but may need to be sent more than once.
+    /// If the returned channel is canceled, e.g. because the
+    /// connection died before the publisher replied, the write can
+    /// be sent again with the same key. If the publisher received
+    /// the first attempt it will not execute the write again, and
+    /// the receipt says it was a duplicate. Use
+    /// `Subscriber::write_key` to make keys.
+    ///
+    /// Like `write_with_recipt` the write is queued if we are not
+    /// currently subscribed. Publishers remember keys for a limited
+    /// time (see `PublisherBuilder::dedup_window`), and publishers
+    /// that are older than keys ignore them.
+    pub fn write_keyed(
+        &self,
+        v: Value,
+        key: WriteKey,
+    ) -> oneshot::Receiver<WriteReceipt> {
+        let (tx, rx) = oneshot::channel();
+        self.queue_write(v, Some(key), WriteReply::Receipt(tx));
+        rx
+    }
+
+    fn queue_write(&self, v: Value, key: Option<WriteKey>, reply: WriteReply) {
+        if let WriteAction::Send(_, con, m) = self.prepare_write(v, key, Some(reply)) {
+            con.send(m);
+        }
+    }
+
+    // track the write if there is a retry policy and a reply, and
+    // then queue it if we aren't subscribed, or return the message
+    // and the connection to send it on if we are
+    fn prepare_write(
+        &self,
+        v: Value,
+        key: Option<WriteKey>,
+        reply: Option<WriteReply>,
+    ) -> WriteAction {
         let mut t = self.0.lock();
-        match &mut t.sub {
-            DvState::Subscribed(ref sub) => {
-                sub.0.connection.send(ToCon::Write(sub.0.id, v, Some(tx)));
+        let t = &mut *t;
+        if let DvState::Failed = t.sub {
+            return WriteAction::Dropped;
+        }
+        let reply = match (reply, &t.write_retry) {
+            (None, _) => None,
+            (Some(reply), None) => Some(reply),
+            (Some(reply), Some(_)) => {
+                t.write_seq += 1;
+                let seq = t.write_seq;
+                let tries = match t.sub {
+                    DvState::Subscribed(_) => 1,
+                    DvState::Dead(_) | DvState::Failed => 0,
+                };
+                t.unacked.insert(seq, Unacked { value: v.clone(), key, reply, tries });
+                Some(WriteReply::Tracked(self.downgrade(), seq))
             }
+        };
+        match &mut t.sub {
+            DvState::Subscribed(ref sub) => WriteAction::Send(
+                sub.0.conid,
+                sub.0.connection.clone(),
+                ToCon::Write(sub.0.id, v, key, reply),
+            ),
             DvState::Dead(dead) => {
-                dead.queued_writes.push((v, Some(tx)));
+                dead.queued_writes.push((v, key, reply));
+                WriteAction::Queued
+            }
+            DvState::Failed => WriteAction::Dropped,
+        }
+    }
+
+    // the publisher replied to a tracked write
+    fn ack(&self, seq: u64, result: Value, duplicate: bool) {
+        let u = self.0.lock().unacked.remove(&seq);
+        match u.map(|u| u.reply) {
+            None | Some(WriteReply::Tracked(_, _)) => (),
+            Some(WriteReply::Value(tx)) => {
+                let _ = tx.send(result);
+            }
+            Some(WriteReply::Receipt(tx)) => {
+                let _ = tx.send(WriteReceipt { result, duplicate });
             }
         }
-        rx
+    }
+
+    /// Set the write retry policy of this `Dval`, see `WriteRetry`.
+    /// `None`, the default, means writes that are in flight when the
+    /// connection dies are lost. Only writes made after the policy
+    /// is set are tracked.
+    pub fn set_write_retry(&self, retry: Option<WriteRetry>) {
+        self.0.lock().write_retry = retry;
+    }
+
+    /// Return the number of tracked writes the publisher has not yet
+    /// replied to, including queued writes, see `WriteRetry`.
+    pub fn unacked_writes(&self) -> usize {
+        self.0.lock().unacked.len()
     }
 
     /// Clear the write queue
     pub fn clear_queued_writes(&self) {
         let mut t = self.0.lock();
+        let t = &mut *t;
         if let DvState::Dead(dead) = &mut t.sub {
             dead.queued_writes.clear();
+            // tracked writes that were never sent are in the queue
+            t.unacked.retain(|_, u| u.tries > 0);
         }
     }
 
     /// Return the number of queued writes
     pub fn queued_writes(&self) -> usize {
         match &mut self.0.lock().sub {
-            DvState::Subscribed(_) => 0,
+            DvState::Subscribed(_) | DvState::Failed => 0,
             DvState::Dead(dead) => dead.queued_writes.len(),
         }
     }
@@ -528,13 +1487,73 @@ impl Dval {
     }
 }
 
+#[derive(Debug)]
+struct PendingSub {
+    id: u64,
+    waiters: Vec<oneshot::Sender<Result<Val>>>,
+    abort: oneshot::Sender<()>,
+}
+
+impl PendingSub {
+    fn complete(&mut self, res: &Result<Val>) {
+        for w in self.waiters.drain(..) {
+            let _ = w.send(match res {
+                Ok(v) => Ok(v.clone()),
+                Err(e) if e.is::<Canceled>() => Err(Error::from(Canceled)),
+                Err(e) if e.is::<PermissionDenied>() => {
+                    Err(Error::from(PermissionDenied))
+                }
+                Err(e) => Err(anyhow!("{}", e)),
+            });
+        }
+    }
+}
+
 #[derive(Debug)]
 enum SubStatus {
     Subscribed(ValWeak),
-    Pending(Box<Vec<oneshot::Sender<Result<Val>>>>), // the box ensures SubStatus is tag + 1 word
+    Pending(Box<PendingSub>), // the box ensures SubStatus is tag + 1 word
+}
+
+// Owns a pending subscription attempt. If the future making the
+// attempt is dropped before it finishes then the attempt is removed,
+// and anyone waiting on it is told it was canceled.
+struct PendingGuard {
+    subscriber: SubscriberWeak,
+    path: Path,
+    id: u64,
+    aborted: oneshot::Receiver<()>,
+}
+
+impl Drop for PendingGuard {
+    fn drop(&mut self) {
+        if let Some(subscriber) = self.subscriber.upgrade() {
+            let p = subscriber.0.lock().take_pending(&self.path, Some(self.id));
+            if let Some(mut p) = p {
+                p.complete(&Err(Error::from(Canceled)))
+            }
+        }
+    }
 }
 
 const REMEBER_FAILED: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_RESUB_BATCH: usize = 100_000;
+const DEFAULT_MAX_BACKGROUND: usize = 1_000;
+
+/// How long durable subscriptions wait before retrying after they
+/// are denied permission, unless `SubscriberBuilder::on_denied` says
+/// otherwise.
+pub const DEFAULT_DENIED_RETRY: Duration = Duration::from_secs(300);
+
+/// How long each attempt to connect to one of a publisher's
+/// addresses may take, unless `SubscriberBuilder::connect_timeout`
+/// says otherwise.
+pub const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// How long a connection may leave subscription requests unanswered
+/// before new subscriptions stop using it, unless
+/// `SubscriberBuilder::stalled_after` says otherwise.
+pub const DEFAULT_STALLED_AFTER: Duration = Duration::from_secs(10);
 
 fn pick(n: usize) -> usize {
     let mut rng = rand::thread_rng();
@@ -543,20 +1562,46 @@ fn pick(n: usize) -> usize {
 
 #[derive(Debug)]
 struct Connection {
-    primary: Option<(ConId, BatchSender<ToCon>)>,
+    primary: Option<(ConId, BatchSender<ToCon>, Arc<Health>)>,
     isolated: FxHashMap<ConId, BatchSender<ToCon>>,
 }
 
 impl Connection {
     fn iter<'a>(&'a self) -> Box<dyn Iterator<Item = &'a BatchSender<ToCon>> + 'a> {
         match &self.primary {
-            Some((_, c)) => Box::new(iter::once(c).chain(self.isolated.values())),
+            Some((_, c, _)) => Box::new(iter::once(c).chain(self.isolated.values())),
             None => Box::new(self.isolated.values()),
         }
     }
 
+    fn iter_ids<'a>(
+        &'a self,
+    ) -> Box<dyn Iterator<Item = (ConId, &'a BatchSender<ToCon>)> + 'a> {
+        let isolated = self.isolated.iter().map(|(id, c)| (*id, c));
+        match &self.primary {
+            Some((id, c, _)) => Box::new(iter::once((*id, c)).chain(isolated)),
+            None => Box::new(isolated),
+        }
+    }
+
+    // true if there is a primary connection and it is answering
+    fn healthy(&self, stalled_after: Duration) -> bool {
+        match &self.primary {
+            Some((_, _, health)) => health.healthy(stalled_after),
+            None => false,
+        }
+    }
+
+    // stop putting new subscriptions on an unhealthy primary
+    // connection, it lives on as long as it's subscriptions do
+    fn retire_primary(&mut self) {
+        if let Some((id, c, _)) = self.primary.take() {
+            self.isolated.insert(id, c);
+        }
+    }
+
     fn remove(&mut self, id: ConId) {
-        if let Some((other, _)) = &self.primary {
+        if let Some((other, _, _)) = &self.primary {
             if id == *other {
                 self.primary = None;
             }
@@ -569,27 +1614,142 @@ impl Connection {
     }
 }
 
+/// What happened to each connection during `Subscriber::flush`
+#[derive(Debug, Clone, Default)]
+pub struct FlushReport {
+    /// Connections that sent everything that was queued before the
+    /// flush
+    pub flushed: Vec<(SocketAddr, ConId)>,
+    /// Connections that didn't flush before the deadline
+    pub timed_out: Vec<(SocketAddr, ConId)>,
+    /// Connections that closed before they flushed
+    pub closed: Vec<(SocketAddr, ConId)>,
+}
+
+impl FlushReport {
+    /// true if every connection flushed
+    pub fn complete(&self) -> bool {
+        self.timed_out.is_empty() && self.closed.is_empty()
+    }
+}
+
+// flush the connections concurrently, giving each one `timeout`
+async fn flush_connections(
+    cons: Vec<(SocketAddr, ConId, BatchSender<ToCon>)>,
+    timeout: Option<Duration>,
+) -> FlushReport {
+    let mut report = FlushReport::default();
+    let mut pending = FuturesUnordered::new();
+    for (addr, id, con) in cons {
+        let (tx, rx) = oneshot::channel();
+        if !con.send(ToCon::Flush(tx)) {
+            report.closed.push((addr, id));
+            continue;
+        }
+        pending.push(async move {
+            let res = match timeout {
+                None => Ok(rx.await),
+                Some(timeout) => time::timeout(timeout, rx).await,
+            };
+            (addr, id, res)
+        });
+    }
+    while let Some((addr, id, res)) = pending.next().await {
+        match res {
+            Ok(Ok(())) => report.flushed.push((addr, id)),
+            Ok(Err(_)) => report.closed.push((addr, id)),
+            Err(_) => report.timed_out.push((addr, id)),
+        }
+    }
+    report
+}
+
+// A publisher record that sent us to an endpoint that accepted the
+// connection but didn't speak the protocol, or failed authentication.
+// Usually the publisher moved and something else has its old
+// address, while the resolver still has the old record.
+#[derive(Debug, Clone, Copy)]
+struct Stale {
+    id: PublisherId,
+    // the resolver's timestamp on the resolution we used
+    timestamp: u64,
+    failed: Instant,
+}
+
+impl Stale {
+    // true if `pb`, resolved at `timestamp`, is the record that
+    // failed, or the resolution is no newer than the one that failed
+    fn matches(&self, pb: &Publisher, timestamp: u64) -> bool {
+        pb.id == self.id || timestamp <= self.timestamp
+    }
+}
+
 struct Chosen {
+    id: PublisherId,
+    timestamp: u64,
     addr: SocketAddr,
+    alt_addr: Option<SocketAddr>,
     target_auth: TargetAuth,
     token: Bytes,
+    subtree_token: Bytes,
     uifo: Option<UserInfo>,
     flags: PublishFlags,
 }
 
+#[derive(Clone)]
+struct OnConnect(Arc<dyn Fn(&Publisher, Option<&UserInfo>) -> bool + Send + Sync>);
+
+impl fmt::Debug for OnConnect {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "OnConnect")
+    }
+}
+
 #[derive(Debug)]
 struct SubscriberInner {
     id: SubscriberId,
     resolver: ResolverRead,
     connections: FxHashMap<SocketAddr, Connection>,
     recently_failed: FxHashMap<SocketAddr, Instant>,
+    stale: FxHashMap<SocketAddr, Stale>,
     subscribed: HashMap<Path, SubStatus>,
     durable_dead: HashMap<Path, DvalWeak>,
     durable_pending: HashMap<Path, DvalWeak>,
     durable_alive: HashMap<Path, DvalWeak>,
+    // dead durable subscriptions ordered by when they are due to be
+    // retried, and then by the order they died in. An entry is
+    // stale if the path is no longer dead, or it's next_try changed.
+    resub_queue: BTreeMap<(Instant, u64), Path>,
+    resub_seq: u64,
+    pending_seq: u64,
+    max_resub_batch: usize,
+    // background subscriptions currently being attempted, and those
+    // that are due but held back because there are too many
+    max_background: usize,
+    background_in_flight: HashSet<Path>,
+    throttled: Vec<((Instant, u64), Path)>,
     trigger_resub: UnboundedSender<()>,
     desired_auth: DesiredAuth,
     tls_ctx: Option<tls::CachedConnector>,
+    on_connect: Option<OnConnect>,
+    on_denied: OnDenied,
+    limiter: Option<Arc<RateLimiter>>,
+    shm_ring: Option<usize>,
+    decode_limits: DecodeLimits,
+    audit: Audit,
+    patches: bool,
+    deltas: bool,
+    stats: bool,
+    prefer: PreferFamily,
+    connect_timeout: Duration,
+    stalled_after: Duration,
+    writer_id: u64,
+    write_seq: u64,
+    rt: Handle,
+    shutdown: bool,
+    conn_events: Vec<UnboundedSender<ConnEvent>>,
+    #[cfg(feature = "fault_injection")]
+    faults: Option<crate::fault::FaultInjector>,
 }
 
 impl SubscriberInner {
@@ -602,109 +1762,522 @@ impl SubscriberInner {
             .map(|d| d.id())
     }
 
+    fn acceptable(&self, pb: &Publisher) -> bool {
+        match &self.on_connect {
+            None => true,
+            Some(OnConnect(f)) => f(pb, pb.user_info.as_ref()),
+        }
+    }
+
     fn choose_addr(
         &mut self,
         publishers: &Pooled<FxHashMap<PublisherId, Publisher>>,
         resolved: &Resolved,
-    ) -> Option<Chosen> {
+    ) -> Result<Chosen> {
         use rand::seq::IteratorRandom;
-        let mut flags = PublishFlags::from_bits(resolved.flags)?;
+        let mut flags = PublishFlags::from_bits(resolved.flags)
+            .ok_or_else(|| anyhow!("invalid publish flags"))?;
+        let candidates = resolved
+            .publishers
+            .iter()
+            .filter_map(|pref| publishers.get(&pref.id).map(|pb| (pref, pb)))
+            .collect::<Vec<_>>();
+        if candidates.is_empty() {
+            bail!("missing publisher record")
+        }
+        let candidates = candidates
+            .into_iter()
+            .filter(|(_, pb)| self.acceptable(pb))
+            .collect::<Vec<_>>();
+        let chosen = |(pref, pb): &(&PublisherRef, &Publisher), flags| Chosen {
+            id: pb.id,
+            timestamp: resolved.timestamp,
+            addr: pb.addr,
+            alt_addr: pb.alt_addr,
+            target_auth: pb.target_auth.clone(),
+            token: pref.token.clone(),
+            subtree_token: pref.subtree_token.clone(),
+            uifo: pb.user_info.clone(),
+            flags,
+        };
         if flags.contains(PublishFlags::USE_EXISTING) {
             flags = flags & !PublishFlags::ISOLATED;
-            for pref in &*resolved.publishers {
-                if let Some(pb) = publishers.get(&pref.id) {
-                    if self.connections.contains_key(&pb.addr) {
-                        return Some(Chosen {
-                            addr: pb.addr,
-                            target_auth: pb.target_auth.clone(),
-                            token: pref.token.clone(),
-                            uifo: pb.user_info.clone(),
-                            flags,
-                        });
+            for c in &candidates {
+                if let Some(con) = self.connections.get(&c.1.addr) {
+                    if con.healthy(self.stalled_after) {
+                        return Ok(chosen(c, flags));
                     }
                 }
             }
         }
-        let res = resolved
-            .publishers
+        // prefer publishers that haven't failed recently, and stale
+        // records least of all, but fall back to any of them
+        let rank = |pb: &Publisher| {
+            (
+                self.is_stale(pb, resolved.timestamp),
+                self.recently_failed.contains_key(&pb.addr),
+            )
+        };
+        let best = candidates.iter().map(|(_, pb)| rank(pb)).min();
+        let res = candidates
             .iter()
-            .filter_map(|pref| {
-                publishers
-                    .get(&pref.id)
-                    .filter(|pb| !self.recently_failed.contains_key(&pb.addr))
-                    .map(|pb| (pref, pb))
-            })
-            .choose(&mut rand::thread_rng())
-            .map(|(pref, pb)| Chosen {
-                addr: pb.addr,
-                target_auth: pb.target_auth.clone(),
-                token: pref.token.clone(),
-                uifo: pb.user_info.clone(),
-                flags,
+            .filter(|(_, pb)| Some(rank(pb)) == best)
+            .choose(&mut rand::thread_rng());
+        match res {
+            Some(c) => Ok(chosen(c, flags)),
+            None => bail!("no acceptable publisher"),
+        }
+    }
+
+    fn add_durable_dead(&mut self, path: Path, w: DvalWeak, next_try: Instant) {
+        self.resub_seq += 1;
+        self.resub_queue.insert((next_try, self.resub_seq), path.clone());
+        self.durable_dead.insert(path, w);
+    }
+
+    // put path back in line if it is being held back by the
+    // background budget
+    fn unthrottle(&mut self, path: &Path) {
+        if let Some(i) = self.throttled.iter().position(|(_, p)| p == path) {
+            let (k, p) = self.throttled.swap_remove(i);
+            self.resub_queue.insert(k, p);
+            let _ = self.trigger_resub.unbounded_send(());
+        }
+    }
+
+    fn conn_event(&mut self, ev: ConnEvent) {
+        self.conn_events.retain(|tx| tx.unbounded_send(ev.clone()).is_ok())
+    }
+
+    fn gc_recently_failed(&mut self) {
+        let now = Instant::now();
+        self.recently_failed.retain(|_, v| (now - *v) < REMEBER_FAILED);
+        self.stale.retain(|_, v| (now - v.failed) < REMEBER_FAILED)
+    }
+
+    fn is_stale(&self, pb: &Publisher, timestamp: u64) -> bool {
+        match self.stale.get(&pb.addr) {
+            Some(st) => st.matches(pb, timestamp),
+            None => false,
+        }
+    }
+
+    // start a new subscription attempt to path, the caller must
+    // already have checked that path isn't subscribed or pending.
+    fn add_pending(&mut self, subscriber: &Subscriber, path: Path) -> PendingGuard {
+        self.pending_seq += 1;
+        let id = self.pending_seq;
+        let (abort, aborted) = oneshot::channel();
+        let p = PendingSub { id, waiters: vec![], abort };
+        self.subscribed.insert(path.clone(), SubStatus::Pending(Box::new(p)));
+        PendingGuard { subscriber: subscriber.downgrade(), path, id, aborted }
+    }
+
+    // remove the pending subscription attempt to path, if id is
+    // specified only if it is that attempt.
+    fn take_pending(&mut self, path: &Path, id: Option<u64>) -> Option<PendingSub> {
+        match self.subscribed.get(path) {
+            Some(SubStatus::Pending(p)) if id.map(|id| p.id == id).unwrap_or(true) => {
+                match self.subscribed.remove(path) {
+                    Some(SubStatus::Pending(p)) => Some(*p),
+                    Some(SubStatus::Subscribed(_)) | None => unreachable!(),
+                }
+            }
+            Some(SubStatus::Pending(_)) | Some(SubStatus::Subscribed(_)) | None => None,
+        }
+    }
+
+    fn all_connections(&self) -> Vec<(SocketAddr, ConId, BatchSender<ToCon>)> {
+        self.connections
+            .iter()
+            .flat_map(|(addr, c)| c.iter_ids().map(|(id, c)| (*addr, id, c.clone())))
+            .collect()
+    }
+
+    fn unsubscribe_matching(&mut self, f: impl Fn(&Path) -> bool) -> usize {
+        let mut paths = HashSet::new();
+        for durable in
+            [&mut self.durable_dead, &mut self.durable_pending, &mut self.durable_alive]
+        {
+            // removing the path from the durable maps is enough to
+            // stop resubscription, stale resub_queue entries are
+            // skipped.
+            durable.retain(|p, w| {
+                if !f(p) {
+                    return true;
+                }
+                if let Some(dv) = w.upgrade() {
+                    dv.0.lock().set_state(DvState::Failed);
+                    paths.insert(p.clone());
+                }
+                false
             });
-        if let Some(chosen) = res {
-            Some(chosen)
-        } else {
-            resolved
-                .publishers
-                .iter()
-                .filter_map(|pref| publishers.get(&pref.id).map(|pb| (pref, pb)))
-                .choose(&mut rand::thread_rng())
-                .map(|(pref, pb)| Chosen {
-                    addr: pb.addr,
-                    target_auth: pb.target_auth.clone(),
-                    token: pref.token.clone(),
-                    uifo: pb.user_info.clone(),
-                    flags,
-                })
         }
+        let matched =
+            self.subscribed.keys().filter(|p| f(p)).cloned().collect::<Vec<_>>();
+        for p in matched {
+            match self.subscribed.get(&p) {
+                None => (),
+                Some(SubStatus::Subscribed(w)) => {
+                    if let Some(v) = w.upgrade() {
+                        v.0.connection.send(ToCon::Unsubscribe(v.0.id));
+                        paths.insert(p);
+                    }
+                }
+                Some(SubStatus::Pending(_)) => {
+                    if let Some(mut pending) = self.take_pending(&p, None) {
+                        pending.complete(&Err(Error::from(Canceled)));
+                        let _ = pending.abort.send(());
+                        paths.insert(p);
+                    }
+                }
+            }
+        }
+        paths.len()
+    }
+}
+
+#[derive(Debug, Clone)]
+struct SubscriberWeak(Weak<Mutex<SubscriberInner>>);
+
+impl SubscriberWeak {
+    fn upgrade(&self) -> Option<Subscriber> {
+        Weak::upgrade(&self.0).map(|s| Subscriber(s))
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct DurableStats {
+    pub alive: usize,
+    pub pending: usize,
+    pub dead: usize,
+}
+
+pub struct SubscriberBuilder {
+    cfg: Option<Config>,
+    desired_auth: Option<DesiredAuth>,
+    max_resub_batch: usize,
+    max_background: usize,
+    on_connect: Option<OnConnect>,
+    on_denied: OnDenied,
+    rate_limit: Option<(u32, u32)>,
+    shm_ring: Option<usize>,
+    decode_limits: DecodeLimits,
+    audit: Audit,
+    patches: bool,
+    deltas: bool,
+    stats: bool,
+    prefer: PreferFamily,
+    connect_timeout: Duration,
+    stalled_after: Duration,
+    writer_id: Option<u64>,
+    pools: Option<PoolConfig>,
+    runtime: Option<Handle>,
+    #[cfg(feature = "fault_injection")]
+    faults: Option<crate::fault::FaultInjector>,
+}
+
+impl SubscriberBuilder {
+    pub fn new() -> Self {
+        Self {
+            cfg: None,
+            desired_auth: None,
+            max_resub_batch: DEFAULT_MAX_RESUB_BATCH,
+            max_background: DEFAULT_MAX_BACKGROUND,
+            on_connect: None,
+            on_denied: OnDenied::default(),
+            rate_limit: None,
+            shm_ring: None,
+            decode_limits: DecodeLimits::default(),
+            audit: Audit::Off,
+            patches: false,
+            deltas: false,
+            stats: false,
+            prefer: PreferFamily::default(),
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            stalled_after: DEFAULT_STALLED_AFTER,
+            writer_id: None,
+            pools: None,
+            runtime: None,
+            #[cfg(feature = "fault_injection")]
+            faults: None,
+        }
+    }
+
+    pub fn build(&mut self) -> Result<Subscriber> {
+        let cfg = self.cfg.take().ok_or_else(|| anyhow!("config is required"))?;
+        let rt = match self.runtime.take() {
+            Some(rt) => rt,
+            None => Handle::try_current().map_err(|_| {
+                anyhow!("no tokio runtime, build inside one or pass a handle to runtime")
+            })?,
+        };
+        if let Some(pools) = self.pools {
+            pool::configure("subscriber::", pools)
+        }
+        let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
+        let (tx, rx) = mpsc::unbounded();
+        let tls_ctx = cfg.tls.clone().map(tls::CachedConnector::new);
+        let resolver = ResolverRead::new(cfg, desired_auth.clone());
+        resolver.set_runtime(rt.clone());
+        let t = Subscriber(Arc::new(Mutex::new(SubscriberInner {
+            id: SubscriberId::new(),
+            resolver,
+            desired_auth,
+            connections: HashMap::default(),
+            recently_failed: HashMap::default(),
+            stale: HashMap::default(),
+            subscribed: HashMap::default(),
+            durable_dead: HashMap::default(),
+            durable_pending: HashMap::default(),
+            durable_alive: HashMap::default(),
+            resub_queue: BTreeMap::new(),
+            resub_seq: 0,
+            pending_seq: 0,
+            max_resub_batch: self.max_resub_batch,
+            max_background: self.max_background,
+            background_in_flight: HashSet::new(),
+            throttled: Vec::new(),
+            trigger_resub: tx,
+            tls_ctx,
+            on_connect: self.on_connect.clone(),
+            on_denied: self.on_denied.clone(),
+            limiter: self
+                .rate_limit
+                .map(|(rate, burst)| Arc::new(RateLimiter::new(rate, burst))),
+            shm_ring: self.shm_ring,
+            decode_limits: self.decode_limits,
+            audit: self.audit,
+            patches: self.patches,
+            deltas: self.deltas,
+            stats: self.stats,
+            prefer: self.prefer,
+            connect_timeout: self.connect_timeout,
+            stalled_after: self.stalled_after,
+            writer_id: self.writer_id.unwrap_or_else(|| rand::thread_rng().gen()),
+            write_seq: 0,
+            rt,
+            shutdown: false,
+            conn_events: Vec::new(),
+            #[cfg(feature = "fault_injection")]
+            faults: self.faults.take(),
+        })));
+        t.start_resub_task(rx);
+        Ok(t)
+    }
+
+    pub fn config(&mut self, cfg: Config) -> &mut Self {
+        self.cfg = Some(cfg);
+        self
+    }
+
+    pub fn desired_auth(&mut self, auth: DesiredAuth) -> &mut Self {
+        self.desired_auth = Some(auth);
+        self
+    }
+
+    /// The maximum number of dead durable subscriptions that will be
+    /// retried in one resubscription pass (default 100,000). Dead
+    /// subscriptions are retried in order of `Priority`, and then in
+    /// the order they became due, so when there are more due than
+    /// this, the remainder will be first in line in the next pass. A
+    /// value of 0 is treated as 1.
+    pub fn max_resub_batch(&mut self, max: usize) -> &mut Self {
+        self.max_resub_batch = max;
+        self
+    }
+
+    /// The maximum number of `Priority::Background` durable
+    /// subscriptions that may be attempted at once (default
+    /// 1,000). A value of 0 is treated as 1.
+    pub fn max_background(&mut self, max: usize) -> &mut Self {
+        self.max_background = max;
+        self
+    }
+
+    /// Register a hook that is consulted for every publisher the
+    /// resolver returns before the subscriber will use it. `f` is
+    /// passed the publisher record, including the identity the
+    /// publisher authenticated to the resolver with
+    /// (`Publisher::target_auth`), and the user info the resolver
+    /// attested to that publisher on our behalf (`None` if
+    /// anonymous). If `f` returns false the publisher is vetoed and
+    /// will not be connected to. If every publisher of a path is
+    /// vetoed the subscription fails with an error.
+    ///
+    /// `f` is called with the subscriber lock held, it must not call
+    /// back into the subscriber, and it should be fast.
+    pub fn on_connect<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Publisher, Option<&UserInfo>) -> bool + Send + Sync + 'static,
+    {
+        self.on_connect = Some(OnConnect(Arc::new(f)));
+        self
+    }
+
+    /// Set what durable subscriptions do when a publisher denies
+    /// them permission, see `OnDenied`. By default they retry after
+    /// `DEFAULT_DENIED_RETRY`, rather than with the usual linear
+    /// backoff, so that a subscriber without permission doesn't load
+    /// the resolver and the publisher's audit log.
+    pub fn on_denied(&mut self, policy: OnDenied) -> &mut Self {
+        self.on_denied = policy;
+        self
+    }
+
+    /// Limit the rate at which the subscriber queries the resolver
+    /// and opens new connections to publishers to `rate` per second,
+    /// with bursts of up to `burst`. By default there is no limit.
+    ///
+    /// When a big publisher dies every durable subscription to it
+    /// will try to resubscribe at about the same time. This keeps the
+    /// resulting storm from overwhelming the resolver and the
+    /// publisher when it comes back. Attempts beyond the limit are
+    /// queued, and released with some random jitter. Time spent
+    /// waiting for the limiter doesn't count against subscription
+    /// timeouts, and the linear backoff of durable subscriptions
+    /// still applies on top of it. Values of 0 are treated as 1.
+    pub fn rate_limit(&mut self, rate: u32, burst: u32) -> &mut Self {
+        self.rate_limit = Some((rate, burst));
+        self
     }
 
-    fn gc_recently_failed(&mut self) {
-        let now = Instant::now();
-        self.recently_failed.retain(|_, v| (now - *v) < REMEBER_FAILED)
+    /// Offer to move connections to publishers on the same machine
+    /// onto a pair of shared memory rings of `ring_size` bytes each
+    /// (rounded up to a power of two, at least 64 KiB and at most 1
+    /// GiB). The offer is only made when the publisher's address is
+    /// the same as ours, and only for anonymous and local auth. If
+    /// the publisher doesn't support shared memory, or can't map the
+    /// segment, the connection silently stays on TCP. The TCP
+    /// connection is kept open either way, it is used to wake up the
+    /// other side and to notice when it dies. By default shared
+    /// memory is not used.
+    pub fn shm(&mut self, ring_size: usize) -> &mut Self {
+        self.shm_ring = Some(ring_size);
+        self
     }
-}
 
-#[derive(Debug, Clone)]
-struct SubscriberWeak(Weak<Mutex<SubscriberInner>>);
+    /// Limits enforced when decoding messages from publishers, see
+    /// `DecodeLimits`. A publisher that sends a message exceeding
+    /// any limit is disconnected, and subscriptions to it fail or
+    /// are retried as usual. The default only limits nesting depth.
+    pub fn decode_limits(&mut self, limits: DecodeLimits) -> &mut Self {
+        self.decode_limits = limits;
+        self
+    }
 
-impl SubscriberWeak {
-    fn upgrade(&self) -> Option<Subscriber> {
-        Weak::upgrade(&self.0).map(|s| Subscriber(s))
+    /// Audit the messages received from publishers, to help diagnose
+    /// suspected message loss. Every connection numbers the batches
+    /// it decodes and checks that they are processed in order and
+    /// without gaps, and checks that every update is for a value it
+    /// is subscribed to, and that every write result answers a
+    /// write it sent. Violations are handled as `mode` specifies.
+    /// The publisher protocol doesn't carry sequence numbers, so
+    /// this can't see loss on the wire, only its consequences. The
+    /// default is `Audit::Off`, which costs nothing.
+    pub fn audit(&mut self, mode: Audit) -> &mut Self {
+        self.audit = mode;
+        self
     }
-}
 
-#[derive(Debug, Clone, Copy)]
-pub struct DurableStats {
-    pub alive: usize,
-    pub pending: usize,
-    pub dead: usize,
-}
+    /// Ask publishers to send changes to large array values as
+    /// patches (see `protocol::patch`) instead of the whole value.
+    /// Patches are applied to the last value received, so
+    /// subscriptions still see whole values. Publishers that don't
+    /// support patches ignore the request. Keeping the last value
+    /// costs a clone of each update, the default is false.
+    pub fn patches(&mut self, enabled: bool) -> &mut Self {
+        self.patches = enabled;
+        self
+    }
 
-pub struct SubscriberBuilder {
-    cfg: Option<Config>,
-    desired_auth: Option<DesiredAuth>,
-}
+    /// Ask publishers to send changes to string values as deltas
+    /// (see `protocol::delta`) instead of the whole string, when only
+    /// part of the string changed. This suits e.g. market data feeds
+    /// where consecutive values differ in a few characters. As with
+    /// patches subscriptions still see whole values, and publishers
+    /// send the whole value when they can't make a smaller delta, or
+    /// don't support deltas. The default is false.
+    pub fn deltas(&mut self, enabled: bool) -> &mut Self {
+        self.deltas = enabled;
+        self
+    }
 
-impl SubscriberBuilder {
-    pub fn new() -> Self {
-        Self { cfg: None, desired_auth: None }
+    /// Count the updates, and bytes, received by each subscription,
+    /// see `Val::stats`. Counting bytes means computing the encoded
+    /// size of every update, so it is off by default.
+    pub fn stats(&mut self, enabled: bool) -> &mut Self {
+        self.stats = enabled;
+        self
     }
 
-    pub fn build(&mut self) -> Result<Subscriber> {
-        let cfg = self.cfg.take().ok_or_else(|| anyhow!("config is required"))?;
-        let desired_auth = self.desired_auth.take().unwrap_or_else(|| cfg.default_auth());
-        Subscriber::new(cfg, desired_auth)
+    /// The writer id used to make write keys, see
+    /// `Subscriber::write_key`. By default it is random. Keys only
+    /// deduplicate writes with the same writer id, so to deduplicate
+    /// writes re-sent by a restarted process set this to something
+    /// stable, and make keys with sequence numbers that survive the
+    /// restart instead of using `write_key`.
+    pub fn writer_id(&mut self, id: u64) -> &mut Self {
+        self.writer_id = Some(id);
+        self
     }
 
-    pub fn config(&mut self, cfg: Config) -> &mut Self {
-        self.cfg = Some(cfg);
+    /// Which address family to try first when connecting to a
+    /// publisher that listens on both ipv4 and ipv6 (see
+    /// `PublisherBuilder::alt_bind_cfg`). Whichever is tried first,
+    /// if it can't be reached the other is tried before the
+    /// connection fails. The default is `PreferFamily::Primary`.
+    pub fn prefer(&mut self, prefer: PreferFamily) -> &mut Self {
+        self.prefer = prefer;
         self
     }
 
-    pub fn desired_auth(&mut self, auth: DesiredAuth) -> &mut Self {
-        self.desired_auth = Some(auth);
+    /// How long each attempt to connect to a publisher address may
+    /// take before giving up on it and trying the next one (default
+    /// `DEFAULT_CONNECT_TIMEOUT`).
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.connect_timeout = timeout;
+        self
+    }
+
+    /// A connection to a publisher that has left subscription
+    /// requests unanswered for longer than `d`, or that has had
+    /// subscriptions time out since it last answered, is considered
+    /// unhealthy. New subscriptions, even to paths published with
+    /// `USE_EXISTING`, are made on a new connection instead, while
+    /// existing subscriptions stay where they are. The default is
+    /// `DEFAULT_STALLED_AFTER`.
+    pub fn stalled_after(&mut self, d: Duration) -> &mut Self {
+        self.stalled_after = d;
+        self
+    }
+
+    /// Run the subscriber's background tasks, and its connections to
+    /// publishers and resolvers, on `rt`. The subscriber may then be
+    /// built outside of any runtime, and used from any runtime, or
+    /// none, without its tasks depending on the caller's runtime
+    /// staying alive. By default the runtime `build` is called in is
+    /// used, and `build` fails if it isn't called in one.
+    pub fn runtime(&mut self, rt: Handle) -> &mut Self {
+        self.runtime = Some(rt);
+        self
+    }
+
+    /// Override the limits of the pools the subscriber uses to
+    /// process updates, see `PoolConfig`. The pools are shared by
+    /// every subscriber in the process, so the last subscriber built
+    /// with this setting wins. Use `pool::stats` to see how the pools
+    /// are being used before tuning them.
+    pub fn pools(&mut self, cfg: PoolConfig) -> &mut Self {
+        self.pools = Some(cfg);
+        self
+    }
+
+    /// Inject faults into connections to publishers, see
+    /// `fault::FaultInjector`.
+    #[cfg(feature = "fault_injection")]
+    pub fn fault_injector(&mut self, faults: crate::fault::FaultInjector) -> &mut Self {
+        self.faults = Some(faults);
         self
     }
 }
@@ -716,24 +2289,7 @@ pub struct Subscriber(Arc<Mutex<SubscriberInner>>);
 impl Subscriber {
     /// create a new subscriber with the specified config and desired auth
     pub fn new(resolver: Config, desired_auth: DesiredAuth) -> Result<Subscriber> {
-        let (tx, rx) = mpsc::unbounded();
-        let tls_ctx = resolver.tls.clone().map(tls::CachedConnector::new);
-        let resolver = ResolverRead::new(resolver, desired_auth.clone());
-        let t = Subscriber(Arc::new(Mutex::new(SubscriberInner {
-            id: SubscriberId::new(),
-            resolver,
-            desired_auth,
-            connections: HashMap::default(),
-            recently_failed: HashMap::default(),
-            subscribed: HashMap::default(),
-            durable_dead: HashMap::default(),
-            durable_pending: HashMap::default(),
-            durable_alive: HashMap::default(),
-            trigger_resub: tx,
-            tls_ctx,
-        })));
-        t.start_resub_task(rx);
-        Ok(t)
+        SubscriberBuilder::new().config(resolver).desired_auth(desired_auth).build()
     }
 
     /// Return a unique identifier for this subscriber instance. The
@@ -743,6 +2299,15 @@ impl Subscriber {
         self.0.lock().id
     }
 
+    /// Make a new idempotency key for `Dval::write_keyed`, from the
+    /// subscriber's writer id (see `SubscriberBuilder::writer_id`)
+    /// and a counter.
+    pub fn write_key(&self) -> WriteKey {
+        let mut t = self.0.lock();
+        t.write_seq += 1;
+        WriteKey { writer: t.writer_id, seq: t.write_seq }
+    }
+
     /// return stats about durable subscriptions
     pub fn durable_stats(&self) -> DurableStats {
         let t = self.0.lock();
@@ -769,31 +2334,8 @@ impl Subscriber {
             }
         }
         fn update_retry(subscriber: &mut SubscriberInner, retry: &mut Option<Instant>) {
-            let now = Instant::now();
-            task::block_in_place(|| {
-                *retry = None;
-                for w in subscriber.durable_dead.values() {
-                    if let Some(dv) = w.upgrade() {
-                        let next_try = match &dv.0.lock().sub {
-                            DvState::Dead(dead) => dead.next_try,
-                            DvState::Subscribed(_) => unreachable!(),
-                        };
-                        match retry {
-                            None => {
-                                *retry = Some(next_try);
-                            }
-                            Some(retry) => {
-                                if next_try < *retry {
-                                    *retry = next_try;
-                                }
-                            }
-                        }
-                        if next_try <= now {
-                            break;
-                        }
-                    }
-                }
-            })
+            // stale entries may cause an early retry, which is harmless
+            *retry = subscriber.resub_queue.keys().next().map(|(t, _)| *t);
         }
         async fn do_resub(
             subscriber: &SubscriberWeak,
@@ -803,42 +2345,67 @@ impl Subscriber {
             info!("doing resubscriptions");
             let now = Instant::now();
             let (batch, timeout) = {
-                let mut dead = Vec::new();
                 let mut batch = Vec::new();
                 let mut subscriber = subscriber.0.lock();
                 let subscriber = &mut *subscriber;
                 let durable_dead = &mut subscriber.durable_dead;
                 let durable_pending = &mut subscriber.durable_pending;
+                let resub_queue = &mut subscriber.resub_queue;
+                let budget = max(1, subscriber.max_resub_batch);
+                let max_background = max(1, subscriber.max_background);
+                let background_in_flight = &mut subscriber.background_in_flight;
+                let throttled = &mut subscriber.throttled;
                 let mut max_tries = 1;
-                let mut total_retries = 0;
                 task::block_in_place(|| {
-                    for (p, w) in durable_dead.iter() {
+                    // everything that is due competes for the budget,
+                    // highest priority first, then in the order it
+                    // became due, anything left over because of the
+                    // budget is first in line next time.
+                    let mut due = Vec::new();
+                    while let Some(((t, _), _)) = resub_queue.first_key_value() {
+                        if *t > now {
+                            break;
+                        }
+                        let (k, p) = resub_queue.pop_first().unwrap();
+                        let w = match durable_dead.get(&p) {
+                            None => continue, // stale
+                            Some(w) => w,
+                        };
                         match w.upgrade() {
                             None => {
-                                dead.push(p.clone());
+                                durable_dead.remove(&p);
                             }
                             Some(s) => {
-                                let (next_try, tries) = {
-                                    let mut dv = s.0.lock();
-                                    match &mut dv.sub {
-                                        DvState::Dead(d) => (d.next_try, d.tries),
-                                        DvState::Subscribed(_) => unreachable!(),
+                                let dv = s.0.lock();
+                                let (next_try, tries) = match &dv.sub {
+                                    DvState::Dead(d) => (d.next_try, d.tries),
+                                    DvState::Subscribed(_) | DvState::Failed => {
+                                        unreachable!()
                                     }
                                 };
-                                if next_try <= now {
-                                    batch.push(p.clone());
-                                    durable_pending.insert(p.clone(), w.clone());
-                                    max_tries = max(max_tries, tries);
-                                    total_retries += 1;
-                                    if total_retries >= 100_000 {
-                                        break;
-                                    }
+                                if next_try == k.0 {
+                                    due.push((dv.priority, k, p, tries));
                                 }
                             }
                         }
                     }
-                    for p in dead.iter().chain(batch.iter()) {
-                        durable_dead.remove(p);
+                    due.sort_by_key(|(priority, k, _, _)| (Reverse(*priority), *k));
+                    for (priority, k, p, tries) in due {
+                        let background = priority == Priority::Background;
+                        if batch.len() >= budget {
+                            resub_queue.insert(k, p);
+                        } else if background
+                            && background_in_flight.len() >= max_background
+                        {
+                            throttled.push((k, p));
+                        } else if let Some(w) = durable_dead.remove(&p) {
+                            durable_pending.insert(p.clone(), w);
+                            if background {
+                                background_in_flight.insert(p.clone());
+                            }
+                            max_tries = max(max_tries, tries);
+                            batch.push(p);
+                        }
                     }
                 });
                 let timeout = 30 + max(10, batch.len() / 10000) * max_tries;
@@ -853,59 +2420,165 @@ impl Subscriber {
                 Some(subscriber.subscribe_nondurable(batch, Some(timeout)).await)
             }
         }
+        async fn refresh(
+            subscriber: SubscriberWeak,
+            f: RefreshHook,
+            path: Path,
+            dv: DvalWeak,
+        ) {
+            let retry = f(path.clone()).await;
+            if let Some(subscriber) = subscriber.upgrade() {
+                let mut t = subscriber.0.lock();
+                // the dval may have been unsubscribed while we waited
+                let waiting = match t.durable_dead.get(&path) {
+                    Some(w) => Weak::ptr_eq(&w.0, &dv.0),
+                    None => false,
+                };
+                if let (true, Some(ds)) = (waiting, dv.upgrade()) {
+                    let mut ds = ds.0.lock();
+                    if retry {
+                        let now = Instant::now();
+                        if let DvState::Dead(d) = &mut ds.sub {
+                            d.next_try = now;
+                        }
+                        t.add_durable_dead(path, dv, now);
+                        let _ = t.trigger_resub.unbounded_send(());
+                    } else {
+                        warn!("refresh for {} failed, giving up", path);
+                        t.durable_dead.remove(&path);
+                        ds.set_state(DvState::Failed);
+                    }
+                }
+            }
+        }
         fn finish_resubscription_batch(
             subscriber: &SubscriberWeak,
             batch: &mut Vec<(Path, Result<Val>)>,
             retry: &mut Option<Instant>,
         ) {
+            let weak = subscriber;
             if let Some(subscriber) = subscriber.upgrade() {
                 let mut subscriber = subscriber.0.lock();
                 let now = Instant::now();
                 for (p, r) in batch.drain(..) {
+                    subscriber.background_in_flight.remove(&p);
                     if let Some(ds) =
                         subscriber.durable_pending.remove(&p).and_then(|ds| ds.upgrade())
                     {
                         let dsw = ds.downgrade();
                         let mut dv = ds.0.lock();
                         match r {
-                            Err(e) => match &mut dv.sub {
-                                DvState::Subscribed(_) => unreachable!(),
-                                DvState::Dead(d) => {
-                                    d.tries += 1;
-                                    let wait = Duration::from_secs(pick(d.tries) as u64);
-                                    d.next_try = now + wait;
-                                    let s = wait.as_secs();
-                                    warn!(
-                                        "resubscription error {}: {}, next try: {}s",
-                                        p, e, s
-                                    );
-                                    subscriber.durable_dead.insert(p.clone(), dsw);
+                            Err(e) => {
+                                let give_up = dv.give_up;
+                                let denied = e.is::<PermissionDenied>();
+                                let policy = if denied {
+                                    subscriber.on_denied.clone()
+                                } else {
+                                    OnDenied::Retry
+                                };
+                                let d = match &mut dv.sub {
+                                    DvState::Dead(d) => d,
+                                    DvState::Subscribed(_) | DvState::Failed => {
+                                        unreachable!()
+                                    }
+                                };
+                                d.tries += 1;
+                                let changed = d.denied != denied;
+                                d.denied = denied;
+                                let expired = match policy {
+                                    OnDenied::GiveUp => true,
+                                    _ => give_up.expired(d, now),
+                                };
+                                if expired {
+                                    warn!("resubscription error {}: {}, giving up", p, e);
+                                    dv.set_state(DvState::Failed);
+                                    continue;
+                                }
+                                match policy {
+                                    OnDenied::Refresh(f) => {
+                                        warn!(
+                                            "resubscription error {}: {}, refreshing",
+                                            p, e
+                                        );
+                                        // dead, but not queued for retry
+                                        // until the hook says so
+                                        subscriber
+                                            .durable_dead
+                                            .insert(p.clone(), dsw.clone());
+                                        subscriber.rt.spawn(refresh(
+                                            weak.clone(),
+                                            f,
+                                            p.clone(),
+                                            dsw,
+                                        ));
+                                    }
+                                    OnDenied::Retry
+                                    | OnDenied::RetryAfter(_)
+                                    | OnDenied::GiveUp => {
+                                        let wait = match policy {
+                                            OnDenied::RetryAfter(wait) => wait,
+                                            _ => {
+                                                Duration::from_secs(pick(d.tries) as u64)
+                                            }
+                                        };
+                                        d.next_try = now + wait;
+                                        let s = wait.as_secs();
+                                        warn!(
+                                            "resubscription error {}: {}, next try: {}s",
+                                            p, e, s
+                                        );
+                                        let next_try = d.next_try;
+                                        subscriber.add_durable_dead(
+                                            p.clone(),
+                                            dsw,
+                                            next_try,
+                                        );
+                                    }
                                 }
-                            },
+                                if changed {
+                                    dv.notify_state()
+                                }
+                            }
                             Ok(sub) => {
                                 info!("resubscription success {}", p);
-                                for (flags, tx) in dv.streams.0.iter().cloned() {
+                                for ((flags, sample), tx) in dv.streams.0.iter().cloned()
+                                {
                                     sub.0.connection.send(ToCon::Stream {
                                         tx: tx.0,
                                         sub_id: dv.sub_id,
                                         id: sub.0.id,
                                         flags: flags | UpdatesFlags::BEGIN_WITH_LAST,
+                                        sample,
                                     });
                                 }
+                                dv.resend_unacked(&dsw, &sub);
+                                let dv = &mut *dv;
                                 if let DvState::Dead(d) = &mut dv.sub {
-                                    for (v, resp) in d.queued_writes.drain(..) {
+                                    for (v, key, resp) in d.queued_writes.drain(..) {
+                                        if let Some(WriteReply::Tracked(_, seq)) = &resp {
+                                            match dv.unacked.get_mut(seq) {
+                                                Some(u) => u.tries += 1,
+                                                None => continue,
+                                            }
+                                        }
                                         sub.0
                                             .connection
-                                            .send(ToCon::Write(sub.0.id, v, resp));
+                                            .send(ToCon::Write(sub.0.id, v, key, resp));
                                     }
                                 }
-                                dv.sub = DvState::Subscribed(sub);
+                                dv.set_state(DvState::Subscribed(sub));
                                 subscriber.durable_alive.insert(p.clone(), dsw);
                             }
                         }
                     }
                 }
-                update_retry(&mut *subscriber, retry);
+                let subscriber = &mut *subscriber;
+                if subscriber.background_in_flight.len() < subscriber.max_background {
+                    for (k, p) in subscriber.throttled.drain(..) {
+                        subscriber.resub_queue.insert(k, p);
+                    }
+                }
+                update_retry(subscriber, retry);
             }
         }
         async fn next_subscription_result(
@@ -938,8 +2611,9 @@ impl Subscriber {
                 }
             }
         }
+        let rt = self.0.lock().rt.clone();
         let subscriber = self.downgrade();
-        task::spawn(async move {
+        rt.spawn(async move {
             let mut incoming = Batched::new(incoming.fuse(), 1_000_000_000);
             let mut subscriptions = VecDeque::new();
             let mut subscription_batch = Vec::new();
@@ -982,28 +2656,101 @@ impl Subscriber {
         });
     }
 
+    // the connection to use for the chosen publisher, which is
+    // started if necessary
+    fn connection_for(&self, t: &mut SubscriberInner, ch: &Chosen) -> BatchSender<ToCon> {
+        let desired_auth = t.desired_auth.clone();
+        let tls_ctx = t.tls_ctx.clone();
+        let limiter = t.limiter.clone();
+        let shm_ring = t.shm_ring;
+        let rt = t.rt.clone();
+        let stalled_after = t.stalled_after;
+        let con = t.connections.entry(ch.addr).or_insert_with(|| Connection {
+            primary: None,
+            isolated: HashMap::default(),
+        });
+        if con.primary.is_some() && !con.healthy(stalled_after) {
+            con.retire_primary()
+        }
+        if ch.flags.contains(PublishFlags::ISOLATED) {
+            let (id, c, _) = self.start_connection(
+                &rt,
+                limiter,
+                shm_ring,
+                tls_ctx,
+                ch.uifo.clone(),
+                ch.addr,
+                ch.alt_addr,
+                &ch.target_auth,
+                &desired_auth,
+                (ch.id, ch.timestamp),
+            );
+            con.isolated.insert(id, c.clone());
+            c
+        } else {
+            match &con.primary {
+                Some((_, c, _)) => c.clone(),
+                None => {
+                    let (id, c, health) = self.start_connection(
+                        &rt,
+                        limiter,
+                        shm_ring,
+                        tls_ctx,
+                        ch.uifo.clone(),
+                        ch.addr,
+                        ch.alt_addr,
+                        &ch.target_auth,
+                        &desired_auth,
+                        (ch.id, ch.timestamp),
+                    );
+                    con.primary = Some((id, c.clone(), health));
+                    c
+                }
+            }
+        }
+    }
+
     fn start_connection(
         &self,
+        rt: &Handle,
+        limiter: Option<Arc<RateLimiter>>,
+        shm_ring: Option<usize>,
         tls_ctx: Option<tls::CachedConnector>,
         uifo: Option<UserInfo>,
         addr: SocketAddr,
+        alt_addr: Option<SocketAddr>,
         target_auth: &TargetAuth,
         desired_auth: &DesiredAuth,
-    ) -> (ConId, BatchSender<ToCon>) {
+        record: (PublisherId, u64),
+    ) -> (ConId, BatchSender<ToCon>, Arc<Health>) {
         let (tx, rx) = batch_channel::channel();
         let subscriber = self.downgrade();
         let desired_auth = desired_auth.clone();
         let conid = ConId::new();
         let target_auth = target_auth.clone();
-        task::spawn(async move {
+        let health = Arc::new(Health::default());
+        let health_ = health.clone();
+        rt.spawn(async move {
+            let limited = match limiter {
+                None => None,
+                Some(limiter) => {
+                    let start = Instant::now();
+                    limiter.acquire().await;
+                    Some((start, Instant::now()))
+                }
+            };
             let res = connection::ConnectionCtx::new(
                 addr,
+                alt_addr,
                 subscriber.clone(),
                 conid,
                 tls_ctx,
                 uifo,
                 target_auth,
                 desired_auth,
+                shm_ring,
+                limited,
+                health_,
                 rx,
             )
             .start()
@@ -1020,16 +2767,28 @@ impl Subscriber {
                 }
                 match res {
                     Ok(()) => {
+                        let ev = ConnEvent::Disconnected(addr, literal!("closed"));
+                        subscriber.0.lock().conn_event(ev);
                         info!("connection to {} closed", addr)
                     }
                     Err(e) => {
-                        subscriber.0.lock().recently_failed.insert(addr, Instant::now());
+                        let mut t = subscriber.0.lock();
+                        let now = Instant::now();
+                        t.recently_failed.insert(addr, now);
+                        if e.is::<HandshakeFailed>() {
+                            let (id, timestamp) = record;
+                            t.stale.insert(addr, Stale { id, timestamp, failed: now });
+                        }
+                        t.conn_event(ConnEvent::Disconnected(
+                            addr,
+                            ArcStr::from(e.to_string()),
+                        ));
                         warn!("connection to {} failed {}", addr, e)
                     }
                 }
             }
         });
-        (conid, tx)
+        (conid, tx, health)
     }
 
     /// Subscribe to the specified set of values.
@@ -1056,6 +2815,12 @@ impl Subscriber {
     /// the batch, which may complete successfully. If you need all or
     /// nothing behavior, specify None for timeout and wrap the
     /// `subscribe` future in a `tokio::time::timeout`.
+    ///
+    /// It is safe to drop the `subscribe` future, or the futures it
+    /// returns, before they complete. Any subscription attempt they
+    /// were making will be canceled, and concurrent callers waiting
+    /// on it will receive a `Canceled` error. See also
+    /// `abort_pending`.
     pub async fn subscribe_nondurable(
         &self,
         batch: impl IntoIterator<Item = Path>,
@@ -1069,38 +2834,36 @@ impl Subscriber {
             Subscribed(Val),
             Error(Error),
         }
-        let now = Instant::now();
         let paths = batch.into_iter().collect::<Vec<_>>();
         let mut pending: HashMap<Path, St> = HashMap::new();
+        // if this future, or the future waiting for the result, is
+        // dropped then these will clean up our subscription attempts
+        let mut guards: HashMap<Path, PendingGuard> = HashMap::new();
         // Init
-        let r = {
+        let (r, limiter) = {
             let mut t = self.0.lock();
             t.gc_recently_failed();
+            let shutdown = t.shutdown;
             for p in paths.clone() {
-                match t.subscribed.entry(p.clone()) {
-                    Entry::Vacant(e) => {
-                        e.insert(SubStatus::Pending(Box::new(vec![])));
-                        pending.insert(p, St::Resolve);
+                let st = match t.subscribed.get_mut(&p) {
+                    _ if shutdown => St::Error(anyhow!("the subscriber is shut down")),
+                    None => St::Resolve,
+                    Some(SubStatus::Pending(ref mut v)) => {
+                        let (tx, rx) = oneshot::channel();
+                        v.waiters.push(tx);
+                        St::WaitingOther(rx)
                     }
-                    Entry::Occupied(mut e) => match e.get_mut() {
-                        SubStatus::Pending(ref mut v) => {
-                            let (tx, rx) = oneshot::channel();
-                            v.push(tx);
-                            pending.insert(p, St::WaitingOther(rx));
-                        }
-                        SubStatus::Subscribed(r) => match r.upgrade() {
-                            Some(r) => {
-                                pending.insert(p, St::Subscribed(r));
-                            }
-                            None => {
-                                e.insert(SubStatus::Pending(Box::new(vec![])));
-                                pending.insert(p, St::Resolve);
-                            }
-                        },
+                    Some(SubStatus::Subscribed(r)) => match r.upgrade() {
+                        Some(r) => St::Subscribed(r),
+                        None => St::Resolve,
                     },
+                };
+                if let St::Resolve = st {
+                    guards.insert(p.clone(), t.add_pending(self, p.clone()));
                 }
+                pending.insert(p, st);
             }
-            t.resolver.clone()
+            (t.resolver.clone(), t.limiter.clone())
         };
         // Resolve, Connect, Subscribe
         {
@@ -1112,6 +2875,11 @@ impl Subscriber {
                 })
                 .map(|(p, _)| p.clone())
                 .collect::<Vec<_>>();
+            if let (Some(limiter), false) = (limiter, to_resolve.is_empty()) {
+                limiter.acquire().await
+            }
+            // waiting for the limiter doesn't count against the timeout
+            let now = Instant::now();
             let r = match timeout {
                 None => Ok(r.resolve(to_resolve.iter().cloned()).await),
                 Some(d) => time::timeout(d, r.resolve(to_resolve.iter().cloned())).await,
@@ -1132,88 +2900,71 @@ impl Subscriber {
                 Ok(Ok((publishers, mut res))) => {
                     let mut t = self.0.lock();
                     let deadline = timeout.map(|t| now + t);
-                    let desired_auth = t.desired_auth.clone();
                     for (p, resolved) in to_resolve.into_iter().zip(res.drain(..)) {
-                        if resolved.publishers.len() == 0 {
+                        let aborted = match (t.subscribed.get(&p), guards.get(&p)) {
+                            (Some(SubStatus::Pending(s)), Some(g)) => s.id != g.id,
+                            (_, _) => true,
+                        };
+                        if aborted {
+                            pending.insert(p, St::Error(Error::from(Canceled)));
+                        } else if resolved.publishers.len() == 0 {
                             pending.insert(p, St::Error(anyhow!("path not found")));
-                        } else if let Some(ch) = t.choose_addr(&publishers, &resolved) {
-                            let tls_ctx = t.tls_ctx.clone();
-                            let sub_id = t.durable_id(&p).unwrap_or_else(SubId::new);
-                            let con = t.connections.entry(ch.addr).or_insert_with(|| {
-                                Connection { primary: None, isolated: HashMap::default() }
-                            });
-                            let con = if ch.flags.contains(PublishFlags::ISOLATED) {
-                                let (id, c) = self.start_connection(
-                                    tls_ctx,
-                                    ch.uifo,
-                                    ch.addr,
-                                    &ch.target_auth,
-                                    &desired_auth,
-                                );
-                                con.isolated.insert(id, c.clone());
-                                c
-                            } else {
-                                match &con.primary {
-                                    Some((_, c)) => c.clone(),
-                                    None => {
-                                        let (id, c) = self.start_connection(
-                                            tls_ctx,
-                                            ch.uifo,
-                                            ch.addr,
-                                            &ch.target_auth,
-                                            &desired_auth,
+                        } else {
+                            match t.choose_addr(&publishers, &resolved) {
+                                Err(e) => {
+                                    pending.insert(p, St::Error(e));
+                                }
+                                Ok(ch) => {
+                                    let sub_id =
+                                        t.durable_id(&p).unwrap_or_else(SubId::new);
+                                    let con = self.connection_for(&mut *t, &ch);
+                                    let (tx, rx) = oneshot::channel();
+                                    let con_ = con.clone();
+                                    let r =
+                                        con.send(ToCon::Subscribe(SubscribeValRequest {
+                                            path: p.clone(),
+                                            sub_id,
+                                            timestamp: resolved.timestamp,
+                                            permissions: resolved.permissions as u32,
+                                            token: ch.token,
+                                            resolver: resolved.resolver,
+                                            finished: tx,
+                                            con: con_,
+                                            queued: Instant::now(),
+                                            deadline,
+                                        }));
+                                    if r {
+                                        pending.insert(p, St::Subscribing(rx));
+                                    } else {
+                                        pending.insert(
+                                            p,
+                                            St::Error(Error::from(anyhow!(
+                                                "connection closed"
+                                            ))),
                                         );
-                                        con.primary = Some((id, c.clone()));
-                                        c
                                     }
                                 }
-                            };
-                            let (tx, rx) = oneshot::channel();
-                            let con_ = con.clone();
-                            let r = con.send(ToCon::Subscribe(SubscribeValRequest {
-                                path: p.clone(),
-                                sub_id,
-                                timestamp: resolved.timestamp,
-                                permissions: resolved.permissions as u32,
-                                token: ch.token,
-                                resolver: resolved.resolver,
-                                finished: tx,
-                                con: con_,
-                                deadline,
-                            }));
-                            if r {
-                                pending.insert(p, St::Subscribing(rx));
-                            } else {
-                                pending.insert(
-                                    p,
-                                    St::Error(Error::from(anyhow!("connection closed"))),
-                                );
                             }
-                        } else {
-                            let e = anyhow!("missing publisher record");
-                            pending.insert(p, St::Error(e));
                         }
                     }
                 }
             }
         }
         // Wait
-        async fn wait_result(sub: Subscriber, path: Path, st: St) -> (Path, Result<Val>) {
+        async fn wait_result(
+            sub: Subscriber,
+            path: Path,
+            st: St,
+            guard: Option<PendingGuard>,
+        ) -> (Path, Result<Val>) {
             match st {
                 St::Resolve => unreachable!(),
                 St::Subscribed(raw) => (path, Ok(raw)),
                 St::Error(e) => {
-                    let mut t = sub.0.lock();
-                    if let Some(sub) = t.subscribed.remove(path.as_ref()) {
-                        match sub {
-                            SubStatus::Subscribed(_) => unreachable!(),
-                            SubStatus::Pending(waiters) => {
-                                for w in waiters.into_iter() {
-                                    let err = Err(anyhow!("{}", e));
-                                    let _ = w.send(err);
-                                }
-                            }
-                        }
+                    let id = guard.as_ref().map(|g| g.id);
+                    let p = sub.0.lock().take_pending(&path, id);
+                    if let Some(mut p) = p {
+                        p.complete(&Err(anyhow!("{}", e)));
                     }
                     (path, Err(e))
                 }
@@ -1223,46 +2974,116 @@ impl Subscriber {
                     Ok(Ok(raw)) => (path, Ok(raw)),
                 },
                 St::Subscribing(w) => {
-                    let res = match w.await {
-                        Err(e) => Err(anyhow!("connection died {}", e)),
-                        Ok(Err(e)) => Err(e),
-                        Ok(Ok(raw)) => Ok(raw),
+                    let mut guard = guard.unwrap();
+                    let res = select_biased! {
+                        _ = (&mut guard.aborted).fuse() => Err(Error::from(Canceled)),
+                        r = w.fuse() => match r {
+                            Err(e) => Err(anyhow!("connection died {}", e)),
+                            Ok(Err(e)) => Err(e),
+                            Ok(Ok(raw)) => Ok(raw),
+                        }
                     };
                     let mut t = sub.0.lock();
-                    match t.subscribed.entry(path.clone()) {
-                        Entry::Vacant(_) => unreachable!(),
-                        Entry::Occupied(mut e) => match res {
-                            Err(err) => match e.remove() {
-                                SubStatus::Subscribed(_) => unreachable!(),
-                                SubStatus::Pending(waiters) => {
-                                    for w in waiters.into_iter() {
-                                        let err = Err(anyhow!("{}", err));
-                                        let _ = w.send(err);
-                                    }
-                                    (path, Err(err))
-                                }
-                            },
-                            Ok(raw) => {
-                                let s = mem::replace(
-                                    e.get_mut(),
-                                    SubStatus::Subscribed(raw.downgrade()),
-                                );
-                                match s {
-                                    SubStatus::Subscribed(_) => unreachable!(),
-                                    SubStatus::Pending(waiters) => {
-                                        for w in waiters.into_iter() {
-                                            let _ = w.send(Ok(raw.clone()));
-                                        }
-                                        (path, Ok(raw))
-                                    }
-                                }
+                    match t.take_pending(&path, Some(guard.id)) {
+                        // we were aborted, abort_pending already
+                        // completed the waiters
+                        None => (path, Err(Error::from(Canceled))),
+                        Some(mut p) => {
+                            if let Ok(raw) = &res {
+                                let s = SubStatus::Subscribed(raw.downgrade());
+                                t.subscribed.insert(path.clone(), s);
                             }
-                        },
+                            drop(t);
+                            p.complete(&res);
+                            (path, res)
+                        }
                     }
                 }
             }
         }
-        pending.drain().map(|(path, st)| wait_result(self.clone(), path, st)).collect()
+        pending
+            .drain()
+            .map(|(path, st)| {
+                let guard = guards.remove(&path);
+                wait_result(self.clone(), path, st, guard)
+            })
+            .collect()
+    }
+
+    // Subscribe to `path` at the publisher `pb`, without choosing
+    // between it's publishers or registering the subscription, the
+    // caller must hold on to the result. None if another
+    // subscription to `path` is in flight, because the connection
+    // can only wait for one at a time. See `DvalGroup`.
+    fn subscribe_at(
+        &self,
+        path: &Path,
+        pb: &Publisher,
+        pref: &PublisherRef,
+        resolved: &Resolved,
+        deadline: Instant,
+    ) -> Result<Option<oneshot::Receiver<Result<Val>>>> {
+        let mut t = self.0.lock();
+        if t.shutdown {
+            bail!("the subscriber is shut down")
+        }
+        if let Some(SubStatus::Pending(_)) = t.subscribed.get(path) {
+            return Ok(None);
+        }
+        let mut flags = PublishFlags::from_bits(resolved.flags)
+            .ok_or_else(|| anyhow!("invalid publish flags"))?;
+        if flags.contains(PublishFlags::USE_EXISTING) {
+            flags = flags & !PublishFlags::ISOLATED;
+        }
+        let ch = Chosen {
+            id: pb.id,
+            timestamp: resolved.timestamp,
+            addr: pb.addr,
+            alt_addr: pb.alt_addr,
+            target_auth: pb.target_auth.clone(),
+            token: pref.token.clone(),
+            subtree_token: pref.subtree_token.clone(),
+            uifo: pb.user_info.clone(),
+            flags,
+        };
+        let con = self.connection_for(&mut *t, &ch);
+        let (tx, rx) = oneshot::channel();
+        let r = con.send(ToCon::Subscribe(SubscribeValRequest {
+            path: path.clone(),
+            sub_id: SubId::new(),
+            timestamp: resolved.timestamp,
+            permissions: resolved.permissions as u32,
+            token: ch.token,
+            resolver: resolved.resolver,
+            finished: tx,
+            con: con.clone(),
+            queued: Instant::now(),
+            deadline: Some(deadline),
+        }));
+        if !r {
+            bail!("connection closed")
+        }
+        Ok(Some(rx))
+    }
+
+    /// Cancel the in flight subscription attempt to `path`, if
+    /// any. Everyone waiting for the attempt to finish will receive a
+    /// `Canceled` error. Return true if there was an attempt in
+    /// flight.
+    ///
+    /// This does not effect an existing subscription to `path`. A
+    /// future attempt to subscribe to `path` will start a new
+    /// subscription attempt.
+    pub fn abort_pending(&self, path: &Path) -> bool {
+        let p = self.0.lock().take_pending(path, None);
+        match p {
+            None => false,
+            Some(mut p) => {
+                p.complete(&Err(Error::from(Canceled)));
+                let _ = p.abort.send(());
+                true
+            }
+        }
     }
 
     /// Subscribe to just one value. This is sufficient for a small
@@ -1277,6 +3098,135 @@ impl Subscriber {
         self.subscribe_nondurable(iter::once(path), timeout).await.next().await.unwrap().1
     }
 
+    /// Subscribe to every value published under `base` that matches
+    /// `glob`, which is relative to `base`, e.g. `*/last`, or `**` for
+    /// the whole subtree. Rather than one subscribe per path this
+    /// sends one message to the publisher that `base` resolves to, so
+    /// `base` must either be published itself, or be the base of a
+    /// default publisher. The permissions the resolver grants for
+    /// `base` apply to all the values, so if the resolver denies any
+    /// of them anywhere under `base`, or `base` has part of it's
+    /// subtree delegated to another resolver, the whole subscribe
+    /// many is denied.
+    ///
+    /// Only values that are already published are included, default
+    /// publishers are not asked to publish anything. As with
+    /// `subscribe_nondurable` existing subscriptions are reused. This
+    /// fails if the publisher or the resolver is older than subscribe
+    /// many, in which case use `subscribe_nondurable`.
+    pub async fn subscribe_many(
+        &self,
+        base: Path,
+        glob: &str,
+        timeout: Option<Duration>,
+    ) -> Result<Vec<(Path, Val)>> {
+        let glob = Chars::from(String::from(glob));
+        // check the glob before sending it
+        Glob::new(Chars::from(String::from(&*base.append(&*glob))))?;
+        let deadline = timeout.map(|t| Instant::now() + t);
+        let r = self.resolver();
+        let resolve = r.resolve(iter::once(base.clone()));
+        let (publishers, resolved) = match timeout {
+            None => resolve.await?,
+            Some(d) => time::timeout(d, resolve)
+                .await
+                .map_err(|_| anyhow!("resolving {} timed out", base))??,
+        };
+        // the rest is bounded by the deadline, which the connection
+        // extends by any time it spends waiting for the rate limiter
+        let rx = {
+            let mut t = self.0.lock();
+            if t.shutdown {
+                bail!("the subscriber is shut down")
+            }
+            let resolved = match resolved.first() {
+                Some(r) if !r.publishers.is_empty() => r,
+                Some(_) | None => bail!("no publisher for {}", base),
+            };
+            let ch = t.choose_addr(&publishers, resolved)?;
+            // the resolver only signs a subtree token if nothing
+            // under base is denied, or if it is too old to know
+            // about subscribe many
+            if !ch.token.is_empty() && ch.subtree_token.is_empty() {
+                bail!("permission denied, subscribe many of {}", base)
+            }
+            let con = self.connection_for(&mut *t, &ch);
+            let (tx, rx) = oneshot::channel();
+            let sent = con.send(ToCon::SubscribeMany(SubscribeManyRequest {
+                base: base.clone(),
+                glob,
+                timestamp: resolved.timestamp,
+                permissions: resolved.permissions as u32,
+                token: ch.subtree_token,
+                resolver: resolved.resolver,
+                finished: tx,
+                con: con.clone(),
+                queued: Instant::now(),
+                deadline,
+            }));
+            if !sent {
+                bail!("connection closed")
+            }
+            rx
+        };
+        let vals = rx.await.map_err(|e| anyhow!("connection died {}", e))??;
+        let mut t = self.0.lock();
+        for (path, val) in vals.iter() {
+            let subscribed = match t.subscribed.get(path) {
+                Some(SubStatus::Pending(_)) => true,
+                Some(SubStatus::Subscribed(v)) => v.upgrade().is_some(),
+                None => false,
+            };
+            if !subscribed {
+                t.subscribed.insert(path.clone(), SubStatus::Subscribed(val.downgrade()));
+            }
+        }
+        Ok(vals)
+    }
+
+    /// Fetch the schema published for `path`, if any, see
+    /// `Publisher::publish_schema`. Returns `None` if no schema is
+    /// published, and an error if one is published but it can't be
+    /// read.
+    pub async fn schema(&self, path: &Path) -> Result<Option<Schema>> {
+        let path = schema_path(path);
+        let (_, resolved) = self.resolver().resolve(iter::once(path.clone())).await?;
+        if resolved.iter().all(|r| r.publishers.is_empty()) {
+            return Ok(None);
+        }
+        match self.subscribe_nondurable_one(path, None).await?.last() {
+            Event::Unsubscribed(_) => bail!("the schema was unpublished"),
+            Event::Update(v) => Ok(Some(v.cast_to::<Schema>()?)),
+        }
+    }
+
+    /// Subscribe to every path published under `base`, now and in
+    /// the future, see `Tree`. The resolver is checked for structural
+    /// changes to the subtree every `DEFAULT_TREE_POLL`.
+    pub fn subscribe_tree(&self, base: Path) -> Tree {
+        self.subscribe_tree_with_poll(base, DEFAULT_TREE_POLL)
+    }
+
+    /// Same as `subscribe_tree`, but check the resolver for
+    /// structural changes every `poll`.
+    pub fn subscribe_tree_with_poll(&self, base: Path, poll: Duration) -> Tree {
+        Tree::new(self, base, poll)
+    }
+
+    /// Subscribe to `path` at every publisher publishing it, now
+    /// and in the future, see `DvalGroup`. The resolver is checked
+    /// for changes to the set of publishers every
+    /// `DEFAULT_GROUP_POLL`.
+    pub fn subscribe_group(&self, path: Path) -> DvalGroup {
+        self.subscribe_group_with_poll(path, DEFAULT_GROUP_POLL)
+    }
+
+    /// Same as `subscribe_group`, but check the resolver for
+    /// changes every `poll`.
+    pub fn subscribe_group_with_poll(&self, path: Path, poll: Duration) -> DvalGroup {
+        DvalGroup::new(self, path, poll)
+    }
+
     /// Create a durable value subscription to `path`.
     ///
     /// Batching of durable subscriptions is automatic, if you create
@@ -1286,6 +3236,19 @@ impl Subscriber {
     /// subscribe_nondurable, except that certain errors are caught,
     /// and resubscriptions are attempted. see `Dval`.
     pub fn subscribe(&self, path: Path) -> Dval {
+        self.subscribe_with_priority(path, Priority::Normal)
+    }
+
+    /// Create a durable value subscription to `path` with
+    /// `priority`, see `Priority`. If there is already a durable
+    /// subscription to `path` it is returned, and its priority is
+    /// raised to `priority` if it is lower.
+    pub fn subscribe_with_priority(&self, path: Path, priority: Priority) -> Dval {
+        self.subscribe_int(path, priority).0
+    }
+
+    // subscribe, and return true if a new `Dval` was created
+    fn subscribe_int(&self, path: Path, priority: Priority) -> (Dval, bool) {
         let mut t = self.0.lock();
         if let Some(s) = t
             .durable_dead
@@ -1294,21 +3257,152 @@ impl Subscriber {
             .or_else(|| t.durable_alive.get(&path))
         {
             if let Some(s) = s.upgrade() {
-                return s;
+                let raise = {
+                    let mut dv = s.0.lock();
+                    let raise = dv.priority < priority;
+                    if raise {
+                        dv.priority = priority;
+                    }
+                    raise
+                };
+                if raise {
+                    t.unthrottle(&path)
+                }
+                return (s, false);
             }
         }
+        let next_try = Instant::now();
+        let sub = if t.shutdown {
+            DvState::Failed
+        } else {
+            DvState::Dead(Box::new(DvDead::new(next_try)))
+        };
         let s = Dval(Arc::new(Mutex::new(DvalInner {
             sub_id: SubId::new(),
-            sub: DvState::Dead(Box::new(DvDead {
-                queued_writes: Vec::new(),
-                tries: 0,
-                next_try: Instant::now(),
-            })),
+            path: path.clone(),
+            subscriber: self.downgrade(),
+            sub,
             streams: DvStreams::new(),
+            give_up: GiveUp::default(),
+            priority,
+            states: Vec::new(),
+            permissions: None,
+            permission_changes: Vec::new(),
+            write_retry: None,
+            unacked: BTreeMap::new(),
+            write_seq: 0,
         })));
-        t.durable_dead.insert(path, s.downgrade());
-        let _ = t.trigger_resub.unbounded_send(());
-        s
+        if !t.shutdown {
+            t.add_durable_dead(path, s.downgrade(), next_try);
+            let _ = t.trigger_resub.unbounded_send(());
+        }
+        (s, true)
+    }
+
+    /// Write many values, keyed by path. Writes to a path with a
+    /// durable subscription go through it, as with `Dval::write`.
+    /// For the other paths a transient durable subscription is made
+    /// and the writes are queued on it, it lives until they are
+    /// sent, or until `TRANSIENT_WRITE_TIMEOUT` passes without
+    /// subscribing.
+    ///
+    /// The writes that can be sent now are grouped by connection,
+    /// and each connection gets all of it's writes in one batch, so
+    /// this is much cheaper than calling `Dval::write` in a loop when
+    /// fanning in writes from many sources. Writes to the same path
+    /// are sent in the order they are given.
+    pub fn write_many<I>(&self, writes: I) -> WriteResults
+    where
+        I: IntoIterator<Item = (Path, Value)>,
+    {
+        self.write_many_int(writes, false)
+    }
+
+    /// Like `write_many`, except the publisher replies to each write,
+    /// as with `Dval::write_with_recipt`. The replies are in
+    /// `WriteResults::receipts`.
+    pub fn write_many_with_recipt<I>(&self, writes: I) -> WriteResults
+    where
+        I: IntoIterator<Item = (Path, Value)>,
+    {
+        self.write_many_int(writes, true)
+    }
+
+    fn write_many_int<I>(&self, writes: I, receipts: bool) -> WriteResults
+    where
+        I: IntoIterator<Item = (Path, Value)>,
+    {
+        let mut res = WriteResults::default();
+        let mut dvals: HashMap<Path, Dval> = HashMap::new();
+        let mut transient: Vec<Dval> = Vec::new();
+        let mut by_con: FxHashMap<ConId, (BatchSender<ToCon>, Vec<ToCon>)> =
+            FxHashMap::default();
+        for (path, v) in writes {
+            let dv = match dvals.get(&path) {
+                Some(dv) => dv.clone(),
+                None => {
+                    let (dv, new) = self.subscribe_int(path.clone(), Priority::Normal);
+                    if new {
+                        dv.set_give_up(GiveUp {
+                            max_tries: None,
+                            after: Some(TRANSIENT_WRITE_TIMEOUT),
+                        });
+                        res.transient.push(path.clone());
+                        transient.push(dv.clone());
+                    }
+                    dvals.insert(path.clone(), dv.clone());
+                    dv
+                }
+            };
+            let reply = if receipts {
+                let (tx, rx) = oneshot::channel();
+                res.receipts.push((path, rx));
+                Some(WriteReply::Value(tx))
+            } else {
+                None
+            };
+            match dv.prepare_write(v, None, reply) {
+                WriteAction::Send(conid, con, m) => {
+                    res.sent += 1;
+                    by_con.entry(conid).or_insert_with(|| (con, Vec::new())).1.push(m)
+                }
+                WriteAction::Queued => res.queued += 1,
+                WriteAction::Dropped => res.dropped += 1,
+            }
+        }
+        for (_, (con, msgs)) in by_con {
+            con.send_many(msgs);
+        }
+        if !transient.is_empty() {
+            let rt = self.0.lock().rt.clone();
+            // the queued writes are sent when the subscription
+            // succeeds, after that it can go
+            let mut waiting = transient
+                .into_iter()
+                .map(|dv| async move {
+                    let _ = dv.wait_subscribed().await;
+                })
+                .collect::<FuturesUnordered<_>>();
+            rt.spawn(async move { while let Some(()) = waiting.next().await {} });
+        }
+        res
+    }
+
+    /// Return one stream of the events from all the `dvals`, tagged
+    /// with the `SubId` of the `Dval` they came from. Events from
+    /// different `Dval`s are delivered in the order they arrived, and
+    /// all the `Dval`s share one channel, so this is cheaper than
+    /// merging streams from `Dval::stream`.
+    pub fn merged_stream<'a>(
+        &self,
+        flags: UpdatesFlags,
+        dvals: impl IntoIterator<Item = &'a Dval>,
+    ) -> impl Stream<Item = (SubId, Event)> + Unpin + Send + 'static {
+        let (tx, rx) = mpsc::channel(3);
+        for dv in dvals {
+            dv.updates(flags, tx.clone());
+        }
+        UpdatesStream::new(rx)
     }
 
     /// This will return when all pending operations are flushed out
@@ -1316,22 +3410,95 @@ impl Subscriber {
     /// pushback in the case you want to do a lot of writes, and you
     /// need pushback in case a publisher is slow to process them,
     /// however it applies to durable_subscribe and unsubscribe as well.
-    pub async fn flush(&self) {
-        let flushes = {
+    ///
+    /// All the connections are flushed concurrently, and the report
+    /// says which ones flushed and which ones closed first. Use
+    /// `flush_timeout` to avoid waiting forever for a stuck publisher.
+    pub async fn flush(&self) -> FlushReport {
+        let cons = self.0.lock().all_connections();
+        flush_connections(cons, None).await
+    }
+
+    /// Same as `flush`, except that connections that haven't flushed
+    /// after `timeout` are given up on and listed as timed out.
+    pub async fn flush_timeout(&self, timeout: Duration) -> FlushReport {
+        let cons = self.0.lock().all_connections();
+        flush_connections(cons, Some(timeout)).await
+    }
+
+    /// Flush only the connection backing the subscription to `path`,
+    /// with an optional deadline. Fails if `path` is not subscribed.
+    pub async fn flush_path(
+        &self,
+        path: &Path,
+        timeout: Option<Duration>,
+    ) -> Result<FlushReport> {
+        let con = {
             let t = self.0.lock();
+            let val = match t.subscribed.get(path) {
+                Some(SubStatus::Subscribed(val)) => val.upgrade(),
+                Some(SubStatus::Pending(_)) | None => None,
+            };
+            let val = match val {
+                Some(val) => val,
+                None => bail!("{} is not subscribed", path),
+            };
+            let addr = t.connections.iter().find_map(|(addr, c)| {
+                c.iter_ids().any(|(id, _)| id == val.0.conid).then_some(*addr)
+            });
+            match addr {
+                Some(addr) => (addr, val.0.conid, val.0.connection.clone()),
+                None => bail!("the connection for {} is closed", path),
+            }
+        };
+        Ok(flush_connections(vec![con], timeout).await)
+    }
+
+    /// Unsubscribe from every path matching `pat`, and return the
+    /// number of paths unsubscribed. Durable subscriptions move to
+    /// `DvalState::Failed` and are not retried, pending subscription
+    /// attempts are canceled, and nondurable subscriptions are
+    /// unsubscribed even if references to them are still held,
+    /// their last value becomes `Event::Unsubscribed`, and their
+    /// streams receive `Event::Unsubscribed` as usual.
+    pub fn unsubscribe_matching(&self, pat: &GlobSet) -> usize {
+        self.0.lock().unsubscribe_matching(|p| pat.is_match(p))
+    }
+
+    /// Shut down the subscriber in an orderly way. Everything is
+    /// unsubscribed as if by `unsubscribe_matching`, all connections
+    /// are flushed and closed, and the resubscription task is
+    /// stopped. The returned future resolves when all the connection
+    /// tasks have exited. After shutdown new subscriptions fail, and
+    /// new durable subscriptions begin in `DvalState::Failed`.
+    pub async fn shutdown(&self) {
+        let closed = {
+            let mut t = self.0.lock();
+            t.shutdown = true;
+            t.unsubscribe_matching(|_| true);
+            t.trigger_resub.close_channel();
             t.connections
                 .values()
                 .flat_map(|c| {
                     c.iter().map(|c| {
                         let (tx, rx) = oneshot::channel();
-                        c.send(ToCon::Flush(tx));
+                        c.send(ToCon::Close(tx));
                         rx
                     })
                 })
                 .collect::<Vec<_>>()
         };
-        for flush in flushes {
-            let _ = flush.await;
-        }
+        future::join_all(closed).await;
+    }
+
+    /// Return a stream of connection level events, connects,
+    /// disconnects, handshake failures, and publishers that are slow
+    /// to accept writes. Events that happen before this is called
+    /// are not reported. If you don't want events anymore you can
+    /// just drop the stream.
+    pub fn connection_events(&self) -> impl Stream<Item = ConnEvent> {
+        let (tx, rx) = mpsc::unbounded();
+        self.0.lock().conn_events.push(tx);
+        rx
     }
 }
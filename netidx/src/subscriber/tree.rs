@@ -0,0 +1,248 @@
+use super::{Dval, Event, SubId, Subscriber, SubscriberWeak, UpdatesFlags};
+use crate::{
+    chars::Chars,
+    path::Path,
+    pool::{Pool, Pooled},
+    protocol::glob::{Glob, GlobSet},
+    resolver_client::ChangeTracker,
+};
+use anyhow::Result;
+use arcstr::ArcStr;
+use futures::{channel::mpsc, prelude::*, select_biased};
+use fxhash::FxHashMap;
+use log::warn;
+use parking_lot::Mutex;
+use std::{
+    collections::HashMap,
+    iter,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::time;
+
+lazy_static! {
+    static ref TREE_BATCHES: Pool<Vec<(Path, TreeEvent)>> =
+        Pool::named("subscriber::tree_batches", 64, 16384);
+}
+
+/// The default interval at which a `Tree` asks the resolver whether
+/// the structure of it's subtree has changed
+pub const DEFAULT_TREE_POLL: Duration = Duration::from_secs(1);
+
+/// Something happened to a path in a `Tree`. Paths are relative to
+/// the base of the tree, e.g. `foo/bar` for `/base/foo/bar`.
+#[derive(Debug, Clone, PartialEq)]
+pub enum TreeEvent {
+    /// a new path was discovered and subscribed
+    Added,
+    /// the path is no longer published, it's subscription was dropped
+    Removed,
+    /// the subscription to the path produced an event
+    Update(Event),
+}
+
+#[derive(Debug)]
+struct TreeInner {
+    base: Path,
+    by_path: FxHashMap<Path, Dval>,
+    by_id: FxHashMap<SubId, Path>,
+    updates: Vec<mpsc::Sender<Pooled<Vec<(Path, TreeEvent)>>>>,
+}
+
+/// A subscription to every path published under a base path,
+/// including paths that are published after the tree was
+/// created. The client side mirror of a published subtree. See
+/// `Subscriber::subscribe_tree`.
+///
+/// When all references to the `Tree` are dropped every subscription
+/// in it will be dropped.
+#[derive(Debug, Clone)]
+pub struct Tree(Arc<Mutex<TreeInner>>);
+
+impl Tree {
+    pub(super) fn new(subscriber: &Subscriber, base: Path, poll: Duration) -> Tree {
+        let t = Tree(Arc::new(Mutex::new(TreeInner {
+            base: base.clone(),
+            by_path: HashMap::default(),
+            by_id: HashMap::default(),
+            updates: Vec::new(),
+        })));
+        let tree = Arc::downgrade(&t.0);
+        let rt = subscriber.0.lock().rt.clone();
+        let subscriber = subscriber.downgrade();
+        rt.spawn(async move {
+            if let Err(e) = run(subscriber, tree, base.clone(), poll).await {
+                warn!("tree {} maintenance task stopped {}", base, e)
+            }
+        });
+        t
+    }
+
+    /// the base path of the tree
+    pub fn base(&self) -> Path {
+        self.0.lock().base.clone()
+    }
+
+    /// Get the subscription to `path`, relative to the base of the
+    /// tree, if it has been discovered.
+    pub fn get(&self, path: &str) -> Option<Dval> {
+        let path = path.trim_start_matches('/');
+        self.0.lock().by_path.get(path).cloned()
+    }
+
+    /// the relative paths of all the subscriptions in the tree
+    pub fn paths(&self) -> Vec<Path> {
+        self.0.lock().by_path.keys().cloned().collect()
+    }
+
+    /// Register `tx` to receive events for every path in the
+    /// tree. When `tx` is registered it will immediately receive an
+    /// `Added` event for every path already in the tree, followed by
+    /// the current value of each subscribed path, as if they had just
+    /// been discovered. Events for different paths are delivered to
+    /// the same channel in batches, keyed by the relative path.
+    pub fn updates(&self, mut tx: mpsc::Sender<Pooled<Vec<(Path, TreeEvent)>>>) {
+        let mut t = self.0.lock();
+        let mut batch = TREE_BATCHES.take();
+        for (path, dv) in t.by_path.iter() {
+            batch.push((path.clone(), TreeEvent::Added));
+            match dv.last() {
+                Event::Unsubscribed(_) => (),
+                e @ Event::Update(_) => batch.push((path.clone(), TreeEvent::Update(e))),
+            }
+        }
+        if batch.is_empty() {
+            t.updates.push(tx)
+        } else {
+            match tx.try_send(batch) {
+                Err(e) if e.is_disconnected() => (),
+                Ok(()) | Err(_) => t.updates.push(tx),
+            }
+        }
+    }
+}
+
+impl TreeInner {
+    async fn send(t: &Mutex<Self>, batch: Pooled<Vec<(Path, TreeEvent)>>) {
+        if batch.is_empty() {
+            return;
+        }
+        let mut chans = t.lock().updates.clone();
+        let mut failed = false;
+        for c in chans.iter_mut() {
+            let mut b = TREE_BATCHES.take();
+            b.extend(batch.iter().cloned());
+            failed |= c.send(b).await.is_err();
+        }
+        if failed {
+            t.lock().updates.retain(|c| !c.is_closed());
+        }
+    }
+}
+
+struct TreeSync {
+    base: Path,
+    globs: GlobSet,
+    ct: ChangeTracker,
+    tx_up: mpsc::Sender<Pooled<Vec<(SubId, Event)>>>,
+}
+
+impl TreeSync {
+    fn relative(&self, path: &Path) -> Option<Path> {
+        if !Path::is_parent(&*self.base, &**path) {
+            return None;
+        }
+        let r = path.strip_prefix(&*self.base)?.trim_start_matches('/');
+        (!r.is_empty()).then(|| Path::from(ArcStr::from(r)))
+    }
+
+    // reconcile the tree with the resolver if the subtree changed
+    async fn sync(
+        &mut self,
+        subscriber: &Subscriber,
+        tree: &Mutex<TreeInner>,
+    ) -> Result<()> {
+        let resolver = subscriber.resolver();
+        if !resolver.check_changed(&mut self.ct).await? {
+            return Ok(());
+        }
+        let mut current = HashMap::new();
+        for batch in resolver.list_matching(&self.globs).await?.iter() {
+            for p in batch.iter() {
+                if let Some(r) = self.relative(p) {
+                    current.insert(r, p.clone());
+                }
+            }
+        }
+        let mut batch = TREE_BATCHES.take();
+        {
+            let mut t = tree.lock();
+            let removed = t
+                .by_path
+                .keys()
+                .filter(|p| !current.contains_key(*p))
+                .cloned()
+                .collect::<Vec<_>>();
+            for r in removed {
+                if let Some(dv) = t.by_path.remove(&r) {
+                    t.by_id.remove(&dv.id());
+                }
+                batch.push((r, TreeEvent::Removed));
+            }
+            for (r, p) in current {
+                if !t.by_path.contains_key(&r) {
+                    let dv = subscriber.subscribe(p);
+                    t.by_id.insert(dv.id(), r.clone());
+                    t.by_path.insert(r.clone(), dv.clone());
+                    batch.push((r, TreeEvent::Added));
+                    dv.updates(UpdatesFlags::BEGIN_WITH_LAST, self.tx_up.clone());
+                }
+            }
+        }
+        TreeInner::send(tree, batch).await;
+        Ok(())
+    }
+}
+
+async fn run(
+    subscriber: SubscriberWeak,
+    tree: Weak<Mutex<TreeInner>>,
+    base: Path,
+    poll: Duration,
+) -> Result<()> {
+    let glob = Glob::new(Chars::from(String::from(&*base.append("**"))))?;
+    let globs = GlobSet::new(true, iter::once(glob))?;
+    let ct = ChangeTracker::new(base.clone());
+    let (tx_up, mut rx_up) = mpsc::channel(3);
+    let mut sync = TreeSync { base, globs, ct, tx_up };
+    let mut poll = time::interval(poll);
+    loop {
+        select_biased! {
+            _ = poll.tick().fuse() => {
+                let (subscriber, tree) = match (subscriber.upgrade(), tree.upgrade()) {
+                    (Some(s), Some(t)) => (s, t),
+                    (_, _) => break Ok(()),
+                };
+                if let Err(e) = sync.sync(&subscriber, &tree).await {
+                    warn!("failed to sync tree {}, will retry {}", sync.base, e)
+                }
+            },
+            mut up = rx_up.select_next_some() => {
+                let tree = match tree.upgrade() {
+                    Some(t) => t,
+                    None => break Ok(()),
+                };
+                let mut batch = TREE_BATCHES.take();
+                {
+                    let t = tree.lock();
+                    for (id, ev) in up.drain(..) {
+                        if let Some(r) = t.by_id.get(&id) {
+                            batch.push((r.clone(), TreeEvent::Update(ev)));
+                        }
+                    }
+                }
+                TreeInner::send(&tree, batch).await
+            },
+        }
+    }
+}
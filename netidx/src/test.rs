@@ -1,14 +1,58 @@
+// the setup most tests share, a resolver server on a free port and a
+// publisher using it
+mod fixture {
+    use crate::{
+        config::Config as ClientConfig,
+        publisher::{DesiredAuth, Publisher},
+        resolver_server::{config::Config as ServerConfig, Server},
+    };
+
+    pub(super) fn server_config() -> ServerConfig {
+        ServerConfig::load("../cfg/simple-server.json")
+            .expect("load simple server config")
+    }
+
+    /// Start a resolver server from `server_cfg`, and return it along
+    /// with a client config that points at it
+    pub(super) async fn start_resolver_with(
+        server_cfg: ServerConfig,
+    ) -> (Server, ClientConfig) {
+        let mut cfg = ClientConfig::load("../cfg/simple-client.json")
+            .expect("load simple client config");
+        let server = Server::new(server_cfg, false, 0).await.expect("start server");
+        cfg.addrs[0].0 = *server.local_addr();
+        (server, cfg)
+    }
+
+    pub(super) async fn start_resolver() -> (Server, ClientConfig) {
+        start_resolver_with(server_config()).await
+    }
+
+    pub(super) async fn start_publisher(cfg: &ClientConfig) -> Publisher {
+        Publisher::new(
+            cfg.clone(),
+            DesiredAuth::Anonymous,
+            "127.0.0.1/32".parse().unwrap(),
+            768,
+        )
+        .await
+        .unwrap()
+    }
+}
+
 mod resolver {
+    use super::fixture::{server_config, start_resolver, start_resolver_with};
     use crate::{
         chars::Chars,
         config::Config as ClientConfig,
         path::Path,
+        pool::Pooled,
         protocol::glob::{Glob, GlobSet},
         publisher::PublishFlags,
         resolver_client::{ChangeTracker, DesiredAuth, ResolverRead, ResolverWrite},
         resolver_server::{config::Config as ServerConfig, Server},
     };
-    use netidx_netproto::resolver::TargetAuth;
+    use netidx_netproto::resolver::{Auth, Referral, TargetAuth};
     use rand::{thread_rng, Rng};
     use std::{iter, net::SocketAddr, time::Duration};
     use tokio::{runtime::Runtime, time};
@@ -52,6 +96,153 @@ mod resolver {
         });
     }
 
+    #[test]
+    fn server_metrics() {
+        use tokio::{
+            io::{AsyncReadExt, AsyncWriteExt},
+            net::TcpStream,
+        };
+        Runtime::new().unwrap().block_on(async {
+            let mut server_cfg = server_config();
+            server_cfg.member_servers[0].metrics_addr =
+                Some("127.0.0.1:0".parse().unwrap());
+            let (server, client_cfg) = start_resolver_with(server_cfg).await;
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            let paths = vec![p("/foo/bar"), p("/foo/baz")];
+            w.publish(paths.iter().cloned()).await.unwrap();
+            r.resolve(paths.clone()).await.unwrap();
+            r.list(p("/foo")).await.unwrap();
+            let mut con =
+                TcpStream::connect(server.metrics_addr().unwrap()).await.unwrap();
+            con.write_all(b"GET /metrics HTTP/1.1\r\nHost: localhost\r\n\r\n")
+                .await
+                .unwrap();
+            let mut rsp = String::new();
+            con.read_to_string(&mut rsp).await.unwrap();
+            assert!(rsp.starts_with("HTTP/1.1 200 OK"));
+            let lines = rsp.lines().collect::<Vec<_>>();
+            for l in [
+                "netidx_resolver_clients 2",
+                "netidx_resolver_paths 2",
+                "netidx_resolver_publishes_total 2",
+                "netidx_resolver_resolves_total 2",
+                "netidx_resolver_lists_total 1",
+                "netidx_resolver_auth_failures_total{mechanism=\"anonymous\"} 0",
+                "netidx_resolver_auth_seconds_count{mechanism=\"anonymous\"} 2",
+            ] {
+                assert!(lines.contains(&l), "missing {}", l);
+            }
+            drop(server)
+        });
+    }
+
+    #[test]
+    fn server_audit() {
+        use crate::resolver_server::config::AuditLog;
+        use std::fs;
+        Runtime::new().unwrap().block_on(async {
+            let file = "resolver-audit-test.jsonl";
+            let _ = fs::remove_file(file);
+            let mut server_cfg = server_config();
+            server_cfg.member_servers[0].audit =
+                Some(AuditLog::JsonLines(file.to_string()));
+            let (server, client_cfg) = start_resolver_with(server_cfg).await;
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            w.publish(iter::once(p("/foo/bar"))).await.unwrap();
+            r.resolve(iter::once(p("/foo/bar"))).await.unwrap();
+            drop(server);
+            let log = fs::read_to_string(file).unwrap();
+            let events = log
+                .lines()
+                .map(|l| serde_json::from_str::<serde_json::Value>(l).unwrap())
+                .collect::<Vec<_>>();
+            assert_eq!(events.len(), 2);
+            for ev in events {
+                assert_eq!(ev["kind"], "auth_success");
+                assert_eq!(ev["mechanism"], "anonymous");
+                assert_eq!(ev["user"], serde_json::Value::Null);
+                assert!(ev["addr"].as_str().unwrap().starts_with("127.0.0.1:"));
+            }
+            let _ = fs::remove_file(file);
+        });
+    }
+
+    #[test]
+    fn server_health() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, mut client_cfg) = start_resolver().await;
+            let live = *server.local_addr();
+            let dead = {
+                let l = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+                l.local_addr().unwrap()
+            };
+            client_cfg.addrs = vec![(dead, Auth::Anonymous), (live, Auth::Anonymous)];
+            // the order servers are first tried in is random
+            for _ in 0..32 {
+                let r = ResolverRead::new(client_cfg.clone(), DesiredAuth::Anonymous);
+                r.resolve(iter::once(p("/foo"))).await.unwrap();
+                let health = r.server_health();
+                if health.len() < 2 {
+                    assert_eq!(health[0].addr, live);
+                    continue;
+                }
+                assert_eq!(health[0].addr.min(health[1].addr), health[0].addr);
+                let (d, l) = if health[0].addr == dead {
+                    (&health[0], &health[1])
+                } else {
+                    (&health[1], &health[0])
+                };
+                assert!(d.failing() && !d.connected);
+                assert_eq!((d.requests, d.errors, d.consecutive_errors), (0, 1, 1));
+                assert!(!l.failing() && l.connected);
+                assert_eq!((l.requests, l.errors), (1, 0));
+                assert!(l.latency.is_some());
+                drop(server);
+                return;
+            }
+            panic!("the dead server was never tried")
+        });
+    }
+
+    #[test]
+    fn graceful_shutdown() {
+        Runtime::new().unwrap().block_on(async {
+            let (s0, mut client_cfg) = start_resolver().await;
+            let (s1, _) = start_resolver().await;
+            let mut servers = vec![s0, s1];
+            client_cfg.addrs =
+                servers.iter().map(|s| (*s.local_addr(), Auth::Anonymous)).collect();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(client_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                .unwrap();
+            w.publish(iter::once(p("/foo"))).await.unwrap();
+            let r = ResolverRead::new(client_cfg, DesiredAuth::Anonymous);
+            let (_, resolved) = r.resolve(iter::once(p("/foo"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            let health = r.server_health();
+            let used = health.iter().find(|h| h.connected).unwrap().addr;
+            let i = servers.iter().position(|s| *s.local_addr() == used).unwrap();
+            let to = Duration::from_secs(10);
+            time::timeout(to, servers.remove(i).shutdown(to)).await.unwrap().unwrap();
+            // the reader moves to the other server without backing off
+            let res = r.resolve(iter::once(p("/foo")));
+            let (_, resolved) =
+                time::timeout(Duration::from_secs(1), res).await.unwrap().unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            let health = r.server_health();
+            let cur = health.iter().find(|h| h.connected).unwrap();
+            assert_eq!(cur.addr, *servers[0].local_addr());
+            assert!(health.iter().find(|h| h.addr == used).unwrap().failing());
+            drop(servers)
+        });
+    }
+
     #[test]
     fn publish_default() {
         Runtime::new().unwrap().block_on(async {
@@ -86,6 +277,131 @@ mod resolver {
         });
     }
 
+    #[test]
+    fn delegate_at_runtime() {
+        Runtime::new().unwrap().block_on(async {
+            let (root, root_cfg) = start_resolver().await;
+            let child_cfg = ServerConfig::parse(&format!(
+                r#"{{
+  "parent": {{ "path": "/app", "ttl": 1, "addrs": [["{}", "Anonymous"]] }},
+  "children": [],
+  "member_servers": [
+    {{
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": "Anonymous"
+    }}
+  ],
+  "perms": {{}}
+}}"#,
+                root.local_addr()
+            ))
+            .expect("parse child config");
+            let child = Server::new(child_cfg, false, 0).await.expect("start child");
+            let mut cfg = root_cfg.clone();
+            cfg.base = p("/app");
+            cfg.addrs[0].0 = *child.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg, DesiredAuth::Anonymous, paddr).unwrap();
+            w.publish(iter::once(p("/app/v0"))).await.unwrap();
+            let admin =
+                ResolverWrite::new(root_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                    .unwrap();
+            let r = ResolverRead::new(root_cfg.clone(), DesiredAuth::Anonymous);
+            let (_, resolved) = r.resolve(iter::once(p("/app/v0"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 0);
+            let referral = |ttl| Referral {
+                path: p("/app"),
+                ttl,
+                addrs: Pooled::orphan(vec![(*child.local_addr(), Auth::Anonymous)]),
+            };
+            assert!(admin.delegate(iter::once(referral(None))).await.is_err());
+            admin.delegate(iter::once(referral(Some(1)))).await.unwrap();
+            let (_, resolved) = r.resolve(iter::once(p("/app/v0"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 1);
+            assert_eq!(&**r.list(p("/")).await.unwrap(), &[p("/app")]);
+            admin.undelegate(iter::once(p("/app"))).await.unwrap();
+            assert!(admin.undelegate(iter::once(p("/app"))).await.is_err());
+            // r will keep using the delegation until it's ttl expires
+            time::sleep(Duration::from_millis(1100)).await;
+            let (_, resolved) = r.resolve(iter::once(p("/app/v0"))).await.unwrap();
+            assert_eq!(resolved[0].publishers.len(), 0);
+            assert_eq!(&**r.list(p("/")).await.unwrap(), &[]);
+            drop(child);
+            drop(root);
+        });
+    }
+
+    #[test]
+    fn resolve_trace() {
+        Runtime::new().unwrap().block_on(async {
+            let (root, root_cfg) = start_resolver().await;
+            let child_cfg = ServerConfig::parse(&format!(
+                r#"{{
+  "parent": {{ "path": "/app", "ttl": 60, "addrs": [["{}", "Anonymous"]] }},
+  "children": [],
+  "member_servers": [
+    {{
+      "pid_file": "",
+      "addr": "127.0.0.1:0",
+      "max_connections": 768,
+      "hello_timeout": 10,
+      "reader_ttl": 60,
+      "writer_ttl": 120,
+      "auth": "Anonymous"
+    }}
+  ],
+  "perms": {{}}
+}}"#,
+                root.local_addr()
+            ))
+            .expect("parse child config");
+            let child = Server::new(child_cfg, false, 0).await.expect("start child");
+            let mut cfg = root_cfg.clone();
+            cfg.base = p("/app");
+            cfg.addrs[0].0 = *child.local_addr();
+            let paddr: SocketAddr = "127.0.0.1:1".parse().unwrap();
+            let w = ResolverWrite::new(cfg, DesiredAuth::Anonymous, paddr).unwrap();
+            w.publish(iter::once(p("/app/v0"))).await.unwrap();
+            let admin =
+                ResolverWrite::new(root_cfg.clone(), DesiredAuth::Anonymous, paddr)
+                    .unwrap();
+            let referral = Referral {
+                path: p("/app"),
+                ttl: Some(60),
+                addrs: Pooled::orphan(vec![(*child.local_addr(), Auth::Anonymous)]),
+            };
+            admin.delegate(iter::once(referral)).await.unwrap();
+            let r = ResolverRead::new(root_cfg.clone(), DesiredAuth::Anonymous);
+            let to = Duration::from_secs(5);
+            let trace = r.resolve_with_trace(p("/app/v0"), to).await;
+            let hops =
+                trace.hops.iter().map(|h| h.referral.path.clone()).collect::<Vec<_>>();
+            assert_eq!(hops, vec![p("/"), p("/app")]);
+            assert!(trace.hops.iter().all(|h| h.elapsed.is_some()));
+            assert_eq!(trace.result.unwrap().publishers.len(), 1);
+            // the second time the cached referral is used directly
+            let trace = r.resolve_with_trace(p("/app/v0"), to).await;
+            assert_eq!(trace.hops.len(), 1);
+            assert_eq!(trace.hops[0].referral.path, p("/app"));
+            assert_eq!(trace.result.unwrap().publishers.len(), 1);
+            let cached = r.referrals();
+            assert_eq!(cached.len(), 2);
+            assert_eq!(cached[0].referral.path, p("/"));
+            assert_eq!(cached[0].ttl_remaining, None);
+            assert_eq!(cached[0].hits, 1);
+            assert_eq!(cached[1].referral.path, p("/app"));
+            assert!(cached[1].ttl_remaining.unwrap() <= Duration::from_secs(60));
+            assert_eq!(cached[1].hits, 2);
+            drop(child);
+            drop(root);
+        });
+    }
+
     struct Ctx {
         _local: Server,
         _root: (Server, Server),
@@ -370,20 +686,36 @@ mod resolver {
 }
 
 mod publisher {
+    use super::fixture::{start_publisher, start_resolver};
     use crate::{
         config::Config as ClientConfig,
+        pack::{DecodeLimits, Pack},
+        path::Path,
+        pool::Pooled,
+        protocol::glob::{Glob, GlobSet},
         publisher::{
-            BindCfg, DesiredAuth, Event as PEvent, PublishFlags, Publisher, Val,
+            BindCfg, Cidr, DesiredAuth, ErrorInfo, Event as PEvent, Patch, PublishFlags,
+            Publisher, PublisherBuilder, Schema, TenantPublisher, Val,
         },
+        resolver_client::ResolverWrite,
         resolver_server::{config::Config as ServerConfig, Server},
-        subscriber::{Event, Subscriber, UpdatesFlags, Value},
+        subscriber::{
+            Audit, Canceled, ConnEvent, DeadLetter, DvalState, Event, GiveUp, GroupEvent,
+            PreferFamily, Priority, Sample, SubId, SubStats, Subscriber,
+            SubscriberBuilder, TreeEvent, Typ, UnsubscribeReason, UpdatesFlags,
+            ValPermissions, Value, WriteOutcome, WriteRetry,
+        },
     };
     use futures::{channel::mpsc, channel::oneshot, prelude::*, select_biased};
     use parking_lot::Mutex;
     use std::{
+        collections::HashMap,
         iter,
         net::{IpAddr, SocketAddr},
-        sync::Arc,
+        sync::{
+            atomic::{AtomicU64, Ordering},
+            Arc,
+        },
         time::Duration,
     };
     use tokio::{runtime::Runtime, task, time};
@@ -407,6 +739,24 @@ mod publisher {
         assert!("ffff:1c00:2700:3c00::".parse::<BindCfg>().is_err());
     }
 
+    #[test]
+    fn cidr() {
+        let net: Cidr = "10.0.0.0/8".parse().unwrap();
+        assert!(net.contains("10.255.0.1".parse().unwrap()));
+        assert!(!net.contains("11.0.0.1".parse().unwrap()));
+        assert!(!net.contains("::1".parse().unwrap()));
+        let host: Cidr = "192.168.0.1".parse().unwrap();
+        assert!(host.contains("192.168.0.1".parse().unwrap()));
+        assert!(!host.contains("192.168.0.2".parse().unwrap()));
+        let all: Cidr = "0.0.0.0/0".parse().unwrap();
+        assert!(all.contains("8.8.8.8".parse().unwrap()));
+        let net: Cidr = "ffff:1c00:2700:3c00::/64".parse().unwrap();
+        assert!(net.contains("ffff:1c00:2700:3c00::1".parse().unwrap()));
+        assert!(!net.contains("ffff:1c00:2700:3c01::1".parse().unwrap()));
+        assert!("10.0.0.0/33".parse::<Cidr>().is_err());
+        assert!("10.0.0.0/8/foo".parse::<Cidr>().is_err());
+    }
+
     async fn run_publisher(
         cfg: ClientConfig,
         default_destroyed: Arc<Mutex<bool>>,
@@ -550,34 +900,2460 @@ mod publisher {
     }
 
     #[test]
-    fn publish_subscribe_tls() {
+    fn publish_alias() {
         let rt = Runtime::new().unwrap();
         rt.block_on(async {
-            let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver.json")
-                .expect("load tls server config");
-            let mut pub_cfg = ClientConfig::load("../cfg/tls/publisher/client.json")
-                .expect("failed to load tls publisher config");
-            let mut sub_cfg = ClientConfig::load("../cfg/tls/client/client.json")
-                .expect("failed to load subscriber cfg");
-            let default_destroyed = Arc::new(Mutex::new(false));
-            let (tx, ready) = oneshot::channel();
-            let server = Server::new(server_cfg, false, 0).await.expect("start server");
-            pub_cfg.addrs[0].0 = *server.local_addr();
-            sub_cfg.addrs[0].0 = *server.local_addr();
-            task::spawn(run_publisher(
-                pub_cfg.clone(),
-                default_destroyed.clone(),
-                tx,
-                DesiredAuth::Tls { identity: None },
-            ));
-            time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
-            run_subscriber(
-                pub_cfg,
-                default_destroyed,
-                DesiredAuth::Tls { identity: None },
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let paths = ["/by-id/42", "/by-name/foo", "/by-name/bar"];
+            let vp = publisher.publish(paths[0].into(), Value::U64(42)).unwrap();
+            publisher.alias(vp.id(), paths[1].into()).unwrap();
+            publisher.alias(vp.id(), paths[2].into()).unwrap();
+            assert!(publisher.alias(vp.id(), paths[0].into()).is_err());
+            publisher.flushed().await;
+            let resolved = || async {
+                let paths = paths.iter().map(|p| Path::from(*p));
+                let (_, res) = subscriber.resolver().resolve(paths).await.unwrap();
+                res.iter().map(|r| r.publishers.len()).collect::<Vec<_>>()
+            };
+            assert_eq!(resolved().await, vec![1, 1, 1]);
+            let v0 = subscriber.subscribe_nondurable_one(paths[0].into(), None);
+            let v1 = subscriber.subscribe_nondurable_one(paths[1].into(), None);
+            let (v0, v1) = (v0.await.unwrap(), v1.await.unwrap());
+            assert_eq!(v1.last(), Event::Update(Value::U64(42)));
+            let (tx, mut rx) = mpsc::channel(10);
+            v1.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            let mut batch = publisher.start_batch();
+            vp.update(&mut batch, Value::U64(43));
+            batch.commit(None).await;
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::U64(43)));
+            assert_eq!(v0.last(), Event::Update(Value::U64(43)));
+            publisher.remove_alias(vp.id(), &paths[2].into());
+            publisher.flushed().await;
+            assert_eq!(resolved().await, vec![1, 1, 0]);
+            // destroying the primary cleans up every alias
+            drop(vp);
+            publisher.flushed().await;
+            assert_eq!(resolved().await, vec![0, 0, 0]);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_interest() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let mut interest = publisher.interest(vp.id());
+            let to = Duration::from_secs(5);
+            assert_eq!(time::timeout(to, interest.next()).await.unwrap(), Some(0));
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(time::timeout(to, interest.next()).await.unwrap(), Some(1));
+            assert_eq!(publisher.subscriber_count(vp.id()), 1);
+            drop(vs);
+            assert_eq!(time::timeout(to, interest.next()).await.unwrap(), Some(0));
+            assert_eq!(publisher.subscriber_count(vp.id()), 0);
+            drop(vp);
+            assert_eq!(time::timeout(to, interest.next()).await.unwrap(), None);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_typed() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish_typed::<u64>("/app/v0".into(), 0).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            vp.writes(tx);
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(0)));
+            let to = Duration::from_secs(5);
+            let r = vs.write_with_recipt(Value::from("not a number"));
+            match time::timeout(to, r).await.unwrap().unwrap() {
+                Value::ErrorInfo(e) => assert_eq!(e.code, ErrorInfo::INVALID_ARGUMENT),
+                v => panic!("expected an error {}", v),
+            }
+            let r = vs.write_with_recipt(Value::I64(42));
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.len(), 1);
+            let req = batch.pop().unwrap();
+            assert_eq!(req.value, 42u64);
+            req.send_result.unwrap().send(Value::Ok);
+            assert_eq!(time::timeout(to, r).await.unwrap().unwrap(), Value::Ok);
+            let r = vs.write_with_recipt(Value::I64(7));
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let pending = WriteOutcome::Pending { token: 1 };
+            batch.pop().unwrap().send_result.unwrap().send_outcome(pending.clone());
+            let reply = time::timeout(to, r).await.unwrap().unwrap();
+            assert_eq!(WriteOutcome::from(reply), pending);
+            let (tx, mut rx) = mpsc::channel(10);
+            vs.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            let mut batch = publisher.start_batch();
+            vp.update(&mut batch, req.value + 1);
+            batch.commit(None).await;
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::U64(43)));
+            assert_eq!(vp.current(), Some(43));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_manifest() {
+        use crate::publisher::Manifest;
+        let bad = r#"{"publications": [{"path": "app", "value": "1"}]}"#;
+        assert!(Manifest::parse(bad).is_err());
+        let bad = r#"{"publications": [{"path": "/a", "value": "1", "deadband": -1}]}"#;
+        assert!(Manifest::parse(bad).is_err());
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let file = std::env::temp_dir()
+                .join(format!("netidx-manifest-{}.json", std::process::id()));
+            std::fs::write(
+                &file,
+                r#"{"publications": [
+                    {"path": "/app/setpoint", "value": "1.5", "writable": true,
+                     "deadband": 0.5, "doc": "the setpoint"},
+                    {"path": "/app/name", "value": "\"boiler\""}
+                ]}"#,
             )
-            .await;
-            drop(server)
-        })
+            .unwrap();
+            let publisher = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg("127.0.0.1/32".parse().unwrap())
+                .manifest(file.clone())
+                .manifest_reload(Duration::from_millis(100))
+                .build()
+                .await
+                .unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(5);
+            let sp = subscriber
+                .subscribe_nondurable_one("/app/setpoint".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(sp.last(), Event::Update(Value::F64(1.5)));
+            let name = subscriber
+                .subscribe_nondurable_one("/app/name".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(name.last(), Event::Update(Value::from("boiler")));
+            let schema = subscriber.schema(&"/app/setpoint".into());
+            let schema = time::timeout(to, schema).await.unwrap().unwrap();
+            assert_eq!(schema, Some(Schema::default().doc("the setpoint")));
+            let r = name.write_with_recipt(Value::from("kettle"));
+            match time::timeout(to, r).await.unwrap().unwrap() {
+                Value::ErrorInfo(e) => {
+                    assert_eq!(e.code, ErrorInfo::WRITES_NOT_ACCEPTED)
+                }
+                v => panic!("expected an error {}", v),
+            }
+            let (tx, mut rx) = mpsc::channel(10);
+            sp.updates(UpdatesFlags::empty(), tx);
+            // within the deadband, accepted but not published
+            let r = sp.write_with_recipt(Value::F64(1.8));
+            assert_eq!(time::timeout(to, r).await.unwrap().unwrap(), Value::Ok);
+            let r = sp.write_with_recipt(Value::F64(3.));
+            assert_eq!(time::timeout(to, r).await.unwrap().unwrap(), Value::Ok);
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::F64(3.)));
+            std::fs::write(
+                &file,
+                r#"{"publications": [
+                    {"path": "/app/setpoint", "value": "5.5", "writable": true},
+                    {"path": "/app/mode", "value": "\"auto\""}
+                ]}"#,
+            )
+            .unwrap();
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::F64(5.5)));
+            publisher.flushed().await;
+            let mode = subscriber
+                .subscribe_nondurable_one("/app/mode".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(mode.last(), Event::Update(Value::from("auto")));
+            let r = subscriber
+                .subscribe_nondurable_one(
+                    "/app/name".into(),
+                    Some(Duration::from_secs(1)),
+                )
+                .await;
+            assert!(r.is_err());
+            let schema = subscriber.schema(&"/app/setpoint".into());
+            assert_eq!(time::timeout(to, schema).await.unwrap().unwrap(), None);
+            let _ = std::fs::remove_file(&file);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_schema() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let schema = Schema::default()
+                .doc("the position of the widget")
+                .field("x", Schema::new(Typ::F64).unit("m").range(-1., 1.))
+                .field("y", Schema::new(Typ::F64).unit("m"));
+            let _v0 = publisher.publish("/app/v0".into(), Value::from((0., 0.))).unwrap();
+            let _s0 = publisher.publish_schema(&"/app/v0".into(), &schema).unwrap();
+            let _v1 = publisher.publish("/app/v1".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(5);
+            let dv0 = subscriber.subscribe("/app/v0".into());
+            let dv1 = subscriber.subscribe("/app/v1".into());
+            let s0 = time::timeout(to, dv0.schema()).await.unwrap().unwrap();
+            assert_eq!(s0, Some(schema));
+            let s1 = time::timeout(to, dv1.schema()).await.unwrap().unwrap();
+            assert_eq!(s1, None);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_stream() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp0 = publisher.publish("/app/v0".into(), 0u64).unwrap();
+            let vp1 = publisher.publish("/app/v1".into(), 100u64).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let dv0 = subscriber.subscribe("/app/v0".into());
+            let dv1 = subscriber.subscribe("/app/v1".into());
+            let mut s0 = dv0.stream(UpdatesFlags::BEGIN_WITH_LAST);
+            let mut merged =
+                subscriber.merged_stream(UpdatesFlags::BEGIN_WITH_LAST, [&dv0, &dv1]);
+            let to = Duration::from_secs(5);
+            assert_eq!(
+                time::timeout(to, s0.next()).await.unwrap(),
+                Some(Event::Update(Value::U64(0)))
+            );
+            let mut last = HashMap::new();
+            while last.len() < 2 {
+                let (id, ev) = time::timeout(to, merged.next()).await.unwrap().unwrap();
+                last.insert(id, ev);
+            }
+            assert_eq!(last[&dv1.id()], Event::Update(Value::U64(100)));
+            let mut batch = publisher.start_batch();
+            for i in 1..10u64 {
+                vp0.update(&mut batch, i);
+            }
+            vp1.update(&mut batch, 101u64);
+            batch.commit(None).await;
+            for i in 1..10u64 {
+                let ev = time::timeout(to, s0.next()).await.unwrap();
+                assert_eq!(ev, Some(Event::Update(Value::U64(i))));
+            }
+            let mut n = 0;
+            while n < 10 {
+                let (id, ev) = time::timeout(to, merged.next()).await.unwrap().unwrap();
+                if id == dv1.id() {
+                    assert_eq!(ev, Event::Update(Value::U64(101)));
+                }
+                n += 1;
+            }
+            drop(dv0);
+            let ev = time::timeout(to, s0.next()).await.unwrap();
+            assert_eq!(ev, Some(Event::Unsubscribed(UnsubscribeReason::Unspecified)));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_rebind() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let _dh = publisher.publish_default("/app/default".into()).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let resolved = |path: &'static str| {
+                let resolver = subscriber.resolver();
+                async move {
+                    let path = Path::from(path);
+                    let (pbs, res) = resolver.resolve(iter::once(path)).await.unwrap();
+                    res[0].publishers.iter().map(|p| pbs[&p.id].addr).collect::<Vec<_>>()
+                }
+            };
+            let old = publisher.addr();
+            assert_eq!(resolved("/app/v0").await, vec![old]);
+            let dv = subscriber.subscribe("/app/v0".into());
+            let to = Duration::from_secs(30);
+            time::timeout(to, dv.wait_subscribed()).await.unwrap().unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            dv.updates(UpdatesFlags::empty(), tx);
+            let new = publisher.rebind().await.unwrap();
+            assert_ne!(old, new);
+            assert_eq!(publisher.addr(), new);
+            assert_eq!(resolved("/app/v0").await, vec![new]);
+            assert_eq!(resolved("/app/default/x").await, vec![new]);
+            // the subscription is dropped, and then moves to the new address
+            let mut events = vec![];
+            while events.last() != Some(&Event::Update(Value::U64(0))) {
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                events.extend(batch.drain(..).map(|(_, e)| e));
+            }
+            assert!(events.contains(&Event::Unsubscribed(UnsubscribeReason::Replaced)));
+            let mut batch = publisher.start_batch();
+            vp.update(&mut batch, Value::U64(1));
+            batch.commit(None).await;
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::U64(1)));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn unsubscribe_reasons() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let v1 = publisher.publish("/app/v1".into(), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(30);
+            let s0 = subscriber.subscribe_nondurable_one("/app/v0".into(), None);
+            let s0 = time::timeout(to, s0).await.unwrap().unwrap();
+            let s1 = subscriber.subscribe_nondurable_one("/app/v1".into(), None);
+            let s1 = time::timeout(to, s1).await.unwrap().unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            s0.updates(UpdatesFlags::empty(), tx.clone());
+            s1.updates(UpdatesFlags::empty(), tx);
+            // shedding a subscriber
+            let mut batch = publisher.start_batch();
+            for cl in publisher.subscribed(&v0.id()) {
+                v0.unsubscribe_with_reason(&mut batch, cl, UnsubscribeReason::Overloaded);
+            }
+            batch.commit(None).await;
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let (id, ev) = batch.pop().unwrap();
+            assert_eq!(id, s0.id());
+            assert_eq!(ev, Event::Unsubscribed(UnsubscribeReason::Overloaded));
+            assert_eq!(s0.last(), Event::Unsubscribed(UnsubscribeReason::Overloaded));
+            // unpublishing
+            drop(v1);
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let (id, ev) = batch.pop().unwrap();
+            assert_eq!(id, s1.id());
+            assert_eq!(ev, Event::Unsubscribed(UnsubscribeReason::Unpublished));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn unpublish_subtree() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let (tx_ev, mut rx_ev) = mpsc::unbounded();
+            publisher.events(tx_ev);
+            let vals = (0..10u64)
+                .map(|i| {
+                    let path = Path::from(format!("/app/a/{}", i));
+                    publisher.publish(path, Value::U64(i)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            let b = publisher.publish("/app/b".into(), Value::U64(42)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(30);
+            let s0 = subscriber.subscribe_nondurable_one("/app/a/0".into(), None);
+            let s0 = time::timeout(to, s0).await.unwrap().unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            s0.updates(UpdatesFlags::empty(), tx);
+            assert_eq!(publisher.unpublish_subtree(&Path::from("/app/a")), 10);
+            assert_eq!(publisher.unpublish_subtree(&Path::from("/app/a")), 0);
+            let mut destroyed = Vec::new();
+            while destroyed.len() < 10 {
+                match time::timeout(to, rx_ev.next()).await.unwrap().unwrap() {
+                    PEvent::Destroyed(id) => destroyed.push(id),
+                    PEvent::Subscribe(_, _) | PEvent::Unsubscribe(_, _) => (),
+                }
+            }
+            for v in vals.iter() {
+                assert!(destroyed.contains(&v.id()))
+            }
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let (id, ev) = batch.pop().unwrap();
+            assert_eq!(id, s0.id());
+            assert_eq!(ev, Event::Unsubscribed(UnsubscribeReason::Unpublished));
+            // dropping the destroyed vals is harmless
+            drop(vals);
+            publisher.flushed().await;
+            let l = subscriber.resolver().list("/app".into()).await.unwrap();
+            assert_eq!(&*l, &[Path::from("/app/b")]);
+            let sb = subscriber.subscribe_nondurable_one("/app/b".into(), None);
+            let sb = time::timeout(to, sb).await.unwrap().unwrap();
+            assert_eq!(sb.last(), Event::Update(Value::U64(42)));
+            drop(b);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn on_subscribe_compute() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let v = publisher.publish("/app/lazy".into(), Value::Null).unwrap();
+            let n = Arc::new(AtomicU64::new(0));
+            let compute = {
+                let n = n.clone();
+                move || {
+                    let n = n.clone();
+                    async move { Value::U64(n.fetch_add(1, Ordering::Relaxed) + 1) }
+                }
+            };
+            let ttl = Duration::from_secs(3600);
+            publisher.on_subscribe_compute(v.id(), ttl, compute.clone());
+            publisher.flushed().await;
+            let to = Duration::from_secs(30);
+            let path = Path::from("/app/lazy");
+            // computed for the first subscriber
+            let s0 = Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
+            let d0 = s0.subscribe_nondurable_one(path.clone(), None);
+            let d0 = time::timeout(to, d0).await.unwrap().unwrap();
+            assert_eq!(d0.last(), Event::Update(Value::U64(1)));
+            let (tx, mut rx) = mpsc::channel(10);
+            d0.updates(UpdatesFlags::empty(), tx);
+            // cached for the second
+            let s1 = Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
+            let d1 = s1.subscribe_nondurable_one(path.clone(), None);
+            let d1 = time::timeout(to, d1).await.unwrap().unwrap();
+            assert_eq!(d1.last(), Event::Update(Value::U64(1)));
+            assert_eq!(n.load(Ordering::Relaxed), 1);
+            // recomputed when stale, existing subscribers get the update
+            publisher.on_subscribe_compute(v.id(), Duration::ZERO, compute);
+            let s2 = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let d2 = s2.subscribe_nondurable_one(path, None);
+            let d2 = time::timeout(to, d2).await.unwrap().unwrap();
+            assert_eq!(d2.last(), Event::Update(Value::U64(2)));
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let (id, ev) = batch.pop().unwrap();
+            assert_eq!(id, d0.id());
+            assert_eq!(ev, Event::Update(Value::U64(2)));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn auto_commit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vals = (0..3u64)
+                .map(|i| {
+                    let path = Path::from(format!("/app/auto/{}", i));
+                    publisher.publish(path, Value::U64(0)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            publisher.flushed().await;
+            let to = Duration::from_secs(30);
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            let mut subs = vec![];
+            for i in 0..3u64 {
+                let path = Path::from(format!("/app/auto/{}", i));
+                let d = subscriber.subscribe_nondurable_one(path, None);
+                let d = time::timeout(to, d).await.unwrap().unwrap();
+                d.updates(UpdatesFlags::empty(), tx.clone());
+                subs.push(d);
+            }
+            assert_eq!(publisher.auto_commit_stats(), None);
+            // committed together once the delay expires
+            publisher.auto_commit(Duration::from_millis(50), 1 << 20);
+            for (i, v) in vals.iter().enumerate() {
+                v.update_auto(&publisher, Value::U64(i as u64 + 1));
+            }
+            let batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.len(), 3);
+            for ((id, ev), (i, d)) in batch.iter().zip(subs.iter().enumerate()) {
+                assert_eq!(*id, d.id());
+                assert_eq!(*ev, Event::Update(Value::U64(i as u64 + 1)));
+            }
+            let st = publisher.auto_commit_stats().unwrap();
+            assert_eq!((st.batches, st.updates, st.max_updates), (1, 3, 3));
+            assert_eq!((st.by_size, st.by_time), (0, 1));
+            // committed immediately once the size limit is reached
+            publisher.auto_commit(Duration::from_secs(3600), 1);
+            vals[0].update_auto(&publisher, Value::U64(42));
+            let batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(&*batch, &[(subs[0].id(), Event::Update(Value::U64(42)))]);
+            let st = publisher.auto_commit_stats().unwrap();
+            assert_eq!((st.batches, st.updates, st.by_size, st.by_time), (2, 4, 1, 1));
+            assert_eq!(st.mean_updates(), 2.);
+            drop(server);
+        });
+    }
+
+    // receive at least n events, and group them by subscription
+    async fn recv_events(
+        rx: &mut mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+        n: usize,
+    ) -> HashMap<SubId, Vec<Event>> {
+        let mut res: HashMap<SubId, Vec<Event>> = HashMap::new();
+        let mut total = 0;
+        while total < n {
+            let to = Duration::from_secs(30);
+            let batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            total += batch.len();
+            for (id, ev) in batch.iter() {
+                res.entry(*id).or_default().push(ev.clone());
+            }
+        }
+        res
+    }
+
+    #[test]
+    fn patches() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let arr = |v: &[u64]| {
+                Value::from(v.iter().map(|i| Value::U64(*i)).collect::<Vec<_>>())
+            };
+            let path = Path::from("/app/patches");
+            let val = publisher.publish(path.clone(), arr(&[0, 1, 2])).unwrap();
+            publisher.flushed().await;
+            let to = Duration::from_secs(30);
+            // one subscriber that applies patches, and one that doesn't
+            let (tx, mut rx) = mpsc::channel(10);
+            let mut subs = vec![];
+            for patches in [true, false] {
+                let subscriber = SubscriberBuilder::new()
+                    .config(cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .patches(patches)
+                    .build()
+                    .unwrap();
+                let d = subscriber.subscribe_nondurable_one(path.clone(), None);
+                let d = time::timeout(to, d).await.unwrap().unwrap();
+                d.updates(UpdatesFlags::empty(), tx.clone());
+                subs.push((subscriber, d));
+            }
+            let check = |evs: HashMap<SubId, Vec<Event>>, expected: &[Value]| {
+                let expected =
+                    expected.iter().map(|v| Event::Update(v.clone())).collect::<Vec<_>>();
+                for (_, d) in &subs {
+                    assert_eq!(evs[&d.id()], expected);
+                }
+            };
+            let mut batch = publisher.start_batch();
+            let mut patch = Patch::new();
+            patch.set(&[1], 42u64).extend(&[], [Value::U64(3)]);
+            val.update_patch(&mut batch, patch);
+            batch.commit(None).await;
+            check(recv_events(&mut rx, 2).await, &[arr(&[0, 42, 2, 3])]);
+            // a patch that doesn't apply is ignored
+            let mut batch = publisher.start_batch();
+            let mut patch = Patch::new();
+            patch.set(&[10], 0u64);
+            val.update_patch(&mut batch, patch);
+            let mut patch = Patch::new();
+            patch.truncate(&[], 1);
+            val.update_patch(&mut batch, patch);
+            batch.commit(None).await;
+            check(recv_events(&mut rx, 2).await, &[arr(&[0])]);
+            // mixed with full updates
+            let mut batch = publisher.start_batch();
+            val.update(&mut batch, arr(&[7, 8]));
+            let mut patch = Patch::new();
+            patch.set(&[0], 9u64);
+            val.update_patch(&mut batch, patch);
+            batch.commit(None).await;
+            check(recv_events(&mut rx, 4).await, &[arr(&[7, 8]), arr(&[9, 8])]);
+            for (_, d) in &subs {
+                assert_eq!(d.last(), Event::Update(arr(&[9, 8])));
+            }
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn deltas() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let quote = |bid: &str| {
+                Value::from(format!("ES bid={} ask=4512.75 size=12 venue=XCME", bid))
+            };
+            let path = Path::from("/app/deltas");
+            let val = publisher.publish(path.clone(), quote("4512.25")).unwrap();
+            publisher.flushed().await;
+            let to = Duration::from_secs(30);
+            // one subscriber that applies deltas, and one that doesn't
+            let (tx, mut rx) = mpsc::channel(10);
+            let mut subs = vec![];
+            for deltas in [true, false] {
+                let subscriber = SubscriberBuilder::new()
+                    .config(cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .deltas(deltas)
+                    .stats(true)
+                    .build()
+                    .unwrap();
+                let d = subscriber.subscribe_nondurable_one(path.clone(), None);
+                let d = time::timeout(to, d).await.unwrap().unwrap();
+                d.updates(UpdatesFlags::empty(), tx.clone());
+                subs.push((subscriber, d));
+            }
+            let check = |evs: HashMap<SubId, Vec<Event>>, expected: &[Value]| {
+                let expected =
+                    expected.iter().map(|v| Event::Update(v.clone())).collect::<Vec<_>>();
+                for (_, d) in &subs {
+                    assert_eq!(evs[&d.id()], expected);
+                }
+            };
+            let mut batch = publisher.start_batch();
+            val.update(&mut batch, quote("4512.50"));
+            batch.commit(None).await;
+            check(recv_events(&mut rx, 2).await, &[quote("4512.50")]);
+            let (with, without) =
+                (subs[0].1.stats().unwrap(), subs[1].1.stats().unwrap());
+            assert_eq!(with.updates, 1);
+            assert!(with.bytes < without.bytes);
+            // after a value sent to just one subscriber the next update
+            // can't be a delta, and a value that isn't a string never is
+            let mut batch = publisher.start_batch();
+            for cl in publisher.subscribed(&val.id()) {
+                val.update_subscriber(&mut batch, cl, quote("4400.00"));
+            }
+            val.update(&mut batch, quote("4512.75"));
+            val.update(&mut batch, Value::U64(42));
+            val.update(&mut batch, quote("4513.00"));
+            batch.commit(None).await;
+            check(
+                recv_events(&mut rx, 8).await,
+                &[quote("4400.00"), quote("4512.75"), Value::U64(42), quote("4513.00")],
+            );
+            for (_, d) in &subs {
+                assert_eq!(d.last(), Event::Update(quote("4513.00")));
+            }
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_many() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let base = Path::from("/app/many");
+            let _root = publisher.publish(base.clone(), Value::Null).unwrap();
+            let mut vals = HashMap::new();
+            for sym in ["IBM", "MSFT", "AMZN"] {
+                for field in ["last", "bid"] {
+                    let path = base.append(sym).append(field);
+                    let v = Value::from(format!("{} {}", sym, field));
+                    vals.insert(path.clone(), publisher.publish(path, v).unwrap());
+                }
+            }
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Some(Duration::from_secs(30));
+            let mut subs =
+                subscriber.subscribe_many(base.clone(), "*/last", to).await.unwrap();
+            subs.sort_by(|(p0, _), (p1, _)| p0.cmp(p1));
+            let paths = subs.iter().map(|(p, _)| p.clone()).collect::<Vec<_>>();
+            assert_eq!(
+                paths,
+                ["/app/many/AMZN/last", "/app/many/IBM/last", "/app/many/MSFT/last"]
+                    .into_iter()
+                    .map(Path::from)
+                    .collect::<Vec<_>>()
+            );
+            for (path, val) in &subs {
+                let sym = Path::dirname(path).and_then(|d| Path::basename(d)).unwrap();
+                assert_eq!(
+                    val.last(),
+                    Event::Update(Value::from(format!("{} last", sym)))
+                );
+            }
+            // updates flow as usual, and existing subscriptions are reused
+            let (tx, mut rx) = mpsc::channel(10);
+            subs[1].1.updates(UpdatesFlags::empty(), tx);
+            let mut batch = publisher.start_batch();
+            vals[&subs[1].0].update(&mut batch, Value::from(42u64));
+            batch.commit(None).await;
+            let mut batch =
+                time::timeout(Duration::from_secs(30), rx.next()).await.unwrap().unwrap();
+            assert_eq!(
+                batch.drain(..).map(|(_, e)| e).collect::<Vec<_>>(),
+                vec![Event::Update(Value::from(42u64))]
+            );
+            let all = subscriber.subscribe_many(base.clone(), "**", to).await.unwrap();
+            assert_eq!(all.len(), 6);
+            let ibm = all.iter().find(|(p, _)| p == &subs[1].0).unwrap();
+            assert_eq!(ibm.1.id(), subs[1].1.id());
+            // nothing matches
+            let none =
+                subscriber.subscribe_many(base.clone(), "*/ask", to).await.unwrap();
+            assert!(none.is_empty());
+            // a base that no publisher owns
+            assert!(subscriber
+                .subscribe_many(Path::from("/app/none"), "**", to)
+                .await
+                .is_err());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn runtime_handle() {
+        let rt = Runtime::new().unwrap();
+        let (server, cfg, publisher, val) = rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let val = publisher.publish(Path::from("/app/rt"), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            (server, cfg, publisher, val)
+        });
+        // outside of a runtime a handle is required
+        let res = SubscriberBuilder::new()
+            .config(cfg.clone())
+            .desired_auth(DesiredAuth::Anonymous)
+            .build();
+        assert!(res.is_err());
+        let subscriber = SubscriberBuilder::new()
+            .config(cfg.clone())
+            .desired_auth(DesiredAuth::Anonymous)
+            .runtime(rt.handle().clone())
+            .build()
+            .unwrap();
+        // subscribe from a short lived runtime, the connections
+        // belong to rt, so they outlive it
+        let other =
+            tokio::runtime::Builder::new_current_thread().enable_all().build().unwrap();
+        let d = other.block_on(async {
+            let d = subscriber.subscribe_nondurable_one(Path::from("/app/rt"), None);
+            time::timeout(Duration::from_secs(30), d).await.unwrap().unwrap()
+        });
+        drop(other);
+        assert_eq!(d.last(), Event::Update(Value::U64(0)));
+        rt.block_on(async move {
+            let (tx, mut rx) = mpsc::channel(10);
+            d.updates(UpdatesFlags::empty(), tx);
+            let mut batch = publisher.start_batch();
+            val.update(&mut batch, Value::U64(1));
+            batch.commit(None).await;
+            let evs = recv_events(&mut rx, 1).await;
+            assert_eq!(evs[&d.id()], vec![Event::Update(Value::U64(1))]);
+            drop(subscriber);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn dual_stack() {
+        // ipv6 may not be available in the test environment
+        if std::net::TcpListener::bind("[::1]:0").is_err() {
+            return;
+        }
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let v4: BindCfg = "127.0.0.1/32".parse().unwrap();
+            let v6: BindCfg = "[::1]:0".parse().unwrap();
+            assert!(v4.check_alt(&v6).is_ok());
+            assert!(v4.check_alt(&"127.0.0.1:0".parse().unwrap()).is_err());
+            assert!(v6.check_alt(&BindCfg::Local).is_ok());
+            let same_family = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(v4.clone())
+                .alt_bind_cfg(BindCfg::Local)
+                .build()
+                .await;
+            assert!(same_family.is_err());
+            let link_local = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg("[fe80::1]:0".parse().unwrap())
+                .build()
+                .await;
+            assert!(link_local.is_err());
+            let publisher = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg(v4)
+                .alt_bind_cfg(v6)
+                .build()
+                .await
+                .unwrap();
+            assert!(publisher.addr().is_ipv4());
+            assert!(publisher.alt_addr().unwrap().is_ipv6());
+            let _val = publisher.publish(Path::from("/app/v"), Value::U64(42)).unwrap();
+            publisher.flushed().await;
+            for prefer in [PreferFamily::Primary, PreferFamily::V4, PreferFamily::V6] {
+                let subscriber = SubscriberBuilder::new()
+                    .config(cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .prefer(prefer)
+                    .connect_timeout(Duration::from_secs(1))
+                    .build()
+                    .unwrap();
+                let d = subscriber.subscribe_nondurable_one(Path::from("/app/v"), None);
+                let d = time::timeout(Duration::from_secs(10), d).await.unwrap().unwrap();
+                assert_eq!(d.last(), Event::Update(Value::U64(42)));
+            }
+            drop(publisher);
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn subscription_stats() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let val = publisher.publish(Path::from("/app/v"), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let counted = SubscriberBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .stats(true)
+                .build()
+                .unwrap();
+            let uncounted = Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
+            let s0 = counted.subscribe_nondurable_one(Path::from("/app/v"), None);
+            let s0 = time::timeout(Duration::from_secs(10), s0).await.unwrap().unwrap();
+            let s1 = uncounted.subscribe_nondurable_one(Path::from("/app/v"), None);
+            let s1 = time::timeout(Duration::from_secs(10), s1).await.unwrap().unwrap();
+            assert_eq!(s0.stats(), Some(SubStats::default()));
+            assert_eq!(s1.stats(), None);
+            let (tx, mut rx) = mpsc::channel(10);
+            s0.updates(UpdatesFlags::empty(), tx);
+            let vals = [Value::U64(1), Value::from("hello world"), Value::F64(4.2)];
+            for v in vals.iter() {
+                let mut batch = publisher.start_batch();
+                val.update(&mut batch, v.clone());
+                batch.commit(None).await;
+            }
+            recv_events(&mut rx, vals.len()).await;
+            let bytes = vals.iter().map(|v| Pack::encoded_len(v) as u64).sum();
+            assert_eq!(s0.stats(), Some(SubStats { updates: 3, bytes, suppressed: 0 }));
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn keyed_writes() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            publisher.writes(vp.id(), tx);
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let dv = subscriber.subscribe("/app/v0".into());
+            dv.wait_subscribed().await.unwrap();
+            let to = Duration::from_secs(5);
+            let key = subscriber.write_key();
+            assert_ne!(key, subscriber.write_key());
+            let r = dv.write_keyed(Value::U64(1), key);
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.len(), 1);
+            let req = batch.pop().unwrap();
+            assert_eq!(req.value, Value::U64(1));
+            req.send_result.unwrap().send(Value::from("done"));
+            let receipt = time::timeout(to, r).await.unwrap().unwrap();
+            assert_eq!(receipt.result, Value::from("done"));
+            assert!(!receipt.duplicate);
+            // the retry is not executed, and gets the original result
+            let r = dv.write_keyed(Value::U64(1), key);
+            let receipt = time::timeout(to, r).await.unwrap().unwrap();
+            assert_eq!(receipt.result, Value::from("done"));
+            assert!(receipt.duplicate);
+            // a new key is a new write
+            let r = dv.write_keyed(Value::U64(2), subscriber.write_key());
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let req = batch.pop().unwrap();
+            assert_eq!(req.value, Value::U64(2));
+            req.send_result.unwrap().send(Value::Ok);
+            let receipt = time::timeout(to, r).await.unwrap().unwrap();
+            assert_eq!(receipt.result, Value::Ok);
+            assert!(!receipt.duplicate);
+            assert!(rx.try_next().is_err());
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn permission_hints() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let rw = publisher.publish("/app/rw".into(), Value::U64(0)).unwrap();
+            let _ro = publisher.publish("/app/ro".into(), Value::U64(0)).unwrap();
+            let (tx, _rx) = mpsc::channel(10);
+            publisher.writes(rw.id(), tx);
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let v = subscriber
+                .subscribe_nondurable_one("/app/rw".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(v.permissions(), Some(ValPermissions { read: true, write: true }));
+            let v = subscriber
+                .subscribe_nondurable_one("/app/ro".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(
+                v.permissions(),
+                Some(ValPermissions { read: true, write: false })
+            );
+            let dv = subscriber.subscribe("/app/ro".into());
+            dv.wait_subscribed().await.unwrap();
+            assert_eq!(
+                dv.permissions(),
+                Some(ValPermissions { read: true, write: false })
+            );
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn write_many() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let v1 = publisher.publish("/app/v1".into(), Value::U64(0)).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            publisher.writes(v0.id(), tx.clone());
+            publisher.writes(v1.id(), tx);
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let dv = subscriber.subscribe("/app/v0".into());
+            dv.wait_subscribed().await.unwrap();
+            let res = subscriber.write_many_with_recipt([
+                (Path::from("/app/v0"), Value::U64(1)),
+                (Path::from("/app/v1"), Value::U64(2)),
+                (Path::from("/app/v0"), Value::U64(3)),
+            ]);
+            assert_eq!(res.sent, 2);
+            assert_eq!(res.queued, 1);
+            assert_eq!(res.dropped, 0);
+            assert_eq!(res.transient, vec![Path::from("/app/v1")]);
+            let to = Duration::from_secs(10);
+            let mut reqs = vec![];
+            while reqs.len() < 3 {
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                reqs.extend(batch.drain(..));
+            }
+            // both writes to v0 were sent in one batch, before the
+            // transient subscription to v1 was made
+            assert_eq!(reqs[0].path, Path::from("/app/v0"));
+            assert_eq!(reqs[0].value, Value::U64(1));
+            assert_eq!(reqs[1].path, Path::from("/app/v0"));
+            assert_eq!(reqs[1].value, Value::U64(3));
+            assert_eq!(reqs[2].path, Path::from("/app/v1"));
+            assert_eq!(reqs[2].value, Value::U64(2));
+            for req in reqs {
+                let v = req.value.clone();
+                req.send_result.unwrap().send(v);
+            }
+            let results = time::timeout(to, res.wait()).await.unwrap();
+            let results =
+                results.into_iter().map(|(p, r)| (p, r.unwrap())).collect::<Vec<_>>();
+            assert_eq!(
+                results,
+                vec![
+                    (Path::from("/app/v0"), Value::U64(1)),
+                    (Path::from("/app/v1"), Value::U64(2)),
+                    (Path::from("/app/v0"), Value::U64(3)),
+                ]
+            );
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn pinned_resolution() {
+        use crate::config::Pin;
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/app/v0".into(), Value::U64(42)).unwrap();
+            publisher.flushed().await;
+            // the subscriber never talks to the resolver about /app
+            let mut cfg = cfg.clone();
+            cfg.addrs[0].0 = "127.0.0.1:1".parse().unwrap();
+            let prefix = Path::from("/app");
+            cfg.pinned =
+                vec![Pin { prefix, addrs: vec![publisher.addr()], fallback: false }];
+            drop(server);
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(10);
+            let v0 = subscriber.subscribe_nondurable_one("/app/v0".into(), Some(to));
+            let v0 = time::timeout(to, v0).await.unwrap().unwrap();
+            assert_eq!(v0.last(), Event::Update(Value::U64(42)));
+            let (publishers, resolved) = subscriber
+                .resolver()
+                .resolve(["/app/v0".into(), "/app/v1".into()])
+                .await
+                .unwrap();
+            assert_eq!(publishers.len(), 1);
+            assert_eq!(resolved.len(), 2);
+            assert_eq!(resolved[0].publishers[0].id, resolved[1].publishers[0].id);
+        })
+    }
+
+    #[test]
+    fn flush_report() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(10);
+            let r = time::timeout(to, subscriber.flush()).await.unwrap();
+            assert!(r.complete());
+            assert!(r.flushed.is_empty());
+            let dv = subscriber.subscribe("/app/v0".into());
+            dv.wait_subscribed().await.unwrap();
+            dv.write(Value::U64(1));
+            let r = subscriber.flush_timeout(to).await;
+            assert!(r.complete());
+            assert_eq!(r.flushed.len(), 1);
+            assert_eq!(r.flushed[0].0, publisher.addr());
+            let path = Path::from("/app/v0");
+            let r = subscriber.flush_path(&path, Some(to)).await.unwrap();
+            assert!(r.complete());
+            assert_eq!(r.flushed.len(), 1);
+            let path = Path::from("/app/v1");
+            assert!(subscriber.flush_path(&path, None).await.is_err());
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn write_retry() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let p0 = start_publisher(&cfg).await;
+            let v0 = p0.publish("/app/v".into(), Value::U64(0)).unwrap();
+            let (tx, mut rx0) = mpsc::channel(10);
+            p0.writes(v0.id(), tx);
+            p0.flushed().await;
+            let subscriber =
+                Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
+            let dv = subscriber.subscribe("/app/v".into());
+            let dead: Arc<Mutex<Vec<DeadLetter>>> = Arc::new(Mutex::new(vec![]));
+            dv.set_write_retry(Some(WriteRetry {
+                max_tries: 2,
+                dead_letter: Some({
+                    let dead = dead.clone();
+                    Arc::new(move |d| dead.lock().push(d))
+                }),
+            }));
+            dv.wait_subscribed().await.unwrap();
+            let to = Duration::from_secs(30);
+            // the publisher dies before replying
+            let r = dv.write_with_recipt(Value::U64(1));
+            let mut batch = time::timeout(to, rx0.next()).await.unwrap().unwrap();
+            let lost = batch.pop().unwrap();
+            assert_eq!(lost.value, Value::U64(1));
+            assert_eq!(dv.unacked_writes(), 1);
+            p0.shutdown().await;
+            // the write is sent again to the new publisher
+            let p1 = start_publisher(&cfg).await;
+            let v1 = p1.publish("/app/v".into(), Value::U64(0)).unwrap();
+            let (tx, mut rx1) = mpsc::channel(10);
+            p1.writes(v1.id(), tx);
+            p1.flushed().await;
+            let mut batch = time::timeout(to, rx1.next()).await.unwrap().unwrap();
+            let req = batch.pop().unwrap();
+            assert_eq!(req.value, Value::U64(1));
+            req.send_result.unwrap().send(Value::from("done"));
+            assert_eq!(time::timeout(to, r).await.unwrap().unwrap(), Value::from("done"));
+            assert_eq!(dv.unacked_writes(), 0);
+            drop(lost);
+            // if the dval gives up the write goes to the dead letter callback
+            let r = dv.write_with_recipt(Value::U64(2));
+            let mut batch = time::timeout(to, rx1.next()).await.unwrap().unwrap();
+            let lost = batch.pop().unwrap();
+            assert_eq!(lost.value, Value::U64(2));
+            dv.set_give_up(GiveUp { max_tries: Some(1), after: None });
+            p1.shutdown().await;
+            assert!(time::timeout(to, r).await.unwrap().is_err());
+            assert_eq!(dv.state(), DvalState::Failed);
+            assert_eq!(dv.unacked_writes(), 0);
+            let dead = dead.lock();
+            assert_eq!(dead.len(), 1);
+            assert_eq!(dead[0].path, Path::from("/app/v"));
+            assert_eq!(dead[0].value, Value::U64(2));
+            assert_eq!(dead[0].tries, 1);
+            drop(lost);
+            drop(server);
+        })
+    }
+
+    #[test]
+    fn publish_writes_subtree() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/dev/v0".into(), Value::U64(0)).unwrap();
+            let _other = publisher.publish("/other/v0".into(), Value::U64(0)).unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            publisher.writes_subtree("/dev".into(), tx);
+            let _v1 = publisher.publish("/dev/sub/v1".into(), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            for p in ["/dev/v0", "/dev/sub/v1", "/other/v0"] {
+                let s =
+                    subscriber.subscribe_nondurable_one(p.into(), None).await.unwrap();
+                s.write(Value::U64(42));
+                s.flush().await.unwrap();
+            }
+            let mut paths = Vec::new();
+            let to = Duration::from_secs(5);
+            while paths.len() < 2 {
+                let batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                for req in batch.iter() {
+                    assert_eq!(req.value, Value::U64(42));
+                    paths.push(req.path.to_string());
+                }
+            }
+            paths.sort();
+            assert_eq!(paths, vec!["/dev/sub/v1", "/dev/v0"]);
+            assert!(time::timeout(Duration::from_millis(100), rx.next()).await.is_err());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_update_hooks() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let map = |f: fn(u64) -> u64| {
+                move |_: &Path, v: Value| match v {
+                    Value::U64(v) => Value::U64(f(v)),
+                    v => v,
+                }
+            };
+            // the /dev hook runs first because its base is shallower
+            let inc = publisher.add_update_hook("/dev/sub".into(), map(|v| v + 1));
+            let mul = publisher.add_update_hook("/dev".into(), map(|v| v * 10));
+            let v0 = publisher.publish("/dev/sub/v0".into(), Value::U64(1)).unwrap();
+            let _v1 = publisher.publish("/other/v1".into(), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let s0 = subscriber.subscribe_nondurable_one("/dev/sub/v0".into(), None);
+            let s0 = s0.await.unwrap();
+            let s1 = subscriber.subscribe_nondurable_one("/other/v1".into(), None);
+            let s1 = s1.await.unwrap();
+            assert_eq!(s0.last(), Event::Update(Value::U64(11)));
+            assert_eq!(s1.last(), Event::Update(Value::U64(1)));
+            let (tx, mut rx) = mpsc::channel(10);
+            s0.updates(UpdatesFlags::empty(), tx);
+            let to = Duration::from_secs(5);
+            for (v, expected, remove) in
+                [(2, 21, Some(mul)), (3, 4, Some(inc)), (4, 4, None)]
+            {
+                let mut batch = publisher.start_batch();
+                v0.update(&mut batch, Value::U64(v));
+                batch.commit(None).await;
+                let mut b = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                assert_eq!(b.pop().unwrap().1, Event::Update(Value::U64(expected)));
+                if let Some(id) = remove {
+                    publisher.remove_update_hook(id)
+                }
+            }
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_tenants() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let tenants = TenantPublisher::new();
+            for base in ["/a", "/b", "/a/nested"] {
+                let mut builder = PublisherBuilder::new();
+                builder
+                    .config(cfg.clone())
+                    .desired_auth(DesiredAuth::Anonymous)
+                    .bind_cfg("127.0.0.1/32".parse().unwrap());
+                tenants.add_tenant(base.into(), &mut builder).await.unwrap();
+            }
+            let mut builder = PublisherBuilder::new();
+            builder.config(cfg.clone()).desired_auth(DesiredAuth::Anonymous);
+            assert!(tenants.add_tenant("/b".into(), &mut builder).await.is_err());
+            assert!(tenants.publish("/c/v".into(), Value::U64(0)).is_err());
+            let paths = ["/a/v", "/b/v", "/a/nested/v"];
+            let vals = paths
+                .iter()
+                .map(|p| tenants.publish((*p).into(), Value::U64(0)).unwrap())
+                .collect::<Vec<_>>();
+            let addrs = paths
+                .iter()
+                .map(|p| tenants.tenant(&(*p).into()).unwrap().addr())
+                .collect::<Vec<_>>();
+            assert_ne!(addrs[0], addrs[1]);
+            assert_ne!(addrs[0], addrs[2]);
+            for (_, p) in tenants.tenants() {
+                p.flushed().await
+            }
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let mut subs = vec![];
+            for path in paths {
+                let s = subscriber.subscribe_nondurable_one(path.into(), None);
+                let s = s.await.unwrap();
+                assert_eq!(s.last(), Event::Update(Value::U64(0)));
+                subs.push(s);
+            }
+            let mut batch = tenants.start_batch();
+            for (path, val) in paths.iter().zip(vals.iter()) {
+                val.update(batch.batch(&(*path).into()).unwrap(), Value::U64(1));
+            }
+            batch.commit(None).await;
+            for s in &subs {
+                let to = Duration::from_secs(5);
+                time::timeout(to, async {
+                    while s.last() != Event::Update(Value::U64(1)) {
+                        time::sleep(Duration::from_millis(10)).await
+                    }
+                })
+                .await
+                .unwrap();
+            }
+            tenants.shutdown().await;
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_tree() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/tree/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let tree = subscriber
+                .subscribe_tree_with_poll("/tree".into(), Duration::from_millis(50));
+            let (tx, mut rx) = mpsc::channel(10);
+            tree.updates(tx);
+            async fn expect(
+                rx: &mut mpsc::Receiver<Pooled<Vec<(Path, TreeEvent)>>>,
+                path: &str,
+                ev: TreeEvent,
+            ) {
+                let to = Duration::from_secs(5);
+                loop {
+                    let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                    for (p, e) in batch.drain(..) {
+                        if &*p == path && e == ev {
+                            return;
+                        }
+                    }
+                }
+            }
+            expect(&mut rx, "v0", TreeEvent::Added).await;
+            expect(&mut rx, "v0", TreeEvent::Update(Event::Update(Value::U64(0)))).await;
+            let v1 = publisher.publish("/tree/sub/v1".into(), Value::U64(1)).unwrap();
+            publisher.flushed().await;
+            expect(&mut rx, "sub/v1", TreeEvent::Added).await;
+            expect(&mut rx, "sub/v1", TreeEvent::Update(Event::Update(Value::U64(1))))
+                .await;
+            assert!(tree.get("sub/v1").is_some());
+            drop(v1);
+            publisher.flushed().await;
+            expect(&mut rx, "sub/v1", TreeEvent::Removed).await;
+            assert!(tree.get("sub/v1").is_none());
+            assert_eq!(tree.paths(), vec![Path::from("v0")]);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_group() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let mut publishers = vec![];
+            let mut vals = vec![];
+            for i in 0..2u64 {
+                let publisher = start_publisher(&cfg).await;
+                vals.push(publisher.publish("/group/v".into(), Value::U64(i)).unwrap());
+                publisher.flushed().await;
+                publishers.push(publisher);
+            }
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            // a durable subscription to the same path is not disturbed
+            // by the group
+            let dv = subscriber.subscribe("/group/v".into());
+            dv.wait_subscribed().await.unwrap();
+            let group = subscriber
+                .subscribe_group_with_poll("/group/v".into(), Duration::from_millis(50));
+            let (tx, mut rx) = mpsc::channel(10);
+            group.updates(tx);
+            let to = Duration::from_secs(5);
+            let mut addrs = HashMap::new();
+            let mut values = HashMap::new();
+            while addrs.len() < 2 || values.len() < 2 {
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                for (id, ev) in batch.drain(..) {
+                    match ev {
+                        GroupEvent::Added(addr) => {
+                            addrs.insert(id, addr);
+                        }
+                        GroupEvent::Update(Event::Update(v)) => {
+                            values.insert(id, v);
+                        }
+                        e => panic!("unexpected group event {:?}", e),
+                    }
+                }
+            }
+            let mut got = values.values().cloned().collect::<Vec<_>>();
+            got.sort_by_key(|v| v.clone().cast_to::<u64>().unwrap());
+            assert_eq!(got, vec![Value::U64(0), Value::U64(1)]);
+            let mut expected = publishers.iter().map(|p| p.addr()).collect::<Vec<_>>();
+            let mut found = addrs.values().copied().collect::<Vec<_>>();
+            expected.sort();
+            found.sort();
+            assert_eq!(expected, found);
+            // stop publishing at the publisher the durable subscription
+            // isn't using
+            let (other, _) = group
+                .publishers()
+                .into_iter()
+                .find(|(id, _)| group.get(id).unwrap().id() != dv.id())
+                .unwrap();
+            let i = publishers.iter().position(|p| p.addr() == addrs[&other]).unwrap();
+            vals.remove(i);
+            publishers[i].flushed().await;
+            loop {
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                if batch.drain(..).any(|(id, e)| id == other && e == GroupEvent::Removed)
+                {
+                    break;
+                }
+            }
+            assert_eq!(group.publishers().len(), 1);
+            assert!(group.get(&other).is_none());
+            assert_eq!(dv.state(), DvalState::Subscribed);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_on_connect_veto() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let addr = publisher.addr();
+            let vetoed = SubscriberBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .on_connect(move |pb, _| pb.addr != addr)
+                .build()
+                .unwrap();
+            assert!(vetoed
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .is_err());
+            let accepted = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .on_connect(move |pb, _| pb.addr == addr)
+                .build()
+                .unwrap();
+            let vs =
+                accepted.subscribe_nondurable_one("/app/v0".into(), None).await.unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(0)));
+            assert!(vs.publisher_user().is_none());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_accept_policy() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let denied = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg("127.0.0.1/32".parse().unwrap())
+                .allow("127.0.0.0/8".parse().unwrap())
+                .deny("127.0.0.1".parse().unwrap())
+                .build()
+                .await
+                .unwrap();
+            let _v0 = denied.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            denied.flushed().await;
+            let accepted = Arc::new(Mutex::new(Vec::new()));
+            let allowed = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg("127.0.0.1/32".parse().unwrap())
+                .allow("127.0.0.0/8".parse().unwrap())
+                .on_accept({
+                    let accepted = accepted.clone();
+                    move |addr| {
+                        accepted.lock().push(addr);
+                        true
+                    }
+                })
+                .build()
+                .await
+                .unwrap();
+            let _v1 = allowed.publish("/app/v1".into(), Value::U64(1)).unwrap();
+            allowed.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            assert!(subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .is_err());
+            assert_eq!(denied.clients(), 0);
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v1".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(1)));
+            assert_eq!(allowed.clients(), 1);
+            assert_eq!(accepted.lock().len(), 1);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_decode_limits() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _short =
+                publisher.publish("/app/short".into(), Value::from("ok")).unwrap();
+            let _long = publisher
+                .publish("/app/long".into(), Value::from("x".repeat(1024)))
+                .unwrap();
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .decode_limits(DecodeLimits { max_string_len: 64, ..Default::default() })
+                .build()
+                .unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/short".into(), None)
+                .await
+                .unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::from("ok")));
+            assert!(subscriber
+                .subscribe_nondurable_one("/app/long".into(), None)
+                .await
+                .is_err());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscriber_audit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let (tx, mut wrx) = mpsc::channel(10);
+            publisher.writes(vp.id(), tx);
+            publisher.flushed().await;
+            // a violation would kill the connection, and with it the
+            // subscription
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .audit(Audit::Panic)
+                .build()
+                .unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            vs.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            let to = Duration::from_secs(30);
+            for i in 1..=100u64 {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, Value::U64(i));
+                batch.commit(None).await;
+            }
+            let mut n = 0;
+            while n < 100 {
+                for (_, ev) in time::timeout(to, rx.next()).await.unwrap().unwrap() {
+                    n += 1;
+                    assert_eq!(ev, Event::Update(Value::U64(n)));
+                }
+            }
+            let r = vs.write_with_recipt(Value::U64(42));
+            let req =
+                time::timeout(to, wrx.next()).await.unwrap().unwrap().pop().unwrap();
+            req.send_result.unwrap().send(Value::Ok);
+            assert_eq!(time::timeout(to, r).await.unwrap().unwrap(), Value::Ok);
+            assert_eq!(vs.last(), Event::Update(Value::U64(100)));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_give_up() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(10);
+            let speculative = subscriber.subscribe("/app/nothing".into());
+            speculative.set_give_up(GiveUp { max_tries: Some(2), after: None });
+            let mut states = speculative.states();
+            assert_eq!(states.next().await, Some(DvalState::Dead));
+            assert_eq!(
+                time::timeout(to, states.next()).await.unwrap(),
+                Some(DvalState::Failed)
+            );
+            assert_eq!(states.next().await, None);
+            assert_eq!(speculative.state(), DvalState::Failed);
+            assert!(speculative.wait_subscribed().await.is_err());
+            assert!(!speculative.write(Value::U64(1)));
+            assert_eq!(subscriber.durable_stats().dead, 0);
+            let expected = subscriber.subscribe("/app/v0".into());
+            expected.set_give_up(GiveUp { max_tries: Some(2), after: None });
+            time::timeout(to, expected.wait_subscribed()).await.unwrap().unwrap();
+            assert_eq!(expected.state(), DvalState::Subscribed);
+            assert_eq!(expected.last(), Event::Update(Value::U64(0)));
+            // a failed dval is forgotten, subscribing again starts over
+            let again = subscriber.subscribe("/app/nothing".into());
+            assert_eq!(again.state(), DvalState::Dead);
+            assert!(again.id() != speculative.id());
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_rate_limit() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vps = (0..6)
+                .map(|i| {
+                    let path = Path::from(format!("/app/v{}", i));
+                    publisher.publish(path, Value::U64(i)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .rate_limit(10, 2)
+                .build()
+                .unwrap();
+            // 6 resolves and 1 connection with a burst of 2 need at
+            // least 5 more tokens at 10 per second
+            let start = time::Instant::now();
+            for i in 0..6 {
+                let path = Path::from(format!("/app/v{}", i));
+                let v = subscriber.subscribe_nondurable_one(path, None).await.unwrap();
+                assert_eq!(v.last(), Event::Update(Value::U64(i)));
+            }
+            assert!(start.elapsed() >= Duration::from_millis(450));
+            drop(vps);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_sampled() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            let (tx_all, mut rx_all) = mpsc::channel(100);
+            let (tx_every, mut rx_every) = mpsc::channel(100);
+            let (tx_ival, mut rx_ival) = mpsc::channel(100);
+            vs.updates(UpdatesFlags::empty(), tx_all);
+            vs.updates_sampled(UpdatesFlags::empty(), Sample::Every(3), tx_every);
+            let ival = Sample::Interval(Duration::from_millis(500));
+            vs.updates_sampled(UpdatesFlags::empty(), ival, tx_ival);
+            subscriber.flush().await;
+            for i in 1..10u64 {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, Value::U64(i));
+                batch.commit(None).await;
+            }
+            async fn recv_until(
+                rx: &mut mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+                last: u64,
+            ) -> Vec<u64> {
+                let mut res = vec![];
+                while res.last() != Some(&last) {
+                    let to = Duration::from_secs(5);
+                    let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                    for (_, ev) in batch.drain(..) {
+                        match ev {
+                            Event::Update(Value::U64(i)) => res.push(i),
+                            e => panic!("unexpected event {:?}", e),
+                        }
+                    }
+                }
+                res
+            }
+            assert_eq!(recv_until(&mut rx_all, 9).await, (1..10).collect::<Vec<_>>());
+            assert_eq!(recv_until(&mut rx_every, 7).await, vec![1, 4, 7]);
+            while let Ok(batch) = rx_every.try_recv() {
+                assert!(batch.is_empty())
+            }
+            // the first update is delivered immediately, the rest
+            // arrive within the interval, so only the latest is
+            // delivered when it ends.
+            assert_eq!(recv_until(&mut rx_ival, 9).await, vec![1, 9]);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_dedup() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .stats(true)
+                .build()
+                .unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            let (tx_all, mut rx_all) = mpsc::channel(100);
+            let (tx_dedup, mut rx_dedup) = mpsc::channel(100);
+            vs.updates(UpdatesFlags::empty(), tx_all);
+            vs.updates(UpdatesFlags::DEDUP | UpdatesFlags::BEGIN_WITH_LAST, tx_dedup);
+            subscriber.flush().await;
+            let vals = [0, 0, 1, 1, 1, 2, 0];
+            for i in vals {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, Value::U64(i));
+                batch.commit(None).await;
+            }
+            async fn recv_n(
+                rx: &mut mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+                n: usize,
+            ) -> Vec<u64> {
+                let mut res = vec![];
+                while res.len() < n {
+                    let to = Duration::from_secs(5);
+                    let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                    for (_, ev) in batch.drain(..) {
+                        match ev {
+                            Event::Update(Value::U64(i)) => res.push(i),
+                            e => panic!("unexpected event {:?}", e),
+                        }
+                    }
+                }
+                res
+            }
+            assert_eq!(recv_n(&mut rx_all, vals.len()).await, vals);
+            // the initial 0 was sent because of BEGIN_WITH_LAST
+            assert_eq!(recv_n(&mut rx_dedup, 4).await, vec![0, 1, 2, 0]);
+            while let Ok(batch) = rx_dedup.try_recv() {
+                assert!(batch.is_empty())
+            }
+            let stats = vs.stats().unwrap();
+            assert_eq!(stats.updates, vals.len() as u64);
+            assert_eq!(stats.suppressed, 4);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_abort_pending() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            // never answer, so subscriptions stay pending
+            let mut default = publisher.publish_default("/app".into()).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(5);
+            let subscribe = |path: &'static str| {
+                let subscriber = subscriber.clone();
+                task::spawn(async move {
+                    subscriber.subscribe_nondurable_one(path.into(), None).await
+                })
+            };
+            // explicitly abort an attempt and a waiter on it
+            let a0 = subscribe("/app/a");
+            let (_, _reply_a) = time::timeout(to, default.next()).await.unwrap().unwrap();
+            let a1 = subscribe("/app/a");
+            time::sleep(Duration::from_millis(100)).await;
+            assert!(subscriber.abort_pending(&"/app/a".into()));
+            assert!(!subscriber.abort_pending(&"/app/a".into()));
+            for a in [a0, a1] {
+                let e = time::timeout(to, a).await.unwrap().unwrap().unwrap_err();
+                assert!(e.is::<Canceled>());
+            }
+            // dropping the attempt cleans it up, so a new attempt
+            // isn't stuck waiting on it
+            let b0 = subscribe("/app/b");
+            let (_, reply_b) = time::timeout(to, default.next()).await.unwrap().unwrap();
+            b0.abort();
+            assert!(b0.await.unwrap_err().is_cancelled());
+            assert!(!subscriber.abort_pending(&"/app/b".into()));
+            let _vp = publisher.publish("/app/b".into(), Value::U64(42)).unwrap();
+            let _ = reply_b.send(());
+            let vs =
+                time::timeout(to, subscribe("/app/b")).await.unwrap().unwrap().unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(42)));
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_shutdown() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let _v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let _v1 = publisher.publish("/app/v1".into(), Value::U64(1)).unwrap();
+            let _v2 = publisher.publish("/app/u/v2".into(), Value::U64(2)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(5);
+            let dv0 = subscriber.subscribe("/app/v0".into());
+            time::timeout(to, dv0.wait_subscribed()).await.unwrap().unwrap();
+            let v1 = subscriber.subscribe_nondurable_one("/app/v1".into(), None);
+            let v1 = time::timeout(to, v1).await.unwrap().unwrap();
+            let v2 = subscriber.subscribe_nondurable_one("/app/u/v2".into(), None);
+            let v2 = time::timeout(to, v2).await.unwrap().unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            v2.updates(UpdatesFlags::empty(), tx);
+            let pat =
+                GlobSet::new(false, iter::once(Glob::new("/app/u/**".into()).unwrap()))
+                    .unwrap();
+            assert_eq!(subscriber.unsubscribe_matching(&pat), 1);
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            let unsubscribed = Event::Unsubscribed(UnsubscribeReason::Unspecified);
+            assert_eq!(batch.pop().unwrap().1, unsubscribed);
+            assert_eq!(v2.last(), unsubscribed);
+            assert_eq!(v1.last(), Event::Update(Value::U64(1)));
+            assert_eq!(dv0.state(), DvalState::Subscribed);
+            time::timeout(to, subscriber.shutdown()).await.unwrap();
+            assert_eq!(dv0.state(), DvalState::Failed);
+            assert_eq!(dv0.last(), unsubscribed);
+            assert_eq!(v1.last(), unsubscribed);
+            assert!(subscriber
+                .subscribe_nondurable_one("/app/v1".into(), None)
+                .await
+                .is_err());
+            assert_eq!(subscriber.subscribe("/app/v1".into()).state(), DvalState::Failed);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscriber_connection_events() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let addr = publisher.addr();
+            let _v0 = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let mut events = subscriber.connection_events();
+            let to = Duration::from_secs(5);
+            let v0 = subscriber.subscribe_nondurable_one("/app/v0".into(), None);
+            let _v0 = time::timeout(to, v0).await.unwrap().unwrap();
+            match time::timeout(to, events.next()).await.unwrap().unwrap() {
+                ConnEvent::Connected(a, _) => assert_eq!(a, addr),
+                e => panic!("expected connected got {:?}", e),
+            }
+            publisher.shutdown().await;
+            match time::timeout(to, events.next()).await.unwrap().unwrap() {
+                ConnEvent::Disconnected(a, _) => assert_eq!(a, addr),
+                e => panic!("expected disconnected got {:?}", e),
+            }
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn moved_publisher() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            // something else has the address of a publisher that moved,
+            // and the resolver still has the old record
+            let imposter = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let imposter_addr = imposter.local_addr().unwrap();
+            let accepted = Arc::new(AtomicU64::new(0));
+            let accepted_ = accepted.clone();
+            task::spawn(async move {
+                use tokio::io::{AsyncReadExt, AsyncWriteExt};
+                while let Ok((mut soc, _)) = imposter.accept().await {
+                    accepted_.fetch_add(1, Ordering::Relaxed);
+                    task::spawn(async move {
+                        let mut buf = [0u8; 12];
+                        soc.read_exact(&mut buf).await?;
+                        soc.write_all(b"HTTP/1.1 400 Bad Request\r\n\r\n").await?;
+                        soc.read_to_end(&mut vec![]).await
+                    });
+                }
+            });
+            let path = Path::from("/app/moved");
+            let w =
+                ResolverWrite::new(cfg.clone(), DesiredAuth::Anonymous, imposter_addr)
+                    .unwrap();
+            w.publish(iter::once(path.clone())).await.unwrap();
+            let subscriber =
+                Subscriber::new(cfg.clone(), DesiredAuth::Anonymous).unwrap();
+            let mut events = subscriber.connection_events();
+            let to = Duration::from_secs(30);
+            let s = subscriber.subscribe_nondurable_one(path.clone(), None);
+            assert!(time::timeout(to, s).await.unwrap().is_err());
+            loop {
+                let ev = time::timeout(to, events.next()).await.unwrap().unwrap();
+                if let ConnEvent::Disconnected(a, _) = ev {
+                    assert_eq!(a, imposter_addr);
+                    break;
+                }
+            }
+            assert_eq!(accepted.load(Ordering::Relaxed), 1);
+            // the stale record is still tried when there is nothing else
+            let s = subscriber.subscribe_nondurable_one(path.clone(), None);
+            assert!(time::timeout(to, s).await.unwrap().is_err());
+            assert_eq!(accepted.load(Ordering::Relaxed), 2);
+            // the publisher's new address is preferred once it's published
+            let publisher = start_publisher(&cfg).await;
+            let _v = publisher.publish(path.clone(), Value::U64(42)).unwrap();
+            publisher.flushed().await;
+            for _ in 0..5 {
+                let s = subscriber.subscribe_nondurable_one(path.clone(), None);
+                let s = time::timeout(to, s).await.unwrap().unwrap();
+                assert_eq!(s.last(), Event::Update(Value::U64(42)));
+            }
+            time::sleep(Duration::from_millis(100)).await;
+            assert_eq!(accepted.load(Ordering::Relaxed), 2);
+            drop(w);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_priority() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vals = (0..20)
+                .map(|i| {
+                    let path = Path::from(format!("/app/v{}", i));
+                    publisher.publish(path, Value::U64(i)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            publisher.flushed().await;
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .max_background(2)
+                .build()
+                .unwrap();
+            let dvs = (0..vals.len())
+                .map(|i| {
+                    let path = Path::from(format!("/app/v{}", i));
+                    let priority =
+                        if i == 0 { Priority::High } else { Priority::Background };
+                    subscriber.subscribe_with_priority(path, priority)
+                })
+                .collect::<Vec<_>>();
+            assert_eq!(dvs[0].priority(), Priority::High);
+            assert_eq!(dvs[1].priority(), Priority::Background);
+            // resubscribing raises the priority, but never lowers it
+            let dv =
+                subscriber.subscribe_with_priority("/app/v1".into(), Priority::Normal);
+            assert_eq!(dv.priority(), Priority::Normal);
+            let dv = subscriber.subscribe("/app/v0".into());
+            assert_eq!(dv.priority(), Priority::High);
+            dvs[2].set_priority(Priority::High);
+            let to = Duration::from_secs(10);
+            for (i, dv) in dvs.iter().enumerate() {
+                time::timeout(to, dv.wait_subscribed()).await.unwrap().unwrap();
+                assert_eq!(dv.last(), Event::Update(Value::U64(i as u64)));
+            }
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn publish_default_veto() {
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let default = publisher.publish_default("/app".into()).unwrap();
+            let vetoed = Arc::new(Mutex::new(Vec::new()));
+            default.set_veto({
+                let vetoed = vetoed.clone();
+                move |req| {
+                    let vetoed = vetoed.clone();
+                    async move {
+                        let ok = !req.path.starts_with("/app/forbidden");
+                        if !ok {
+                            vetoed.lock().push(req.path);
+                        }
+                        ok
+                    }
+                }
+            });
+            let (tx_req, mut rx_req) = mpsc::unbounded();
+            task::spawn({
+                let publisher = publisher.clone();
+                let mut requests = default.requests();
+                async move {
+                    let mut published = vec![];
+                    while let Some((req, reply)) = requests.next().await {
+                        let v =
+                            publisher.publish(req.path.clone(), Value::U64(42)).unwrap();
+                        published.push(v);
+                        let _ = reply.send(());
+                        let _ = tx_req.unbounded_send(req);
+                    }
+                }
+            });
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let to = Duration::from_secs(10);
+            let vs = time::timeout(
+                to,
+                subscriber.subscribe_nondurable_one("/app/ok".into(), None),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            assert_eq!(vs.last(), Event::Update(Value::U64(42)));
+            let req = rx_req.next().await.unwrap();
+            assert_eq!(&*req.path, "/app/ok");
+            assert!(req.user.is_none());
+            assert!(publisher.user(&req.client).is_none());
+            let r = time::timeout(
+                to,
+                subscriber.subscribe_nondurable_one("/app/forbidden".into(), None),
+            )
+            .await
+            .unwrap();
+            assert!(r.is_err());
+            assert_eq!(&*vetoed.lock(), &[Path::from("/app/forbidden")]);
+            assert!(publisher.id("/app/forbidden").is_none());
+            drop(server);
+        });
+    }
+
+    #[cfg(feature = "fault_injection")]
+    #[test]
+    fn fault_injection() {
+        use crate::{fault::FaultInjector, publisher::PublisherBuilder};
+        async fn wait_for(
+            rx: &mut mpsc::Receiver<Pooled<Vec<(SubId, Event)>>>,
+            ev: Event,
+        ) -> Vec<Event> {
+            let mut seen = vec![];
+            loop {
+                let to = Duration::from_secs(10);
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                for (_, e) in batch.drain(..) {
+                    let done = e == ev;
+                    seen.push(e);
+                    if done {
+                        return seen;
+                    }
+                }
+            }
+        }
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let pub_faults = FaultInjector::new();
+            let publisher = PublisherBuilder::new()
+                .config(cfg.clone())
+                .desired_auth(DesiredAuth::Anonymous)
+                .bind_cfg("127.0.0.1/32".parse().unwrap())
+                .fault_injector(pub_faults.clone())
+                .build()
+                .await
+                .unwrap();
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            let (tx_writes, mut rx_writes) = mpsc::channel(10);
+            publisher.writes(vp.id(), tx_writes);
+            publisher.flushed().await;
+            let sub_faults = FaultInjector::new();
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .fault_injector(sub_faults.clone())
+                .build()
+                .unwrap();
+            let dv = subscriber.subscribe("/app/v0".into());
+            let (tx, mut rx) = mpsc::channel(10);
+            dv.updates(UpdatesFlags::BEGIN_WITH_LAST, tx);
+            dv.wait_subscribed().await.unwrap();
+            wait_for(&mut rx, Event::Update(Value::U64(0))).await;
+            let update = |v: u64| {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, Value::U64(v));
+                batch.commit(None)
+            };
+            // a dropped frame is just lost
+            pub_faults.drop_next(1);
+            update(1).await;
+            time::sleep(Duration::from_millis(100)).await;
+            update(2).await;
+            let seen = wait_for(&mut rx, Event::Update(Value::U64(2))).await;
+            assert_eq!(seen, vec![Event::Update(Value::U64(2))]);
+            // delayed writes
+            pub_faults.delay_writes(Some(Duration::from_millis(500)));
+            let start = std::time::Instant::now();
+            update(3).await;
+            wait_for(&mut rx, Event::Update(Value::U64(3))).await;
+            assert!(start.elapsed() >= Duration::from_millis(500));
+            pub_faults.clear();
+            // writes made while disconnected are queued, and sent
+            // when the durable subscription comes back
+            sub_faults.disconnect();
+            wait_for(&mut rx, Event::Unsubscribed(UnsubscribeReason::Unspecified)).await;
+            dv.write(Value::U64(42));
+            let to = Duration::from_secs(10);
+            let batch = time::timeout(to, rx_writes.next()).await.unwrap().unwrap();
+            assert_eq!(batch[0].value, Value::U64(42));
+            wait_for(&mut rx, Event::Update(Value::U64(3))).await;
+            // a corrupt frame kills the connection
+            pub_faults.corrupt_next(1);
+            update(4).await;
+            let seen = wait_for(&mut rx, Event::Update(Value::U64(4))).await;
+            let unsubscribed = Event::Unsubscribed(UnsubscribeReason::Unspecified);
+            assert_eq!(seen, vec![unsubscribed, Event::Update(Value::U64(4))]);
+            drop(server);
+        });
+    }
+
+    #[cfg(feature = "fault_injection")]
+    #[test]
+    fn half_open_connection() {
+        use crate::{fault::FaultInjector, publisher::PublishFlags};
+        Runtime::new().unwrap().block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let flags = PublishFlags::USE_EXISTING;
+            let vals = (0..3)
+                .map(|i| {
+                    let path = Path::from(format!("/app/v{}", i));
+                    publisher.publish_with_flags(flags, path, Value::U64(i)).unwrap()
+                })
+                .collect::<Vec<_>>();
+            publisher.flushed().await;
+            let faults = FaultInjector::new();
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .stalled_after(Duration::from_millis(500))
+                .fault_injector(faults.clone())
+                .build()
+                .unwrap();
+            let to = Duration::from_secs(10);
+            let s0 = subscriber.subscribe_nondurable_one("/app/v0".into(), None);
+            let s0 = time::timeout(to, s0).await.unwrap().unwrap();
+            let (tx, mut rx) = mpsc::channel(10);
+            s0.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            // the connection stays up, but the publisher will never
+            // hear about new subscriptions
+            faults.half_open();
+            let mut s1 = task::spawn({
+                let subscriber = subscriber.clone();
+                async move {
+                    subscriber.subscribe_nondurable_one("/app/v1".into(), None).await
+                }
+            });
+            time::sleep(Duration::from_secs(1)).await;
+            assert!((&mut s1).now_or_never().is_none());
+            // the stalled connection isn't used for new subscriptions
+            let s2 = subscriber.subscribe_nondurable_one("/app/v2".into(), None);
+            let s2 = time::timeout(to, s2).await.unwrap().unwrap();
+            assert_eq!(s2.last(), Event::Update(Value::U64(2)));
+            // and existing subscriptions are left where they are
+            let mut batch = publisher.start_batch();
+            vals[0].update(&mut batch, Value::U64(42));
+            batch.commit(None).await;
+            let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+            assert_eq!(batch.pop().unwrap().1, Event::Update(Value::U64(42)));
+            s1.abort();
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_history() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            let vp = publisher.publish("/app/v0".into(), Value::U64(0)).unwrap();
+            publisher.flushed().await;
+            let subscriber = Subscriber::new(cfg, DesiredAuth::Anonymous).unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            vs.set_history(3);
+            let (tx, mut rx) = mpsc::channel(100);
+            vs.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            for i in 1..6u64 {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, Value::U64(i));
+                batch.commit(None).await;
+            }
+            let mut n = 0;
+            while n < 5 {
+                let to = Duration::from_secs(5);
+                n += time::timeout(to, rx.next()).await.unwrap().unwrap().len();
+            }
+            let values = |evs: &mut dyn Iterator<Item = Event>| {
+                evs.map(|ev| match ev {
+                    Event::Update(Value::U64(i)) => i,
+                    e => panic!("unexpected event {:?}", e),
+                })
+                .collect::<Vec<_>>()
+            };
+            let hist = vs.history();
+            assert!(hist.windows(2).all(|w| w[0].0 <= w[1].0));
+            assert_eq!(values(&mut hist.into_iter().map(|(_, ev)| ev)), vec![3, 4, 5]);
+            let (tx, mut rx) = mpsc::channel(100);
+            vs.updates(UpdatesFlags::BEGIN_WITH_HISTORY, tx);
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(values(&mut batch.drain(..).map(|(_, ev)| ev)), vec![3, 4, 5]);
+            vs.set_history(0);
+            assert!(vs.history().is_empty());
+            let (tx, mut rx) = mpsc::channel(100);
+            let flags = UpdatesFlags::BEGIN_WITH_HISTORY | UpdatesFlags::BEGIN_WITH_LAST;
+            vs.updates(flags, tx);
+            let mut batch = rx.next().await.unwrap();
+            assert_eq!(values(&mut batch.drain(..).map(|(_, ev)| ev)), vec![5]);
+            drop(server);
+        });
+    }
+
+    #[test]
+    fn subscribe_shm() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let (server, cfg) = start_resolver().await;
+            let publisher = start_publisher(&cfg).await;
+            // values bigger than the ring force it to wrap and the
+            // writer to wait for the reader
+            let big = |i: u8| Value::Bytes(vec![i; 100_000].into());
+            let vp = publisher.publish("/app/v0".into(), big(0)).unwrap();
+            let (tx_writes, mut rx_writes) = mpsc::channel(10);
+            publisher.writes(vp.id(), tx_writes);
+            publisher.flushed().await;
+            let started = crate::shm::STARTED.load(Ordering::Relaxed);
+            let subscriber = SubscriberBuilder::new()
+                .config(cfg)
+                .desired_auth(DesiredAuth::Anonymous)
+                .shm(1 << 16)
+                .build()
+                .unwrap();
+            let vs = subscriber
+                .subscribe_nondurable_one("/app/v0".into(), None)
+                .await
+                .unwrap();
+            assert!(crate::shm::STARTED.load(Ordering::Relaxed) >= started + 2);
+            assert_eq!(vs.last(), Event::Update(big(0)));
+            let (tx, mut rx) = mpsc::channel(100);
+            vs.updates(UpdatesFlags::empty(), tx);
+            subscriber.flush().await;
+            for i in 1..10u8 {
+                let mut batch = publisher.start_batch();
+                vp.update(&mut batch, big(i));
+                batch.commit(None).await;
+            }
+            let mut i = 1;
+            while i < 10 {
+                let to = Duration::from_secs(5);
+                let mut batch = time::timeout(to, rx.next()).await.unwrap().unwrap();
+                for (_, ev) in batch.drain(..) {
+                    assert_eq!(ev, Event::Update(big(i)));
+                    i += 1;
+                }
+            }
+            vs.write(big(42));
+            let to = Duration::from_secs(5);
+            let batch = time::timeout(to, rx_writes.next()).await.unwrap().unwrap();
+            assert_eq!(batch.len(), 1);
+            assert_eq!(batch[0].value, big(42));
+            drop(server);
+        });
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn shm_open_untrusted() {
+        use crate::{
+            os::shm::Mapping,
+            protocol::publisher::ShmOffer,
+            shm::{Segment, MIN_RING},
+        };
+        let offer =
+            |name: &str| ShmOffer { name: name.into(), ring_size: MIN_RING as u64 };
+        // the header page, then both rings
+        let len = 4096 + 2 * MIN_RING;
+        // names that Segment::create could not have made are refused
+        let name = format!("/other-{}", std::process::id());
+        let _other = Mapping::create(&name, len).unwrap();
+        assert!(Segment::open(&offer(&name)).is_err());
+        assert!(Mapping::open(&name, len).is_ok());
+        Mapping::unlink(&name);
+        assert!(Segment::open(&offer("/netidx-")).is_err());
+        assert!(Segment::open(&offer("/netidx-1/../x")).is_err());
+        // a segment with an invalid header is refused and not unlinked
+        let name = format!("/netidx-{:x}", std::process::id());
+        let _bad = Mapping::create(&name, len).unwrap();
+        assert!(Segment::open(&offer(&name)).is_err());
+        assert!(Mapping::open(&name, len).is_ok());
+        Mapping::unlink(&name);
+        // a valid segment is unlinked once it's opened
+        let seg = Segment::create(MIN_RING).unwrap();
+        let offer = seg.offer();
+        assert!(Segment::open(&offer).is_ok());
+        assert!(Mapping::open(&offer.name, len).is_err());
+    }
+    #[test]
+    fn publish_subscribe_tls() {
+        let rt = Runtime::new().unwrap();
+        rt.block_on(async {
+            let server_cfg = ServerConfig::load("../cfg/tls/resolver/resolver.json")
+                .expect("load tls server config");
+            let mut pub_cfg = ClientConfig::load("../cfg/tls/publisher/client.json")
+                .expect("failed to load tls publisher config");
+            let mut sub_cfg = ClientConfig::load("../cfg/tls/client/client.json")
+                .expect("failed to load subscriber cfg");
+            let default_destroyed = Arc::new(Mutex::new(false));
+            let (tx, ready) = oneshot::channel();
+            let server = Server::new(server_cfg, false, 0).await.expect("start server");
+            pub_cfg.addrs[0].0 = *server.local_addr();
+            sub_cfg.addrs[0].0 = *server.local_addr();
+            task::spawn(run_publisher(
+                pub_cfg.clone(),
+                default_destroyed.clone(),
+                tx,
+                DesiredAuth::Tls { identity: None },
+            ));
+            time::timeout(Duration::from_secs(1), ready).await.unwrap().unwrap();
+            run_subscriber(
+                pub_cfg,
+                default_destroyed,
+                DesiredAuth::Tls { identity: None },
+            )
+            .await;
+            drop(server)
+        })
+    }
+}
+
+mod tls {
+    use crate::{
+        config::{Tls, TlsIdentity},
+        tls::Cached,
+    };
+    use anyhow::Result;
+    use std::{collections::BTreeMap, fs};
+
+    // stands in for building a tls context, so the tests can see
+    // which certificate was used
+    fn read_cert(_: Option<&str>, _: &str, certificate: &str, _: &str) -> Result<String> {
+        Ok(fs::read_to_string(certificate)?)
+    }
+
+    fn identity(dir: &str) -> TlsIdentity {
+        TlsIdentity {
+            trusted: "../cfg/tls/ca/certificate".into(),
+            name: dir.into(),
+            certificate: format!("{}/certificate", dir),
+            private_key: format!("{}/private.key", dir),
+        }
+    }
+
+    fn tls<const N: usize>(identities: [(&str, TlsIdentity); N]) -> Tls {
+        let identities = identities
+            .into_iter()
+            .map(|(domain, id)| {
+                let mut domain = String::from(domain);
+                Tls::reverse_domain_name(&mut domain);
+                (domain, id)
+            })
+            .collect::<BTreeMap<_, _>>();
+        let default_identity = identities.keys().next().unwrap().clone();
+        Tls { default_identity, identities, askpass: None }
+    }
+
+    #[test]
+    fn identity_selection() {
+        let client = fs::read_to_string("../cfg/tls/client/certificate").unwrap();
+        let publisher = fs::read_to_string("../cfg/tls/publisher/certificate").unwrap();
+        let cached = Cached::<String>::new(tls([
+            ("example.com", identity("../cfg/tls/client")),
+            ("a.example.com", identity("../cfg/tls/publisher")),
+        ]));
+        assert_eq!(cached.load("example.com", read_cert).unwrap(), client);
+        // the parent's context is cached, but a closer identity exists
+        assert_eq!(cached.load("resolver.a.example.com", read_cert).unwrap(), publisher);
+        assert_eq!(cached.load("a.example.com", read_cert).unwrap(), publisher);
+        // sorts after a.example.com, but isn't beneath it
+        assert_eq!(cached.load("b.example.com", read_cert).unwrap(), client);
+        // there is no fallback to the default identity
+        assert!(cached.load("example.org", read_cert).is_err());
+    }
+
+    #[test]
+    fn reload() {
+        let dir = std::env::temp_dir().join(format!("netidx-tls-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        for f in ["certificate", "private.key"] {
+            fs::copy(format!("../cfg/tls/client/{}", f), dir.join(f)).unwrap();
+        }
+        let id = identity(dir.to_str().unwrap());
+        let cached = Cached::<String>::new(tls([("example.com", id)]));
+        let client = fs::read_to_string("../cfg/tls/client/certificate").unwrap();
+        assert_eq!(cached.load("example.com", read_cert).unwrap(), client);
+        let publisher = fs::read_to_string("../cfg/tls/publisher/certificate").unwrap();
+        fs::write(dir.join("certificate"), &publisher).unwrap();
+        assert_eq!(cached.load("example.com", read_cert).unwrap(), publisher);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
+
+mod pool {
+    use crate::pool::{self, Pool, PoolConfig};
+
+    #[test]
+    fn adaptive() {
+        let pool: Pool<Vec<usize>> = Pool::new(4, 100);
+        let cycle = |n: usize| drop((0..n).map(|_| pool.take()).collect::<Vec<_>>());
+        // 16 objects in flight at once grows the pool until they all fit
+        for _ in 0..1024 {
+            cycle(16)
+        }
+        let s = pool.stats();
+        assert_eq!(s.capacity, 16);
+        assert_eq!(s.grown, 2);
+        assert_eq!(s.high_water, 16);
+        // then only one in flight shrinks it again
+        for _ in 0..4096 {
+            cycle(1)
+        }
+        let s = pool.stats();
+        assert_eq!(s.capacity, 3);
+        assert_eq!(s.shrunk, 3);
+        assert!(s.pooled <= 3);
+        assert!(s.hit_rate() > 0.9);
+        // a fixed size pool
+        pool.configure(PoolConfig {
+            adaptive: Some(false),
+            max_capacity: Some(8),
+            ..PoolConfig::default()
+        });
+        for _ in 0..1024 {
+            cycle(16)
+        }
+        assert_eq!(pool.stats().capacity, 8);
+        let mut big = pool.take();
+        big.reserve(1000);
+        let discarded = pool.stats().discarded;
+        drop(big);
+        assert_eq!(pool.stats().discarded, discarded + 1);
+    }
+
+    #[test]
+    fn named() {
+        let cfg = PoolConfig { max_elt_capacity: Some(10), ..PoolConfig::default() };
+        pool::configure("test::named::", cfg);
+        let a: Pool<Vec<usize>> = Pool::named("test::named::a", 4, 100);
+        let b: Pool<Vec<usize>> = Pool::named("test::named::b", 4, 100);
+        drop(a.take());
+        let stats = pool::stats()
+            .into_iter()
+            .filter(|(name, _)| name.starts_with("test::named::"))
+            .collect::<Vec<_>>();
+        assert_eq!(stats.len(), 2);
+        assert_eq!(stats[0].0, "test::named::a");
+        assert_eq!(stats[0].1.takes, 1);
+        assert_eq!(stats[0].1.max_elt_capacity, 10);
+        assert_eq!(stats[1].1.takes, 0);
+        drop(b);
+        assert!(pool::stats().iter().all(|(name, _)| *name != "test::named::b"));
     }
 }
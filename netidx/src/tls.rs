@@ -3,11 +3,23 @@ use anyhow::Result;
 use log::{debug, info, warn};
 use parking_lot::Mutex;
 use std::{
-    collections::{BTreeMap, Bound},
-    fmt, mem,
+    collections::BTreeMap,
+    fmt, fs, mem,
     sync::Arc,
+    time::{Duration, Instant, SystemTime},
 };
 
+/// How often a cached tls context will check whether any of the
+/// files it was built from have changed on disk. If they have, the
+/// context is rebuilt, so rotated certificates are picked up by new
+/// connections without restarting the process.
+#[cfg(not(test))]
+const RELOAD_CHECK: Duration = Duration::from_secs(10);
+
+// tests don't want to wait
+#[cfg(test)]
+const RELOAD_CHECK: Duration = Duration::ZERO;
+
 pub(crate) fn load_certs(path: &str) -> Result<Vec<rustls::Certificate>> {
     use std::{fs, io::BufReader};
     Ok(rustls_pemfile::certs(&mut BufReader::new(fs::File::open(path)?))?
@@ -131,27 +143,45 @@ pub(crate) fn create_tls_acceptor(
     Ok(tokio_rustls::TlsAcceptor::from(Arc::new(config)))
 }
 
+/// Find the entry for the reversed domain name `identity`, or else
+/// for the closest of its parent domains.
 pub(crate) fn get_match<'a: 'b, 'b, U>(
     m: &'a BTreeMap<String, U>,
     identity: &'b str,
 ) -> Option<&'a U> {
-    m.range::<str, (Bound<&str>, Bound<&str>)>((
-        Bound::Unbounded,
-        Bound::Included(identity),
-    ))
-    .next_back()
-    .and_then(|(k, v)| {
-        if k == identity || (identity.starts_with(k)) {
-            Some(v)
-        } else {
-            None
+    // the closest entry before identity isn't necessarily a parent,
+    // e.g. com.example.a. sorts between com.example. and
+    // com.example.b., so try each parent in turn
+    let mut name = identity;
+    loop {
+        if let Some(v) = m.get(name) {
+            break Some(v);
         }
-    })
+        if name.is_empty() {
+            break None;
+        }
+        let parent = name[..name.len() - 1].rfind('.').map(|i| i + 1).unwrap_or(0);
+        name = &name[..parent];
+    }
+}
+
+type Stamps = [Option<SystemTime>; 3];
+
+fn stamps(id: &TlsIdentity) -> Stamps {
+    [&id.trusted, &id.certificate, &id.private_key]
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+}
+
+struct CachedEntry<T> {
+    t: T,
+    identity: TlsIdentity,
+    stamps: Stamps,
+    checked: Instant,
 }
 
 struct CachedInnerLocked<T> {
     tmp: String,
-    cached: BTreeMap<String, T>,
+    cached: BTreeMap<String, CachedEntry<T>>,
 }
 
 struct CachedInner<T> {
@@ -160,7 +190,7 @@ struct CachedInner<T> {
 }
 
 #[derive(Clone)]
-struct Cached<T>(Arc<CachedInner<T>>);
+pub(crate) struct Cached<T>(Arc<CachedInner<T>>);
 
 impl<T> fmt::Debug for Cached<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
@@ -169,7 +199,7 @@ impl<T> fmt::Debug for Cached<T> {
 }
 
 impl<T: Clone + 'static> Cached<T> {
-    fn new(tls: Tls) -> Self {
+    pub(crate) fn new(tls: Tls) -> Self {
         Self(Arc::new(CachedInner {
             tls,
             t: Mutex::new(CachedInnerLocked {
@@ -187,7 +217,55 @@ impl<T: Clone + 'static> Cached<T> {
         self.0.tls.identities.get(id)
     }
 
-    fn load(
+    fn build(
+        &self,
+        id: &TlsIdentity,
+        f: fn(Option<&str>, &str, &str, &str) -> Result<T>,
+    ) -> Result<T> {
+        let askpass = self.0.tls.askpass.as_ref().map(|s| s.as_str());
+        f(askpass, &id.trusted, &id.certificate, &id.private_key)
+    }
+
+    // if the files backing a cached context have changed since it
+    // was built then rebuild it. If the rebuild fails, e.g. because
+    // the new files are only partially written, keep using the old
+    // context and try again later.
+    fn maybe_reload(
+        &self,
+        key: &str,
+        f: fn(Option<&str>, &str, &str, &str) -> Result<T>,
+    ) -> Option<T> {
+        let (identity, current) = {
+            let mut inner = self.0.t.lock();
+            let ent = inner.cached.get_mut(key)?;
+            if ent.checked.elapsed() < RELOAD_CHECK {
+                return Some(ent.t.clone());
+            }
+            ent.checked = Instant::now();
+            let current = stamps(&ent.identity);
+            if current == ent.stamps {
+                return Some(ent.t.clone());
+            }
+            info!("tls files for {} changed, reloading", ent.identity.name);
+            (ent.identity.clone(), current)
+        };
+        let res = self.build(&identity, f);
+        let mut inner = self.0.t.lock();
+        let ent = inner.cached.get_mut(key)?;
+        match res {
+            Ok(t) => {
+                ent.t = t;
+                ent.stamps = current;
+            }
+            Err(e) => warn!("failed to reload tls identity {}, {}", identity.name, e),
+        }
+        Some(ent.t.clone())
+    }
+
+    /// Get the context for the target domain `identity`, building it
+    /// with `f` from the identity that matches `identity` most
+    /// closely if it isn't cached.
+    pub(crate) fn load(
         &self,
         identity: &str,
         f: fn(Option<&str>, &str, &str, &str) -> Result<T>,
@@ -197,23 +275,35 @@ impl<T: Clone + 'static> Cached<T> {
             inner.tmp.clear();
             inner.tmp.push_str(&identity);
             Tls::reverse_domain_name(&mut inner.tmp);
-            if let Some(v) = get_match(&inner.cached, &inner.tmp) {
-                return Ok(v.clone());
+            // contexts are cached by target, a parent domain's context
+            // may have been built from a less specific identity
+            if inner.cached.contains_key(&inner.tmp) {
+                let key = inner.tmp.clone();
+                drop(inner);
+                if let Some(t) = self.maybe_reload(&key, f) {
+                    return Ok(t);
+                }
+                inner = self.0.t.lock();
             }
             mem::replace(&mut inner.tmp, String::new())
         };
-        match get_match(&self.0.tls.identities, &rev_identity) {
+        let id = match get_match(&self.0.tls.identities, &rev_identity) {
+            Some(id) => id,
             None => {
                 self.0.t.lock().tmp = rev_identity;
                 bail!("no plausable identity matches {}", identity)
             }
-            Some(TlsIdentity { name: _, trusted, certificate, private_key }) => {
-                let askpass = self.0.tls.askpass.as_ref().map(|s| s.as_str());
-                let con = f(askpass, trusted, certificate, private_key)?;
-                self.0.t.lock().cached.insert(rev_identity, con.clone());
-                Ok(con)
-            }
-        }
+        };
+        let stamps = stamps(id);
+        let t = self.build(id, f)?;
+        let ent = CachedEntry {
+            t: t.clone(),
+            identity: id.clone(),
+            stamps,
+            checked: Instant::now(),
+        };
+        self.0.t.lock().cached.insert(rev_identity, ent);
+        Ok(t)
     }
 }
 